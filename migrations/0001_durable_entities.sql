@@ -0,0 +1,49 @@
+-- Rules and their history, alerts, ended attacks and the audit log.
+-- Whole records are kept as JSON, with the columns queries filter on beside them.
+
+CREATE TABLE rules (
+    id TEXT PRIMARY KEY,
+    definition JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE rule_history (
+    rule_id TEXT NOT NULL,
+    version BIGINT NOT NULL,
+    entry JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (rule_id, version)
+);
+
+CREATE TABLE alerts (
+    id TEXT PRIMARY KEY,
+    level TEXT NOT NULL,
+    status TEXT NOT NULL,
+    source TEXT NOT NULL,
+    alert JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX alerts_created_at ON alerts (created_at);
+
+CREATE TABLE attacks (
+    id TEXT PRIMARY KEY,
+    vector TEXT NOT NULL,
+    attack JSONB NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    ended_at TIMESTAMPTZ
+);
+
+CREATE INDEX attacks_started_at ON attacks (started_at);
+
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    target TEXT NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX audit_log_created_at ON audit_log (created_at);
@@ -0,0 +1,129 @@
+//! Alert rule management.
+//!
+//! Rules are evaluated against the system metrics on every monitoring
+//! tick; see `core::alert_rules` for how they match.
+
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use serde::Deserialize;
+use std::collections::HashMap;
+use crate::core::alert_rules::{AlertRule, AlertRuleError, Comparison};
+use crate::core::monitoring::AlertLevel;
+use super::ApiState;
+use super::error::ApiError;
+
+/// Alert rule request
+#[derive(Deserialize)]
+pub struct AlertRuleRequest {
+    /// Rule ID; generated when creating a rule without one
+    id: Option<String>,
+    name: String,
+    metric: String,
+    comparison: Comparison,
+    threshold: f64,
+    resolve_threshold: Option<f64>,
+    #[serde(default)]
+    duration_seconds: u64,
+    severity: AlertLevel,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    enabled: Option<bool>,
+}
+
+fn rule_error_response(action: &str, e: AlertRuleError) -> HttpResponse {
+    match e {
+        AlertRuleError::InvalidRule(_) => ApiError::bad_request(e.to_string()).into(),
+        e => {
+            log::error!("Failed to {} alert rule: {}", action, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// List alert rules endpoint
+pub async fn get_alert_rules(state: web::Data<ApiState>) -> impl Responder {
+    match state.alert_rules.get_rules().await {
+        Ok(rules) => HttpResponse::Ok().json(rules),
+        Err(e) => rule_error_response("list", e),
+    }
+}
+
+/// Create alert rule endpoint
+pub async fn create_alert_rule(
+    state: web::Data<ApiState>,
+    req: web::Json<AlertRuleRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    let mut rule = AlertRule::new(&req.name, &req.metric, req.comparison, req.threshold, req.severity);
+    if let Some(id) = req.id {
+        match state.alert_rules.get_rule(&id).await {
+            Ok(None) => rule.id = id,
+            Ok(Some(_)) => return ApiError::conflict(format!("Alert rule {} already exists", id)).into(),
+            Err(e) => return rule_error_response("get", e),
+        }
+    }
+    rule.resolve_threshold = req.resolve_threshold;
+    rule.duration_seconds = req.duration_seconds;
+    rule.labels = req.labels;
+    rule.enabled = req.enabled.unwrap_or(true);
+
+    match state.alert_rules.set_rule(&rule).await {
+        Ok(()) => HttpResponse::Created().json(rule),
+        Err(e) => rule_error_response("create", e),
+    }
+}
+
+/// Get alert rule endpoint
+pub async fn get_alert_rule(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.alert_rules.get_rule(&path.into_inner()).await {
+        Ok(Some(rule)) => HttpResponse::Ok().json(rule),
+        Ok(None) => ApiError::not_found("Alert rule").into(),
+        Err(e) => rule_error_response("get", e),
+    }
+}
+
+/// Update alert rule endpoint
+///
+/// The rule's ID is taken from the path.
+pub async fn update_alert_rule(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+    req: web::Json<AlertRuleRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    let mut rule = match state.alert_rules.get_rule(&path.into_inner()).await {
+        Ok(Some(rule)) => rule,
+        Ok(None) => return ApiError::not_found("Alert rule").into(),
+        Err(e) => return rule_error_response("get", e),
+    };
+    rule.name = req.name;
+    rule.metric = req.metric;
+    rule.comparison = req.comparison;
+    rule.threshold = req.threshold;
+    rule.resolve_threshold = req.resolve_threshold;
+    rule.duration_seconds = req.duration_seconds;
+    rule.severity = req.severity;
+    rule.labels = req.labels;
+    rule.enabled = req.enabled.unwrap_or(rule.enabled);
+    rule.updated_at = Utc::now();
+
+    match state.alert_rules.set_rule(&rule).await {
+        Ok(()) => HttpResponse::Ok().json(rule),
+        Err(e) => rule_error_response("update", e),
+    }
+}
+
+/// Delete alert rule endpoint
+pub async fn delete_alert_rule(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.alert_rules.remove_rule(&path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Alert rule").into(),
+        Err(e) => rule_error_response("delete", e),
+    }
+}
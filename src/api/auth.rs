@@ -0,0 +1,260 @@
+//! Access control for the management endpoints.
+//!
+//! With `auth.enabled`, every request under `/api/v1` is matched to the
+//! permission it needs, and must carry a key whose role grants it: `401`
+//! without a known key, `403` naming the missing permission otherwise.
+//! Decision endpoints called for traffic need no key, except those acting
+//! on whichever client or cost the caller names, which need a proxy key.
+//! Permissions follow
+//! the route a request is dispatched to, so encoded paths get the same
+//! check as plain ones, and paths no check knows need an admin.
+
+use std::fmt;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use serde_json::json;
+use crate::models::{AccessKey, Role};
+use crate::utils::constant_time_eq;
+use super::ApiState;
+use super::error::ApiError;
+
+/// Something a request may need to be allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Report traffic for a client named by the caller: count rate limits,
+    /// report logins, connections and responses, and release concurrency slots
+    DecisionsReport,
+    /// Read analytics, traffic, attacks and baselines
+    AnalyticsRead,
+    /// Read monitoring metrics, alerts and the audit log
+    MonitoringRead,
+    /// Read rules, lists, reputations, signatures, protection state, webhooks and tenants
+    ConfigRead,
+    /// Acknowledge and resolve alerts, and manage silences
+    AlertsManage,
+    /// Change lists, reputations, lockouts, attack mode and baselines, and
+    /// reset detection and rate limits
+    MitigationsManage,
+    /// Enable and disable rules
+    RulesToggle,
+    /// Create, change and delete rules, alert rules, signatures, webhooks and tenants
+    ConfigWrite,
+    /// Manage API keys and their quotas
+    KeysManage,
+}
+
+impl Permission {
+    /// Name of the permission, as reported to callers lacking it
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::DecisionsReport => "decisions:report",
+            Permission::AnalyticsRead => "analytics:read",
+            Permission::MonitoringRead => "monitoring:read",
+            Permission::ConfigRead => "config:read",
+            Permission::AlertsManage => "alerts:manage",
+            Permission::MitigationsManage => "mitigations:manage",
+            Permission::RulesToggle => "rules:toggle",
+            Permission::ConfigWrite => "config:write",
+            Permission::KeysManage => "keys:manage",
+        }
+    }
+
+    /// Least role granted the permission
+    pub fn role(self) -> Role {
+        match self {
+            Permission::DecisionsReport => Role::Proxy,
+            Permission::AnalyticsRead | Permission::MonitoringRead => Role::Viewer,
+            Permission::ConfigRead
+            | Permission::AlertsManage
+            | Permission::MitigationsManage
+            | Permission::RulesToggle => Role::Operator,
+            Permission::ConfigWrite | Permission::KeysManage => Role::Admin,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Holder of the key a request was made with
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub name: String,
+}
+
+/// Permission a request needs, or `None` if it needs no key
+///
+/// `pattern` is the route the request matched, such as
+/// `/api/v1/rules/{id}/enable`; requests matching none need the strictest
+/// permission.
+pub fn required_permission(method: &Method, pattern: Option<&str>) -> Option<Permission> {
+    let Some(path) = pattern.and_then(|pattern| pattern.strip_prefix("/api/v1")) else {
+        return Some(Permission::KeysManage);
+    };
+    let mut segments = path.trim_matches('/').split('/');
+    let first = segments.next().unwrap_or_default();
+    let second = segments.next();
+    let third = segments.next();
+    let read = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+
+    let permission = match (first, second) {
+        ("rate-limit" | "ddos", Some(_)) if *method == Method::DELETE => Permission::MitigationsManage,
+        // Decision endpoints that change the state of any client the caller names
+        ("rate-limit", None) if !read => Permission::DecisionsReport,
+        ("concurrency", Some("release")) | ("connections" | "responses", _) | ("login", Some("result")) => {
+            Permission::DecisionsReport
+        }
+        // Decision endpoints called for traffic
+        ("health" | "openapi.json" | "docs" | "rate-limit" | "concurrency" | "check" | "ddos-check" | "authorize" | "challenge", _)
+        | ("login", Some("attempt"))
+        | ("blocklist", Some("check")) => return None,
+        ("analytics" | "stream" | "attacks" | "baselines", _) if read => Permission::AnalyticsRead,
+        ("monitoring" | "audit-log", _) if read => Permission::MonitoringRead,
+        ("monitoring", Some("alerts" | "silences")) => Permission::AlertsManage,
+        ("quotas", _) => Permission::KeysManage,
+        ("rules", Some("test")) => Permission::ConfigRead,
+        ("rules", Some(_)) if !read && matches!(third, Some("enable" | "disable")) => Permission::RulesToggle,
+        ("allowlist" | "blocklist" | "reputation" | "login" | "protection" | "baselines", _) if !read => Permission::MitigationsManage,
+        ("tenants", Some(_)) if !read && third == Some("blocklist") => Permission::MitigationsManage,
+        ("rules" | "monitoring" | "scanners" | "webhooks" | "tenants" | "allowlist" | "blocklist" | "reputation" | "protection", _)
+            if read =>
+        {
+            Permission::ConfigRead
+        }
+        ("rules" | "monitoring" | "scanners" | "webhooks" | "tenants", _) => Permission::ConfigWrite,
+        // Routes added without a permission are kept to admins until given one
+        _ => Permission::KeysManage,
+    };
+    Some(permission)
+}
+
+/// Route a request is dispatched to
+///
+/// The router matches the percent-decoded path, so the pattern is found
+/// the same way rather than from the raw path.
+fn route_pattern(req: &ServiceRequest) -> Option<String> {
+    req.resource_map().match_pattern(req.match_info().as_str())
+}
+
+/// Key matching the one a request carries
+fn find_key<'a>(keys: &'a [AccessKey], req: &ServiceRequest) -> Option<&'a AccessKey> {
+    let headers = req.headers();
+    let presented = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get("X-API-Key").and_then(|value| value.to_str().ok()))?
+        .trim();
+    keys.iter()
+        .find(|key| !key.key.is_empty() && constant_time_eq(key.key.as_bytes(), presented.as_bytes()))
+}
+
+/// Let a request through only if its key grants the permission it needs
+///
+/// The caller is added to the request extensions for handlers to record.
+pub async fn check_access(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(auth) = req.app_data::<web::Data<ApiState>>().map(|state| state.config.auth.clone()) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let permission = required_permission(req.method(), route_pattern(&req).as_deref());
+    let Some(permission) = permission.filter(|_| auth.enabled) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let Some(key) = find_key(&auth.keys, &req) else {
+        let mut response = HttpResponse::from(ApiError::unauthorized("a valid API key is required"));
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        return Ok(req.into_response(response));
+    };
+    if key.role < permission.role() {
+        let response = ApiError::forbidden(format!("missing permission {}", permission))
+            .with_details(json!({ "permission": permission.name() }));
+        return Ok(req.into_response(HttpResponse::from(response)));
+    }
+
+    req.extensions_mut().insert(Caller { name: key.name.clone() });
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::App;
+
+    #[test]
+    fn test_required_permission() {
+        let cases = [
+            (Method::GET, "/api/v1/health", None),
+            (Method::GET, "/api/v1/openapi.json", None),
+            (Method::POST, "/api/v1/rate-limit", Some(Permission::DecisionsReport)),
+            (Method::GET, "/api/v1/rate-limit/status", None),
+            (Method::POST, "/api/v1/check", None),
+            (Method::GET, "/api/v1/blocklist/check/{ip}", None),
+            (Method::POST, "/api/v1/login/attempt", None),
+            (Method::POST, "/api/v1/login/result", Some(Permission::DecisionsReport)),
+            (Method::POST, "/api/v1/concurrency/acquire", None),
+            (Method::POST, "/api/v1/concurrency/release", Some(Permission::DecisionsReport)),
+            (Method::POST, "/api/v1/connections/report", Some(Permission::DecisionsReport)),
+            (Method::POST, "/api/v1/responses/report", Some(Permission::DecisionsReport)),
+            (Method::GET, "/api/v1/analytics/top/{dimension}", Some(Permission::AnalyticsRead)),
+            (Method::GET, "/api/v1/audit-log", Some(Permission::MonitoringRead)),
+            (Method::POST, "/api/v1/monitoring/alerts/{id}/acknowledge", Some(Permission::AlertsManage)),
+            (Method::POST, "/api/v1/monitoring/alert-rules", Some(Permission::ConfigWrite)),
+            (Method::GET, "/api/v1/rules", Some(Permission::ConfigRead)),
+            (Method::POST, "/api/v1/rules", Some(Permission::ConfigWrite)),
+            (Method::DELETE, "/api/v1/rules/{id}", Some(Permission::ConfigWrite)),
+            (Method::PATCH, "/api/v1/rules/{id}", Some(Permission::ConfigWrite)),
+            (Method::POST, "/api/v1/rules/{id}/disable", Some(Permission::RulesToggle)),
+            (Method::DELETE, "/api/v1/baselines/{metric:.*}", Some(Permission::MitigationsManage)),
+            (Method::DELETE, "/api/v1/login/lockouts", Some(Permission::MitigationsManage)),
+            (Method::DELETE, "/api/v1/rate-limit/{key:.*}", Some(Permission::MitigationsManage)),
+            (Method::DELETE, "/api/v1/ddos/{ip}", Some(Permission::MitigationsManage)),
+            (Method::GET, "/api/v1/quotas", Some(Permission::KeysManage)),
+            (Method::POST, "/api/v1/tenants", Some(Permission::ConfigWrite)),
+            (Method::POST, "/api/v1/tenants/{id}/blocklist", Some(Permission::MitigationsManage)),
+            (Method::GET, "/api/v1/unknown", Some(Permission::KeysManage)),
+        ];
+        for (method, pattern, expected) in cases {
+            assert_eq!(required_permission(&method, Some(pattern)), expected, "{} {}", method, pattern);
+        }
+        assert_eq!(required_permission(&Method::GET, None), Some(Permission::KeysManage));
+
+        assert!(Role::Operator >= Permission::AlertsManage.role());
+        assert!(Role::Operator >= Permission::RulesToggle.role());
+        assert!(Role::Viewer < Permission::ConfigRead.role());
+        assert!(Role::Proxy >= Permission::DecisionsReport.role());
+        assert!(Role::Proxy < Permission::AnalyticsRead.role());
+        assert!(Role::Operator < Permission::KeysManage.role());
+    }
+
+    #[actix_web::test]
+    async fn test_route_pattern() {
+        async fn pattern<B>(req: ServiceRequest, _: Next<B>) -> Result<ServiceResponse<BoxBody>, Error> {
+            let pattern = route_pattern(&req).unwrap_or_default();
+            Ok(req.into_response(HttpResponse::Ok().body(pattern)))
+        }
+        let app = actix_web::test::init_service(App::new().wrap(from_fn(pattern)).configure(crate::api::config)).await;
+        let cases = [
+            ("/api/v1/%71uotas", "/api/v1/quotas"),
+            ("/api/v1/rules/r1/%65nable", "/api/v1/rules/{id}/enable"),
+            ("/api/v1/blocklist/10.0.0.0%2F8", "/api/v1/blocklist/{target:.*}"),
+            ("/api/v1/unknown", ""),
+        ];
+        for (path, expected) in cases {
+            let body = actix_web::test::call_and_read_body(&app, actix_web::test::TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(body, expected, "{}", path);
+        }
+    }
+}
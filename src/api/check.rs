@@ -0,0 +1,340 @@
+//! Unified decision endpoint.
+//!
+//! `POST /api/v1/check` runs a request through every layer in order:
+//! allowlist, blocklist, rate limiting, then the honeypot, attack mode,
+//! scanner detection, rules and the DDoS detector. It answers with a single
+//! action, the status the client should get, and the reason each layer gave,
+//! so callers don't have to reconcile `/rate-limit` and `/ddos-check`.
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use crate::core::ddos_detector::DetectionType;
+use crate::core::Mitigation;
+use super::error::{FieldErrors, Validate};
+use super::headers::{insert_rate_limit_headers, RateLimitHeaderValues};
+use super::{ddos_decision, rate_limit_decision, resolve_tenant, ApiState, DdosCheckRequest, DdosCheckResponse, RateLimitResponse};
+
+/// Unified check request
+#[derive(Deserialize)]
+pub struct CheckRequest {
+    #[serde(flatten)]
+    request: DdosCheckRequest,
+    /// Explicit request cost, overriding the configured per-path cost
+    cost: Option<u32>,
+    /// API key to charge against its daily/monthly quota
+    api_key: Option<String>,
+}
+
+impl Validate for CheckRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        self.request.check(errors);
+        errors.check(self.cost != Some(0), "cost", "must be positive");
+    }
+}
+
+/// What the caller should do with the request
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CheckAction {
+    /// Serve the request
+    Allow,
+    /// Reject the request as rate limited
+    RateLimit {
+        /// Seconds until the client may retry
+        retry_after: u64,
+    },
+    /// Reject the request
+    Block,
+    /// Serve a challenge instead of the response
+    Challenge,
+    /// Redirect the client
+    Redirect { url: String, status: u16 },
+}
+
+impl CheckAction {
+    /// Status the client should be answered with
+    pub fn status(&self) -> u16 {
+        match self {
+            CheckAction::Allow => 200,
+            CheckAction::RateLimit { .. } => 429,
+            CheckAction::Block => 403,
+            CheckAction::Challenge => 401,
+            CheckAction::Redirect { status, .. } => *status,
+        }
+    }
+}
+
+impl From<Option<Mitigation>> for CheckAction {
+    fn from(mitigation: Option<Mitigation>) -> Self {
+        match mitigation {
+            None => CheckAction::Allow,
+            Some(Mitigation::Block) => CheckAction::Block,
+            Some(Mitigation::Challenge) => CheckAction::Challenge,
+            Some(Mitigation::Redirect { url, status }) => CheckAction::Redirect { url, status },
+        }
+    }
+}
+
+/// Layer a request goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckLayer {
+    Allowlist,
+    Blocklist,
+    RateLimit,
+    Honeypot,
+    AttackMode,
+    Scanner,
+    Rules,
+    DdosDetector,
+    Escalation,
+}
+
+/// What a layer made of the request
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckReason {
+    pub layer: CheckLayer,
+    /// Whether the layer acted on the request
+    pub matched: bool,
+    pub detail: String,
+}
+
+impl CheckReason {
+    fn new(layer: CheckLayer, matched: bool, detail: impl Into<String>) -> Self {
+        Self {
+            layer,
+            matched,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Unified check response
+#[derive(Serialize)]
+pub struct CheckResponse {
+    pub action: CheckAction,
+    /// Status the client should be answered with
+    pub status: u16,
+    /// Layers the request went through, in order, up to the deciding one
+    pub reasons: Vec<CheckReason>,
+    pub rate_limit: RateLimitResponse,
+    /// Outcome of the layers after rate limiting, unless it stopped the request
+    pub ddos: Option<DdosCheckResponse>,
+}
+
+/// Unified decision endpoint
+///
+/// Always answers 200 with the decision in the body, along with the rate
+/// limit headers unless the client is allowlisted.
+pub async fn check_request(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    req: web::Json<CheckRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let CheckRequest { mut request, cost, api_key } = req.into_inner();
+    request.resolve_ip(&state);
+    let tenant = match resolve_tenant(&state, &http_req, api_key.as_deref()).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.into(),
+    };
+    request.tenant = tenant.as_ref().map(|tenant| tenant.id.clone());
+
+    let cost = cost.unwrap_or_else(|| state.config.rate_limit.cost_for_path(&request.path));
+    let rate_limit = rate_limit_decision(&state, &request.ip, None, &request.path, cost, api_key.as_deref(), tenant.as_ref()).await;
+    let path = request.path.clone();
+    let ddos = if rate_limit.allowlisted {
+        None
+    } else if rate_limit.blocked.is_some() || !rate_limit.allowed {
+        // Requests that get past rate limiting are counted by the DDoS check
+        state.traffic.record_request(&request.ip, &request.path, &request.user_agent, request.request_size);
+        if rate_limit.blocked.is_some() {
+            state.traffic.record_block(&request.ip);
+        }
+        None
+    } else {
+        Some(ddos_decision(&state, request).await)
+    };
+
+    let (action, reasons) = decide(&rate_limit, ddos.as_ref());
+    let mut builder = HttpResponse::Ok();
+    if !rate_limit.allowlisted {
+        insert_rate_limit_headers(
+            &mut builder,
+            &state.config.rate_limit.headers,
+            &path,
+            &RateLimitHeaderValues {
+                limit: rate_limit.limit,
+                remaining: rate_limit.remaining,
+                reset: rate_limit.reset,
+                rejected: matches!(action, CheckAction::RateLimit { .. }),
+            },
+        );
+    }
+    builder.json(CheckResponse {
+        status: action.status(),
+        action,
+        reasons,
+        rate_limit,
+        ddos,
+    })
+}
+
+/// The action for a request, and the reasons of the layers it went through
+fn decide(rate_limit: &RateLimitResponse, ddos: Option<&DdosCheckResponse>) -> (CheckAction, Vec<CheckReason>) {
+    let mut reasons = Vec::new();
+    if rate_limit.allowlisted {
+        reasons.push(CheckReason::new(CheckLayer::Allowlist, true, "client is allowlisted"));
+        return (CheckAction::Allow, reasons);
+    }
+    reasons.push(CheckReason::new(CheckLayer::Allowlist, false, "client is not allowlisted"));
+
+    if let Some(entry) = &rate_limit.blocked {
+        reasons.push(CheckReason::new(CheckLayer::Blocklist, true, format!("{} is blocked: {}", entry.target, entry.reason)));
+        return (CheckAction::Block, reasons);
+    }
+    reasons.push(CheckReason::new(CheckLayer::Blocklist, false, "client is not blocked"));
+
+    if !rate_limit.allowed {
+        let detail = match &rate_limit.penalty {
+            Some(penalty) if penalty.banned_for_seconds > 0 => {
+                format!("client is banned for {}s after {} offenses", penalty.banned_for_seconds, penalty.offense_count)
+            }
+            _ => format!("limit of {} exceeded", rate_limit.limit),
+        };
+        reasons.push(CheckReason::new(CheckLayer::RateLimit, true, detail));
+        return (CheckAction::RateLimit { retry_after: rate_limit.reset }, reasons);
+    }
+    let detail = if rate_limit.shadowed {
+        format!("limit of {} exceeded, let through in shadow mode", rate_limit.limit)
+    } else {
+        format!("{} of {} left", rate_limit.remaining, rate_limit.limit)
+    };
+    reasons.push(CheckReason::new(CheckLayer::RateLimit, false, detail));
+
+    let Some(ddos) = ddos else {
+        return (CheckAction::Allow, reasons);
+    };
+    let action = CheckAction::from(ddos.mitigation.clone());
+    match ddos.detection_type {
+        // The DDoS check found the client blocked since rate limiting looked
+        Some(DetectionType::Blocklist) => {
+            reasons.retain(|reason| reason.layer == CheckLayer::Allowlist);
+            reasons.push(CheckReason::new(CheckLayer::Blocklist, true, "client is blocked"));
+            return (action, reasons);
+        }
+        Some(DetectionType::Honeypot) => {
+            reasons.push(CheckReason::new(CheckLayer::Honeypot, true, "client requested a honeypot path"));
+            return (action, reasons);
+        }
+        Some(DetectionType::AttackMode) => {
+            reasons.push(CheckReason::new(CheckLayer::AttackMode, true, "attack mode blocks the client"));
+            return (action, reasons);
+        }
+        _ => (),
+    }
+
+    if let Some(scanner) = &ddos.scanner {
+        let detail = match &scanner.signature {
+            Some(signature) => format!("matched scanner signature {}", signature),
+            None => format!("scanner behavior: {}", scanner.classification.vector),
+        };
+        reasons.push(CheckReason::new(CheckLayer::Scanner, true, detail));
+    }
+    let detail = match ddos.rule_actions.len() {
+        0 => "no rule matched".to_string(),
+        count => format!("{} rule actions", count),
+    };
+    reasons.push(CheckReason::new(CheckLayer::Rules, !ddos.rule_actions.is_empty(), detail));
+    let detail = match &ddos.classification {
+        Some(classification) => format!("{} ({}% confidence)", classification.vector, classification.confidence),
+        None => "no attack detected".to_string(),
+    };
+    reasons.push(CheckReason::new(CheckLayer::DdosDetector, ddos.classification.is_some(), detail));
+
+    // Under attack mode or escalation, everyone is challenged
+    if action == CheckAction::Challenge && ddos.detection_type.is_none() && ddos.rule_actions.is_empty() {
+        reasons.push(match ddos.attack_mode {
+            true => CheckReason::new(CheckLayer::AttackMode, true, "attack mode challenges all clients"),
+            false => CheckReason::new(CheckLayer::Escalation, true, "escalation challenges all clients"),
+        });
+    }
+
+    (action, reasons)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit(allowed: bool) -> RateLimitResponse {
+        RateLimitResponse {
+            allowed,
+            limit: 100,
+            remaining: if allowed { 99 } else { 0 },
+            reset: 30,
+            penalty: None,
+            quota: None,
+            shadowed: false,
+            allowlisted: false,
+            blocked: None,
+        }
+    }
+
+    fn layers(reasons: &[CheckReason]) -> Vec<(CheckLayer, bool)> {
+        reasons.iter().map(|reason| (reason.layer, reason.matched)).collect()
+    }
+
+    #[test]
+    fn test_decide() {
+        let (action, reasons) = decide(&RateLimitResponse { allowlisted: true, ..rate_limit(true) }, None);
+        assert_eq!(action, CheckAction::Allow);
+        assert_eq!(layers(&reasons), [(CheckLayer::Allowlist, true)]);
+
+        // Rate limiting stops the request before rules and detection
+        let (action, reasons) = decide(&rate_limit(false), None);
+        assert_eq!(action, CheckAction::RateLimit { retry_after: 30 });
+        assert_eq!(action.status(), 429);
+        assert_eq!(
+            layers(&reasons),
+            [(CheckLayer::Allowlist, false), (CheckLayer::Blocklist, false), (CheckLayer::RateLimit, true)]
+        );
+
+        let ddos = DdosCheckResponse {
+            is_under_attack: false,
+            detection_type: None,
+            classification: None,
+            rule_actions: Vec::new(),
+            mitigation: Some(Mitigation::Challenge),
+            aggregate_detection: None,
+            bot: None,
+            scanner: None,
+            attack_mode: true,
+        };
+        let (action, reasons) = decide(&rate_limit(true), Some(&ddos));
+        assert_eq!(action, CheckAction::Challenge);
+        assert_eq!(action.status(), 401);
+        assert_eq!(
+            layers(&reasons),
+            [
+                (CheckLayer::Allowlist, false),
+                (CheckLayer::Blocklist, false),
+                (CheckLayer::RateLimit, false),
+                (CheckLayer::Rules, false),
+                (CheckLayer::DdosDetector, false),
+                (CheckLayer::AttackMode, true),
+            ]
+        );
+
+        let honeypot = DdosCheckResponse {
+            detection_type: Some(DetectionType::Honeypot),
+            mitigation: Some(Mitigation::Block),
+            ..ddos
+        };
+        let (action, reasons) = decide(&rate_limit(true), Some(&honeypot));
+        assert_eq!(action, CheckAction::Block);
+        assert_eq!(reasons.last().map(|reason| reason.layer), Some(CheckLayer::Honeypot));
+    }
+}
@@ -0,0 +1,205 @@
+//! Error responses and request validation.
+//!
+//! Every error the API answers with has a JSON body of the form
+//! `{"code": ..., "message": ..., "details": ...}`: `code` is a stable
+//! identifier to match on, `message` explains it, and `details` carries
+//! structured context such as the fields that failed validation, or `null`.
+//! Requests are validated before they are acted on; see [`Validate`].
+
+use std::fmt;
+use std::net::IpAddr;
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Error answered to an API request
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    details: Value,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: Value::Null,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    /// Request whose fields failed validation, listed in the details
+    pub fn validation(errors: Vec<FieldError>) -> Self {
+        let message = errors
+            .iter()
+            .map(|error| format!("{} {}", error.field, error.message))
+            .collect::<Vec<_>>()
+            .join("; ");
+        Self::new(StatusCode::BAD_REQUEST, "validation_failed", message).with_details(json!({ "fields": errors }))
+    }
+
+    /// Missing resource, such as `"Rule"`
+    pub fn not_found(resource: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", format!("{} not found", resource))
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, "conflict", message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    /// Dependency that is down, such as Redis
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", message)
+    }
+
+    /// Failure on our side; the cause is logged, not answered
+    pub fn internal() -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", "Internal server error")
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(self)
+    }
+}
+
+impl From<ApiError> for HttpResponse {
+    fn from(error: ApiError) -> Self {
+        error.error_response()
+    }
+}
+
+/// Answer bodies that can't be read or deserialized with an `ApiError`
+pub fn json_error(error: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::new(error.status_code(), "invalid_body", error.to_string()).into()
+}
+
+/// Answer query strings that can't be deserialized with an `ApiError`
+pub fn query_error(error: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_query", error.to_string()).into()
+}
+
+/// Answer path parameters that can't be deserialized with an `ApiError`
+pub fn path_error(error: PathError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_path", error.to_string()).into()
+}
+
+/// Problem with one field of a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Problems found validating a request
+#[derive(Debug, Default)]
+pub struct FieldErrors(Vec<FieldError>);
+
+impl FieldErrors {
+    /// Record a problem with `field` unless `valid`
+    pub fn check(&mut self, valid: bool, field: impl Into<String>, message: impl Into<String>) {
+        if !valid {
+            self.0.push(FieldError {
+                field: field.into(),
+                message: message.into(),
+            });
+        }
+    }
+
+    /// Record a problem with `field` unless it is an IP address
+    pub fn check_ip(&mut self, ip: &str, field: impl Into<String>) {
+        self.check(ip.parse::<IpAddr>().is_ok(), field, "must be an IP address");
+    }
+
+    pub fn into_result(self) -> Result<(), ApiError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::validation(self.0))
+        }
+    }
+}
+
+/// Request checked before it is acted on
+pub trait Validate {
+    /// Record the problems with the request's fields
+    fn check(&self, errors: &mut FieldErrors);
+
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut errors = FieldErrors::default();
+        self.check(&mut errors);
+        errors.into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    struct Range {
+        start: u64,
+        end: u64,
+        ip: &'static str,
+    }
+
+    impl Validate for Range {
+        fn check(&self, errors: &mut FieldErrors) {
+            errors.check(self.start <= self.end, "start_time", "must be no later than end_time");
+            errors.check_ip(self.ip, "ip");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_api_error() {
+        assert!(Range { start: 1, end: 2, ip: "192.0.2.1" }.validate().is_ok());
+
+        let error = Range { start: 2, end: 1, ip: "nope" }.validate().unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(error.error_response().into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "validation_failed");
+        assert_eq!(body["message"], "start_time must be no later than end_time; ip must be an IP address");
+        assert_eq!(body["details"]["fields"][1], json!({ "field": "ip", "message": "must be an IP address" }));
+
+        let body = to_bytes(HttpResponse::from(ApiError::not_found("Rule")).into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({ "code": "not_found", "message": "Rule not found", "details": null })
+        );
+    }
+}
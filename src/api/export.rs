@@ -0,0 +1,147 @@
+//! Event export endpoint.
+//!
+//! Streams the analytics events of a time range as CSV or NDJSON, reading
+//! them a few minutes at a time so that long ranges are never held in
+//! memory whole.
+
+use actix_web::{web, HttpResponse, Responder};
+use bytes::Bytes;
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use crate::core::analytics::{AnalyticsError, Event};
+use super::ApiState;
+use super::error::ApiError;
+
+/// Seconds of events read at a time
+const EXPORT_CHUNK_SECONDS: u64 = 300;
+
+const CSV_HEADER: &str = "id,timestamp,event_type,source,ip,data\n";
+
+/// Format events are exported in
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Event export request
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: ExportFormat,
+    /// Start of the range as a Unix timestamp; an hour before `to` by default
+    from: Option<u64>,
+    /// End of the range as a Unix timestamp; now by default
+    to: Option<u64>,
+}
+
+/// A CSV field, quoted when it needs to be
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// An event as a line of the export
+fn format_event(event: &Event, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Ndjson => format!("{}\n", serde_json::to_string(event).unwrap_or_default()),
+        ExportFormat::Csv => {
+            let fields = [
+                event.id.clone(),
+                event.timestamp.to_rfc3339(),
+                event.event_type.name(),
+                event.source.clone(),
+                event.client().unwrap_or_default().to_string(),
+                serde_json::to_string(&event.data).unwrap_or_default(),
+            ];
+            let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            format!("{}\n", fields.join(","))
+        }
+    }
+}
+
+/// Export events endpoint
+///
+/// The response ends early if reading events fails partway.
+pub async fn export_events(
+    state: web::Data<ApiState>,
+    query: web::Query<ExportQuery>,
+) -> impl Responder {
+    let to = query.to.unwrap_or_else(|| Utc::now().timestamp().max(0) as u64);
+    let from = query.from.unwrap_or(to.saturating_sub(3600));
+    if from > to {
+        return ApiError::bad_request("from must be no later than to").into();
+    }
+
+    let format = query.format;
+    let analytics = state.analytics.clone();
+    let chunks = stream::unfold(Some(from), move |start| {
+        let analytics = analytics.clone();
+        async move {
+            let start = start?;
+            let end = start.saturating_add(EXPORT_CHUNK_SECONDS - 1).min(to);
+            let events = analytics.get_events(start, end, None).await;
+            let next = match &events {
+                Ok(_) => (end < to).then_some(end + 1),
+                Err(e) => {
+                    log::error!("Failed to export events: {}", e);
+                    None
+                }
+            };
+            let lines = events.map(|events| {
+                Bytes::from(events.iter().map(|event| format_event(event, format)).collect::<String>())
+            });
+            Some((lines, next))
+        }
+    });
+    let header = (format == ExportFormat::Csv).then(|| Ok::<_, AnalyticsError>(Bytes::from_static(CSV_HEADER.as_bytes())));
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"events-{}-{}.{}\"", from, to, format.extension()),
+        ))
+        .streaming(stream::iter(header).chain(chunks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::analytics::EventType;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_format_event() {
+        let data = HashMap::from([("ip".to_string(), serde_json::json!("192.0.2.1"))]);
+        let event = Event::new(EventType::BlockedRequest, "rate, limiter", data);
+
+        let line = format_event(&event, ExportFormat::Csv);
+        assert!(line.starts_with(&format!("{},", event.id)));
+        assert!(line.ends_with(",BlockedRequest,\"rate, limiter\",192.0.2.1,\"{\"\"ip\"\":\"\"192.0.2.1\"\"}\"\n"));
+
+        let line = format_event(&event, ExportFormat::Ndjson);
+        let parsed: Event = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(parsed.id, event.id);
+    }
+}
@@ -0,0 +1,98 @@
+//! Rate limit response headers.
+//!
+//! Builds the IETF draft `RateLimit-*` headers, their legacy `X-RateLimit-*`
+//! counterparts and `Retry-After`, so that proxies consulting the service
+//! can pass them through to clients unchanged.
+
+use actix_web::HttpResponseBuilder;
+use crate::models::RateLimitHeadersConfig;
+use crate::utils::get_current_timestamp;
+
+/// Rate limit state to report in response headers
+pub struct RateLimitHeaderValues {
+    /// Quota per window
+    pub limit: u32,
+    /// Quota remaining in the current window
+    pub remaining: u32,
+    /// Seconds until the quota resets
+    pub reset: u64,
+    /// Whether the request was rejected
+    pub rejected: bool,
+}
+
+/// Collect the headers to emit for a request to `path`
+pub fn rate_limit_headers(
+    config: &RateLimitHeadersConfig,
+    path: &str,
+    values: &RateLimitHeaderValues,
+) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    if !config.enabled_for_path(path) {
+        return headers;
+    }
+
+    if config.standard {
+        headers.push(("RateLimit-Limit", values.limit.to_string()));
+        headers.push(("RateLimit-Remaining", values.remaining.to_string()));
+        headers.push(("RateLimit-Reset", values.reset.to_string()));
+    }
+
+    if config.legacy {
+        // Legacy headers conventionally carry the reset time as a Unix timestamp
+        headers.push(("X-RateLimit-Limit", values.limit.to_string()));
+        headers.push(("X-RateLimit-Remaining", values.remaining.to_string()));
+        headers.push(("X-RateLimit-Reset", (get_current_timestamp() + values.reset).to_string()));
+    }
+
+    if config.retry_after && values.rejected {
+        headers.push(("Retry-After", values.reset.max(1).to_string()));
+    }
+
+    headers
+}
+
+/// Add rate limit headers for a request to `path` to a response
+pub fn insert_rate_limit_headers(
+    builder: &mut HttpResponseBuilder,
+    config: &RateLimitHeadersConfig,
+    path: &str,
+    values: &RateLimitHeaderValues,
+) {
+    for header in rate_limit_headers(config, path, values) {
+        builder.insert_header(header);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let mut config = RateLimitHeadersConfig::default();
+        config.routes.insert("/internal".to_string(), false);
+
+        let values = RateLimitHeaderValues {
+            limit: 100,
+            remaining: 0,
+            reset: 30,
+            rejected: true,
+        };
+
+        let headers = rate_limit_headers(&config, "/api/items", &values);
+        assert!(headers.contains(&("RateLimit-Limit", "100".to_string())));
+        assert!(headers.contains(&("RateLimit-Remaining", "0".to_string())));
+        assert!(headers.contains(&("RateLimit-Reset", "30".to_string())));
+        assert!(headers.contains(&("Retry-After", "30".to_string())));
+        assert!(headers.iter().any(|(name, _)| *name == "X-RateLimit-Reset"));
+
+        // Disabled routes emit nothing
+        assert!(rate_limit_headers(&config, "/internal/jobs", &values).is_empty());
+
+        // Retry-After is only sent on rejections
+        let allowed = RateLimitHeaderValues { rejected: false, ..values };
+        assert!(!rate_limit_headers(&config, "/api/items", &allowed)
+            .iter()
+            .any(|(name, _)| *name == "Retry-After"));
+    }
+}
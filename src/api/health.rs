@@ -0,0 +1,239 @@
+//! Liveness and readiness probes.
+//!
+//! `GET /api/v1/health/live` answers as long as the server handles requests.
+//! `GET /api/v1/health/ready` also checks what serving traffic depends on:
+//! Redis, the heartbeats of the background tasks, the rules and the GeoIP
+//! databases. It answers 503 when any of them fails, so load balancers and
+//! Kubernetes stop routing to the instance until it recovers.
+
+use std::time::{Duration, Instant};
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
+use crate::models::HealthConfig;
+use super::ApiState;
+
+/// Outcome of a readiness check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    /// Redis answered, but slower than `redis_max_latency_ms`
+    Slow,
+    /// Redis didn't answer within `redis_timeout_ms`
+    Down,
+    /// A background task stopped beating
+    Stale,
+    /// Rules or GeoIP databases aren't loaded yet
+    Missing,
+    /// The dependency is turned off and not checked
+    Disabled,
+}
+
+impl CheckStatus {
+    /// Whether the instance can serve traffic with this outcome
+    pub fn ready(self) -> bool {
+        matches!(self, CheckStatus::Ok | CheckStatus::Disabled)
+    }
+}
+
+/// Redis connectivity
+#[derive(Serialize)]
+pub struct RedisCheck {
+    pub status: CheckStatus,
+    /// How long the ping took, unless it timed out
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Heartbeat of a background task
+#[derive(Serialize)]
+pub struct TaskHeartbeat {
+    pub name: String,
+    /// Seconds since the task last beat
+    pub age_seconds: i64,
+    pub status: CheckStatus,
+}
+
+/// Background task heartbeats
+#[derive(Serialize)]
+pub struct TasksCheck {
+    pub status: CheckStatus,
+    pub tasks: Vec<TaskHeartbeat>,
+}
+
+/// Rules cache
+#[derive(Serialize)]
+pub struct RulesCheck {
+    pub status: CheckStatus,
+}
+
+/// GeoIP databases
+#[derive(Serialize)]
+pub struct GeoIpCheck {
+    pub status: CheckStatus,
+    /// Configured databases that aren't loaded
+    pub missing: Vec<String>,
+}
+
+/// Readiness of each dependency
+#[derive(Serialize)]
+pub struct ReadinessChecks {
+    pub redis: RedisCheck,
+    pub tasks: TasksCheck,
+    pub rules: RulesCheck,
+    pub geoip: GeoIpCheck,
+}
+
+impl ReadinessChecks {
+    fn ready(&self) -> bool {
+        [self.redis.status, self.tasks.status, self.rules.status, self.geoip.status]
+            .into_iter()
+            .all(CheckStatus::ready)
+    }
+}
+
+/// Readiness probe response
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    /// `ready` or `not_ready`
+    pub status: &'static str,
+    pub checks: ReadinessChecks,
+}
+
+/// Liveness probe response
+#[derive(Serialize)]
+pub struct LivenessResponse {
+    pub status: &'static str,
+}
+
+/// Liveness probe
+pub async fn live() -> impl Responder {
+    HttpResponse::Ok().json(LivenessResponse { status: "ok" })
+}
+
+/// Readiness probe
+///
+/// Answers 200 when every dependency is ready and 503 otherwise, with the
+/// outcome of each check either way.
+pub async fn ready(state: web::Data<ApiState>) -> impl Responder {
+    let config = &state.config.monitoring;
+    let checks = ReadinessChecks {
+        redis: check_redis(&state, &config.health).await,
+        tasks: check_tasks(state.heartbeats.ages(), config.watchdog.heartbeat_timeout_seconds),
+        rules: RulesCheck {
+            status: match state.rule_engine.rules_loaded() {
+                true => CheckStatus::Ok,
+                false => CheckStatus::Missing,
+            },
+        },
+        geoip: check_geoip(&state).await,
+    };
+
+    let ready = checks.ready();
+    let mut builder = match ready {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    builder.json(ReadinessResponse {
+        status: if ready { "ready" } else { "not_ready" },
+        checks,
+    })
+}
+
+async fn check_redis(state: &ApiState, config: &HealthConfig) -> RedisCheck {
+    let started = Instant::now();
+    let mut conn = state.redis_pool.get();
+    let cmd = redis::cmd("PING");
+    let ping = cmd.query_async::<_, String>(&mut conn);
+    match tokio::time::timeout(Duration::from_millis(config.redis_timeout_ms), ping).await {
+        Ok(Ok(_)) => {
+            let latency = started.elapsed();
+            RedisCheck {
+                status: redis_status(latency, config),
+                latency_ms: Some(latency.as_millis() as u64),
+                error: None,
+            }
+        }
+        Ok(Err(e)) => RedisCheck {
+            status: CheckStatus::Down,
+            latency_ms: None,
+            error: Some(e.to_string()),
+        },
+        Err(_) => RedisCheck {
+            status: CheckStatus::Down,
+            latency_ms: None,
+            error: Some(format!("no reply within {}ms", config.redis_timeout_ms)),
+        },
+    }
+}
+
+/// Status of a Redis ping that answered after `latency`
+fn redis_status(latency: Duration, config: &HealthConfig) -> CheckStatus {
+    match latency > Duration::from_millis(config.redis_max_latency_ms) {
+        true => CheckStatus::Slow,
+        false => CheckStatus::Ok,
+    }
+}
+
+/// Check task heartbeats, given as seconds since each task's last beat
+fn check_tasks(ages: Vec<(String, i64)>, timeout_seconds: u64) -> TasksCheck {
+    let tasks: Vec<TaskHeartbeat> = ages
+        .into_iter()
+        .map(|(name, age_seconds)| TaskHeartbeat {
+            status: match age_seconds > timeout_seconds as i64 {
+                true => CheckStatus::Stale,
+                false => CheckStatus::Ok,
+            },
+            name,
+            age_seconds,
+        })
+        .collect();
+    let status = match tasks.iter().any(|task| task.status == CheckStatus::Stale) {
+        true => CheckStatus::Stale,
+        false => CheckStatus::Ok,
+    };
+    TasksCheck { status, tasks }
+}
+
+async fn check_geoip(state: &ApiState) -> GeoIpCheck {
+    if !state.geoip.enabled() {
+        return GeoIpCheck {
+            status: CheckStatus::Disabled,
+            missing: Vec::new(),
+        };
+    }
+    let missing = state.geoip.missing_databases().await;
+    GeoIpCheck {
+        status: if missing.is_empty() { CheckStatus::Ok } else { CheckStatus::Missing },
+        missing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redis_status() {
+        let config = HealthConfig {
+            redis_timeout_ms: 1000,
+            redis_max_latency_ms: 250,
+        };
+        assert_eq!(redis_status(Duration::from_millis(3), &config), CheckStatus::Ok);
+        assert_eq!(redis_status(Duration::from_millis(250), &config), CheckStatus::Ok);
+        assert_eq!(redis_status(Duration::from_millis(400), &config), CheckStatus::Slow);
+        assert!(!CheckStatus::Slow.ready());
+        assert!(CheckStatus::Disabled.ready());
+    }
+
+    #[test]
+    fn test_check_tasks() {
+        let check = check_tasks(Vec::new(), 120);
+        assert_eq!(check.status, CheckStatus::Ok);
+
+        let check = check_tasks(vec![("Analytics".to_string(), 5), ("Traffic".to_string(), 300)], 120);
+        assert_eq!(check.status, CheckStatus::Stale);
+        let statuses: Vec<_> = check.tasks.iter().map(|task| (task.name.as_str(), task.status)).collect();
+        assert_eq!(statuses, [("Analytics", CheckStatus::Ok), ("Traffic", CheckStatus::Stale)]);
+    }
+}
@@ -4,22 +4,100 @@
 //! including rate limit management, DDoS protection configuration,
 //! rule engine management, analytics, and monitoring.
 
-use actix_web::{web, HttpResponse, Responder, HttpRequest};
+mod alert_rules;
+mod auth;
+mod check;
+mod error;
+mod export;
+mod headers;
+mod health;
+mod openapi;
+mod silences;
+mod stream;
+mod tenants;
+mod webhooks;
+
+use actix_web::{web, HttpMessage, HttpResponse, Responder, HttpRequest};
+use actix_web::middleware::from_fn;
+use base64::Engine;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tracing::Instrument;
 use uuid::Uuid;
 
-use crate::core::{RateLimiter, DdosDetector, RuleEngine, Rule, Analytics, Monitoring, RuleCondition, RuleAction};
-use crate::core::analytics::EventType;
-use crate::models::Config;
+use crate::core::{RedisPool, Degradation, LiveEvents, Allowlist, Blocklist, GeoIp, Reputation, AttackTracker, BaselineLearner, RateLimiter, ConcurrencyLimiter, QuotaManager, DdosDetector, RuleEngine, Rule, Analytics, Monitoring, RuleCondition, RuleAction, RequestContext, Mitigation};
+use crate::core::alert_rules::AlertRules;
+use crate::core::silences::Silences;
+use crate::core::tenants::{Tenant, Tenants};
+use crate::core::allowlist::{AllowlistError, AllowlistKind};
+use crate::core::analytics::{Event, EventCursor, EventFilter, EventType};
+use crate::core::blocklist::{BlockEntry, BlocklistError};
+use crate::core::bot_detection::{BotDetector, BotScore};
+use crate::core::challenge::{safe_return_to, ChallengeManager};
+use crate::core::attack_mode::{AttackMode, AttackModeState};
+use crate::core::attack_reports::AttackReporter;
+use crate::core::escalation::Escalation;
+use crate::core::honeypot::Honeypot;
+use crate::core::login_protection::{LoginDecision, LoginProtection};
+use crate::core::monitoring::{AlertQuery, SystemMetrics, MAX_ALERT_QUERY_LIMIT};
+use crate::core::scanner::{ScanDetection, ScannerDetector, ScannerError};
+use crate::core::reputation::ReputationEvent;
+use crate::core::rule_engine::{self, ConflictPolicy, RuleQuery, RuleSet, MAX_RULE_QUERY_LIMIT};
+use crate::core::schedule::RuleSchedule;
+use crate::core::quota::{Quota, QuotaError, QuotaStatus};
+use crate::core::concurrency_limiter::ConcurrencyError;
+use crate::core::degradation::Subsystem;
+use crate::core::ddos_detector::{AggregateDetection, AttackCategory, Classification, ConnectionStats, DdosDetectionError, DetectionType};
+use crate::core::rate_limiter::{PenaltyState, RateLimitError, RateLimitUsage};
+use crate::core::traffic::{self, Point, TopDimension, TrafficMetric, TrafficStats};
+use crate::core::trusted_proxies::TrustedProxies;
+use crate::core::tls_fingerprint::{ClientHello, FingerprintKind, FingerprintTracker};
+use crate::core::watchdog::Heartbeats;
+use crate::core::webhooks::Webhooks;
+use crate::storage::{Archive, StorageError};
+use crate::models::{Config, EscalationLevel, FailurePolicy, LoginAction};
+use crate::utils::parse_network;
+use error::{ApiError, FieldErrors, Validate};
+use headers::{insert_rate_limit_headers, RateLimitHeaderValues};
 
 pub struct ApiState {
-    pub rate_limiter: Arc<Mutex<RateLimiter>>,
-    pub ddos_detector: Arc<Mutex<DdosDetector>>,
-    pub rule_engine: Arc<Mutex<RuleEngine>>,
-    pub analytics: Arc<Mutex<Analytics>>,
-    pub monitoring: Arc<Mutex<Monitoring>>,
+    pub allowlist: Allowlist,
+    pub blocklist: Blocklist,
+    pub geoip: GeoIp,
+    pub trusted_proxies: TrustedProxies,
+    pub reputation: Reputation,
+    pub attacks: AttackTracker,
+    pub attack_reports: AttackReporter,
+    pub baseline: BaselineLearner,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub concurrency_limiter: Arc<ConcurrencyLimiter>,
+    pub quota_manager: Arc<QuotaManager>,
+    pub ddos_detector: Arc<DdosDetector>,
+    pub fingerprints: FingerprintTracker,
+    pub bots: BotDetector,
+    pub honeypot: Honeypot,
+    pub scanners: ScannerDetector,
+    pub logins: LoginProtection,
+    pub attack_mode: AttackMode,
+    pub escalation: Escalation,
+    pub challenges: ChallengeManager,
+    pub rule_engine: Arc<RuleEngine>,
+    pub analytics: Arc<Analytics>,
+    pub monitoring: Arc<Monitoring>,
+    pub alert_rules: AlertRules,
+    pub silences: Silences,
+    pub live_events: LiveEvents,
+    pub traffic: TrafficStats,
+    pub webhooks: Webhooks,
+    pub tenants: Tenants,
+    pub redis_pool: RedisPool,
+    pub degradation: Degradation,
+    /// Last beats of the supervised background tasks
+    pub heartbeats: Heartbeats,
+    /// PostgreSQL archive, if enabled
+    pub archive: Option<Archive>,
     pub config: Config,
 }
 
@@ -27,19 +105,176 @@ pub struct ApiState {
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api/v1")
+            .wrap(from_fn(auth::check_access))
+            .app_data(web::JsonConfig::default().error_handler(error::json_error))
+            .app_data(web::QueryConfig::default().error_handler(error::query_error))
+            .app_data(web::PathConfig::default().error_handler(error::path_error))
             .service(web::resource("/health").route(web::get().to(health_check)))
+            .service(web::resource("/health/live").route(web::get().to(health::live)))
+            .service(web::resource("/health/ready").route(web::get().to(health::ready)))
+            .service(web::resource("/openapi.json").route(web::get().to(openapi::get_openapi)))
+            .service(web::resource("/docs").route(web::get().to(openapi::get_docs)))
+            .service(web::resource("/docs/{file}").route(web::get().to(openapi::get_docs_file)))
+            .service(
+                web::resource("/allowlist")
+                    .route(web::get().to(get_allowlist))
+                    .route(web::post().to(add_allowlist_entry)),
+            )
+            .service(web::resource("/allowlist/{value:.*}").route(web::delete().to(remove_allowlist_entry)))
+            .service(
+                web::resource("/blocklist")
+                    .route(web::get().to(get_blocklist))
+                    .route(web::post().to(add_blocklist_entry)),
+            )
+            .service(web::resource("/blocklist/check/{ip}").route(web::get().to(check_blocklist)))
+            .service(web::resource("/blocklist/{target:.*}").route(web::delete().to(remove_blocklist_entry)))
+            .service(
+                web::resource("/reputation/{ip}")
+                    .route(web::get().to(get_reputation))
+                    .route(web::post().to(adjust_reputation))
+                    .route(web::delete().to(reset_reputation)),
+            )
             .service(web::resource("/rate-limit").route(web::post().to(check_rate_limit)))
+            .service(web::resource("/rate-limit/status").route(web::get().to(get_rate_limit_status)))
+            .service(web::resource("/rate-limit/effective").route(web::get().to(get_effective_rate_limit)))
+            .service(web::resource("/rate-limit/{key:.*}").route(web::delete().to(reset_rate_limit)))
+            .service(
+                web::resource("/quotas")
+                    .route(web::get().to(get_quotas))
+                    .route(web::post().to(create_quota)),
+            )
+            .service(
+                web::resource("/quotas/{api_key}")
+                    .route(web::get().to(get_quota))
+                    .route(web::put().to(update_quota))
+                    .route(web::delete().to(delete_quota)),
+            )
+            .service(web::resource("/quotas/{api_key}/reset").route(web::post().to(reset_quota)))
+            .service(web::resource("/concurrency/acquire").route(web::post().to(acquire_concurrency)))
+            .service(web::resource("/concurrency/release").route(web::post().to(release_concurrency)))
+            .service(web::resource("/ddos/{ip}").route(web::delete().to(reset_ddos_detection)))
+            .service(web::resource("/check").route(web::post().to(check::check_request)))
             .service(web::resource("/ddos-check").route(web::post().to(check_ddos)))
-            .service(web::resource("/rules").route(web::get().to(get_rules)))
-            .service(web::resource("/rules").route(web::post().to(create_rule)))
-            .service(web::resource("/rules/{id}").route(web::get().to(get_rule)))
-            .service(web::resource("/rules/{id}").route(web::put().to(update_rule)))
-            .service(web::resource("/rules/{id}").route(web::delete().to(delete_rule)))
+            .service(web::resource("/authorize").route(web::get().to(authorize)))
+            .service(web::resource("/connections/report").route(web::post().to(report_connection)))
+            .service(web::resource("/responses/report").route(web::post().to(report_response)))
+            .service(
+                web::resource("/scanners/signatures")
+                    .route(web::get().to(get_scanner_signatures))
+                    .route(web::post().to(add_scanner_signature))
+                    .route(web::delete().to(remove_scanner_signature)),
+            )
+            .service(web::resource("/login/attempt").route(web::post().to(check_login_attempt)))
+            .service(web::resource("/login/result").route(web::post().to(report_login_result)))
+            .service(web::resource("/login/lockouts").route(web::delete().to(remove_login_lockout)))
+            .service(
+                web::resource("/protection/attack-mode")
+                    .route(web::get().to(get_attack_mode))
+                    .route(web::post().to(set_attack_mode)),
+            )
+            .service(web::resource("/protection/escalation").route(web::get().to(get_escalation)))
+            .service(web::resource("/challenge").route(web::get().to(get_challenge)))
+            .service(web::resource("/challenge/verify").route(web::post().to(verify_challenge)))
+            .service(web::resource("/attacks").route(web::get().to(get_attacks)))
+            .service(web::resource("/attacks/{id}").route(web::get().to(get_attack)))
+            .service(web::resource("/attacks/{id}/report").route(web::get().to(get_attack_report)))
+            .service(web::resource("/baselines").route(web::get().to(get_baselines)))
+            .service(
+                web::resource("/baselines/{metric:.*}")
+                    .route(web::get().to(get_baseline))
+                    .route(web::delete().to(reset_baseline)),
+            )
+            .service(
+                web::resource("/rules")
+                    .route(web::get().to(get_rules))
+                    .route(web::post().to(create_rule)),
+            )
+            .service(web::resource("/rules/test").route(web::post().to(test_rule)))
+            .service(web::resource("/rules/export").route(web::get().to(export_rules)))
+            .service(web::resource("/rules/import").route(web::post().to(import_rules)))
+            .service(web::resource("/rules/stats").route(web::get().to(get_all_rule_stats)))
+            .service(
+                web::resource("/rules/{id}")
+                    .route(web::get().to(get_rule))
+                    .route(web::put().to(update_rule))
+                    .route(web::patch().to(patch_rule))
+                    .route(web::delete().to(delete_rule)),
+            )
+            .service(web::resource("/rules/{id}/enable").route(web::post().to(enable_rule)))
+            .service(web::resource("/rules/{id}/disable").route(web::post().to(disable_rule)))
+            .service(web::resource("/rules/{id}/stats").route(web::get().to(get_rule_stats)))
+            .service(web::resource("/rules/{id}/history").route(web::get().to(get_rule_history)))
+            .service(web::resource("/rules/{id}/rollback/{version}").route(web::post().to(rollback_rule)))
             .service(web::resource("/analytics/metrics").route(web::get().to(get_analytics_metrics)))
             .service(web::resource("/analytics/events").route(web::get().to(get_analytics_events)))
+            .service(web::resource("/analytics/events/export").route(web::get().to(export::export_events)))
+            .service(web::resource("/analytics/event-counts").route(web::get().to(get_analytics_event_counts)))
+            .service(web::resource("/analytics/timeseries").route(web::get().to(get_analytics_timeseries)))
+            .service(web::resource("/analytics/top/{dimension}").route(web::get().to(get_analytics_top)))
+            .service(web::resource("/stream/events").route(web::get().to(stream::stream_events)))
+            .service(web::resource("/stream/ws").route(web::get().to(stream::stream_events_ws)))
+            .service(web::resource("/analytics/tls-fingerprints").route(web::get().to(get_tls_fingerprints)))
             .service(web::resource("/monitoring/metrics").route(web::get().to(get_monitoring_metrics)))
+            .service(web::resource("/monitoring/metrics/history").route(web::get().to(get_monitoring_metrics_history)))
+            .service(web::resource("/monitoring/redis-pool").route(web::get().to(get_redis_pool_stats)))
+            .service(web::resource("/monitoring/degradation").route(web::get().to(get_degradation_status)))
+            .service(web::resource("/audit-log").route(web::get().to(get_audit_log)))
             .service(web::resource("/monitoring/alerts").route(web::get().to(get_monitoring_alerts)))
             .service(web::resource("/monitoring/alerts/{id}/acknowledge").route(web::post().to(acknowledge_alert)))
+            .service(web::resource("/monitoring/alerts/{id}/resolve").route(web::post().to(resolve_alert)))
+            .service(
+                web::resource("/monitoring/alert-rules")
+                    .route(web::get().to(alert_rules::get_alert_rules))
+                    .route(web::post().to(alert_rules::create_alert_rule)),
+            )
+            .service(
+                web::resource("/monitoring/alert-rules/{id}")
+                    .route(web::get().to(alert_rules::get_alert_rule))
+                    .route(web::put().to(alert_rules::update_alert_rule))
+                    .route(web::delete().to(alert_rules::delete_alert_rule)),
+            )
+            .service(
+                web::resource("/monitoring/silences")
+                    .route(web::get().to(silences::get_silences))
+                    .route(web::post().to(silences::create_silence)),
+            )
+            .service(
+                web::resource("/monitoring/silences/{id}")
+                    .route(web::get().to(silences::get_silence))
+                    .route(web::delete().to(silences::delete_silence)),
+            )
+            .service(
+                web::resource("/webhooks")
+                    .route(web::get().to(webhooks::get_webhooks))
+                    .route(web::post().to(webhooks::create_webhook)),
+            )
+            .service(web::resource("/webhooks/dead-letters").route(web::get().to(webhooks::get_dead_letters)))
+            .service(
+                web::resource("/webhooks/{id}")
+                    .route(web::get().to(webhooks::get_webhook))
+                    .route(web::put().to(webhooks::update_webhook))
+                    .route(web::delete().to(webhooks::delete_webhook)),
+            )
+            .service(
+                web::resource("/tenants")
+                    .route(web::get().to(tenants::get_tenants))
+                    .route(web::post().to(tenants::create_tenant)),
+            )
+            .service(
+                web::resource("/tenants/{id}")
+                    .route(web::get().to(tenants::get_tenant))
+                    .route(web::put().to(tenants::update_tenant))
+                    .route(web::delete().to(tenants::delete_tenant)),
+            )
+            .service(
+                web::resource("/tenants/{id}/blocklist")
+                    .route(web::get().to(tenants::get_tenant_blocklist))
+                    .route(web::post().to(tenants::add_tenant_blocklist_entry)),
+            )
+            .service(
+                web::resource("/tenants/{id}/blocklist/{target:.*}")
+                    .route(web::delete().to(tenants::remove_tenant_blocklist_entry)),
+            )
     );
 }
 
@@ -53,30 +288,280 @@ pub struct HealthCheckResponse {
 /// Rate limit request
 #[derive(Deserialize)]
 pub struct RateLimitRequest {
-    ip: String,
+    /// Client address; the caller's address when left out
+    ip: Option<String>,
+    /// Key to count the request against instead of the client's address or subnet
+    key: Option<String>,
     path: String,
+    /// Explicit request cost, overriding the configured per-path cost
+    cost: Option<u32>,
+    /// API key to charge against its daily/monthly quota
+    api_key: Option<String>,
+}
+
+impl Validate for RateLimitRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        if let Some(ip) = &self.ip {
+            errors.check_ip(ip, "ip");
+        }
+        if let Some(key) = &self.key {
+            errors.check(!key.trim().is_empty(), "key", "must not be empty");
+        }
+        errors.check(self.path.starts_with('/'), "path", "must start with /");
+        errors.check(self.cost != Some(0), "cost", "must be positive");
+    }
 }
 
 /// Rate limit response
 #[derive(Serialize)]
 pub struct RateLimitResponse {
+    pub(crate) allowed: bool,
+    /// Limit currently enforced (lower than configured while under load)
+    pub(crate) limit: u32,
+    pub(crate) remaining: u32,
+    pub(crate) reset: u64,
+    /// Penalty state if the client has recent offenses
+    penalty: Option<PenaltyState>,
+    /// Quota usage if an API key with a quota was given
+    quota: Option<QuotaStatus>,
+    /// Whether the request would have been rejected but shadow mode let it through
+    pub(crate) shadowed: bool,
+    /// Whether the client is allowlisted and was not counted
+    pub(crate) allowlisted: bool,
+    /// Blocklist entry if the client is blocked
+    pub(crate) blocked: Option<BlockEntry>,
+}
+
+/// Rate limit status query
+#[derive(Deserialize)]
+pub struct RateLimitStatusQuery {
+    /// Key to report on, as given to `POST /rate-limit`
+    key: Option<String>,
+    /// Client whose limit applies, and whose address or subnet is the key
+    /// without one; the caller's address by default
+    ip: Option<String>,
+}
+
+impl Validate for RateLimitStatusQuery {
+    fn check(&self, errors: &mut FieldErrors) {
+        if let Some(ip) = &self.ip {
+            errors.check_ip(ip, "ip");
+        }
+        if let Some(key) = &self.key {
+            errors.check(!key.trim().is_empty(), "key", "must not be empty");
+        }
+    }
+}
+
+/// Rate limit status response
+#[derive(Serialize)]
+pub struct RateLimitStatusResponse {
+    key: String,
+    #[serde(flatten)]
+    usage: RateLimitUsage,
+}
+
+/// Blocklist entry request
+#[derive(Deserialize)]
+pub struct BlocklistRequest {
+    /// IP or network in CIDR notation
+    target: String,
+    reason: String,
+    /// Block duration in seconds (0 = permanent, omitted = configured default)
+    duration_seconds: Option<u64>,
+}
+
+/// Blocklist check response
+#[derive(Serialize)]
+pub struct BlocklistCheckResponse {
+    ip: String,
+    blocked: bool,
+    entry: Option<BlockEntry>,
+}
+
+/// Reputation adjustment request
+#[derive(Deserialize)]
+pub struct ReputationAdjustRequest {
+    /// Amount added to the score (negative to penalize)
+    delta: f64,
+}
+
+impl Validate for ReputationAdjustRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(self.delta.is_finite(), "delta", "must be a finite number");
+    }
+}
+
+/// Allowlist entry request
+#[derive(Deserialize)]
+pub struct AllowlistRequest {
+    kind: AllowlistKind,
+    value: String,
+    description: Option<String>,
+}
+
+impl Validate for AllowlistRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(!self.value.trim().is_empty(), "value", "must not be empty");
+    }
+}
+
+/// Quota request
+#[derive(Deserialize)]
+pub struct QuotaRequest {
+    api_key: String,
+    daily_limit: Option<u64>,
+    monthly_limit: Option<u64>,
+}
+
+impl Validate for QuotaRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(!self.api_key.trim().is_empty(), "api_key", "must not be empty");
+        check_quota_limits(errors, self.daily_limit, self.monthly_limit);
+    }
+}
+
+/// Quota update request
+#[derive(Deserialize)]
+pub struct QuotaUpdateRequest {
+    daily_limit: Option<u64>,
+    monthly_limit: Option<u64>,
+}
+
+impl Validate for QuotaUpdateRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        check_quota_limits(errors, self.daily_limit, self.monthly_limit);
+    }
+}
+
+fn check_quota_limits(errors: &mut FieldErrors, daily_limit: Option<u64>, monthly_limit: Option<u64>) {
+    errors.check(daily_limit != Some(0), "daily_limit", "must be positive");
+    errors.check(monthly_limit != Some(0), "monthly_limit", "must be positive");
+}
+
+/// Quota response
+#[derive(Serialize)]
+pub struct QuotaResponse {
+    quota: Quota,
+    status: Option<QuotaStatus>,
+}
+
+/// Concurrency request
+#[derive(Deserialize)]
+pub struct ConcurrencyRequest {
+    ip: String,
+}
+
+impl Validate for ConcurrencyRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check_ip(&self.ip, "ip");
+    }
+}
+
+/// Concurrency response
+#[derive(Serialize)]
+pub struct ConcurrencyResponse {
     allowed: bool,
-    remaining: u32,
-    reset: u64,
+    in_flight: u32,
+    limit: u32,
 }
 
 /// DDoS check request
 #[derive(Deserialize)]
 pub struct DdosCheckRequest {
-    ip: String,
-    request_size: u64,
+    pub(crate) ip: String,
+    pub(crate) request_size: u64,
+    /// User agent, merged into `headers`
+    #[serde(default)]
+    pub(crate) user_agent: String,
+    /// Request attributes matched by rule conditions
+    #[serde(default)]
+    pub(crate) method: String,
+    #[serde(default)]
+    pub(crate) path: String,
+    #[serde(default)]
+    pub(crate) headers: HashMap<String, String>,
+    /// Header names in the order the client sent them
+    #[serde(default)]
+    pub(crate) header_order: Vec<String>,
+    #[serde(default)]
+    pub(crate) query: HashMap<String, String>,
+    /// TLS fingerprints computed by the proxy
+    pub(crate) ja3: Option<String>,
+    pub(crate) ja4: Option<String>,
+    /// Base64-encoded ClientHello, fingerprinted when the proxy doesn't compute fingerprints
+    pub(crate) tls_client_hello: Option<String>,
+    /// Tenant the request is attributed to, whose rules and blocklist apply
+    #[serde(skip)]
+    pub(crate) tenant: Option<String>,
+}
+
+impl Validate for DdosCheckRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check_ip(&self.ip, "ip");
+    }
+}
+
+impl DdosCheckRequest {
+    /// Replace the reported address with the client's
+    ///
+    /// The reported address may be a load balancer in front of the proxy
+    /// calling us.
+    fn resolve_ip(&mut self, state: &ApiState) {
+        self.ip = state.trusted_proxies.resolve(&self.ip, |name| {
+            self.headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+                .reduce(|a, b| a + "," + &b)
+        });
+    }
+
+    fn request_context(&self) -> RequestContext {
+        let mut headers = self.headers.clone();
+        if !self.user_agent.is_empty() {
+            headers.insert("User-Agent".to_string(), self.user_agent.clone());
+        }
+        let client_hello = self
+            .tls_client_hello
+            .as_ref()
+            .and_then(|encoded| base64::engine::general_purpose::STANDARD.decode(encoded).ok())
+            .and_then(|data| ClientHello::parse(&data));
+        RequestContext {
+            ip: self.ip.clone(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            headers,
+            query: self.query.clone(),
+            size: self.request_size,
+            header_order: self.header_order.clone(),
+            bot_score: None,
+            ja3: self.ja3.clone().or_else(|| client_hello.as_ref().map(ClientHello::ja3)),
+            ja4: self.ja4.clone().or_else(|| client_hello.as_ref().map(ClientHello::ja4)),
+            tenant: self.tenant.clone(),
+        }
+    }
 }
 
 /// DDoS check response
 #[derive(Serialize)]
 pub struct DdosCheckResponse {
-    is_under_attack: bool,
-    detection_type: Option<String>,
+    pub(crate) is_under_attack: bool,
+    pub(crate) detection_type: Option<DetectionType>,
+    /// Category, confidence and triggering metrics of a detection by the DDoS detector
+    classification: Option<Classification>,
+    /// Actions of the rules the request matched
+    rule_actions: Vec<RuleAction>,
+    /// How the request should be answered, if rules call for more than a delay
+    pub(crate) mitigation: Option<Mitigation>,
+    /// Distributed attack the service is under, found across all clients
+    aggregate_detection: Option<AggregateDetection>,
+    /// How likely the request is to come from a bot
+    bot: Option<BotScore>,
+    /// Scanner behavior the client was detected for
+    scanner: Option<ScanDetection>,
+    /// Whether attack mode is on
+    pub(crate) attack_mode: bool,
 }
 
 /// Rule request
@@ -88,6 +573,124 @@ pub struct RuleRequest {
     actions: Vec<RuleAction>,
     priority: i32,
     enabled: bool,
+    #[serde(default)]
+    shadow: bool,
+    #[serde(default)]
+    schedule: Option<RuleSchedule>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    /// Tenant the rule applies to, or all traffic without one
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+impl Validate for RuleRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        check_rule(errors, "", &self.name, self.priority, self.schedule.as_ref());
+    }
+}
+
+/// Check the fields rule requests and imported rules share
+///
+/// `prefix` locates the rule in the request, such as `rules[2].`.
+fn check_rule(errors: &mut FieldErrors, prefix: &str, name: &str, priority: i32, schedule: Option<&RuleSchedule>) {
+    errors.check(!name.trim().is_empty(), format!("{}name", prefix), "must not be empty");
+    errors.check(priority >= 0, format!("{}priority", prefix), "must not be negative");
+    if let Some(Err(e)) = schedule.map(RuleSchedule::validate) {
+        errors.check(false, format!("{}schedule", prefix), e.to_string());
+    }
+}
+
+impl RuleRequest {
+    fn into_rule(self, id: String) -> Rule {
+        Rule {
+            id,
+            name: self.name,
+            description: self.description,
+            conditions: self.conditions,
+            actions: self.actions,
+            priority: self.priority,
+            enabled: self.enabled,
+            shadow: self.shadow,
+            schedule: self.schedule,
+            expires_at: self.expires_at,
+            tenant: self.tenant,
+        }
+    }
+}
+
+/// Partial rule update; fields left out are kept
+///
+/// `description`, `schedule` and `expires_at` are cleared with `null`.
+#[derive(Deserialize)]
+pub struct RulePatchRequest {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    description: Option<Option<String>>,
+    conditions: Option<Vec<RuleCondition>>,
+    actions: Option<Vec<RuleAction>>,
+    priority: Option<i32>,
+    enabled: Option<bool>,
+    shadow: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    schedule: Option<Option<RuleSchedule>>,
+    #[serde(default, deserialize_with = "nullable")]
+    expires_at: Option<Option<DateTime<Utc>>>,
+}
+
+/// Tell a field set to `null` apart from one left out
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl Validate for RulePatchRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        if let Some(name) = &self.name {
+            errors.check(!name.trim().is_empty(), "name", "must not be empty");
+        }
+        if let Some(priority) = self.priority {
+            errors.check(priority >= 0, "priority", "must not be negative");
+        }
+        if let Some(Some(Err(e))) = self.schedule.as_ref().map(|schedule| schedule.as_ref().map(RuleSchedule::validate)) {
+            errors.check(false, "schedule", e.to_string());
+        }
+    }
+}
+
+impl RulePatchRequest {
+    fn apply(self, rule: &mut Rule) {
+        if let Some(name) = self.name {
+            rule.name = name;
+        }
+        if let Some(description) = self.description {
+            rule.description = description;
+        }
+        if let Some(conditions) = self.conditions {
+            rule.conditions = conditions;
+        }
+        if let Some(actions) = self.actions {
+            rule.actions = actions;
+        }
+        if let Some(priority) = self.priority {
+            rule.priority = priority;
+        }
+        if let Some(enabled) = self.enabled {
+            rule.enabled = enabled;
+        }
+        if let Some(shadow) = self.shadow {
+            rule.shadow = shadow;
+        }
+        if let Some(schedule) = self.schedule {
+            rule.schedule = schedule;
+        }
+        if let Some(expires_at) = self.expires_at {
+            rule.expires_at = expires_at;
+        }
+    }
 }
 
 /// Rule response
@@ -100,6 +703,63 @@ pub struct RuleResponse {
     actions: Vec<RuleAction>,
     priority: i32,
     enabled: bool,
+    shadow: bool,
+    schedule: Option<RuleSchedule>,
+    expires_at: Option<DateTime<Utc>>,
+    tenant: Option<String>,
+}
+
+impl From<Rule> for RuleResponse {
+    fn from(rule: Rule) -> Self {
+        Self {
+            id: rule.id,
+            name: rule.name,
+            description: rule.description,
+            conditions: rule.conditions,
+            actions: rule.actions,
+            priority: rule.priority,
+            enabled: rule.enabled,
+            shadow: rule.shadow,
+            schedule: rule.schedule,
+            expires_at: rule.expires_at,
+            tenant: rule.tenant,
+        }
+    }
+}
+
+/// Rule import request
+#[derive(Deserialize)]
+pub struct RuleImportRequest {
+    rules: Vec<Rule>,
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+impl Validate for RuleImportRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        for (index, rule) in self.rules.iter().enumerate() {
+            check_rule(errors, &format!("rules[{}].", index), &rule.name, rule.priority, rule.schedule.as_ref());
+        }
+    }
+}
+
+/// Rule dry-run request
+#[derive(Deserialize)]
+pub struct RuleTestRequest {
+    /// Sample request to evaluate
+    request: RequestContext,
+    /// ID of a stored rule to test
+    rule_id: Option<String>,
+    /// Inline rule definition to test instead of a stored rule
+    rule: Option<RuleRequest>,
+}
+
+impl Validate for RuleTestRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        if let Some(rule) = &self.rule {
+            check_rule(errors, "rule.", &rule.name, rule.priority, rule.schedule.as_ref());
+        }
+    }
 }
 
 /// Analytics events request
@@ -108,130 +768,2077 @@ pub struct AnalyticsEventsRequest {
     start_time: u64,
     end_time: u64,
     event_type: Option<String>,
+    /// IP or CIDR network of the event's client
+    ip: Option<String>,
+    /// Start of the request path the event is about
+    path_prefix: Option<String>,
+    /// ID of the rule the event is about
+    rule_id: Option<String>,
+    /// Country code of the event's client
+    country: Option<String>,
+    /// Tenant the event's request was attributed to
+    tenant: Option<String>,
+    /// Text in the event's source or data, ignoring case
+    q: Option<String>,
+    /// Matching events to skip
+    #[serde(default)]
+    offset: usize,
+    /// Page to return, from 1, instead of skipping `offset` events
+    page: Option<usize>,
+    /// Where the page starts, as given in the `X-Next-Cursor` header of the previous page
+    cursor: Option<String>,
+    #[serde(default = "default_events_limit")]
+    limit: usize,
 }
 
-/// Health check endpoint
-pub async fn health_check() -> impl Responder {
-    let response = HealthCheckResponse {
-        status: "ok".to_string(),
-        version: env!("CARGO_PKG_VERSION").to_string(),
-    };
-    
-    HttpResponse::Ok().json(response)
+impl AnalyticsEventsRequest {
+    /// Where the requested page starts, unless the cursor is invalid
+    fn cursor(&self) -> Option<EventCursor> {
+        match (&self.cursor, self.page) {
+            (Some(cursor), _) => cursor.parse().ok(),
+            (None, Some(page)) => Some(EventCursor::Offset(page.saturating_sub(1).saturating_mul(self.limit))),
+            (None, None) => Some(EventCursor::Offset(self.offset)),
+        }
+    }
 }
 
-/// Rate limit check endpoint
-pub async fn check_rate_limit(
-    state: web::Data<ApiState>,
-    req: HttpRequest,
-) -> impl Responder {
-    let key = req.connection_info().peer_addr().unwrap_or("unknown").to_string();
-    let mut rate_limiter = state.rate_limiter.lock().await;
-    
-    match rate_limiter.check_rate_limit(&key).await {
-        Ok(_) => {
-            let remaining = rate_limiter.get_remaining(&key).await;
-            let reset = rate_limiter.get_reset_time(&key).await.unwrap_or(0);
-            
-            HttpResponse::Ok().json(RateLimitResponse {
-                allowed: true,
-                remaining: remaining.try_into().unwrap_or(0),
-                reset,
-            })
+impl Validate for AnalyticsEventsRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(self.start_time <= self.end_time, "start_time", "must be no later than end_time");
+        errors.check(
+            self.ip.as_deref().is_none_or(|ip| parse_network(ip).is_some()),
+            "ip",
+            "must be an IP address or CIDR network",
+        );
+        errors.check(
+            (1..=MAX_EVENTS_LIMIT).contains(&self.limit),
+            "limit",
+            format!("must be between 1 and {}", MAX_EVENTS_LIMIT),
+        );
+        check_page(errors, self.page);
+        errors.check(self.cursor().is_some(), "cursor", "must be a cursor returned with a previous page");
+    }
+}
+
+/// Header giving how many items match a listing, on any page
+const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+/// Check a page number, counted from 1
+fn check_page(errors: &mut FieldErrors, page: Option<usize>) {
+    errors.check(page != Some(0), "page", "must be at least 1");
+}
+
+impl Validate for RuleQuery {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(
+            (1..=MAX_RULE_QUERY_LIMIT).contains(&self.limit),
+            "limit",
+            format!("must be between 1 and {}", MAX_RULE_QUERY_LIMIT),
+        );
+        check_page(errors, self.page);
+    }
+}
+
+impl Validate for AlertQuery {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(self.limit <= MAX_ALERT_QUERY_LIMIT, "limit", format!("may be at most {}", MAX_ALERT_QUERY_LIMIT));
+        check_page(errors, self.page);
+    }
+}
+
+fn default_events_limit() -> usize {
+    MAX_EVENTS_LIMIT
+}
+
+/// Most events returned by one events query
+const MAX_EVENTS_LIMIT: usize = 1000;
+
+/// Analytics event counts request
+#[derive(Deserialize)]
+pub struct AnalyticsEventCountsRequest {
+    start_time: u64,
+    end_time: u64,
+}
+
+impl Validate for AnalyticsEventCountsRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(self.start_time <= self.end_time, "start_time", "must be no later than end_time");
+    }
+}
+
+/// Traffic series request
+#[derive(Deserialize)]
+pub struct TimeseriesRequest {
+    metric: TrafficMetric,
+    /// Start of the range as a Unix timestamp; an hour before `to` by default
+    from: Option<i64>,
+    /// End of the range as a Unix timestamp; now by default
+    to: Option<i64>,
+    /// Seconds each point sums over, a whole number of minutes
+    #[serde(default = "default_metrics_step")]
+    step: i64,
+}
+
+/// Traffic series response
+#[derive(Serialize)]
+pub struct TimeseriesResponse {
+    metric: TrafficMetric,
+    from: i64,
+    to: i64,
+    step: i64,
+    points: Vec<Point>,
+}
+
+/// Leaderboard request
+#[derive(Deserialize)]
+pub struct TopRequest {
+    /// Start of the range as a Unix timestamp; an hour before `to` by default
+    from: Option<i64>,
+    /// End of the range as a Unix timestamp; now by default
+    to: Option<i64>,
+    #[serde(default = "default_top_limit")]
+    limit: usize,
+}
+
+fn default_top_limit() -> usize {
+    10
+}
+
+/// Most members a leaderboard request may return
+const MAX_TOP_LIMIT: usize = 1000;
+
+/// Connection report, sent by the proxy when a connection completes or is closed
+#[derive(Deserialize)]
+pub struct ConnectionReportRequest {
+    ip: String,
+    #[serde(flatten)]
+    stats: ConnectionStats,
+}
+
+impl Validate for ConnectionReportRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check_ip(&self.ip, "ip");
+    }
+}
+
+/// Response report, sent by the proxy once a response is served
+#[derive(Deserialize)]
+pub struct ResponseReportRequest {
+    ip: String,
+    /// HTTP status of the response
+    status: u16,
+}
+
+impl Validate for ResponseReportRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check_ip(&self.ip, "ip");
+        errors.check((100..600).contains(&self.status), "status", "must be an HTTP status");
+    }
+}
+
+/// Login attempt, checked by the proxy before passing it on
+#[derive(Deserialize)]
+pub struct LoginAttemptRequest {
+    ip: String,
+    username: String,
+    /// Cookie header of the request, carrying the challenge pass if any
+    cookie: Option<String>,
+}
+
+impl Validate for LoginAttemptRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check_ip(&self.ip, "ip");
+        errors.check(!self.username.is_empty(), "username", "must not be empty");
+    }
+}
+
+/// Login attempt outcome, reported by the proxy once the login is answered
+#[derive(Deserialize)]
+pub struct LoginResultRequest {
+    ip: String,
+    username: String,
+    success: bool,
+}
+
+impl Validate for LoginResultRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check_ip(&self.ip, "ip");
+        errors.check(!self.username.is_empty(), "username", "must not be empty");
+    }
+}
+
+/// Login lockout removal request
+#[derive(Deserialize)]
+pub struct LoginLockoutRequest {
+    ip: Option<String>,
+    username: Option<String>,
+}
+
+impl Validate for LoginLockoutRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(self.ip.is_some() || self.username.is_some(), "ip", "an ip or username is required");
+        if let Some(ip) = &self.ip {
+            errors.check_ip(ip, "ip");
+        }
+    }
+}
+
+/// Attack mode switch request
+#[derive(Deserialize)]
+pub struct AttackModeRequest {
+    enabled: bool,
+    /// How long attack mode stays on; defaults to the configured duration
+    duration_seconds: Option<u64>,
+    reason: Option<String>,
+}
+
+impl Validate for AttackModeRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(self.duration_seconds != Some(0), "duration_seconds", "must be positive");
+    }
+}
+
+/// Attack mode status response
+#[derive(Serialize)]
+pub struct AttackModeResponse {
+    enabled: bool,
+    /// Attack mode state while it is on
+    state: Option<AttackModeState>,
+}
+
+/// Scanner signature request
+#[derive(Deserialize)]
+pub struct ScannerSignatureRequest {
+    signature: String,
+}
+
+/// Connection report response
+#[derive(Serialize)]
+pub struct ConnectionReportResponse {
+    /// Whether the connection transferred too slowly
+    slow: bool,
+    /// Whether the client was blocked for making too many slow connections
+    blocked: bool,
+}
+
+/// Attack list request
+#[derive(Deserialize)]
+pub struct AttacksRequest {
+    /// Only list attacks that are still going on
+    #[serde(default)]
+    active: bool,
+    #[serde(default = "default_attack_limit")]
+    limit: usize,
+}
+
+fn default_attack_limit() -> usize {
+    50
+}
+
+/// How an attack report is rendered
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Html,
+    Pdf,
+}
+
+/// Attack report request
+#[derive(Deserialize)]
+pub struct AttackReportRequest {
+    #[serde(default)]
+    format: ReportFormat,
+}
+
+/// Audit log request
+#[derive(Deserialize)]
+pub struct AuditLogRequest {
+    #[serde(default = "default_audit_log_limit")]
+    limit: usize,
+}
+
+fn default_audit_log_limit() -> usize {
+    100
+}
+
+/// Most points a metrics history request may return
+const MAX_METRICS_HISTORY_POINTS: i64 = 10_000;
+
+/// Metrics history request
+#[derive(Deserialize)]
+pub struct MetricsHistoryRequest {
+    /// Start of the range as a Unix timestamp; an hour before `to` by default
+    from: Option<i64>,
+    /// End of the range as a Unix timestamp; now by default
+    to: Option<i64>,
+    /// Seconds each point averages over
+    #[serde(default = "default_metrics_step")]
+    step: i64,
+}
+
+fn default_metrics_step() -> i64 {
+    60
+}
+
+/// Metrics history response
+#[derive(Serialize)]
+pub struct MetricsHistoryResponse {
+    from: i64,
+    to: i64,
+    step: i64,
+    points: Vec<SystemMetrics>,
+}
+
+/// Challenge page request
+#[derive(Deserialize)]
+pub struct ChallengeRequest {
+    /// Path to return to once the challenge is passed
+    #[serde(default)]
+    return_to: String,
+}
+
+/// Challenge solution, submitted by the challenge page
+#[derive(Deserialize)]
+pub struct ChallengeVerifyRequest {
+    token: String,
+    #[serde(default)]
+    nonce: String,
+    /// Token of the CAPTCHA widget, under the provider's field name
+    #[serde(default, alias = "cf-turnstile-response", alias = "h-captcha-response", alias = "g-recaptcha-response")]
+    captcha_response: String,
+    #[serde(default)]
+    return_to: String,
+}
+
+/// TLS fingerprint counts request
+#[derive(Deserialize)]
+pub struct TlsFingerprintsRequest {
+    #[serde(default = "default_fingerprint_kind")]
+    kind: FingerprintKind,
+    #[serde(default = "default_fingerprint_limit")]
+    limit: usize,
+}
+
+fn default_fingerprint_kind() -> FingerprintKind {
+    FingerprintKind::Ja4
+}
+
+fn default_fingerprint_limit() -> usize {
+    20
+}
+
+/// Check an IP address given in the path
+fn check_ip_param(ip: &str) -> Result<(), ApiError> {
+    let mut errors = FieldErrors::default();
+    errors.check_ip(ip, "ip");
+    errors.into_result()
+}
+
+/// Health check endpoint
+pub async fn health_check() -> impl Responder {
+    let response = HealthCheckResponse {
+        status: "ok".to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    
+    HttpResponse::Ok().json(response)
+}
+
+/// W3C trace context the proxy sent along, continued by the decision spans
+fn traceparent(req: &HttpRequest) -> Option<&str> {
+    req.headers().get("traceparent").and_then(|value| value.to_str().ok())
+}
+
+/// Tenant a decision request is attributed to, when tenants are enabled
+///
+/// The tenant header names the tenant; without it, the tenant owning the
+/// request's API key, given in the body or the `X-API-Key` header. Requests
+/// attributed to no tenant are handled globally.
+async fn resolve_tenant(state: &ApiState, req: &HttpRequest, api_key: Option<&str>) -> Result<Option<Tenant>, ApiError> {
+    let config = &state.config.tenants;
+    if !config.enabled {
+        return Ok(None);
+    }
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let result = match header(&config.header) {
+        Some(id) => match state.tenants.get_tenant(id.trim()).await {
+            Ok(None) => return Err(ApiError::bad_request(format!("Unknown tenant: {}", id.trim()))),
+            result => result,
+        },
+        None => match api_key.or_else(|| header("X-API-Key")) {
+            Some(api_key) => state.tenants.tenant_for_key(api_key).await,
+            None => Ok(None),
+        },
+    };
+    // Tenant lookups fail open to global handling, like the other Redis-backed layers
+    result.or_else(|e| {
+        log::error!("Failed to resolve tenant: {}", e);
+        Ok(None)
+    })
+}
+
+/// Rate limit check endpoint
+#[tracing::instrument(name = "check_rate_limit", skip_all, fields(otel.kind = "server", traceparent = traceparent(&req)))]
+pub async fn check_rate_limit(
+    state: web::Data<ApiState>,
+    req: HttpRequest,
+    body: Option<web::Json<RateLimitRequest>>,
+) -> impl Responder {
+    if let Some(Err(e)) = body.as_ref().map(|body| body.validate()) {
+        return e.into();
+    }
+    let ip = body
+        .as_ref()
+        .and_then(|body| body.ip.clone())
+        .unwrap_or_else(|| state.trusted_proxies.client_ip(&req));
+    let key = body.as_ref().and_then(|body| body.key.clone());
+    let path = body
+        .as_ref()
+        .map(|body| body.path.clone())
+        .unwrap_or_else(|| req.path().to_string());
+    let api_key = body.as_ref().and_then(|body| body.api_key.clone());
+    let cost = body
+        .and_then(|body| body.cost)
+        .unwrap_or_else(|| state.config.rate_limit.cost_for_path(&path));
+    let tenant = match resolve_tenant(&state, &req, api_key.as_deref()).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.into(),
+    };
+    let response = rate_limit_decision(&state, &ip, key.as_deref(), &path, cost, api_key.as_deref(), tenant.as_ref()).await;
+    if response.blocked.is_some() {
+        return HttpResponse::Forbidden().json(response);
+    }
+    if response.allowlisted {
+        return HttpResponse::Ok().json(response);
+    }
+
+    let mut builder = if response.allowed {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::TooManyRequests()
+    };
+
+    insert_rate_limit_headers(
+        &mut builder,
+        &state.config.rate_limit.headers,
+        &path,
+        &RateLimitHeaderValues {
+            limit: response.limit,
+            remaining: response.remaining,
+            reset: response.reset,
+            rejected: !response.allowed,
+        },
+    );
+
+    builder.json(response)
+}
+
+/// Rate limit status endpoint
+///
+/// Reports the count, remaining quota and reset of a key's current window
+/// without counting a request.
+pub async fn get_rate_limit_status(
+    state: web::Data<ApiState>,
+    req: HttpRequest,
+    query: web::Query<RateLimitStatusQuery>,
+) -> impl Responder {
+    if let Err(e) = query.validate() {
+        return e.into();
+    }
+    let tenant = match resolve_tenant(&state, &req, None).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.into(),
+    };
+    let query = query.into_inner();
+    let ip = query.ip.unwrap_or_else(|| state.trusted_proxies.client_ip(&req));
+    let mut key = query.key.unwrap_or_else(|| state.config.subnets.client_key(&ip));
+    if let Some(tenant) = &tenant {
+        key = tenant.client_key(&key);
+    }
+    let base_limit = base_limit(&state, &ip, tenant.as_ref()).await;
+
+    let rate_limiter = &state.rate_limiter;
+    match rate_limiter.get_usage(&key, base_limit).await {
+        Ok(usage) => HttpResponse::Ok().json(RateLimitStatusResponse { key, usage }),
+        Err(RateLimitError::Unavailable) => {
+            ApiError::unavailable("Rate limit status is unavailable while Redis is down").into()
+        }
+        Err(e) => {
+            log::error!("Failed to get rate limit status of {}: {}", key, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Count a request against the client's rate limit and quota
+///
+/// The request is counted against `key` if given, else the client's
+/// address or subnet. Allowlisted clients aren't counted and blocked clients are rejected
+/// outright. In shadow mode rejections are recorded but the request is let
+/// through. A tenant's clients are counted apart, against the tenant's limit
+/// and blocklist as well as the global one.
+#[tracing::instrument(name = "rate_limit", skip_all, fields(ip = %ip, path = %path, cost))]
+pub(crate) async fn rate_limit_decision(
+    state: &ApiState,
+    ip: &str,
+    key: Option<&str>,
+    path: &str,
+    cost: u32,
+    api_key: Option<&str>,
+    tenant: Option<&Tenant>,
+) -> RateLimitResponse {
+    let mut key = key.map_or_else(|| state.config.subnets.client_key(ip), str::to_string);
+    if let Some(tenant) = tenant {
+        key = tenant.client_key(&key);
+    }
+    let base_limit = base_limit(state, ip, tenant).await;
+    let rate_limiter = &state.rate_limiter;
+
+    if state.allowlist.is_allowed(Some(ip), api_key).await {
+        let limit = effective_limit(rate_limiter, base_limit).await;
+        return RateLimitResponse {
+            allowed: true,
+            limit,
+            remaining: limit,
+            reset: 0,
+            penalty: None,
+            quota: None,
+            shadowed: false,
+            allowlisted: true,
+            blocked: None,
+        };
+    }
+
+    match check_blocklists(state, ip, tenant.map(|tenant| tenant.id.as_str())).await {
+        Ok(Some(entry)) => {
+            return RateLimitResponse {
+                allowed: false,
+                limit: effective_limit(rate_limiter, base_limit).await,
+                remaining: 0,
+                reset: entry.expires_at.map_or(0, |e| (e - Utc::now()).num_seconds().max(0) as u64),
+                penalty: None,
+                quota: None,
+                shadowed: false,
+                allowlisted: false,
+                blocked: Some(entry),
+            };
+        }
+        Ok(None) => (),
+        Err(e) => log::error!("Failed to check blocklist for {}: {}", ip, e),
+    }
+    
+    let mut response = match rate_limiter.check_rate_limit(&key, cost, base_limit).await {
+        Ok(status) => {
+            let penalty = rate_limiter.get_penalty(&key).await.unwrap_or(None);
+            let (allowed, quota) = match api_key {
+                Some(api_key) => charge_quota(state, api_key, cost).await,
+                None => (true, None),
+            };
+            
+            RateLimitResponse {
+                allowed,
+                limit: status.limit,
+                remaining: status.remaining,
+                reset: status.reset,
+                penalty,
+                quota,
+                shadowed: false,
+                allowlisted: false,
+                blocked: None,
+            }
+        }
+        Err(RateLimitError::Banned(penalty)) => {
+            RateLimitResponse {
+                allowed: false,
+                limit: effective_limit(rate_limiter, base_limit).await,
+                remaining: 0,
+                reset: penalty.banned_for_seconds,
+                penalty: Some(penalty),
+                quota: None,
+                shadowed: false,
+                allowlisted: false,
+                blocked: None,
+            }
+        }
+        Err(_) => {
+            let reset = rate_limiter.get_reset_time(&key).await.unwrap_or(0);
+            
+            RateLimitResponse {
+                allowed: false,
+                limit: effective_limit(rate_limiter, base_limit).await,
+                remaining: 0,
+                reset,
+                penalty: None,
+                quota: None,
+                shadowed: false,
+                allowlisted: false,
+                blocked: None,
+            }
+        }
+    };
+    let shadow = rate_limiter.shadow_enabled();
+
+    // In shadow mode rejections are recorded but the request is let through
+    if shadow && !response.allowed {
+        let mut data = HashMap::new();
+        data.insert("key".to_string(), serde_json::json!(key));
+        data.insert("path".to_string(), serde_json::json!(path));
+        data.insert("decision".to_string(), serde_json::json!(response));
+        if let Some(tenant) = tenant {
+            data.insert("tenant".to_string(), serde_json::json!(tenant.id));
+        }
+        let event = Event::new(EventType::ShadowDecision, "rate_limit", data);
+        if let Err(e) = state.analytics.record_event(event).await {
+            log::error!("Failed to record shadow decision: {}", e);
+        }
+        response.allowed = true;
+        response.shadowed = true;
+    }
+
+    if !response.shadowed {
+        let event = if response.allowed {
+            ReputationEvent::CleanRequest
+        } else {
+            state.traffic.record_rate_limit(ip);
+            ReputationEvent::RateLimitViolation
+        };
+        state.reputation.record(&key, event).await;
+    }
+
+    response
+}
+
+/// Limit of a client before adaptive scaling, lowered in attack mode and
+/// on escalation
+///
+/// A tenant's own limit replaces the configured ones for its clients.
+async fn base_limit(state: &ApiState, ip: &str, tenant: Option<&Tenant>) -> u32 {
+    let mut base_limit = if let Some(limit) = tenant.and_then(|tenant| tenant.rate_limit) {
+        limit
+    } else if state.config.rate_limit.geo_limits.is_empty() {
+        // Geo limits need a GeoIP lookup per request, so it is skipped unless they are configured
+        state.config.rate_limit.default_limit
+    } else {
+        state.config.rate_limit.limit_for(&state.geoip.lookup(ip).await)
+    };
+    if state.attack_mode.is_active().await {
+        base_limit = state.attack_mode.scale_limit(base_limit);
+    }
+    if state.escalation.level().await >= EscalationLevel::RateLimit {
+        base_limit = state.escalation.scale_limit(base_limit);
+    }
+    base_limit
+}
+
+/// Check the global blocklist, then the tenant's
+async fn check_blocklists(state: &ApiState, ip: &str, tenant: Option<&str>) -> Result<Option<BlockEntry>, BlocklistError> {
+    if let Some(entry) = state.blocklist.check(ip).await? {
+        return Ok(Some(entry));
+    }
+    match tenant {
+        Some(tenant) => state.tenants.blocklist(tenant).await.check(ip).await,
+        None => Ok(None),
+    }
+}
+
+/// Charge a request against an API key's quota
+///
+/// Returns whether the request fits in the quota, along with the usage.
+/// Quota storage errors fail open so that an outage doesn't block all keyed traffic.
+async fn charge_quota(state: &ApiState, api_key: &str, cost: u32) -> (bool, Option<QuotaStatus>) {
+    // Quotas live only in Redis, so the fallback policy lets requests through like `open`
+    if let Some(policy) = state.degradation.active_policy(Subsystem::Quota).await {
+        return (policy != FailurePolicy::Closed, None);
+    }
+    let quota_manager = &state.quota_manager;
+
+    match quota_manager.consume(api_key, cost as u64).await {
+        Ok(status) => (true, status),
+        Err(QuotaError::ExceededQuota(status)) => {
+            let mut data = HashMap::new();
+            data.insert("api_key".to_string(), serde_json::json!(api_key));
+            data.insert("quota".to_string(), serde_json::json!(status));
+            let event = Event::new(EventType::QuotaExceeded, "quota", data);
+            if let Err(e) = state.analytics.record_event(event).await {
+                log::error!("Failed to record quota event: {}", e);
+            }
+            (false, Some(status))
+        }
+        Err(QuotaError::RedisError(e)) if state.degradation.fail(Subsystem::Quota, &e).await == Some(FailurePolicy::Closed) => {
+            (false, None)
+        }
+        Err(e) => {
+            log::error!("Failed to charge quota for {}: {}", api_key, e);
+            (true, None)
+        }
+    }
+}
+
+/// Get the enforced limit, falling back to the configured one
+async fn effective_limit(rate_limiter: &RateLimiter, base_limit: u32) -> u32 {
+    rate_limiter
+        .get_effective_limit()
+        .await
+        .map(|limit| ((base_limit as f64 * limit.factor).floor() as u32).max(1))
+        .unwrap_or(base_limit)
+}
+
+/// Effective rate limit endpoint
+///
+/// Shows how far adaptive limits have scaled the configured limit down.
+pub async fn get_effective_rate_limit(
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    let rate_limiter = &state.rate_limiter;
+
+    match rate_limiter.get_effective_limit().await {
+        Ok(limit) => HttpResponse::Ok().json(limit),
+        Err(e) => {
+            log::error!("Failed to get effective rate limit: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get runtime allowlist entries endpoint
+pub async fn get_allowlist(
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    match state.allowlist.get_entries().await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            log::error!("Failed to get allowlist: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Add allowlist entry endpoint
+pub async fn add_allowlist_entry(
+    state: web::Data<ApiState>,
+    req: web::Json<AllowlistRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let req = req.into_inner();
+
+    match state.allowlist.add_entry(req.kind, &req.value, req.description).await {
+        Ok(entry) => HttpResponse::Created().json(entry),
+        Err(AllowlistError::InvalidNetwork(value)) => {
+            ApiError::bad_request(format!("Invalid network: {}", value)).into()
+        }
+        Err(e) => {
+            log::error!("Failed to add allowlist entry: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Remove allowlist entry endpoint
+///
+/// The value is the network (e.g. `10.0.0.0/8`) or API key to remove.
+pub async fn remove_allowlist_entry(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.allowlist.remove_entry(&path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Allowlist entry").into(),
+        Err(e) => {
+            log::error!("Failed to remove allowlist entry: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get active blocklist entries endpoint
+pub async fn get_blocklist(
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    match state.blocklist.get_entries().await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            log::error!("Failed to get blocklist: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Add blocklist entry endpoint
+pub async fn add_blocklist_entry(
+    state: web::Data<ApiState>,
+    req: web::Json<BlocklistRequest>,
+) -> impl Responder {
+    let duration = match req.duration_seconds {
+        Some(0) => None,
+        Some(seconds) => Some(std::time::Duration::from_secs(seconds)),
+        None => state.blocklist.default_duration(),
+    };
+
+    match state.blocklist.block(&req.target, &req.reason, "api", duration).await {
+        Ok(entry) => HttpResponse::Created().json(entry),
+        Err(BlocklistError::InvalidTarget(target)) => {
+            ApiError::bad_request(format!("Invalid target: {}", target)).into()
+        }
+        Err(e) => {
+            log::error!("Failed to add blocklist entry: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Check whether an IP is blocked endpoint
+pub async fn check_blocklist(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let ip = path.into_inner();
+    if let Err(e) = check_ip_param(&ip) {
+        return e.into();
+    }
+
+    match state.blocklist.check(&ip).await {
+        Ok(entry) => HttpResponse::Ok().json(BlocklistCheckResponse {
+            ip,
+            blocked: entry.is_some(),
+            entry,
+        }),
+        Err(e) => {
+            log::error!("Failed to check blocklist: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Remove blocklist entry endpoint
+///
+/// The target is the IP or network (e.g. `10.0.0.0/24`) to unblock.
+pub async fn remove_blocklist_entry(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.blocklist.unblock(&path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Blocklist entry").into(),
+        Err(BlocklistError::InvalidTarget(target)) => {
+            ApiError::bad_request(format!("Invalid target: {}", target)).into()
+        }
+        Err(e) => {
+            log::error!("Failed to remove blocklist entry: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get reputation score endpoint
+pub async fn get_reputation(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(e) = check_ip_param(&path) {
+        return e.into();
+    }
+    match state.reputation.get(&path.into_inner()).await {
+        Ok(score) => HttpResponse::Ok().json(score),
+        Err(e) => {
+            log::error!("Failed to get reputation: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Adjust reputation score endpoint
+pub async fn adjust_reputation(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+    req: web::Json<ReputationAdjustRequest>,
+) -> impl Responder {
+    if let Err(e) = check_ip_param(&path).and(req.validate()) {
+        return e.into();
+    }
+
+    match state.reputation.adjust(&path.into_inner(), req.delta).await {
+        Ok(score) => HttpResponse::Ok().json(score),
+        Err(e) => {
+            log::error!("Failed to adjust reputation: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Reset reputation score endpoint
+pub async fn reset_reputation(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(e) = check_ip_param(&path) {
+        return e.into();
+    }
+    match state.reputation.reset(&path.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            log::error!("Failed to reset reputation: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Reset DDoS detection endpoint
+///
+/// Forgets the request, connection and traffic counts and the anomaly
+/// history of the client's address or subnet, lifts the blocks the detector
+/// added and resets its reputation, so a wrongly flagged client is let
+/// through at once. The response lists what was lifted, and any block added
+/// by others that still applies.
+pub async fn reset_ddos_detection(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(e) = check_ip_param(&path) {
+        return e.into();
+    }
+    let ip = path.into_inner();
+    let result = state.ddos_detector.reset_detection(&ip).await;
+    match result {
+        Ok(reset) => {
+            record_audit(&state, &actor(&http_req), "detection.reset", &ip).await;
+            HttpResponse::Ok().json(reset)
+        }
+        Err(e) => {
+            log::error!("Failed to reset detection of {}: {}", ip, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Reset rate limit endpoint
+///
+/// Clears the current window, penalty ban and offenses of a key, as given
+/// to `POST /rate-limit`, or of a client address's key. With the tenant
+/// header, the key of the tenant's client is reset.
+pub async fn reset_rate_limit(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    let mut key = state.config.subnets.client_key(&path);
+    match resolve_tenant(&state, &http_req, None).await {
+        Ok(Some(tenant)) => key = tenant.client_key(&key),
+        Ok(None) => (),
+        Err(e) => return e.into(),
+    }
+    let result = state.rate_limiter.reset_rate_limit(&key).await;
+    match result {
+        Ok(()) => {
+            record_audit(&state, &actor(&http_req), "rate_limit.reset", &key).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => {
+            log::error!("Failed to reset rate limit of {}: {}", key, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Record an action taken through the API
+///
+/// Actions are logged, and kept in the audit log when PostgreSQL is enabled.
+async fn record_audit(state: &ApiState, actor: &str, action: &str, target: &str) {
+    log::info!("{} of {} by {}", action, target, actor);
+    if let Some(archive) = &state.archive {
+        if let Err(e) = archive.record_audit(actor, action, target, serde_json::Value::Null).await {
+            log::error!("Failed to record {} of {} in the audit log: {}", action, target, e);
+        }
+    }
+}
+
+/// Get all quotas endpoint
+///
+/// API keys are redacted; a quota is managed through the full key.
+pub async fn get_quotas(
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    let quota_manager = &state.quota_manager;
+
+    match quota_manager.get_quotas().await {
+        Ok(quotas) => {
+            let quotas: Vec<_> = quotas.into_iter().map(Quota::redacted).collect();
+            HttpResponse::Ok().json(quotas)
+        }
+        Err(e) => {
+            log::error!("Failed to get quotas: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Create quota endpoint
+pub async fn create_quota(
+    state: web::Data<ApiState>,
+    req: web::Json<QuotaRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let quota_manager = &state.quota_manager;
+    let now = Utc::now();
+    let quota = Quota {
+        api_key: req.api_key.clone(),
+        daily_limit: req.daily_limit,
+        monthly_limit: req.monthly_limit,
+        created_at: now,
+        updated_at: now,
+    };
+
+    match quota_manager.get_quota(&quota.api_key).await {
+        Ok(Some(_)) => return ApiError::conflict("A quota already exists for the API key").into(),
+        Ok(None) => (),
+        Err(e) => {
+            log::error!("Failed to get quota: {}", e);
+            return ApiError::internal().into();
+        }
+    }
+
+    match quota_manager.set_quota(&quota).await {
+        Ok(()) => HttpResponse::Created().json(quota),
+        Err(e) => {
+            log::error!("Failed to create quota: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get quota by API key endpoint
+pub async fn get_quota(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let api_key = path.into_inner();
+    let quota_manager = &state.quota_manager;
+
+    let quota = match quota_manager.get_quota(&api_key).await {
+        Ok(Some(quota)) => quota,
+        Ok(None) => return ApiError::not_found("Quota").into(),
+        Err(e) => {
+            log::error!("Failed to get quota: {}", e);
+            return ApiError::internal().into();
+        }
+    };
+
+    match quota_manager.get_status(&api_key).await {
+        Ok(status) => HttpResponse::Ok().json(QuotaResponse { quota, status }),
+        Err(e) => {
+            log::error!("Failed to get quota usage: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Update quota endpoint
+///
+/// Usage in the current period is kept; use the reset endpoint to clear it.
+pub async fn update_quota(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+    req: web::Json<QuotaUpdateRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let api_key = path.into_inner();
+    let quota_manager = &state.quota_manager;
+
+    let mut quota = match quota_manager.get_quota(&api_key).await {
+        Ok(Some(quota)) => quota,
+        Ok(None) => return ApiError::not_found("Quota").into(),
+        Err(e) => {
+            log::error!("Failed to get quota: {}", e);
+            return ApiError::internal().into();
+        }
+    };
+    quota.daily_limit = req.daily_limit;
+    quota.monthly_limit = req.monthly_limit;
+    quota.updated_at = Utc::now();
+
+    match quota_manager.set_quota(&quota).await {
+        Ok(()) => HttpResponse::Ok().json(quota),
+        Err(e) => {
+            log::error!("Failed to update quota: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Delete quota endpoint
+pub async fn delete_quota(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let api_key = path.into_inner();
+    let quota_manager = &state.quota_manager;
+
+    match quota_manager.remove_quota(&api_key).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Quota").into(),
+        Err(e) => {
+            log::error!("Failed to delete quota: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Reset quota usage endpoint
+///
+/// Clears usage for the current day and month without waiting for the period to end.
+pub async fn reset_quota(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let api_key = path.into_inner();
+    let quota_manager = &state.quota_manager;
+
+    match quota_manager.reset_usage(&api_key).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("Failed to reset quota usage: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Concurrency acquire endpoint
+///
+/// Called when a proxied request starts; every allowed acquire must be
+/// followed by a release when the request finishes.
+pub async fn acquire_concurrency(
+    state: web::Data<ApiState>,
+    req: web::Json<ConcurrencyRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let concurrency_limiter = &state.concurrency_limiter;
+    let limit = concurrency_limiter.max_concurrent();
+
+    if !state.config.concurrency.enabled || state.allowlist.is_allowed(Some(&req.ip), None).await {
+        return HttpResponse::Ok().json(ConcurrencyResponse {
+            allowed: true,
+            in_flight: 0,
+            limit,
+        });
+    }
+
+    // Slots are only counted in Redis, so the fallback policy lets requests through like `open`
+    let policy = match state.degradation.active_policy(Subsystem::Concurrency).await {
+        Some(policy) => policy,
+        None => match concurrency_limiter.acquire(&state.config.subnets.client_key(&req.ip)).await {
+            Ok(in_flight) => {
+                return HttpResponse::Ok().json(ConcurrencyResponse {
+                    allowed: true,
+                    in_flight,
+                    limit,
+                })
+            }
+            Err(ConcurrencyError::StorageError(StorageError::Redis(e))) => match state.degradation.fail(Subsystem::Concurrency, &e).await {
+                Some(policy) => policy,
+                None => {
+                    log::error!("Failed to acquire concurrency slot: {}", e);
+                    return ApiError::internal().into();
+                }
+            },
+            Err(ConcurrencyError::ExceededLimit(in_flight)) => {
+                return HttpResponse::TooManyRequests().json(ConcurrencyResponse {
+                    allowed: false,
+                    in_flight,
+                    limit,
+                })
+            }
+        },
+    };
+    match policy {
+        FailurePolicy::Closed => HttpResponse::ServiceUnavailable().json(ConcurrencyResponse {
+            allowed: false,
+            in_flight: 0,
+            limit,
+        }),
+        FailurePolicy::Open | FailurePolicy::Fallback => HttpResponse::Ok().json(ConcurrencyResponse {
+            allowed: true,
+            in_flight: 0,
+            limit,
+        }),
+    }
+}
+
+/// Concurrency release endpoint
+pub async fn release_concurrency(
+    state: web::Data<ApiState>,
+    req: web::Json<ConcurrencyRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    if !state.config.concurrency.enabled {
+        return HttpResponse::NoContent().finish();
+    }
+
+    // Slots acquired while Redis was down weren't counted
+    if state.degradation.active_policy(Subsystem::Concurrency).await.is_some() {
+        return HttpResponse::NoContent().finish();
+    }
+    let concurrency_limiter = &state.concurrency_limiter;
+
+    match concurrency_limiter.release(&state.config.subnets.client_key(&req.ip)).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(ConcurrencyError::StorageError(StorageError::Redis(e))) if state.degradation.fail(Subsystem::Concurrency, &e).await.is_some() => {
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => {
+            log::error!("Failed to release concurrency slot: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// DDoS check endpoint
+#[tracing::instrument(name = "check_ddos", skip_all, fields(otel.kind = "server", traceparent = traceparent(&http_req)))]
+pub async fn check_ddos(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    req: web::Json<DdosCheckRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let mut req = req.into_inner();
+    req.resolve_ip(&state);
+    req.tenant = match resolve_tenant(&state, &http_req, None).await {
+        Ok(tenant) => tenant.map(|tenant| tenant.id),
+        Err(e) => return e.into(),
+    };
+
+    HttpResponse::Ok().json(ddos_decision(&state, req).await)
+}
+
+/// Run a request through the allowlist, blocklist, bot scoring, rules and DDoS detection
+///
+/// The request, and whether it was blocked, are counted in the traffic
+/// aggregates. Detector failures are handled by the `detection`
+/// degradation policy, so a decision is always made.
+pub(crate) async fn ddos_decision(state: &ApiState, req: DdosCheckRequest) -> DdosCheckResponse {
+    state.traffic.record_request(&req.ip, &req.path, &req.user_agent, req.request_size);
+    let ip = req.ip.clone();
+    let response = evaluate_request(state, req).await;
+    if response.mitigation == Some(Mitigation::Block) {
+        state.traffic.record_block(&ip);
+    }
+    response
+}
+
+#[tracing::instrument(name = "ddos_decision", skip_all, fields(ip = %req.ip, path = %req.path))]
+async fn evaluate_request(state: &ApiState, req: DdosCheckRequest) -> DdosCheckResponse {
+    // All traffic counts towards the totals, including allowlisted and blocked clients
+    let aggregate_detection = async {
+        match state.ddos_detector.check_aggregate(&req.ip, &req.path, req.request_size).await {
+            Ok(detection) => detection,
+            Err(e) => {
+                log::error!("Failed to run aggregate detection: {}", e);
+                None
+            }
+        }
+    }
+    .instrument(tracing::info_span!("aggregate_detection"))
+    .await;
+    let attack_mode = state.attack_mode.is_active().await;
+    let escalation = state.escalation.level().await;
+
+    let mut request = req.request_context();
+    if state.allowlist.is_allowed(Some(&req.ip), None).await {
+        return DdosCheckResponse {
+            is_under_attack: false,
+            detection_type: None,
+            classification: None,
+            rule_actions: Vec::new(),
+            mitigation: None,
+            aggregate_detection,
+            bot: None,
+            scanner: None,
+            attack_mode,
+        };
+    }
+
+    match check_blocklists(state, &req.ip, req.tenant.as_deref()).await {
+        Ok(Some(_)) => {
+            return DdosCheckResponse {
+                is_under_attack: true,
+                detection_type: Some(DetectionType::Blocklist),
+                classification: None,
+                rule_actions: Vec::new(),
+                mitigation: Some(Mitigation::Block),
+                aggregate_detection,
+                bot: None,
+                scanner: None,
+                attack_mode,
+            };
+        }
+        Ok(None) => (),
+        Err(e) => log::error!("Failed to check blocklist for {}: {}", req.ip, e),
+    }
+
+    if let Some(trap) = state.honeypot.trap(&req.path) {
+        let blocked = state.honeypot.spring(&req.ip, trap).await;
+        let mut data = HashMap::new();
+        data.insert("ip".to_string(), serde_json::json!(req.ip));
+        data.insert("path".to_string(), serde_json::json!(req.path));
+        data.insert("trap".to_string(), serde_json::json!(trap));
+        data.insert("blocked".to_string(), serde_json::json!(blocked));
+        if let Some(tenant) = &req.tenant {
+            data.insert("tenant".to_string(), serde_json::json!(tenant));
+        }
+        let event = Event::new(EventType::HoneypotHit, "honeypot", data);
+        if let Err(e) = state.analytics.record_event(event).await {
+            log::error!("Failed to record honeypot event: {}", e);
+        }
+        if blocked {
+            return DdosCheckResponse {
+                is_under_attack: true,
+                detection_type: Some(DetectionType::Honeypot),
+                classification: None,
+                rule_actions: Vec::new(),
+                mitigation: Some(Mitigation::Block),
+                aggregate_detection,
+                bot: None,
+                scanner: None,
+                attack_mode,
+            };
+        }
+    }
+
+    let bot = state.bots.score(&request).instrument(tracing::info_span!("bot_detection")).await;
+    request.bot_score = bot.as_ref().map(|bot| bot.score);
+    let verified_bot = bot.as_ref().is_some_and(|bot| bot.verified_bot.is_some());
+
+    if attack_mode && attack_mode_blocks(state, &req.ip, bot.as_ref()).await {
+        return DdosCheckResponse {
+            is_under_attack: true,
+            detection_type: Some(DetectionType::AttackMode),
+            classification: None,
+            rule_actions: Vec::new(),
+            mitigation: Some(Mitigation::Block),
+            aggregate_detection,
+            bot,
+            scanner: None,
+            attack_mode,
+        };
+    }
+
+    let scanner = match state.scanners.check(&request).instrument(tracing::info_span!("scanner_detection")).await {
+        Ok(scanner) => scanner,
+        Err(e) => {
+            log::error!("Failed to check {} for scanner behavior: {}", req.ip, e);
+            None
+        }
+    };
+
+    let rule_actions = if state.config.rule_config.enabled {
+        match state.rule_engine.evaluate_request(&request).await {
+            Ok(actions) => actions,
+            Err(e) => {
+                log::error!("Failed to evaluate rules for {}: {}", req.ip, e);
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    let mitigation = Mitigation::from_actions(&rule_actions);
+    let rule_blocked = mitigation == Some(Mitigation::Block);
+    let passed_challenge = state.challenges.has_passed(&req.ip, request.header("Cookie"));
+
+    // Callers wait on this response, so the delay slows the client down
+    if let Some(delay) = rule_engine::tarpit_delay(&rule_actions) {
+        tokio::time::sleep(delay).await;
+    }
+
+    let detection = match state.degradation.active_policy(Subsystem::Detection).await {
+        Some(policy) => Err(policy),
+        None => match async { state.ddos_detector.check_request(&request).await }
+            .instrument(tracing::info_span!("ddos_detection"))
+            .await
+        {
+            Ok(classification) => Ok(classification),
+            Err(DdosDetectionError::RedisError(e)) => match state.degradation.fail(Subsystem::Detection, &e).await {
+                Some(policy) => Err(policy),
+                None => {
+                    log::error!("Failed to check {} for DDoS attacks: {}", req.ip, e);
+                    Ok(None)
+                }
+            },
+            Err(e) => {
+                log::error!("Failed to check {} for DDoS attacks: {}", req.ip, e);
+                Ok(None)
+            }
+        },
+    };
+    // The detector only counts requests in Redis, so the fallback policy lets requests through like `open`
+    let (classification, detection_closed) = match detection {
+        Ok(classification) => (classification, false),
+        Err(policy) => (None, policy == FailurePolicy::Closed),
+    };
+
+    // Flooding clients are challenged rather than blocked, unless rules block them
+    let http_flood = classification
+        .as_ref()
+        .is_some_and(|classification| classification.category == AttackCategory::ApplicationLayer);
+    let (detection_type, mitigation) = if classification.is_some() && !http_flood {
+        (Some(DetectionType::RequestRate), Some(Mitigation::Block))
+    } else if rule_blocked {
+        (Some(DetectionType::Rule), mitigation)
+    } else if let Some(scanner) = &scanner {
+        (Some(DetectionType::Scanner), scanner.mitigation.clone().or(mitigation))
+    } else if http_flood {
+        (Some(DetectionType::HttpFlood), mitigation.or(Some(Mitigation::Challenge)))
+    } else if (attack_mode && state.attack_mode.config().challenge_all || escalation >= EscalationLevel::Challenge)
+        && !verified_bot
+    {
+        (None, mitigation.or(Some(Mitigation::Challenge)))
+    } else {
+        (None, mitigation)
+    };
+    // Once escalated far enough, detected clients take their whole subnet down with them
+    let mitigation = if detection_type.is_some()
+        && escalation >= EscalationLevel::BlockSubnet
+        && state.escalation.block_subnet(&req.ip).await
+    {
+        Some(Mitigation::Block)
+    } else {
+        mitigation
+    };
+    // Clients that passed a challenge aren't challenged again until their pass expires
+    let mitigation = mitigation.filter(|mitigation| !(passed_challenge && *mitigation == Mitigation::Challenge));
+    // With detection failing closed, clients that can't be checked are blocked
+    let mitigation = if detection_closed { Some(Mitigation::Block) } else { mitigation };
+
+    DdosCheckResponse {
+        is_under_attack: detection_type.is_some(),
+        detection_type,
+        classification,
+        rule_actions,
+        mitigation,
+        aggregate_detection,
+        bot,
+        scanner,
+        attack_mode,
+    }
+}
+
+/// nginx `auth_request` endpoint
+///
+/// nginx describes the original request with `X-Original-URI` and
+/// `X-Original-Method` and passes the client's headers along; the client
+/// address comes from `X-Forwarded-For` or `X-Real-IP`, so nginx must be a
+/// trusted proxy. The request goes through rate limiting, then rules and
+/// DDoS detection, and is answered with the status codes `auth_request`
+/// understands: 204 to let it through, 401 to challenge (or redirect, with
+/// `Location` set) and 403 to reject it. Rate limited requests are rejected
+/// with the usual rate limit headers, which nginx can map to a 429.
+#[tracing::instrument(name = "authorize", skip_all, fields(otel.kind = "server", traceparent = traceparent(&req)))]
+pub async fn authorize(
+    state: web::Data<ApiState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let ip = state.trusted_proxies.client_ip(&req);
+    let mut check = original_request(&req, ip);
+    let tenant = match resolve_tenant(&state, &req, None).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e.into(),
+    };
+    check.tenant = tenant.as_ref().map(|tenant| tenant.id.clone());
+
+    let cost = state.config.rate_limit.cost_for_path(&check.path);
+    let rate_limit = rate_limit_decision(&state, &check.ip, None, &check.path, cost, None, tenant.as_ref()).await;
+    if rate_limit.blocked.is_some() || !rate_limit.allowed {
+        // Requests that get past rate limiting are counted by the DDoS check
+        state.traffic.record_request(&check.ip, &check.path, &check.user_agent, check.request_size);
+    }
+    if rate_limit.blocked.is_some() {
+        state.traffic.record_block(&check.ip);
+        return HttpResponse::Forbidden().finish();
+    }
+    if !rate_limit.allowed {
+        let mut builder = HttpResponse::Forbidden();
+        insert_rate_limit_headers(
+            &mut builder,
+            &state.config.rate_limit.headers,
+            &check.path,
+            &RateLimitHeaderValues {
+                limit: rate_limit.limit,
+                remaining: rate_limit.remaining,
+                reset: rate_limit.reset,
+                rejected: true,
+            },
+        );
+        return builder.finish();
+    }
+
+    let path = check.path.clone();
+    let decision = if rate_limit.allowlisted {
+        None
+    } else {
+        ddos_decision(&state, check).await.mitigation
+    };
+    let mut builder = match decision {
+        Some(Mitigation::Block) => HttpResponse::Forbidden(),
+        Some(Mitigation::Challenge) => HttpResponse::Unauthorized(),
+        Some(Mitigation::Redirect { url, .. }) => {
+            let mut builder = HttpResponse::Unauthorized();
+            builder.insert_header(("Location", url));
+            builder
+        }
+        None => HttpResponse::NoContent(),
+    };
+    if !rate_limit.allowlisted {
+        insert_rate_limit_headers(
+            &mut builder,
+            &state.config.rate_limit.headers,
+            &path,
+            &RateLimitHeaderValues {
+                limit: rate_limit.limit,
+                remaining: rate_limit.remaining,
+                reset: rate_limit.reset,
+                rejected: false,
+            },
+        );
+    }
+    builder.finish()
+}
+
+/// The original request of an `auth_request` subrequest
+fn original_request(req: &HttpRequest, ip: String) -> DdosCheckRequest {
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let uri = header("X-Original-URI").unwrap_or("/");
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let request_size = header("X-Original-Content-Length")
+        .or_else(|| header("Content-Length"))
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let headers = req
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    DdosCheckRequest {
+        ip,
+        request_size,
+        user_agent: String::new(),
+        method: header("X-Original-Method").unwrap_or("GET").to_string(),
+        path: path.to_string(),
+        headers,
+        header_order: Vec::new(),
+        query: web::Query::<HashMap<String, String>>::from_query(query)
+            .map(web::Query::into_inner)
+            .unwrap_or_default(),
+        ja3: None,
+        ja4: None,
+        tls_client_hello: None,
+        tenant: None,
+    }
+}
+
+/// Whether attack mode blocks a client for its reputation or bot score
+///
+/// Verified crawlers are never blocked for their bot score.
+async fn attack_mode_blocks(state: &ApiState, ip: &str, bot: Option<&BotScore>) -> bool {
+    let config = state.attack_mode.config();
+    if bot.is_some_and(|bot| bot.verified_bot.is_none() && bot.score >= config.block_bot_score) {
+        return true;
+    }
+    match state.reputation.get(ip).await {
+        Ok(reputation) => reputation.score < config.block_reputation_below,
+        Err(e) => {
+            log::error!("Failed to get reputation of {}: {}", ip, e);
+            false
+        }
+    }
+}
+
+/// Connection report endpoint
+pub async fn report_connection(
+    state: web::Data<ApiState>,
+    req: web::Json<ConnectionReportRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let slow = req.stats.is_slow(&state.config.ddos_detection.slow_connection);
+    if !slow || state.allowlist.is_allowed(Some(&req.ip), None).await {
+        return HttpResponse::Ok().json(ConnectionReportResponse { slow, blocked: false });
+    }
+
+    match state.ddos_detector.check_slow_connection(&req.ip, &req.stats).await {
+        Ok(blocked) => {
+            if blocked {
+                let mut data = HashMap::new();
+                data.insert("ip".to_string(), serde_json::json!(req.ip));
+                data.insert("stats".to_string(), serde_json::json!(req.stats));
+                let event = Event::new(EventType::SlowConnection, "ddos_detector", data);
+                if let Err(e) = state.analytics.record_event(event).await {
+                    log::error!("Failed to record slow connection event: {}", e);
+                }
+            }
+            HttpResponse::Ok().json(ConnectionReportResponse { slow, blocked })
+        }
+        Err(e) => {
+            log::error!("Failed to check slow connection from {}: {}", req.ip, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Response report endpoint
+///
+/// Proxies report the status of each response served so that clients
+/// probing for paths that don't exist are detected as scanners.
+pub async fn report_response(
+    state: web::Data<ApiState>,
+    req: web::Json<ResponseReportRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    match state.scanners.record_response(&req.ip, req.status).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("Failed to record response to {}: {}", req.ip, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get scanner signatures endpoint
+pub async fn get_scanner_signatures(state: web::Data<ApiState>) -> impl Responder {
+    HttpResponse::Ok().json(state.scanners.signatures().await)
+}
+
+/// Add scanner signature endpoint
+pub async fn add_scanner_signature(
+    state: web::Data<ApiState>,
+    req: web::Json<ScannerSignatureRequest>,
+) -> impl Responder {
+    match state.scanners.add_signature(&req.signature).await {
+        Ok(true) => HttpResponse::Created().finish(),
+        Ok(false) => HttpResponse::Ok().finish(),
+        Err(ScannerError::InvalidSignature(signature)) => {
+            ApiError::bad_request(format!("Invalid signature: {}", signature)).into()
+        }
+        Err(e) => {
+            log::error!("Failed to add scanner signature: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Remove scanner signature endpoint
+///
+/// Only signatures added through the API can be removed.
+pub async fn remove_scanner_signature(
+    state: web::Data<ApiState>,
+    query: web::Query<ScannerSignatureRequest>,
+) -> impl Responder {
+    match state.scanners.remove_signature(&query.signature).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Scanner signature").into(),
+        Err(e) => {
+            log::error!("Failed to remove scanner signature: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Login attempt check endpoint
+///
+/// Attempts that aren't allowed should be rejected with a 429 and
+/// `Retry-After` when locked out, or answered with the challenge page.
+pub async fn check_login_attempt(
+    state: web::Data<ApiState>,
+    req: web::Json<LoginAttemptRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    if state.allowlist.is_allowed(Some(&req.ip), None).await {
+        return HttpResponse::Ok().json(LoginDecision::allowed());
+    }
+
+    let mut decision = match state.logins.check_attempt(&req.ip, &req.username).await {
+        Ok(decision) => decision,
+        Err(e) => {
+            log::error!("Failed to check login attempt from {}: {}", req.ip, e);
+            return ApiError::internal().into();
+        }
+    };
+    // Clients that passed a challenge aren't challenged again until their pass expires
+    if decision.action == Some(LoginAction::Challenge) && state.challenges.has_passed(&req.ip, req.cookie.as_deref()) {
+        decision.allowed = true;
+        decision.action = None;
+    }
+
+    if !decision.allowed {
+        let mut data = HashMap::new();
+        data.insert("ip".to_string(), serde_json::json!(req.ip));
+        data.insert("action".to_string(), serde_json::json!(decision.action));
+        data.insert("signal".to_string(), serde_json::json!(decision.signal));
+        let event = Event::new(EventType::LoginProtection, "login_protection", data);
+        if let Err(e) = state.analytics.record_event(event).await {
+            log::error!("Failed to record login protection event: {}", e);
+        }
+    }
+    HttpResponse::Ok().json(decision)
+}
+
+/// Login attempt outcome report endpoint
+pub async fn report_login_result(
+    state: web::Data<ApiState>,
+    req: web::Json<LoginResultRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    match state.logins.record_result(&req.ip, &req.username, req.success).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("Failed to record login result from {}: {}", req.ip, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Login lockout removal endpoint
+pub async fn remove_login_lockout(
+    state: web::Data<ApiState>,
+    query: web::Query<LoginLockoutRequest>,
+) -> impl Responder {
+    if let Err(e) = query.validate() {
+        return e.into();
+    }
+    match state.logins.unlock(query.ip.as_deref(), query.username.as_deref()).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Lockout").into(),
+        Err(e) => {
+            log::error!("Failed to remove login lockout: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Attack mode status endpoint
+pub async fn get_attack_mode(state: web::Data<ApiState>) -> impl Responder {
+    match state.attack_mode.status().await {
+        Ok(mode) => HttpResponse::Ok().json(AttackModeResponse {
+            enabled: mode.is_some(),
+            state: mode,
+        }),
+        Err(e) => {
+            log::error!("Failed to get attack mode: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Attack mode switch endpoint
+///
+/// While attack mode is on, every instance lowers rate limits, challenges
+/// clients that haven't passed a challenge and blocks badly scored ones.
+/// It switches itself off once its duration has passed.
+pub async fn set_attack_mode(
+    state: web::Data<ApiState>,
+    req: web::Json<AttackModeRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let req = req.into_inner();
+    let reason = req.reason.unwrap_or_else(|| "Switched on through the API".to_string());
+    let result = if req.enabled {
+        state.attack_mode.enable(req.duration_seconds, &reason).await.map(Some)
+    } else {
+        state.attack_mode.disable().await.map(|_| None)
+    };
+    let mode = match result {
+        Ok(mode) => mode,
+        Err(e) => {
+            log::error!("Failed to switch attack mode: {}", e);
+            return ApiError::internal().into();
+        }
+    };
+
+    let mut data = HashMap::new();
+    data.insert("enabled".to_string(), serde_json::json!(req.enabled));
+    if let Some(mode) = &mode {
+        data.insert("reason".to_string(), serde_json::json!(mode.reason));
+        data.insert("expires_at".to_string(), serde_json::json!(mode.expires_at));
+    }
+    let event = Event::new(EventType::System, "attack_mode", data);
+    if let Err(e) = state.analytics.record_event(event).await {
+        log::error!("Failed to record attack mode event: {}", e);
+    }
+
+    HttpResponse::Ok().json(AttackModeResponse {
+        enabled: mode.is_some(),
+        state: mode,
+    })
+}
+
+/// Mitigation escalation status endpoint
+pub async fn get_escalation(state: web::Data<ApiState>) -> impl Responder {
+    match state.escalation.status().await {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => {
+            log::error!("Failed to get escalation status: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Challenge page endpoint
+///
+/// Proxies serve this page to clients whose DDoS check calls for a
+/// challenge, and route the verify endpoint to this service.
+pub async fn get_challenge(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    query: web::Query<ChallengeRequest>,
+) -> impl Responder {
+    let ip = state.trusted_proxies.client_ip(&http_req);
+    let challenge = state.challenges.issue(&ip);
+    metrics::increment_counter!("challenges_issued_total");
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Cache-Control", "no-store"))
+        .body(state.challenges.page(&challenge, &query.return_to))
+}
+
+/// Challenge verification endpoint
+///
+/// Passed clients get a pass cookie and are sent back where they came
+/// from; failed ones get a new challenge.
+pub async fn verify_challenge(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    form: web::Form<ChallengeVerifyRequest>,
+) -> impl Responder {
+    let ip = state.trusted_proxies.client_ip(&http_req);
+    let result = state
+        .challenges
+        .verify(&ip, &form.token, &form.nonce, &form.captcha_response)
+        .await;
+
+    let mut data = HashMap::new();
+    data.insert("ip".to_string(), serde_json::json!(ip));
+    data.insert("mode".to_string(), serde_json::json!(state.challenges.mode()));
+    data.insert("passed".to_string(), serde_json::json!(result.is_ok()));
+    if let Err(e) = &result {
+        data.insert("reason".to_string(), serde_json::json!(e.reason()));
+    }
+    let event = Event::new(EventType::Challenge, "challenge", data);
+    if let Err(e) = state.analytics.record_event(event).await {
+        log::error!("Failed to record challenge event: {}", e);
+    }
+
+    match result {
+        Ok(pass) => {
+            metrics::increment_counter!("challenges_passed_total");
+            let cookie = format!(
+                "{}={}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Lax",
+                state.challenges.cookie_name(),
+                pass,
+                state.challenges.pass_duration()
+            );
+            HttpResponse::SeeOther()
+                .insert_header(("Location", safe_return_to(&form.return_to)))
+                .insert_header(("Set-Cookie", cookie))
+                .finish()
+        }
+        Err(e) => {
+            metrics::increment_counter!("challenges_failed_total", "reason" => e.reason());
+            log::debug!("Challenge from {} failed: {}", ip, e);
+            let challenge = state.challenges.issue(&ip);
+            HttpResponse::Forbidden()
+                .content_type("text/html; charset=utf-8")
+                .insert_header(("Cache-Control", "no-store"))
+                .body(state.challenges.page(&challenge, &form.return_to))
+        }
+    }
+}
+
+/// List attacks endpoint
+pub async fn get_attacks(
+    state: web::Data<ApiState>,
+    query: web::Query<AttacksRequest>,
+) -> impl Responder {
+    match state.attacks.list(query.active, query.limit).await {
+        Ok(attacks) => HttpResponse::Ok().json(attacks),
+        Err(e) => {
+            log::error!("Failed to list attacks: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get attack endpoint
+pub async fn get_attack(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.attacks.get(&path.into_inner()).await {
+        Ok(Some(attack)) => HttpResponse::Ok().json(attack),
+        Ok(None) => ApiError::not_found("Attack").into(),
+        Err(e) => {
+            log::error!("Failed to get attack: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get the report of an attack endpoint
+pub async fn get_attack_report(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+    query: web::Query<AttackReportRequest>,
+) -> impl Responder {
+    match state.attack_reports.get(&path.into_inner()).await {
+        Ok(Some(report)) => match query.format {
+            ReportFormat::Json => HttpResponse::Ok().json(report),
+            ReportFormat::Html => HttpResponse::Ok().content_type("text/html; charset=utf-8").body(report.render_html()),
+            ReportFormat::Pdf => HttpResponse::Ok().content_type("application/pdf").body(report.render_pdf()),
+        },
+        Ok(None) => ApiError::not_found("Attack").into(),
+        Err(e) => {
+            log::error!("Failed to get attack report: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// List baseline metrics endpoint
+pub async fn get_baselines(
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    match state.baseline.metrics().await {
+        Ok(metrics) => HttpResponse::Ok().json(metrics),
+        Err(e) => {
+            log::error!("Failed to list baselines: {}", e);
+            ApiError::internal().into()
         }
-        Err(_) => {
-            let reset = rate_limiter.get_reset_time(&key).await.unwrap_or(0);
-            
-            HttpResponse::TooManyRequests().json(RateLimitResponse {
-                allowed: false,
-                remaining: 0,
-                reset,
-            })
+    }
+}
+
+/// Get baseline endpoint
+pub async fn get_baseline(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.baseline.profile(&path.into_inner()).await {
+        Ok(Some(profile)) => HttpResponse::Ok().json(profile),
+        Ok(None) => ApiError::not_found("Baseline").into(),
+        Err(e) => {
+            log::error!("Failed to get baseline: {}", e);
+            ApiError::internal().into()
         }
     }
 }
 
-/// DDoS check endpoint
-pub async fn check_ddos(
+/// Reset baseline endpoint
+pub async fn reset_baseline(
     state: web::Data<ApiState>,
-    req: web::Json<DdosCheckRequest>,
+    path: web::Path<String>,
 ) -> impl Responder {
-    let mut ddos_detector = state.ddos_detector.lock().await;
-    
-    match ddos_detector.check_request(&req.ip, req.request_size).await {
-        Ok(is_under_attack) => {
-            let response = DdosCheckResponse {
-                is_under_attack,
-                detection_type: if is_under_attack {
-                    Some("request_rate".to_string())
-                } else {
-                    None
-                },
-            };
-            
-            HttpResponse::Ok().json(response)
-        },
-        Err(_) => {
-            HttpResponse::InternalServerError().finish()
-        },
+    match state.baseline.reset(&path.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => ApiError::not_found("Baseline").into(),
+        Err(e) => {
+            log::error!("Failed to reset baseline: {}", e);
+            ApiError::internal().into()
+        }
     }
 }
 
-/// Get all rules endpoint
+/// List rules endpoint
+///
+/// Rules can be filtered by `enabled`, `shadow` and text `q`, sorted with
+/// `sort` and `order`, and paged with `page` or `offset` and `limit`.
 pub async fn get_rules(
     state: web::Data<ApiState>,
+    query: web::Query<RuleQuery>,
 ) -> impl Responder {
-    let rule_engine = state.rule_engine.lock().await;
-    let rules = rule_engine.get_rules().await;
-    
-    let response: Vec<RuleResponse> = rules.iter().map(|rule| {
-        RuleResponse {
-            id: rule.id.clone(),
-            name: rule.name.clone(),
-            description: rule.description.clone(),
-            conditions: rule.conditions.clone(),
-            actions: rule.actions.clone(),
-            priority: rule.priority,
-            enabled: rule.enabled,
+    if let Err(e) = query.validate() {
+        return e.into();
+    }
+    let page = state.rule_engine.query_rules(&query).await;
+    let response: Vec<RuleResponse> = page.rules.into_iter().map(RuleResponse::from).collect();
+
+    HttpResponse::Ok()
+        .insert_header((TOTAL_COUNT_HEADER, page.total.to_string()))
+        .json(response)
+}
+
+/// Who is making a change, for audit history
+///
+/// The holder of the request's key when access control is enabled, else the
+/// `X-Actor` header, defaulting to `api`.
+fn actor(req: &HttpRequest) -> String {
+    if let Some(caller) = req.extensions().get::<auth::Caller>() {
+        return caller.name.clone();
+    }
+    req.headers()
+        .get("X-Actor")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("api")
+        .to_string()
+}
+
+/// Check that the tenant a rule is for exists
+async fn check_tenant_exists(state: &ApiState, tenant: Option<&str>) -> Result<(), ApiError> {
+    let Some(tenant) = tenant else {
+        return Ok(());
+    };
+    match state.tenants.get_tenant(tenant).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ApiError::bad_request(format!("Unknown tenant: {}", tenant))),
+        Err(e) => {
+            log::error!("Failed to get tenant {}: {}", tenant, e);
+            Err(ApiError::internal())
         }
-    }).collect();
-    
-    HttpResponse::Ok().json(response)
+    }
 }
 
 /// Create rule endpoint
 pub async fn create_rule(
     state: web::Data<ApiState>,
+    http_req: HttpRequest,
     req: web::Json<RuleRequest>,
 ) -> impl Responder {
-    let mut rule_engine = state.rule_engine.lock().await;
-    
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+
+    if let Err(e) = check_tenant_exists(&state, req.tenant.as_deref()).await {
+        return e.into();
+    }
+
     // Generate a unique ID
     let id = format!("rule_{}", Uuid::new_v4());
+    let rule = req.into_inner().into_rule(id);
     
-    let rule = Rule {
-        id: id.clone(),
-        name: req.name.clone(),
-        description: req.description.clone(),
-        conditions: req.conditions.clone(),
-        actions: req.actions.clone(),
-        priority: req.priority,
-        enabled: req.enabled,
-    };
-    
-    rule_engine.add_rule(rule);
-    
-    let response = RuleResponse {
-        id,
-        name: req.name.clone(),
-        description: req.description.clone(),
-        conditions: req.conditions.clone(),
-        actions: req.actions.clone(),
-        priority: req.priority,
-        enabled: req.enabled,
-    };
+    if let Err(e) = state.rule_engine.add_rule(rule.clone(), &actor(&http_req)).await {
+        log::error!("Failed to add rule: {}", e);
+        return ApiError::internal().into();
+    }
     
-    HttpResponse::Created().json(response)
+    HttpResponse::Created().json(RuleResponse::from(rule))
 }
 
 /// Get rule by ID endpoint
@@ -240,60 +2847,224 @@ pub async fn get_rule(
     path: web::Path<String>,
 ) -> impl Responder {
     let id = path.into_inner();
-    let rule_engine = state.rule_engine.lock().await;
-    
-    if let Some(rule) = rule_engine.get_rule(&id).await {
-        HttpResponse::Ok().json(RuleResponse {
-            id: rule.id,
-            name: rule.name,
-            description: rule.description,
-            conditions: rule.conditions,
-            actions: rule.actions,
-            priority: rule.priority,
-            enabled: rule.enabled,
-        })
+    if let Some(rule) = state.rule_engine.get_rule(&id).await {
+        HttpResponse::Ok().json(RuleResponse::from(rule))
     } else {
-        HttpResponse::NotFound().finish()
+        ApiError::not_found("Rule").into()
     }
 }
 
 /// Update rule endpoint
 pub async fn update_rule(
     state: web::Data<ApiState>,
+    http_req: HttpRequest,
     path: web::Path<String>,
     rule: web::Json<RuleRequest>,
 ) -> impl Responder {
+    if let Err(e) = rule.validate() {
+        return e.into();
+    }
+    if let Err(e) = check_tenant_exists(&state, rule.tenant.as_deref()).await {
+        return e.into();
+    }
+
     let id = path.into_inner();
-    let mut rule_engine = state.rule_engine.lock().await;
-    let updated_rule = Rule {
-        id: id.clone(),
-        name: rule.name.clone(),
-        description: rule.description.clone(),
-        conditions: rule.conditions.clone(),
-        actions: rule.actions.clone(),
-        priority: rule.priority,
-        enabled: rule.enabled,
-    };
+    let updated_rule = rule.into_inner().into_rule(id.clone());
     
-    if rule_engine.update_rule(&id, updated_rule).await {
-        HttpResponse::Ok().finish()
-    } else {
-        HttpResponse::NotFound().finish()
+    match state.rule_engine.update_rule(&id, updated_rule, &actor(&http_req)).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Rule").into(),
+        Err(e) => {
+            log::error!("Failed to update rule {}: {}", id, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Partial rule update endpoint, answering with the updated rule
+pub async fn patch_rule(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    patch: web::Json<RulePatchRequest>,
+) -> impl Responder {
+    if let Err(e) = patch.validate() {
+        return e.into();
+    }
+
+    let id = path.into_inner();
+    let Some(mut rule) = state.rule_engine.get_rule(&id).await else {
+        return ApiError::not_found("Rule").into();
+    };
+    patch.into_inner().apply(&mut rule);
+
+    match state.rule_engine.update_rule(&id, rule.clone(), &actor(&http_req)).await {
+        Ok(true) => HttpResponse::Ok().json(RuleResponse::from(rule)),
+        Ok(false) => ApiError::not_found("Rule").into(),
+        Err(e) => {
+            log::error!("Failed to update rule {}: {}", id, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Enable rule endpoint
+pub async fn enable_rule(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    set_rule_enabled(&state, &http_req, path.into_inner(), true).await
+}
+
+/// Disable rule endpoint
+pub async fn disable_rule(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    set_rule_enabled(&state, &http_req, path.into_inner(), false).await
+}
+
+async fn set_rule_enabled(state: &ApiState, http_req: &HttpRequest, id: String, enabled: bool) -> HttpResponse {
+    match state.rule_engine.set_rule_enabled(&id, enabled, &actor(http_req)).await {
+        Ok(Some(rule)) => HttpResponse::Ok().json(RuleResponse::from(rule)),
+        Ok(None) => ApiError::not_found("Rule").into(),
+        Err(e) => {
+            log::error!("Failed to update rule {}: {}", id, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Export all rules endpoint
+pub async fn export_rules(
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    HttpResponse::Ok().json(RuleSet {
+        rules: state.rule_engine.get_rules().await,
+    })
+}
+
+/// Import rules endpoint
+pub async fn import_rules(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    req: web::Json<RuleImportRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let req = req.into_inner();
+
+    match state.rule_engine.import_rules(req.rules, req.on_conflict, &actor(&http_req)).await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => {
+            log::error!("Failed to import rules: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Rule dry-run endpoint
+pub async fn test_rule(
+    state: web::Data<ApiState>,
+    req: web::Json<RuleTestRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
     }
+    let req = req.into_inner();
+    let rule = match (req.rule_id, req.rule) {
+        (Some(id), None) => match state.rule_engine.get_rule(&id).await {
+            Some(rule) => rule,
+            None => return ApiError::not_found("Rule").into(),
+        },
+        (None, Some(rule)) => rule.into_rule("test".to_string()),
+        _ => return ApiError::bad_request("Provide exactly one of rule_id and rule").into(),
+    };
+
+    HttpResponse::Ok().json(state.rule_engine.test_rule(&rule, &req.request).await)
 }
 
 /// Delete rule endpoint
 pub async fn delete_rule(
     state: web::Data<ApiState>,
+    http_req: HttpRequest,
     path: web::Path<String>,
 ) -> impl Responder {
     let id = path.into_inner();
-    let mut rule_engine = state.rule_engine.lock().await;
-    
-    if rule_engine.remove_rule(&id).await {
-        HttpResponse::Ok().finish()
-    } else {
-        HttpResponse::NotFound().finish()
+    match state.rule_engine.remove_rule(&id, &actor(&http_req)).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Rule").into(),
+        Err(e) => {
+            log::error!("Failed to remove rule {}: {}", id, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Rule statistics endpoint
+pub async fn get_rule_stats(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let id = path.into_inner();
+    if state.rule_engine.get_rule(&id).await.is_none() {
+        return ApiError::not_found("Rule").into();
+    }
+    match state.rule_engine.get_rule_stats(&id).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(e) => {
+            log::error!("Failed to get stats of rule {}: {}", id, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Statistics of all rules endpoint, most matched first
+pub async fn get_all_rule_stats(state: web::Data<ApiState>) -> impl Responder {
+    match state.rule_engine.get_all_rule_stats().await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(e) => {
+            log::error!("Failed to get rule stats: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Rule history endpoint
+///
+/// Lists the changes made through the API. Rules enabled or disabled by
+/// their schedule or expiry aren't recorded.
+pub async fn get_rule_history(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let id = path.into_inner();
+    match state.rule_engine.get_history(&id).await {
+        Ok(history) => HttpResponse::Ok().json(history),
+        Err(e) => {
+            log::error!("Failed to get history of rule {}: {}", id, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Rule rollback endpoint
+pub async fn rollback_rule(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<(String, u64)>,
+) -> impl Responder {
+    let (id, version) = path.into_inner();
+    match state.rule_engine.rollback_rule(&id, version, &actor(&http_req)).await {
+        Ok(Some(rule)) => HttpResponse::Ok().json(RuleResponse::from(rule)),
+        Ok(None) => ApiError::not_found("Rule version").into(),
+        Err(e) => {
+            log::error!("Failed to roll back rule {}: {}", id, e);
+            ApiError::internal().into()
+        }
     }
 }
 
@@ -301,7 +3072,7 @@ pub async fn delete_rule(
 pub async fn get_analytics_metrics(
     state: web::Data<ApiState>,
 ) -> impl Responder {
-    let analytics = state.analytics.lock().await;
+    let analytics = &state.analytics;
     
     match analytics.get_metrics().await {
         Ok(metrics) => {
@@ -309,7 +3080,7 @@ pub async fn get_analytics_metrics(
         }
         Err(e) => {
             log::error!("Failed to get metrics: {}", e);
-            HttpResponse::InternalServerError().finish()
+            ApiError::internal().into()
         }
     }
 }
@@ -319,53 +3090,242 @@ pub async fn get_analytics_events(
     state: web::Data<ApiState>,
     query: web::Query<AnalyticsEventsRequest>,
 ) -> impl Responder {
-    let analytics = state.analytics.lock().await;
+    if let Err(e) = query.validate() {
+        return e.into();
+    }
+    let analytics = &state.analytics;
     
     let event_type = query.event_type.as_ref().map(|t| {
         match t.as_str() {
             "Request" => EventType::Request,
             "RateLimit" => EventType::RateLimit,
             "DdosDetection" => EventType::DdosDetection,
+            "SlowConnection" => EventType::SlowConnection,
+            "Challenge" => EventType::Challenge,
+            "HoneypotHit" => EventType::HoneypotHit,
+            "LoginProtection" => EventType::LoginProtection,
+            "Escalation" => EventType::Escalation,
             "RuleEngine" => EventType::RuleEngine,
+            "QuotaExceeded" => EventType::QuotaExceeded,
+            "ShadowDecision" => EventType::ShadowDecision,
             "System" => EventType::System,
             _ => EventType::Request,
         }
     });
     
-    match analytics.get_events(query.start_time, query.end_time, event_type).await {
-        Ok(events) => {
-            HttpResponse::Ok().json(events)
+    let filter = EventFilter {
+        event_type,
+        network: query.ip.as_deref().and_then(parse_network),
+        path_prefix: query.path_prefix.clone(),
+        rule_id: query.rule_id.clone(),
+        country: query.country.clone(),
+        tenant: query.tenant.clone(),
+        text: query.q.clone(),
+    };
+
+    // The next page's offset is in a header, so the body stays a list of events
+    let cursor = query.cursor().unwrap_or(EventCursor::Offset(query.offset));
+    match analytics.query_events(query.start_time, query.end_time, &filter, &cursor, query.limit).await {
+        Ok(page) => {
+            let mut response = HttpResponse::Ok();
+            response.insert_header((TOTAL_COUNT_HEADER, page.total.to_string()));
+            if let Some(next_cursor) = page.next_cursor {
+                response.insert_header(("X-Next-Cursor", next_cursor.to_string()));
+            }
+            response.json(page.events)
         },
-        Err(_) => {
-            HttpResponse::InternalServerError().finish()
+        Err(e) => {
+            log::error!("Failed to query events: {}", e);
+            ApiError::internal().into()
         },
     }
 }
 
+/// Events recorded per minute, by type
+pub async fn get_analytics_event_counts(
+    state: web::Data<ApiState>,
+    query: web::Query<AnalyticsEventCountsRequest>,
+) -> impl Responder {
+    if let Err(e) = query.validate() {
+        return e.into();
+    }
+    let analytics = &state.analytics;
+    match analytics.event_counts(query.start_time, query.end_time).await {
+        Ok(counts) => HttpResponse::Ok().json(counts),
+        Err(e) => {
+            log::error!("Failed to get event counts: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get a traffic metric as a series for graphs endpoint
+pub async fn get_analytics_timeseries(
+    state: web::Data<ApiState>,
+    query: web::Query<TimeseriesRequest>,
+) -> impl Responder {
+    let to = query.to.unwrap_or_else(|| Utc::now().timestamp());
+    let from = query.from.unwrap_or(to - 3600);
+    let step = query.step;
+    if step <= 0 || step % 60 != 0 || from > to {
+        return ApiError::bad_request("step must be a positive number of minutes and from no later than to").into();
+    }
+    if (to - from) / step > MAX_METRICS_HISTORY_POINTS || (to - from) / traffic::resolution(step) > traffic::MAX_BUCKETS {
+        return ApiError::bad_request("Range is too large; use a shorter range or a step in whole hours").into();
+    }
+
+    match state.traffic.series(query.metric, from, to, step).await {
+        Ok(points) => HttpResponse::Ok().json(TimeseriesResponse { metric: query.metric, from, to, step, points }),
+        Err(e) => {
+            log::error!("Failed to get traffic series: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get the offending IPs, paths, user agents, countries or ASNs with the most requests endpoint
+pub async fn get_analytics_top(
+    state: web::Data<ApiState>,
+    path: web::Path<TopDimension>,
+    query: web::Query<TopRequest>,
+) -> impl Responder {
+    let to = query.to.unwrap_or_else(|| Utc::now().timestamp());
+    let from = query.from.unwrap_or(to - 3600);
+    if from > to || query.limit > MAX_TOP_LIMIT {
+        return ApiError::bad_request(format!(
+            "from must be no later than to and limit at most {}",
+            MAX_TOP_LIMIT
+        ))
+        .into();
+    }
+    if (to - from) / 3600 > traffic::MAX_BUCKETS {
+        return ApiError::bad_request("Range is too large").into();
+    }
+
+    match state.traffic.top(path.into_inner(), from, to, query.limit).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            log::error!("Failed to get leaderboard: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// TLS fingerprints with the most requests in the current window
+pub async fn get_tls_fingerprints(
+    state: web::Data<ApiState>,
+    query: web::Query<TlsFingerprintsRequest>,
+) -> impl Responder {
+    match state.fingerprints.top(query.kind, query.limit).await {
+        Ok(counts) => HttpResponse::Ok().json(counts),
+        Err(e) => {
+            log::error!("Failed to get TLS fingerprint counts: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
 /// Get monitoring metrics endpoint
 pub async fn get_monitoring_metrics(
     state: web::Data<ApiState>,
 ) -> impl Responder {
-    let monitoring = state.monitoring.lock().await;
+    let monitoring = &state.monitoring;
     
     match monitoring.get_current_metrics().await {
         Ok(metrics) => {
             HttpResponse::Ok().json(metrics)
         },
         Err(_) => {
-            HttpResponse::InternalServerError().finish()
+            ApiError::internal().into()
         },
     }
 }
 
+/// Get monitoring metrics history endpoint
+pub async fn get_monitoring_metrics_history(
+    state: web::Data<ApiState>,
+    query: web::Query<MetricsHistoryRequest>,
+) -> impl Responder {
+    let to = query.to.unwrap_or_else(|| Utc::now().timestamp());
+    let from = query.from.unwrap_or(to - 3600);
+    let step = query.step;
+    if step <= 0 || from > to {
+        return ApiError::bad_request("step must be positive and from no later than to").into();
+    }
+    if (to - from) / step > MAX_METRICS_HISTORY_POINTS {
+        return ApiError::bad_request(format!(
+            "Range holds more than {} points; use a larger step",
+            MAX_METRICS_HISTORY_POINTS
+        ))
+        .into();
+    }
+
+    let monitoring = &state.monitoring;
+    match monitoring.get_metrics_history(from, to, step).await {
+        Ok(points) => HttpResponse::Ok().json(MetricsHistoryResponse { from, to, step, points }),
+        Err(e) => {
+            log::error!("Failed to get metrics history: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get Redis connection pool statistics endpoint
+pub async fn get_redis_pool_stats(
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    HttpResponse::Ok().json(state.redis_pool.stats())
+}
+
+/// Get Redis outage state endpoint
+pub async fn get_degradation_status(
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    HttpResponse::Ok().json(state.degradation.status().await)
+}
+
+/// Get audit log endpoint, newest entries first
+///
+/// Not found unless PostgreSQL is enabled.
+pub async fn get_audit_log(
+    state: web::Data<ApiState>,
+    query: web::Query<AuditLogRequest>,
+) -> impl Responder {
+    let Some(archive) = &state.archive else {
+        return ApiError::not_found("Audit log").into();
+    };
+    match archive.audit_log(query.limit).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            log::error!("Failed to read audit log: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
 /// Get monitoring alerts endpoint
+///
+/// Alerts can be filtered by `level`, `status`, `source` and `since`,
+/// sorted with `sort` and `order`, and paged with `page` or `offset` and
+/// `limit`.
 pub async fn get_monitoring_alerts(
     state: web::Data<ApiState>,
+    query: web::Query<AlertQuery>,
 ) -> impl Responder {
-    let monitoring = state.monitoring.lock().await;
-    
-    let alerts = monitoring.get_active_alerts().await;
-    HttpResponse::Ok().json(alerts)
+    if let Err(e) = query.validate() {
+        return e.into();
+    }
+    let monitoring = &state.monitoring;
+
+    match monitoring.query_alerts(&query).await {
+        Ok(page) => HttpResponse::Ok()
+            .insert_header((TOTAL_COUNT_HEADER, page.total.to_string()))
+            .json(page.alerts),
+        Err(e) => {
+            log::error!("Failed to query alerts: {}", e);
+            ApiError::internal().into()
+        }
+    }
 }
 
 /// Acknowledge alert endpoint
@@ -373,7 +3333,7 @@ pub async fn acknowledge_alert(
     state: web::Data<ApiState>,
     path: web::Path<String>,
 ) -> impl Responder {
-    let monitoring = state.monitoring.lock().await;
+    let monitoring = &state.monitoring;
     let id = path.into_inner();
     
     match monitoring.acknowledge_alert(&id).await {
@@ -381,15 +3341,34 @@ pub async fn acknowledge_alert(
             HttpResponse::NoContent().finish()
         },
         Err(_) => {
-            HttpResponse::InternalServerError().finish()
+            ApiError::internal().into()
         },
     }
 }
 
+/// Resolve alert endpoint
+pub async fn resolve_alert(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let monitoring = &state.monitoring;
+    let id = path.into_inner();
+
+    match monitoring.resolve_alert(&id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("Failed to resolve alert: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use actix_web::{test, web, App};
+    use crate::core::captcha::Captcha;
+    use crate::storage::RedisStorage;
     use redis::Client;
 
     #[actix_web::test]
@@ -405,24 +3384,180 @@ mod tests {
         assert!(resp.status().is_success());
     }
 
+    /// Build API state backed by a local Redis instance
+    async fn test_state(app_config: Config) -> web::Data<ApiState> {
+        let client = Client::open("redis://127.0.0.1:6379").unwrap();
+        let pool = RedisPool::new(client, 1).await.unwrap();
+        let retention_period = std::time::Duration::from_secs(app_config.analytics.retention_days * 24 * 60 * 60);
+
+        web::Data::new(ApiState {
+            allowlist: Allowlist::new(pool.clone(), app_config.allowlist.clone()).unwrap(),
+            blocklist: Blocklist::new(pool.clone(), app_config.blocklist.clone()),
+            geoip: GeoIp::new(app_config.geoip.clone()),
+            trusted_proxies: TrustedProxies::new(&app_config.trusted_proxies),
+            reputation: Reputation::new(pool.clone(), app_config.reputation.clone()),
+            attacks: AttackTracker::new(pool.clone(), app_config.attacks.clone()),
+            attack_reports: AttackReporter::new(
+                pool.clone(),
+                AttackTracker::new(pool.clone(), app_config.attacks.clone()),
+                Arc::new(Analytics::new(pool.clone(), app_config.analytics.clone(), retention_period)),
+                TrafficStats::new(pool.clone(), GeoIp::new(app_config.geoip.clone()), retention_period),
+                std::time::Duration::from_secs(app_config.attacks.retention_seconds),
+            ),
+            baseline: BaselineLearner::new(
+                pool.clone(),
+                app_config.ddos_detection.baseline.clone(),
+                app_config.ddos_detection.anomaly_threshold,
+            ),
+            rate_limiter: Arc::new(RateLimiter::new(
+                pool.clone(),
+                app_config.rate_limit.clone(),
+            )),
+            concurrency_limiter: Arc::new(ConcurrencyLimiter::new(
+                Arc::new(RedisStorage::new(pool.clone())),
+                app_config.concurrency.clone(),
+            )),
+            quota_manager: Arc::new(QuotaManager::new(pool.clone())),
+            ddos_detector: Arc::new(DdosDetector::new(
+                pool.clone(),
+                app_config.ddos_detection.clone(),
+            )),
+            fingerprints: FingerprintTracker::new(Arc::new(RedisStorage::new(pool.clone())), app_config.ddos_detection.tls_fingerprint.clone()),
+            bots: BotDetector::new(pool.clone(), app_config.bot_detection.clone()),
+            honeypot: Honeypot::new(
+                app_config.honeypot.clone(),
+                Blocklist::new(pool.clone(), app_config.blocklist.clone()),
+                Reputation::new(pool.clone(), app_config.reputation.clone()),
+            ),
+            scanners: ScannerDetector::new(pool.clone(), app_config.scanner_detection.clone()),
+            logins: LoginProtection::new(pool.clone(), app_config.login_protection.clone()),
+            attack_mode: AttackMode::new(pool.clone(), app_config.attack_mode.clone()),
+            escalation: Escalation::new(
+                pool.clone(),
+                app_config.escalation.clone(),
+                Blocklist::new(pool.clone(), app_config.blocklist.clone()),
+                app_config.subnets.clone(),
+                app_config.ddos_detection.request_rate_window,
+            ),
+            challenges: ChallengeManager::new(app_config.challenge.clone())
+                .with_captcha(Captcha::new(app_config.challenge.captcha.clone()).unwrap()),
+            rule_engine: Arc::new(RuleEngine::new(
+                pool.clone(),
+                app_config.rule_config.clone(),
+            )),
+            analytics: Arc::new(Analytics::new(
+                pool.clone(),
+                app_config.analytics.clone(),
+                retention_period,
+            )),
+            monitoring: Arc::new(Monitoring::new(
+                pool.clone(),
+                app_config.monitoring.clone(),
+            )),
+            alert_rules: AlertRules::new(pool.clone()),
+            silences: Silences::new(pool.clone()),
+            live_events: LiveEvents::new(),
+            traffic: TrafficStats::new(pool.clone(), GeoIp::new(app_config.geoip.clone()), retention_period),
+            webhooks: Webhooks::new(pool.clone(), app_config.webhooks.clone()),
+            tenants: Tenants::new(pool.clone(), Blocklist::new(pool.clone(), app_config.blocklist.clone())),
+            degradation: Degradation::new(pool.clone(), app_config.degradation.clone()),
+            heartbeats: Heartbeats::default(),
+            archive: None,
+            redis_pool: pool,
+            config: app_config,
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_original_request() {
+        let req = test::TestRequest::get()
+            .uri("/api/v1/authorize")
+            .insert_header(("X-Original-URI", "/search?q=a%20b"))
+            .insert_header(("X-Original-Method", "POST"))
+            .insert_header(("X-Original-Content-Length", "512"))
+            .insert_header(("User-Agent", "curl/8.0"))
+            .to_http_request();
+
+        let check = original_request(&req, "203.0.113.7".to_string());
+        assert_eq!(check.ip, "203.0.113.7");
+        assert_eq!(check.method, "POST");
+        assert_eq!(check.path, "/search");
+        assert_eq!(check.query.get("q").map(String::as_str), Some("a b"));
+        assert_eq!(check.request_size, 512);
+        assert_eq!(check.request_context().header("User-Agent"), Some("curl/8.0"));
+
+        let req = test::TestRequest::get().uri("/api/v1/authorize").to_http_request();
+        let check = original_request(&req, "203.0.113.7".to_string());
+        assert_eq!((check.method.as_str(), check.path.as_str()), ("GET", "/"));
+    }
+
+    #[actix_web::test]
+    async fn test_request_validation() {
+        let rule: RuleRequest = serde_json::from_value(serde_json::json!({
+            "name": " ",
+            "conditions": [],
+            "actions": [],
+            "priority": -1,
+            "enabled": true,
+        }))
+        .unwrap();
+        let error = rule.validate().unwrap_err();
+        assert_eq!(error.to_string(), "name must not be empty; priority must not be negative");
+
+        let events: AnalyticsEventsRequest = serde_json::from_value(serde_json::json!({
+            "start_time": 20,
+            "end_time": 10,
+            "ip": "10.0.0.0/8",
+        }))
+        .unwrap();
+        assert_eq!(events.validate().unwrap_err().to_string(), "start_time must be no later than end_time");
+
+        let concurrency = ConcurrencyRequest { ip: "not-an-ip".to_string() };
+        assert!(concurrency.validate().is_err());
+        assert!(check_ip_param("2001:db8::1").is_ok());
+
+        let rate_limit: RateLimitRequest = serde_json::from_value(serde_json::json!({ "path": "/", "cost": 0 })).unwrap();
+        assert_eq!(rate_limit.validate().unwrap_err().to_string(), "cost must be positive");
+        let check: check::CheckRequest =
+            serde_json::from_value(serde_json::json!({ "ip": "203.0.113.7", "request_size": 0, "cost": 0 })).unwrap();
+        assert_eq!(check.validate().unwrap_err().to_string(), "cost must be positive");
+    }
+
+    #[actix_web::test]
+    async fn test_rule_patch() {
+        let request: RuleRequest = serde_json::from_value(serde_json::json!({
+            "name": "Block scrapers",
+            "description": "Scrapers",
+            "conditions": [],
+            "actions": [],
+            "priority": 10,
+            "enabled": true,
+            "expires_at": "2030-01-01T00:00:00Z",
+        }))
+        .unwrap();
+        let mut rule = request.into_rule("r1".to_string());
+
+        // Fields left out are kept, and null clears them
+        let patch: RulePatchRequest = serde_json::from_value(serde_json::json!({
+            "enabled": false,
+            "description": null,
+        }))
+        .unwrap();
+        assert!(patch.validate().is_ok());
+        patch.apply(&mut rule);
+        assert!(!rule.enabled);
+        assert_eq!(rule.description, None);
+        assert_eq!(rule.name, "Block scrapers");
+        assert_eq!(rule.priority, 10);
+        assert!(rule.expires_at.is_some());
+
+        let patch: RulePatchRequest = serde_json::from_value(serde_json::json!({ "name": "", "priority": -1 })).unwrap();
+        assert_eq!(patch.validate().unwrap_err().to_string(), "name must not be empty; priority must not be negative");
+    }
+
     #[actix_web::test]
     async fn test_rate_limit() {
-        let client = Client::open("redis://127.0.0.1:6379").unwrap();
-        let config = Config::default();
-        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(
-            client.clone(),
-            config.rate_limit.clone(),
-        )));
-        let ddos_detector = Arc::new(Mutex::new(DdosDetector::new(
-            client.clone(),
-            config.ddos_detection.clone(),
-        )));
-
-        let state = web::Data::new(ApiState {
-            rate_limiter,
-            ddos_detector,
-            config,
-        });
+        let state = test_state(Config::default()).await;
 
         let app = test::init_service(
             App::new()
@@ -432,12 +3567,13 @@ mod tests {
 
         let req = test::TestRequest::post()
             .uri("/api/v1/rate-limit")
-            .set_json(RateLimitRequest {
-                ip: "127.0.0.1".to_string(),
-            })
+            .set_json(serde_json::json!({
+                "ip": "127.0.0.1",
+                "path": "/",
+            }))
             .to_request();
         
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,1192 @@
+//! OpenAPI document of the API.
+//!
+//! The document is built from the table of operations below, with the
+//! permission each operation needs worked out by `auth`, and served at
+//! `/api/v1/openapi.json`; `/api/v1/docs` renders it with Swagger UI, whose
+//! files are served from `server.swagger_ui_dir` rather than a CDN.
+//! Request schemas carry an example, which the tests deserialize into the
+//! request types. The tests also check each schema's properties against
+//! the fields of its type, and that every operation is routed, so the
+//! document doesn't drift from the handlers.
+
+use std::path::Path;
+use actix_web::http::Method;
+use actix_web::{web, HttpResponse, Responder};
+use serde_json::{json, Map, Value};
+use super::auth::required_permission;
+use super::error::ApiError;
+use super::ApiState;
+
+/// Swagger UI files served from `server.swagger_ui_dir`, with their media types
+const SWAGGER_UI_FILES: [(&str, &str); 2] = [
+    ("swagger-ui.css", "text/css; charset=utf-8"),
+    ("swagger-ui-bundle.js", "text/javascript; charset=utf-8"),
+];
+
+/// A query parameter
+#[derive(Clone)]
+struct Param {
+    name: &'static str,
+    schema: Value,
+    required: bool,
+    description: &'static str,
+}
+
+fn param(name: &'static str, schema: Value, description: &'static str) -> Param {
+    Param { name, schema, required: false, description }
+}
+
+fn required(name: &'static str, schema: Value, description: &'static str) -> Param {
+    Param { name, schema, required: true, description }
+}
+
+/// What an operation answers with on success
+enum Reply {
+    /// JSON matching a schema
+    Json(Value),
+    /// A body of another media type
+    Media(&'static str),
+    /// No body
+    Empty,
+}
+
+/// An operation of the API
+struct Operation {
+    method: Method,
+    /// Path below `/api/v1`
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    query: Vec<Param>,
+    /// Schema name of the JSON body
+    body: Option<&'static str>,
+    reply: Reply,
+    /// Whether the reply is a page with a total count
+    paged: bool,
+}
+
+fn op(method: Method, path: &'static str, tag: &'static str, summary: &'static str) -> Operation {
+    Operation {
+        method,
+        path,
+        tag,
+        summary,
+        query: Vec::new(),
+        body: None,
+        reply: Reply::Json(json!({ "type": "object" })),
+        paged: false,
+    }
+}
+
+impl Operation {
+    fn query(mut self, query: Vec<Param>) -> Self {
+        self.query = query;
+        self
+    }
+
+    fn body(mut self, schema: &'static str) -> Self {
+        self.body = Some(schema);
+        self
+    }
+
+    fn returns(mut self, schema: Value) -> Self {
+        self.reply = Reply::Json(schema);
+        self
+    }
+
+    fn returns_media(mut self, media_type: &'static str) -> Self {
+        self.reply = Reply::Media(media_type);
+        self
+    }
+
+    fn returns_nothing(mut self) -> Self {
+        self.reply = Reply::Empty;
+        self
+    }
+
+    /// Paged with `page` or `offset` and `limit`, counting matches in `X-Total-Count`
+    fn paged(mut self) -> Self {
+        self.query.extend([
+            param("page", integer(), "Page to return, from 1, instead of skipping `offset` items"),
+            param("offset", integer(), "Items to skip"),
+            param("limit", integer(), "Most items to return"),
+        ]);
+        self.paged = true;
+        self
+    }
+
+    /// Path parameters, such as `id` in `/rules/{id}`
+    fn path_params(&self) -> impl Iterator<Item = &str> {
+        self.path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+    }
+
+    fn to_json(&self) -> Value {
+        let mut parameters: Vec<Value> = self
+            .path_params()
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": string() }))
+            .collect();
+        parameters.extend(self.query.iter().map(|param| {
+            json!({
+                "name": param.name,
+                "in": "query",
+                "required": param.required,
+                "description": param.description,
+                "schema": param.schema,
+            })
+        }));
+
+        let mut success = match &self.reply {
+            Reply::Json(schema) => json!({ "description": "Success", "content": { "application/json": { "schema": schema } } }),
+            Reply::Media(media_type) => json!({ "description": "Success", "content": { *media_type: {} } }),
+            Reply::Empty => json!({ "description": "Success" }),
+        };
+        if self.paged {
+            success["headers"] = json!({
+                "X-Total-Count": { "description": "Items matching the query, on any page", "schema": integer() }
+            });
+        }
+        let mut responses = Map::new();
+        responses.insert("200".to_string(), success);
+        responses.insert("default".to_string(), response_ref("Error"));
+
+        let mut operation = json!({
+            "tags": [self.tag],
+            "summary": self.summary,
+            "operationId": operation_id(&self.method, self.path),
+            "parameters": parameters,
+        });
+        match required_permission(&self.method, Some(&format!("/api/v1{}", self.path))) {
+            Some(permission) => {
+                operation["description"] = json!(format!("Requires the `{}` permission when access control is enabled.", permission));
+                operation["security"] = json!([{ "bearer": [] }, { "apiKey": [] }]);
+                responses.insert("401".to_string(), response_ref("Error"));
+                responses.insert("403".to_string(), response_ref("Error"));
+            }
+            None => operation["security"] = json!([]),
+        }
+        if let Some(schema) = self.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema_ref(schema) } },
+            });
+        }
+        operation["responses"] = Value::Object(responses);
+        operation
+    }
+}
+
+/// Identifier of an operation for generated clients, such as `delete_rules_id`
+fn operation_id(method: &Method, path: &str) -> String {
+    let words: Vec<String> = path
+        .split(['/', '-', '.'])
+        .filter(|word| !word.is_empty())
+        .map(|word| word.trim_matches(['{', '}']).replace('-', "_"))
+        .collect();
+    format!("{}_{}", method.as_str().to_lowercase(), words.join("_"))
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer", "minimum": 0 })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn timestamp() -> Value {
+    json!({ "type": "integer", "description": "Unix timestamp in seconds" })
+}
+
+fn enumeration(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn response_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/responses/{}", name) })
+}
+
+/// Readiness probe response, also sent along with a 503
+fn readiness_schema() -> Value {
+    let status = || enumeration(&["ok", "slow", "down", "stale", "missing", "disabled"]);
+    json!({
+        "type": "object",
+        "properties": {
+            "status": enumeration(&["ready", "not_ready"]),
+            "checks": {
+                "type": "object",
+                "properties": {
+                    "redis": {
+                        "type": "object",
+                        "properties": {
+                            "status": status(),
+                            "latency_ms": { "type": "integer", "nullable": true },
+                            "error": { "type": "string", "nullable": true },
+                        },
+                    },
+                    "tasks": {
+                        "type": "object",
+                        "properties": {
+                            "status": status(),
+                            "tasks": array(json!({
+                                "type": "object",
+                                "properties": { "name": string(), "age_seconds": integer(), "status": status() },
+                            })),
+                        },
+                    },
+                    "rules": { "type": "object", "properties": { "status": status() } },
+                    "geoip": {
+                        "type": "object",
+                        "properties": { "status": status(), "missing": array(string()) },
+                    },
+                },
+            },
+        },
+    })
+}
+
+const ALERT_LEVELS: &[&str] = &["Info", "Warning", "Error", "Critical"];
+
+/// Rule fields that may be cleared with `null` marked as such
+fn nullable_rule_fields(mut fields: Value) -> Value {
+    for field in ["description", "schedule", "expires_at"] {
+        fields[field]["nullable"] = json!(true);
+    }
+    fields
+}
+
+/// Every operation of the API
+fn operations() -> Vec<Operation> {
+    use Method as M;
+    let range = || {
+        vec![
+            param("from", timestamp(), "Start of the range; an hour before `to` by default"),
+            param("to", timestamp(), "End of the range; now by default"),
+        ]
+    };
+    let events_range = || {
+        vec![
+            required("start_time", timestamp(), "Start of the range"),
+            required("end_time", timestamp(), "End of the range, included"),
+        ]
+    };
+
+    vec![
+        op(M::GET, "/health", "System", "Check that the service is up").returns(schema_ref("Health")),
+        op(M::GET, "/health/live", "System", "Liveness probe").returns(json!({
+            "type": "object",
+            "properties": { "status": enumeration(&["ok"]) },
+        })),
+        op(M::GET, "/health/ready", "System", "Readiness probe; answers 503 when a dependency fails")
+            .returns(schema_ref("Readiness")),
+        // Decisions, called by the proxy for traffic
+        op(M::POST, "/rate-limit", "Decisions", "Count a request against the client's rate limit")
+            .body("RateLimitRequest")
+            .returns(schema_ref("RateLimitResponse")),
+        op(M::GET, "/rate-limit/status", "Decisions", "Get a rate limit's usage without counting a request")
+            .query(vec![
+                param("key", string(), "Key to report on, as given to `POST /rate-limit`"),
+                param("ip", string(), "Client whose limit applies, and whose address or subnet is the key without one"),
+            ])
+            .returns(schema_ref("RateLimitStatus")),
+        op(M::DELETE, "/rate-limit/{key}", "Protection", "Reset the rate limit and penalties of a key or client")
+            .returns_nothing(),
+        op(M::DELETE, "/ddos/{ip}", "Protection", "Reset DDoS detection of a client")
+            .returns(schema_ref("DetectionReset")),
+        op(M::GET, "/rate-limit/effective", "Decisions", "Get the rate limit currently enforced"),
+        op(M::POST, "/concurrency/acquire", "Decisions", "Take a concurrent request slot")
+            .body("ConcurrencyRequest")
+            .returns(schema_ref("ConcurrencyResponse")),
+        op(M::POST, "/concurrency/release", "Decisions", "Give back a concurrent request slot")
+            .body("ConcurrencyRequest")
+            .returns_nothing(),
+        op(M::POST, "/check", "Decisions", "Run a request through every layer and get a single decision")
+            .body("CheckRequest")
+            .returns(schema_ref("CheckResponse")),
+        op(M::POST, "/ddos-check", "Decisions", "Run a request through the protections").body("DdosCheckRequest"),
+        op(M::GET, "/authorize", "Decisions", "Decide on the request described by the X-Original-* headers").returns_nothing(),
+        op(M::POST, "/connections/report", "Decisions", "Report a completed connection").body("ConnectionReportRequest"),
+        op(M::POST, "/responses/report", "Decisions", "Report a served response")
+            .body("ResponseReportRequest")
+            .returns_nothing(),
+        op(M::POST, "/login/attempt", "Decisions", "Check a login attempt").body("LoginAttemptRequest"),
+        op(M::POST, "/login/result", "Decisions", "Report the outcome of a login attempt")
+            .body("LoginResultRequest")
+            .returns_nothing(),
+        op(M::GET, "/challenge", "Decisions", "Get the challenge page")
+            .query(vec![param("return_to", string(), "Path to return to once the challenge is passed")])
+            .returns_media("text/html"),
+        op(M::POST, "/challenge/verify", "Decisions", "Submit a challenge solution").returns_media("text/html"),
+        // Lists and reputation
+        op(M::GET, "/allowlist", "Lists", "List allowlist entries").returns(array(json!({ "type": "object" }))),
+        op(M::POST, "/allowlist", "Lists", "Add an allowlist entry").body("AllowlistRequest"),
+        op(M::DELETE, "/allowlist/{value}", "Lists", "Remove an allowlist entry").returns_nothing(),
+        op(M::GET, "/blocklist", "Lists", "List blocklist entries").returns(array(json!({ "type": "object" }))),
+        op(M::POST, "/blocklist", "Lists", "Block an IP or network").body("BlocklistRequest"),
+        op(M::GET, "/blocklist/check/{ip}", "Lists", "Check whether an IP is blocked"),
+        op(M::DELETE, "/blocklist/{target}", "Lists", "Unblock an IP or network").returns_nothing(),
+        op(M::GET, "/reputation/{ip}", "Lists", "Get a client's reputation score"),
+        op(M::POST, "/reputation/{ip}", "Lists", "Adjust a client's reputation score").body("ReputationAdjustRequest"),
+        op(M::DELETE, "/reputation/{ip}", "Lists", "Reset a client's reputation score").returns_nothing(),
+        // API key quotas
+        op(M::GET, "/quotas", "Quotas", "List quotas").returns(array(schema_ref("Quota"))),
+        op(M::POST, "/quotas", "Quotas", "Create a quota").body("QuotaRequest").returns(schema_ref("Quota")),
+        op(M::GET, "/quotas/{api_key}", "Quotas", "Get a quota and its usage"),
+        op(M::PUT, "/quotas/{api_key}", "Quotas", "Change a quota's limits").body("QuotaUpdateRequest"),
+        op(M::DELETE, "/quotas/{api_key}", "Quotas", "Delete a quota").returns_nothing(),
+        op(M::POST, "/quotas/{api_key}/reset", "Quotas", "Reset a quota's usage").returns_nothing(),
+        // Protections
+        op(M::GET, "/scanners/signatures", "Protection", "List scanner signatures").returns(array(string())),
+        op(M::POST, "/scanners/signatures", "Protection", "Add a scanner signature").body("ScannerSignatureRequest"),
+        op(M::DELETE, "/scanners/signatures", "Protection", "Remove a scanner signature")
+            .query(vec![required("signature", string(), "Signature to remove")])
+            .returns_nothing(),
+        op(M::DELETE, "/login/lockouts", "Protection", "Lift a login lockout")
+            .query(vec![
+                param("ip", string(), "Locked out client"),
+                param("username", string(), "Locked out username"),
+            ])
+            .returns_nothing(),
+        op(M::GET, "/protection/attack-mode", "Protection", "Get whether attack mode is on"),
+        op(M::POST, "/protection/attack-mode", "Protection", "Switch attack mode on or off").body("AttackModeRequest"),
+        op(M::GET, "/protection/escalation", "Protection", "Get the escalation level"),
+        // Attacks and baselines
+        op(M::GET, "/attacks", "Attacks", "List attacks")
+            .query(vec![
+                param("active", boolean(), "Only list attacks still going on"),
+                param("limit", integer(), "Most attacks to return"),
+            ])
+            .returns(array(json!({ "type": "object" }))),
+        op(M::GET, "/attacks/{id}", "Attacks", "Get an attack"),
+        op(M::GET, "/attacks/{id}/report", "Attacks", "Get the report of an attack")
+            .query(vec![param("format", enumeration(&["json", "html", "pdf"]), "How the report is rendered")]),
+        op(M::GET, "/baselines", "Attacks", "List learned traffic baselines"),
+        op(M::GET, "/baselines/{metric}", "Attacks", "Get a learned traffic baseline"),
+        op(M::DELETE, "/baselines/{metric}", "Attacks", "Forget a learned traffic baseline").returns_nothing(),
+        // Rules
+        op(M::GET, "/rules", "Rules", "List rules")
+            .query(vec![
+                param("enabled", boolean(), "Only rules that are, or aren't, enabled"),
+                param("shadow", boolean(), "Only rules that are, or aren't, in shadow mode"),
+                param("q", string(), "Text in the rule's ID, name or description, ignoring case"),
+                param("tenant", string(), "Only rules of this tenant"),
+                param("sort", enumeration(&["priority", "name", "id"]), "Field rules are sorted by"),
+                param("order", enumeration(&["asc", "desc"]), "Sort direction"),
+            ])
+            .paged()
+            .returns(array(schema_ref("Rule"))),
+        op(M::POST, "/rules", "Rules", "Create a rule").body("RuleRequest").returns(schema_ref("Rule")),
+        op(M::POST, "/rules/test", "Rules", "Dry-run a rule against a sample request").body("RuleTestRequest"),
+        op(M::GET, "/rules/export", "Rules", "Export all rules").returns(schema_ref("RuleSet")),
+        op(M::POST, "/rules/import", "Rules", "Import rules").body("RuleImportRequest"),
+        op(M::GET, "/rules/stats", "Rules", "Get the match statistics of all rules"),
+        op(M::GET, "/rules/{id}", "Rules", "Get a rule").returns(schema_ref("Rule")),
+        op(M::PUT, "/rules/{id}", "Rules", "Replace a rule").body("RuleRequest").returns_nothing(),
+        op(M::PATCH, "/rules/{id}", "Rules", "Change some fields of a rule")
+            .body("RulePatchRequest")
+            .returns(schema_ref("Rule")),
+        op(M::DELETE, "/rules/{id}", "Rules", "Delete a rule").returns_nothing(),
+        op(M::POST, "/rules/{id}/enable", "Rules", "Enable a rule").returns(schema_ref("Rule")),
+        op(M::POST, "/rules/{id}/disable", "Rules", "Disable a rule").returns(schema_ref("Rule")),
+        op(M::GET, "/rules/{id}/stats", "Rules", "Get the match statistics of a rule"),
+        op(M::GET, "/rules/{id}/history", "Rules", "List the versions of a rule"),
+        op(M::POST, "/rules/{id}/rollback/{version}", "Rules", "Restore an earlier version of a rule").returns(schema_ref("Rule")),
+        // Analytics
+        op(M::GET, "/analytics/metrics", "Analytics", "Get the request counters"),
+        op(M::GET, "/analytics/events", "Analytics", "List events")
+            .query(
+                [
+                    events_range(),
+                    vec![
+                        param("event_type", string(), "Type of the events"),
+                        param("ip", string(), "IP address or CIDR network of the event's client"),
+                        param("path_prefix", string(), "Start of the request path"),
+                        param("rule_id", string(), "ID of the rule the event is about"),
+                        param("country", string(), "Country code of the event's client"),
+                        param("tenant", string(), "Tenant the event's request was attributed to"),
+                        param("q", string(), "Text in the event's source or data, ignoring case"),
+                        param("cursor", string(), "Where the page starts, from the X-Next-Cursor header of the previous page"),
+                    ],
+                ]
+                .concat(),
+            )
+            .paged()
+            .returns(array(schema_ref("Event"))),
+        op(M::GET, "/analytics/events/export", "Analytics", "Download events")
+            .query(
+                [
+                    vec![required("format", enumeration(&["csv", "ndjson"]), "File format")],
+                    range(),
+                ]
+                .concat(),
+            )
+            .returns_media("application/octet-stream"),
+        op(M::GET, "/analytics/event-counts", "Analytics", "Count events per minute, by type").query(events_range()),
+        op(M::GET, "/analytics/timeseries", "Analytics", "Get a traffic metric as a series")
+            .query(
+                [
+                    vec![
+                        required("metric", enumeration(&["requests", "blocks", "rate_limits", "bytes", "unique_ips"]), "Metric"),
+                        param("step", integer(), "Seconds each point sums over, a whole number of minutes"),
+                    ],
+                    range(),
+                ]
+                .concat(),
+            ),
+        op(M::GET, "/analytics/top/{dimension}", "Analytics", "Get the top IPs, paths, user agents, countries or ASNs")
+            .query([range(), vec![param("limit", integer(), "Most members to return")]].concat()),
+        op(M::GET, "/stream/events", "Analytics", "Stream live events as Server-Sent Events")
+            .query(stream_filters())
+            .returns_media("text/event-stream"),
+        op(M::GET, "/stream/ws", "Analytics", "Stream live events over a WebSocket")
+            .query(stream_filters())
+            .returns_nothing(),
+        op(M::GET, "/analytics/tls-fingerprints", "Analytics", "Count requests by TLS fingerprint").query(vec![
+            param("kind", enumeration(&["ja3", "ja4"]), "Fingerprint kind"),
+            param("limit", integer(), "Most fingerprints to return"),
+        ]),
+        // Monitoring
+        op(M::GET, "/monitoring/metrics", "Monitoring", "Get the current system metrics"),
+        op(M::GET, "/monitoring/metrics/history", "Monitoring", "Get system metrics over time")
+            .query([range(), vec![param("step", integer(), "Seconds each point averages over")]].concat()),
+        op(M::GET, "/monitoring/redis-pool", "Monitoring", "Get Redis connection pool statistics"),
+        op(M::GET, "/monitoring/degradation", "Monitoring", "Get which subsystems run degraded"),
+        op(M::GET, "/audit-log", "Monitoring", "List rule changes, newest first")
+            .query(vec![param("limit", integer(), "Most entries to return")]),
+        op(M::GET, "/monitoring/alerts", "Monitoring", "List alerts")
+            .query(vec![
+                param("level", enumeration(ALERT_LEVELS), "Alert level"),
+                param("status", enumeration(&["Active", "Acknowledged", "Resolved"]), "Alert status"),
+                param("source", string(), "Alert source"),
+                param("since", json!({ "type": "string", "format": "date-time" }), "Only alerts created at or after this time"),
+                param("sort", enumeration(&["created_at", "updated_at", "level"]), "Field alerts are sorted by"),
+                param("order", enumeration(&["asc", "desc"]), "Sort direction"),
+            ])
+            .paged()
+            .returns(array(json!({ "type": "object" }))),
+        op(M::POST, "/monitoring/alerts/{id}/acknowledge", "Monitoring", "Acknowledge an alert").returns_nothing(),
+        op(M::POST, "/monitoring/alerts/{id}/resolve", "Monitoring", "Resolve an alert").returns_nothing(),
+        op(M::GET, "/monitoring/alert-rules", "Alerting", "List alert rules"),
+        op(M::POST, "/monitoring/alert-rules", "Alerting", "Create an alert rule").body("AlertRuleRequest"),
+        op(M::GET, "/monitoring/alert-rules/{id}", "Alerting", "Get an alert rule"),
+        op(M::PUT, "/monitoring/alert-rules/{id}", "Alerting", "Replace an alert rule").body("AlertRuleRequest"),
+        op(M::DELETE, "/monitoring/alert-rules/{id}", "Alerting", "Delete an alert rule").returns_nothing(),
+        op(M::GET, "/monitoring/silences", "Alerting", "List silences")
+            .query(vec![param("active", boolean(), "Only silences in effect now")]),
+        op(M::POST, "/monitoring/silences", "Alerting", "Silence matching alerts for a while").body("SilenceRequest"),
+        op(M::GET, "/monitoring/silences/{id}", "Alerting", "Get a silence"),
+        op(M::DELETE, "/monitoring/silences/{id}", "Alerting", "End a silence").returns_nothing(),
+        op(M::GET, "/webhooks", "Alerting", "List webhook endpoints"),
+        op(M::POST, "/webhooks", "Alerting", "Add a webhook endpoint").body("WebhookRequest"),
+        op(M::GET, "/webhooks/dead-letters", "Alerting", "List notifications that couldn't be delivered")
+            .query(vec![param("limit", integer(), "Most notifications to return")]),
+        op(M::GET, "/webhooks/{id}", "Alerting", "Get a webhook endpoint"),
+        op(M::PUT, "/webhooks/{id}", "Alerting", "Replace a webhook endpoint").body("WebhookRequest"),
+        op(M::DELETE, "/webhooks/{id}", "Alerting", "Delete a webhook endpoint").returns_nothing(),
+        // Tenants
+        op(M::GET, "/tenants", "Tenants", "List tenants").returns(array(schema_ref("Tenant"))),
+        op(M::POST, "/tenants", "Tenants", "Create a tenant").body("TenantRequest").returns(schema_ref("Tenant")),
+        op(M::GET, "/tenants/{id}", "Tenants", "Get a tenant").returns(schema_ref("Tenant")),
+        op(M::PUT, "/tenants/{id}", "Tenants", "Replace a tenant").body("TenantRequest").returns(schema_ref("Tenant")),
+        op(M::DELETE, "/tenants/{id}", "Tenants", "Delete a tenant with its counters and blocklist").returns_nothing(),
+        op(M::GET, "/tenants/{id}/blocklist", "Tenants", "List a tenant's blocklist entries").returns(array(json!({ "type": "object" }))),
+        op(M::POST, "/tenants/{id}/blocklist", "Tenants", "Block an IP or network for a tenant").body("BlocklistRequest"),
+        op(M::DELETE, "/tenants/{id}/blocklist/{target}", "Tenants", "Unblock an IP or network for a tenant").returns_nothing(),
+    ]
+}
+
+fn stream_filters() -> Vec<Param> {
+    vec![
+        param("types", string(), "Comma-separated event types"),
+        param("ip", string(), "IP address or CIDR network of the client"),
+        param("severity", string(), "Least alert severity"),
+    ]
+}
+
+/// Schemas of request bodies, each with an example, and of shared replies
+fn schemas() -> Value {
+    let mut schemas = traffic_schemas();
+    if let (Some(schemas), Value::Object(more)) = (schemas.as_object_mut(), management_schemas()) {
+        schemas.extend(more);
+    }
+    schemas
+}
+
+/// Schemas of errors and of the decision endpoints called for traffic
+fn traffic_schemas() -> Value {
+    json!({
+        "Error": {
+            "type": "object",
+            "required": ["code", "message", "details"],
+            "properties": {
+                "code": { "type": "string", "description": "Stable identifier of the error, such as `validation_failed`" },
+                "message": string(),
+                "details": {
+                    "description": "Context of the error, such as the fields that failed validation, or null",
+                    "nullable": true,
+                    "type": "object",
+                    "properties": {
+                        "fields": array(json!({
+                            "type": "object",
+                            "properties": { "field": string(), "message": string() },
+                        })),
+                    },
+                },
+            },
+        },
+        "Health": {
+            "type": "object",
+            "properties": { "status": string(), "version": string() },
+        },
+        "Readiness": readiness_schema(),
+        "RateLimitRequest": {
+            "type": "object",
+            "required": ["path"],
+            "properties": {
+                "ip": { "type": "string", "description": "Client address; the caller's address when left out" },
+                "key": { "type": "string", "description": "Key to count against instead of the client's address or subnet" },
+                "path": { "type": "string", "pattern": "^/" },
+                "cost": { "type": "integer", "minimum": 1, "description": "Overrides the configured per-path cost" },
+                "api_key": { "type": "string", "description": "API key to charge against its quota" },
+            },
+            "example": { "ip": "203.0.113.7", "path": "/search", "cost": 2 },
+        },
+        "RateLimitResponse": {
+            "type": "object",
+            "properties": {
+                "allowed": boolean(),
+                "limit": integer(),
+                "remaining": integer(),
+                "reset": integer(),
+                "penalty": { "type": "object", "nullable": true },
+                "quota": { "type": "object", "nullable": true },
+                "shadowed": boolean(),
+                "allowlisted": boolean(),
+                "blocked": { "type": "object", "nullable": true },
+            },
+        },
+        "RateLimitStatus": {
+            "type": "object",
+            "properties": {
+                "key": string(),
+                "limit": integer(),
+                "count": integer(),
+                "remaining": integer(),
+                "reset": integer(),
+                "banned_for_seconds": integer(),
+            },
+        },
+        "DetectionReset": {
+            "type": "object",
+            "properties": {
+                "unblocked": array(string()),
+                "reputation_reset": boolean(),
+                "still_blocked": { "type": "object", "nullable": true },
+            },
+        },
+        "ConcurrencyRequest": {
+            "type": "object",
+            "required": ["ip"],
+            "properties": { "ip": string() },
+            "example": { "ip": "203.0.113.7" },
+        },
+        "ConcurrencyResponse": {
+            "type": "object",
+            "properties": { "allowed": boolean(), "in_flight": integer(), "limit": integer() },
+        },
+        "DdosCheckRequest": {
+            "type": "object",
+            "required": ["ip", "request_size"],
+            "properties": {
+                "ip": string(),
+                "request_size": integer(),
+                "user_agent": string(),
+                "method": string(),
+                "path": string(),
+                "headers": { "type": "object", "additionalProperties": string() },
+                "header_order": array(string()),
+                "query": { "type": "object", "additionalProperties": string() },
+                "ja3": string(),
+                "ja4": string(),
+                "tls_client_hello": { "type": "string", "format": "byte" },
+            },
+            "example": { "ip": "203.0.113.7", "request_size": 512, "method": "GET", "path": "/" },
+        },
+        "CheckRequest": {
+            "type": "object",
+            "required": ["ip", "request_size"],
+            "description": "The fields of `DdosCheckRequest`, and those below",
+            "properties": {
+                "ip": string(),
+                "request_size": integer(),
+                "method": string(),
+                "path": string(),
+                "headers": { "type": "object", "additionalProperties": string() },
+                "cost": { "type": "integer", "minimum": 1, "description": "Overrides the configured per-path cost" },
+                "api_key": { "type": "string", "description": "API key to charge against its quota" },
+            },
+            "example": { "ip": "203.0.113.7", "request_size": 512, "method": "GET", "path": "/search" },
+        },
+        "CheckResponse": {
+            "type": "object",
+            "properties": {
+                "action": {
+                    "type": "object",
+                    "properties": {
+                        "type": enumeration(&["allow", "rate_limit", "block", "challenge", "redirect"]),
+                        "retry_after": integer(),
+                        "url": string(),
+                        "status": integer(),
+                    },
+                },
+                "status": { "type": "integer", "description": "Status the client should be answered with" },
+                "reasons": array(json!({
+                    "type": "object",
+                    "properties": {
+                        "layer": enumeration(&[
+                            "allowlist", "blocklist", "rate_limit", "honeypot", "attack_mode", "scanner", "rules",
+                            "ddos_detector", "escalation",
+                        ]),
+                        "matched": boolean(),
+                        "detail": string(),
+                    },
+                })),
+                "rate_limit": schema_ref("RateLimitResponse"),
+                "ddos": { "type": "object", "nullable": true },
+            },
+        },
+        "ConnectionReportRequest": {
+            "type": "object",
+            "required": ["ip", "header_bytes", "header_ms"],
+            "properties": {
+                "ip": string(),
+                "header_bytes": integer(),
+                "header_ms": integer(),
+                "body_bytes": integer(),
+                "body_ms": integer(),
+            },
+            "example": { "ip": "203.0.113.7", "header_bytes": 900, "header_ms": 40 },
+        },
+        "ResponseReportRequest": {
+            "type": "object",
+            "required": ["ip", "status"],
+            "properties": { "ip": string(), "status": { "type": "integer", "minimum": 100, "maximum": 599 } },
+            "example": { "ip": "203.0.113.7", "status": 404 },
+        },
+        "LoginAttemptRequest": {
+            "type": "object",
+            "required": ["ip", "username"],
+            "properties": { "ip": string(), "username": string(), "cookie": string() },
+            "example": { "ip": "203.0.113.7", "username": "alice" },
+        },
+        "LoginResultRequest": {
+            "type": "object",
+            "required": ["ip", "username", "success"],
+            "properties": { "ip": string(), "username": string(), "success": boolean() },
+            "example": { "ip": "203.0.113.7", "username": "alice", "success": false },
+        },
+    })
+}
+
+/// Schemas of the management endpoints
+fn management_schemas() -> Value {
+    let rule_fields = || {
+        json!({
+            "name": { "type": "string", "minLength": 1 },
+            "description": { "type": "string" },
+            "conditions": array(json!({ "type": "object", "description": "Condition the request must match" })),
+            "actions": array(json!({ "type": "object", "description": "Action taken on a match" })),
+            "priority": { "type": "integer", "minimum": 0, "description": "Higher numbers are evaluated first" },
+            "enabled": boolean(),
+            "shadow": { "type": "boolean", "description": "Only log matches instead of acting on them" },
+            "schedule": {
+                "type": "object",
+                "description": "When the rule is active, from weekly `windows` and/or a `cron` start with `duration_minutes`, \
+                    in a fixed-offset `timezone`; overrides `enabled`",
+            },
+            "expires_at": { "type": "string", "format": "date-time" },
+        })
+    };
+    let tenant = || json!({ "type": "string", "description": "Tenant the rule applies to; all traffic when omitted" });
+    let mut rule = json!({ "type": "object", "required": ["id", "name", "conditions", "actions", "priority", "enabled"], "properties": rule_fields() });
+    rule["properties"]["id"] = string();
+    rule["properties"]["tenant"] = tenant();
+    let mut rule_request_fields = rule_fields();
+    rule_request_fields["tenant"] = tenant();
+
+    json!({
+        "AllowlistRequest": {
+            "type": "object",
+            "required": ["kind", "value"],
+            "properties": {
+                "kind": enumeration(&["network", "api_key"]),
+                "value": { "type": "string", "minLength": 1 },
+                "description": string(),
+            },
+            "example": { "kind": "network", "value": "10.0.0.0/8", "description": "Office" },
+        },
+        "BlocklistRequest": {
+            "type": "object",
+            "required": ["target", "reason"],
+            "properties": {
+                "target": { "type": "string", "description": "IP or CIDR network" },
+                "reason": string(),
+                "duration_seconds": { "type": "integer", "minimum": 0, "description": "0 blocks for good; the configured default when omitted" },
+            },
+            "example": { "target": "198.51.100.0/24", "reason": "Credential stuffing", "duration_seconds": 3600 },
+        },
+        "ReputationAdjustRequest": {
+            "type": "object",
+            "required": ["delta"],
+            "properties": { "delta": { "type": "number", "description": "Added to the score; negative to penalize" } },
+            "example": { "delta": -10.0 },
+        },
+        "Quota": {
+            "type": "object",
+            "properties": {
+                "api_key": string(),
+                "daily_limit": { "type": "integer", "nullable": true },
+                "monthly_limit": { "type": "integer", "nullable": true },
+                "created_at": { "type": "string", "format": "date-time" },
+                "updated_at": { "type": "string", "format": "date-time" },
+            },
+        },
+        "QuotaRequest": {
+            "type": "object",
+            "required": ["api_key"],
+            "properties": {
+                "api_key": { "type": "string", "minLength": 1 },
+                "daily_limit": { "type": "integer", "minimum": 1 },
+                "monthly_limit": { "type": "integer", "minimum": 1 },
+            },
+            "example": { "api_key": "key-1", "daily_limit": 10000 },
+        },
+        "QuotaUpdateRequest": {
+            "type": "object",
+            "properties": {
+                "daily_limit": { "type": "integer", "minimum": 1 },
+                "monthly_limit": { "type": "integer", "minimum": 1 },
+            },
+            "example": { "monthly_limit": 250000 },
+        },
+        "ScannerSignatureRequest": {
+            "type": "object",
+            "required": ["signature"],
+            "properties": { "signature": string() },
+            "example": { "signature": "sqlmap" },
+        },
+        "AttackModeRequest": {
+            "type": "object",
+            "required": ["enabled"],
+            "properties": {
+                "enabled": boolean(),
+                "duration_seconds": { "type": "integer", "minimum": 1, "description": "The configured duration when omitted" },
+                "reason": string(),
+            },
+            "example": { "enabled": true, "duration_seconds": 1800, "reason": "Ongoing flood" },
+        },
+        "Rule": rule,
+        "RuleRequest": {
+            "type": "object",
+            "required": ["name", "conditions", "actions", "priority", "enabled"],
+            "properties": rule_request_fields,
+            "example": { "name": "Block scrapers", "conditions": [], "actions": [], "priority": 10, "enabled": true },
+        },
+        "RulePatchRequest": {
+            "type": "object",
+            "description": "Fields left out are kept; `null` clears `description`, `schedule` and `expires_at`",
+            "properties": nullable_rule_fields(rule_fields()),
+            "example": { "enabled": false, "description": null },
+        },
+        "RuleSet": {
+            "type": "object",
+            "properties": { "rules": array(schema_ref("Rule")) },
+        },
+        "RuleImportRequest": {
+            "type": "object",
+            "required": ["rules"],
+            "properties": {
+                "rules": array(schema_ref("Rule")),
+                "on_conflict": enumeration(&["skip", "overwrite"]),
+            },
+            "example": {
+                "rules": [{ "id": "r1", "name": "Block scrapers", "conditions": [], "actions": [], "priority": 10, "enabled": true }],
+                "on_conflict": "overwrite",
+            },
+        },
+        "RuleTestRequest": {
+            "type": "object",
+            "required": ["request"],
+            "description": "Exactly one of `rule_id` and `rule` is required",
+            "properties": {
+                "request": { "type": "object", "description": "Sample request, with the fields of `DdosCheckRequest`" },
+                "rule_id": string(),
+                "rule": schema_ref("RuleRequest"),
+            },
+            "example": { "request": { "ip": "203.0.113.7", "path": "/login" }, "rule_id": "r1" },
+        },
+        "Event": {
+            "type": "object",
+            "properties": {
+                "id": string(),
+                "event_type": string(),
+                "timestamp": { "type": "string", "format": "date-time" },
+                "source": string(),
+                "data": { "type": "object" },
+            },
+        },
+        "AlertRuleRequest": {
+            "type": "object",
+            "required": ["name", "metric", "comparison", "threshold", "severity"],
+            "properties": {
+                "id": { "type": "string", "description": "Generated when omitted" },
+                "name": string(),
+                "metric": string(),
+                "comparison": enumeration(&["GreaterThan", "GreaterThanOrEqual", "LessThan", "LessThanOrEqual", "Equals", "NotEquals"]),
+                "threshold": { "type": "number" },
+                "resolve_threshold": { "type": "number" },
+                "duration_seconds": integer(),
+                "severity": enumeration(ALERT_LEVELS),
+                "labels": { "type": "object", "additionalProperties": string() },
+                "enabled": boolean(),
+            },
+            "example": { "name": "High CPU", "metric": "cpu_usage", "comparison": "GreaterThan", "threshold": 90.0, "severity": "Warning" },
+        },
+        "SilenceRequest": {
+            "type": "object",
+            "description": "One of `ends_at` and `duration_minutes` is required",
+            "properties": {
+                "source": string(),
+                "level": enumeration(ALERT_LEVELS),
+                "labels": { "type": "object", "additionalProperties": string() },
+                "starts_at": { "type": "string", "format": "date-time" },
+                "ends_at": { "type": "string", "format": "date-time" },
+                "duration_minutes": { "type": "integer", "minimum": 1 },
+                "comment": string(),
+                "created_by": string(),
+            },
+            "example": { "source": "High CPU", "duration_minutes": 60, "comment": "Maintenance" },
+        },
+        "WebhookRequest": {
+            "type": "object",
+            "required": ["url"],
+            "properties": {
+                "url": { "type": "string", "format": "uri" },
+                "secret": { "type": "string", "description": "Signing secret; generated when omitted" },
+                "channels": array(string()),
+                "min_level": enumeration(ALERT_LEVELS),
+                "enabled": boolean(),
+            },
+            "example": { "url": "https://hooks.example.com/ddos", "min_level": "Error" },
+        },
+        "Tenant": {
+            "type": "object",
+            "properties": {
+                "id": string(),
+                "name": string(),
+                "api_keys": { "type": "array", "items": string(), "description": "Redacted, except when the tenant is created or updated" },
+                "rate_limit": { "type": "integer", "nullable": true },
+                "created_at": { "type": "string", "format": "date-time" },
+                "updated_at": { "type": "string", "format": "date-time" },
+            },
+        },
+        "TenantRequest": {
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "id": { "type": "string", "pattern": "^[A-Za-z0-9_-]{1,64}$", "description": "Required on creation; ignored on update" },
+                "name": { "type": "string", "minLength": 1 },
+                "api_keys": { "type": "array", "items": string(), "description": "Keys whose requests are attributed to the tenant" },
+                "rate_limit": { "type": "integer", "minimum": 1, "description": "Requests per window for each client; the configured default when omitted" },
+            },
+            "example": { "id": "shop-eu", "name": "Shop EU", "api_keys": ["sk_live_4f9a2c7e"], "rate_limit": 200 },
+        },
+    })
+}
+
+/// The OpenAPI document
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for operation in operations() {
+        let item = paths.entry(operation.path).or_insert_with(|| json!({}));
+        item[operation.method.as_str().to_lowercase()] = operation.to_json();
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "DDoS Protection Service API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": "/api/v1" }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "responses": {
+                "Error": {
+                    "description": "Error",
+                    "content": { "application/json": { "schema": schema_ref("Error") } },
+                },
+            },
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-API-Key" },
+            },
+        },
+    })
+}
+
+/// OpenAPI document endpoint
+pub async fn get_openapi() -> impl Responder {
+    HttpResponse::Ok().json(document())
+}
+
+/// Swagger UI endpoint, rendering the OpenAPI document
+///
+/// Answers 503 when no `server.swagger_ui_dir` is configured.
+pub async fn get_docs(state: web::Data<ApiState>) -> impl Responder {
+    if state.config.server.swagger_ui_dir.is_none() {
+        return ApiError::unavailable("Swagger UI isn't installed; the document is at openapi.json").into();
+    }
+    let page = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>DDoS Protection Service API</title>
+<link rel="stylesheet" href="docs/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="docs/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>"##;
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page)
+}
+
+/// Swagger UI file endpoint
+///
+/// Only the files the docs page loads are served.
+pub async fn get_docs_file(state: web::Data<ApiState>, path: web::Path<String>) -> HttpResponse {
+    let name = path.into_inner();
+    let (Some(dir), Some((file, content_type))) = (
+        &state.config.server.swagger_ui_dir,
+        SWAGGER_UI_FILES.iter().find(|(file, _)| *file == name),
+    ) else {
+        return ApiError::not_found("File").into();
+    };
+    match tokio::fs::read(Path::new(dir).join(file)).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(*content_type)
+            .insert_header(("Cache-Control", "public, max-age=86400"))
+            .body(body),
+        Err(e) => {
+            log::error!("Failed to read Swagger UI file {}: {}", file, e);
+            ApiError::not_found("File").into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use actix_web::{test, App};
+    use serde::de::{DeserializeOwned, Visitor};
+    use serde::Serialize;
+    use crate::api::{alert_rules, silences, tenants, webhooks};
+    use crate::api::check::{CheckAction, CheckResponse};
+    use crate::api::{ConcurrencyResponse, DdosCheckRequest, RateLimitResponse, RateLimitStatusResponse};
+    use crate::core::ddos_detector::{ConnectionStats, DetectionReset};
+    use crate::core::rate_limiter::RateLimitUsage;
+    use crate::core::silences::SilenceMatchers;
+
+    /// Deserializer recording the fields of the struct deserialized from it
+    struct FieldNames<'a>(&'a mut Vec<&'static str>);
+
+    impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0.extend(fields);
+            Err(serde::de::Error::custom("fields recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+            unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    /// Fields a type is deserialized from
+    fn fields<T: DeserializeOwned>() -> BTreeSet<String> {
+        let mut fields = Vec::new();
+        let _ = T::deserialize(FieldNames(&mut fields));
+        fields.into_iter().map(str::to_string).collect()
+    }
+
+    /// Fields a value is serialized to
+    fn keys<T: Serialize>(value: &T) -> BTreeSet<String> {
+        serde_json::to_value(value).unwrap().as_object().unwrap().keys().cloned().collect()
+    }
+
+    fn properties(schemas: &Value, name: &str) -> BTreeSet<String> {
+        schemas[name]["properties"].as_object().unwrap_or_else(|| panic!("{} has no properties", name)).keys().cloned().collect()
+    }
+
+    fn example<T: DeserializeOwned>(schemas: &Value, name: &str) -> T {
+        serde_json::from_value(schemas[name]["example"].clone()).unwrap_or_else(|e| panic!("{} example: {}", name, e))
+    }
+
+    #[actix_web::test]
+    async fn test_schema_examples() {
+        let schemas = schemas();
+        example::<super::super::RateLimitRequest>(&schemas, "RateLimitRequest");
+        example::<super::super::ConcurrencyRequest>(&schemas, "ConcurrencyRequest");
+        example::<super::super::DdosCheckRequest>(&schemas, "DdosCheckRequest");
+        example::<crate::api::check::CheckRequest>(&schemas, "CheckRequest");
+        example::<super::super::ConnectionReportRequest>(&schemas, "ConnectionReportRequest");
+        example::<super::super::ResponseReportRequest>(&schemas, "ResponseReportRequest");
+        example::<super::super::LoginAttemptRequest>(&schemas, "LoginAttemptRequest");
+        example::<super::super::LoginResultRequest>(&schemas, "LoginResultRequest");
+        example::<super::super::AllowlistRequest>(&schemas, "AllowlistRequest");
+        example::<super::super::BlocklistRequest>(&schemas, "BlocklistRequest");
+        example::<super::super::ReputationAdjustRequest>(&schemas, "ReputationAdjustRequest");
+        example::<super::super::QuotaRequest>(&schemas, "QuotaRequest");
+        example::<super::super::QuotaUpdateRequest>(&schemas, "QuotaUpdateRequest");
+        example::<super::super::ScannerSignatureRequest>(&schemas, "ScannerSignatureRequest");
+        example::<super::super::AttackModeRequest>(&schemas, "AttackModeRequest");
+        example::<super::super::RuleRequest>(&schemas, "RuleRequest");
+        example::<super::super::RulePatchRequest>(&schemas, "RulePatchRequest");
+        example::<super::super::RuleImportRequest>(&schemas, "RuleImportRequest");
+        example::<super::super::RuleTestRequest>(&schemas, "RuleTestRequest");
+        example::<alert_rules::AlertRuleRequest>(&schemas, "AlertRuleRequest");
+        example::<silences::SilenceRequest>(&schemas, "SilenceRequest");
+        example::<webhooks::WebhookRequest>(&schemas, "WebhookRequest");
+        example::<tenants::TenantRequest>(&schemas, "TenantRequest");
+
+        // Every body refers to a schema with an example
+        for operation in operations() {
+            if let Some(body) = operation.body {
+                assert!(schemas[body]["example"].is_object(), "{} has no example", body);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_schemas_match_types() {
+        let schemas = schemas();
+        let check = |name: &str, fields: BTreeSet<String>| {
+            assert_eq!(properties(&schemas, name), fields, "{} doesn't match its type", name);
+        };
+        let with = |mut fields: BTreeSet<String>, more: &[&str]| {
+            fields.extend(more.iter().map(|field| field.to_string()));
+            fields
+        };
+
+        check("RateLimitRequest", fields::<super::super::RateLimitRequest>());
+        check("ConcurrencyRequest", fields::<super::super::ConcurrencyRequest>());
+        check("DdosCheckRequest", fields::<DdosCheckRequest>());
+        check("ResponseReportRequest", fields::<super::super::ResponseReportRequest>());
+        check("LoginAttemptRequest", fields::<super::super::LoginAttemptRequest>());
+        check("LoginResultRequest", fields::<super::super::LoginResultRequest>());
+        check("AllowlistRequest", fields::<super::super::AllowlistRequest>());
+        check("BlocklistRequest", fields::<super::super::BlocklistRequest>());
+        check("ReputationAdjustRequest", fields::<super::super::ReputationAdjustRequest>());
+        check("QuotaRequest", fields::<super::super::QuotaRequest>());
+        check("QuotaUpdateRequest", fields::<super::super::QuotaUpdateRequest>());
+        check("ScannerSignatureRequest", fields::<super::super::ScannerSignatureRequest>());
+        check("AttackModeRequest", fields::<super::super::AttackModeRequest>());
+        check("RuleRequest", fields::<super::super::RuleRequest>());
+        check("RulePatchRequest", fields::<super::super::RulePatchRequest>());
+        check("RuleImportRequest", fields::<super::super::RuleImportRequest>());
+        check("RuleTestRequest", fields::<super::super::RuleTestRequest>());
+        check("AlertRuleRequest", fields::<alert_rules::AlertRuleRequest>());
+        check("WebhookRequest", fields::<webhooks::WebhookRequest>());
+        check("TenantRequest", fields::<tenants::TenantRequest>());
+        check("Quota", fields::<crate::core::quota::Quota>());
+        check("Tenant", fields::<crate::core::tenants::Tenant>());
+        check("Rule", fields::<crate::core::rule_engine::Rule>());
+        check("RuleSet", fields::<crate::core::rule_engine::RuleSet>());
+        check("Event", fields::<crate::core::analytics::Event>());
+
+        // Flattened fields aren't listed by the outer type, so those are named here
+        check("ConnectionReportRequest", with(fields::<ConnectionStats>(), &["ip"]));
+        check(
+            "SilenceRequest",
+            with(fields::<SilenceMatchers>(), &["starts_at", "ends_at", "duration_minutes", "comment", "created_by"]),
+        );
+        let check_request = properties(&schemas, "CheckRequest");
+        assert!(check_request.is_subset(&with(fields::<DdosCheckRequest>(), &["cost", "api_key"])));
+
+        let rate_limit = RateLimitResponse {
+            allowed: true,
+            limit: 100,
+            remaining: 99,
+            reset: 60,
+            penalty: None,
+            quota: None,
+            shadowed: false,
+            allowlisted: false,
+            blocked: None,
+        };
+        check("RateLimitResponse", keys(&rate_limit));
+        check(
+            "RateLimitStatus",
+            keys(&RateLimitStatusResponse {
+                key: "203.0.113.7".to_string(),
+                usage: RateLimitUsage { limit: 100, count: 1, remaining: 99, reset: 60, banned_for_seconds: 0 },
+            }),
+        );
+        check("ConcurrencyResponse", keys(&ConcurrencyResponse { allowed: true, in_flight: 1, limit: 10 }));
+        check("DetectionReset", keys(&DetectionReset::default()));
+        check(
+            "CheckResponse",
+            keys(&CheckResponse { action: CheckAction::Allow, status: 200, reasons: Vec::new(), rate_limit, ddos: None }),
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_operations_are_routed() {
+        let app = test::init_service(App::new().configure(crate::api::config)).await;
+        for operation in operations() {
+            let path = operation.path.replace("{version}", "1").replace(['{', '}'], "");
+            let req = test::TestRequest::default()
+                .method(operation.method.clone())
+                .uri(&format!("/api/v1{}", path))
+                .to_request();
+            let status = test::call_service(&app, req).await.status();
+            assert!(status != 404 && status != 405, "{} {} is not routed", operation.method, operation.path);
+        }
+
+        let document = document();
+        assert_eq!(
+            document["paths"]["/rules/{id}"]["delete"]["security"],
+            json!([{ "bearer": [] }, { "apiKey": [] }])
+        );
+        assert_eq!(document["paths"]["/health"]["get"]["security"], json!([]));
+        assert_eq!(document["paths"]["/rules/{id}"]["delete"]["operationId"], "delete_rules_id");
+    }
+}
@@ -0,0 +1,111 @@
+//! Alert silence management.
+//!
+//! Silences mute matching alerts for a time window; see `core::silences`.
+//! Deleting a silence ends it early.
+
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use crate::core::silences::{Silence, SilenceError, SilenceMatchers};
+use super::ApiState;
+use super::error::ApiError;
+
+/// Silence request
+#[derive(Deserialize)]
+pub struct SilenceRequest {
+    #[serde(flatten)]
+    matchers: SilenceMatchers,
+    /// Start of the window; now by default
+    starts_at: Option<DateTime<Utc>>,
+    /// End of the window; either this or `duration_minutes` is required
+    ends_at: Option<DateTime<Utc>>,
+    duration_minutes: Option<i64>,
+    #[serde(default)]
+    comment: String,
+    created_by: Option<String>,
+}
+
+/// Silence list query
+#[derive(Deserialize)]
+pub struct SilencesQuery {
+    /// Only list silences in effect now
+    #[serde(default)]
+    active: bool,
+}
+
+fn silence_error_response(action: &str, e: SilenceError) -> HttpResponse {
+    match e {
+        SilenceError::InvalidSilence(_) => ApiError::bad_request(e.to_string()).into(),
+        e => {
+            log::error!("Failed to {} silence: {}", action, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// List silences endpoint
+pub async fn get_silences(
+    state: web::Data<ApiState>,
+    query: web::Query<SilencesQuery>,
+) -> impl Responder {
+    match state.silences.get_silences().await {
+        Ok(silences) => {
+            let now = Utc::now();
+            let silences: Vec<_> = silences
+                .into_iter()
+                .filter(|silence| !query.active || silence.is_active(now))
+                .collect();
+            HttpResponse::Ok().json(silences)
+        }
+        Err(e) => silence_error_response("list", e),
+    }
+}
+
+/// Create silence endpoint
+pub async fn create_silence(
+    state: web::Data<ApiState>,
+    req: web::Json<SilenceRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    let starts_at = req.starts_at.unwrap_or_else(Utc::now);
+    let ends_at = match (req.ends_at, req.duration_minutes) {
+        (Some(ends_at), _) => ends_at,
+        (None, Some(minutes)) => starts_at + Duration::minutes(minutes),
+        (None, None) => return ApiError::bad_request("ends_at or duration_minutes is required").into(),
+    };
+    let mut silence = match Silence::new(req.matchers, starts_at, ends_at) {
+        Ok(silence) => silence,
+        Err(e) => return silence_error_response("create", e),
+    };
+    silence.comment = req.comment;
+    silence.created_by = req.created_by;
+
+    match state.silences.set_silence(&silence).await {
+        Ok(()) => HttpResponse::Created().json(silence),
+        Err(e) => silence_error_response("create", e),
+    }
+}
+
+/// Get silence endpoint
+pub async fn get_silence(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.silences.get_silence(&path.into_inner()).await {
+        Ok(Some(silence)) => HttpResponse::Ok().json(silence),
+        Ok(None) => ApiError::not_found("Silence").into(),
+        Err(e) => silence_error_response("get", e),
+    }
+}
+
+/// Delete silence endpoint
+pub async fn delete_silence(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.silences.remove_silence(&path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Silence").into(),
+        Err(e) => silence_error_response("delete", e),
+    }
+}
@@ -0,0 +1,188 @@
+//! Live event stream endpoints.
+//!
+//! Pushes analytics events and monitoring alerts to dashboards as they are
+//! recorded, over Server-Sent Events or a WebSocket. Both take the same
+//! filters as query parameters: `types` (comma-separated event type names,
+//! `Alert` for alerts), `ip` (an IP or CIDR network) and `severity` (lowest
+//! severity to receive).
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval_at, Instant, Interval};
+use crate::core::live_events::{LiveEvent, LiveEventFilter};
+use super::ApiState;
+use super::error::ApiError;
+
+/// How often idle connections are kept alive, so proxies don't close them
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Live event stream filters
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    types: Option<String>,
+    ip: Option<String>,
+    severity: Option<String>,
+}
+
+impl StreamQuery {
+    fn filter(&self) -> Result<LiveEventFilter, String> {
+        LiveEventFilter::parse(self.types.as_deref(), self.ip.as_deref(), self.severity.as_deref())
+    }
+}
+
+fn keepalive() -> Interval {
+    interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL)
+}
+
+fn to_json(event: &LiveEvent) -> String {
+    serde_json::to_string(event).unwrap_or_default()
+}
+
+/// Server-Sent Events stream of live events endpoint
+pub async fn stream_events(
+    state: web::Data<ApiState>,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    let filter = match query.filter() {
+        Ok(filter) => filter,
+        Err(e) => return ApiError::bad_request(e).into(),
+    };
+
+    let connection = SseConnection {
+        events: state.live_events.subscribe(),
+        filter,
+        keepalive: keepalive(),
+    };
+    let body = futures::stream::unfold(connection, |mut connection| async move {
+        let message = connection.next_message().await?;
+        Some((Ok::<_, actix_web::Error>(message), connection))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body)
+}
+
+struct SseConnection {
+    events: broadcast::Receiver<LiveEvent>,
+    filter: LiveEventFilter,
+    keepalive: Interval,
+}
+
+impl SseConnection {
+    /// Next message to send, or `None` once the service shuts down
+    async fn next_message(&mut self) -> Option<Bytes> {
+        loop {
+            tokio::select! {
+                event = self.events.recv() => match event {
+                    Ok(event) if self.filter.matches(&event) => {
+                        return Some(Bytes::from(format!("event: {}\ndata: {}\n\n", event.kind(), to_json(&event))));
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        return Some(Bytes::from(format!(": missed {} messages\n\n", missed)));
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = self.keepalive.tick() => return Some(Bytes::from_static(b": keepalive\n\n")),
+            }
+        }
+    }
+}
+
+/// WebSocket stream of live events endpoint
+///
+/// Each event is sent as a JSON text message. Messages from the client other
+/// than pings and close frames are ignored.
+pub async fn stream_events_ws(
+    state: web::Data<ApiState>,
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<StreamQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let filter = query.filter().map_err(ApiError::bad_request)?;
+    ws::verify_handshake(req.head())?;
+    // The handshake was verified, so the key is present
+    let key = req.headers().get(header::SEC_WEBSOCKET_KEY).unwrap();
+    let accept = ws::hash_key(key.as_bytes());
+
+    let connection = WsConnection {
+        events: state.live_events.subscribe(),
+        filter,
+        keepalive: keepalive(),
+        payload,
+        codec: ws::Codec::new(),
+        received: BytesMut::new(),
+        closed: false,
+    };
+    let body = futures::stream::unfold(connection, |mut connection| async move {
+        let frame = connection.next_frame().await?;
+        Some((Ok::<_, actix_web::Error>(frame), connection))
+    });
+
+    Ok(HttpResponse::SwitchingProtocols()
+        .upgrade("websocket")
+        .insert_header((header::SEC_WEBSOCKET_ACCEPT, &accept[..]))
+        .streaming(body))
+}
+
+struct WsConnection {
+    events: broadcast::Receiver<LiveEvent>,
+    filter: LiveEventFilter,
+    keepalive: Interval,
+    payload: web::Payload,
+    codec: ws::Codec,
+    /// Bytes received from the client that don't make up a whole frame yet
+    received: BytesMut,
+    closed: bool,
+}
+
+impl WsConnection {
+    /// Next frame to send, or `None` once the connection is closed
+    async fn next_frame(&mut self) -> Option<Bytes> {
+        loop {
+            if self.closed {
+                return None;
+            }
+            match self.codec.decode(&mut self.received) {
+                Ok(Some(ws::Frame::Ping(data))) => return self.encode(ws::Message::Pong(data)),
+                Ok(Some(ws::Frame::Close(reason))) => {
+                    self.closed = true;
+                    return self.encode(ws::Message::Close(reason));
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => {}
+                Err(_) => return None,
+            }
+
+            tokio::select! {
+                chunk = self.payload.next() => match chunk {
+                    Some(Ok(chunk)) => self.received.extend_from_slice(&chunk),
+                    _ => return None,
+                },
+                event = self.events.recv() => match event {
+                    Ok(event) if self.filter.matches(&event) => {
+                        return self.encode(ws::Message::Text(to_json(&event).into()));
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = self.keepalive.tick() => return self.encode(ws::Message::Ping(Bytes::new())),
+            }
+        }
+    }
+
+    fn encode(&mut self, message: ws::Message) -> Option<Bytes> {
+        let mut frame = BytesMut::new();
+        self.codec.encode(message, &mut frame).ok()?;
+        Some(frame.freeze())
+    }
+}
@@ -0,0 +1,243 @@
+//! Tenant management.
+//!
+//! Tenants are listed with their API keys redacted; the keys are only
+//! returned in full when a tenant is created or updated. Each tenant has a
+//! blocklist of its own, checked after the global one for its requests.
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use serde::Deserialize;
+use crate::core::blocklist::BlocklistError;
+use crate::core::tenants::{Tenant, TenantError};
+use super::error::{ApiError, FieldErrors, Validate};
+use super::{actor, record_audit, ApiState, BlocklistRequest};
+
+/// Tenant request
+#[derive(Deserialize)]
+pub struct TenantRequest {
+    /// Tenant ID, required on creation and ignored on update
+    id: Option<String>,
+    name: String,
+    #[serde(default)]
+    api_keys: Vec<String>,
+    /// Requests per window allowed to each client, instead of the configured default
+    rate_limit: Option<u32>,
+}
+
+impl Validate for TenantRequest {
+    fn check(&self, errors: &mut FieldErrors) {
+        errors.check(!self.name.trim().is_empty(), "name", "must not be empty");
+        for (index, key) in self.api_keys.iter().enumerate() {
+            errors.check(!key.trim().is_empty(), format!("api_keys[{}]", index), "must not be empty");
+        }
+        errors.check(self.rate_limit != Some(0), "rate_limit", "must be positive");
+    }
+}
+
+/// Answer for tenant storage errors
+fn tenant_error(e: TenantError, action: &str) -> HttpResponse {
+    match e {
+        TenantError::InvalidTenant(_) => ApiError::bad_request(e.to_string()).into(),
+        TenantError::KeyInUse(_) => ApiError::conflict(e.to_string()).into(),
+        e => {
+            log::error!("Failed to {}: {}", action, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Check that a tenant exists, answering 404 otherwise
+async fn find_tenant(state: &ApiState, id: &str) -> Result<Tenant, HttpResponse> {
+    match state.tenants.get_tenant(id).await {
+        Ok(Some(tenant)) => Ok(tenant),
+        Ok(None) => Err(ApiError::not_found("Tenant").into()),
+        Err(e) => Err(tenant_error(e, "get tenant")),
+    }
+}
+
+/// List tenants endpoint
+pub async fn get_tenants(state: web::Data<ApiState>) -> impl Responder {
+    match state.tenants.get_tenants().await {
+        Ok(tenants) => {
+            let tenants: Vec<_> = tenants.into_iter().map(Tenant::redacted).collect();
+            HttpResponse::Ok().json(tenants)
+        }
+        Err(e) => tenant_error(e, "get tenants"),
+    }
+}
+
+/// Create tenant endpoint
+pub async fn create_tenant(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    req: web::Json<TenantRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let req = req.into_inner();
+    let Some(id) = req.id else {
+        return ApiError::bad_request("id is required").into();
+    };
+    let mut tenant = match Tenant::new(id.trim(), req.name.trim()) {
+        Ok(tenant) => tenant,
+        Err(e) => return tenant_error(e, "create tenant"),
+    };
+    match state.tenants.get_tenant(&tenant.id).await {
+        Ok(None) => (),
+        Ok(Some(_)) => return ApiError::conflict(format!("Tenant {} already exists", tenant.id)).into(),
+        Err(e) => return tenant_error(e, "get tenant"),
+    }
+    tenant.api_keys = req.api_keys;
+    tenant.rate_limit = req.rate_limit;
+
+    match state.tenants.set_tenant(&tenant).await {
+        Ok(()) => {
+            record_audit(&state, &actor(&http_req), "tenant.create", &tenant.id).await;
+            HttpResponse::Created().json(tenant)
+        }
+        Err(e) => tenant_error(e, "create tenant"),
+    }
+}
+
+/// Get tenant endpoint
+pub async fn get_tenant(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match find_tenant(&state, &path.into_inner()).await {
+        Ok(tenant) => HttpResponse::Ok().json(tenant.redacted()),
+        Err(response) => response,
+    }
+}
+
+/// Update tenant endpoint
+///
+/// The API keys given replace the tenant's.
+pub async fn update_tenant(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<TenantRequest>,
+) -> impl Responder {
+    if let Err(e) = req.validate() {
+        return e.into();
+    }
+    let mut tenant = match find_tenant(&state, &path.into_inner()).await {
+        Ok(tenant) => tenant,
+        Err(response) => return response,
+    };
+    let req = req.into_inner();
+    tenant.name = req.name.trim().to_string();
+    tenant.api_keys = req.api_keys;
+    tenant.rate_limit = req.rate_limit;
+    tenant.updated_at = Utc::now();
+
+    match state.tenants.set_tenant(&tenant).await {
+        Ok(()) => {
+            record_audit(&state, &actor(&http_req), "tenant.update", &tenant.id).await;
+            HttpResponse::Ok().json(tenant)
+        }
+        Err(e) => tenant_error(e, "update tenant"),
+    }
+}
+
+/// Delete tenant endpoint
+///
+/// The tenant's counters and blocklist are deleted with it. Its rules are
+/// kept, but no longer match any request.
+pub async fn delete_tenant(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    let id = path.into_inner();
+    match state.tenants.remove_tenant(&id).await {
+        Ok(true) => {
+            record_audit(&state, &actor(&http_req), "tenant.delete", &id).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => ApiError::not_found("Tenant").into(),
+        Err(e) => tenant_error(e, "delete tenant"),
+    }
+}
+
+/// Get a tenant's active blocklist entries endpoint
+pub async fn get_tenant_blocklist(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let tenant = match find_tenant(&state, &path.into_inner()).await {
+        Ok(tenant) => tenant,
+        Err(response) => return response,
+    };
+    match state.tenants.blocklist(&tenant.id).await.get_entries().await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            log::error!("Failed to get blocklist of tenant {}: {}", tenant.id, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Add a tenant blocklist entry endpoint
+pub async fn add_tenant_blocklist_entry(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<BlocklistRequest>,
+) -> impl Responder {
+    let tenant = match find_tenant(&state, &path.into_inner()).await {
+        Ok(tenant) => tenant,
+        Err(response) => return response,
+    };
+    let blocklist = state.tenants.blocklist(&tenant.id).await;
+    let duration = match req.duration_seconds {
+        Some(0) => None,
+        Some(seconds) => Some(std::time::Duration::from_secs(seconds)),
+        None => blocklist.default_duration(),
+    };
+
+    match blocklist.block(&req.target, &req.reason, "api", duration).await {
+        Ok(entry) => {
+            let target = format!("{}/{}", tenant.id, req.target);
+            record_audit(&state, &actor(&http_req), "tenant.block", &target).await;
+            HttpResponse::Created().json(entry)
+        }
+        Err(BlocklistError::InvalidTarget(target)) => {
+            ApiError::bad_request(format!("Invalid target: {}", target)).into()
+        }
+        Err(e) => {
+            log::error!("Failed to add blocklist entry of tenant {}: {}", tenant.id, e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Remove a tenant blocklist entry endpoint
+pub async fn remove_tenant_blocklist_entry(
+    state: web::Data<ApiState>,
+    http_req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (id, target) = path.into_inner();
+    let tenant = match find_tenant(&state, &id).await {
+        Ok(tenant) => tenant,
+        Err(response) => return response,
+    };
+
+    match state.tenants.blocklist(&tenant.id).await.unblock(&target).await {
+        Ok(true) => {
+            record_audit(&state, &actor(&http_req), "tenant.unblock", &format!("{}/{}", tenant.id, target)).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => ApiError::not_found("Blocklist entry").into(),
+        Err(BlocklistError::InvalidTarget(target)) => {
+            ApiError::bad_request(format!("Invalid target: {}", target)).into()
+        }
+        Err(e) => {
+            log::error!("Failed to remove blocklist entry of tenant {}: {}", tenant.id, e);
+            ApiError::internal().into()
+        }
+    }
+}
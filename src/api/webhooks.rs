@@ -0,0 +1,157 @@
+//! Webhook endpoint management.
+//!
+//! Endpoints receive alerts and rule `Notify` actions; see
+//! `core::webhooks` for the payload and how it is signed. Secrets are only
+//! returned when an endpoint is created.
+
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use serde::Deserialize;
+use crate::core::monitoring::AlertLevel;
+use crate::core::webhooks::{self, WebhookEndpoint};
+use super::ApiState;
+use super::error::ApiError;
+
+/// Dead letters returned when no limit is given
+const DEFAULT_DEAD_LETTER_LIMIT: usize = 100;
+
+/// Webhook endpoint request
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    url: String,
+    /// Signing secret; generated when omitted
+    secret: Option<String>,
+    #[serde(default)]
+    channels: Vec<String>,
+    min_level: Option<AlertLevel>,
+    enabled: Option<bool>,
+}
+
+/// Dead letter query
+#[derive(Deserialize)]
+pub struct DeadLetterQuery {
+    limit: Option<usize>,
+}
+
+/// List webhook endpoints endpoint
+pub async fn get_webhooks(state: web::Data<ApiState>) -> impl Responder {
+    match state.webhooks.get_endpoints().await {
+        Ok(endpoints) => {
+            let endpoints: Vec<_> = endpoints.into_iter().map(WebhookEndpoint::redacted).collect();
+            HttpResponse::Ok().json(endpoints)
+        }
+        Err(e) => {
+            log::error!("Failed to get webhooks: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Create webhook endpoint endpoint
+///
+/// The response holds the signing secret, which isn't returned again.
+pub async fn create_webhook(
+    state: web::Data<ApiState>,
+    req: web::Json<WebhookRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    let mut endpoint = match WebhookEndpoint::new(&req.url, req.secret) {
+        Ok(endpoint) => endpoint,
+        Err(e) => return ApiError::bad_request(e.to_string()).into(),
+    };
+    endpoint.channels = req.channels;
+    endpoint.min_level = req.min_level;
+    endpoint.enabled = req.enabled.unwrap_or(true);
+
+    match state.webhooks.set_endpoint(&endpoint).await {
+        Ok(()) => HttpResponse::Created().json(endpoint),
+        Err(e) => {
+            log::error!("Failed to create webhook: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Get webhook endpoint endpoint
+pub async fn get_webhook(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.webhooks.get_endpoint(&path.into_inner()).await {
+        Ok(Some(endpoint)) => HttpResponse::Ok().json(endpoint.redacted()),
+        Ok(None) => ApiError::not_found("Webhook").into(),
+        Err(e) => {
+            log::error!("Failed to get webhook: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Update webhook endpoint endpoint
+///
+/// The secret is kept unless a new one is given.
+pub async fn update_webhook(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+    req: web::Json<WebhookRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    if let Err(e) = webhooks::validate_url(&req.url) {
+        return ApiError::bad_request(e.to_string()).into();
+    }
+
+    let mut endpoint = match state.webhooks.get_endpoint(&path.into_inner()).await {
+        Ok(Some(endpoint)) => endpoint,
+        Ok(None) => return ApiError::not_found("Webhook").into(),
+        Err(e) => {
+            log::error!("Failed to get webhook: {}", e);
+            return ApiError::internal().into();
+        }
+    };
+    endpoint.url = req.url;
+    if let Some(secret) = req.secret {
+        endpoint.secret = secret;
+    }
+    endpoint.channels = req.channels;
+    endpoint.min_level = req.min_level;
+    endpoint.enabled = req.enabled.unwrap_or(endpoint.enabled);
+    endpoint.updated_at = Utc::now();
+
+    match state.webhooks.set_endpoint(&endpoint).await {
+        Ok(()) => HttpResponse::Ok().json(endpoint.redacted()),
+        Err(e) => {
+            log::error!("Failed to update webhook: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// Delete webhook endpoint endpoint
+pub async fn delete_webhook(
+    state: web::Data<ApiState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match state.webhooks.remove_endpoint(&path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => ApiError::not_found("Webhook").into(),
+        Err(e) => {
+            log::error!("Failed to delete webhook: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
+
+/// List undelivered notifications endpoint
+pub async fn get_dead_letters(
+    state: web::Data<ApiState>,
+    query: web::Query<DeadLetterQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(DEFAULT_DEAD_LETTER_LIMIT);
+    match state.webhooks.get_dead_letters(limit).await {
+        Ok(letters) => HttpResponse::Ok().json(letters),
+        Err(e) => {
+            log::error!("Failed to get webhook dead letters: {}", e);
+            ApiError::internal().into()
+        }
+    }
+}
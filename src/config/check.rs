@@ -0,0 +1,425 @@
+//! Configuration self-check.
+//!
+//! Run at startup, and on its own with `--check-config`, to catch a bad
+//! deploy before it boots into a broken state: Redis (and PostgreSQL or
+//! ClickHouse, if used) must be reachable, the rules file must parse, GeoIP databases must exist, thresholds must make
+//! sense, enabled integrations must have their credentials, access
+//! control must have keys, the flow collector must know its exporters, the
+//! tenant header must be valid, and the in-memory storage backend is only
+//! used by development builds.
+
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+use actix_web::http::header::HeaderName;
+use crate::core::clickhouse::ClickHouse;
+use crate::core::redis_pool::RedisPool;
+use crate::core::rule_engine::RuleSet;
+use crate::models::{ChallengeMode, Config, RedisTopology, StorageBackend};
+use crate::storage::Postgres;
+use crate::utils::parse_network;
+
+/// How long the Redis check waits for a reply
+const REDIS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of a check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    Ok,
+    /// Worth knowing, but the service can run
+    Warning,
+    /// The service would run broken
+    Failed,
+}
+
+/// Result of one check
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+/// Results of all checks
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl CheckReport {
+    fn add(&mut self, name: &str, status: CheckStatus, message: impl Into<String>) {
+        self.results.push(CheckResult {
+            name: name.to_string(),
+            status,
+            message: message.into(),
+        });
+    }
+
+    fn ok(&mut self, name: &str, message: impl Into<String>) {
+        self.add(name, CheckStatus::Ok, message);
+    }
+
+    fn warn(&mut self, name: &str, message: impl Into<String>) {
+        self.add(name, CheckStatus::Warning, message);
+    }
+
+    fn fail(&mut self, name: &str, message: impl Into<String>) {
+        self.add(name, CheckStatus::Failed, message);
+    }
+
+    /// Fail a check if `problems` isn't empty
+    fn require(&mut self, name: &str, problems: Vec<String>, message: &str) {
+        if problems.is_empty() {
+            self.ok(name, message);
+        } else {
+            self.fail(name, problems.join("; "));
+        }
+    }
+
+    /// Whether no check failed
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.status != CheckStatus::Failed)
+    }
+
+    /// Results with a given status
+    pub fn with_status(&self, status: CheckStatus) -> impl Iterator<Item = &CheckResult> {
+        self.results.iter().filter(move |result| result.status == status)
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            let status = match result.status {
+                CheckStatus::Ok => "ok",
+                CheckStatus::Warning => "warning",
+                CheckStatus::Failed => "FAILED",
+            };
+            writeln!(f, "[{:>7}] {}: {}", status, result.name, result.message)?;
+        }
+        let failed = self.with_status(CheckStatus::Failed).count();
+        let warnings = self.with_status(CheckStatus::Warning).count();
+        write!(f, "{} checks, {} failed, {} warnings", self.results.len(), failed, warnings)
+    }
+}
+
+/// Run every check
+pub async fn check_config(config: &Config) -> CheckReport {
+    let mut report = check_settings(config);
+    check_redis(config, &mut report).await;
+    check_postgres(config, &mut report).await;
+    check_clickhouse(config, &mut report).await;
+    check_rules_file(config, &mut report).await;
+    report
+}
+
+/// Checks that only look at the configuration and local files
+pub fn check_settings(config: &Config) -> CheckReport {
+    let mut report = CheckReport::default();
+    check_thresholds(config, &mut report);
+    check_geoip(config, &mut report);
+    check_integrations(config, &mut report);
+    check_auth(config, &mut report);
+    check_flow_collector(config, &mut report);
+    check_tenants(config, &mut report);
+    check_storage(config, &mut report, cfg!(debug_assertions));
+    report
+}
+
+fn check_thresholds(config: &Config, report: &mut CheckReport) {
+    let mut problems = Vec::new();
+    let rate_limit = &config.rate_limit;
+    if rate_limit.default_limit == 0 || rate_limit.window_seconds == 0 {
+        problems.push("rate_limit.default_limit and rate_limit.window_seconds must be positive".to_string());
+    }
+    if rate_limit.burst_size < rate_limit.default_limit {
+        problems.push(format!(
+            "rate_limit.burst_size ({}) is below rate_limit.default_limit ({})",
+            rate_limit.burst_size, rate_limit.default_limit
+        ));
+    }
+    for (prefix, cost) in &rate_limit.path_costs {
+        if *cost == 0 {
+            problems.push(format!("rate_limit.path_costs for {} must be positive", prefix));
+        }
+    }
+    if config.redis.pool_size == 0 {
+        problems.push("redis.pool_size must be positive".to_string());
+    }
+    if config.redis.topology == RedisTopology::Sentinel && config.redis.sentinels.is_empty() {
+        problems.push("redis.sentinels is required with the sentinel topology".to_string());
+    }
+    let detection = &config.ddos_detection;
+    if [
+        detection.connection_rate_window,
+        detection.request_rate_window,
+        detection.traffic_volume_window,
+        detection.anomaly_window,
+    ]
+    .contains(&0)
+    {
+        problems.push("ddos_detection windows must be positive".to_string());
+    }
+    let monitoring = &config.monitoring;
+    if monitoring.interval_seconds == 0 {
+        problems.push("monitoring.interval_seconds must be positive".to_string());
+    }
+    for (name, threshold) in [
+        ("cpu_usage", monitoring.alert_thresholds.cpu_usage),
+        ("memory_usage", monitoring.alert_thresholds.memory_usage),
+    ] {
+        if !(0.0..=100.0).contains(&threshold) {
+            problems.push(format!("monitoring.alert_thresholds.{} ({}) is not a percentage", name, threshold));
+        }
+    }
+    let watchdog = &monitoring.watchdog;
+    if watchdog.initial_backoff_seconds > watchdog.max_backoff_seconds {
+        problems.push("monitoring.watchdog.initial_backoff_seconds exceeds max_backoff_seconds".to_string());
+    }
+    if watchdog.heartbeat_timeout_seconds < monitoring.interval_seconds as u64 * 2 {
+        problems.push(format!(
+            "monitoring.watchdog.heartbeat_timeout_seconds ({}) must be at least twice monitoring.interval_seconds ({})",
+            watchdog.heartbeat_timeout_seconds, monitoring.interval_seconds
+        ));
+    }
+    if monitoring.health.redis_max_latency_ms >= monitoring.health.redis_timeout_ms {
+        problems.push("monitoring.health.redis_max_latency_ms must be less than redis_timeout_ms".to_string());
+    }
+    report.require("Thresholds", problems, "limits, windows and alert thresholds are consistent");
+}
+
+fn check_geoip(config: &Config, report: &mut CheckReport) {
+    let geoip = &config.geoip;
+    if !geoip.enabled {
+        return;
+    }
+    let databases: Vec<&String> = [&geoip.country_database, &geoip.asn_database].into_iter().flatten().collect();
+    if databases.is_empty() {
+        report.fail("GeoIP", "enabled, but neither geoip.country_database nor geoip.asn_database is set");
+        return;
+    }
+    let missing: Vec<String> = databases
+        .iter()
+        .filter(|path| !Path::new(path.as_str()).is_file())
+        .map(|path| format!("database {} not found", path))
+        .collect();
+    report.require("GeoIP", missing, "databases present");
+}
+
+fn check_integrations(config: &Config, report: &mut CheckReport) {
+    let mut problems = Vec::new();
+    let mut warnings = Vec::new();
+
+    if config.cloudflare.enabled {
+        if config.cloudflare.api_token.is_empty() {
+            problems.push("cloudflare.api_token is required".to_string());
+        }
+        if config.cloudflare.zone_id.is_none() && config.cloudflare.zone_name.is_none() {
+            problems.push("cloudflare.zone_id or cloudflare.zone_name is required".to_string());
+        }
+        if config.cloudflare.block_duration_seconds == 0 {
+            problems.push("cloudflare.block_duration_seconds must be positive".to_string());
+        }
+    }
+    if config.crowdsec.enabled && config.crowdsec.bouncer_api_key.is_none() {
+        problems.push("crowdsec.bouncer_api_key is required".to_string());
+    }
+    if config.crowdsec.enabled
+        && config.crowdsec.push_alerts
+        && (config.crowdsec.machine_id.is_none() || config.crowdsec.machine_password.is_none()) {
+        problems.push("crowdsec.machine_id and crowdsec.machine_password are required to push alerts".to_string());
+    }
+    if config.abuseipdb.enabled && config.abuseipdb.api_key.is_none() {
+        problems.push("abuseipdb.api_key is required".to_string());
+    }
+    if config.challenge.mode == ChallengeMode::Captcha
+        && (config.challenge.captcha.site_key.is_empty() || config.challenge.captcha.secret.is_empty())
+    {
+        problems.push("challenge.captcha.site_key and challenge.captcha.secret are required in captcha mode".to_string());
+    }
+    if config.challenge.secret.is_empty() {
+        warnings.push("challenge.secret is unset, so passes only work on the instance that issued them");
+    }
+    if config.email.enabled {
+        if config.email.smtp_host.is_empty() {
+            problems.push("email.smtp_host is required".to_string());
+        }
+        if config.email.alert_recipients.is_empty() && config.email.report_recipients.is_empty() {
+            warnings.push("email is enabled without any recipients");
+        }
+    }
+    if config.event_archive.enabled
+        && (config.event_archive.bucket.is_empty()
+            || config.event_archive.access_key_id.is_empty()
+            || config.event_archive.secret_access_key.is_empty())
+    {
+        problems.push("event_archive.bucket, event_archive.access_key_id and event_archive.secret_access_key are required".to_string());
+    }
+
+    if problems.is_empty() && !warnings.is_empty() {
+        report.warn("Integrations", warnings.join("; "));
+    } else {
+        report.require("Integrations", problems, "enabled integrations have their credentials");
+    }
+}
+
+fn check_auth(config: &Config, report: &mut CheckReport) {
+    let auth = &config.auth;
+    if !auth.enabled {
+        return;
+    }
+    let mut problems = Vec::new();
+    if auth.keys.is_empty() {
+        problems.push("enabled, but auth.keys is empty".to_string());
+    }
+    for (index, key) in auth.keys.iter().enumerate() {
+        if key.key.is_empty() {
+            problems.push(format!("auth.keys[{}] ({}) has an empty key", index, key.name));
+        } else if auth.keys[..index].iter().any(|other| other.key == key.key) {
+            problems.push(format!("auth.keys[{}] ({}) reuses the key of an earlier entry", index, key.name));
+        }
+    }
+    report.require("Access control", problems, "management keys configured");
+}
+
+fn check_flow_collector(config: &Config, report: &mut CheckReport) {
+    let flow_collector = &config.flow_collector;
+    if !flow_collector.enabled {
+        return;
+    }
+    let mut problems = Vec::new();
+    if flow_collector.exporters.is_empty() {
+        problems.push("enabled, but flow_collector.exporters is empty".to_string());
+    }
+    for exporter in &flow_collector.exporters {
+        if parse_network(exporter).is_none() {
+            problems.push(format!("flow_collector.exporters has an invalid address: {}", exporter));
+        }
+    }
+    report.require("Flow collector", problems, "exporters configured");
+}
+
+/// The memory backend only covers counters, sorted sets and event logs, and
+/// isn't shared between instances, so release builds refuse it
+fn check_storage(config: &Config, report: &mut CheckReport, development: bool) {
+    if config.storage.backend != StorageBackend::Memory {
+        return;
+    }
+    let mut problems = Vec::new();
+    if !development {
+        problems.push("storage.backend = memory is only for development builds and tests".to_string());
+    }
+    report.require("Storage", problems, "memory backend, for development only; Redis is still required");
+}
+
+fn check_tenants(config: &Config, report: &mut CheckReport) {
+    let tenants = &config.tenants;
+    if !tenants.enabled {
+        return;
+    }
+    let mut problems = Vec::new();
+    if HeaderName::from_bytes(tenants.header.as_bytes()).is_err() {
+        problems.push(format!("tenants.header ({:?}) is not a valid header name", tenants.header));
+    }
+    report.require("Tenants", problems, "tenant header is valid");
+}
+
+async fn check_redis(config: &Config, report: &mut CheckReport) {
+    let ping = async {
+        let pool = RedisPool::connect(&config.redis).await?;
+        redis::cmd("PING").query_async::<_, String>(&mut pool.get()).await?;
+        Ok::<_, redis::RedisError>(pool.stats().nodes.join(", "))
+    };
+    match tokio::time::timeout(REDIS_TIMEOUT, ping).await {
+        Ok(Ok(nodes)) => report.ok("Redis", format!("{} is reachable", nodes)),
+        Ok(Err(e)) => report.fail("Redis", e.to_string()),
+        Err(_) => report.fail("Redis", format!("no reply within {:?}", REDIS_TIMEOUT)),
+    }
+}
+
+async fn check_postgres(config: &Config, report: &mut CheckReport) {
+    if !config.postgres.enabled {
+        return;
+    }
+    let timeout = Duration::from_secs(config.postgres.timeout_seconds.max(1));
+    let ping = async {
+        let postgres = Postgres::new(&config.postgres.url, timeout)?;
+        postgres.execute("SELECT 1", &[]).await
+    };
+    match ping.await {
+        Ok(()) => report.ok("PostgreSQL", "reachable"),
+        Err(e) => report.fail("PostgreSQL", e.to_string()),
+    }
+}
+
+async fn check_clickhouse(config: &Config, report: &mut CheckReport) {
+    if config.analytics.storage_type != "clickhouse" {
+        return;
+    }
+    let ping = async {
+        let clickhouse = ClickHouse::new(config.clickhouse.clone(), config.analytics.retention_days)?;
+        clickhouse.ping().await
+    };
+    match ping.await {
+        Ok(()) => report.ok("ClickHouse", format!("{} is reachable", config.clickhouse.url)),
+        Err(e) => report.fail("ClickHouse", e.to_string()),
+    }
+}
+
+async fn check_rules_file(config: &Config, report: &mut CheckReport) {
+    let Some(path) = &config.rule_config.rules_file else {
+        return;
+    };
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) => {
+            report.warn("Rules file", format!("{} can't be read: {}", path, e));
+            return;
+        }
+    };
+    let yaml = path.ends_with(".yaml") || path.ends_with(".yml");
+    match RuleSet::parse(&contents, yaml) {
+        Ok(rule_set) => report.ok("Rules file", format!("{} has {} rules", path, rule_set.rules.len())),
+        Err(e) => report.fail("Rules file", format!("{} doesn't parse: {}", path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_settings() {
+        let report = check_settings(&Config::default());
+        assert!(report.passed(), "{}", report);
+
+        let mut config = Config::default();
+        config.rate_limit.path_costs.insert("/free".to_string(), 0);
+        assert!(!check_settings(&config).passed());
+
+        let mut config = Config::default();
+        config.rate_limit.burst_size = config.rate_limit.default_limit - 1;
+        config.cloudflare.enabled = true;
+        config.geoip.enabled = true;
+        config.geoip.country_database = Some("/nonexistent/GeoLite2-Country.mmdb".to_string());
+        config.auth.enabled = true;
+        config.flow_collector.enabled = true;
+        config.tenants.enabled = true;
+        config.tenants.header = "X Tenant".to_string();
+        let report = check_settings(&config);
+        assert!(!report.passed());
+        let failed: Vec<&str> = report.with_status(CheckStatus::Failed).map(|result| result.name.as_str()).collect();
+        assert_eq!(failed, vec!["Thresholds", "GeoIP", "Integrations", "Access control", "Flow collector", "Tenants"]);
+    }
+
+    #[test]
+    fn test_check_storage() {
+        let mut config = Config::default();
+        config.storage.backend = StorageBackend::Memory;
+        let mut report = CheckReport::default();
+        check_storage(&config, &mut report, true);
+        assert!(report.passed());
+        check_storage(&config, &mut report, false);
+        assert!(!report.passed());
+    }
+}
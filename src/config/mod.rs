@@ -1,52 +1,252 @@
 //! Configuration management for the DDoS protection service.
-//! 
-//! This module handles loading and managing application configuration
-//! from environment variables and configuration files.
+//!
+//! Configuration is layered, each layer overriding only the settings it
+//! gives:
+//!
+//! 1. built-in defaults
+//! 2. a TOML or YAML file: `CONFIG_FILE`, or `config/default.toml` if present
+//! 3. flat environment variables such as `REDIS_URL` (see `.env.example`)
+//! 4. `DDOS__`-prefixed environment variables naming any setting by its
+//!    path, such as `DDOS__RATE_LIMIT__DEFAULT_LIMIT=200`
+//!
+//! Every invalid setting is reported together, named by its path or
+//! variable.
 
-use std::env;
-use config::{Config as ConfigBuilder, ConfigError, Environment, File};
+mod check;
+
+pub use check::{check_config, CheckStatus};
+
+use serde_json::{Map, Value};
+use std::path::Path;
+use thiserror::Error;
 use crate::models::Config;
+use crate::utils::yaml_to_json;
+
+/// Configuration file used when `CONFIG_FILE` isn't set
+const DEFAULT_CONFIG_FILE: &str = "config/default.toml";
+/// Prefix of environment variables naming a setting by its path
+const ENV_PREFIX: &str = "DDOS__";
+/// Separates the sections of a setting's path in environment variable names
+const ENV_SEPARATOR: &str = "__";
 
-/// Load configuration from environment variables
+/// Errors that can occur while loading the configuration
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read configuration file {path}: {message}")]
+    File { path: String, message: String },
+    #[error("Invalid configuration:\n{}", .0.iter().map(|error| format!("  - {}", error)).collect::<Vec<_>>().join("\n"))]
+    Invalid(Vec<String>),
+}
+
+/// Load the configuration from its layers
 pub fn load_config() -> Result<Config, ConfigError> {
-    let config_file = env::var("CONFIG_FILE").unwrap_or_else(|_| "config/default.toml".to_string());
-    
-    let config = ConfigBuilder::builder()
-        .add_source(File::with_name(&config_file))
-        .add_source(Environment::default())
-        .set_default("server.host", "127.0.0.1")?
-        .set_default("server.port", 8080)?
-        .set_default("redis.url", "redis://127.0.0.1:6379")?
-        .set_default("redis.pool_size", 10)?
-        .set_default("rate_limit.default_limit", 100)?
-        .set_default("rate_limit.burst_size", 200)?
-        .set_default("rate_limit.window_seconds", 60)?
-        // DDoS detection defaults
-        .set_default("ddos_detection.connection_rate_threshold", 100)?
-        .set_default("ddos_detection.connection_rate_window", 60)?
-        .set_default("ddos_detection.request_rate_threshold", 1000)?
-        .set_default("ddos_detection.request_rate_window", 60)?
-        .set_default("ddos_detection.traffic_volume_threshold", 10_000_000)?
-        .set_default("ddos_detection.traffic_volume_window", 60)?
-        .set_default("ddos_detection.anomaly_threshold", 3.0)?
-        .set_default("ddos_detection.anomaly_window", 300)?
-        // Rule engine defaults
-        .set_default("rule_config.rules_file", "config/rules.json")?
-        .set_default("rule_config.default_priority", 0)?
-        .set_default("rule_config.enabled", true)?
-        // Analytics defaults
-        .set_default("analytics.enabled", true)?
-        .set_default("analytics.storage_type", "redis")?
-        .set_default("analytics.retention_days", 30)?
-        .set_default("analytics.real_time_enabled", true)?
-        // Monitoring defaults
-        .set_default("monitoring.enabled", true)?
-        .set_default("monitoring.interval_seconds", 60)?
-        .set_default("monitoring.alert_thresholds.cpu_usage", 80.0)?
-        .set_default("monitoring.alert_thresholds.memory_usage", 80.0)?
-        .set_default("monitoring.alert_thresholds.request_rate", 1000)?
-        .set_default("monitoring.alert_thresholds.error_rate", 10)?
-        .build()?;
-
-    config.try_deserialize()
-} 
\ No newline at end of file
+    dotenv::dotenv().ok();
+
+    let config = match std::env::var("CONFIG_FILE") {
+        Ok(path) => from_file(Config::default(), &path, true)?,
+        Err(_) => from_file(Config::default(), DEFAULT_CONFIG_FILE, false)?,
+    };
+    let config = config.with_env().map_err(ConfigError::Invalid)?;
+    with_prefixed_env(config, std::env::vars())
+}
+
+/// Override a configuration with a TOML or YAML file, chosen by its extension
+fn from_file(base: Config, path: &str, required: bool) -> Result<Config, ConfigError> {
+    let file_error = |message: String| ConfigError::File {
+        path: path.to_string(),
+        message,
+    };
+    if !required && !Path::new(path).exists() {
+        return Ok(base);
+    }
+    let contents = std::fs::read_to_string(path).map_err(|e| file_error(e.to_string()))?;
+    let overrides = if path.ends_with(".yaml") || path.ends_with(".yml") {
+        let mut documents = yaml_rust::YamlLoader::load_from_str(&contents).map_err(|e| file_error(e.to_string()))?;
+        if documents.is_empty() {
+            return Ok(base);
+        }
+        yaml_to_json(documents.remove(0))
+    } else {
+        toml::from_str(&contents).map_err(|e| file_error(e.to_string()))?
+    };
+
+    let base = to_value(&base);
+    let mut merged = base.clone();
+    merge(&mut merged, overrides);
+    deserialize(&base, merged)
+}
+
+/// Override a configuration with `DDOS__`-prefixed variables
+///
+/// Path sections are matched case-insensitively. A value replaces a string
+/// setting as is, is split on commas for a list setting unless it is a JSON
+/// array, and is read as JSON otherwise, falling back to a string.
+fn with_prefixed_env(base: Config, vars: impl Iterator<Item = (String, String)>) -> Result<Config, ConfigError> {
+    let base = to_value(&base);
+    let mut merged = base.clone();
+    let mut errors = Vec::new();
+
+    for (name, value) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path: Vec<String> = path.split(ENV_SEPARATOR).map(str::to_lowercase).collect();
+        if let Err(e) = set_path(&mut merged, &path, &value) {
+            errors.push(format!("{}: {}", name, e));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ConfigError::Invalid(errors));
+    }
+    deserialize(&base, merged)
+}
+
+fn to_value(config: &Config) -> Value {
+    serde_json::to_value(config).expect("configuration serializes to JSON")
+}
+
+/// Merge `overrides` into `target`, recursing into tables
+fn merge(target: &mut Value, overrides: Value) {
+    match (target, overrides) {
+        (Value::Object(target), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match target.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (target, value) => *target = value,
+    }
+}
+
+/// Set the setting at `path` from an environment variable value
+fn set_path(target: &mut Value, path: &[String], value: &str) -> Result<(), String> {
+    let (last, parents) = path.split_last().ok_or("empty setting path")?;
+    let mut table = target;
+    for (depth, section) in parents.iter().enumerate() {
+        table = table
+            .get_mut(section.as_str())
+            .filter(|table| table.is_object())
+            .ok_or_else(|| format!("unknown setting {}", path[..=depth].join(".")))?;
+    }
+    let Value::Object(table) = table else {
+        return Err(format!("unknown setting {}", path.join(".")));
+    };
+
+    let parsed = match table.get(last.as_str()) {
+        Some(Value::String(_)) => Value::String(value.to_string()),
+        Some(Value::Array(_)) if !value.trim_start().starts_with('[') => Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(parse_scalar)
+                .collect(),
+        ),
+        _ => parse_scalar(value),
+    };
+    table.insert(last.clone(), parsed);
+    Ok(())
+}
+
+fn parse_scalar(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+/// Deserialize a merged configuration, naming each setting that doesn't fit
+///
+/// When deserialization fails, each setting that differs from `base` is
+/// tried on its own, so every invalid one is reported.
+fn deserialize(base: &Value, merged: Value) -> Result<Config, ConfigError> {
+    let error = match serde_json::from_value(merged.clone()) {
+        Ok(config) => return Ok(config),
+        Err(e) => e,
+    };
+
+    let mut changes = Vec::new();
+    changed_settings(base, &merged, &mut Vec::new(), &mut changes);
+    let mut errors: Vec<String> = changes
+        .into_iter()
+        .filter_map(|(path, value)| {
+            let mut candidate = base.clone();
+            let mut table = &mut candidate;
+            for section in &path {
+                table = table
+                    .as_object_mut()?
+                    .entry(section.clone())
+                    .or_insert_with(|| Value::Object(Map::new()));
+            }
+            *table = value;
+            serde_json::from_value::<Config>(candidate)
+                .err()
+                .map(|e| format!("{}: {}", path.join("."), e))
+        })
+        .collect();
+    if errors.is_empty() {
+        errors.push(error.to_string());
+    }
+    Err(ConfigError::Invalid(errors))
+}
+
+/// Collect the settings in `merged` that differ from `base`, by path
+fn changed_settings(base: &Value, merged: &Value, path: &mut Vec<String>, changes: &mut Vec<(Vec<String>, Value)>) {
+    match (base, merged) {
+        (Value::Object(base), Value::Object(merged)) if !merged.is_empty() => {
+            for (key, value) in merged {
+                path.push(key.clone());
+                changed_settings(base.get(key).unwrap_or(&Value::Null), value, path, changes);
+                path.pop();
+            }
+        }
+        (base, merged) if base != merged => changes.push((path.clone(), merged.clone())),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layers() {
+        let defaults = Config::default();
+        assert_eq!(to_value(&deserialize(&to_value(&defaults), to_value(&defaults)).unwrap()), to_value(&defaults));
+
+        let config = from_file(defaults, DEFAULT_CONFIG_FILE, true).unwrap();
+        let vars = vec![
+            ("DDOS__RATE_LIMIT__DEFAULT_LIMIT".to_string(), "250".to_string()),
+            ("DDOS__SERVER__HOST".to_string(), "0.0.0.0".to_string()),
+            ("DDOS__TRUSTED_PROXIES__NETWORKS".to_string(), "10.0.0.0/8, 192.168.0.0/16".to_string()),
+            ("REDIS_URL".to_string(), "redis://elsewhere:6379".to_string()),
+        ];
+        let config = with_prefixed_env(config, vars.into_iter()).unwrap();
+        assert_eq!(config.rate_limit.default_limit, 250);
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.trusted_proxies.networks, vec!["10.0.0.0/8", "192.168.0.0/16"]);
+        // Left as the file has it
+        assert_eq!(config.rate_limit.burst_size, Config::default().rate_limit.burst_size);
+    }
+
+    #[test]
+    fn test_invalid_settings_are_listed() {
+        let vars = vec![
+            ("DDOS__RATE_LIMIT__DEFAULT_LIMIT".to_string(), "lots".to_string()),
+            ("DDOS__FIREWALL__BACKEND".to_string(), "pf".to_string()),
+        ];
+        let Err(ConfigError::Invalid(errors)) = with_prefixed_env(Config::default(), vars.into_iter()) else {
+            panic!("invalid settings were accepted");
+        };
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|error| error.starts_with("rate_limit.default_limit:")));
+        assert!(errors.iter().any(|error| error.starts_with("firewall.backend:")));
+
+        let vars = vec![("DDOS__RATE_LIMITS__DEFAULT_LIMIT".to_string(), "100".to_string())];
+        let Err(ConfigError::Invalid(errors)) = with_prefixed_env(Config::default(), vars.into_iter()) else {
+            panic!("unknown setting was accepted");
+        };
+        assert_eq!(errors, vec!["DDOS__RATE_LIMITS__DEFAULT_LIMIT: unknown setting rate_limits"]);
+    }
+}
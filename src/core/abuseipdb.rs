@@ -0,0 +1,169 @@
+//! AbuseIPDB reporting for the DDoS protection service.
+//!
+//! This module reports clients confirmed by the DDoS detector to AbuseIPDB.
+//! Submissions are rate-limited through Redis so that every instance shares
+//! the per-IP interval and the daily limit.
+
+use std::net::IpAddr;
+use std::time::Duration;
+use chrono::Utc;
+use thiserror::Error;
+use crate::core::redis_pool::RedisPool;
+use crate::models::AbuseIpdbConfig;
+use crate::utils::normalize_ip;
+
+/// AbuseIPDB report endpoint
+const REPORT_URL: &str = "https://api.abuseipdb.com/api/v2/report";
+
+/// Longest comment AbuseIPDB accepts
+const MAX_COMMENT_LENGTH: usize = 1024;
+
+/// Errors that can occur while reporting
+#[derive(Error, Debug)]
+pub enum AbuseIpdbError {
+    #[error("Request error: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("No API key configured")]
+    MissingApiKey,
+}
+
+/// Confidence (0-100) that a detection is an attack, from how far the
+/// observed value exceeds its threshold: twice the threshold gives 50 and
+/// ten times gives 90
+pub fn detection_confidence(observed: u64, threshold: u64) -> u8 {
+    if observed == 0 || observed <= threshold {
+        return 0;
+    }
+    (100.0 * (1.0 - threshold as f64 / observed as f64)).round() as u8
+}
+
+/// Whether an address is publicly routable enough to be worth reporting
+fn is_reportable(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => !(addr.is_private()
+            || addr.is_loopback()
+            || addr.is_link_local()
+            || addr.is_unspecified()
+            || addr.is_multicast()
+            || addr.is_broadcast()),
+        IpAddr::V6(addr) => !(addr.is_loopback() || addr.is_unspecified() || addr.is_multicast()),
+    }
+}
+
+/// AbuseIPDB client reporting detected attackers
+///
+/// Cloning is cheap and all clones share the same rate limits.
+#[derive(Clone)]
+pub struct AbuseIpdb {
+    /// HTTP client
+    client: reqwest::Client,
+    /// Redis connection pool
+    redis: RedisPool,
+    /// AbuseIPDB configuration
+    config: AbuseIpdbConfig,
+}
+
+impl AbuseIpdb {
+    /// Create a new AbuseIPDB client
+    pub fn new(redis: RedisPool, config: AbuseIpdbConfig) -> Result<Self, AbuseIpdbError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self { client, redis, config })
+    }
+
+    /// Report an attacker, returning whether a report was submitted
+    ///
+    /// Detections below the minimum confidence, addresses that aren't
+    /// publicly routable (including IPv6 subnets) and IPs reported within
+    /// the interval are skipped, as is everything once the daily limit is
+    /// reached.
+    pub async fn report(&self, ip: &str, comment: &str, confidence: u8) -> Result<bool, AbuseIpdbError> {
+        if !self.config.enabled || confidence < self.config.min_confidence {
+            return Ok(false);
+        }
+        let api_key = self.config.api_key.as_deref().ok_or(AbuseIpdbError::MissingApiKey)?;
+        let addr = match normalize_ip(ip) {
+            Some(addr) if is_reportable(addr) => addr,
+            _ => return Ok(false),
+        };
+
+        let mut conn = self.redis.get();
+        let first: Option<String> = redis::cmd("SET")
+            .arg(format!("abuseipdb:reported:{}", addr))
+            .arg(1)
+            .arg("EX")
+            .arg(self.config.report_interval_seconds.max(1))
+            .arg("NX")
+            .query_async(&mut conn)
+            .await?;
+        if first.is_none() {
+            return Ok(false);
+        }
+
+        let daily_key = format!("abuseipdb:reports:{}", Utc::now().format("%Y%m%d"));
+        let (reports,): (u64,) = redis::pipe()
+            .cmd("INCR")
+            .arg(&daily_key)
+            .cmd("EXPIRE")
+            .arg(&daily_key)
+            .arg(2 * 86400)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        if reports > self.config.daily_limit {
+            return Ok(false);
+        }
+
+        let categories: Vec<String> = self.config.categories.iter().map(u32::to_string).collect();
+        let comment: String = comment.chars().take(MAX_COMMENT_LENGTH).collect();
+        self.client
+            .post(REPORT_URL)
+            .header("Key", api_key)
+            .header("Accept", "application/json")
+            .form(&[
+                ("ip", addr.to_string()),
+                ("categories", categories.join(",")),
+                ("comment", comment),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        metrics::increment_counter!("abuseipdb_reports_total");
+        Ok(true)
+    }
+
+    /// Report an attacker without waiting for the submission
+    pub fn report_in_background(&self, ip: &str, comment: &str, confidence: u8) {
+        let reporter = self.clone();
+        let ip = ip.to_string();
+        let comment = comment.to_string();
+        tokio::spawn(async move {
+            match reporter.report(&ip, &comment, confidence).await {
+                Ok(true) => log::info!("Reported {} to AbuseIPDB", ip),
+                Ok(false) => (),
+                Err(e) => log::error!("Failed to report {} to AbuseIPDB: {}", ip, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detection_confidence() {
+        assert_eq!(detection_confidence(100, 100), 0);
+        assert_eq!(detection_confidence(200, 100), 50);
+        assert_eq!(detection_confidence(1000, 100), 90);
+        assert_eq!(detection_confidence(5, 0), 100);
+
+        assert!(is_reportable("203.0.113.7".parse().unwrap()));
+        assert!(!is_reportable("10.0.0.1".parse().unwrap()));
+        assert!(!is_reportable("::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,230 @@
+//! Alert notification channels for the DDoS protection service.
+//!
+//! Alerts are routed by level to Slack, as messages colored by severity,
+//! and to PagerDuty, as Events API v2 incidents that are triggered,
+//! acknowledged and resolved along with the alert.
+
+use async_trait::async_trait;
+use serde_json::json;
+use std::time::Duration;
+use crate::core::monitoring::{Alert, AlertLevel, AlertStatus};
+use crate::models::{PagerDutyConfig, SlackConfig};
+
+/// Longest incident summary PagerDuty accepts
+const MAX_SUMMARY_LENGTH: usize = 1024;
+
+/// A destination alerts are sent to when created or when their status changes
+#[async_trait]
+pub trait AlertChannel: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &str;
+
+    /// Whether alerts of this level are routed to the channel
+    fn routes(&self, level: &AlertLevel) -> bool;
+
+    /// Send an alert in its current status
+    async fn send(&self, alert: &Alert) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_default()
+}
+
+/// Slack incoming webhook channel
+pub struct SlackChannel {
+    config: SlackConfig,
+    webhook_url: String,
+    client: reqwest::Client,
+}
+
+impl SlackChannel {
+    /// Channel for the configuration, if a webhook URL is set
+    pub fn from_config(config: &SlackConfig) -> Option<Self> {
+        Some(Self {
+            webhook_url: config.webhook_url.clone()?,
+            config: config.clone(),
+            client: http_client(),
+        })
+    }
+}
+
+/// Slack message for an alert
+fn slack_message(alert: &Alert) -> serde_json::Value {
+    let (color, emoji) = match alert.level {
+        AlertLevel::Info => ("#439FE0", ":information_source:"),
+        AlertLevel::Warning => ("warning", ":warning:"),
+        AlertLevel::Error => ("danger", ":x:"),
+        AlertLevel::Critical => ("danger", ":rotating_light:"),
+    };
+    let title = match alert.status {
+        AlertStatus::Active => format!("{} {:?}: {}", emoji, alert.level, alert.source),
+        AlertStatus::Acknowledged => format!(":eyes: Acknowledged: {}", alert.source),
+        AlertStatus::Resolved => format!(":white_check_mark: Resolved: {}", alert.source),
+    };
+    let color = match alert.status {
+        AlertStatus::Resolved => "good",
+        _ => color,
+    };
+
+    json!({
+        "text": format!("{} - {}", title, alert.message),
+        "attachments": [{
+            "color": color,
+            "title": title,
+            "text": alert.message,
+            "fields": [
+                {"title": "Level", "value": format!("{:?}", alert.level), "short": true},
+                {"title": "Status", "value": format!("{:?}", alert.status), "short": true},
+            ],
+            "footer": format!("Alert {}", alert.id),
+            "ts": alert.created_at.timestamp(),
+        }],
+    })
+}
+
+#[async_trait]
+impl AlertChannel for SlackChannel {
+    fn name(&self) -> &str {
+        "slack"
+    }
+
+    fn routes(&self, level: &AlertLevel) -> bool {
+        self.config.levels.contains(level)
+    }
+
+    async fn send(&self, alert: &Alert) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.client
+            .post(&self.webhook_url)
+            .json(&slack_message(alert))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// PagerDuty Events API v2 channel
+pub struct PagerDutyChannel {
+    config: PagerDutyConfig,
+    routing_key: String,
+    client: reqwest::Client,
+}
+
+impl PagerDutyChannel {
+    /// Channel for the configuration, if a routing key is set
+    pub fn from_config(config: &PagerDutyConfig) -> Option<Self> {
+        Some(Self {
+            routing_key: config.routing_key.clone()?,
+            config: config.clone(),
+            client: http_client(),
+        })
+    }
+}
+
+/// PagerDuty event for an alert, deduplicated by alert ID
+fn pagerduty_event(routing_key: &str, alert: &Alert) -> serde_json::Value {
+    let action = match alert.status {
+        AlertStatus::Active => "trigger",
+        AlertStatus::Acknowledged => "acknowledge",
+        AlertStatus::Resolved => "resolve",
+    };
+    let mut event = json!({
+        "routing_key": routing_key,
+        "event_action": action,
+        "dedup_key": alert.id,
+    });
+    if alert.status == AlertStatus::Active {
+        let severity = match alert.level {
+            AlertLevel::Info => "info",
+            AlertLevel::Warning => "warning",
+            AlertLevel::Error => "error",
+            AlertLevel::Critical => "critical",
+        };
+        let summary: String = alert.message.chars().take(MAX_SUMMARY_LENGTH).collect();
+        event["payload"] = json!({
+            "summary": summary,
+            "source": alert.source,
+            "severity": severity,
+            "timestamp": alert.created_at.to_rfc3339(),
+            "component": "ddos-protection-service",
+        });
+    }
+    event
+}
+
+#[async_trait]
+impl AlertChannel for PagerDutyChannel {
+    fn name(&self) -> &str {
+        "pagerduty"
+    }
+
+    fn routes(&self, level: &AlertLevel) -> bool {
+        self.config.levels.contains(level)
+    }
+
+    async fn send(&self, alert: &Alert) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.client
+            .post(&self.config.events_url)
+            .json(&pagerduty_event(&self.routing_key, alert))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn alert(level: AlertLevel, status: AlertStatus) -> Alert {
+        Alert {
+            id: "alert-1".to_string(),
+            level,
+            message: "Request rate above threshold".to_string(),
+            source: "monitoring".to_string(),
+            status,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            acknowledged_at: None,
+            resolved_at: None,
+            labels: Default::default(),
+            fingerprint: None,
+            occurrences: 1,
+            silenced_by: None,
+            escalation_level: 0,
+            escalated_at: None,
+        }
+    }
+
+    #[test]
+    fn test_slack_message() {
+        let message = slack_message(&alert(AlertLevel::Critical, AlertStatus::Active));
+        let attachment = &message["attachments"][0];
+        assert_eq!(attachment["color"], "danger");
+        assert_eq!(attachment["title"], ":rotating_light: Critical: monitoring");
+        assert_eq!(attachment["fields"][1]["value"], "Active");
+
+        let message = slack_message(&alert(AlertLevel::Critical, AlertStatus::Resolved));
+        assert_eq!(message["attachments"][0]["color"], "good");
+        assert_eq!(message["attachments"][0]["title"], ":white_check_mark: Resolved: monitoring");
+    }
+
+    #[test]
+    fn test_pagerduty_event() {
+        let event = pagerduty_event("key", &alert(AlertLevel::Error, AlertStatus::Active));
+        assert_eq!(event["event_action"], "trigger");
+        assert_eq!(event["dedup_key"], "alert-1");
+        assert_eq!(event["payload"]["severity"], "error");
+
+        let event = pagerduty_event("key", &alert(AlertLevel::Error, AlertStatus::Acknowledged));
+        assert_eq!(event["event_action"], "acknowledge");
+        assert!(event.get("payload").is_none());
+        let event = pagerduty_event("key", &alert(AlertLevel::Error, AlertStatus::Resolved));
+        assert_eq!(event["event_action"], "resolve");
+    }
+}
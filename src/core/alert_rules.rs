@@ -0,0 +1,418 @@
+//! Alert rules for the DDoS protection service.
+//!
+//! An alert rule compares one of the system metrics against a threshold and
+//! raises an alert once the comparison has held for a number of consecutive
+//! samples and for the rule's duration. While it keeps holding, the same
+//! alert is updated rather than a new one raised; once the metric gets back
+//! across the resolve threshold, the alert is resolved.
+//!
+//! Rules come from the configuration, including the classic CPU, memory,
+//! request rate and error rate thresholds, and from the API. They live in
+//! Redis, shared by all instances; configured rules are added on startup
+//! unless a rule with the same ID already exists, so API changes stick.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+use uuid::Uuid;
+use crate::core::monitoring::{AlertLevel, SystemMetrics};
+use crate::core::redis_pool::RedisPool;
+use crate::models::AlertThresholds;
+use crate::utils::{hex, sha256};
+
+/// Redis hash holding alert rules keyed by ID
+const RULES_KEY: &str = "monitoring:alert_rules";
+
+/// Errors that can occur during alert rule operations
+#[derive(Error, Debug)]
+pub enum AlertRuleError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Invalid alert rule: {0}")]
+    InvalidRule(String),
+}
+
+/// How a metric is compared with a rule's threshold
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Comparison {
+    GreaterThan,
+    GreaterThanOrEqual,
+    LessThan,
+    LessThanOrEqual,
+    Equals,
+    NotEquals,
+}
+
+impl Comparison {
+    /// Whether `value` compares to `threshold` this way
+    pub fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::GreaterThan => value > threshold,
+            Comparison::GreaterThanOrEqual => value >= threshold,
+            Comparison::LessThan => value < threshold,
+            Comparison::LessThanOrEqual => value <= threshold,
+            Comparison::Equals => value == threshold,
+            Comparison::NotEquals => value != threshold,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Comparison::GreaterThan => ">",
+            Comparison::GreaterThanOrEqual => ">=",
+            Comparison::LessThan => "<",
+            Comparison::LessThanOrEqual => "<=",
+            Comparison::Equals => "==",
+            Comparison::NotEquals => "!=",
+        }
+    }
+}
+
+/// A condition on the system metrics that raises an alert
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AlertRule {
+    pub id: String,
+    pub name: String,
+    /// System metric compared, such as `cpu_usage` or `request_rate`
+    pub metric: String,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// Threshold the metric must get back across for the alert to resolve,
+    /// so a metric hovering around the threshold doesn't flap; the trigger
+    /// threshold when unset
+    #[serde(default)]
+    pub resolve_threshold: Option<f64>,
+    /// How long the comparison must hold before the alert is raised, in seconds
+    #[serde(default)]
+    pub duration_seconds: u64,
+    /// Level of the alert raised
+    pub severity: AlertLevel,
+    /// Labels copied onto the alert, for routing and grouping
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl AlertRule {
+    /// Create an enabled rule with a new ID
+    pub fn new(name: &str, metric: &str, comparison: Comparison, threshold: f64, severity: AlertLevel) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            metric: metric.to_string(),
+            comparison,
+            threshold,
+            resolve_threshold: None,
+            duration_seconds: 0,
+            severity,
+            labels: HashMap::new(),
+            enabled: true,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Check that the rule names a known metric and can be evaluated
+    pub fn validate(&self) -> Result<(), AlertRuleError> {
+        if self.id.is_empty() || self.name.is_empty() {
+            return Err(AlertRuleError::InvalidRule("id and name must not be empty".to_string()));
+        }
+        if SystemMetrics::default().value(&self.metric).is_none() {
+            return Err(AlertRuleError::InvalidRule(format!("unknown metric: {}", self.metric)));
+        }
+        if !self.threshold.is_finite() || !self.resolve_threshold.unwrap_or(0.0).is_finite() {
+            return Err(AlertRuleError::InvalidRule("thresholds must be finite numbers".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Identifies the alerts the rule raises, so a matching rule updates its
+    /// open alert instead of raising another
+    pub fn fingerprint(&self) -> String {
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort();
+        let mut key = format!("alert_rule:{}", self.id);
+        for (name, value) in labels {
+            key.push_str(&format!("\n{}={}", name, value));
+        }
+        hex(&sha256(key.as_bytes())[..16])
+    }
+
+    /// Alert message for a value that matched the rule
+    pub fn message(&self, value: f64) -> String {
+        format!(
+            "{}: {} is {:.2} ({} {})",
+            self.name,
+            self.metric,
+            value,
+            self.comparison.symbol(),
+            self.threshold
+        )
+    }
+}
+
+/// Rules for the fixed alert thresholds
+pub fn threshold_rules(thresholds: &AlertThresholds) -> Vec<AlertRule> {
+    let rule = |id: &str, name: &str, metric: &str, threshold: f64, severity: AlertLevel| AlertRule {
+        id: id.to_string(),
+        ..AlertRule::new(name, metric, Comparison::GreaterThan, threshold, severity)
+    };
+    vec![
+        rule("high_cpu_usage", "High CPU Usage", "cpu_usage", thresholds.cpu_usage, AlertLevel::Warning),
+        rule("high_memory_usage", "High Memory Usage", "memory_usage", thresholds.memory_usage, AlertLevel::Warning),
+        rule("high_request_rate", "High Request Rate", "request_rate", thresholds.request_rate as f64, AlertLevel::Warning),
+        rule("high_error_rate", "High Error Rate", "error_rate", thresholds.error_rate as f64, AlertLevel::Error),
+    ]
+}
+
+/// Alert rule storage
+#[derive(Clone)]
+pub struct AlertRules {
+    redis: RedisPool,
+}
+
+impl AlertRules {
+    pub fn new(redis: RedisPool) -> Self {
+        Self { redis }
+    }
+
+    /// Add rules that don't exist yet, leaving existing ones as they are
+    pub async fn seed(&self, rules: &[AlertRule]) -> Result<(), AlertRuleError> {
+        let mut conn = self.redis.get();
+        let mut pipe = redis::pipe();
+        for rule in rules {
+            rule.validate()?;
+            pipe.cmd("HSETNX").arg(RULES_KEY).arg(&rule.id).arg(serde_json::to_string(rule)?).ignore();
+        }
+        let _: () = pipe.query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Create or replace a rule
+    pub async fn set_rule(&self, rule: &AlertRule) -> Result<(), AlertRuleError> {
+        rule.validate()?;
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("HSET")
+            .arg(RULES_KEY)
+            .arg(&rule.id)
+            .arg(serde_json::to_string(rule)?)
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Get a rule by ID
+    pub async fn get_rule(&self, id: &str) -> Result<Option<AlertRule>, AlertRuleError> {
+        let mut conn = self.redis.get();
+        let json: Option<String> = redis::cmd("HGET")
+            .arg(RULES_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Get all rules, by name
+    pub async fn get_rules(&self) -> Result<Vec<AlertRule>, AlertRuleError> {
+        let mut conn = self.redis.get();
+        let rules_json: Vec<String> = redis::cmd("HVALS")
+            .arg(RULES_KEY)
+            .query_async(&mut conn)
+            .await?;
+        let mut rules: Vec<AlertRule> = rules_json
+            .into_iter()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect();
+        rules.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(rules)
+    }
+
+    /// Remove a rule, returning whether it existed
+    pub async fn remove_rule(&self, id: &str) -> Result<bool, AlertRuleError> {
+        let mut conn = self.redis.get();
+        let removed: u32 = redis::cmd("HDEL")
+            .arg(RULES_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(removed > 0)
+    }
+}
+
+/// Where a rule stands after a sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuleState {
+    /// The comparison doesn't hold, or hasn't held for long enough yet
+    Pending,
+    /// The comparison holds, with the value that matched; the alert is raised or updated
+    Firing(f64),
+    /// The metric is back across the resolve threshold; any open alert is resolved
+    Recovered,
+}
+
+/// How far along a rule is towards firing
+#[derive(Debug, Default)]
+struct RuleProgress {
+    /// Consecutive samples the comparison held for
+    breaches: u32,
+    /// Time of the first of those samples
+    since: i64,
+    firing: bool,
+}
+
+/// Tracks how long each rule's comparison has held
+#[derive(Debug)]
+pub struct RuleEvaluator {
+    /// Consecutive matching samples needed before a rule fires
+    consecutive_breaches: u32,
+    progress: HashMap<String, RuleProgress>,
+}
+
+impl RuleEvaluator {
+    pub fn new(consecutive_breaches: u32) -> Self {
+        Self {
+            consecutive_breaches: consecutive_breaches.max(1),
+            progress: HashMap::new(),
+        }
+    }
+
+    /// State of each enabled rule after a sample
+    pub fn evaluate<'a>(&mut self, rules: &'a [AlertRule], metrics: &SystemMetrics) -> Vec<(&'a AlertRule, RuleState)> {
+        let mut states = Vec::new();
+        let mut seen = HashSet::new();
+        for rule in rules.iter().filter(|rule| rule.enabled) {
+            seen.insert(rule.id.as_str());
+            let progress = self.progress.entry(rule.id.clone()).or_default();
+            let value = metrics.value(&rule.metric);
+            let breached = value.filter(|value| rule.comparison.holds(*value, rule.threshold));
+            // A firing rule keeps firing until the metric gets back across the resolve threshold
+            let held = value.filter(|value| {
+                progress.firing && rule.comparison.holds(*value, rule.resolve_threshold.unwrap_or(rule.threshold))
+            });
+
+            let state = match (breached, held) {
+                (_, Some(value)) => RuleState::Firing(value),
+                (Some(value), None) => {
+                    if progress.breaches == 0 {
+                        progress.since = metrics.timestamp;
+                    }
+                    progress.breaches += 1;
+                    progress.firing = progress.breaches >= self.consecutive_breaches
+                        && metrics.timestamp - progress.since >= rule.duration_seconds as i64;
+                    if progress.firing {
+                        RuleState::Firing(value)
+                    } else {
+                        RuleState::Pending
+                    }
+                }
+                (None, None) => {
+                    *progress = RuleProgress::default();
+                    RuleState::Recovered
+                }
+            };
+            states.push((rule, state));
+        }
+        // Forget rules that were removed or disabled
+        self.progress.retain(|id, _| seen.contains(id.as_str()));
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: i64, cpu_usage: f64) -> SystemMetrics {
+        SystemMetrics {
+            cpu_usage,
+            timestamp,
+            ..SystemMetrics::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let thresholds = AlertThresholds {
+            cpu_usage: 80.0,
+            memory_usage: 80.0,
+            request_rate: 1000,
+            error_rate: 10,
+        };
+        assert!(threshold_rules(&thresholds).iter().all(|rule| rule.validate().is_ok()));
+
+        let rule = AlertRule::new("Slow", "response_time", Comparison::GreaterThan, 500.0, AlertLevel::Warning);
+        assert!(matches!(rule.validate(), Err(AlertRuleError::InvalidRule(_))));
+        let rule = AlertRule::new("Slow", "response_time_ms", Comparison::GreaterThan, f64::NAN, AlertLevel::Warning);
+        assert!(rule.validate().is_err());
+    }
+
+    fn state(evaluator: &mut RuleEvaluator, rules: &[AlertRule], timestamp: i64, cpu_usage: f64) -> RuleState {
+        evaluator.evaluate(rules, &sample(timestamp, cpu_usage))[0].1
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut rule = AlertRule::new("High CPU", "cpu_usage", Comparison::GreaterThan, 80.0, AlertLevel::Warning);
+        rule.duration_seconds = 120;
+        assert_eq!(rule.message(99.0), "High CPU: cpu_usage is 99.00 (> 80)");
+        let rules = vec![rule];
+        let mut evaluator = RuleEvaluator::new(1);
+
+        assert_eq!(state(&mut evaluator, &rules, 0, 90.0), RuleState::Pending);
+        assert_eq!(state(&mut evaluator, &rules, 60, 95.0), RuleState::Pending);
+        assert_eq!(state(&mut evaluator, &rules, 120, 99.0), RuleState::Firing(99.0));
+        assert_eq!(state(&mut evaluator, &rules, 180, 99.0), RuleState::Firing(99.0));
+
+        // Recovering starts the duration over
+        assert_eq!(state(&mut evaluator, &rules, 240, 50.0), RuleState::Recovered);
+        assert_eq!(state(&mut evaluator, &rules, 300, 90.0), RuleState::Pending);
+        assert_eq!(state(&mut evaluator, &rules, 420, 90.0), RuleState::Firing(90.0));
+    }
+
+    #[test]
+    fn test_consecutive_breaches_and_hysteresis() {
+        let mut rule = AlertRule::new("High CPU", "cpu_usage", Comparison::GreaterThan, 80.0, AlertLevel::Warning);
+        rule.resolve_threshold = Some(70.0);
+        let rules = vec![rule];
+        let mut evaluator = RuleEvaluator::new(3);
+
+        assert_eq!(state(&mut evaluator, &rules, 0, 90.0), RuleState::Pending);
+        assert_eq!(state(&mut evaluator, &rules, 60, 90.0), RuleState::Pending);
+        // A dip resets the count
+        assert_eq!(state(&mut evaluator, &rules, 120, 60.0), RuleState::Recovered);
+        assert_eq!(state(&mut evaluator, &rules, 180, 90.0), RuleState::Pending);
+        assert_eq!(state(&mut evaluator, &rules, 240, 90.0), RuleState::Pending);
+        assert_eq!(state(&mut evaluator, &rules, 300, 90.0), RuleState::Firing(90.0));
+
+        // Between the thresholds the alert stays open
+        assert_eq!(state(&mut evaluator, &rules, 360, 75.0), RuleState::Firing(75.0));
+        assert_eq!(state(&mut evaluator, &rules, 420, 65.0), RuleState::Recovered);
+        // Without having fired, the same value isn't a breach
+        assert_eq!(state(&mut evaluator, &rules, 480, 75.0), RuleState::Recovered);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut rule = AlertRule::new("High CPU", "cpu_usage", Comparison::GreaterThan, 80.0, AlertLevel::Warning);
+        let fingerprint = rule.fingerprint();
+        assert_eq!(fingerprint.len(), 32);
+        rule.threshold = 90.0;
+        rule.name = "Very high CPU".to_string();
+        assert_eq!(rule.fingerprint(), fingerprint);
+        rule.labels.insert("team".to_string(), "edge".to_string());
+        assert_ne!(rule.fingerprint(), fingerprint);
+    }
+}
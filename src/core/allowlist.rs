@@ -0,0 +1,288 @@
+//! Allowlist for the DDoS protection service.
+//!
+//! This module keeps the networks and API keys that bypass every protection
+//! layer. Configured entries are combined with entries managed at runtime,
+//! which are persisted in Redis and periodically reloaded so that all
+//! instances converge on the same set.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::core::prefix_trie::PrefixTrie;
+use crate::core::redis_pool::RedisPool;
+use crate::models::AllowlistConfig;
+use crate::utils;
+
+/// Redis hash holding runtime allowlist entries
+const ALLOWLIST_KEY: &str = "allowlist";
+
+/// Errors that can occur during allowlist operations
+#[derive(Error, Debug)]
+pub enum AllowlistError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Invalid network: {0}")]
+    InvalidNetwork(String),
+}
+
+/// Kind of allowlist entry
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AllowlistKind {
+    /// A CIDR range or single IP
+    Network,
+    /// An API key
+    ApiKey,
+}
+
+/// Runtime allowlist entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllowlistEntry {
+    /// Entry kind
+    pub kind: AllowlistKind,
+    /// Network in CIDR notation or API key
+    pub value: String,
+    /// Why the entry was added
+    pub description: Option<String>,
+    /// Creation timestamp
+    pub created_at: DateTime<Utc>,
+}
+
+impl AllowlistEntry {
+    fn field(&self) -> String {
+        entry_field(self.kind, &self.value)
+    }
+}
+
+fn entry_field(kind: AllowlistKind, value: &str) -> String {
+    match kind {
+        AllowlistKind::Network => format!("network:{}", value),
+        AllowlistKind::ApiKey => format!("api_key:{}", value),
+    }
+}
+
+fn parse_network(value: &str) -> Result<IpNet, AllowlistError> {
+    utils::parse_network(value).ok_or_else(|| AllowlistError::InvalidNetwork(value.to_string()))
+}
+
+/// In-memory set of allowlisted networks and API keys
+#[derive(Default)]
+pub struct AllowlistSet {
+    networks: PrefixTrie<()>,
+    api_keys: HashSet<String>,
+}
+
+impl AllowlistSet {
+    /// Build a set from configured networks and API keys
+    pub fn from_config(config: &AllowlistConfig) -> Result<Self, AllowlistError> {
+        let mut set = Self::default();
+        for network in &config.networks {
+            set.networks.insert(parse_network(network)?, ());
+        }
+        set.api_keys.extend(config.api_keys.iter().cloned());
+        Ok(set)
+    }
+
+    fn insert(&mut self, entry: &AllowlistEntry) -> Result<(), AllowlistError> {
+        match entry.kind {
+            AllowlistKind::Network => {
+                self.networks.insert(parse_network(&entry.value)?, ());
+            }
+            AllowlistKind::ApiKey => {
+                self.api_keys.insert(entry.value.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether an IP falls in an allowlisted network
+    pub fn contains_ip(&self, ip: &str) -> bool {
+        match utils::normalize_ip(ip) {
+            Some(ip) => self.networks.contains(ip),
+            None => false,
+        }
+    }
+
+    /// Whether an API key is allowlisted
+    pub fn contains_api_key(&self, api_key: &str) -> bool {
+        self.api_keys.contains(api_key)
+    }
+
+    /// Whether an allowlisted network overlaps `network`
+    pub fn overlaps(&self, network: &IpNet) -> bool {
+        self.networks.overlaps(network)
+    }
+}
+
+/// Shared allowlist
+///
+/// Cloning is cheap and all clones see the same entries.
+#[derive(Clone)]
+pub struct Allowlist {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Allowlist configuration
+    config: AllowlistConfig,
+    /// Configured and runtime entries
+    entries: Arc<RwLock<AllowlistSet>>,
+}
+
+impl Allowlist {
+    /// Create a new allowlist holding the configured entries
+    ///
+    /// Runtime entries are only available after the first `reload`.
+    pub fn new(redis: RedisPool, config: AllowlistConfig) -> Result<Self, AllowlistError> {
+        let entries = AllowlistSet::from_config(&config)?;
+        Ok(Self {
+            redis,
+            config,
+            entries: Arc::new(RwLock::new(entries)),
+        })
+    }
+
+    /// Whether a client bypasses protection, by IP or API key
+    pub async fn is_allowed(&self, ip: Option<&str>, api_key: Option<&str>) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+
+        let entries = self.entries.read().await;
+        ip.is_some_and(|ip| entries.contains_ip(ip))
+            || api_key.is_some_and(|api_key| entries.contains_api_key(api_key))
+    }
+
+    /// Whether blocking `network` would block allowlisted clients
+    ///
+    /// True if an allowlisted network contains `network` or lies inside it.
+    pub async fn overlaps(&self, network: &IpNet) -> bool {
+        self.config.enabled && self.entries.read().await.overlaps(network)
+    }
+
+    /// Rebuild the in-memory set from the configuration and Redis
+    #[tracing::instrument(name = "allowlist.reload", skip_all)]
+    pub async fn reload(&self) -> Result<(), AllowlistError> {
+        let mut set = AllowlistSet::from_config(&self.config)?;
+        for entry in self.get_entries().await? {
+            if let Err(e) = set.insert(&entry) {
+                log::warn!("Skipping invalid allowlist entry {}: {}", entry.value, e);
+            }
+        }
+
+        *self.entries.write().await = set;
+        Ok(())
+    }
+
+    /// Get the runtime entries stored in Redis
+    pub async fn get_entries(&self) -> Result<Vec<AllowlistEntry>, AllowlistError> {
+        let mut conn = self.redis.get();
+        let entries_json: Vec<String> = redis::cmd("HVALS")
+            .arg(ALLOWLIST_KEY)
+            .query_async(&mut conn)
+            .await?;
+        Ok(entries_json
+            .into_iter()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect())
+    }
+
+    /// Add a runtime entry, normalizing networks to CIDR notation
+    pub async fn add_entry(
+        &self,
+        kind: AllowlistKind,
+        value: &str,
+        description: Option<String>,
+    ) -> Result<AllowlistEntry, AllowlistError> {
+        let value = match kind {
+            AllowlistKind::Network => parse_network(value)?.to_string(),
+            AllowlistKind::ApiKey => value.to_string(),
+        };
+        let entry = AllowlistEntry {
+            kind,
+            value,
+            description,
+            created_at: Utc::now(),
+        };
+
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("HSET")
+            .arg(ALLOWLIST_KEY)
+            .arg(entry.field())
+            .arg(serde_json::to_string(&entry)?)
+            .query_async(&mut conn)
+            .await?;
+
+        self.entries.write().await.insert(&entry)?;
+        Ok(entry)
+    }
+
+    /// Remove a runtime entry by network or API key, returning whether it existed
+    pub async fn remove_entry(&self, value: &str) -> Result<bool, AllowlistError> {
+        let mut fields = vec![entry_field(AllowlistKind::ApiKey, value)];
+        if let Ok(network) = parse_network(value) {
+            fields.push(entry_field(AllowlistKind::Network, &network.to_string()));
+        }
+
+        let mut conn = self.redis.get();
+        let removed: u32 = redis::cmd("HDEL")
+            .arg(ALLOWLIST_KEY)
+            .arg(fields)
+            .query_async(&mut conn)
+            .await?;
+
+        if removed > 0 {
+            self.reload().await?;
+        }
+        Ok(removed > 0)
+    }
+
+    /// Periodically reload runtime entries so changes made on other instances are picked up
+    pub async fn start_refresh(&self) -> Result<(), AllowlistError> {
+        let interval = Duration::from_secs(self.config.refresh_interval_seconds.max(1));
+        loop {
+            if let Err(e) = self.reload().await {
+                log::error!("Failed to reload allowlist: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist_set() {
+        let config = AllowlistConfig {
+            networks: vec![
+                "10.0.0.0/8".to_string(),
+                "192.168.1.5".to_string(),
+                "2001:db8::/32".to_string(),
+            ],
+            api_keys: vec!["payments".to_string()],
+            ..AllowlistConfig::default()
+        };
+        let set = AllowlistSet::from_config(&config).unwrap();
+
+        assert!(set.contains_ip("10.1.2.3"));
+        assert!(set.contains_ip("192.168.1.5"));
+        assert!(!set.contains_ip("192.168.1.6"));
+        assert!(set.contains_ip("2001:db8::1"));
+        assert!(!set.contains_ip("not-an-ip"));
+        assert!(set.contains_api_key("payments"));
+        assert!(!set.contains_api_key("other"));
+        assert!(set.overlaps(&"192.168.0.0/16".parse().unwrap()));
+        assert!(!set.overlaps(&"192.168.2.0/24".parse().unwrap()));
+
+        // Host bits are dropped when normalizing
+        assert_eq!(parse_network("10.1.2.3/8").unwrap().to_string(), "10.0.0.0/8");
+        assert!(parse_network("10.0.0.0/33").is_err());
+    }
+}
@@ -2,13 +2,37 @@
 //! 
 //! This module provides analytics collection and reporting capabilities
 //! for monitoring service performance and detecting patterns.
+//!
+//! Events are kept in an event log in storage (a Redis stream), or in
+//! ClickHouse when `storage_type` is `clickhouse`, in which case the event
+//! log only buffers the latest events.
+//!
+//! Recording an event also bumps the analytics counters, in total and in
+//! per-minute buckets, in the same transaction. The background task adds
+//! the requests each instance served and turns the counters into the
+//! metrics returned by `get_metrics`.
+//!
+//! With an event buffer, events are queued and stored in batches instead,
+//! and request events are sampled under load. Sampled events add their
+//! sample rate to the counters, so counts stay close to the real ones.
 
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use crate::models::AnalyticsConfig;
-use redis::Client as RedisClient;
+use crate::core::redis_pool::{RedisConnection, RedisPool};
+use crate::core::clickhouse::{ClickHouse, ClickHouseError};
+use crate::core::enrichment::Enricher;
+use crate::core::event_buffer::EventBuffer;
+use crate::core::event_sink::EventSinks;
+use crate::core::watchdog::Heartbeat;
+use crate::storage::{RedisStorage, Storage};
+use crate::utils::normalize_ip;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use tokio::sync::RwLock;
@@ -22,6 +46,34 @@ pub enum AnalyticsError {
     SerializationError(String),
     #[error("Deserialization error: {0}")]
     DeserializationError(String),
+    #[error("ClickHouse error: {0}")]
+    ClickHouseError(String),
+}
+
+/// Channel every recorded event is published on, as JSON
+pub const EVENTS_CHANNEL: &str = "analytics:events:live";
+
+/// Event log recorded events are appended to, as JSON
+const EVENTS_LOG: &str = "analytics:events";
+
+/// Entries of the event log read at a time
+const LOG_READ_BATCH: usize = 1000;
+
+/// Hash of the events recorded, by type name
+const EVENT_COUNTS_KEY: &str = "analytics:events:counts";
+
+/// How often the metrics are refreshed
+const METRICS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often events older than the retention period are dropped
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most minutes of event counts returned at once
+const MAX_COUNT_MINUTES: u64 = 7 * 24 * 60;
+
+/// Hash of the events recorded in the minute starting at `minute`, by type name
+fn minute_key(minute: u64) -> String {
+    format!("analytics:events:minute:{}", minute)
 }
 
 /// Event types for analytics
@@ -32,12 +84,46 @@ pub enum EventType {
     DdosAttack,
     RuleTriggered,
     RateLimitExceeded,
+    QuotaExceeded,
+    /// A decision that was logged but not enforced because of shadow mode
+    ShadowDecision,
+    /// A rule was disabled because it reached its expiry time
+    RuleExpired,
     RateLimit,
     DdosDetection,
+    /// A client was blocked for making too many slow connections
+    SlowConnection,
+    /// A client passed or failed a challenge
+    Challenge,
+    /// A client requested a honeypot path
+    HoneypotHit,
+    /// A login attempt was locked out or challenged
+    LoginProtection,
+    /// Mitigations moved up or down the escalation ladder
+    Escalation,
     RuleEngine,
     System,
 }
 
+/// Events that count against the client they are about
+pub const SECURITY_EVENTS: [EventType; 8] = [
+    EventType::BlockedRequest,
+    EventType::DdosAttack,
+    EventType::DdosDetection,
+    EventType::RuleTriggered,
+    EventType::RateLimitExceeded,
+    EventType::SlowConnection,
+    EventType::HoneypotHit,
+    EventType::LoginProtection,
+];
+
+impl EventType {
+    /// Name of the event type, as serialized
+    pub fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
 /// Analytics event
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
@@ -48,16 +134,298 @@ pub struct Event {
     pub data: HashMap<String, serde_json::Value>,
 }
 
+impl Event {
+    /// Create a new event timestamped now
+    pub fn new(event_type: EventType, source: &str, data: HashMap<String, serde_json::Value>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: Utc::now(),
+            event_type,
+            source: source.to_string(),
+            data,
+        }
+    }
+}
+
+impl Event {
+    /// The client the event is about, if any
+    pub fn client(&self) -> Option<&str> {
+        self.data.get("ip").or_else(|| self.data.get("client")).and_then(|client| client.as_str())
+    }
+
+    /// The rule the event is about, if any
+    pub fn rule_name(&self) -> Option<&str> {
+        self.data.get("rule_name").and_then(|rule| rule.as_str())
+    }
+
+    /// A string field of the event data
+    fn field(&self, name: &str) -> Option<&str> {
+        self.data.get(name).and_then(|value| value.as_str())
+    }
+}
+
+/// Which events a query returns, besides their time range
+///
+/// Events match when they match every criterion set.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub event_type: Option<EventType>,
+    /// Network the event's client is in
+    pub network: Option<ipnet::IpNet>,
+    /// Start of the request path the event is about
+    pub path_prefix: Option<String>,
+    /// ID of the rule the event is about
+    pub rule_id: Option<String>,
+    /// Country of the event's client, as added by enrichment
+    pub country: Option<String>,
+    /// Tenant the event's request was attributed to
+    pub tenant: Option<String>,
+    /// Text in the event's source or data, ignoring case
+    pub text: Option<String>,
+}
+
+impl EventFilter {
+    /// Whether an event matches the filter
+    pub fn matches(&self, event: &Event) -> bool {
+        if self.event_type.as_ref().is_some_and(|event_type| event.event_type != *event_type) {
+            return false;
+        }
+        if let Some(network) = &self.network {
+            if !event.client().and_then(normalize_ip).is_some_and(|addr| network.contains(&addr)) {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.path_prefix {
+            if !event.field("path").is_some_and(|path| path.starts_with(prefix.as_str())) {
+                return false;
+            }
+        }
+        if let Some(rule_id) = &self.rule_id {
+            if event.field("rule_id") != Some(rule_id.as_str()) {
+                return false;
+            }
+        }
+        if let Some(country) = &self.country {
+            if !event.field("country").is_some_and(|code| code.eq_ignore_ascii_case(country)) {
+                return false;
+            }
+        }
+        if let Some(tenant) = &self.tenant {
+            if event.field("tenant") != Some(tenant.as_str()) {
+                return false;
+            }
+        }
+        if let Some(text) = &self.text {
+            let text = text.to_lowercase();
+            let data = serde_json::to_string(&event.data).unwrap_or_default();
+            if !event.source.to_lowercase().contains(&text) && !data.to_lowercase().contains(&text) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Where a page of events starts
+///
+/// Written as `offset:<n>` or `after:<entry ID>` for callers to pass back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventCursor {
+    /// After skipping this many matching events
+    Offset(usize),
+    /// Right after this entry of the event log
+    After(String),
+}
+
+impl fmt::Display for EventCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventCursor::Offset(offset) => write!(f, "offset:{}", offset),
+            EventCursor::After(id) => write!(f, "after:{}", id),
+        }
+    }
+}
+
+impl FromStr for EventCursor {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some(("offset", offset)) => offset.parse().map(EventCursor::Offset).map_err(|e| e.to_string()),
+            Some(("after", id)) if !id.is_empty() => Ok(EventCursor::After(id.to_string())),
+            _ => Err(format!("invalid cursor {}", value)),
+        }
+    }
+}
+
+/// A page of the events matching a query
+#[derive(Debug, Clone)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    /// Events matching the query, on any page
+    pub total: u64,
+    /// Where the next page starts, if there are more events
+    pub next_cursor: Option<EventCursor>,
+}
+
+/// Analytics counter an event adds to, read back by `collect_metrics`
+///
+/// Rule matches are counted by the rule engine, as rule events are only
+/// recorded once per cooldown.
+fn counter(event_type: &EventType) -> Option<&'static str> {
+    match event_type {
+        EventType::BlockedRequest => Some("blocked_requests"),
+        EventType::RateLimitExceeded => Some("rate_limited_requests"),
+        EventType::DdosAttack => Some("ddos_attacks"),
+        _ => None,
+    }
+}
+
+/// How many events an event stands for, more than one when it was sampled
+fn weight(event: &Event) -> u64 {
+    event.data.get("sample_rate").and_then(|rate| rate.as_u64()).unwrap_or(1)
+}
+
+/// Sort counts by count, then name, and keep the first `limit`
+fn ranked(counts: HashMap<String, u64>, limit: usize) -> Vec<(String, u64)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(limit);
+    counts
+}
+
+/// Event counts of a period
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventSummary {
+    /// Events recorded, by type name
+    pub events_by_type: BTreeMap<String, u64>,
+    /// Clients behind the most security events, with their event counts
+    pub top_clients: Vec<(String, u64)>,
+    /// Matches by rule name, most matched first
+    pub rule_matches: Vec<(String, u64)>,
+}
+
+impl EventSummary {
+    /// Count the events recorded from `start` until before `end`, keeping the `top_clients` first clients
+    pub fn from_events(start: DateTime<Utc>, end: DateTime<Utc>, events: &[Event], top_clients: usize) -> Self {
+        let mut events_by_type = BTreeMap::new();
+        let mut clients = HashMap::new();
+        let mut rules = HashMap::new();
+        for event in events.iter().filter(|event| event.timestamp >= start && event.timestamp < end) {
+            *events_by_type.entry(event.event_type.name()).or_insert(0) += 1;
+            if !SECURITY_EVENTS.contains(&event.event_type) {
+                continue;
+            }
+            if let Some(client) = event.client() {
+                *clients.entry(client.to_string()).or_insert(0) += 1;
+            }
+            if event.event_type == EventType::RuleTriggered {
+                if let Some(rule) = event.rule_name() {
+                    *rules.entry(rule.to_string()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        Self {
+            events_by_type,
+            top_clients: ranked(clients, top_clients),
+            rule_matches: ranked(rules, usize::MAX),
+        }
+    }
+}
+
 /// Analytics metrics
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct Metrics {
     pub total_requests: u64,
     pub blocked_requests: u64,
     pub rate_limited_requests: u64,
     pub ddos_attacks_detected: u64,
     pub rules_triggered: u64,
+    /// In milliseconds
     pub average_response_time: f64,
+    /// Share of requests answered with a server error
     pub error_rate: f64,
+    /// Events recorded, by type name
+    #[serde(default)]
+    pub events_by_type: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    /// Metrics from the analytics counters, by name, and the event counts
+    fn from_counters(counters: &HashMap<&str, u64>, events_by_type: BTreeMap<String, u64>) -> Self {
+        let counter = |name: &str| counters.get(name).copied().unwrap_or(0);
+        let total_requests = counter("total_requests");
+        let (average_response_time, error_rate) = match total_requests {
+            0 => (0.0, 0.0),
+            requests => (
+                counter("response_time_us") as f64 / requests as f64 / 1000.0,
+                counter("errors") as f64 / requests as f64,
+            ),
+        };
+        Self {
+            total_requests,
+            blocked_requests: counter("blocked_requests"),
+            rate_limited_requests: counter("rate_limited_requests"),
+            ddos_attacks_detected: counter("ddos_attacks"),
+            rules_triggered: counter("rules_triggered"),
+            average_response_time,
+            error_rate,
+            events_by_type,
+        }
+    }
+}
+
+/// Events recorded in a minute
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MinuteCounts {
+    /// Start of the minute, as a Unix timestamp
+    pub minute: u64,
+    /// Events recorded, by type name
+    pub counts: BTreeMap<String, u64>,
+}
+
+/// Requests served by this instance, counted as responses are sent
+///
+/// Cheap to clone; all clones share the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct RequestCounters {
+    requests: Arc<AtomicU64>,
+    errors: Arc<AtomicU64>,
+    response_time_us: Arc<AtomicU64>,
+}
+
+/// Totals of a set of request counters
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RequestTotals {
+    pub requests: u64,
+    /// Requests answered with a server error
+    pub errors: u64,
+    /// Time spent on all requests, in microseconds
+    pub response_time_us: u64,
+}
+
+impl RequestCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a response
+    pub fn record(&self, status: u16, response_time: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if status >= 500 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.response_time_us.fetch_add(response_time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> RequestTotals {
+        RequestTotals {
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            response_time_us: self.response_time_us.load(Ordering::Relaxed),
+        }
+    }
 }
 
 impl From<redis::RedisError> for AnalyticsError {
@@ -66,6 +434,12 @@ impl From<redis::RedisError> for AnalyticsError {
     }
 }
 
+impl From<ClickHouseError> for AnalyticsError {
+    fn from(err: ClickHouseError) -> Self {
+        AnalyticsError::ClickHouseError(err.to_string())
+    }
+}
+
 impl redis::FromRedisValue for Event {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         let str_value: String = redis::FromRedisValue::from_redis_value(v)?;
@@ -92,31 +466,88 @@ impl redis::FromRedisValue for Metrics {
 
 /// Analytics service
 pub struct Analytics {
-    redis_client: RedisClient,
+    redis_client: RedisPool,
+    /// Where recorded events are kept
+    storage: Arc<dyn Storage>,
     config: AnalyticsConfig,
     events: RwLock<Vec<Event>>,
     metrics: RwLock<Metrics>,
     retention_period: Duration,
+    sinks: EventSinks,
+    /// Adds client details to events before they are stored
+    enricher: Option<Enricher>,
+    /// Where events wait to be stored in batches
+    buffer: Option<EventBuffer>,
+    /// Where events are queried when they are stored in ClickHouse
+    clickhouse: Option<ClickHouse>,
+    /// Requests served by this instance
+    requests: Option<RequestCounters>,
+    /// Request totals already added to the analytics counters
+    flushed_requests: std::sync::Mutex<RequestTotals>,
 }
 
 impl Analytics {
     /// Create a new analytics instance
-    pub fn new(redis_client: RedisClient, config: AnalyticsConfig, retention_period: Duration) -> Self {
+    pub fn new(redis_client: RedisPool, config: AnalyticsConfig, retention_period: Duration) -> Self {
         Self {
+            storage: Arc::new(RedisStorage::new(redis_client.clone())),
             redis_client,
             config,
             events: RwLock::new(Vec::new()),
             metrics: RwLock::new(Metrics::default()),
             retention_period,
+            sinks: EventSinks::default(),
+            enricher: None,
+            buffer: None,
+            clickhouse: None,
+            requests: None,
+            flushed_requests: std::sync::Mutex::new(RequestTotals::default()),
         }
     }
 
+    /// Keep recorded events in another storage backend than Redis
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Forward recorded events to the given sinks
+    pub fn with_sinks(mut self, sinks: EventSinks) -> Self {
+        self.sinks = sinks;
+        self
+    }
+
+    /// Enrich events with details of their client before they are stored and forwarded
+    pub fn with_enricher(mut self, enricher: Enricher) -> Self {
+        self.enricher = Some(enricher);
+        self
+    }
+
+    /// Queue events in the given buffer and store them in batches
+    pub fn with_buffer(mut self, buffer: EventBuffer) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// Query events in ClickHouse, which a ClickHouse sink among the event
+    /// sinks writes them to, keeping only the latest events in the event log
+    pub fn with_clickhouse(mut self, clickhouse: ClickHouse) -> Self {
+        self.clickhouse = Some(clickhouse);
+        self
+    }
+
+    /// Add the requests counted by the given counters to the request metrics
+    pub fn with_request_counters(mut self, requests: RequestCounters) -> Self {
+        self.requests = Some(requests);
+        self
+    }
+
     /// Start analytics collection
-    pub async fn start_collection(&self) -> Result<()> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(anyhow::anyhow!("Redis connection error: {}", e)),
-        };
+    ///
+    /// Refreshes the metrics periodically, and drops events older than the
+    /// retention period.
+    pub async fn start_collection(&self, heartbeat: Heartbeat) -> Result<()> {
+        let mut conn = self.redis_client.get();
 
         // Initialize metrics in Redis if they don't exist
         let _: () = redis::cmd("SETNX")
@@ -125,36 +556,156 @@ impl Analytics {
             .query_async::<_, ()>(&mut conn)
             .await?;
 
-        Ok(())
+        let mut interval = tokio::time::interval(METRICS_INTERVAL);
+        let mut last_cleanup: Option<Instant> = None;
+
+        loop {
+            interval.tick().await;
+            heartbeat.beat().await;
+            if let Err(e) = self.collect_metrics().await {
+                log::error!("Failed to collect analytics metrics: {}", e);
+            }
+            if last_cleanup.is_none_or(|at| at.elapsed() >= CLEANUP_INTERVAL) {
+                if let Err(e) = self.cleanup_old_data().await {
+                    log::error!("Failed to clean up analytics events: {}", e);
+                }
+                last_cleanup = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Store queued events periodically until the task is cancelled
+    pub async fn start_flushing(&self, heartbeat: Heartbeat) -> Result<()> {
+        let mut interval = tokio::time::interval(Duration::from_millis(self.config.buffer.flush_interval_ms.max(1)));
+        loop {
+            interval.tick().await;
+            heartbeat.beat().await;
+            if let Err(e) = self.flush_events().await {
+                log::error!("Failed to store analytics events: {}", e);
+            }
+        }
     }
 
     /// Record an event
+    ///
+    /// With an event buffer, the event is queued, and a batch is stored
+    /// when one is full; the event may also be sampled out or dropped.
     pub async fn record_event(&self, event: Event) -> Result<()> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(anyhow::anyhow!("Redis connection error: {}", e)),
+        match &self.buffer {
+            Some(buffer) => {
+                if buffer.push(event) {
+                    self.store_events(buffer.take()).await?;
+                }
+                Ok(())
+            }
+            None => self.store_events(vec![event]).await,
+        }
+    }
+
+    /// Store all the events queued in the event buffer
+    pub async fn flush_events(&self) -> Result<()> {
+        let Some(buffer) = &self.buffer else {
+            return Ok(());
         };
+        loop {
+            let events = buffer.take();
+            if events.is_empty() {
+                return Ok(());
+            }
+            self.store_events(events).await?;
+        }
+    }
 
-        let event_json = match serde_json::to_string(&event) {
-            Ok(json) => json,
-            Err(e) => return Err(anyhow::anyhow!("Event serialization error: {}", e)),
+    /// Enrich, forward and store events, and add them to the counters
+    async fn store_events(&self, mut events: Vec<Event>) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        if let Some(enricher) = &self.enricher {
+            for event in &mut events {
+                enricher.enrich(event).await;
+            }
+        }
+        let mut conn = self.redis_client.get();
+
+        let mut entries = Vec::with_capacity(events.len());
+        for event in &events {
+            match serde_json::to_string(event) {
+                Ok(json) => entries.push(json),
+                Err(e) => return Err(anyhow::anyhow!("Event serialization error: {}", e)),
+            }
+            self.sinks.publish(event);
+        }
+
+        let max_len = match &self.clickhouse {
+            Some(clickhouse) => clickhouse.buffer_size(),
+            None => self.config.max_events,
         };
+        self.storage.append_all(EVENTS_LOG, &entries, max_len).await?;
 
-        let _: () = redis::cmd("RPUSH")
-            .arg("analytics:events")
-            .arg(event_json)
-            .query_async::<_, ()>(&mut conn)
-            .await?;
+        // Counters are bumped together, so the metrics never see half a batch
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (event, event_json) in events.iter().zip(&entries) {
+            let event_type = event.event_type.name();
+            let weight = weight(event);
+            let minute_key = minute_key(event.timestamp.timestamp().max(0) as u64 / 60 * 60);
+            if let Some(counter) = counter(&event.event_type) {
+                pipe.cmd("INCRBY").arg(format!("analytics:{}", counter)).arg(weight).ignore();
+            }
+            pipe.cmd("HINCRBY")
+                .arg(EVENT_COUNTS_KEY)
+                .arg(&event_type)
+                .arg(weight)
+                .ignore()
+                .cmd("HINCRBY")
+                .arg(&minute_key)
+                .arg(&event_type)
+                .arg(weight)
+                .ignore()
+                .cmd("EXPIRE")
+                .arg(&minute_key)
+                .arg(self.retention_period.as_secs().max(60))
+                .ignore()
+                // Subscribers of the live channel stream events as they are stored
+                .cmd("PUBLISH")
+                .arg(EVENTS_CHANNEL)
+                .arg(event_json)
+                .ignore();
+        }
+        let _: () = pipe.query_async(&mut conn).await?;
 
         Ok(())
     }
 
+    /// Events recorded per minute within a time range, by type, oldest first
+    ///
+    /// Minutes without events are left out, and at most a week is returned,
+    /// counting back from `end_time`.
+    pub async fn event_counts(&self, start_time: u64, end_time: u64) -> Result<Vec<MinuteCounts>, AnalyticsError> {
+        let last = end_time / 60 * 60;
+        let first = (start_time.div_ceil(60) * 60).max(last.saturating_sub((MAX_COUNT_MINUTES - 1) * 60));
+        let minutes: Vec<u64> = (first..=last).step_by(60).collect();
+        if minutes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for minute in &minutes {
+            pipe.cmd("HGETALL").arg(minute_key(*minute));
+        }
+        let counts: Vec<BTreeMap<String, u64>> = pipe.query_async(&mut self.redis_client.get()).await?;
+        Ok(minutes
+            .into_iter()
+            .zip(counts)
+            .filter(|(_, counts)| !counts.is_empty())
+            .map(|(minute, counts)| MinuteCounts { minute, counts })
+            .collect())
+    }
+
     /// Get analytics metrics
     pub async fn get_metrics(&self) -> Result<Metrics, AnalyticsError> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(AnalyticsError::RedisError(e.to_string())),
-        };
+        let mut conn = self.redis_client.get();
 
         let metrics: redis::RedisResult<Option<String>> = redis::cmd("GET")
             .arg("analytics:metrics")
@@ -174,81 +725,201 @@ impl Analytics {
     }
 
     /// Get events within a time range
+    ///
+    /// With ClickHouse, events still queued for insertion are added from the
+    /// event log.
     pub async fn get_events(&self, start_time: u64, end_time: u64, event_type: Option<EventType>) -> Result<Vec<Event>, AnalyticsError> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(AnalyticsError::RedisError(e.to_string())),
+        let filter = EventFilter {
+            event_type,
+            ..EventFilter::default()
         };
+        self.find_events(start_time, end_time, &filter, usize::MAX).await
+    }
 
-        let events: redis::RedisResult<Vec<String>> = redis::cmd("LRANGE")
-            .arg("analytics:events")
-            .arg(0)
-            .arg(-1)
-            .query_async::<_, Vec<String>>(&mut conn)
-            .await;
+    /// A page of the events within a time range that match a filter, oldest first
+    ///
+    /// With ClickHouse, the filter and count are applied there, and pages are
+    /// found by offset. Otherwise the event log is read in batches from the
+    /// cursor, and the next page starts after the last entry on this one.
+    pub async fn query_events(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        filter: &EventFilter,
+        cursor: &EventCursor,
+        limit: usize,
+    ) -> Result<EventPage, AnalyticsError> {
+        let total = self.count_events(start_time, end_time, filter).await?;
+        let (skip, after) = match (cursor, &self.clickhouse) {
+            (EventCursor::Offset(offset), Some(_)) => {
+                // One more event than asked for tells whether there is another page
+                let wanted = offset.saturating_add(limit).saturating_add(1);
+                let events = self.find_events(start_time, end_time, filter, wanted).await?;
+                let more = events.len() == wanted;
+                return Ok(EventPage {
+                    events: events.into_iter().skip(*offset).take(limit).collect(),
+                    total,
+                    next_cursor: more.then(|| EventCursor::Offset(offset + limit)),
+                });
+            }
+            (EventCursor::Offset(offset), None) => (*offset, None),
+            (EventCursor::After(id), _) => (0, Some(id.clone())),
+        };
 
-        match events {
-            Ok(json_strs) => {
-                let mut filtered_events = Vec::new();
-                for json_str in json_strs {
-                    match serde_json::from_str::<Event>(&json_str) {
-                        Ok(event) => {
-                            let timestamp = event.timestamp.timestamp() as u64;
-                            if timestamp >= start_time && timestamp <= end_time {
-                                if let Some(ref expected_type) = event_type {
-                                    if event.event_type == *expected_type {
-                                        filtered_events.push(event);
-                                    }
-                                } else {
-                                    filtered_events.push(event);
-                                }
-                            }
-                        },
-                        Err(e) => log::error!("Failed to parse event: {}", e),
-                    }
-                }
-                Ok(filtered_events)
-            },
-            Err(e) => Err(AnalyticsError::RedisError(e.to_string())),
+        let mut events = Vec::new();
+        let mut skipped = 0;
+        let mut last = None;
+        let mut more = false;
+        self.scan_log(start_time, end_time, after, |id, event| {
+            if !filter.matches(&event) {
+                return true;
+            }
+            if skipped < skip {
+                skipped += 1;
+                return true;
+            }
+            if events.len() >= limit {
+                more = true;
+                return false;
+            }
+            events.push(event);
+            last = Some(id.to_string());
+            true
+        })
+        .await?;
+        Ok(EventPage {
+            events,
+            total,
+            next_cursor: last.filter(|_| more).map(EventCursor::After),
+        })
+    }
+
+    /// Count the events within a time range that match a filter
+    ///
+    /// With ClickHouse, the counting is done there, and events still queued
+    /// for insertion aren't counted.
+    pub async fn count_events(&self, start_time: u64, end_time: u64, filter: &EventFilter) -> Result<u64, AnalyticsError> {
+        if let Some(clickhouse) = &self.clickhouse {
+            return Ok(clickhouse.count_events(start_time, end_time, filter).await?);
         }
+        let mut total = 0;
+        self.scan_log(start_time, end_time, None, |_, event| {
+            if filter.matches(&event) {
+                total += 1;
+            }
+            true
+        })
+        .await?;
+        Ok(total)
     }
 
-    /// Collect metrics from events
-    pub async fn collect_metrics(&self) -> Result<()> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(anyhow::anyhow!("Redis connection error: {}", e)),
+    /// The first `limit` events within a time range that match a filter, oldest first
+    async fn find_events(&self, start_time: u64, end_time: u64, filter: &EventFilter, limit: usize) -> Result<Vec<Event>, AnalyticsError> {
+        let Some(clickhouse) = &self.clickhouse else {
+            return self.logged_events(start_time, end_time, filter, limit).await;
         };
 
-        let total_requests = match self.get_metric_value(&mut conn, "total_requests").await {
-            Ok(value) => value,
-            Err(e) => return Err(anyhow::anyhow!("Failed to get total_requests: {}", e)),
-        };
+        let mut events = clickhouse.events(start_time, end_time, filter, limit).await?;
+        let inserted: HashSet<String> = events.iter().map(|event| event.id.clone()).collect();
+        let buffered = self.logged_events(start_time, end_time, filter, limit).await?;
+        events.extend(buffered.into_iter().filter(|event| !inserted.contains(&event.id)));
+        events.sort_by_key(|event| event.timestamp);
+        events.truncate(limit);
+        Ok(events)
+    }
 
-        let blocked_requests = match self.get_metric_value(&mut conn, "blocked_requests").await {
-            Ok(value) => value,
-            Err(e) => return Err(anyhow::anyhow!("Failed to get blocked_requests: {}", e)),
-        };
+    /// Count the events recorded from `start` until before `end`
+    ///
+    /// With ClickHouse, the counting is done there, and events still queued
+    /// for insertion aren't counted.
+    pub async fn summarize(&self, start: DateTime<Utc>, end: DateTime<Utc>, top_clients: usize) -> Result<EventSummary, AnalyticsError> {
+        if let Some(clickhouse) = &self.clickhouse {
+            return Ok(clickhouse.summarize(start, end, top_clients).await?);
+        }
+        let events = self
+            .logged_events(start.timestamp() as u64, end.timestamp() as u64, &EventFilter::default(), usize::MAX)
+            .await?;
+        Ok(EventSummary::from_events(start, end, &events, top_clients))
+    }
 
-        let ddos_attacks_detected = match self.get_metric_value(&mut conn, "ddos_attacks").await {
-            Ok(value) => value,
-            Err(e) => return Err(anyhow::anyhow!("Failed to get ddos_attacks: {}", e)),
-        };
+    /// Events of the event log within a time range
+    ///
+    /// The range is read by the time events were appended, which is when
+    /// they were recorded.
+    async fn logged_events(&self, start_time: u64, end_time: u64, filter: &EventFilter, limit: usize) -> Result<Vec<Event>, AnalyticsError> {
+        let mut events = Vec::new();
+        self.scan_log(start_time, end_time, None, |_, event| {
+            if filter.matches(&event) {
+                events.push(event);
+            }
+            events.len() < limit
+        })
+        .await?;
+        Ok(events)
+    }
 
-        let average_response_time = match self.get_metric_value(&mut conn, "avg_response_time").await {
-            Ok(value) => value as f64,
-            Err(e) => return Err(anyhow::anyhow!("Failed to get avg_response_time: {}", e)),
-        };
+    /// Read the event log within a time range in batches, following the entry `after` if given
+    ///
+    /// Each event is passed to `visit` with its entry ID, until `visit` returns false.
+    async fn scan_log(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        mut after: Option<String>,
+        mut visit: impl FnMut(&str, Event) -> bool,
+    ) -> Result<(), AnalyticsError> {
+        let start_ms = start_time.saturating_mul(1000);
+        let end_ms = end_time.saturating_mul(1000).saturating_add(999);
+        loop {
+            let entries = self
+                .storage
+                .range(EVENTS_LOG, start_ms, end_ms, after.as_deref(), LOG_READ_BATCH)
+                .await
+                .map_err(|e| AnalyticsError::RedisError(e.to_string()))?;
+            let last_batch = entries.len() < LOG_READ_BATCH;
+            for entry in entries {
+                match serde_json::from_str::<Event>(&entry.entry) {
+                    Ok(event) => {
+                        if !visit(&entry.id, event) {
+                            return Ok(());
+                        }
+                    }
+                    Err(e) => log::error!("Failed to parse event: {}", e),
+                }
+                after = Some(entry.id);
+            }
+            if last_batch {
+                return Ok(());
+            }
+        }
+    }
 
-        let metrics = Metrics {
-            total_requests,
-            blocked_requests,
-            rate_limited_requests: 0, // TODO: Implement this
-            ddos_attacks_detected,
-            rules_triggered: 0, // TODO: Implement this
-            average_response_time,
-            error_rate: 0.0, // TODO: Implement this
-        };
+    /// Collect metrics from events
+    pub async fn collect_metrics(&self) -> Result<()> {
+        let mut conn = self.redis_client.get();
+        self.flush_requests(&mut conn).await?;
+
+        let mut counters = HashMap::new();
+        for name in [
+            "total_requests",
+            "errors",
+            "response_time_us",
+            "blocked_requests",
+            "rate_limited_requests",
+            "ddos_attacks",
+            "rules_triggered",
+        ] {
+            match self.get_metric_value(&mut conn, name).await {
+                Ok(value) => counters.insert(name, value),
+                Err(e) => return Err(anyhow::anyhow!("Failed to get {}: {}", name, e)),
+            };
+        }
+        let events_by_type: BTreeMap<String, u64> = redis::cmd("HGETALL")
+            .arg(EVENT_COUNTS_KEY)
+            .query_async(&mut conn)
+            .await?;
+
+        let metrics = Metrics::from_counters(&counters, events_by_type);
 
         let metrics_json = match serde_json::to_string(&metrics) {
             Ok(json) => json,
@@ -267,8 +938,36 @@ impl Analytics {
         Ok(())
     }
 
+    /// Add the requests this instance served since the last flush to the request counters
+    async fn flush_requests(&self, conn: &mut RedisConnection) -> Result<()> {
+        let Some(requests) = &self.requests else {
+            return Ok(());
+        };
+        let totals = requests.totals();
+        let flushed = *self.flushed_requests.lock().unwrap();
+
+        let _: () = redis::pipe()
+            .atomic()
+            .cmd("INCRBY")
+            .arg("analytics:total_requests")
+            .arg(totals.requests - flushed.requests)
+            .ignore()
+            .cmd("INCRBY")
+            .arg("analytics:errors")
+            .arg(totals.errors - flushed.errors)
+            .ignore()
+            .cmd("INCRBY")
+            .arg("analytics:response_time_us")
+            .arg(totals.response_time_us - flushed.response_time_us)
+            .ignore()
+            .query_async(conn)
+            .await?;
+        *self.flushed_requests.lock().unwrap() = totals;
+        Ok(())
+    }
+
     /// Helper function to get a metric value from Redis
-    async fn get_metric_value(&self, conn: &mut redis::aio::Connection, key: &str) -> Result<u64> {
+    async fn get_metric_value(&self, conn: &mut RedisConnection, key: &str) -> Result<u64> {
         let value: Option<String> = match redis::cmd("GET")
             .arg(format!("analytics:{}", key))
             .query_async(conn)
@@ -288,40 +987,13 @@ impl Analytics {
 
     /// Clean up old data based on retention policy
     pub async fn cleanup_old_data(&self) -> Result<()> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(anyhow::anyhow!("Redis connection error: {}", e)),
-        };
-
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_secs();
+            .as_millis() as u64;
 
-        let cutoff = now - self.retention_period.as_secs();
-        let cutoff_dt = DateTime::<Utc>::from_timestamp(cutoff as i64, 0)
-            .ok_or_else(|| anyhow::anyhow!("Invalid timestamp"))?;
-
-        let events: Vec<Event> = redis::cmd("LRANGE")
-            .arg("analytics:events")
-            .arg(0)
-            .arg(-1)
-            .query_async::<_, Vec<Event>>(&mut conn)
-            .await?;
-
-        for event in events {
-            if event.timestamp < cutoff_dt {
-                let _: () = match redis::cmd("LREM")
-                    .arg("analytics:events")
-                    .arg(1)
-                    .arg(serde_json::to_string(&event)?)
-                    .query_async::<_, ()>(&mut conn)
-                    .await {
-                        Ok(_) => (),
-                        Err(e) => return Err(anyhow::anyhow!("Redis query error: {}", e)),
-                    };
-            }
-        }
+        let cutoff = now.saturating_sub(self.retention_period.as_millis() as u64);
+        self.storage.trim_before(EVENTS_LOG, cutoff).await?;
 
         Ok(())
     }
@@ -337,4 +1009,67 @@ mod tests {
         // This is a placeholder test
         // In a real implementation, we would use a test Redis instance
     }
+
+    #[test]
+    fn test_metrics_from_counters() {
+        let counters = HashMap::from([
+            ("total_requests", 200),
+            ("errors", 5),
+            ("response_time_us", 3_000_000),
+            ("rate_limited_requests", 12),
+        ]);
+        let events_by_type = BTreeMap::from([("RateLimitExceeded".to_string(), 12)]);
+        let metrics = Metrics::from_counters(&counters, events_by_type.clone());
+        assert_eq!(metrics.total_requests, 200);
+        assert_eq!(metrics.rate_limited_requests, 12);
+        assert_eq!(metrics.average_response_time, 15.0);
+        assert_eq!(metrics.error_rate, 0.025);
+        assert_eq!(metrics.events_by_type, events_by_type);
+
+        assert_eq!(Metrics::from_counters(&HashMap::new(), BTreeMap::new()), Metrics::default());
+        assert_eq!(counter(&EventType::RuleTriggered), None);
+    }
+
+    #[test]
+    fn test_event_filter() {
+        let data = HashMap::from([
+            ("ip".to_string(), serde_json::json!("192.0.2.10")),
+            ("path".to_string(), serde_json::json!("/api/login")),
+            ("rule_id".to_string(), serde_json::json!("rule-1")),
+            ("country".to_string(), serde_json::json!("DE")),
+        ]);
+        let event = Event::new(EventType::RuleTriggered, "rule_engine", data);
+        assert!(EventFilter::default().matches(&event));
+
+        let filter = EventFilter {
+            event_type: Some(EventType::RuleTriggered),
+            network: "192.0.2.0/24".parse().ok(),
+            path_prefix: Some("/api/".to_string()),
+            rule_id: Some("rule-1".to_string()),
+            country: Some("de".to_string()),
+            tenant: None,
+            text: Some("LOGIN".to_string()),
+        };
+        assert!(filter.matches(&event));
+
+        for filter in [
+            EventFilter { network: "198.51.100.0/24".parse().ok(), ..filter.clone() },
+            EventFilter { path_prefix: Some("/admin".to_string()), ..filter.clone() },
+            EventFilter { rule_id: Some("rule-2".to_string()), ..filter.clone() },
+            EventFilter { tenant: Some("shop".to_string()), ..filter.clone() },
+            EventFilter { text: Some("logout".to_string()), ..filter.clone() },
+            EventFilter { event_type: Some(EventType::BlockedRequest), ..filter.clone() },
+        ] {
+            assert!(!filter.matches(&event));
+        }
+    }
+
+    #[test]
+    fn test_event_cursor() {
+        for cursor in [EventCursor::Offset(40), EventCursor::After("1700000000000-3".to_string())] {
+            assert_eq!(cursor.to_string().parse::<EventCursor>(), Ok(cursor));
+        }
+        assert!("after:".parse::<EventCursor>().is_err());
+        assert!("page:2".parse::<EventCursor>().is_err());
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,169 @@
+//! Attack mode for the DDoS protection service.
+//!
+//! This module keeps the attack mode switch in Redis so that every instance
+//! flips together. The switch is stored with a TTL, so attack mode ends on
+//! its own even if nobody switches it off. Instances cache the switch for a
+//! second to keep it off the request path.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::core::redis_pool::RedisPool;
+use crate::models::AttackModeConfig;
+
+/// Redis key holding the attack mode state
+const ATTACK_MODE_KEY: &str = "protection:attack_mode";
+
+/// How long instances use the cached state before checking Redis again
+const CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// Attack mode state read from Redis, with when it was read
+type CachedState = Option<(Instant, Option<AttackModeState>)>;
+
+/// Errors that can occur while switching attack mode
+#[derive(Error, Debug)]
+pub enum AttackModeError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+}
+
+/// Attack mode while it is on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttackModeState {
+    /// When attack mode was switched on
+    pub enabled_at: DateTime<Utc>,
+    /// When attack mode switches itself off
+    pub expires_at: DateTime<Utc>,
+    /// Why attack mode was switched on
+    pub reason: String,
+}
+
+/// Attack mode switch shared by every instance
+///
+/// Cloning is cheap and all clones share the cache.
+#[derive(Clone)]
+pub struct AttackMode {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Attack mode configuration
+    config: AttackModeConfig,
+    /// Last state read from Redis
+    cache: Arc<Mutex<CachedState>>,
+}
+
+impl AttackMode {
+    /// Create a new attack mode switch
+    pub fn new(redis: RedisPool, config: AttackModeConfig) -> Self {
+        Self {
+            redis,
+            config,
+            cache: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Attack mode configuration
+    pub fn config(&self) -> &AttackModeConfig {
+        &self.config
+    }
+
+    /// Current state, read from Redis
+    pub async fn status(&self) -> Result<Option<AttackModeState>, AttackModeError> {
+        let mut conn = self.redis.get();
+        let json: Option<String> = redis::cmd("GET").arg(ATTACK_MODE_KEY).query_async(&mut conn).await?;
+        let state = json.map(|json| serde_json::from_str(&json)).transpose()?;
+        self.cache_state(state.clone());
+        Ok(state)
+    }
+
+    /// Whether attack mode is on, from a state at most a second old
+    ///
+    /// Attack mode is considered off when Redis can't be reached.
+    pub async fn is_active(&self) -> bool {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .filter(|(read_at, _)| read_at.elapsed() < CACHE_TTL)
+            .map(|(_, state)| state.is_some());
+        if let Some(active) = cached {
+            return active;
+        }
+        match self.status().await {
+            Ok(state) => state.is_some(),
+            Err(e) => {
+                log::error!("Failed to read attack mode: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Switch attack mode on for the given duration (default and maximum from the configuration)
+    pub async fn enable(&self, duration_seconds: Option<u64>, reason: &str) -> Result<AttackModeState, AttackModeError> {
+        let duration = duration_seconds
+            .unwrap_or(self.config.default_duration_seconds)
+            .clamp(1, self.config.max_duration_seconds.max(1));
+        let now = Utc::now();
+        let state = AttackModeState {
+            enabled_at: now,
+            expires_at: now + chrono::Duration::seconds(duration as i64),
+            reason: reason.to_string(),
+        };
+
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("SET")
+            .arg(ATTACK_MODE_KEY)
+            .arg(serde_json::to_string(&state)?)
+            .arg("EX")
+            .arg(duration)
+            .query_async(&mut conn)
+            .await?;
+        self.cache_state(Some(state.clone()));
+        metrics::increment_counter!("attack_mode_switches_total", "enabled" => "true");
+        log::warn!("Attack mode on for {}s: {}", duration, reason);
+        Ok(state)
+    }
+
+    /// Switch attack mode off, returning whether it was on
+    pub async fn disable(&self) -> Result<bool, AttackModeError> {
+        let mut conn = self.redis.get();
+        let removed: u64 = redis::cmd("DEL").arg(ATTACK_MODE_KEY).query_async(&mut conn).await?;
+        self.cache_state(None);
+        metrics::increment_counter!("attack_mode_switches_total", "enabled" => "false");
+        log::warn!("Attack mode off");
+        Ok(removed > 0)
+    }
+
+    /// Scale a rate limit down to the attack mode fraction
+    pub fn scale_limit(&self, limit: u32) -> u32 {
+        scaled_limit(limit, self.config.limit_factor)
+    }
+
+    fn cache_state(&self, state: Option<AttackModeState>) {
+        *self.cache.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), state));
+    }
+}
+
+/// Limit scaled by a factor between 0 and 1, never below one request
+pub fn scaled_limit(limit: u32, factor: f64) -> u32 {
+    ((limit as f64 * factor.clamp(0.0, 1.0)).floor() as u32).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scaled_limit() {
+        assert_eq!(scaled_limit(100, 0.25), 25);
+        assert_eq!(scaled_limit(10, 0.25), 2);
+        assert_eq!(scaled_limit(2, 0.25), 1);
+        assert_eq!(scaled_limit(100, 0.0), 1);
+        // Attack mode never raises limits
+        assert_eq!(scaled_limit(100, 2.0), 100);
+    }
+}
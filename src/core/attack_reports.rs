@@ -0,0 +1,469 @@
+//! Post-attack reports for the DDoS protection service.
+//!
+//! When an attack ends, a report is built from the attack, the analytics
+//! events and the traffic aggregates of its duration: peak rates, the
+//! vectors seen at the same time, top sources and countries, the rules and
+//! mitigations that fired and a timeline. Reports are stored in Redis for
+//! as long as ended attacks are kept, and can be rendered as HTML or PDF.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::core::analytics::{Analytics, AnalyticsError};
+use crate::core::attacks::{Attack, AttackError, AttackStatus, AttackTracker};
+use crate::core::redis_pool::RedisPool;
+use crate::core::traffic::{TopDimension, TopEntry, TrafficMetric, TrafficStats};
+use crate::utils::html_escape;
+
+/// How often ended attacks are checked for a report
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Latest attacks checked for a report
+const RECENT_ATTACKS: usize = 50;
+
+/// Sources and countries listed in a report
+const TOP_SOURCES: usize = 10;
+
+/// Most points in a report's timeline
+const MAX_TIMELINE_POINTS: i64 = 240;
+
+/// How long an instance has to build a report it claimed (seconds)
+const CLAIM_TTL: u64 = 300;
+
+/// Lines on each PDF page, and characters on each line
+const PDF_PAGE_LINES: usize = 64;
+const PDF_LINE_WIDTH: usize = 100;
+
+/// Errors that can occur while building attack reports
+#[derive(Error, Debug)]
+pub enum AttackReportError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("Attack error: {0}")]
+    Attack(#[from] AttackError),
+    #[error("Analytics error: {0}")]
+    Analytics(#[from] AnalyticsError),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Requests of a timeline point
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimelinePoint {
+    /// Start of the point's interval, as a Unix timestamp
+    pub timestamp: i64,
+    pub requests: u64,
+    pub blocks: u64,
+    pub rate_limits: u64,
+}
+
+/// Report of an attack
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttackReport {
+    pub attack: Attack,
+    pub generated_at: DateTime<Utc>,
+    /// Seconds from the first detection until the vector went quiet, or until the report for active attacks
+    pub duration_seconds: i64,
+    /// Vectors of the attacks going on at the same time, including this one
+    pub vectors: Vec<String>,
+    /// Highest rates of all traffic over a timeline point, per second
+    pub peak_requests_per_second: u64,
+    pub peak_blocks_per_second: u64,
+    /// Events recorded during the attack, by type name
+    pub events_by_type: BTreeMap<String, u64>,
+    /// Clients with the most blocked and rate-limited requests
+    pub top_sources: Vec<TopEntry>,
+    /// Countries with the most requests
+    pub top_countries: Vec<TopEntry>,
+    /// Matches by rule name, most matched first
+    pub rule_matches: Vec<(String, u64)>,
+    /// Seconds each timeline point covers
+    pub timeline_step: i64,
+    pub timeline: Vec<TimelinePoint>,
+}
+
+/// Seconds each point of the timeline of an attack lasting `duration` seconds covers
+///
+/// Steps are whole minutes, or whole hours once they are over an hour, so
+/// that long timelines are read from the hour buckets.
+fn timeline_step(duration: i64) -> i64 {
+    let minutes = (duration.max(1) + 60 * MAX_TIMELINE_POINTS - 1) / (60 * MAX_TIMELINE_POINTS);
+    if minutes > 60 {
+        (minutes + 59) / 60 * 3600
+    } else {
+        minutes.max(1) * 60
+    }
+}
+
+fn format_duration(seconds: i64) -> String {
+    match seconds {
+        s if s >= 3600 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+impl AttackReport {
+    /// Sections of the report as titles and rows of cells
+    fn sections(&self) -> Vec<(&'static str, Vec<Vec<String>>)> {
+        let attack = &self.attack;
+        let ended = match attack.ended_at {
+            Some(ended_at) => ended_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            None => "ongoing".to_string(),
+        };
+        let entries = |entries: &[TopEntry]| -> Vec<Vec<String>> {
+            entries.iter().map(|entry| vec![entry.value.clone(), entry.requests.to_string()]).collect()
+        };
+        let counts = |counts: Vec<(&String, &u64)>| -> Vec<Vec<String>> {
+            counts.into_iter().map(|(name, count)| vec![name.clone(), count.to_string()]).collect()
+        };
+
+        vec![
+            (
+                "Summary",
+                vec![
+                    vec!["Vector".to_string(), attack.vector.clone()],
+                    vec!["Concurrent vectors".to_string(), self.vectors.join(", ")],
+                    vec!["Started".to_string(), attack.started_at.format("%Y-%m-%d %H:%M:%S UTC").to_string()],
+                    vec!["Ended".to_string(), ended],
+                    vec!["Duration".to_string(), format_duration(self.duration_seconds)],
+                    vec!["Detections".to_string(), attack.detections.to_string()],
+                    vec!["Peak detected rate".to_string(), format!("{} rps", attack.peak_rps)],
+                    vec!["Peak requests".to_string(), format!("{} rps", self.peak_requests_per_second)],
+                    vec!["Peak blocks".to_string(), format!("{} rps", self.peak_blocks_per_second)],
+                    vec!["Mitigations".to_string(), attack.mitigations.join(", ")],
+                    vec!["Targets".to_string(), attack.targets.join(", ")],
+                ],
+            ),
+            ("Top sources", entries(&self.top_sources)),
+            ("Top countries", entries(&self.top_countries)),
+            ("Rule matches", counts(self.rule_matches.iter().map(|(name, count)| (name, count)).collect())),
+            ("Events", counts(self.events_by_type.iter().collect())),
+            (
+                "Timeline (requests, blocks, rate limits)",
+                self.timeline
+                    .iter()
+                    .map(|point| {
+                        let time = DateTime::from_timestamp(point.timestamp, 0).unwrap_or_default();
+                        vec![
+                            time.format("%Y-%m-%d %H:%M").to_string(),
+                            point.requests.to_string(),
+                            point.blocks.to_string(),
+                            point.rate_limits.to_string(),
+                        ]
+                    })
+                    .collect(),
+            ),
+        ]
+    }
+
+    /// Plain-text rendering of the report
+    pub fn render(&self) -> String {
+        let mut text = format!("Attack report {}\n", self.attack.id);
+        for (title, rows) in self.sections() {
+            text.push_str(&format!("\n{}\n", title));
+            if rows.is_empty() {
+                text.push_str("  None\n");
+            }
+            for row in rows {
+                let (first, rest) = row.split_first().expect("rows have cells");
+                text.push_str(&format!("  {:<40}{}\n", first, rest.join("  ")));
+            }
+        }
+        text
+    }
+
+    /// HTML rendering of the report
+    pub fn render_html(&self) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Attack report {id}</title></head>\n<body>\n<h1>Attack report {id}</h1>\n",
+            id = html_escape(&self.attack.id)
+        );
+        for (title, rows) in self.sections() {
+            html.push_str(&format!("<h2>{}</h2>\n", html_escape(title)));
+            if rows.is_empty() {
+                html.push_str("<p>None</p>\n");
+                continue;
+            }
+            html.push_str("<table>\n");
+            for row in rows {
+                let cells: String = row.iter().map(|cell| format!("<td>{}</td>", html_escape(cell))).collect();
+                html.push_str(&format!("<tr>{}</tr>\n", cells));
+            }
+            html.push_str("</table>\n");
+        }
+        html.push_str("</body></html>\n");
+        html
+    }
+
+    /// PDF rendering of the report, as monospaced text
+    pub fn render_pdf(&self) -> Vec<u8> {
+        pdf(&self.render())
+    }
+}
+
+/// Text as a PDF of pages in Courier; characters outside ASCII are replaced
+fn pdf(text: &str) -> Vec<u8> {
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            line.chars()
+                .map(|c| if c.is_ascii() && !c.is_ascii_control() { c } else { '?' })
+                .take(PDF_LINE_WIDTH)
+                .collect()
+        })
+        .collect();
+    let mut pages: Vec<&[String]> = lines.chunks(PDF_PAGE_LINES).collect();
+    if pages.is_empty() {
+        pages.push(&[]);
+    }
+
+    // Objects 1 to 3 are the catalog, the page tree and the font
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        String::new(),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>".to_string(),
+    ];
+    let mut kids = Vec::new();
+    for page in pages {
+        let mut content = String::from("BT /F1 9 Tf 12 TL 40 806 Td\n");
+        for line in page {
+            let line = line.replace('\\', "\\\\").replace('(', "\\(").replace(')', "\\)");
+            content.push_str(&format!("({}) '\n", line));
+        }
+        content.push_str("ET");
+        objects.push(format!("<< /Length {} >>\nstream\n{}\nendstream", content.len(), content));
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 595 842] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            objects.len()
+        ));
+        kids.push(format!("{} 0 R", objects.len()));
+    }
+    objects[1] = format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), kids.len());
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::new();
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", index + 1, object).as_bytes());
+    }
+    let xref = pdf.len();
+    pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).as_bytes(),
+    );
+    pdf
+}
+
+fn report_key(id: &str) -> String {
+    format!("attack:{}:report", id)
+}
+
+/// Builds and stores attack reports
+///
+/// Cheap to clone; all clones use the same Redis.
+#[derive(Clone)]
+pub struct AttackReporter {
+    redis: RedisPool,
+    attacks: AttackTracker,
+    analytics: Arc<Analytics>,
+    traffic: TrafficStats,
+    /// How long reports are kept, as long as ended attacks
+    retention: Duration,
+}
+
+impl AttackReporter {
+    pub fn new(redis: RedisPool, attacks: AttackTracker, analytics: Arc<Analytics>, traffic: TrafficStats, retention: Duration) -> Self {
+        Self {
+            redis,
+            attacks,
+            analytics,
+            traffic,
+            retention,
+        }
+    }
+
+    /// Build the report of an attack from what was recorded during it
+    pub async fn build(&self, attack: Attack) -> Result<AttackReport, AttackReportError> {
+        let now = Utc::now();
+        let end = attack.ended_at.unwrap_or(now);
+        let (from, to) = (attack.started_at.timestamp(), end.timestamp());
+        let step = timeline_step(to - from);
+
+        let mut vectors: Vec<String> = self
+            .attacks
+            .list(false, RECENT_ATTACKS)
+            .await?
+            .into_iter()
+            .filter(|other| other.started_at <= end && other.ended_at.unwrap_or(now) >= attack.started_at)
+            .map(|other| other.vector)
+            .chain([attack.vector.clone()])
+            .collect();
+        vectors.sort();
+        vectors.dedup();
+
+        let summary = self.analytics.summarize(attack.started_at, end, TOP_SOURCES).await?;
+        let top_sources = self.traffic.top(TopDimension::Ips, from, to, TOP_SOURCES).await?;
+        let top_countries = self.traffic.top(TopDimension::Countries, from, to, TOP_SOURCES).await?;
+
+        let requests = self.traffic.series(TrafficMetric::Requests, from, to, step).await?;
+        let blocks = self.traffic.series(TrafficMetric::Blocks, from, to, step).await?;
+        let rate_limits = self.traffic.series(TrafficMetric::RateLimits, from, to, step).await?;
+        let timeline: Vec<TimelinePoint> = requests
+            .iter()
+            .zip(&blocks)
+            .zip(&rate_limits)
+            .map(|((requests, blocks), rate_limits)| TimelinePoint {
+                timestamp: requests.timestamp,
+                requests: requests.value,
+                blocks: blocks.value,
+                rate_limits: rate_limits.value,
+            })
+            .collect();
+        let peak = |value: fn(&TimelinePoint) -> u64| timeline.iter().map(value).max().unwrap_or(0) / step as u64;
+
+        Ok(AttackReport {
+            generated_at: now,
+            duration_seconds: to - from,
+            vectors,
+            peak_requests_per_second: peak(|point| point.requests),
+            peak_blocks_per_second: peak(|point| point.blocks),
+            events_by_type: summary.events_by_type,
+            top_sources,
+            top_countries,
+            rule_matches: summary.rule_matches,
+            timeline_step: step,
+            timeline,
+            attack,
+        })
+    }
+
+    /// Report of an attack
+    ///
+    /// Reports of ended attacks are built once and stored; reports of
+    /// attacks still going on are built on each request.
+    pub async fn get(&self, id: &str) -> Result<Option<AttackReport>, AttackReportError> {
+        let stored: Option<String> = redis::cmd("GET").arg(report_key(id)).query_async(&mut self.redis.get()).await?;
+        if let Some(stored) = stored {
+            return Ok(Some(serde_json::from_str(&stored)?));
+        }
+        let Some(attack) = self.attacks.get(id).await? else {
+            return Ok(None);
+        };
+        let report = self.build(attack).await?;
+        if report.attack.status == AttackStatus::Ended {
+            self.store(&report).await?;
+        }
+        Ok(Some(report))
+    }
+
+    async fn store(&self, report: &AttackReport) -> Result<(), AttackReportError> {
+        let _: () = redis::cmd("SET")
+            .arg(report_key(&report.attack.id))
+            .arg(serde_json::to_string(report)?)
+            .arg("EX")
+            .arg(self.retention.as_secs().max(1))
+            .query_async(&mut self.redis.get())
+            .await?;
+        Ok(())
+    }
+
+    /// Build and store the reports of attacks that ended since the last check
+    ///
+    /// Every instance checks; the first to claim an attack builds its report.
+    pub async fn report_ended(&self) -> Result<(), AttackReportError> {
+        for attack in self.attacks.list(false, RECENT_ATTACKS).await? {
+            if attack.status != AttackStatus::Ended {
+                continue;
+            }
+            let key = report_key(&attack.id);
+            let mut conn = self.redis.get();
+            let (stored, claimed): (bool, Option<String>) = redis::pipe()
+                .cmd("EXISTS").arg(&key)
+                .cmd("SET").arg(format!("{}:claim", key)).arg(1).arg("NX").arg("EX").arg(CLAIM_TTL)
+                .query_async(&mut conn)
+                .await?;
+            if stored || claimed.is_none() {
+                continue;
+            }
+            let id = attack.id.clone();
+            self.store(&self.build(attack).await?).await?;
+            log::info!("Built report of attack {}", id);
+        }
+        Ok(())
+    }
+
+    /// Build reports of ended attacks periodically until the task is cancelled
+    pub async fn start(&self) {
+        loop {
+            if let Err(e) = self.report_ended().await {
+                log::error!("Failed to build attack reports: {}", e);
+            }
+            tokio::time::sleep(REPORT_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attack_report() {
+        assert_eq!(timeline_step(0), 60);
+        assert_eq!(timeline_step(240 * 60), 60);
+        assert_eq!(timeline_step(240 * 60 + 1), 120);
+        assert_eq!(timeline_step(7 * 86400), 42 * 60);
+        assert_eq!(timeline_step(30 * 86400), 3 * 3600);
+
+        let started_at = DateTime::from_timestamp(1_715_754_600, 0).unwrap();
+        let report = AttackReport {
+            attack: Attack {
+                id: "a1".to_string(),
+                vector: "request_rate".to_string(),
+                status: AttackStatus::Ended,
+                started_at,
+                updated_at: started_at,
+                ended_at: Some(started_at + chrono::Duration::seconds(3725)),
+                targets: vec!["203.0.113.7".to_string()],
+                peak_rps: 5000,
+                detections: 12,
+                mitigations: vec!["block".to_string()],
+            },
+            generated_at: started_at,
+            duration_seconds: 3725,
+            vectors: vec!["request_rate".to_string(), "syn_flood".to_string()],
+            peak_requests_per_second: 4200,
+            peak_blocks_per_second: 3900,
+            events_by_type: BTreeMap::from([("BlockedRequest".to_string(), 7)]),
+            top_sources: vec![TopEntry { value: "203.0.113.7".to_string(), requests: 900 }],
+            top_countries: Vec::new(),
+            rule_matches: vec![("block <scrapers>".to_string(), 3)],
+            timeline_step: 60,
+            timeline: vec![TimelinePoint { timestamp: 1_715_754_600, requests: 252_000, blocks: 234_000, rate_limits: 0 }],
+        };
+
+        let text = report.render();
+        assert!(text.contains("Duration                                1h 2m"));
+        assert!(text.contains("Concurrent vectors                      request_rate, syn_flood"));
+        assert!(text.contains("2024-05-15 06:30                        252000  234000  0"));
+        assert!(text.contains("Top countries\n  None\n"));
+
+        let html = report.render_html();
+        assert!(html.contains("<td>block &lt;scrapers&gt;</td><td>3</td>"));
+
+        let pdf = report.render_pdf();
+        let pdf = String::from_utf8(pdf).unwrap();
+        assert!(pdf.starts_with("%PDF-1.4\n"));
+        assert!(pdf.contains("(Attack report a1) '"));
+        assert!(pdf.ends_with("%%EOF\n"));
+        let xref: usize = pdf.rsplit("startxref\n").next().unwrap().lines().next().unwrap().parse().unwrap();
+        assert!(pdf[xref..].starts_with("xref\n"));
+    }
+}
@@ -0,0 +1,366 @@
+//! Attack lifecycle tracking for the DDoS protection service.
+//!
+//! This module groups detections into attacks: the first detection of a
+//! vector opens an attack, later detections of the same vector update it
+//! (targets, peak rate, mitigations) and the attack ends once the vector
+//! has gone quiet. Attacks are stored in Redis so that every instance
+//! contributes to the same record; ended attacks are also archived in
+//! PostgreSQL when it is configured, outliving the Redis retention period.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+use crate::core::redis_pool::RedisPool;
+use crate::models::AttackConfig;
+use crate::storage::{Archive, ArchiveError};
+
+/// Active attack IDs keyed by vector
+const ACTIVE_KEY: &str = "attacks:active";
+
+/// Attack IDs scored by start time
+const INDEX_KEY: &str = "attacks:index";
+
+/// Opens an attack for the vector if none is active, then records a detection
+///
+/// KEYS[1] active attacks, KEYS[2] attack index
+/// ARGV: vector, ID for a new attack, now, rate, target, mitigation, max targets
+const RECORD_SCRIPT: &str = r#"
+local id = redis.call('HGET', KEYS[1], ARGV[1])
+if not id then
+    id = ARGV[2]
+    redis.call('HSET', KEYS[1], ARGV[1], id)
+    redis.call('HSET', 'attack:' .. id, 'vector', ARGV[1], 'started_at', ARGV[3], 'peak_rps', 0, 'detections', 0)
+    redis.call('ZADD', KEYS[2], ARGV[3], id)
+end
+
+local key = 'attack:' .. id
+redis.call('HSET', key, 'updated_at', ARGV[3])
+redis.call('HINCRBY', key, 'detections', 1)
+if tonumber(ARGV[4]) > (tonumber(redis.call('HGET', key, 'peak_rps')) or 0) then
+    redis.call('HSET', key, 'peak_rps', ARGV[4])
+end
+if ARGV[5] ~= '' and redis.call('SCARD', key .. ':targets') < tonumber(ARGV[7]) then
+    redis.call('SADD', key .. ':targets', ARGV[5])
+end
+if ARGV[6] ~= '' then
+    redis.call('SADD', key .. ':mitigations', ARGV[6])
+end
+return id
+"#;
+
+/// Ends an attack unless it was updated after the cutoff
+///
+/// KEYS[1] active attacks
+/// ARGV: vector, attack ID, cutoff, now, retention
+const CLOSE_SCRIPT: &str = r#"
+if redis.call('HGET', KEYS[1], ARGV[1]) ~= ARGV[2] then
+    return 0
+end
+local key = 'attack:' .. ARGV[2]
+if (tonumber(redis.call('HGET', key, 'updated_at')) or 0) > tonumber(ARGV[3]) then
+    return 0
+end
+redis.call('HDEL', KEYS[1], ARGV[1])
+redis.call('HSET', key, 'ended_at', ARGV[4])
+for _, k in ipairs({key, key .. ':targets', key .. ':mitigations'}) do
+    redis.call('EXPIRE', k, ARGV[5])
+end
+return 1
+"#;
+
+/// Errors that can occur while tracking attacks
+#[derive(Error, Debug)]
+pub enum AttackError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Archive error: {0}")]
+    ArchiveError(#[from] ArchiveError),
+}
+
+/// Whether an attack is still going on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttackStatus {
+    Active,
+    Ended,
+}
+
+/// An attack, from its first detection until its vector goes quiet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attack {
+    /// Attack ID
+    pub id: String,
+    /// What triggered detection (`request_rate`, `global_request_rate`, ...)
+    pub vector: String,
+    /// Whether the attack is still going on
+    pub status: AttackStatus,
+    /// First detection
+    pub started_at: DateTime<Utc>,
+    /// Latest detection
+    pub updated_at: DateTime<Utc>,
+    /// When the vector went quiet
+    pub ended_at: Option<DateTime<Utc>>,
+    /// Clients, subnets or paths involved, up to the configured maximum
+    pub targets: Vec<String>,
+    /// Highest request rate observed (requests per second)
+    pub peak_rps: u64,
+    /// Number of detections
+    pub detections: u64,
+    /// Mitigations applied (`block`, ...)
+    pub mitigations: Vec<String>,
+}
+
+fn attack_key(id: &str) -> String {
+    format!("attack:{}", id)
+}
+
+fn timestamp(value: Option<&String>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|value| value.parse().ok())
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+}
+
+/// Shared attack tracker
+///
+/// Cloning is cheap; all clones use the same Redis.
+#[derive(Clone)]
+pub struct AttackTracker {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Attack tracking configuration
+    config: AttackConfig,
+    /// Script recording detections
+    record_script: redis::Script,
+    /// Script ending quiet attacks
+    close_script: redis::Script,
+    /// Durable copy of ended attacks
+    archive: Option<Archive>,
+}
+
+impl AttackTracker {
+    /// Create a new attack tracker
+    pub fn new(redis: RedisPool, config: AttackConfig) -> Self {
+        Self {
+            redis,
+            config,
+            record_script: redis::Script::new(RECORD_SCRIPT),
+            close_script: redis::Script::new(CLOSE_SCRIPT),
+            archive: None,
+        }
+    }
+
+    /// Keep ended attacks in the given archive too, listing them from it
+    /// once they have expired from Redis
+    pub fn with_archive(mut self, archive: Archive) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Record a detection, returning the ID of the attack it belongs to
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - What triggered detection
+    /// * `target` - Client, subnet or path involved, if any
+    /// * `rps` - Observed request rate, or 0 if not applicable
+    /// * `mitigation` - Mitigation applied, if any
+    pub async fn record(
+        &self,
+        vector: &str,
+        target: Option<&str>,
+        rps: u64,
+        mitigation: Option<&str>,
+    ) -> Result<String, AttackError> {
+        let mut conn = self.redis.get();
+        let id: String = self.record_script
+            .key(ACTIVE_KEY)
+            .key(INDEX_KEY)
+            .arg(vector)
+            .arg(Uuid::new_v4().to_string())
+            .arg(Utc::now().timestamp())
+            .arg(rps)
+            .arg(target.unwrap_or_default())
+            .arg(mitigation.unwrap_or_default())
+            .arg(self.config.max_targets)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(id)
+    }
+
+    /// Get an attack by ID
+    pub async fn get(&self, id: &str) -> Result<Option<Attack>, AttackError> {
+        let key = attack_key(id);
+        let mut conn = self.redis.get();
+        let (fields, mut targets, mut mitigations): (HashMap<String, String>, Vec<String>, Vec<String>) = redis::pipe()
+            .cmd("HGETALL")
+            .arg(&key)
+            .cmd("SMEMBERS")
+            .arg(format!("{}:targets", key))
+            .cmd("SMEMBERS")
+            .arg(format!("{}:mitigations", key))
+            .query_async(&mut conn)
+            .await?;
+
+        let (started_at, updated_at) = match (timestamp(fields.get("started_at")), timestamp(fields.get("updated_at"))) {
+            (Some(started_at), Some(updated_at)) => (started_at, updated_at),
+            _ => return Ok(None),
+        };
+        let ended_at = timestamp(fields.get("ended_at"));
+        targets.sort();
+        mitigations.sort();
+
+        Ok(Some(Attack {
+            id: id.to_string(),
+            vector: fields.get("vector").cloned().unwrap_or_default(),
+            status: if ended_at.is_some() { AttackStatus::Ended } else { AttackStatus::Active },
+            started_at,
+            updated_at,
+            ended_at,
+            targets,
+            peak_rps: fields.get("peak_rps").and_then(|value| value.parse().ok()).unwrap_or(0),
+            detections: fields.get("detections").and_then(|value| value.parse().ok()).unwrap_or(0),
+            mitigations,
+        }))
+    }
+
+    /// List attacks, newest first
+    pub async fn list(&self, active_only: bool, limit: usize) -> Result<Vec<Attack>, AttackError> {
+        let mut conn = self.redis.get();
+        let ids: Vec<String> = if active_only {
+            redis::cmd("HVALS").arg(ACTIVE_KEY).query_async(&mut conn).await?
+        } else {
+            redis::cmd("ZREVRANGE")
+                .arg(INDEX_KEY)
+                .arg(0)
+                .arg(limit.saturating_sub(1))
+                .query_async(&mut conn)
+                .await?
+        };
+
+        let mut attacks = Vec::new();
+        for id in ids {
+            // Ended attacks expire after the retention period
+            if let Some(attack) = self.get(&id).await? {
+                attacks.push(attack);
+            }
+        }
+        if let (false, Some(archive)) = (active_only, &self.archive) {
+            for attack in archive.attacks(limit).await? {
+                if !attacks.iter().any(|listed| listed.id == attack.id) {
+                    attacks.push(attack);
+                }
+            }
+        }
+        attacks.sort_by_key(|attack| std::cmp::Reverse(attack.started_at));
+        attacks.truncate(limit);
+        Ok(attacks)
+    }
+
+    /// End attacks whose vector has been quiet for the quiet period,
+    /// returning the ended attack IDs
+    pub async fn close_quiet(&self) -> Result<Vec<String>, AttackError> {
+        let now = Utc::now().timestamp();
+        let cutoff = now - self.config.quiet_period_seconds as i64;
+        let mut conn = self.redis.get();
+        let active: Vec<(String, String)> = redis::cmd("HGETALL").arg(ACTIVE_KEY).query_async(&mut conn).await?;
+
+        let mut closed = Vec::new();
+        for (vector, id) in &active {
+            let ended: bool = self.close_script
+                .key(ACTIVE_KEY)
+                .arg(vector)
+                .arg(id)
+                .arg(cutoff)
+                .arg(now)
+                .arg(self.config.retention_seconds.max(1))
+                .invoke_async(&mut conn)
+                .await?;
+            if ended {
+                self.archive_attack(id).await;
+                closed.push(id.clone());
+            }
+        }
+
+        let _: () = redis::cmd("ZREMRANGEBYSCORE")
+            .arg(INDEX_KEY)
+            .arg("-inf")
+            .arg(now - self.config.retention_seconds as i64)
+            .query_async(&mut conn)
+            .await?;
+        metrics::gauge!("attacks_active", (active.len() - closed.len()) as f64);
+        Ok(closed)
+    }
+
+    /// Copy an ended attack to the archive, if there is one
+    async fn archive_attack(&self, id: &str) {
+        let Some(archive) = &self.archive else {
+            return;
+        };
+        let archived = match self.get(id).await {
+            Ok(Some(attack)) => archive.save_attack(&attack).await.map_err(AttackError::from),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = archived {
+            log::error!("Failed to archive attack {}: {}", id, e);
+        }
+    }
+
+    /// End quiet attacks periodically until the task is cancelled
+    pub async fn start(&self) {
+        let interval = Duration::from_secs((self.config.quiet_period_seconds / 4).clamp(1, 30));
+        loop {
+            match self.close_quiet().await {
+                Ok(closed) => {
+                    for id in closed {
+                        log::info!("Attack {} ended", id);
+                    }
+                }
+                Err(e) => log::error!("Failed to end quiet attacks: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Client;
+
+    #[tokio::test]
+    async fn test_attack_lifecycle() {
+        let client = Client::open("redis://127.0.0.1:6379").unwrap();
+        let pool = RedisPool::new(client, 1).await.unwrap();
+        let config = AttackConfig {
+            quiet_period_seconds: 0,
+            ..AttackConfig::default()
+        };
+        let tracker = AttackTracker::new(pool, config);
+        let vector = "test_request_rate";
+
+        let id = tracker.record(vector, Some("192.0.2.1"), 50, Some("block")).await.unwrap();
+        let same = tracker.record(vector, Some("192.0.2.2"), 80, None).await.unwrap();
+        assert_eq!(id, same);
+
+        let attack = tracker.get(&id).await.unwrap().unwrap();
+        assert_eq!(attack.status, AttackStatus::Active);
+        assert_eq!(attack.targets, vec!["192.0.2.1", "192.0.2.2"]);
+        assert_eq!(attack.peak_rps, 80);
+        assert_eq!(attack.detections, 2);
+        assert_eq!(attack.mitigations, vec!["block"]);
+
+        // Updated attacks are still active until a full second has passed
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(tracker.close_quiet().await.unwrap().contains(&id));
+        let attack = tracker.get(&id).await.unwrap().unwrap();
+        assert_eq!(attack.status, AttackStatus::Ended);
+
+        // The next detection opens a new attack
+        let next = tracker.record(vector, None, 0, None).await.unwrap();
+        assert_ne!(id, next);
+    }
+}
@@ -0,0 +1,383 @@
+//! Traffic baselines for the DDoS protection service.
+//!
+//! This module learns what normal traffic looks like: every completed
+//! window of a metric (requests, bytes, requests to a path) is folded into
+//! an exponentially weighted moving average and variance kept in Redis,
+//! both overall and for the hour of the week the window fell in. The
+//! detector then flags windows that deviate from the baseline by more than
+//! the configured number of standard deviations, instead of relying on
+//! fixed thresholds alone. Once an hour of the week has been learned its
+//! seasonal baseline is used, so that a Monday morning peak isn't flagged
+//! while the same traffic early on a Sunday is.
+
+use std::collections::HashMap;
+use serde::Serialize;
+use crate::core::ddos_detector::BaselineDetectionConfig;
+use crate::core::redis_pool::RedisPool;
+
+/// Baselines of metrics that stop being observed, such as removed paths,
+/// expire after two weeks, long enough for a seasonal profile to survive a
+/// quiet week
+const BASELINE_TTL: u64 = 14 * 86400;
+
+/// Most empty windows folded into a baseline after a gap in traffic
+const MAX_EMPTY_WINDOWS: u64 = 100;
+
+/// Hours in a week
+const HOURS_PER_WEEK: u32 = 168;
+
+/// Records observations of one or more metrics
+///
+/// The largest value seen in a window is its count so far; once a later
+/// window is observed it is complete and folded into the overall baseline
+/// and the baseline of its hour of the week, unless it was itself
+/// anomalous, so that attacks don't teach the baseline that attack traffic
+/// is normal. Windows without traffic are folded as zeros.
+///
+/// KEYS[i] baseline hash of metric i
+/// ARGV[1..7]: smoothing, seasonal smoothing, sensitivity, min increase,
+/// min samples, max empty windows, TTL
+/// ARGV[5 + 3i], ARGV[6 + 3i], ARGV[7 + 3i]: window, value and window length of metric i
+const OBSERVE_SCRIPT: &str = r#"
+local alpha = tonumber(ARGV[1])
+local seasonal_alpha = tonumber(ARGV[2])
+local sensitivity = tonumber(ARGV[3])
+local min_increase = tonumber(ARGV[4])
+local min_samples = tonumber(ARGV[5])
+local max_empty = tonumber(ARGV[6])
+
+-- The Unix epoch was a Thursday; hour 0 is Monday 00:00 UTC
+local function hour_of_week(window, length)
+    return (math.floor(window * length / 3600) + 72) % 168
+end
+
+local function fold(baseline, x, a)
+    if baseline.samples == 0 then
+        baseline.mean = x
+        baseline.variance = 0
+    else
+        local diff = x - baseline.mean
+        local incr = a * diff
+        baseline.mean = baseline.mean + incr
+        baseline.variance = (1 - a) * (baseline.variance + diff * incr)
+    end
+    baseline.samples = baseline.samples + 1
+end
+
+local function is_anomalous(baseline, x)
+    return baseline.samples >= min_samples and x > math.max(
+        baseline.mean + sensitivity * math.sqrt(baseline.variance),
+        baseline.mean * (1 + min_increase))
+end
+
+local results = {}
+for i, key in ipairs(KEYS) do
+    local window = tonumber(ARGV[5 + 3 * i])
+    local value = tonumber(ARGV[6 + 3 * i])
+    local length = tonumber(ARGV[7 + 3 * i])
+
+    local function load(suffix)
+        local data = redis.call('HMGET', key, 'mean' .. suffix, 'variance' .. suffix, 'samples' .. suffix)
+        return {mean = tonumber(data[1]) or 0, variance = tonumber(data[2]) or 0, samples = tonumber(data[3]) or 0}
+    end
+    local function save(suffix, baseline)
+        redis.call('HSET', key, 'mean' .. suffix, tostring(baseline.mean),
+            'variance' .. suffix, tostring(baseline.variance), 'samples' .. suffix, baseline.samples)
+    end
+
+    local overall = load('')
+    local function complete(w, x, check)
+        local suffix = ':' .. hour_of_week(w, length)
+        local seasonal = load(suffix)
+        local effective = seasonal.samples >= min_samples and seasonal or overall
+        if check and is_anomalous(effective, x) then
+            return
+        end
+        fold(overall, x, alpha)
+        fold(seasonal, x, seasonal_alpha)
+        save(suffix, seasonal)
+    end
+
+    local state = redis.call('HMGET', key, 'window', 'last')
+    local last_window = tonumber(state[1]) or window
+    local last = tonumber(state[2]) or 0
+    if window > last_window then
+        complete(last_window, last, true)
+        for w = last_window + 1, math.min(window - 1, last_window + max_empty) do
+            complete(w, 0, false)
+        end
+        last = 0
+        last_window = window
+    end
+    if window == last_window then
+        last = math.max(last, value)
+    end
+
+    save('', overall)
+    redis.call('HSET', key, 'window', last_window, 'last', last)
+    redis.call('EXPIRE', key, ARGV[7])
+
+    local seasonal = load(':' .. hour_of_week(window, length))
+    results[i] = {
+        tostring(overall.mean), tostring(overall.variance), overall.samples,
+        tostring(seasonal.mean), tostring(seasonal.variance), seasonal.samples,
+    }
+end
+return results
+"#;
+
+/// Current value of a metric
+#[derive(Debug, Clone)]
+pub struct Observation {
+    /// Metric name (`requests`, `volume`, `path:/login`, ...)
+    pub metric: String,
+    /// Length of the metric's counting window (seconds)
+    pub window_seconds: u64,
+    /// Value counted in the current window so far
+    pub value: u64,
+}
+
+/// Learned baseline of a metric
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Baseline {
+    /// Average value per window
+    pub mean: f64,
+    /// Standard deviation per window
+    pub stddev: f64,
+    /// Number of windows learned from
+    pub samples: u64,
+}
+
+impl Baseline {
+    fn from_fields(mean: Option<&String>, variance: Option<&String>, samples: Option<&String>) -> Self {
+        Self {
+            mean: mean.and_then(|mean| mean.parse().ok()).unwrap_or(0.0),
+            stddev: variance
+                .and_then(|variance| variance.parse::<f64>().ok())
+                .unwrap_or(0.0)
+                .max(0.0)
+                .sqrt(),
+            samples: samples.and_then(|samples| samples.parse().ok()).unwrap_or(0),
+        }
+    }
+
+    /// Value above which a window is anomalous, or `None` while the
+    /// baseline is still warming up
+    ///
+    /// `sensitivity` is the number of standard deviations above the mean;
+    /// the value must also exceed the mean by `min_increase` (a fraction)
+    /// so that very steady traffic isn't flagged for small bumps.
+    pub fn threshold(&self, sensitivity: f64, config: &BaselineDetectionConfig) -> Option<f64> {
+        if self.samples < config.min_samples {
+            return None;
+        }
+        Some((self.mean + sensitivity * self.stddev).max(self.mean * (1.0 + config.min_increase)))
+    }
+}
+
+/// Baselines of a metric at the time of an observation
+#[derive(Debug, Clone)]
+pub struct MetricBaseline {
+    /// Baseline over all hours
+    pub overall: Baseline,
+    /// Baseline of the current hour of the week
+    pub seasonal: Baseline,
+}
+
+/// Baseline of one hour of the week
+#[derive(Debug, Clone, Serialize)]
+pub struct SeasonalBaseline {
+    /// Hour of the week, 0 being Monday 00:00 UTC
+    pub hour_of_week: u32,
+    /// Readable hour, such as `Mon 09:00 UTC`
+    pub label: String,
+    #[serde(flatten)]
+    pub baseline: Baseline,
+}
+
+/// Everything learned about a metric
+#[derive(Debug, Clone, Serialize)]
+pub struct BaselineProfile {
+    /// Metric name
+    pub metric: String,
+    /// Baseline over all hours
+    pub overall: Baseline,
+    /// Baselines of the hours of the week seen so far
+    pub seasonal: Vec<SeasonalBaseline>,
+}
+
+/// Readable name of an hour of the week
+pub fn hour_label(hour_of_week: u32) -> String {
+    const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    let hour_of_week = hour_of_week % HOURS_PER_WEEK;
+    format!("{} {:02}:00 UTC", DAYS[(hour_of_week / 24) as usize], hour_of_week % 24)
+}
+
+fn baseline_key(metric: &str) -> String {
+    format!("baseline:{}", metric)
+}
+
+/// Shared baseline learner
+///
+/// Cloning is cheap; all clones use the same Redis.
+#[derive(Clone)]
+pub struct BaselineLearner {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Baseline configuration
+    config: BaselineDetectionConfig,
+    /// Standard deviations above the mean that are anomalous
+    sensitivity: f64,
+    /// Script recording observations
+    observe_script: redis::Script,
+}
+
+impl BaselineLearner {
+    /// Create a new baseline learner
+    pub fn new(redis: RedisPool, config: BaselineDetectionConfig, sensitivity: f64) -> Self {
+        Self {
+            redis,
+            config,
+            sensitivity,
+            observe_script: redis::Script::new(OBSERVE_SCRIPT),
+        }
+    }
+
+    /// Record the current values of metrics at `now` (Unix seconds),
+    /// returning their baselines
+    pub async fn observe(&self, now: u64, observations: &[Observation]) -> Result<Vec<MetricBaseline>, redis::RedisError> {
+        if observations.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut invocation = self.observe_script.prepare_invoke();
+        invocation
+            .arg(self.config.smoothing)
+            .arg(self.config.seasonal_smoothing)
+            .arg(self.sensitivity)
+            .arg(self.config.min_increase)
+            .arg(self.config.min_samples)
+            .arg(MAX_EMPTY_WINDOWS)
+            .arg(BASELINE_TTL);
+        for observation in observations {
+            let window_seconds = observation.window_seconds.max(1);
+            invocation
+                .key(baseline_key(&observation.metric))
+                .arg(now / window_seconds)
+                .arg(observation.value)
+                .arg(window_seconds);
+        }
+
+        let mut conn = self.redis.get();
+        type Results = Vec<(String, String, String, String, String, String)>;
+        let results: Results = invocation.invoke_async(&mut conn).await?;
+        Ok(results
+            .iter()
+            .map(|(mean, variance, samples, seasonal_mean, seasonal_variance, seasonal_samples)| MetricBaseline {
+                overall: Baseline::from_fields(Some(mean), Some(variance), Some(samples)),
+                seasonal: Baseline::from_fields(Some(seasonal_mean), Some(seasonal_variance), Some(seasonal_samples)),
+            })
+            .collect())
+    }
+
+    /// Value above which a window is anomalous, if the baseline is ready
+    ///
+    /// The seasonal baseline is used once its hour has been learned.
+    pub fn threshold(&self, baseline: &MetricBaseline) -> Option<f64> {
+        baseline
+            .seasonal
+            .threshold(self.sensitivity, &self.config)
+            .or_else(|| baseline.overall.threshold(self.sensitivity, &self.config))
+    }
+
+    /// Names of the metrics with a baseline
+    pub async fn metrics(&self) -> Result<Vec<String>, redis::RedisError> {
+        let mut conn = self.redis.get();
+        let mut metrics = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(baseline_key("*"))
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(&mut conn)
+                .await?;
+            metrics.extend(keys.into_iter().filter_map(|key| key.strip_prefix("baseline:").map(str::to_string)));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        metrics.sort();
+        Ok(metrics)
+    }
+
+    /// Get the overall and seasonal baselines of a metric
+    pub async fn profile(&self, metric: &str) -> Result<Option<BaselineProfile>, redis::RedisError> {
+        let mut conn = self.redis.get();
+        let fields: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(baseline_key(metric))
+            .query_async(&mut conn)
+            .await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+
+        let seasonal = (0..HOURS_PER_WEEK)
+            .filter_map(|hour| {
+                let suffix = format!(":{}", hour);
+                let samples = fields.get(&format!("samples{}", suffix))?;
+                Some(SeasonalBaseline {
+                    hour_of_week: hour,
+                    label: hour_label(hour),
+                    baseline: Baseline::from_fields(
+                        fields.get(&format!("mean{}", suffix)),
+                        fields.get(&format!("variance{}", suffix)),
+                        Some(samples),
+                    ),
+                })
+            })
+            .collect();
+        Ok(Some(BaselineProfile {
+            metric: metric.to_string(),
+            overall: Baseline::from_fields(fields.get("mean"), fields.get("variance"), fields.get("samples")),
+            seasonal,
+        }))
+    }
+
+    /// Forget everything learned about a metric, returning whether it had a baseline
+    pub async fn reset(&self, metric: &str) -> Result<bool, redis::RedisError> {
+        let mut conn = self.redis.get();
+        let removed: u32 = redis::cmd("DEL").arg(baseline_key(metric)).query_async(&mut conn).await?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold() {
+        let config = BaselineDetectionConfig::default();
+        let baseline = Baseline {
+            mean: 1000.0,
+            stddev: 100.0,
+            samples: config.min_samples,
+        };
+        assert_eq!(baseline.threshold(3.0, &config), Some(1500.0));
+        assert_eq!(baseline.threshold(6.0, &config), Some(1600.0));
+
+        let warming_up = Baseline {
+            samples: config.min_samples - 1,
+            ..baseline
+        };
+        assert_eq!(warming_up.threshold(3.0, &config), None);
+
+        assert_eq!(hour_label(0), "Mon 00:00 UTC");
+        assert_eq!(hour_label(24 + 9), "Tue 09:00 UTC");
+        assert_eq!(hour_label(167), "Sun 23:00 UTC");
+    }
+}
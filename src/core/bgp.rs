@@ -0,0 +1,411 @@
+//! BGP mitigation for the DDoS protection service.
+//!
+//! This module announces attacking networks to our routers through a BGP
+//! speaker (ExaBGP or GoBGP), as source-based blackhole routes or FlowSpec
+//! rules, so that large volumetric attacks are dropped at the network edge.
+//! Announcements are only made while the escalation ladder is at its
+//! `upstream` step, and are bounded by safety checks: a minimum prefix
+//! length, protected prefixes, a maximum number of active announcements and
+//! a TTL after which they're withdrawn. Active announcements are kept in
+//! Redis so the limits hold across instances.
+
+use std::process::Stdio;
+use std::time::Duration;
+use async_trait::async_trait;
+use ipnet::IpNet;
+use thiserror::Error;
+use tokio::process::Command;
+use crate::core::escalation::UpstreamMitigation;
+use crate::core::redis_pool::RedisPool;
+use crate::models::{BgpBackend, BgpConfig, BgpMode};
+use crate::utils::{get_current_timestamp, parse_network};
+
+/// Redis sorted set of active announcements, scored by when they expire
+const ANNOUNCEMENTS_KEY: &str = "bgp:announcements";
+
+/// Redis key set while the escalation ladder has engaged BGP mitigation
+const ENGAGED_KEY: &str = "bgp:engaged";
+
+/// Reserves an announcement if it fits under the limit
+///
+/// Returns 1 when reserved, 0 when the network is already announced (its
+/// expiry is extended) and -1 when the limit is reached.
+const RESERVE_SCRIPT: &str = r#"
+if redis.call('ZSCORE', KEYS[1], ARGV[1]) then
+    redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+    return 0
+end
+if redis.call('ZCOUNT', KEYS[1], '(' .. ARGV[2], '+inf') >= tonumber(ARGV[4]) then
+    return -1
+end
+redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+return 1
+"#;
+
+/// Removes and returns the expired announcements, so only one instance withdraws each
+const TAKE_EXPIRED_SCRIPT: &str = r#"
+local expired = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+if #expired > 0 then
+    redis.call('ZREM', KEYS[1], unpack(expired))
+end
+return expired
+"#;
+
+/// Errors that can occur while announcing routes
+#[derive(Error, Debug)]
+pub enum BgpError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("HTTP error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Failed to run {0}: {1}")]
+    CommandError(String, std::io::Error),
+    #[error("{0} failed: {1}")]
+    CommandFailed(String, String),
+    #[error("Invalid network: {0}")]
+    InvalidNetwork(String),
+    #[error("Prefix {0} is shorter than the minimum")]
+    PrefixTooShort(IpNet),
+    #[error("Prefix {0} overlaps a protected prefix")]
+    Protected(IpNet),
+    #[error("Announcement limit of {0} reached")]
+    LimitReached(usize),
+}
+
+/// Check that a network may be announced
+fn check_prefix(config: &BgpConfig, protected: &[IpNet], target: &str) -> Result<IpNet, BgpError> {
+    let network = parse_network(target)
+        .ok_or_else(|| BgpError::InvalidNetwork(target.to_string()))?
+        .trunc();
+    let min_length = match network {
+        IpNet::V4(_) => config.min_prefix_length_v4,
+        IpNet::V6(_) => config.min_prefix_length_v6,
+    };
+    if network.prefix_len() < min_length {
+        return Err(BgpError::PrefixTooShort(network));
+    }
+    if protected.iter().any(|p| p.contains(&network) || network.contains(p)) {
+        return Err(BgpError::Protected(network));
+    }
+    Ok(network)
+}
+
+/// ExaBGP API command announcing or withdrawing a network
+fn exabgp_command(config: &BgpConfig, network: &IpNet, announce: bool) -> String {
+    let action = if announce { "announce" } else { "withdraw" };
+    match config.mode {
+        BgpMode::Rtbh => format!(
+            "{} route {} next-hop {} community [{}]",
+            action,
+            network,
+            next_hop(config, network),
+            config.community
+        ),
+        BgpMode::Flowspec => format!("{} flow route {{ match {{ source {}; }} then {{ discard; }} }}", action, network),
+    }
+}
+
+/// `gobgp` arguments announcing or withdrawing a network
+fn gobgp_args(config: &BgpConfig, network: &IpNet, announce: bool) -> Vec<String> {
+    let action = if announce { "add" } else { "del" };
+    let family = match network {
+        IpNet::V4(_) => "ipv4",
+        IpNet::V6(_) => "ipv6",
+    };
+    match config.mode {
+        BgpMode::Rtbh => vec![
+            "global".to_string(),
+            "rib".to_string(),
+            action.to_string(),
+            "-a".to_string(),
+            family.to_string(),
+            network.to_string(),
+            "nexthop".to_string(),
+            next_hop(config, network).to_string(),
+            "community".to_string(),
+            config.community.clone(),
+        ],
+        BgpMode::Flowspec => vec![
+            "global".to_string(),
+            "rib".to_string(),
+            "-a".to_string(),
+            format!("{}-flowspec", family),
+            action.to_string(),
+            "match".to_string(),
+            "source".to_string(),
+            network.to_string(),
+            "then".to_string(),
+            "discard".to_string(),
+        ],
+    }
+}
+
+fn next_hop<'a>(config: &'a BgpConfig, network: &IpNet) -> &'a str {
+    match network {
+        IpNet::V4(_) => &config.next_hop,
+        IpNet::V6(_) => &config.next_hop_v6,
+    }
+}
+
+/// Announcer of attacking networks to the routers
+pub struct BgpAnnouncer {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// BGP mitigation configuration
+    config: BgpConfig,
+    /// Networks that are never announced
+    protected: Vec<IpNet>,
+    /// HTTP client for the ExaBGP API
+    client: reqwest::Client,
+    /// Script reserving announcements
+    reserve_script: redis::Script,
+    /// Script taking expired announcements
+    take_expired_script: redis::Script,
+}
+
+impl BgpAnnouncer {
+    /// Create a new BGP announcer
+    pub fn new(redis: RedisPool, config: BgpConfig) -> Self {
+        let protected = config
+            .protected_prefixes
+            .iter()
+            .filter_map(|prefix| {
+                let network = parse_network(prefix);
+                if network.is_none() {
+                    log::warn!("Ignoring invalid protected prefix {}", prefix);
+                }
+                network
+            })
+            .collect();
+        Self {
+            redis,
+            config,
+            protected,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+            reserve_script: redis::Script::new(RESERVE_SCRIPT),
+            take_expired_script: redis::Script::new(TAKE_EXPIRED_SCRIPT),
+        }
+    }
+
+    /// Announce a network, returning whether a new announcement was made
+    ///
+    /// Announcing a network that is already announced extends its TTL.
+    pub async fn announce(&self, target: &str, reason: &str) -> Result<bool, BgpError> {
+        let network = check_prefix(&self.config, &self.protected, target)?;
+        let now = get_current_timestamp();
+        let mut conn = self.redis.get();
+        let reserved: i64 = self
+            .reserve_script
+            .key(ANNOUNCEMENTS_KEY)
+            .arg(network.to_string())
+            .arg(now)
+            .arg(now + self.config.announcement_ttl_seconds)
+            .arg(self.config.max_announcements)
+            .invoke_async(&mut conn)
+            .await?;
+        match reserved {
+            0 => return Ok(false),
+            -1 => {
+                metrics::increment_counter!("bgp_announcements_rejected_total", "reason" => "limit");
+                return Err(BgpError::LimitReached(self.config.max_announcements));
+            }
+            _ => (),
+        }
+
+        if let Err(e) = self.send(&network, true).await {
+            let _: Result<(), _> = redis::cmd("ZREM")
+                .arg(ANNOUNCEMENTS_KEY)
+                .arg(network.to_string())
+                .query_async(&mut conn)
+                .await;
+            return Err(e);
+        }
+        metrics::increment_counter!("bgp_announcements_total");
+        log::warn!("Announced {} over BGP for {}s: {}", network, self.config.announcement_ttl_seconds, reason);
+        Ok(true)
+    }
+
+    /// Withdraw the announcements whose TTL has passed
+    #[tracing::instrument(name = "bgp.withdraw_expired", skip_all)]
+    pub async fn withdraw_expired(&self) -> Result<(), BgpError> {
+        let mut conn = self.redis.get();
+        let expired: Vec<String> = self
+            .take_expired_script
+            .key(ANNOUNCEMENTS_KEY)
+            .arg(get_current_timestamp())
+            .invoke_async(&mut conn)
+            .await?;
+        self.withdraw_all_of(expired).await;
+        Ok(())
+    }
+
+    /// Withdraw every active announcement
+    pub async fn withdraw_all(&self) -> Result<(), BgpError> {
+        let mut conn = self.redis.get();
+        let (announced, _): (Vec<String>, ()) = redis::pipe()
+            .atomic()
+            .cmd("ZRANGE")
+            .arg(ANNOUNCEMENTS_KEY)
+            .arg(0)
+            .arg(-1)
+            .cmd("DEL")
+            .arg(ANNOUNCEMENTS_KEY)
+            .ignore()
+            .cmd("DEL")
+            .arg(ENGAGED_KEY)
+            .query_async(&mut conn)
+            .await?;
+        self.withdraw_all_of(announced).await;
+        Ok(())
+    }
+
+    async fn withdraw_all_of(&self, networks: Vec<String>) {
+        for network in networks.iter().filter_map(|network| parse_network(network)) {
+            match self.send(&network, false).await {
+                Ok(()) => {
+                    metrics::increment_counter!("bgp_withdrawals_total");
+                    log::info!("Withdrew BGP announcement of {}", network);
+                }
+                Err(e) => log::error!("Failed to withdraw BGP announcement of {}: {}", network, e),
+            }
+        }
+    }
+
+    /// Withdraw expired announcements until the task is aborted
+    pub async fn start(&self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.withdraw_interval_seconds.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.withdraw_expired().await {
+                log::error!("Failed to withdraw expired BGP announcements: {}", e);
+            }
+        }
+    }
+
+    /// Send an announcement or withdrawal to the BGP speaker
+    async fn send(&self, network: &IpNet, announce: bool) -> Result<(), BgpError> {
+        match self.config.backend {
+            BgpBackend::Exabgp => {
+                let command = exabgp_command(&self.config, network, announce);
+                self.client
+                    .post(&self.config.exabgp_url)
+                    .form(&[("command", command)])
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            BgpBackend::Gobgp => {
+                let program = self.config.gobgp_command.clone();
+                let (host, port) = self
+                    .config
+                    .gobgp_address
+                    .rsplit_once(':')
+                    .unwrap_or((&self.config.gobgp_address, "50051"));
+                let output = Command::new(&program)
+                    .args(["-u", host, "-p", port])
+                    .args(gobgp_args(&self.config, network, announce))
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .output()
+                    .await
+                    .map_err(|e| BgpError::CommandError(program.clone(), e))?;
+                if !output.status.success() {
+                    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                    return Err(BgpError::CommandFailed(program, stderr));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UpstreamMitigation for BgpAnnouncer {
+    fn name(&self) -> &str {
+        "bgp"
+    }
+
+    async fn engage(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("SET").arg(ENGAGED_KEY).arg(1).query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn release(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.withdraw_all().await?)
+    }
+
+    /// Announce a network, but only while the ladder is at its `upstream` step
+    async fn block(&self, target: &str, reason: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.redis.get();
+        let engaged: bool = redis::cmd("EXISTS").arg(ENGAGED_KEY).query_async(&mut conn).await?;
+        if engaged {
+            self.announce(target, reason).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(value: &str) -> IpNet {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_check_prefix() {
+        let config = BgpConfig::default();
+        let protected = vec![network("198.51.100.0/24"), network("2001:db8:1::/48")];
+
+        assert_eq!(check_prefix(&config, &protected, "203.0.113.0/24").unwrap(), network("203.0.113.0/24"));
+        // Host bits are cleared and single addresses become host routes
+        assert_eq!(check_prefix(&config, &protected, "203.0.113.7/24").unwrap(), network("203.0.113.0/24"));
+        assert_eq!(check_prefix(&config, &protected, "203.0.113.7").unwrap(), network("203.0.113.7/32"));
+
+        assert!(matches!(check_prefix(&config, &protected, "203.0.0.0/16"), Err(BgpError::PrefixTooShort(_))));
+        assert!(matches!(check_prefix(&config, &protected, "2001:db8::/32"), Err(BgpError::PrefixTooShort(_))));
+        assert!(matches!(check_prefix(&config, &protected, "198.51.100.9"), Err(BgpError::Protected(_))));
+        assert!(matches!(check_prefix(&config, &protected, "2001:db8:1::/64"), Err(BgpError::Protected(_))));
+        assert!(matches!(check_prefix(&config, &protected, "not a network"), Err(BgpError::InvalidNetwork(_))));
+    }
+
+    #[test]
+    fn test_exabgp_command() {
+        let mut config = BgpConfig::default();
+        assert_eq!(
+            exabgp_command(&config, &network("203.0.113.0/24"), true),
+            "announce flow route { match { source 203.0.113.0/24; } then { discard; } }"
+        );
+
+        config.mode = BgpMode::Rtbh;
+        assert_eq!(
+            exabgp_command(&config, &network("203.0.113.0/24"), true),
+            "announce route 203.0.113.0/24 next-hop 192.0.2.1 community [65535:666]"
+        );
+        assert_eq!(
+            exabgp_command(&config, &network("2001:db8::/48"), false),
+            "withdraw route 2001:db8::/48 next-hop 100::1 community [65535:666]"
+        );
+    }
+
+    #[test]
+    fn test_gobgp_args() {
+        let mut config = BgpConfig::default();
+        assert_eq!(
+            gobgp_args(&config, &network("2001:db8::/48"), true).join(" "),
+            "global rib -a ipv6-flowspec add match source 2001:db8::/48 then discard"
+        );
+
+        config.mode = BgpMode::Rtbh;
+        assert_eq!(
+            gobgp_args(&config, &network("203.0.113.0/24"), false).join(" "),
+            "global rib del -a ipv4 203.0.113.0/24 nexthop 192.0.2.1 community 65535:666"
+        );
+    }
+}
@@ -0,0 +1,558 @@
+//! IP blocklist for the DDoS protection service.
+//!
+//! This module stores blocked IPs and CIDR ranges in Redis with optional
+//! expirations. Blocks are added by the DDoS detector, the rule engine's
+//! `Block` action and the management API, and are honoured by every
+//! instance sharing the same Redis.
+//!
+//! While Redis is down, checks and blocks follow the blocklist's
+//! degradation policy; with `fallback`, the in-memory copy answers checks
+//! and new blocks are written to Redis once it returns.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::core::degradation::{Degradation, Subsystem};
+use crate::core::prefix_trie::PrefixTrie;
+use crate::core::redis_pool::RedisPool;
+use crate::core::tenants;
+use crate::models::{BlocklistConfig, FailurePolicy, SubnetConfig};
+use crate::utils::{format_rate_limit_key, normalize_ip, parse_network};
+
+/// Sorted set of blocked targets scored by expiry timestamp
+const BLOCKLIST_INDEX_KEY: &str = "blocklist:index";
+
+/// Errors that can occur during blocklist operations
+#[derive(Error, Debug)]
+pub enum BlocklistError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Invalid target: {0}")]
+    InvalidTarget(String),
+}
+
+/// Blocklist entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockEntry {
+    /// Blocked network in CIDR notation (single IPs use a full-length prefix)
+    pub target: String,
+    /// Why the target was blocked
+    pub reason: String,
+    /// Component that added the block (`api`, `ddos_detector`, `rule_engine`)
+    pub source: String,
+    /// Creation timestamp
+    pub created_at: DateTime<Utc>,
+    /// When the block lifts, or `None` for a permanent block
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl BlockEntry {
+    /// Whether the block has lifted at `now`
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Source of the blocks added by the DDoS detector
+const DETECTOR_SOURCE: &str = "ddos_detector";
+
+fn entry_key(target: &str) -> String {
+    format_rate_limit_key("blocklist:entry", target)
+}
+
+/// Shared IP blocklist
+///
+/// Exact IPs are looked up in Redis directly so blocks added by other
+/// instances apply immediately; CIDR ranges are matched against an
+/// in-memory prefix trie that is refreshed periodically. Cloning is cheap
+/// and all clones see the same entries.
+#[derive(Clone)]
+pub struct Blocklist {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Blocklist configuration
+    config: BlocklistConfig,
+    /// Cached entries keyed by network
+    entries: Arc<RwLock<PrefixTrie<BlockEntry>>>,
+    /// Subnet sizes used to escalate detector blocks
+    subnets: SubnetConfig,
+    /// Redis outage handling
+    degradation: Option<Degradation>,
+    /// Prefix of the blocklist's Redis keys; empty for the global blocklist
+    namespace: String,
+}
+
+impl Blocklist {
+    /// Create a new blocklist instance
+    pub fn new(redis: RedisPool, config: BlocklistConfig) -> Self {
+        Self {
+            redis,
+            config,
+            entries: Arc::new(RwLock::new(PrefixTrie::new())),
+            subnets: SubnetConfig::default(),
+            degradation: None,
+            namespace: String::new(),
+        }
+    }
+
+    /// A separate blocklist of a tenant, with its own entries under the
+    /// tenant's namespace and this one's configuration
+    pub fn for_tenant(&self, tenant: &str) -> Self {
+        Self {
+            entries: Arc::new(RwLock::new(PrefixTrie::new())),
+            namespace: tenants::namespace(tenant),
+            ..self.clone()
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.namespace, key)
+    }
+
+    fn entry_key(&self, target: &str) -> String {
+        self.key(&entry_key(target))
+    }
+
+    /// Keep working from memory while Redis is down, as the degradation policy says
+    pub fn with_degradation(mut self, degradation: Degradation) -> Self {
+        self.degradation = Some(degradation);
+        self
+    }
+
+    /// Policy to follow instead of using Redis: during an outage, or when `error` starts one
+    async fn outage_policy(&self, error: Option<&redis::RedisError>) -> Option<FailurePolicy> {
+        let degradation = self.degradation.as_ref()?;
+        match error {
+            None => degradation.active_policy(Subsystem::Blocklist).await,
+            Some(error) => degradation.fail(Subsystem::Blocklist, error).await,
+        }
+    }
+
+    /// Group detector blocks into subnets of the given sizes
+    pub fn with_subnets(mut self, subnets: SubnetConfig) -> Self {
+        self.subnets = subnets;
+        self
+    }
+
+    /// Block an IP or CIDR range
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - IP or network in CIDR notation
+    /// * `reason` - Why the target is blocked
+    /// * `source` - Component adding the block
+    /// * `duration` - How long the block lasts, or `None` for a permanent block
+    pub async fn block(
+        &self,
+        target: &str,
+        reason: &str,
+        source: &str,
+        duration: Option<Duration>,
+    ) -> Result<BlockEntry, BlocklistError> {
+        let network = parse_network(target)
+            .ok_or_else(|| BlocklistError::InvalidTarget(target.to_string()))?;
+        let now = Utc::now();
+        let entry = BlockEntry {
+            target: network.to_string(),
+            reason: reason.to_string(),
+            source: source.to_string(),
+            created_at: now,
+            expires_at: duration.map(|d| now + chrono::Duration::seconds(d.as_secs() as i64)),
+        };
+
+        let policy = match self.outage_policy(None).await {
+            Some(policy) => Some(policy),
+            None => match self.store(&entry).await {
+                Ok(()) => None,
+                Err(BlocklistError::RedisError(e)) => match self.outage_policy(Some(&e)).await {
+                    Some(policy) => Some(policy),
+                    None => return Err(e.into()),
+                },
+                Err(e) => return Err(e),
+            },
+        };
+        // Without Redis, a block only holds on this instance, and only with the fallback policy
+        match (policy, &self.degradation) {
+            (None, _) => (),
+            (Some(FailurePolicy::Fallback), Some(degradation)) => {
+                if !degradation.queue_block(entry.clone()).await {
+                    log::warn!("Too many blocks added while Redis is down; {} won't be written to Redis", entry.target);
+                }
+            }
+            (Some(_), _) => {
+                return Err(BlocklistError::RedisError(
+                    (redis::ErrorKind::IoError, "Redis is unavailable").into(),
+                ));
+            }
+        }
+
+        self.entries.write().await.insert(network, entry.clone());
+        metrics::increment_counter!("blocklist_blocks_total", "source" => source.to_string());
+
+        Ok(entry)
+    }
+
+    /// Write an entry to Redis, with the time it has left
+    pub(crate) async fn store(&self, entry: &BlockEntry) -> Result<(), BlocklistError> {
+        let key = self.entry_key(&entry.target);
+        let json = serde_json::to_string(entry)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        match entry.expires_at {
+            Some(expires_at) => pipe
+                .cmd("SET")
+                .arg(&key)
+                .arg(json)
+                .arg("EX")
+                .arg((expires_at - Utc::now()).num_seconds().max(1)),
+            None => pipe.cmd("SET").arg(&key).arg(json),
+        };
+        pipe.cmd("ZADD")
+            .arg(self.key(BLOCKLIST_INDEX_KEY))
+            .arg(entry.expires_at.map_or("+inf".to_string(), |e| e.timestamp().to_string()))
+            .arg(&entry.target);
+
+        let mut conn = self.redis.get();
+        let _: () = pipe.query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Block many networks with the same reason and duration in one round trip
+    ///
+    /// Used to load bulk lists such as threat intelligence feeds.
+    pub async fn block_many(
+        &self,
+        networks: &[IpNet],
+        reason: &str,
+        source: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), BlocklistError> {
+        let now = Utc::now();
+        let expires_at = duration.map(|d| now + chrono::Duration::seconds(d.as_secs() as i64));
+        let score = expires_at.map_or("+inf".to_string(), |e| e.timestamp().to_string());
+
+        let mut pipe = redis::pipe();
+        let mut entries = Vec::with_capacity(networks.len());
+        for network in networks {
+            let network = network.trunc();
+            let entry = BlockEntry {
+                target: network.to_string(),
+                reason: reason.to_string(),
+                source: source.to_string(),
+                created_at: now,
+                expires_at,
+            };
+            let key = self.entry_key(&entry.target);
+            let json = serde_json::to_string(&entry)?;
+            match duration {
+                Some(duration) => pipe.cmd("SET").arg(&key).arg(json).arg("EX").arg(duration.as_secs().max(1)),
+                None => pipe.cmd("SET").arg(&key).arg(json),
+            }
+            .ignore();
+            pipe.cmd("ZADD").arg(self.key(BLOCKLIST_INDEX_KEY)).arg(&score).arg(&entry.target).ignore();
+            entries.push((network, entry));
+        }
+
+        let mut conn = self.redis.get();
+        let _: () = pipe.query_async(&mut conn).await?;
+
+        let mut trie = self.entries.write().await;
+        for (network, entry) in entries {
+            trie.insert(network, entry);
+        }
+        metrics::counter!("blocklist_blocks_total", networks.len() as u64, "source" => source.to_string());
+
+        Ok(())
+    }
+
+    /// Block a target flagged by the DDoS detector for the configured duration
+    ///
+    /// Once enough addresses of one subnet have been blocked within that
+    /// duration, the whole subnet is blocked as well.
+    pub async fn block_detected(&self, target: &str, reason: &str) -> Result<BlockEntry, BlocklistError> {
+        let duration = Duration::from_secs(self.config.detector_block_seconds);
+        let entry = self.block(target, reason, DETECTOR_SOURCE, Some(duration)).await?;
+
+        let threshold = self.config.subnet_escalation_threshold;
+        let addr = match target.parse::<IpAddr>() {
+            Ok(addr) if threshold > 0 => addr,
+            _ => return Ok(entry),
+        };
+
+        let subnet = self.subnets.subnet_of(addr);
+        if subnet == IpNet::from(addr) {
+            return Ok(entry);
+        }
+
+        let offenders_key = self.key(&format_rate_limit_key("blocklist:subnet_offenders", &subnet.to_string()));
+        let mut conn = self.redis.get();
+        let (offenders,): (u32,) = redis::pipe()
+            .atomic()
+            .cmd("SADD")
+            .arg(&offenders_key)
+            .arg(addr.to_string())
+            .ignore()
+            .cmd("SCARD")
+            .arg(&offenders_key)
+            .cmd("EXPIRE")
+            .arg(&offenders_key)
+            .arg(self.config.detector_block_seconds)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        if offenders >= threshold {
+            let reason = format!("{} addresses in subnet blocked", offenders);
+            self.block(&subnet.to_string(), &reason, DETECTOR_SOURCE, Some(duration)).await?;
+        }
+
+        Ok(entry)
+    }
+
+    /// Lift the blocks the DDoS detector added for a target, returning the
+    /// targets unblocked
+    ///
+    /// The target no longer counts towards blocking its subnet, and the
+    /// subnet's block is lifted too once too few offenders remain. Blocks
+    /// added by anything but the detector are kept.
+    pub async fn unblock_detected(&self, target: &str) -> Result<Vec<String>, BlocklistError> {
+        let network = parse_network(target)
+            .ok_or_else(|| BlocklistError::InvalidTarget(target.to_string()))?;
+        let mut targets = vec![network];
+
+        let threshold = self.config.subnet_escalation_threshold;
+        if let Ok(addr) = target.parse::<IpAddr>() {
+            let subnet = self.subnets.subnet_of(addr);
+            if threshold > 0 && subnet != IpNet::from(addr) {
+                let offenders_key = self.key(&format_rate_limit_key("blocklist:subnet_offenders", &subnet.to_string()));
+                let mut conn = self.redis.get();
+                let (offenders,): (u32,) = redis::pipe()
+                    .atomic()
+                    .cmd("SREM")
+                    .arg(&offenders_key)
+                    .arg(addr.to_string())
+                    .ignore()
+                    .cmd("SCARD")
+                    .arg(&offenders_key)
+                    .query_async(&mut conn)
+                    .await?;
+                if offenders < threshold {
+                    targets.push(subnet);
+                }
+            }
+        }
+
+        let mut unblocked = Vec::new();
+        for network in targets {
+            let target = network.to_string();
+            let detected = self
+                .get_entry(&target)
+                .await?
+                .is_some_and(|entry| entry.source == DETECTOR_SOURCE);
+            if detected && self.unblock(&target).await? {
+                unblocked.push(target);
+            }
+        }
+        Ok(unblocked)
+    }
+
+    /// Default duration for blocks added through the API
+    pub fn default_duration(&self) -> Option<Duration> {
+        match self.config.default_duration_seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// Get the entry blocking exactly this target, if any
+    pub async fn get_entry(&self, target: &str) -> Result<Option<BlockEntry>, BlocklistError> {
+        let network = parse_network(target)
+            .ok_or_else(|| BlocklistError::InvalidTarget(target.to_string()))?;
+        let mut conn = self.redis.get();
+        let json: Option<String> = redis::cmd("GET")
+            .arg(self.entry_key(&network.to_string()))
+            .query_async(&mut conn)
+            .await?;
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Remove a block, returning whether it existed
+    pub async fn unblock(&self, target: &str) -> Result<bool, BlocklistError> {
+        let network = parse_network(target)
+            .ok_or_else(|| BlocklistError::InvalidTarget(target.to_string()))?;
+        let target = network.to_string();
+
+        let mut conn = self.redis.get();
+        let (removed, _): (u32, u32) = redis::pipe()
+            .atomic()
+            .cmd("DEL")
+            .arg(self.entry_key(&target))
+            .cmd("ZREM")
+            .arg(self.key(BLOCKLIST_INDEX_KEY))
+            .arg(&target)
+            .query_async(&mut conn)
+            .await?;
+
+        self.entries.write().await.remove(&network);
+        Ok(removed > 0)
+    }
+
+    /// Check whether an IP is currently blocked
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(BlockEntry))` with the entry explaining the block
+    /// * `Ok(None)` if the IP isn't blocked or the blocklist is disabled
+    pub async fn check(&self, ip: &str) -> Result<Option<BlockEntry>, BlocklistError> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+
+        let addr = match normalize_ip(ip) {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+
+        if let Some(policy) = self.outage_policy(None).await {
+            return Ok(self.check_without_redis(addr, policy).await);
+        }
+        let mut conn = self.redis.get();
+        let json: Option<String> = match redis::cmd("GET")
+            .arg(self.entry_key(&IpNet::from(addr).to_string()))
+            .query_async(&mut conn)
+            .await
+        {
+            Ok(json) => json,
+            Err(e) => match self.outage_policy(Some(&e)).await {
+                Some(policy) => return Ok(self.check_without_redis(addr, policy).await),
+                None => return Err(e.into()),
+            },
+        };
+        if let Some(json) = json {
+            return Ok(Some(serde_json::from_str(&json)?));
+        }
+
+        Ok(self.cached_block(addr).await)
+    }
+
+    /// Check an IP while Redis is down
+    ///
+    /// With the fallback policy, the in-memory copy answers: entries as of
+    /// the last refresh, plus the blocks added since.
+    async fn check_without_redis(&self, addr: IpAddr, policy: FailurePolicy) -> Option<BlockEntry> {
+        match policy {
+            FailurePolicy::Fallback => self.cached_block(addr).await,
+            FailurePolicy::Open => None,
+            FailurePolicy::Closed => Some(BlockEntry {
+                target: IpNet::from(addr).to_string(),
+                reason: "Blocklist unavailable".to_string(),
+                source: "degradation".to_string(),
+                created_at: Utc::now(),
+                expires_at: None,
+            }),
+        }
+    }
+
+    /// Most specific active block of an IP in the in-memory copy
+    async fn cached_block(&self, addr: IpAddr) -> Option<BlockEntry> {
+        let now = Utc::now();
+        let entries = self.entries.read().await;
+        entries
+            .matches(addr)
+            .into_iter()
+            .rev()
+            .find(|(_, entry)| !entry.is_expired(now))
+            .map(|(_, entry)| entry.clone())
+    }
+
+    /// Get all active entries, pruning expired ones from the index
+    pub async fn get_entries(&self) -> Result<Vec<BlockEntry>, BlocklistError> {
+        let mut conn = self.redis.get();
+        let (targets,): (Vec<String>,) = redis::pipe()
+            .cmd("ZREMRANGEBYSCORE")
+            .arg(self.key(BLOCKLIST_INDEX_KEY))
+            .arg("-inf")
+            .arg(Utc::now().timestamp())
+            .ignore()
+            .cmd("ZRANGE")
+            .arg(self.key(BLOCKLIST_INDEX_KEY))
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut conn)
+            .await?;
+
+        if targets.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let entries_json: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(targets.iter().map(|target| self.entry_key(target)).collect::<Vec<_>>())
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(entries_json
+            .into_iter()
+            .flatten()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect())
+    }
+
+    /// Rebuild the in-memory copy from Redis
+    #[tracing::instrument(name = "blocklist.reload", skip_all)]
+    pub async fn reload(&self) -> Result<(), BlocklistError> {
+        let mut entries = PrefixTrie::new();
+        for entry in self.get_entries().await? {
+            if let Some(network) = parse_network(&entry.target) {
+                entries.insert(network, entry);
+            }
+        }
+
+        *self.entries.write().await = entries;
+        Ok(())
+    }
+
+    /// How often the in-memory copy is reloaded
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.config.refresh_interval_seconds.max(1))
+    }
+
+    /// Periodically reload entries so ranges blocked on other instances are picked up
+    pub async fn start_refresh(&self) -> Result<(), BlocklistError> {
+        let interval = self.refresh_interval();
+        loop {
+            if let Err(e) = self.reload().await {
+                log::error!("Failed to reload blocklist: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_entry_expiry() {
+        let now = Utc::now();
+        let mut entry = BlockEntry {
+            target: "10.0.0.0/24".to_string(),
+            reason: "test".to_string(),
+            source: "api".to_string(),
+            created_at: now,
+            expires_at: None,
+        };
+        assert!(!entry.is_expired(now));
+
+        entry.expires_at = Some(now + chrono::Duration::seconds(60));
+        assert!(!entry.is_expired(now));
+        assert!(entry.is_expired(now + chrono::Duration::seconds(60)));
+    }
+}
@@ -0,0 +1,342 @@
+//! Bot detection for the DDoS protection service.
+//!
+//! This module scores how likely a request is to come from a bot, from 0
+//! (a browser) to 100 (certainly automated), based on the user agent, how
+//! complete and well-ordered the headers are, and the client's request
+//! rate. Clients claiming to be search engine crawlers are verified with a
+//! reverse DNS lookup confirmed by a forward lookup: verified crawlers
+//! score 0 and impersonators score 100.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use crate::core::redis_pool::RedisPool;
+use crate::core::rule_engine::RequestContext;
+use crate::models::BotDetectionConfig;
+use crate::utils::normalize_ip;
+
+/// Search engine crawlers, by user agent token, and the domains their
+/// addresses reverse-resolve to
+const KNOWN_BOTS: &[(&str, &[&str])] = &[
+    ("googlebot", &["googlebot.com", "google.com", "googleusercontent.com"]),
+    ("bingbot", &["search.msn.com"]),
+    ("applebot", &["applebot.apple.com"]),
+    ("duckduckbot", &["duckduckgo.com"]),
+    ("yandexbot", &["yandex.ru", "yandex.net", "yandex.com"]),
+    ("baiduspider", &["baidu.com", "baidu.jp"]),
+];
+
+/// User agent tokens of HTTP libraries and automation tools
+const AUTOMATION_TOKENS: &[&str] = &[
+    "curl", "wget", "python-requests", "python-urllib", "aiohttp", "go-http-client", "java/", "okhttp",
+    "libwww-perl", "httpclient", "scrapy", "headlesschrome", "phantomjs", "selenium", "puppeteer",
+];
+
+/// User agent tokens of self-declared bots
+const BOT_TOKENS: &[&str] = &["bot", "crawler", "spider", "scraper"];
+
+/// How likely a request is to come from a bot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BotScore {
+    /// Score from 0 (a browser) to 100 (certainly automated)
+    pub score: u8,
+    /// Crawler the client was verified as, if any
+    pub verified_bot: Option<String>,
+    /// Signals that contributed to the score
+    pub signals: Vec<String>,
+}
+
+/// Crawler a user agent claims to be, with its domains
+fn claimed_bot(user_agent: &str) -> Option<(&'static str, &'static [&'static str])> {
+    let user_agent = user_agent.to_ascii_lowercase();
+    KNOWN_BOTS.iter().copied().find(|(token, _)| user_agent.contains(token))
+}
+
+/// Whether a host name is one of the domains or under them
+fn in_domains(host: &str, domains: &[&str]) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    domains
+        .iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+}
+
+/// Score a request from its user agent and headers alone
+///
+/// Returns the score and the signals that contributed to it.
+fn score_headers(request: &RequestContext) -> (u32, Vec<String>) {
+    let mut score = 0;
+    let mut signals = Vec::new();
+    let mut signal = |points: u32, name: &str| {
+        score += points;
+        signals.push(name.to_string());
+    };
+
+    let user_agent = request.header("User-Agent").unwrap_or_default().to_ascii_lowercase();
+    if user_agent.is_empty() {
+        signal(40, "missing_user_agent");
+    } else if AUTOMATION_TOKENS.iter().any(|token| user_agent.contains(token)) {
+        signal(50, "automation_user_agent");
+    } else if BOT_TOKENS.iter().any(|token| user_agent.contains(token)) {
+        signal(40, "bot_user_agent");
+    }
+
+    for (header, points) in [("Accept", 10), ("Accept-Language", 15), ("Accept-Encoding", 10)] {
+        if request.header(header).is_none() {
+            signal(points, &format!("missing_{}", header.to_ascii_lowercase().replace('-', "_")));
+        }
+    }
+
+    // Browsers send Host first and User-Agent, Accept and Accept-Encoding in
+    // that order; HTTP libraries claiming to be browsers usually don't
+    if user_agent.starts_with("mozilla/") && !request.header_order.is_empty() {
+        let position = |name: &str| {
+            request
+                .header_order
+                .iter()
+                .position(|header| header.eq_ignore_ascii_case(name))
+        };
+        let in_order = |first: &str, second: &str| match (position(first), position(second)) {
+            (Some(first), Some(second)) => first < second,
+            _ => true,
+        };
+        let host_first = position("Host").is_none_or(|host| host == 0);
+        if !host_first || !in_order("User-Agent", "Accept") || !in_order("Accept", "Accept-Encoding") {
+            signal(20, "browser_header_order");
+        }
+    }
+
+    (score, signals)
+}
+
+/// Reverse-resolve an address through the system resolver
+pub(crate) fn reverse_lookup(addr: IpAddr) -> Option<String> {
+    let mut host = [0 as libc::c_char; 1025];
+    // SAFETY: the socket addresses are fully initialized and outlive the
+    // call, and getnameinfo writes at most `host.len()` bytes, NUL-terminated
+    let result = unsafe {
+        match addr {
+            IpAddr::V4(addr) => {
+                let mut socket: libc::sockaddr_in = std::mem::zeroed();
+                socket.sin_family = libc::AF_INET as libc::sa_family_t;
+                socket.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.octets()) };
+                libc::getnameinfo(
+                    &socket as *const libc::sockaddr_in as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    host.as_mut_ptr(),
+                    host.len() as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+            IpAddr::V6(addr) => {
+                let mut socket: libc::sockaddr_in6 = std::mem::zeroed();
+                socket.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                socket.sin6_addr = libc::in6_addr { s6_addr: addr.octets() };
+                libc::getnameinfo(
+                    &socket as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    host.as_mut_ptr(),
+                    host.len() as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+        }
+    };
+    if result != 0 {
+        return None;
+    }
+    // SAFETY: getnameinfo succeeded, so `host` holds a NUL-terminated name
+    let host = unsafe { CStr::from_ptr(host.as_ptr()) };
+    host.to_str().ok().map(str::to_string)
+}
+
+/// Bot scorer with a shared crawler verification cache
+///
+/// Cloning is cheap and all clones share the cache.
+#[derive(Clone)]
+pub struct BotDetector {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Bot detection configuration
+    config: BotDetectionConfig,
+    /// Crawler verifications keyed by crawler and IP, with when they expire
+    cache: Arc<RwLock<HashMap<String, (bool, Instant)>>>,
+}
+
+impl BotDetector {
+    /// Create a new bot detector
+    pub fn new(redis: RedisPool, config: BotDetectionConfig) -> Self {
+        Self {
+            redis,
+            config,
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Score a request, or return `None` if bot detection is disabled
+    ///
+    /// The client's request is counted towards its rate, so each request
+    /// should be scored once. Failed rate lookups only leave the rate out
+    /// of the score.
+    pub async fn score(&self, request: &RequestContext) -> Option<BotScore> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let user_agent = request.header("User-Agent").unwrap_or_default();
+        if let Some((name, domains)) = claimed_bot(user_agent) {
+            return Some(match normalize_ip(&request.ip) {
+                Some(addr) if self.verify(addr, name, domains).await => BotScore {
+                    score: 0,
+                    verified_bot: Some(name.to_string()),
+                    signals: vec!["verified_bot".to_string()],
+                },
+                _ => {
+                    metrics::increment_counter!("bot_impersonations_total", "bot" => name);
+                    BotScore {
+                        score: 100,
+                        verified_bot: None,
+                        signals: vec!["unverified_bot".to_string()],
+                    }
+                }
+            });
+        }
+
+        let (mut score, mut signals) = score_headers(request);
+        match self.count_request(&request.ip).await {
+            Ok(requests) => {
+                let threshold = self.config.request_rate_threshold.max(1);
+                if requests * 2 > threshold {
+                    score += (20 * requests.min(threshold) / threshold) as u32;
+                    signals.push("request_rate".to_string());
+                }
+                // Browsers keep cookies the site sets; most bots don't
+                if requests > 3 && request.header("Cookie").is_none() {
+                    score += 10;
+                    signals.push("no_cookies".to_string());
+                }
+            }
+            Err(e) => log::error!("Failed to count requests of {} for bot detection: {}", request.ip, e),
+        }
+
+        Some(BotScore {
+            score: score.min(100) as u8,
+            verified_bot: None,
+            signals,
+        })
+    }
+
+    /// Count a request from the client, returning its requests in the current window
+    async fn count_request(&self, ip: &str) -> Result<u64, redis::RedisError> {
+        let window = self.config.window.max(1);
+        let key = format!("bot:requests:{}:{}", ip, Utc::now().timestamp() as u64 / window as u64);
+        let mut conn = self.redis.get();
+        let (requests,): (u64,) = redis::pipe()
+            .cmd("INCR").arg(&key)
+            .cmd("EXPIRE").arg(&key).arg(window).ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(requests)
+    }
+
+    /// Whether the address belongs to the crawler
+    ///
+    /// The address must reverse-resolve to a name under the crawler's
+    /// domains, and that name must resolve back to the address. Lookups
+    /// that fail or time out fail verification and aren't cached.
+    async fn verify(&self, addr: IpAddr, name: &'static str, domains: &[&str]) -> bool {
+        let key = format!("{}:{}", name, addr);
+        if let Some((verified, expires_at)) = self.cache.read().await.get(&key) {
+            if *expires_at > Instant::now() {
+                return *verified;
+            }
+        }
+
+        let timeout = Duration::from_millis(self.config.verify_timeout_ms);
+        let lookup = async {
+            let host = tokio::task::spawn_blocking(move || reverse_lookup(addr)).await.ok()?;
+            let host = match host {
+                Some(host) if in_domains(&host, domains) => host,
+                // A name outside the domains, or none at all, is a definite answer
+                _ => return Some(false),
+            };
+            let mut addrs = tokio::net::lookup_host((host.as_str(), 0)).await.ok()?;
+            Some(addrs.any(|resolved| resolved.ip() == addr))
+        };
+        let verified = match tokio::time::timeout(timeout, lookup).await {
+            Ok(Some(verified)) => verified,
+            Ok(None) => return false,
+            Err(_) => {
+                metrics::increment_counter!("bot_verification_timeouts_total", "bot" => name);
+                return false;
+            }
+        };
+
+        let mut cache = self.cache.write().await;
+        if cache.len() >= self.config.max_cache_entries {
+            let now = Instant::now();
+            cache.retain(|_, (_, expires_at)| *expires_at > now);
+            if cache.len() >= self.config.max_cache_entries {
+                cache.clear();
+            }
+        }
+        let ttl = Duration::from_secs(self.config.cache_ttl_seconds);
+        cache.insert(key, (verified, Instant::now() + ttl));
+        verified
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestContext {
+        RequestContext {
+            ip: "192.0.2.1".to_string(),
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            header_order: headers.iter().map(|(name, _)| name.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_score_headers() {
+        let browser = [
+            ("Host", "example.com"),
+            ("User-Agent", "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0"),
+            ("Accept", "text/html"),
+            ("Accept-Language", "en-US"),
+            ("Accept-Encoding", "gzip, br"),
+        ];
+        assert_eq!(score_headers(&request(&browser)), (0, Vec::new()));
+
+        // The same headers sent by an HTTP library in its own order
+        let mut reordered = browser;
+        reordered.swap(2, 4);
+        assert_eq!(score_headers(&request(&reordered)), (20, vec!["browser_header_order".to_string()]));
+
+        let (score, signals) = score_headers(&request(&[("User-Agent", "curl/8.5.0"), ("Accept", "*/*")]));
+        assert_eq!(score, 75);
+        assert_eq!(signals, vec!["automation_user_agent", "missing_accept_language", "missing_accept_encoding"]);
+        assert_eq!(score_headers(&request(&[])).0, 75);
+    }
+
+    #[test]
+    fn test_claimed_bot() {
+        let user_agent = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        let (name, domains) = claimed_bot(user_agent).unwrap();
+        assert_eq!(name, "googlebot");
+        assert!(in_domains("crawl-66-249-66-1.googlebot.com.", domains));
+        assert!(!in_domains("googlebot.com.attacker.example", domains));
+        assert!(!in_domains("notgooglebot.com", domains));
+        assert!(claimed_bot("Mozilla/5.0 Firefox/128.0").is_none());
+    }
+}
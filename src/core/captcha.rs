@@ -0,0 +1,153 @@
+//! CAPTCHA verification for the DDoS protection service.
+//!
+//! This module renders the widget of the configured CAPTCHA provider
+//! (Cloudflare Turnstile, hCaptcha or reCAPTCHA) on challenge pages and
+//! verifies the tokens it produces with the provider's API. All three
+//! providers share the same `siteverify` protocol.
+
+use std::time::Duration;
+use serde::Deserialize;
+use thiserror::Error;
+use crate::models::{CaptchaConfig, CaptchaProvider};
+
+/// Errors that can occur while verifying a CAPTCHA token
+#[derive(Error, Debug)]
+pub enum CaptchaError {
+    #[error("Request error: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("No CAPTCHA secret configured")]
+    MissingSecret,
+}
+
+impl CaptchaProvider {
+    /// Script rendering the widget
+    pub fn script_url(&self) -> &'static str {
+        match self {
+            Self::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/api.js",
+            Self::Hcaptcha => "https://js.hcaptcha.com/1/api.js",
+            Self::Recaptcha => "https://www.google.com/recaptcha/api.js",
+        }
+    }
+
+    /// Class of the element the script turns into a widget
+    pub fn widget_class(&self) -> &'static str {
+        match self {
+            Self::Turnstile => "cf-turnstile",
+            Self::Hcaptcha => "h-captcha",
+            Self::Recaptcha => "g-recaptcha",
+        }
+    }
+
+    /// Endpoint verifying tokens
+    pub fn verify_url(&self) -> &'static str {
+        match self {
+            Self::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            Self::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+            Self::Recaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Turnstile => "turnstile",
+            Self::Hcaptcha => "hcaptcha",
+            Self::Recaptcha => "recaptcha",
+        }
+    }
+}
+
+/// Provider answer to a verification
+#[derive(Debug, Deserialize)]
+struct VerifyResponse {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+/// CAPTCHA client verifying tokens with the provider
+///
+/// Cloning is cheap; clones share the HTTP client.
+#[derive(Clone)]
+pub struct Captcha {
+    /// HTTP client
+    client: reqwest::Client,
+    /// CAPTCHA configuration
+    config: CaptchaConfig,
+}
+
+impl Captcha {
+    /// Create a new CAPTCHA client
+    pub fn new(config: CaptchaConfig) -> Result<Self, CaptchaError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()?;
+        Ok(Self { client, config })
+    }
+
+    /// Widget HTML, with a script submitting the enclosing form once solved
+    pub fn widget(&self, form_id: &str) -> String {
+        format!(
+            r#"<div class="{class}" data-sitekey="{site_key}" data-callback="captchaSolved"></div>
+<script>function captchaSolved() {{ document.getElementById("{form_id}").submit(); }}</script>
+<script src="{script}" async defer></script>"#,
+            class = self.config.provider.widget_class(),
+            site_key = self.config.site_key.replace('"', ""),
+            form_id = form_id,
+            script = self.config.provider.script_url(),
+        )
+    }
+
+    /// Verify a token the widget produced for the client, returning whether it is valid
+    pub async fn verify(&self, token: &str, ip: &str) -> Result<bool, CaptchaError> {
+        if self.config.secret.is_empty() {
+            return Err(CaptchaError::MissingSecret);
+        }
+        if token.is_empty() {
+            return Ok(false);
+        }
+        let response: VerifyResponse = self
+            .client
+            .post(self.config.provider.verify_url())
+            .form(&[
+                ("secret", self.config.secret.as_str()),
+                ("response", token),
+                ("remoteip", ip),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if !response.success {
+            log::debug!(
+                "{} rejected CAPTCHA token from {}: {}",
+                self.config.provider.as_str(),
+                ip,
+                response.error_codes.join(", ")
+            );
+        }
+        Ok(response.success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_captcha() {
+        let captcha = Captcha::new(CaptchaConfig {
+            provider: CaptchaProvider::Hcaptcha,
+            site_key: "site-key".to_string(),
+            ..CaptchaConfig::default()
+        })
+        .unwrap();
+        let widget = captcha.widget("challenge");
+        assert!(widget.contains(r#"<div class="h-captcha" data-sitekey="site-key""#));
+        assert!(widget.contains("https://js.hcaptcha.com/1/api.js"));
+        assert!(widget.contains(r#"document.getElementById("challenge")"#));
+
+        // Tokens can't be verified without a secret
+        assert!(matches!(captcha.verify("token", "192.0.2.1").await, Err(CaptchaError::MissingSecret)));
+    }
+}
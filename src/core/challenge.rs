@@ -0,0 +1,355 @@
+//! Challenges for the DDoS protection service.
+//!
+//! This module issues the challenges served to clients that rules or the
+//! DDoS detector want challenged rather than blocked: a page whose
+//! JavaScript solves a proof of work (in cookie mode, simply resubmits; in
+//! CAPTCHA mode, shows a CAPTCHA) and receives a signed cookie that
+//! exempts the client from further challenges for a while. Challenges and
+//! cookies are signed with HMAC-SHA256 and bound to the client IP, so
+//! nothing is stored and a cookie solved by one client can't be shared
+//! with a botnet.
+
+use chrono::Utc;
+use thiserror::Error;
+use uuid::Uuid;
+use crate::core::captcha::Captcha;
+use crate::models::{ChallengeConfig, ChallengeMode};
+use crate::utils::{constant_time_eq, hex, hmac_sha256, html_escape, sha256};
+
+/// Path of the endpoint challenge pages submit solutions to
+pub const VERIFY_PATH: &str = "/api/v1/challenge/verify";
+
+/// Errors that can occur verifying a challenge solution
+#[derive(Error, Debug, PartialEq)]
+pub enum ChallengeError {
+    #[error("Malformed challenge")]
+    Malformed,
+    #[error("Invalid challenge signature")]
+    InvalidSignature,
+    #[error("Challenge expired")]
+    Expired,
+    #[error("Invalid solution")]
+    InvalidSolution,
+    #[error("CAPTCHA failed")]
+    CaptchaFailed,
+    #[error("CAPTCHA verification error: {0}")]
+    CaptchaError(String),
+}
+
+impl ChallengeError {
+    /// Label for metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Malformed => "malformed",
+            Self::InvalidSignature => "invalid_signature",
+            Self::Expired => "expired",
+            Self::InvalidSolution => "invalid_solution",
+            Self::CaptchaFailed => "captcha_failed",
+            Self::CaptchaError(_) => "captcha_error",
+        }
+    }
+}
+
+/// A challenge issued to a client
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    /// Signed challenge, `expires.difficulty.salt.signature`
+    pub token: String,
+    /// Leading zero bits the hash of `token:nonce` must have
+    pub difficulty: u8,
+}
+
+/// Number of leading zero bits of a hash
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Value of a cookie in a `Cookie` header
+pub fn cookie_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Where to send a client after a passed challenge
+///
+/// Only paths on the same site are allowed, so challenge pages can't be
+/// used as open redirects. Whitespace and control characters are refused
+/// too, as browsers strip them and could read `/\t/host` as `//host`.
+pub fn safe_return_to(return_to: &str) -> &str {
+    let path_only = return_to.starts_with('/')
+        && !return_to.starts_with("//")
+        && !return_to.chars().any(|c| c == '\\' || c.is_whitespace() || c.is_control());
+    if path_only {
+        return_to
+    } else {
+        "/"
+    }
+}
+
+/// Issues and verifies challenges
+///
+/// Cloning is cheap; clones share the configuration and secret.
+#[derive(Clone)]
+pub struct ChallengeManager {
+    /// Challenge configuration
+    config: ChallengeConfig,
+    /// Key signing challenges and cookies
+    secret: Vec<u8>,
+    /// CAPTCHA provider client, for `captcha` mode
+    captcha: Option<Captcha>,
+}
+
+impl ChallengeManager {
+    /// Create a new challenge manager
+    pub fn new(config: ChallengeConfig) -> Self {
+        let secret = if config.secret.is_empty() {
+            log::warn!("No challenge secret configured; passes won't be accepted by other instances or after a restart");
+            format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()).into_bytes()
+        } else {
+            config.secret.clone().into_bytes()
+        };
+        Self {
+            config,
+            secret,
+            captcha: None,
+        }
+    }
+
+    /// Verify CAPTCHAs in `captcha` mode with the given client
+    pub fn with_captcha(mut self, captcha: Captcha) -> Self {
+        self.captcha = Some(captcha);
+        self
+    }
+
+    /// How clients pass challenges
+    pub fn mode(&self) -> ChallengeMode {
+        self.config.mode
+    }
+
+    /// Name of the cookie proving a passed challenge
+    pub fn cookie_name(&self) -> &str {
+        &self.config.cookie_name
+    }
+
+    /// How long a passed challenge exempts the client, in seconds
+    pub fn pass_duration(&self) -> u64 {
+        self.config.pass_duration_seconds
+    }
+
+    fn sign(&self, message: &str) -> String {
+        hex(&hmac_sha256(&self.secret, message.as_bytes()))
+    }
+
+    /// Issue a challenge to a client
+    pub fn issue(&self, ip: &str) -> Challenge {
+        let difficulty = match self.config.mode {
+            ChallengeMode::ProofOfWork => self.config.difficulty.min(32),
+            ChallengeMode::Cookie | ChallengeMode::Captcha => 0,
+        };
+        let expires = Utc::now().timestamp() as u64 + self.config.challenge_ttl_seconds;
+        let payload = format!("{}.{}.{}", expires, difficulty, Uuid::new_v4().simple());
+        let signature = self.sign(&format!("challenge:{}:{}", ip, payload));
+        Challenge {
+            token: format!("{}.{}", payload, signature),
+            difficulty,
+        }
+    }
+
+    /// Verify a client's solution, returning the value of its pass cookie
+    ///
+    /// `captcha_response` is the token of the CAPTCHA widget, checked with
+    /// the provider in `captcha` mode.
+    pub async fn verify(
+        &self,
+        ip: &str,
+        token: &str,
+        nonce: &str,
+        captcha_response: &str,
+    ) -> Result<String, ChallengeError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(ChallengeError::Malformed)?;
+        let mut fields = payload.split('.');
+        let (expires, difficulty) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(expires), Some(difficulty), Some(_), None) => (expires, difficulty),
+            _ => return Err(ChallengeError::Malformed),
+        };
+        let expected = self.sign(&format!("challenge:{}:{}", ip, payload));
+        if !constant_time_eq(expected.as_bytes(), signature.as_bytes()) {
+            return Err(ChallengeError::InvalidSignature);
+        }
+        let expires: u64 = expires.parse().map_err(|_| ChallengeError::Malformed)?;
+        if expires < Utc::now().timestamp() as u64 {
+            return Err(ChallengeError::Expired);
+        }
+        let difficulty: u32 = difficulty.parse().map_err(|_| ChallengeError::Malformed)?;
+        if leading_zero_bits(&sha256(format!("{}:{}", token, nonce).as_bytes())) < difficulty {
+            return Err(ChallengeError::InvalidSolution);
+        }
+        if self.config.mode == ChallengeMode::Captcha {
+            let captcha = self
+                .captcha
+                .as_ref()
+                .ok_or_else(|| ChallengeError::CaptchaError("no CAPTCHA client".to_string()))?;
+            match captcha.verify(captcha_response, ip).await {
+                Ok(true) => (),
+                Ok(false) => return Err(ChallengeError::CaptchaFailed),
+                Err(e) => return Err(ChallengeError::CaptchaError(e.to_string())),
+            }
+        }
+
+        let expires = Utc::now().timestamp() as u64 + self.config.pass_duration_seconds;
+        Ok(format!("{}.{}", expires, self.sign(&format!("pass:{}:{}", ip, expires))))
+    }
+
+    /// Whether the client's cookies hold an unexpired pass issued to it
+    pub fn has_passed(&self, ip: &str, cookie_header: Option<&str>) -> bool {
+        let pass = match cookie_header.and_then(|header| cookie_value(header, &self.config.cookie_name)) {
+            Some(pass) => pass,
+            None => return false,
+        };
+        let (expires, signature) = match pass.split_once('.') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let expected = self.sign(&format!("pass:{}:{}", ip, expires));
+        constant_time_eq(expected.as_bytes(), signature.as_bytes())
+            && expires.parse::<u64>().is_ok_and(|expires| expires >= Utc::now().timestamp() as u64)
+    }
+
+    /// HTML page solving a challenge and submitting the solution
+    ///
+    /// The page needs a secure context (HTTPS) for the Web Crypto API.
+    pub fn page(&self, challenge: &Challenge, return_to: &str) -> String {
+        let solver = match (self.config.mode, &self.captcha) {
+            (ChallengeMode::Captcha, Some(captcha)) => captcha.widget("challenge"),
+            _ => proof_of_work_script(challenge.difficulty),
+        };
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="robots" content="noindex">
+<title>Checking your browser</title>
+</head>
+<body>
+<p>Checking your browser before continuing&hellip;</p>
+<noscript><p>JavaScript is required to continue.</p></noscript>
+<form id="challenge" method="post" action="{action}">
+<input type="hidden" name="token" value="{token}">
+<input type="hidden" name="nonce" value="">
+<input type="hidden" name="return_to" value="{return_to}">
+</form>
+{solver}
+</body>
+</html>
+"#,
+            action = VERIFY_PATH,
+            token = html_escape(&challenge.token),
+            return_to = html_escape(safe_return_to(return_to)),
+            solver = solver,
+        )
+    }
+}
+
+/// Script finding the proof-of-work nonce and submitting the challenge form
+fn proof_of_work_script(difficulty: u8) -> String {
+    format!(
+        r#"<script>
+(async () => {{
+  const form = document.getElementById("challenge");
+  const encoder = new TextEncoder();
+  for (let nonce = 0; ; nonce++) {{
+    const hash = new Uint8Array(await crypto.subtle.digest("SHA-256", encoder.encode(form.token.value + ":" + nonce)));
+    let bits = 0;
+    for (const byte of hash) {{
+      bits += Math.clz32(byte) - 24;
+      if (byte !== 0) break;
+    }}
+    if (bits >= {difficulty}) {{
+      form.nonce.value = nonce;
+      form.submit();
+      return;
+    }}
+  }}
+}})();
+</script>"#,
+        difficulty = difficulty,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solve(challenge: &Challenge) -> String {
+        (0u64..)
+            .find(|nonce| {
+                let hash = sha256(format!("{}:{}", challenge.token, nonce).as_bytes());
+                leading_zero_bits(&hash) >= challenge.difficulty as u32
+            })
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_challenge() {
+        let manager = ChallengeManager::new(ChallengeConfig {
+            secret: "secret".to_string(),
+            difficulty: 8,
+            ..ChallengeConfig::default()
+        });
+        let challenge = manager.issue("192.0.2.1");
+        assert_eq!(challenge.difficulty, 8);
+        let nonce = solve(&challenge);
+
+        // Solutions only count for the client the challenge was issued to
+        assert_eq!(manager.verify("192.0.2.2", &challenge.token, &nonce, "").await, Err(ChallengeError::InvalidSignature));
+        let tampered = challenge.token.replacen(".8.", ".0.", 1);
+        assert_eq!(manager.verify("192.0.2.1", &tampered, "0", "").await, Err(ChallengeError::InvalidSignature));
+        assert_eq!(manager.verify("192.0.2.1", "token", "0", "").await, Err(ChallengeError::Malformed));
+        if leading_zero_bits(&sha256(format!("{}:x", challenge.token).as_bytes())) < 8 {
+            assert_eq!(manager.verify("192.0.2.1", &challenge.token, "x", "").await, Err(ChallengeError::InvalidSolution));
+        }
+
+        let pass = manager.verify("192.0.2.1", &challenge.token, &nonce, "").await.unwrap();
+        let cookie = format!("session=abc; ddos_pass={}", pass);
+        assert!(manager.has_passed("192.0.2.1", Some(&cookie)));
+        assert!(!manager.has_passed("192.0.2.2", Some(&cookie)));
+        assert!(!manager.has_passed("192.0.2.1", Some("ddos_pass=1.abc")));
+        assert!(!manager.has_passed("192.0.2.1", None));
+    }
+
+    #[tokio::test]
+    async fn test_page() {
+        assert_eq!(cookie_value("a=1; b=2", "b"), Some("2"));
+        assert_eq!(cookie_value("a=1", "b"), None);
+        assert_eq!(safe_return_to("/search?q=1"), "/search?q=1");
+        assert_eq!(safe_return_to("//evil.example"), "/");
+        assert_eq!(safe_return_to("https://evil.example"), "/");
+        assert_eq!(safe_return_to("/\t/evil.example"), "/");
+        assert_eq!(safe_return_to("/\n/evil.example"), "/");
+        assert_eq!(safe_return_to("/ /evil.example"), "/");
+        assert_eq!(safe_return_to("/\\evil.example"), "/");
+
+        let manager = ChallengeManager::new(ChallengeConfig {
+            mode: ChallengeMode::Cookie,
+            ..ChallengeConfig::default()
+        });
+        let challenge = manager.issue("192.0.2.1");
+        assert_eq!(challenge.difficulty, 0);
+        let page = manager.page(&challenge, "/a?b=\"<c>\"");
+        assert!(page.contains(&challenge.token));
+        assert!(page.contains("value=\"/a?b=&quot;&lt;c&gt;&quot;\""));
+        assert!(manager.verify("192.0.2.1", &challenge.token, "0", "").await.is_ok());
+    }
+}
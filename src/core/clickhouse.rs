@@ -0,0 +1,402 @@
+//! ClickHouse analytics backend for the DDoS protection service.
+//!
+//! With `analytics.storage_type = "clickhouse"`, events are inserted into a
+//! ClickHouse table by an event sink, in batches using asynchronous inserts,
+//! and event queries and report aggregations run in ClickHouse instead of
+//! reading the Redis event log, which only buffers the latest events.
+//!
+//! Everything goes through the HTTP interface. Values are passed as query
+//! parameters (`{start:Int64}`), never spliced into SQL.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use thiserror::Error;
+use crate::core::analytics::{Event, EventFilter, EventSummary, EventType, SECURITY_EVENTS};
+use crate::core::event_sink::{EventSink, SinkError};
+use crate::models::ClickHouseConfig;
+
+/// Most events an event query returns
+const MAX_EVENTS: usize = 100_000;
+
+/// Errors that can occur while talking to ClickHouse
+#[derive(Error, Debug)]
+pub enum ClickHouseError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("ClickHouse returned {status}: {message}")]
+    Server { status: u16, message: String },
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// An event as inserted into the events table
+#[derive(Debug, Serialize)]
+struct EventRow<'a> {
+    id: &'a str,
+    /// `YYYY-MM-DD hh:mm:ss.sss`, in UTC
+    timestamp: String,
+    event_type: String,
+    source: &'a str,
+    /// Client the event is about, or empty
+    client: &'a str,
+    /// Rule the event is about, or empty
+    rule_name: &'a str,
+    /// Event data as JSON
+    data: String,
+}
+
+impl<'a> EventRow<'a> {
+    fn new(event: &'a Event) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            id: &event.id,
+            timestamp: event.timestamp.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+            event_type: event.event_type.name(),
+            source: &event.source,
+            client: event.client().unwrap_or_default(),
+            rule_name: event.rule_name().unwrap_or_default(),
+            data: serde_json::to_string(&event.data)?,
+        })
+    }
+}
+
+/// An event as read from the events table
+#[derive(Debug, Deserialize)]
+struct StoredEvent {
+    id: String,
+    timestamp_ms: i64,
+    event_type: String,
+    source: String,
+    data: String,
+}
+
+impl StoredEvent {
+    /// The event, or `None` if its type or data isn't understood
+    fn into_event(self) -> Option<Event> {
+        Some(Event {
+            id: self.id,
+            timestamp: DateTime::from_timestamp_millis(self.timestamp_ms)?,
+            event_type: serde_json::from_value(serde_json::Value::String(self.event_type)).ok()?,
+            source: self.source,
+            data: serde_json::from_str(&self.data).ok()?,
+        })
+    }
+}
+
+/// A name and how many events have it
+#[derive(Debug, Deserialize)]
+struct Count {
+    name: String,
+    count: u64,
+}
+
+/// Condition selecting the events within a time range (in seconds, both
+/// ends included) that match a filter, with its query parameters
+fn event_conditions(start_time: u64, end_time: u64, filter: &EventFilter) -> (String, Vec<(&'static str, String)>) {
+    let mut sql = "timestamp >= toDateTime({start:UInt64}, 'UTC') AND timestamp < toDateTime({end:UInt64}, 'UTC') + 1".to_string();
+    let mut params = vec![("param_start", start_time.to_string()), ("param_end", end_time.to_string())];
+    if let Some(event_type) = &filter.event_type {
+        sql.push_str(" AND event_type = {event_type:String}");
+        params.push(("param_event_type", event_type.name()));
+    }
+    if let Some(network) = &filter.network {
+        sql.push_str(" AND (isIPv4String(client) OR isIPv6String(client)) AND isIPAddressInRange(client, {network:String})");
+        params.push(("param_network", network.to_string()));
+    }
+    if let Some(prefix) = &filter.path_prefix {
+        sql.push_str(" AND startsWith(JSONExtractString(data, 'path'), {path_prefix:String})");
+        params.push(("param_path_prefix", prefix.clone()));
+    }
+    if let Some(rule_id) = &filter.rule_id {
+        sql.push_str(" AND JSONExtractString(data, 'rule_id') = {rule_id:String}");
+        params.push(("param_rule_id", rule_id.clone()));
+    }
+    if let Some(country) = &filter.country {
+        sql.push_str(" AND upper(JSONExtractString(data, 'country')) = upper({country:String})");
+        params.push(("param_country", country.clone()));
+    }
+    if let Some(tenant) = &filter.tenant {
+        sql.push_str(" AND JSONExtractString(data, 'tenant') = {tenant:String}");
+        params.push(("param_tenant", tenant.clone()));
+    }
+    if let Some(text) = &filter.text {
+        sql.push_str(" AND (positionCaseInsensitiveUTF8(source, {text:String}) > 0 OR positionCaseInsensitiveUTF8(data, {text:String}) > 0)");
+        params.push(("param_text", text.clone()));
+    }
+    (sql, params)
+}
+
+/// How many rows a query counted
+#[derive(Debug, Deserialize)]
+struct Total {
+    count: u64,
+}
+
+/// Array literal of strings, for an `Array(String)` query parameter
+fn string_array(values: &[String]) -> String {
+    let quoted: Vec<String> = values
+        .iter()
+        .map(|value| format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")))
+        .collect();
+    format!("[{}]", quoted.join(","))
+}
+
+/// Backtick-quoted identifier
+fn identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+/// ClickHouse client for the events table
+///
+/// Cloning is cheap and all clones share the same HTTP client.
+#[derive(Clone)]
+pub struct ClickHouse {
+    client: reqwest::Client,
+    config: ClickHouseConfig,
+    /// Days events are kept
+    retention_days: u64,
+}
+
+impl ClickHouse {
+    /// Create a client for the configured events table, keeping events for `retention_days`
+    pub fn new(config: ClickHouseConfig, retention_days: u64) -> Result<Self, ClickHouseError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()?;
+        Ok(Self {
+            client,
+            config,
+            retention_days,
+        })
+    }
+
+    /// Latest events kept in the Redis event log
+    pub fn buffer_size(&self) -> usize {
+        self.config.buffer_size
+    }
+
+    fn table(&self) -> String {
+        format!("{}.{}", identifier(&self.config.database), identifier(&self.config.table))
+    }
+
+    /// Send a statement with the given URL parameters, returning the response body
+    async fn post(&self, params: &[(&str, String)], body: String) -> Result<String, ClickHouseError> {
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .query(params)
+            .header("X-ClickHouse-User", &self.config.user)
+            .body(body);
+        if let Some(password) = &self.config.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(ClickHouseError::Server {
+                status: status.as_u16(),
+                message: text.trim().to_string(),
+            });
+        }
+        Ok(text)
+    }
+
+    /// Run a query, returning its rows
+    async fn query<T: DeserializeOwned>(&self, sql: &str, mut params: Vec<(&str, String)>) -> Result<Vec<T>, ClickHouseError> {
+        params.push(("output_format_json_quote_64bit_integers", "0".to_string()));
+        let body = self.post(&params, format!("{} FORMAT JSONEachRow", sql)).await?;
+        body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(ClickHouseError::from))
+            .collect()
+    }
+
+    /// Check that ClickHouse answers
+    pub async fn ping(&self) -> Result<(), ClickHouseError> {
+        self.post(&[], "SELECT 1".to_string()).await.map(|_| ())
+    }
+
+    /// Create the events table if it doesn't exist
+    pub async fn create_table(&self) -> Result<(), ClickHouseError> {
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                id String,
+                timestamp DateTime64(3, 'UTC'),
+                event_type LowCardinality(String),
+                source LowCardinality(String),
+                client String,
+                rule_name String,
+                data String
+            ) ENGINE = MergeTree
+            PARTITION BY toDate(timestamp)
+            ORDER BY (event_type, timestamp)
+            TTL toDateTime(timestamp) + INTERVAL {} DAY",
+            self.table(),
+            self.retention_days.max(1)
+        );
+        self.post(&[], sql).await.map(|_| ())
+    }
+
+    /// Insert events
+    ///
+    /// ClickHouse buffers asynchronous inserts and writes them together, so
+    /// frequent small batches don't create a part each.
+    pub async fn insert(&self, events: &[Event]) -> Result<(), ClickHouseError> {
+        let mut body = String::new();
+        for event in events {
+            body.push_str(&serde_json::to_string(&EventRow::new(event)?)?);
+            body.push('\n');
+        }
+        let params = [
+            ("query", format!("INSERT INTO {} FORMAT JSONEachRow", self.table())),
+            ("async_insert", "1".to_string()),
+            ("wait_for_async_insert", "1".to_string()),
+        ];
+        self.post(&params, body).await.map(|_| ())
+    }
+
+    /// The first `limit` events within a time range (in seconds, both ends
+    /// included) that match a filter, oldest first
+    pub async fn events(&self, start_time: u64, end_time: u64, filter: &EventFilter, limit: usize) -> Result<Vec<Event>, ClickHouseError> {
+        let (conditions, mut params) = event_conditions(start_time, end_time, filter);
+        let sql = format!(
+            "SELECT id, toUnixTimestamp64Milli(timestamp) AS timestamp_ms, event_type, source, data FROM {}
+             WHERE {} ORDER BY timestamp LIMIT {{limit:UInt64}}",
+            self.table(),
+            conditions
+        );
+        params.push(("param_limit", limit.min(MAX_EVENTS).to_string()));
+
+        let rows: Vec<StoredEvent> = self.query(&sql, params).await?;
+        Ok(rows.into_iter().filter_map(StoredEvent::into_event).collect())
+    }
+
+    /// Count the events within a time range (in seconds, both ends included) that match a filter
+    pub async fn count_events(&self, start_time: u64, end_time: u64, filter: &EventFilter) -> Result<u64, ClickHouseError> {
+        let (conditions, params) = event_conditions(start_time, end_time, filter);
+        let sql = format!("SELECT count() AS count FROM {} WHERE {}", self.table(), conditions);
+        let rows: Vec<Total> = self.query(&sql, params).await?;
+        Ok(rows.first().map_or(0, |row| row.count))
+    }
+
+    /// Count the events recorded from `start` until before `end`, keeping the `top_clients` first clients
+    pub async fn summarize(&self, start: DateTime<Utc>, end: DateTime<Utc>, top_clients: usize) -> Result<EventSummary, ClickHouseError> {
+        let period = "timestamp >= fromUnixTimestamp64Milli({start:Int64}) AND timestamp < fromUnixTimestamp64Milli({end:Int64})";
+        let params = || {
+            vec![
+                ("param_start", start.timestamp_millis().to_string()),
+                ("param_end", end.timestamp_millis().to_string()),
+            ]
+        };
+        let security_events: Vec<String> = SECURITY_EVENTS.iter().map(EventType::name).collect();
+
+        let by_type: Vec<Count> = self
+            .query(
+                &format!("SELECT event_type AS name, count() AS count FROM {} WHERE {} GROUP BY name", self.table(), period),
+                params(),
+            )
+            .await?;
+
+        let mut client_params = params();
+        client_params.push(("param_security_events", string_array(&security_events)));
+        client_params.push(("param_limit", top_clients.to_string()));
+        let clients: Vec<Count> = self
+            .query(
+                &format!(
+                    "SELECT client AS name, count() AS count FROM {} WHERE {} AND client != ''
+                     AND event_type IN {{security_events:Array(String)}}
+                     GROUP BY name ORDER BY count DESC, name LIMIT {{limit:UInt64}}",
+                    self.table(),
+                    period
+                ),
+                client_params,
+            )
+            .await?;
+
+        let mut rule_params = params();
+        rule_params.push(("param_event_type", EventType::RuleTriggered.name()));
+        let rules: Vec<Count> = self
+            .query(
+                &format!(
+                    "SELECT rule_name AS name, count() AS count FROM {} WHERE {} AND rule_name != ''
+                     AND event_type = {{event_type:String}}
+                     GROUP BY name ORDER BY count DESC, name",
+                    self.table(),
+                    period
+                ),
+                rule_params,
+            )
+            .await?;
+
+        let pairs = |counts: Vec<Count>| counts.into_iter().map(|count| (count.name, count.count));
+        Ok(EventSummary {
+            events_by_type: pairs(by_type).collect::<BTreeMap<_, _>>(),
+            top_clients: pairs(clients).collect(),
+            rule_matches: pairs(rules).collect(),
+        })
+    }
+}
+
+/// Sink inserting events into ClickHouse
+pub struct ClickHouseSink {
+    clickhouse: ClickHouse,
+}
+
+impl ClickHouseSink {
+    pub fn new(clickhouse: ClickHouse) -> Self {
+        Self { clickhouse }
+    }
+}
+
+#[async_trait]
+impl EventSink for ClickHouseSink {
+    fn name(&self) -> &str {
+        "clickhouse"
+    }
+
+    async fn send(&self, events: &[Event]) -> Result<(), SinkError> {
+        self.clickhouse
+            .insert(events)
+            .await
+            .map_err(|e| SinkError::Protocol(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_event_rows() {
+        let data = HashMap::from([
+            ("ip".to_string(), serde_json::json!("203.0.113.7")),
+            ("rule_name".to_string(), serde_json::json!("block-scrapers")),
+        ]);
+        let mut event = Event::new(EventType::RuleTriggered, "rule_engine", data);
+        event.timestamp = DateTime::from_timestamp_millis(1_715_776_200_250).unwrap();
+
+        let row = serde_json::to_value(EventRow::new(&event).unwrap()).unwrap();
+        assert_eq!(row["timestamp"], "2024-05-15 12:30:00.250");
+        assert_eq!(row["event_type"], "RuleTriggered");
+        assert_eq!(row["client"], "203.0.113.7");
+        assert_eq!(row["rule_name"], "block-scrapers");
+
+        let stored = StoredEvent {
+            id: event.id.clone(),
+            timestamp_ms: 1_715_776_200_250,
+            event_type: "RuleTriggered".to_string(),
+            source: "rule_engine".to_string(),
+            data: row["data"].as_str().unwrap().to_string(),
+        };
+        let read = stored.into_event().unwrap();
+        assert_eq!((read.timestamp, read.event_type, read.data), (event.timestamp, event.event_type, event.data));
+
+        assert_eq!(string_array(&["a'b".to_string(), "c".to_string()]), r"['a\'b','c']");
+    }
+}
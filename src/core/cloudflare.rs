@@ -1,12 +1,45 @@
 //! Cloudflare API client for the DDoS protection service.
-//! 
+//!
 //! This module provides functionality to interact with the Cloudflare API,
-//! including retrieving zone information and managing DDoS protection settings.
+//! including retrieving zone information, managing DDoS protection settings
+//! and "Under Attack" mode, and blocking clients at the edge with IP Access
+//! Rules. The access rules created are kept in Redis with when they expire,
+//! so that any instance deletes them once their block ends.
 
+use std::sync::Arc;
 use std::time::Duration;
-use reqwest::Client;
+use async_trait::async_trait;
+use ipnet::IpNet;
+use reqwest::{Client, Method};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::sync::OnceCell;
+use crate::core::escalation::UpstreamMitigation;
+use crate::core::redis_pool::RedisPool;
+use crate::models::CloudflareConfig;
+use crate::utils::{get_current_timestamp, parse_network};
+
+/// Cloudflare API base URL
+const API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// Zones requested per page when listing zones
+const ZONES_PER_PAGE: u32 = 50;
+
+/// Security level of "Under Attack" mode
+const UNDER_ATTACK_LEVEL: &str = "under_attack";
+
+/// Redis sorted set of the access rules created, scored by when they expire
+const ACCESS_RULES_KEY: &str = "cloudflare:access_rules";
+
+/// Removes and returns the expired access rules, so only one instance deletes each
+const TAKE_EXPIRED_SCRIPT: &str = r#"
+local expired = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+if #expired > 0 then
+    redis.call('ZREM', KEYS[1], unpack(expired))
+end
+return expired
+"#;
 
 /// Errors that can occur during Cloudflare API operations
 #[derive(Debug, Error)]
@@ -15,6 +48,12 @@ pub enum CloudflareError {
     RequestError(#[from] reqwest::Error),
     #[error("Invalid response: {0}")]
     InvalidResponse(String),
+    #[error("API error: {0}")]
+    ApiError(String),
+    #[error("Invalid target: {0}")]
+    InvalidTarget(String),
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
 }
 
 /// Cloudflare zone information
@@ -28,6 +67,64 @@ pub struct Zone {
     pub status: String,
 }
 
+/// Envelope wrapping every Cloudflare API response
+#[derive(Debug, Deserialize)]
+struct Envelope<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiMessage>,
+    result: Option<T>,
+    result_info: Option<ResultInfo>,
+}
+
+/// Error reported by the Cloudflare API
+#[derive(Debug, Deserialize)]
+struct ApiMessage {
+    code: i64,
+    message: String,
+}
+
+/// Pagination of a list response
+#[derive(Debug, Deserialize)]
+struct ResultInfo {
+    page: u32,
+    total_pages: u32,
+}
+
+/// Object identified by its ID, such as a created access rule
+#[derive(Debug, Deserialize)]
+struct Created {
+    id: String,
+}
+
+/// Result of a successful response, with its pagination
+fn unwrap_envelope<T>(envelope: Envelope<T>) -> Result<(T, Option<ResultInfo>), CloudflareError> {
+    if !envelope.success {
+        let errors: Vec<String> = envelope
+            .errors
+            .iter()
+            .map(|error| format!("{} ({})", error.message, error.code))
+            .collect();
+        return Err(CloudflareError::ApiError(errors.join(", ")));
+    }
+    let result = envelope
+        .result
+        .ok_or_else(|| CloudflareError::InvalidResponse("missing result".to_string()))?;
+    Ok((result, envelope.result_info))
+}
+
+/// Access rule target for an address or network: `ip`, `ip6` or `ip_range`
+fn access_rule_target(target: &str) -> Result<(&'static str, String), CloudflareError> {
+    let network: IpNet = parse_network(target).ok_or_else(|| CloudflareError::InvalidTarget(target.to_string()))?;
+    if network.prefix_len() < network.max_prefix_len() {
+        return Ok(("ip_range", network.to_string()));
+    }
+    match network {
+        IpNet::V4(network) => Ok(("ip", network.addr().to_string())),
+        IpNet::V6(network) => Ok(("ip6", network.addr().to_string())),
+    }
+}
+
 /// Cloudflare API client
 pub struct CloudflareClient {
     /// HTTP client
@@ -36,29 +133,125 @@ pub struct CloudflareClient {
     api_token: String,
     /// Zone ID
     zone_id: Option<String>,
+    /// Zone name the zone ID is looked up from
+    zone_name: Option<String>,
+    /// Zone ID looked up from the zone name
+    resolved_zone_id: OnceCell<String>,
+    /// Security level restored when "Under Attack" mode is switched off
+    normal_security_level: String,
+    /// Challenge pass duration while under attack
+    challenge_ttl: Duration,
+    /// How long edge blocks last
+    block_duration: Duration,
+    /// How often expired edge blocks are deleted
+    expiry_interval: Duration,
+    /// Redis connection pool tracking the access rules created, if they expire
+    redis: Option<RedisPool>,
+    /// Script taking expired access rules
+    take_expired_script: redis::Script,
+}
+
+/// HTTP client giving up on the API after `timeout`
+fn http_client(timeout: Duration) -> Client {
+    Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()
+        .unwrap_or_default()
 }
 
 impl CloudflareClient {
     /// Create a new Cloudflare client instance
+    ///
+    /// Access rules created by this client are never deleted; see `from_config`.
     pub fn new(api_token: String, zone_id: Option<String>) -> Self {
+        let defaults = CloudflareConfig::default();
         Self {
-            client: Client::new(),
+            client: http_client(Duration::from_secs(defaults.timeout_seconds)),
             api_token,
             zone_id,
+            zone_name: None,
+            resolved_zone_id: OnceCell::new(),
+            normal_security_level: defaults.normal_security_level,
+            challenge_ttl: Duration::from_secs(defaults.challenge_ttl_seconds),
+            block_duration: Duration::from_secs(defaults.block_duration_seconds),
+            expiry_interval: Duration::from_secs(defaults.expiry_interval_seconds),
+            redis: None,
+            take_expired_script: redis::Script::new(TAKE_EXPIRED_SCRIPT),
+        }
+    }
+
+    /// Create a client from the service configuration
+    ///
+    /// The access rules it creates are deleted by `start` once their block ends.
+    pub fn from_config(config: &CloudflareConfig, redis: RedisPool) -> Self {
+        Self {
+            client: http_client(Duration::from_secs(config.timeout_seconds.max(1))),
+            zone_name: config.zone_name.clone(),
+            normal_security_level: config.normal_security_level.clone(),
+            challenge_ttl: Duration::from_secs(config.challenge_ttl_seconds),
+            block_duration: Duration::from_secs(config.block_duration_seconds),
+            expiry_interval: Duration::from_secs(config.expiry_interval_seconds.max(1)),
+            redis: Some(redis),
+            ..Self::new(config.api_token.clone(), config.zone_id.clone())
+        }
+    }
+
+    /// Send an API request and unwrap the response envelope
+    async fn request<T, B>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<(T, Option<ResultInfo>), CloudflareError>
+    where
+        T: DeserializeOwned,
+        B: Serialize + ?Sized,
+    {
+        let mut request = self.client
+            .request(method, format!("{}{}", API_URL, path))
+            .bearer_auth(&self.api_token);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let envelope: Envelope<T> = request.send().await?.json().await?;
+        unwrap_envelope(envelope)
+    }
+
+    /// List the zones the API token can access, following pagination
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Only list the zone with this name
+    pub async fn list_zones(&self, name: Option<&str>) -> Result<Vec<Zone>, CloudflareError> {
+        let mut zones = Vec::new();
+        let mut page = 1;
+        loop {
+            let mut path = format!("/zones?page={}&per_page={}", page, ZONES_PER_PAGE);
+            if let Some(name) = name {
+                path.push_str(&format!("&name={}", name));
+            }
+            let (batch, info): (Vec<Zone>, _) = self.request(Method::GET, &path, None::<&()>).await?;
+            let last = batch.is_empty() || info.is_none_or(|info| info.page >= info.total_pages);
+            zones.extend(batch);
+            if last {
+                return Ok(zones);
+            }
+            page += 1;
         }
     }
 
     /// Get the zone ID for a domain
-    /// 
+    ///
     /// If a zone ID is already configured, it will be returned.
     /// Otherwise, it will be retrieved from the Cloudflare API.
-    /// 
+    ///
     /// # Arguments
-    /// 
+    ///
     /// * `domain` - The domain to get the zone ID for
-    /// 
+    ///
     /// # Returns
-    /// 
+    ///
     /// * `Ok(String)` if the zone ID was found
     /// * `Err(CloudflareError)` if there was an error retrieving the zone ID
     pub async fn get_zone_id(&self, domain: &str) -> Result<String, CloudflareError> {
@@ -66,38 +259,182 @@ impl CloudflareClient {
         if let Some(zone_id) = &self.zone_id {
             return Ok(zone_id.clone());
         }
-        
+
         // Otherwise, retrieve it from the API
-        let url = "https://api.cloudflare.com/client/v4/zones";
-        let response = self.client
-            .get(url)
-            .header("Authorization", format!("Bearer {}", self.api_token))
-            .header("Content-Type", "application/json")
-            .send()
-            .await?;
-        
-        let zones: Vec<Zone> = response.json().await?;
-        
-        // Find the zone for the domain
-        let zone = zones.into_iter()
+        let zone = self.list_zones(Some(domain))
+            .await?
+            .into_iter()
             .find(|z| z.name == domain)
-            .ok_or(CloudflareError::InvalidResponse("No zones found".to_string()))?;
-        
+            .ok_or_else(|| CloudflareError::InvalidResponse(format!("No zone found for {}", domain)))?;
+        log::info!("Using Cloudflare zone {} ({}, {})", zone.name, zone.id, zone.status);
+
         Ok(zone.id)
     }
-    
+
+    /// The configured zone's ID, looked up from its name once if needed
+    async fn zone(&self) -> Result<&str, CloudflareError> {
+        if let Some(zone_id) = &self.zone_id {
+            return Ok(zone_id);
+        }
+        let name = self.zone_name
+            .as_deref()
+            .ok_or_else(|| CloudflareError::InvalidResponse("No zone ID or zone name configured".to_string()))?;
+        self.resolved_zone_id
+            .get_or_try_init(|| self.get_zone_id(name))
+            .await
+            .map(String::as_str)
+    }
+
+    /// Change a setting of the zone
+    async fn update_setting(&self, setting: &str, value: serde_json::Value) -> Result<(), CloudflareError> {
+        let path = format!("/zones/{}/settings/{}", self.zone().await?, setting);
+        let body = serde_json::json!({ "value": value });
+        let _: (serde_json::Value, _) = self.request(Method::PATCH, &path, Some(&body)).await?;
+        Ok(())
+    }
+
+    /// Change the zone's security level (`essentially_off`, `low`, `medium`, `high` or `under_attack`)
+    pub async fn set_security_level(&self, level: &str) -> Result<(), CloudflareError> {
+        self.update_setting("security_level", serde_json::json!(level)).await
+    }
+
+    /// Switch "Under Attack" mode on, or back to the normal security level
+    pub async fn set_under_attack(&self, enabled: bool) -> Result<(), CloudflareError> {
+        let level = if enabled { UNDER_ATTACK_LEVEL } else { &self.normal_security_level };
+        self.set_security_level(level).await
+    }
+
     /// Update DDoS protection settings for a zone
-    /// 
+    ///
     /// # Arguments
-    /// 
+    ///
     /// * `settings` - The DDoS protection settings to apply
-    /// 
+    ///
     /// # Returns
-    /// 
+    ///
     /// * `Ok(())` if the settings were updated successfully
     /// * `Err(CloudflareError)` if there was an error updating the settings
     pub async fn update_ddos_protection(&self, settings: DdosProtectionSettings) -> Result<(), CloudflareError> {
-        // TODO: Implement Cloudflare API calls
+        self.set_security_level(&settings.security_level).await?;
+        self.update_setting("challenge_ttl", serde_json::json!(settings.challenge_pass.as_secs())).await?;
+        let browser_check = if settings.browser_check { "on" } else { "off" };
+        self.update_setting("browser_check", serde_json::json!(browser_check)).await
+    }
+
+    /// Create an IP Access Rule for an address or network, returning the rule ID
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - IP address or CIDR network
+    /// * `mode` - What to do with matching requests (`block`, `challenge`, `js_challenge`, `managed_challenge` or `whitelist`)
+    /// * `notes` - Notes shown with the rule in the dashboard
+    pub async fn create_access_rule(&self, target: &str, mode: &str, notes: &str) -> Result<String, CloudflareError> {
+        let (kind, value) = access_rule_target(target)?;
+        let path = format!("/zones/{}/firewall/access_rules/rules", self.zone().await?);
+        let body = serde_json::json!({
+            "mode": mode,
+            "configuration": { "target": kind, "value": value },
+            "notes": notes,
+        });
+        let (rule, _): (Created, _) = self.request(Method::POST, &path, Some(&body)).await?;
+        metrics::increment_counter!("cloudflare_access_rules_total", "mode" => mode.to_string());
+        Ok(rule.id)
+    }
+
+    /// Delete an IP Access Rule
+    pub async fn delete_access_rule(&self, rule_id: &str) -> Result<(), CloudflareError> {
+        let path = format!("/zones/{}/firewall/access_rules/rules/{}", self.zone().await?, rule_id);
+        let _: (serde_json::Value, _) = self.request(Method::DELETE, &path, None::<&()>).await?;
+        Ok(())
+    }
+
+    /// Block an address or network at the edge, returning the access rule ID
+    ///
+    /// The rule is deleted once the block duration has passed.
+    pub async fn block(&self, target: &str, notes: &str) -> Result<String, CloudflareError> {
+        let rule_id = self.create_access_rule(target, "block", notes).await?;
+        if let Some(redis) = &self.redis {
+            let expires_at = get_current_timestamp() + self.block_duration.as_secs();
+            let _: () = redis::cmd("ZADD")
+                .arg(ACCESS_RULES_KEY)
+                .arg(expires_at)
+                .arg(&rule_id)
+                .query_async(&mut redis.get())
+                .await?;
+        }
+        log::warn!("Blocked {} at Cloudflare for {}s: {}", target, self.block_duration.as_secs(), notes);
+        Ok(rule_id)
+    }
+
+    /// Block an address or network at the edge without waiting for the API
+    ///
+    /// Used on the request path, where a slow API must not hold up decisions.
+    pub fn block_in_background(self: &Arc<Self>, target: &str, notes: &str) {
+        let (cloudflare, target, notes) = (self.clone(), target.to_string(), notes.to_string());
+        tokio::spawn(async move {
+            if let Err(e) = cloudflare.block(&target, &notes).await {
+                log::error!("Failed to block {} at Cloudflare: {}", target, e);
+            }
+        });
+    }
+
+    /// Delete the access rules whose block has ended
+    #[tracing::instrument(name = "cloudflare.delete_expired", skip_all)]
+    pub async fn delete_expired(&self) -> Result<(), CloudflareError> {
+        let Some(redis) = &self.redis else {
+            return Ok(());
+        };
+        let expired: Vec<String> = self
+            .take_expired_script
+            .key(ACCESS_RULES_KEY)
+            .arg(get_current_timestamp())
+            .invoke_async(&mut redis.get())
+            .await?;
+        for rule_id in expired {
+            match self.delete_access_rule(&rule_id).await {
+                Ok(()) => {
+                    metrics::increment_counter!("cloudflare_access_rules_deleted_total");
+                    log::info!("Deleted expired Cloudflare access rule {}", rule_id);
+                }
+                Err(e) => log::error!("Failed to delete Cloudflare access rule {}: {}", rule_id, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete expired access rules until the task is aborted
+    pub async fn start(&self) {
+        let mut interval = tokio::time::interval(self.expiry_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.delete_expired().await {
+                log::error!("Failed to delete expired Cloudflare access rules: {}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl UpstreamMitigation for CloudflareClient {
+    fn name(&self) -> &str {
+        "cloudflare"
+    }
+
+    async fn engage(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let settings = DdosProtectionSettings {
+            security_level: UNDER_ATTACK_LEVEL.to_string(),
+            challenge_pass: self.challenge_ttl,
+            browser_check: true,
+        };
+        Ok(self.update_ddos_protection(settings).await?)
+    }
+
+    async fn release(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.set_under_attack(false).await?)
+    }
+
+    async fn block(&self, target: &str, reason: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        CloudflareClient::block(self, target, reason).await?;
         Ok(())
     }
 }
@@ -116,12 +453,41 @@ pub struct DdosProtectionSettings {
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
     #[tokio::test]
     async fn test_get_zone_id() {
         let client = CloudflareClient::new("test_token".to_string(), Some("test_zone_id".to_string()));
         let zone_id = client.get_zone_id("example.com").await.unwrap();
-        
+
         assert_eq!(zone_id, "test_zone_id");
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_unwrap_envelope() {
+        let envelope: Envelope<Vec<Zone>> = serde_json::from_str(
+            r#"{"success": true, "errors": [], "messages": [],
+                "result": [{"id": "023e105f4ecef8ad9ca31a8372d0c353", "name": "example.com", "status": "active"}],
+                "result_info": {"page": 1, "per_page": 50, "count": 1, "total_count": 51, "total_pages": 2}}"#,
+        )
+        .unwrap();
+        let (zones, info) = unwrap_envelope(envelope).unwrap();
+        assert_eq!(zones[0].name, "example.com");
+        let info = info.unwrap();
+        assert_eq!((info.page, info.total_pages), (1, 2));
+
+        let envelope: Envelope<Vec<Zone>> = serde_json::from_str(
+            r#"{"success": false, "errors": [{"code": 9109, "message": "Invalid access token"}], "result": null}"#,
+        )
+        .unwrap();
+        let error = unwrap_envelope(envelope).unwrap_err();
+        assert_eq!(error.to_string(), "API error: Invalid access token (9109)");
+    }
+
+    #[test]
+    fn test_access_rule_target() {
+        assert_eq!(access_rule_target("192.0.2.1").unwrap(), ("ip", "192.0.2.1".to_string()));
+        assert_eq!(access_rule_target("2001:db8::1").unwrap(), ("ip6", "2001:db8::1".to_string()));
+        assert_eq!(access_rule_target("192.0.2.0/24").unwrap(), ("ip_range", "192.0.2.0/24".to_string()));
+        assert!(access_rule_target("example.com").is_err());
+    }
+}
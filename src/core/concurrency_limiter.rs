@@ -0,0 +1,141 @@
+//! Concurrent-connection limiting for the DDoS protection service.
+//!
+//! This module tracks in-flight requests per client in storage and rejects
+//! new requests once a client holds too many of them open at once, which
+//! rate limiting alone cannot catch (e.g. slow-read attacks).
+
+use std::sync::Arc;
+use std::time::Duration;
+use crate::models::ConcurrencyConfig;
+use crate::storage::{Storage, StorageError};
+use crate::utils::format_rate_limit_key;
+use thiserror::Error;
+
+/// Errors that can occur during concurrency limiting operations
+#[derive(Error, Debug)]
+pub enum ConcurrencyError {
+    #[error("Storage error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Concurrency limit exceeded: {0} requests in flight")]
+    ExceededLimit(u32),
+}
+
+/// Concurrency limiter implementation
+pub struct ConcurrencyLimiter {
+    /// Where in-flight counters are kept
+    storage: Arc<dyn Storage>,
+    /// Concurrency limit configuration
+    config: ConcurrencyConfig,
+}
+
+impl ConcurrencyLimiter {
+    /// Create a new concurrency limiter instance
+    pub fn new(storage: Arc<dyn Storage>, config: ConcurrencyConfig) -> Self {
+        Self { storage, config }
+    }
+
+    /// Register the start of a request for a client
+    ///
+    /// Every successful call must be paired with a call to `release`. The
+    /// counter carries a safety TTL, set when the client's first request
+    /// starts and not extended by later ones, so that requests which never
+    /// release (e.g. because the caller crashed) do not lock a client out
+    /// forever, even while it keeps sending requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to limit (e.g., IP address)
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u32)` with the number of requests in flight, including this one
+    /// * `Err(ConcurrencyError::ExceededLimit)` if the client holds too many requests open
+    /// * `Err(ConcurrencyError::StorageError)` if there was an error reaching storage
+    pub async fn acquire(&self, key: &str) -> Result<u32, ConcurrencyError> {
+        let concurrency_key = format_rate_limit_key("concurrency", key);
+        let safety_ttl = Duration::from_secs(self.config.safety_ttl_seconds.into());
+
+        let in_flight = self.storage.increment(&concurrency_key, 1, Some(safety_ttl)).await?.max(0) as u32;
+
+        if in_flight > self.config.max_concurrent {
+            // The rejected request never starts, so give its slot back
+            self.storage.increment(&concurrency_key, -1, None).await?;
+            return Err(ConcurrencyError::ExceededLimit(in_flight - 1));
+        }
+
+        Ok(in_flight)
+    }
+
+    /// Register the end of a request for a client
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key passed to `acquire`
+    pub async fn release(&self, key: &str) -> Result<(), ConcurrencyError> {
+        let concurrency_key = format_rate_limit_key("concurrency", key);
+
+        // Don't leave zero or negative counters behind (e.g. after the safety TTL
+        // fired); the store removes them in the same step, so an acquire racing
+        // with this release can't have its slot deleted
+        self.storage.decrement(&concurrency_key, 1).await?;
+
+        Ok(())
+    }
+
+    /// Maximum number of concurrent requests allowed per client
+    pub fn max_concurrent(&self) -> u32 {
+        self.config.max_concurrent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Client;
+    use crate::core::redis_pool::RedisPool;
+    use crate::storage::{MemoryStorage, RedisStorage};
+
+    #[tokio::test]
+    async fn test_concurrency_limiter() {
+        let client = Client::open("redis://127.0.0.1:6379").unwrap();
+        let pool = RedisPool::new(client, 1).await.unwrap();
+        check_limiter(Arc::new(RedisStorage::new(pool))).await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter_in_memory() {
+        check_limiter(Arc::new(MemoryStorage::new())).await;
+    }
+
+    async fn check_limiter(storage: Arc<dyn Storage>) {
+        let config = ConcurrencyConfig {
+            enabled: true,
+            max_concurrent: 2,
+            safety_ttl_seconds: 30,
+        };
+
+        let limiter = ConcurrencyLimiter::new(storage, config);
+
+        assert_eq!(limiter.acquire("concurrency_test").await.unwrap(), 1);
+        assert_eq!(limiter.acquire("concurrency_test").await.unwrap(), 2);
+
+        // Third concurrent request should be rejected without holding a slot
+        assert!(matches!(
+            limiter.acquire("concurrency_test").await,
+            Err(ConcurrencyError::ExceededLimit(2))
+        ));
+
+        // Finishing a request frees a slot
+        limiter.release("concurrency_test").await.unwrap();
+        assert_eq!(limiter.acquire("concurrency_test").await.unwrap(), 2);
+
+        limiter.release("concurrency_test").await.unwrap();
+        limiter.release("concurrency_test").await.unwrap();
+
+        // A release without a slot (e.g. after the safety TTL fired) leaves no counter behind
+        limiter.release("concurrency_test").await.unwrap();
+        limiter.release("concurrency_test").await.unwrap();
+        assert_eq!(limiter.acquire("concurrency_test").await.unwrap(), 1);
+        limiter.release("concurrency_test").await.unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+//! CrowdSec integration for the DDoS protection service.
+//!
+//! This module acts as a CrowdSec bouncer, pulling ban decisions from the
+//! local API (LAPI) decision stream into the blocklist, and as a CrowdSec
+//! machine, pushing DDoS detections back to the LAPI as alerts so that the
+//! other bouncers apply the same bans.
+
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::core::blocklist::{Blocklist, BlocklistError};
+use crate::models::CrowdSecConfig;
+
+/// Origin of decisions pushed by this service, skipped when pulling decisions
+const ORIGIN: &str = "ddos-protection-service";
+
+/// Scenario of pushed alerts
+const SCENARIO: &str = "ddos-protection-service/ddos";
+
+/// Block source of entries pulled from CrowdSec
+const SOURCE: &str = "crowdsec";
+
+/// Errors that can occur while talking to the LAPI
+#[derive(Error, Debug)]
+pub enum CrowdSecError {
+    #[error("Request error: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Blocklist error: {0}")]
+    BlocklistError(#[from] BlocklistError),
+    #[error("Missing credentials: {0}")]
+    MissingCredentials(&'static str),
+}
+
+/// LAPI decision
+#[derive(Debug, Clone, Deserialize)]
+pub struct Decision {
+    /// Who made the decision (`crowdsec`, `cscli`, `CAPI`, ...)
+    #[serde(default)]
+    pub origin: String,
+    /// Remediation (`ban`, `captcha`, ...)
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// What the value is (`Ip`, `Range`, `Country`, ...)
+    pub scope: String,
+    /// Decided IP or range
+    pub value: String,
+    /// Remaining duration as a Go duration (e.g. `3h59m12.5s`)
+    pub duration: String,
+    /// Scenario that triggered the decision
+    #[serde(default)]
+    pub scenario: String,
+}
+
+/// Changes in the LAPI decision stream
+#[derive(Debug, Default, Deserialize)]
+struct DecisionStream {
+    #[serde(default)]
+    new: Option<Vec<Decision>>,
+    #[serde(default)]
+    deleted: Option<Vec<Decision>>,
+}
+
+/// LAPI machine login response
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    token: String,
+    expire: DateTime<Utc>,
+}
+
+/// Alert pushed to the LAPI
+#[derive(Debug, Serialize)]
+struct Alert {
+    scenario: String,
+    scenario_hash: String,
+    scenario_version: String,
+    message: String,
+    events_count: u32,
+    start_at: String,
+    stop_at: String,
+    capacity: u32,
+    leakspeed: String,
+    simulated: bool,
+    events: Vec<serde_json::Value>,
+    source: AlertSource,
+    decisions: Vec<AlertDecision>,
+}
+
+#[derive(Debug, Serialize)]
+struct AlertSource {
+    scope: String,
+    value: String,
+    ip: String,
+}
+
+#[derive(Debug, Serialize)]
+struct AlertDecision {
+    duration: String,
+    origin: String,
+    scenario: String,
+    scope: String,
+    #[serde(rename = "type")]
+    kind: String,
+    value: String,
+}
+
+/// Parse a Go duration such as `3h59m12.5s` or `-1m` (expired)
+///
+/// Returns `None` for malformed, zero and negative durations.
+pub fn parse_go_duration(value: &str) -> Option<Duration> {
+    if value.starts_with('-') {
+        return None;
+    }
+
+    let mut seconds = 0.0;
+    let mut number = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let amount: f64 = number.parse().ok()?;
+        number.clear();
+        seconds += amount
+            * match c {
+                'h' => 3600.0,
+                'm' if chars.peek() == Some(&'s') => {
+                    chars.next();
+                    0.001
+                }
+                'm' => 60.0,
+                's' => 1.0,
+                _ => return None,
+            };
+    }
+    if !number.is_empty() || seconds <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(seconds))
+}
+
+/// CrowdSec bouncer and alert publisher
+///
+/// Cloning is cheap and all clones share the LAPI login token.
+#[derive(Clone)]
+pub struct CrowdSec {
+    /// HTTP client
+    client: reqwest::Client,
+    /// CrowdSec configuration
+    config: CrowdSecConfig,
+    /// Blocklist decisions are pulled into
+    blocklist: Blocklist,
+    /// Machine login token and its expiry
+    token: Arc<RwLock<Option<LoginResponse>>>,
+}
+
+impl CrowdSec {
+    /// Create a new CrowdSec integration
+    pub fn new(config: CrowdSecConfig, blocklist: Blocklist) -> Result<Self, CrowdSecError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self {
+            client,
+            config,
+            blocklist,
+            token: Arc::new(RwLock::new(None)),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.lapi_url.trim_end_matches('/'), path)
+    }
+
+    /// Apply decision changes from the stream, returning how many were applied
+    ///
+    /// `startup` requests every active decision rather than the changes
+    /// since the last poll.
+    #[tracing::instrument(name = "crowdsec.sync_decisions", skip_all)]
+    pub async fn sync_decisions(&self, startup: bool) -> Result<usize, CrowdSecError> {
+        let api_key = self
+            .config
+            .bouncer_api_key
+            .as_deref()
+            .ok_or(CrowdSecError::MissingCredentials("bouncer API key"))?;
+        let stream: DecisionStream = self
+            .client
+            .get(self.url("/v1/decisions/stream"))
+            .query(&[("startup", startup)])
+            .header("X-Api-Key", api_key)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let applies = |decision: &&Decision| {
+            decision.kind.eq_ignore_ascii_case("ban")
+                && (decision.scope.eq_ignore_ascii_case("ip") || decision.scope.eq_ignore_ascii_case("range"))
+                && decision.origin != ORIGIN
+        };
+
+        let mut applied = 0;
+        for decision in stream.deleted.iter().flatten().filter(applies) {
+            // Only lift blocks that came from CrowdSec
+            match self.blocklist.get_entry(&decision.value).await {
+                Ok(Some(entry)) if entry.source == SOURCE => {
+                    self.blocklist.unblock(&decision.value).await?;
+                    applied += 1;
+                }
+                Ok(_) => (),
+                Err(BlocklistError::InvalidTarget(target)) => log::warn!("Invalid CrowdSec decision: {}", target),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for decision in stream.new.iter().flatten().filter(applies) {
+            let duration = match parse_go_duration(&decision.duration) {
+                Some(duration) => duration,
+                None => continue,
+            };
+            let reason = format!("CrowdSec decision ({})", decision.scenario);
+            match self.blocklist.block(&decision.value, &reason, SOURCE, Some(duration)).await {
+                Ok(_) => applied += 1,
+                Err(BlocklistError::InvalidTarget(target)) => log::warn!("Invalid CrowdSec decision: {}", target),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(applied)
+    }
+
+    /// Poll the decision stream until the task is cancelled
+    pub async fn start_sync(&self) {
+        if !self.config.enabled || self.config.bouncer_api_key.is_none() {
+            return;
+        }
+
+        let interval = Duration::from_secs(self.config.poll_interval_seconds.max(1));
+        let mut startup = true;
+        loop {
+            match self.sync_decisions(startup).await {
+                Ok(applied) => {
+                    if applied > 0 {
+                        log::info!("Applied {} CrowdSec decisions", applied);
+                    }
+                    startup = false;
+                }
+                Err(e) => log::error!("Failed to pull CrowdSec decisions: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Machine token, logging in again when it is missing or about to expire
+    async fn token(&self) -> Result<String, CrowdSecError> {
+        if let Some(login) = self.token.read().await.as_ref() {
+            if login.expire > Utc::now() + chrono::Duration::seconds(60) {
+                return Ok(login.token.clone());
+            }
+        }
+
+        let (machine_id, password) = match (&self.config.machine_id, &self.config.machine_password) {
+            (Some(machine_id), Some(password)) => (machine_id, password),
+            _ => return Err(CrowdSecError::MissingCredentials("machine ID and password")),
+        };
+        let login: LoginResponse = self
+            .client
+            .post(self.url("/v1/watchers/login"))
+            .json(&serde_json::json!({ "machine_id": machine_id, "password": password }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let token = login.token.clone();
+        *self.token.write().await = Some(login);
+        Ok(token)
+    }
+
+    /// Push a detection to the LAPI as an alert with a ban decision
+    pub async fn push_alert(&self, ip: &str, reason: &str) -> Result<(), CrowdSecError> {
+        let scope = if ip.contains('/') { "Range" } else { "Ip" };
+        let now = Utc::now().to_rfc3339();
+        let alert = Alert {
+            scenario: SCENARIO.to_string(),
+            scenario_hash: String::new(),
+            scenario_version: String::new(),
+            message: format!("{}: {}", ip, reason),
+            events_count: 1,
+            start_at: now.clone(),
+            stop_at: now,
+            capacity: 0,
+            leakspeed: "0".to_string(),
+            simulated: false,
+            events: Vec::new(),
+            source: AlertSource {
+                scope: scope.to_string(),
+                value: ip.to_string(),
+                ip: ip.to_string(),
+            },
+            decisions: vec![AlertDecision {
+                duration: format!("{}s", self.config.alert_ban_seconds.max(1)),
+                origin: ORIGIN.to_string(),
+                scenario: SCENARIO.to_string(),
+                scope: scope.to_string(),
+                kind: "ban".to_string(),
+                value: ip.to_string(),
+            }],
+        };
+
+        let token = self.token().await?;
+        self.client
+            .post(self.url("/v1/alerts"))
+            .bearer_auth(token)
+            .json(&[alert])
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Push a detection without waiting for the LAPI
+    pub fn push_alert_in_background(&self, ip: &str, reason: &str) {
+        if !self.config.enabled || !self.config.push_alerts {
+            return;
+        }
+
+        let crowdsec = self.clone();
+        let ip = ip.to_string();
+        let reason = reason.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = crowdsec.push_alert(&ip, &reason).await {
+                log::error!("Failed to push CrowdSec alert for {}: {}", ip, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_go_duration() {
+        assert_eq!(parse_go_duration("4h"), Some(Duration::from_secs(14400)));
+        assert_eq!(parse_go_duration("3h59m12.5s"), Some(Duration::from_secs_f64(14352.5)));
+        assert_eq!(parse_go_duration("1m30s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_go_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_go_duration("-5m"), None);
+        assert_eq!(parse_go_duration("0s"), None);
+        assert_eq!(parse_go_duration("12"), None);
+
+        let stream: DecisionStream = serde_json::from_str(
+            r#"{"new": [{"origin": "crowdsec", "type": "ban", "scope": "Ip", "value": "192.0.2.1",
+                "duration": "3h59m", "scenario": "crowdsecurity/http-probing"}], "deleted": null}"#,
+        )
+        .unwrap();
+        assert_eq!(stream.new.unwrap()[0].value, "192.0.2.1");
+        assert!(stream.deleted.is_none());
+    }
+}
@@ -5,10 +5,25 @@
 //! and anomaly detection.
 
 use std::collections::{HashMap, VecDeque};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use crate::core::abuseipdb::{detection_confidence, AbuseIpdb};
+use crate::core::attacks::AttackTracker;
+use crate::core::baseline::{BaselineLearner, Observation};
+use crate::core::blocklist::{BlockEntry, Blocklist, BlocklistError};
+use crate::core::crowdsec::CrowdSec;
+use crate::core::detection::{builtin_detectors, DetectionContext, Detector, Scorer};
+use crate::core::geoip::GeoIp;
+use crate::core::lru::LruMap;
+use crate::core::redis_pool::RedisPool;
+use crate::core::reputation::{Reputation, ReputationEvent};
+use crate::core::rule_engine::RequestContext;
+use crate::core::tls_fingerprint::FingerprintTracker;
+use crate::models::SubnetConfig;
+use crate::utils::parse_network;
 
 /// Errors that can occur during DDoS detection
 #[derive(Error, Debug)]
@@ -17,8 +32,41 @@ pub enum DdosDetectionError {
     RedisError(#[from] redis::RedisError),
     #[error("Detection error: {0}")]
     DetectionError(String),
+    #[error("Blocklist error: {0}")]
+    Blocklist(#[from] BlocklistError),
 }
 
+/// What resetting a client's detection undid
+#[derive(Debug, Default, Serialize)]
+pub struct DetectionReset {
+    /// Blocks added by the detector that were lifted
+    pub unblocked: Vec<String>,
+    /// Whether the client's reputation was reset to neutral
+    pub reputation_reset: bool,
+    /// Block still applying to the client, added by something other than the detector
+    pub still_blocked: Option<BlockEntry>,
+}
+
+/// Counts into sliding windows shared by every instance
+///
+/// Each window is approximated from two fixed buckets: the current bucket
+/// plus the previous one weighted by how much of it the window still
+/// covers. Unlike plain fixed windows this doesn't let clients double their
+/// rate across a window boundary.
+///
+/// KEYS: current and previous bucket of each counter, in pairs
+/// ARGV: increment, weight of the previous bucket and TTL of each counter
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+local counts = {}
+for n = 1, #KEYS / 2 do
+    local current = redis.call('INCRBY', KEYS[2 * n - 1], ARGV[3 * n - 2])
+    redis.call('EXPIRE', KEYS[2 * n - 1], ARGV[3 * n])
+    local previous = tonumber(redis.call('GET', KEYS[2 * n])) or 0
+    counts[n] = math.floor(current + previous * tonumber(ARGV[3 * n - 1]))
+end
+return counts
+"#;
+
 /// DDoS detection configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DdosDetectionConfig {
@@ -38,6 +86,492 @@ pub struct DdosDetectionConfig {
     pub anomaly_threshold: f64,
     /// Time window for anomaly detection (seconds)
     pub anomaly_window: u32,
+    /// Most clients tracked in memory per tracker; the least recently seen are evicted
+    #[serde(default = "default_max_tracked_clients")]
+    pub max_tracked_clients: usize,
+    /// Per-subnet detection
+    #[serde(default)]
+    pub subnet: SubnetDetectionConfig,
+    /// Aggregate (service-wide) detection
+    #[serde(default)]
+    pub aggregate: AggregateDetectionConfig,
+    /// Baseline-based detection
+    #[serde(default)]
+    pub baseline: BaselineDetectionConfig,
+    /// Slow connection (slowloris, slow POST) detection
+    #[serde(default)]
+    pub slow_connection: SlowConnectionConfig,
+    /// Network flow (NetFlow, IPFIX, sFlow) detection
+    #[serde(default)]
+    pub flow: FlowDetectionConfig,
+    /// Layer-7 HTTP flood detection
+    #[serde(default)]
+    pub http_flood: HttpFloodConfig,
+    /// TLS fingerprint tracking
+    #[serde(default)]
+    pub tls_fingerprint: TlsFingerprintConfig,
+    /// Detectors scoring each request and how their scores are combined
+    #[serde(default)]
+    pub pipeline: DetectionPipelineConfig,
+}
+
+fn default_max_tracked_clients() -> usize {
+    100_000
+}
+
+/// Per-subnet detection configuration
+///
+/// Traffic is also aggregated per subnet (see `SubnetConfig`) so that
+/// botnets spreading requests across many addresses of one network are
+/// detected even when each address stays below the per-IP thresholds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubnetDetectionConfig {
+    /// Whether to aggregate traffic per subnet
+    pub enabled: bool,
+    /// Threshold for request rate across a subnet (uses `request_rate_window`)
+    pub request_rate_threshold: u32,
+    /// Threshold for traffic volume across a subnet (uses `traffic_volume_window`)
+    pub traffic_volume_threshold: u64,
+}
+
+impl Default for SubnetDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            request_rate_threshold: 10_000,
+            traffic_volume_threshold: 100_000_000, // 100 MB/s
+        }
+    }
+}
+
+/// Aggregate detection configuration
+///
+/// Traffic is also counted across all clients, globally and per target
+/// path, so that distributed attacks where every client stays below the
+/// per-IP and per-subnet thresholds are still detected. Aggregate
+/// detections flag the service as under attack without blocking anyone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateDetectionConfig {
+    /// Whether to count traffic across all clients
+    pub enabled: bool,
+    /// Threshold for the total request rate (uses `request_rate_window`)
+    pub request_rate_threshold: u64,
+    /// Threshold for the total traffic volume (uses `traffic_volume_window`)
+    pub traffic_volume_threshold: u64,
+    /// Threshold for the request rate to a single path (uses `request_rate_window`)
+    pub path_request_rate_threshold: u64,
+    /// Window over which distinct source IPs are counted (seconds)
+    pub distinct_ip_window: u32,
+    /// Fewest distinct source IPs in a window that can be a spike
+    pub distinct_ip_min: u64,
+    /// How many times the previous window's distinct source IPs make a spike
+    pub distinct_ip_spike_factor: f64,
+}
+
+impl Default for AggregateDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            request_rate_threshold: 100_000,
+            traffic_volume_threshold: 1_000_000_000, // 1 GB/s
+            path_request_rate_threshold: 20_000,
+            distinct_ip_window: 60,
+            distinct_ip_min: 1000,
+            distinct_ip_spike_factor: 5.0,
+        }
+    }
+}
+
+/// Baseline detection configuration
+///
+/// The aggregate counters (total requests, total volume and requests per
+/// path) are compared against their learned baselines, flagging windows
+/// more than `anomaly_threshold` standard deviations above normal. This
+/// adapts to both quiet and busy periods where fixed thresholds can't.
+/// Baselines are also learned per hour of the week and used once an hour
+/// has enough samples. Requires aggregate detection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BaselineDetectionConfig {
+    /// Whether to learn baselines and detect deviations from them
+    pub enabled: bool,
+    /// Weight of each new window in the moving average (0-1)
+    pub smoothing: f64,
+    /// Weight of each new window in the average of its hour of the week (0-1)
+    ///
+    /// Lower than `smoothing`, since an hour's windows all arrive together
+    /// once a week and shouldn't outweigh previous weeks.
+    pub seasonal_smoothing: f64,
+    /// Windows to learn from before deviations are flagged
+    pub min_samples: u64,
+    /// Fraction a window must also exceed the average by to be flagged
+    pub min_increase: f64,
+}
+
+impl Default for BaselineDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            smoothing: 0.05,
+            seasonal_smoothing: 0.01,
+            min_samples: 60,
+            min_increase: 0.5,
+        }
+    }
+}
+
+/// Slow connection detection configuration
+///
+/// Low-and-slow attacks exhaust connection slots by trickling headers
+/// (slowloris) or bodies (slow POST) just fast enough to avoid timeouts.
+/// The proxy reports each connection's transfer once it completes or is
+/// closed, and clients with too many slow connections in the window are
+/// blocked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlowConnectionConfig {
+    /// Whether to detect slow connections
+    pub enabled: bool,
+    /// Slowest acceptable header transfer rate (bytes per second)
+    pub min_header_rate: u64,
+    /// Slowest acceptable body transfer rate (bytes per second)
+    pub min_body_rate: u64,
+    /// Longest acceptable time to receive the headers (seconds)
+    pub max_header_seconds: u64,
+    /// Transfers shorter than this aren't judged on their rate (seconds)
+    pub grace_seconds: u64,
+    /// Slow connections a client may make within the window
+    pub max_slow_connections: u32,
+    /// Window over which slow connections are counted (seconds)
+    pub window: u32,
+}
+
+impl Default for SlowConnectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_header_rate: 50,
+            min_body_rate: 100,
+            max_header_seconds: 30,
+            grace_seconds: 5,
+            max_slow_connections: 10,
+            window: 300,
+        }
+    }
+}
+
+/// Network flow detection configuration
+///
+/// Flows exported by routers (see `FlowCollectorConfig`) show traffic that
+/// never reaches the HTTP layer, such as UDP floods or SYN floods. Sources
+/// whose packet or byte rate across their flows exceeds a threshold are
+/// blocked. Rates are averaged over the window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowDetectionConfig {
+    /// Whether to detect attacks from flows
+    pub enabled: bool,
+    /// Threshold for a source's packet rate (packets per second)
+    pub packet_rate_threshold: u64,
+    /// Threshold for a source's byte rate (bytes per second)
+    pub byte_rate_threshold: u64,
+    /// Window over which flow rates are averaged (seconds)
+    pub window: u32,
+}
+
+impl Default for FlowDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            packet_rate_threshold: 100_000,
+            byte_rate_threshold: 125_000_000, // 1 Gbit/s
+            window: 60,
+        }
+    }
+}
+
+/// Transfer statistics of a connection, as reported by the proxy
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    /// Header bytes received
+    pub header_bytes: u64,
+    /// Time spent receiving the headers (milliseconds)
+    pub header_ms: u64,
+    /// Body bytes received
+    #[serde(default)]
+    pub body_bytes: u64,
+    /// Time spent receiving the body (milliseconds)
+    #[serde(default)]
+    pub body_ms: u64,
+}
+
+impl ConnectionStats {
+    /// Whether the connection transferred too slowly to be legitimate
+    pub fn is_slow(&self, config: &SlowConnectionConfig) -> bool {
+        let grace_ms = config.grace_seconds * 1000;
+        let too_slow = |bytes: u64, ms: u64, min_rate: u64| ms >= grace_ms.max(1) && bytes * 1000 / ms < min_rate;
+        self.header_ms >= config.max_header_seconds * 1000
+            || too_slow(self.header_bytes, self.header_ms, config.min_header_rate)
+            || too_slow(self.body_bytes, self.body_ms, config.min_body_rate)
+    }
+}
+
+/// HTTP flood detection configuration
+///
+/// Layer-7 floods stay under volumetric thresholds by making requests that
+/// are expensive to serve. Each client's requests in the window are
+/// profiled and the client is flagged once it has made enough of them and
+/// its traffic matches a flood pattern.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpFloodConfig {
+    /// Whether to detect HTTP floods
+    pub enabled: bool,
+    /// Window over which a client's requests are profiled (seconds)
+    pub window: u32,
+    /// Requests a client must make in the window before it is profiled
+    pub min_requests: u64,
+    /// Share of a client's requests to a single path that is a flood
+    pub path_concentration: f64,
+    /// Ratio of distinct query strings to requests that is a flood
+    pub query_cardinality: f64,
+    /// Share of requests carrying a random-looking parameter that is a flood
+    pub cache_busting_ratio: f64,
+}
+
+impl Default for HttpFloodConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 60,
+            min_requests: 300,
+            path_concentration: 0.9,
+            query_cardinality: 0.9,
+            cache_busting_ratio: 0.8,
+        }
+    }
+}
+
+/// TLS fingerprint tracking configuration
+///
+/// Requests are counted per JA3 and JA4 fingerprint of the client's TLS
+/// stack, which is shared by every member of a botnet however many
+/// addresses it uses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsFingerprintConfig {
+    /// Whether to count requests per fingerprint
+    pub enabled: bool,
+    /// Window over which requests per fingerprint are counted (seconds)
+    pub window: u32,
+    /// Requests per window from one fingerprint at which the `fingerprint` detector reaches its threshold
+    pub request_rate_threshold: u64,
+}
+
+impl Default for TlsFingerprintConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 60,
+            request_rate_threshold: 100_000,
+        }
+    }
+}
+
+/// How detector scores are combined
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreCombination {
+    /// The highest score, so that each detector acts on its own threshold
+    Max,
+    /// The sum of the scores, so that detectors close to their thresholds add up
+    Sum,
+}
+
+/// Detection pipeline configuration
+///
+/// Each detector scores a request against its own threshold, 1 meaning the
+/// threshold is reached. Scores are weighted and combined, and the client
+/// is detected once the combined score exceeds the threshold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetectionPipelineConfig {
+    /// Built-in detectors to run, in order (`rate`, `volume`, `anomaly`, `entropy`, `fingerprint`)
+    pub detectors: Vec<String>,
+    /// How detector scores are combined
+    pub combination: ScoreCombination,
+    /// Weight of each detector's score by name; unlisted detectors weigh 1, and 0 disables a detector
+    #[serde(default)]
+    pub weights: HashMap<String, f64>,
+    /// Combined score above which a client is detected
+    pub threshold: f64,
+    /// Fewest requests per window for which the anomaly detector reaches its threshold
+    pub anomaly_min_requests: u64,
+}
+
+impl Default for DetectionPipelineConfig {
+    fn default() -> Self {
+        Self {
+            detectors: vec!["rate".to_string(), "volume".to_string(), "entropy".to_string()],
+            combination: ScoreCombination::Max,
+            weights: HashMap::new(),
+            threshold: 1.0,
+            anomaly_min_requests: 100,
+        }
+    }
+}
+
+/// What a client was detected for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionType {
+    /// The client is on the blocklist
+    Blocklist,
+    /// The client exceeded the request rate or traffic volume thresholds
+    RequestRate,
+    /// The client's requests look like a layer-7 flood
+    HttpFlood,
+    /// The request matched a blocking rule
+    Rule,
+    /// The client requested a honeypot path
+    Honeypot,
+    /// The client behaves like a vulnerability scanner
+    Scanner,
+    /// Attack mode blocked the client for its reputation or bot score
+    AttackMode,
+}
+
+/// Broad class of an attack, for choosing a mitigation
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttackCategory {
+    /// Too many requests or too much traffic
+    Volumetric,
+    /// Too many connections, or connections held open
+    ConnectionFlood,
+    /// Requests crafted to be expensive to serve
+    ApplicationLayer,
+    /// Traffic deviating from its learned baseline
+    Anomaly,
+    /// Probing for vulnerabilities or enumerating paths
+    Reconnaissance,
+}
+
+/// Metric value that triggered a detection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TriggerMetric {
+    /// Metric name (`request_rate`, `cache_busting_ratio`, ...)
+    pub name: String,
+    /// Observed value
+    pub observed: f64,
+    /// Threshold the value reached
+    pub threshold: f64,
+}
+
+/// Classification of a detection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Classification {
+    /// Broad class of the attack
+    pub category: AttackCategory,
+    /// What triggered detection (`request_rate`, `subnet_traffic_volume`, `cache_busting`, ...)
+    pub vector: String,
+    /// Confidence (0-100) that the detection is an attack
+    pub confidence: u8,
+    /// Metric values that triggered detection
+    pub metrics: Vec<TriggerMetric>,
+}
+
+impl Classification {
+    /// Classify a counter that exceeded its threshold
+    pub fn threshold_exceeded(category: AttackCategory, vector: &str, observed: u64, threshold: u64) -> Self {
+        Self {
+            category,
+            vector: vector.to_string(),
+            confidence: detection_confidence(observed, threshold),
+            metrics: vec![TriggerMetric {
+                name: vector.to_string(),
+                observed: observed as f64,
+                threshold: threshold as f64,
+            }],
+        }
+    }
+}
+
+/// Kind of distributed attack found by aggregate detection
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateDetectionType {
+    /// Total request rate exceeded its threshold
+    GlobalRequestRate,
+    /// Total traffic volume exceeded its threshold
+    GlobalTrafficVolume,
+    /// Request rate to one path exceeded its threshold
+    PathRequestRate,
+    /// Number of distinct source IPs jumped compared to the previous window
+    DistinctIpSpike,
+    /// Total request rate deviates from its baseline
+    RequestRateAnomaly,
+    /// Total traffic volume deviates from its baseline
+    TrafficVolumeAnomaly,
+    /// Request rate to one path deviates from its baseline
+    PathRequestRateAnomaly,
+}
+
+impl AggregateDetectionType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::GlobalRequestRate => "global_request_rate",
+            Self::GlobalTrafficVolume => "global_traffic_volume",
+            Self::PathRequestRate => "path_request_rate",
+            Self::DistinctIpSpike => "distinct_ip_spike",
+            Self::RequestRateAnomaly => "request_rate_anomaly",
+            Self::TrafficVolumeAnomaly => "traffic_volume_anomaly",
+            Self::PathRequestRateAnomaly => "path_request_rate_anomaly",
+        }
+    }
+
+    /// Broad class of the attack
+    pub fn category(&self) -> AttackCategory {
+        match self {
+            Self::GlobalRequestRate | Self::GlobalTrafficVolume | Self::DistinctIpSpike => AttackCategory::Volumetric,
+            Self::PathRequestRate => AttackCategory::ApplicationLayer,
+            Self::RequestRateAnomaly | Self::TrafficVolumeAnomaly | Self::PathRequestRateAnomaly => {
+                AttackCategory::Anomaly
+            }
+        }
+    }
+}
+
+/// Distributed attack found by aggregate detection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateDetection {
+    /// What exceeded its threshold
+    pub detection_type: AggregateDetectionType,
+    /// Broad class of the attack
+    pub category: AttackCategory,
+    /// Confidence (0-100) that the detection is an attack
+    pub confidence: u8,
+    /// Targeted path, for per-path detections
+    pub target: Option<String>,
+    /// Observed value in the current window
+    pub observed: u64,
+    /// Value the observation exceeded
+    pub threshold: u64,
+}
+
+impl AggregateDetection {
+    fn new(detection_type: AggregateDetectionType, target: Option<&str>, observed: u64, threshold: u64) -> Self {
+        Self {
+            detection_type,
+            category: detection_type.category(),
+            confidence: detection_confidence(observed, threshold),
+            target: target.map(str::to_string),
+            observed,
+            threshold,
+        }
+    }
+}
+
+/// Whether the distinct source IPs in the current window are a spike
+///
+/// A botnet joining an attack shows up as many more distinct sources than
+/// usual even when the total rate is still moderate.
+fn is_distinct_ip_spike(current: u64, previous: u64, config: &AggregateDetectionConfig) -> bool {
+    current >= config.distinct_ip_min && current as f64 > previous as f64 * config.distinct_ip_spike_factor
 }
 
 impl Default for DdosDetectionConfig {
@@ -51,34 +585,209 @@ impl Default for DdosDetectionConfig {
             traffic_volume_window: 60,
             anomaly_threshold: 3.0,
             anomaly_window: 300, // 5 minutes
+            max_tracked_clients: default_max_tracked_clients(),
+            subnet: SubnetDetectionConfig::default(),
+            aggregate: AggregateDetectionConfig::default(),
+            baseline: BaselineDetectionConfig::default(),
+            slow_connection: SlowConnectionConfig::default(),
+            flow: FlowDetectionConfig::default(),
+            http_flood: HttpFloodConfig::default(),
+            tls_fingerprint: TlsFingerprintConfig::default(),
+            pipeline: DetectionPipelineConfig::default(),
         }
     }
 }
 
 /// DDoS detector implementation
 pub struct DdosDetector {
-    /// Redis connection manager
-    redis: redis::Client,
+    /// Redis connection pool
+    redis: RedisPool,
     /// DDoS detection configuration
     config: DdosDetectionConfig,
-    /// In-memory connection tracking
-    connection_tracker: HashMap<String, VecDeque<Instant>>,
+    /// In-memory connection tracking, used while Redis is unavailable
+    connection_tracker: Mutex<LruMap<String, VecDeque<Instant>>>,
     /// In-memory request tracking
-    request_tracker: HashMap<String, VecDeque<Instant>>,
+    request_tracker: Mutex<LruMap<String, VecDeque<Instant>>>,
     /// In-memory traffic tracking
-    traffic_tracker: HashMap<String, VecDeque<(Instant, u64)>>,
+    traffic_tracker: Mutex<LruMap<String, VecDeque<(Instant, u64)>>>,
+    /// Blocklist that detected clients are added to
+    blocklist: Option<Blocklist>,
+    /// GeoIP resolver used to locate detected clients
+    geoip: Option<GeoIp>,
+    /// Reputation store penalizing detected clients
+    reputation: Option<Reputation>,
+    /// AbuseIPDB client reporting detected clients
+    abuseipdb: Option<AbuseIpdb>,
+    /// CrowdSec integration receiving detections as alerts
+    crowdsec: Option<CrowdSec>,
+    /// Tracker grouping detections into attacks
+    attacks: Option<AttackTracker>,
+    /// Learner of normal aggregate traffic
+    baseline: BaselineLearner,
+    /// Script counting into sliding windows
+    window_script: redis::Script,
+    /// Detectors scoring each request
+    pipeline: Vec<Box<dyn Detector>>,
+    /// Scorer combining detector scores
+    scorer: Scorer,
+    /// Counter of requests per TLS fingerprint
+    fingerprints: Option<FingerprintTracker>,
+    /// Subnet sizes used for per-subnet detection
+    subnets: SubnetConfig,
 }
 
 impl DdosDetector {
     /// Create a new DDoS detector instance
-    pub fn new(redis: redis::Client, config: DdosDetectionConfig) -> Self {
-        Self {
+    pub fn new(redis: RedisPool, config: DdosDetectionConfig) -> Self {
+        let baseline = BaselineLearner::new(redis.clone(), config.baseline.clone(), config.anomaly_threshold);
+        let builtin = builtin_detectors(&redis, &config);
+        let scorer = Scorer::new(config.pipeline.clone());
+        let detector = Self {
             redis,
+            connection_tracker: Mutex::new(LruMap::new("connection_tracker", config.max_tracked_clients)),
+            request_tracker: Mutex::new(LruMap::new("request_tracker", config.max_tracked_clients)),
+            traffic_tracker: Mutex::new(LruMap::new("traffic_tracker", config.max_tracked_clients)),
             config,
-            connection_tracker: HashMap::new(),
-            request_tracker: HashMap::new(),
-            traffic_tracker: HashMap::new(),
+            blocklist: None,
+            geoip: None,
+            reputation: None,
+            abuseipdb: None,
+            crowdsec: None,
+            attacks: None,
+            baseline,
+            window_script: redis::Script::new(SLIDING_WINDOW_SCRIPT),
+            pipeline: Vec::new(),
+            scorer,
+            fingerprints: None,
+            subnets: SubnetConfig::default(),
+        };
+        builtin.into_iter().fold(detector, Self::with_detector)
+    }
+
+    /// Group clients into subnets of the given sizes
+    pub fn with_subnets(mut self, subnets: SubnetConfig) -> Self {
+        self.subnets = subnets;
+        self
+    }
+
+    /// Block detected clients on the given blocklist
+    pub fn with_blocklist(mut self, blocklist: Blocklist) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    /// Include the location of detected clients in block reasons
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// Lower the reputation of detected clients
+    pub fn with_reputation(mut self, reputation: Reputation) -> Self {
+        self.reputation = Some(reputation);
+        self
+    }
+
+    /// Report detected clients to AbuseIPDB
+    pub fn with_abuseipdb(mut self, abuseipdb: AbuseIpdb) -> Self {
+        self.abuseipdb = Some(abuseipdb);
+        self
+    }
+
+    /// Push detections to CrowdSec as alerts
+    pub fn with_crowdsec(mut self, crowdsec: CrowdSec) -> Self {
+        self.crowdsec = Some(crowdsec);
+        self
+    }
+
+    /// Group detections into attacks
+    pub fn with_attacks(mut self, attacks: AttackTracker) -> Self {
+        self.attacks = Some(attacks);
+        self
+    }
+
+    /// Count requests per TLS fingerprint, for the `fingerprint` detector
+    pub fn with_fingerprints(mut self, fingerprints: FingerprintTracker) -> Self {
+        self.fingerprints = Some(fingerprints);
+        self
+    }
+
+    /// Add a detector to the pipeline, after the built-in ones
+    ///
+    /// Detectors are weighted by name like the built-in ones, with
+    /// `pipeline.weights`.
+    pub fn with_detector(mut self, detector: Box<dyn Detector>) -> Self {
+        self.pipeline.push(detector);
+        self
+    }
+
+    /// Record a detection against the attack for its vector
+    async fn record_attack(&self, vector: &str, target: Option<&str>, rps: u64, mitigation: Option<&str>) {
+        if let Some(attacks) = &self.attacks {
+            if let Err(e) = attacks.record(vector, target, rps, mitigation).await {
+                log::error!("Failed to record {} attack: {}", vector, e);
+            }
+        }
+    }
+
+    /// Penalize and report a detected client and add it to the blocklist, if one is configured
+    ///
+    /// `confidence` (0-100) rates how clearly the client exceeded the
+    /// threshold, and `rps` is its observed request rate, or 0 if not
+    /// applicable to the vector.
+    async fn block_detected(&self, ip: &str, vector: &str, reason: &str, confidence: u8, rps: u64) {
+        let mitigation = self.blocklist.as_ref().map(|_| "block");
+        self.record_attack(vector, Some(ip), rps, mitigation).await;
+        if let Some(reputation) = &self.reputation {
+            reputation.record(ip, ReputationEvent::DdosDetection).await;
+        }
+        if let Some(abuseipdb) = &self.abuseipdb {
+            abuseipdb.report_in_background(ip, &format!("DDoS protection: {}", reason), confidence);
+        }
+        if let Some(crowdsec) = &self.crowdsec {
+            crowdsec.push_alert_in_background(ip, reason);
+        }
+        if let Some(blocklist) = &self.blocklist {
+            let reason = match &self.geoip {
+                Some(geoip) => {
+                    let info = geoip.lookup(ip).await;
+                    let location: Vec<String> = info
+                        .country
+                        .into_iter()
+                        .chain(info.asn.map(|asn| format!("AS{}", asn)))
+                        .collect();
+                    if location.is_empty() {
+                        reason.to_string()
+                    } else {
+                        format!("{} ({})", reason, location.join(", "))
+                    }
+                }
+                None => reason.to_string(),
+            };
+            if let Err(e) = blocklist.block_detected(ip, &reason).await {
+                log::error!("Failed to block {}: {}", ip, e);
+            }
+        }
+    }
+
+    /// Add to counters in sliding windows, returning their counts
+    ///
+    /// Each counter is a key, an increment and a window in seconds. Counts
+    /// live in Redis so that every instance enforces the same thresholds.
+    async fn count_windows(&self, counters: &[(String, u64, u32)]) -> Result<Vec<u64>, redis::RedisError> {
+        let now = current_millis();
+        let mut invocation = self.window_script.prepare_invoke();
+        for (key, increment, window) in counters {
+            let (current, previous, weight) = window_buckets(key, *window, now);
+            invocation
+                .key(current)
+                .key(previous)
+                .arg(*increment)
+                .arg(weight)
+                .arg(2 * (*window).max(1));
         }
+        let mut conn = self.redis.get();
+        invocation.invoke_async(&mut conn).await
     }
 
     /// Check if a connection should be blocked due to DDoS detection
@@ -92,26 +801,35 @@ impl DdosDetector {
     /// * `Ok(false)` if the connection should be allowed
     /// * `Ok(true)` if the connection should be blocked
     /// * `Err(DdosDetectionError)` if there was an error during detection
-    pub async fn check_connection(&mut self, ip: &str) -> Result<bool, DdosDetectionError> {
-        let key = format!("connection:{}", ip);
-        let mut conn = match self.redis.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
-        };
+    pub async fn check_connection(&self, ip: &str) -> Result<bool, DdosDetectionError> {
+        let client = self.subnets.client_key(ip);
+        let ip = client.as_str();
+        let local_count = track_events(
+            &mut self.connection_tracker.lock().unwrap_or_else(|e| e.into_inner()),
+            ip,
+            self.config.connection_rate_window,
+            self.config.connection_rate_threshold,
+        );
         
-        let count: u32 = match conn.incr(&key, 1).await {
-            Ok(count) => count,
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
+        let counter = (format!("connection:{}", ip), 1, self.config.connection_rate_window);
+        let count = match self.count_windows(&[counter]).await {
+            Ok(counts) => counts[0] as u32,
+            Err(e) => {
+                log::warn!("Falling back to in-memory connection tracking: {}", e);
+                metrics::increment_counter!("ddos_detector_local_fallbacks_total");
+                local_count
+            }
         };
         
-        if count == 1 {
-            let _: () = match conn.expire::<_, ()>(&key, self.config.connection_rate_window as usize).await {
-                Ok(_) => (),
-                Err(e) => return Err(DdosDetectionError::RedisError(e)),
-            };
-        }
-        
         if count > self.config.connection_rate_threshold {
+            self.block_detected(
+                ip,
+                "connection_rate",
+                "connection rate threshold exceeded",
+                detection_confidence(count as u64, self.config.connection_rate_threshold as u64),
+                0,
+            )
+            .await;
             return Ok(true);
         }
         
@@ -120,50 +838,402 @@ impl DdosDetector {
 
     /// Check if a request should be blocked due to DDoS detection
     /// 
+    /// The client's request count and traffic volume, and the requests made
+    /// with its TLS fingerprint, are counted in sliding windows and scored
+    /// by the detection pipeline, then the client's subnet is checked.
+    /// 
     /// # Arguments
     /// 
-    /// * `ip` - The IP address of the request
-    /// * `size` - The size of the request in bytes
+    /// * `request` - The request; its IP, size, path, query and TLS fingerprints are used
     /// 
     /// # Returns
     /// 
-    /// * `Ok(false)` if the request should be allowed
-    /// * `Ok(true)` if the request should be blocked
+    /// * `Ok(None)` if the request should be allowed
+    /// * `Ok(Some(classification))` if the client was detected; application-layer
+    ///   detections should be challenged, anything else blocked
     /// * `Err(DdosDetectionError)` if there was an error during detection
-    pub async fn check_request(&mut self, ip: &str, size: u64) -> Result<bool, DdosDetectionError> {
-        let key = format!("request:{}", ip);
-        let mut conn = match self.redis.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
-        };
+    pub async fn check_request(&self, request: &RequestContext) -> Result<Option<Classification>, DdosDetectionError> {
+        let size = request.size;
+        let client = self.subnets.client_key(&request.ip);
+        let ip = client.as_str();
+        let local_count = track_events(
+            &mut self.request_tracker.lock().unwrap_or_else(|e| e.into_inner()),
+            ip,
+            self.config.request_rate_window,
+            self.config.request_rate_threshold,
+        );
+        let local_volume = track_traffic(
+            &mut self.traffic_tracker.lock().unwrap_or_else(|e| e.into_inner()),
+            ip,
+            size,
+            self.config.traffic_volume_window,
+            self.config.request_rate_threshold,
+        );
         
-        let count: u32 = match conn.incr(&key, 1).await {
-            Ok(count) => count,
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
+        let counters = [
+            (format!("request:{}", ip), 1, self.config.request_rate_window),
+            (format!("volume:{}", ip), size, self.config.traffic_volume_window),
+        ];
+        let (requests, volume, redis_available) = match self.count_windows(&counters).await {
+            Ok(counts) => (counts[0], counts[1], true),
+            Err(e) => {
+                log::warn!("Falling back to in-memory request tracking: {}", e);
+                metrics::increment_counter!("ddos_detector_local_fallbacks_total");
+                (local_count as u64, local_volume, false)
+            }
         };
-        let volume: u64 = match conn.incr(format!("volume:{}", ip), size).await {
-            Ok(volume) => volume,
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
+
+        let fingerprint_requests = match &self.fingerprints {
+            Some(fingerprints) if redis_available => {
+                match fingerprints.record(request.ja3.as_deref(), request.ja4.as_deref()).await {
+                    Ok(count) => count,
+                    Err(e) => {
+                        log::error!("Failed to count TLS fingerprint of {}: {}", ip, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
         };
-        
-        if count == 1 {
-            let _: () = match conn.expire::<_, ()>(&key, self.config.request_rate_window as usize).await {
-                Ok(_) => (),
-                Err(e) => return Err(DdosDetectionError::RedisError(e)),
-            };
-            let _: () = match conn.expire::<_, ()>(format!("volume:{}", ip), self.config.traffic_volume_window as usize).await {
-                Ok(_) => (),
-                Err(e) => return Err(DdosDetectionError::RedisError(e)),
-            };
+
+        let context = DetectionContext {
+            client: ip,
+            path: &request.path,
+            query: &request.query,
+            requests,
+            volume,
+            fingerprint_requests,
+        };
+        if let Some(classification) = self.run_pipeline(&context).await {
+            return Ok(Some(classification));
+        }
+
+        // Subnet detection needs Redis too, so only the per-client checks run without it
+        if self.config.subnet.enabled && redis_available {
+            return self.check_subnet(ip, size).await;
         }
         
-        if count > self.config.request_rate_threshold || volume > self.config.traffic_volume_threshold {
+        Ok(None)
+    }
+
+    /// Check a completed or closed connection for low-and-slow transfer
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the connection
+    /// * `stats` - The connection's transfer statistics
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(false)` if the client should be allowed
+    /// * `Ok(true)` if the client made too many slow connections and should be blocked
+    /// * `Err(DdosDetectionError)` if there was an error during detection
+    pub async fn check_slow_connection(&self, ip: &str, stats: &ConnectionStats) -> Result<bool, DdosDetectionError> {
+        let config = &self.config.slow_connection;
+        if !config.enabled || !stats.is_slow(config) {
+            return Ok(false);
+        }
+        metrics::increment_counter!("ddos_slow_connections_total");
+
+        let client = self.subnets.client_key(ip);
+        let counts = self.count_windows(&[(format!("slow:{}", client), 1, config.window)]).await?;
+        if counts[0] > config.max_slow_connections as u64 {
+            self.block_detected(
+                &client,
+                "slow_connection",
+                "slow connection threshold exceeded",
+                detection_confidence(counts[0], config.max_slow_connections as u64),
+                0,
+            )
+            .await;
             return Ok(true);
         }
-        
         Ok(false)
     }
 
+    /// Check traffic seen in network flows for volumetric attacks
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The source address of the flows
+    /// * `packets` - Packets sent by the source since it was last checked
+    /// * `bytes` - Bytes sent by the source since it was last checked
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(None)` if the source should be allowed
+    /// * `Ok(Some(Classification))` if the source exceeded a flow threshold and was blocked
+    /// * `Err(DdosDetectionError)` if there was an error during detection
+    pub async fn check_flow(&self, ip: &str, packets: u64, bytes: u64) -> Result<Option<Classification>, DdosDetectionError> {
+        let config = &self.config.flow;
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let client = self.subnets.client_key(ip);
+        let counters = [
+            (format!("flow_packets:{}", client), packets, config.window),
+            (format!("flow_bytes:{}", client), bytes, config.window),
+        ];
+        let counts = self.count_windows(&counters).await?;
+        let window = config.window.max(1) as u64;
+        let (packet_rate, byte_rate) = (counts[0] / window, counts[1] / window);
+
+        let exceeded = if packet_rate > config.packet_rate_threshold {
+            Some(("flow_packet_rate", packet_rate, config.packet_rate_threshold))
+        } else if byte_rate > config.byte_rate_threshold {
+            Some(("flow_byte_rate", byte_rate, config.byte_rate_threshold))
+        } else {
+            None
+        };
+        let Some((vector, observed, threshold)) = exceeded else {
+            return Ok(None);
+        };
+
+        let classification = Classification::threshold_exceeded(AttackCategory::Volumetric, vector, observed, threshold);
+        let reason = format!("{} threshold exceeded", vector.replace('_', " "));
+        self.block_detected(&client, vector, &reason, classification.confidence, packet_rate)
+            .await;
+        Ok(Some(classification))
+    }
+
+    /// Score a request with every detector and act on the verdict
+    ///
+    /// Detectors that fail are skipped. Application-layer detections are
+    /// weaker evidence than exceeding a volumetric threshold, so those
+    /// clients are recorded for challenging rather than blocked.
+    async fn run_pipeline(&self, context: &DetectionContext<'_>) -> Option<Classification> {
+        let mut scores = Vec::new();
+        for detector in &self.pipeline {
+            if self.scorer.weight(detector.name()) == 0.0 {
+                continue;
+            }
+            match detector.score(context).await {
+                Ok(Some(score)) => scores.push((detector.name().to_string(), score)),
+                Ok(None) => (),
+                Err(e) => {
+                    metrics::increment_counter!("ddos_detector_errors_total", "detector" => detector.name().to_string());
+                    log::error!("Detector {} failed for {}: {}", detector.name(), context.client, e);
+                }
+            }
+        }
+
+        let verdict = self.scorer.combine(scores)?;
+        let classification = verdict.classification;
+        metrics::increment_counter!(
+            "ddos_pipeline_detections_total",
+            "detector" => verdict.detector,
+            "vector" => classification.vector.clone()
+        );
+        let rps = context.requests / self.config.request_rate_window.max(1) as u64;
+        if classification.category == AttackCategory::ApplicationLayer {
+            log::warn!("HTTP flood from {}: {} ({} requests)", context.client, classification.vector, context.requests);
+            self.record_attack("http_flood", Some(context.client), rps, Some("challenge")).await;
+        } else {
+            let reason = format!("{} threshold exceeded", classification.vector.replace('_', " "));
+            self.block_detected(context.client, &classification.vector, &reason, classification.confidence, rps)
+                .await;
+        }
+        Some(classification)
+    }
+
+    async fn check_subnet(&self, client: &str, size: u64) -> Result<Option<Classification>, DdosDetectionError> {
+        let subnet = match parse_network(client) {
+            Some(network) => self.subnets.subnet_of(network.network()).to_string(),
+            None => return Ok(None),
+        };
+        let counters = [
+            (format!("request:subnet:{}", subnet), 1, self.config.request_rate_window),
+            (format!("volume:subnet:{}", subnet), size, self.config.traffic_volume_window),
+        ];
+        let counts = self.count_windows(&counters).await?;
+        let (count, volume) = (counts[0] as u32, counts[1]);
+
+        if count > self.config.subnet.request_rate_threshold {
+            let classification = Classification::threshold_exceeded(
+                AttackCategory::Volumetric,
+                "subnet_request_rate",
+                count as u64,
+                self.config.subnet.request_rate_threshold as u64,
+            );
+            self.block_detected(
+                &subnet,
+                "subnet_request_rate",
+                "subnet request rate threshold exceeded",
+                classification.confidence,
+                count as u64 / self.config.request_rate_window.max(1) as u64,
+            )
+            .await;
+            return Ok(Some(classification));
+        }
+        if volume > self.config.subnet.traffic_volume_threshold {
+            let classification = Classification::threshold_exceeded(
+                AttackCategory::Volumetric,
+                "subnet_traffic_volume",
+                volume,
+                self.config.subnet.traffic_volume_threshold,
+            );
+            self.block_detected(
+                &subnet,
+                "subnet_traffic_volume",
+                "subnet traffic volume threshold exceeded",
+                classification.confidence,
+                0,
+            )
+            .await;
+            return Ok(Some(classification));
+        }
+
+        Ok(None)
+    }
+
+    /// Count a request towards the service-wide totals and detect distributed attacks
+    ///
+    /// Counters use fixed windows shared by every instance through Redis.
+    /// Distinct source IPs are counted with HyperLogLogs, so memory stays
+    /// constant however many addresses an attack uses.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the request
+    /// * `path` - The requested path, or an empty string if unknown
+    /// * `size` - The size of the request in bytes
+    pub async fn check_aggregate(
+        &self,
+        ip: &str,
+        path: &str,
+        size: u64,
+    ) -> Result<Option<AggregateDetection>, DdosDetectionError> {
+        let config = &self.config.aggregate;
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let now = get_current_timestamp();
+        let request_window = self.config.request_rate_window.max(1) as u64;
+        let volume_window = self.config.traffic_volume_window.max(1) as u64;
+        let ip_window = config.distinct_ip_window.max(1) as u64;
+        let request_key = format!("aggregate:requests:{}", now / request_window);
+        let volume_key = format!("aggregate:volume:{}", now / volume_window);
+        let ips_key = format!("aggregate:ips:{}", now / ip_window);
+        let previous_ips_key = format!("aggregate:ips:{}", now / ip_window - 1);
+        let path = path.split('?').next().unwrap_or_default();
+
+        let mut pipe = redis::pipe();
+        pipe.cmd("INCR").arg(&request_key)
+            .cmd("EXPIRE").arg(&request_key).arg(request_window).ignore()
+            .cmd("INCRBY").arg(&volume_key).arg(size)
+            .cmd("EXPIRE").arg(&volume_key).arg(volume_window).ignore()
+            .cmd("PFADD").arg(&ips_key).arg(ip).ignore()
+            // Kept for two windows so it can be compared against
+            .cmd("EXPIRE").arg(&ips_key).arg(2 * ip_window).ignore()
+            .cmd("PFCOUNT").arg(&ips_key)
+            .cmd("PFCOUNT").arg(&previous_ips_key);
+        let path_key = format!("aggregate:path:{}:{}", path, now / request_window);
+        if !path.is_empty() {
+            pipe.cmd("INCR").arg(&path_key)
+                .cmd("EXPIRE").arg(&path_key).arg(request_window).ignore();
+        }
+
+        let mut conn = self.redis.get();
+        let counts: Vec<u64> = pipe.query_async(&mut conn).await?;
+        let (requests, volume, ips, previous_ips, path_requests) = match counts[..] {
+            [requests, volume, ips, previous_ips] => (requests, volume, ips, previous_ips, None),
+            [requests, volume, ips, previous_ips, path_requests] => {
+                (requests, volume, ips, previous_ips, Some(path_requests))
+            }
+            _ => return Err(DdosDetectionError::DetectionError("unexpected aggregate counts".to_string())),
+        };
+
+        let anomaly = if self.config.baseline.enabled {
+            self.detect_baseline_anomaly(now, path, requests, volume, path_requests).await?
+        } else {
+            None
+        };
+        let detection = if requests > config.request_rate_threshold {
+            Some(AggregateDetection::new(AggregateDetectionType::GlobalRequestRate, None, requests, config.request_rate_threshold))
+        } else if volume > config.traffic_volume_threshold {
+            Some(AggregateDetection::new(AggregateDetectionType::GlobalTrafficVolume, None, volume, config.traffic_volume_threshold))
+        } else if let Some(count) = path_requests.filter(|count| *count > config.path_request_rate_threshold) {
+            Some(AggregateDetection::new(AggregateDetectionType::PathRequestRate, Some(path), count, config.path_request_rate_threshold))
+        } else if anomaly.is_some() {
+            anomaly
+        } else if is_distinct_ip_spike(ips, previous_ips, config) {
+            let threshold = (previous_ips as f64 * config.distinct_ip_spike_factor) as u64;
+            Some(AggregateDetection::new(AggregateDetectionType::DistinctIpSpike, None, ips, threshold.max(config.distinct_ip_min)))
+        } else {
+            None
+        };
+
+        if let Some(detection) = &detection {
+            metrics::increment_counter!(
+                "ddos_aggregate_detections_total",
+                "type" => detection.detection_type.as_str()
+            );
+            log::warn!(
+                "Distributed attack detected: {} {} exceeds {}{}",
+                detection.detection_type.as_str(),
+                detection.observed,
+                detection.threshold,
+                detection.target.as_deref().map(|path| format!(" on {}", path)).unwrap_or_default()
+            );
+            let rps = match detection.detection_type {
+                AggregateDetectionType::GlobalRequestRate
+                | AggregateDetectionType::PathRequestRate
+                | AggregateDetectionType::RequestRateAnomaly
+                | AggregateDetectionType::PathRequestRateAnomaly => detection.observed / request_window,
+                _ => 0,
+            };
+            self.record_attack(detection.detection_type.as_str(), detection.target.as_deref(), rps, None)
+                .await;
+        }
+        Ok(detection)
+    }
+
+    /// Update the baselines of the aggregate counters and detect deviations from them
+    async fn detect_baseline_anomaly(
+        &self,
+        now: u64,
+        path: &str,
+        requests: u64,
+        volume: u64,
+        path_requests: Option<u64>,
+    ) -> Result<Option<AggregateDetection>, DdosDetectionError> {
+        let observation = |metric: String, window: u32, value| Observation {
+            metric,
+            window_seconds: window as u64,
+            value,
+        };
+        let mut observations = vec![
+            observation("requests".to_string(), self.config.request_rate_window, requests),
+            observation("volume".to_string(), self.config.traffic_volume_window, volume),
+        ];
+        if let Some(count) = path_requests {
+            observations.push(observation(format!("path:{}", path), self.config.request_rate_window, count));
+        }
+        let baselines = self.baseline.observe(now, &observations).await?;
+
+        let detection_types = [
+            AggregateDetectionType::RequestRateAnomaly,
+            AggregateDetectionType::TrafficVolumeAnomaly,
+            AggregateDetectionType::PathRequestRateAnomaly,
+        ];
+        for (baseline, name) in baselines.iter().zip(["requests", "volume"]) {
+            metrics::gauge!("ddos_baseline_mean", baseline.overall.mean, "metric" => name);
+            metrics::gauge!("ddos_baseline_stddev", baseline.overall.stddev, "metric" => name);
+            metrics::gauge!("ddos_baseline_seasonal_mean", baseline.seasonal.mean, "metric" => name);
+        }
+        for ((baseline, observation), detection_type) in baselines.iter().zip(&observations).zip(detection_types) {
+            let threshold = match self.baseline.threshold(baseline) {
+                Some(threshold) if observation.value as f64 > threshold => threshold,
+                _ => continue,
+            };
+            let target = (detection_type == AggregateDetectionType::PathRequestRateAnomaly).then_some(path);
+            return Ok(Some(AggregateDetection::new(detection_type, target, observation.value, threshold as u64)));
+        }
+        Ok(None)
+    }
+
     /// Detect anomalies in traffic patterns
     /// 
     /// # Arguments
@@ -176,11 +1246,10 @@ impl DdosDetector {
     /// * `Ok(true)` if anomalies were detected
     /// * `Err(DdosDetectionError)` if there was an error during detection
     async fn detect_anomaly(&self, ip: &str) -> Result<bool, DdosDetectionError> {
+        let client = self.subnets.client_key(ip);
+        let ip = client.as_str();
         let key = format!("anomaly:{}", ip);
-        let mut conn = match self.redis.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
-        };
+        let mut conn = self.redis.get();
         
         let count: u32 = match conn.incr(&key, 1).await {
             Ok(count) => count,
@@ -202,24 +1271,33 @@ impl DdosDetector {
     }
 
     /// Reset DDoS detection for a given IP
-    /// 
+    ///
+    /// Forgets the client's counts and anomaly history, lifts the blocks
+    /// the detector added for it and resets its reputation, undoing what a
+    /// detection did. Blocks added by others are reported, not lifted.
+    ///
     /// # Arguments
-    /// 
+    ///
     /// * `ip` - The IP address to reset detection for
-    pub async fn reset_detection(&mut self, ip: &str) -> Result<(), DdosDetectionError> {
-        let mut conn = match self.redis.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
-        };
-        let _: () = match conn.del::<_, ()>(format!("connection:{}", ip)).await {
-            Ok(_) => (),
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
-        };
-        let _: () = match conn.del::<_, ()>(format!("request:{}", ip)).await {
-            Ok(_) => (),
-            Err(e) => return Err(DdosDetectionError::RedisError(e)),
-        };
-        let _: () = match conn.del::<_, ()>(format!("volume:{}", ip)).await {
+    pub async fn reset_detection(&self, ip: &str) -> Result<DetectionReset, DdosDetectionError> {
+        let client = self.subnets.client_key(ip);
+        let ip = client.as_str();
+        self.connection_tracker.lock().unwrap_or_else(|e| e.into_inner()).remove(&client);
+        self.request_tracker.lock().unwrap_or_else(|e| e.into_inner()).remove(&client);
+        self.traffic_tracker.lock().unwrap_or_else(|e| e.into_inner()).remove(&client);
+        let now = current_millis();
+        let mut keys = Vec::new();
+        for (counter, window) in [
+            ("connection", self.config.connection_rate_window),
+            ("request", self.config.request_rate_window),
+            ("volume", self.config.traffic_volume_window),
+        ] {
+            let (current, previous, _) = window_buckets(&format!("{}:{}", counter, ip), window, now);
+            keys.push(current);
+            keys.push(previous);
+        }
+        let mut conn = self.redis.get();
+        let _: () = match conn.del::<_, ()>(keys).await {
             Ok(_) => (),
             Err(e) => return Err(DdosDetectionError::RedisError(e)),
         };
@@ -227,10 +1305,88 @@ impl DdosDetector {
             Ok(_) => (),
             Err(e) => return Err(DdosDetectionError::RedisError(e)),
         };
-        Ok(())
+
+        let mut reset = DetectionReset::default();
+        if let Some(blocklist) = &self.blocklist {
+            reset.unblocked = blocklist.unblock_detected(ip).await?;
+            reset.still_blocked = blocklist.check(ip).await?;
+        }
+        if let Some(reputation) = &self.reputation {
+            match reputation.reset(ip).await {
+                Ok(()) => reset.reputation_reset = true,
+                Err(e) => log::error!("Failed to reset reputation of {}: {}", ip, e),
+            }
+        }
+        Ok(reset)
     }
 }
 
+/// Record an event in a client's in-memory sliding window, returning the
+/// number of events in the window
+///
+/// In-memory tracking stands in for Redis while it is unavailable. Only
+/// `threshold + 1` events are kept per client, which is enough to tell
+/// whether the threshold is exceeded.
+fn track_events(tracker: &mut LruMap<String, VecDeque<Instant>>, client: &str, window: u32, threshold: u32) -> u32 {
+    let now = Instant::now();
+    let window = Duration::from_secs(window as u64);
+    let events = tracker.get_or_insert_with(client.to_string(), VecDeque::new);
+    while events.front().is_some_and(|at| now.duration_since(*at) >= window) {
+        events.pop_front();
+    }
+    events.push_back(now);
+    while events.len() > threshold as usize + 1 {
+        events.pop_front();
+    }
+    events.len() as u32
+}
+
+/// Record traffic in a client's in-memory sliding window, returning the
+/// bytes in the window
+///
+/// At most `max_entries` requests are kept per client, with the oldest
+/// merged into the next so that the total is preserved.
+fn track_traffic(
+    tracker: &mut LruMap<String, VecDeque<(Instant, u64)>>,
+    client: &str,
+    size: u64,
+    window: u32,
+    max_entries: u32,
+) -> u64 {
+    let now = Instant::now();
+    let window = Duration::from_secs(window as u64);
+    let traffic = tracker.get_or_insert_with(client.to_string(), VecDeque::new);
+    while traffic.front().is_some_and(|(at, _)| now.duration_since(*at) >= window) {
+        traffic.pop_front();
+    }
+    traffic.push_back((now, size));
+    while traffic.len() > max_entries.max(1) as usize {
+        if let Some((_, bytes)) = traffic.pop_front() {
+            if let Some((_, next)) = traffic.front_mut() {
+                *next += bytes;
+            }
+        }
+    }
+    traffic.iter().map(|(_, bytes)| bytes).sum()
+}
+
+/// Current and previous bucket of a sliding window counter, and the weight
+/// of the previous bucket at `now` (Unix milliseconds)
+fn window_buckets(key: &str, window: u32, now: u64) -> (String, String, f64) {
+    let window = window.max(1) as u64 * 1000;
+    let bucket = now / window;
+    let weight = 1.0 - (now % window) as f64 / window as f64;
+    (format!("{}:{}", key, bucket), format!("{}:{}", key, bucket.saturating_sub(1)), weight)
+}
+
+/// Get the current Unix timestamp in milliseconds
+fn current_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
 /// Get the current Unix timestamp
 fn get_current_timestamp() -> u64 {
     SystemTime::now()
@@ -242,11 +1398,13 @@ fn get_current_timestamp() -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::detection::DetectorScore;
     use redis::Client;
 
     #[tokio::test]
     async fn test_connection_detection() {
         let client = Client::open("redis://127.0.0.1:6379").unwrap();
+        let pool = RedisPool::new(client, 1).await.unwrap();
         let config = DdosDetectionConfig {
             connection_rate_threshold: 2,
             connection_rate_window: 60,
@@ -256,9 +1414,18 @@ mod tests {
             traffic_volume_window: 60,
             anomaly_threshold: 3.0,
             anomaly_window: 300,
+            max_tracked_clients: 1000,
+            subnet: SubnetDetectionConfig::default(),
+            aggregate: AggregateDetectionConfig::default(),
+            baseline: BaselineDetectionConfig::default(),
+            slow_connection: SlowConnectionConfig::default(),
+            flow: FlowDetectionConfig::default(),
+            http_flood: HttpFloodConfig::default(),
+            tls_fingerprint: TlsFingerprintConfig::default(),
+            pipeline: DetectionPipelineConfig::default(),
         };
         
-        let mut detector = DdosDetector::new(client, config);
+        let detector = DdosDetector::new(pool, config);
         
         // First connection should be allowed
         assert!(!detector.check_connection("127.0.0.1").await.unwrap());
@@ -270,7 +1437,110 @@ mod tests {
         assert!(detector.check_connection("127.0.0.1").await.unwrap());
         
         // Reset should allow new connections
-        detector.reset_detection("127.0.0.1").await.unwrap();
+        let reset = detector.reset_detection("127.0.0.1").await.unwrap();
+        assert!(reset.unblocked.is_empty());
+        assert!(reset.still_blocked.is_none());
         assert!(!detector.check_connection("127.0.0.1").await.unwrap());
     }
+
+    /// Detector flagging every client that made a request to `/login`
+    struct LoginDetector;
+
+    #[async_trait::async_trait]
+    impl Detector for LoginDetector {
+        fn name(&self) -> &str {
+            "login"
+        }
+
+        async fn score(&self, context: &DetectionContext<'_>) -> Result<Option<DetectorScore>, DdosDetectionError> {
+            Ok((context.path == "/login").then(|| DetectorScore {
+                score: 2.0,
+                classification: Classification {
+                    category: AttackCategory::ApplicationLayer,
+                    vector: "login_flood".to_string(),
+                    confidence: 50,
+                    metrics: Vec::new(),
+                },
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_detector() {
+        let client = Client::open("redis://127.0.0.1:6379").unwrap();
+        let pool = RedisPool::new(client, 1).await.unwrap();
+        let mut config = DdosDetectionConfig::default();
+        config.subnet.enabled = false;
+        let detector = DdosDetector::new(pool, config).with_detector(Box::new(LoginDetector));
+
+        let request = |path: &str| RequestContext {
+            ip: "192.0.2.44".to_string(),
+            path: path.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(detector.check_request(&request("/")).await.unwrap(), None);
+        let classification = detector.check_request(&request("/login")).await.unwrap().unwrap();
+        assert_eq!(classification.vector, "login_flood");
+        detector.reset_detection("192.0.2.44").await.unwrap();
+    }
+
+    #[test]
+    fn test_window_buckets() {
+        let (current, previous, weight) = window_buckets("request:192.0.2.1", 60, 120_000);
+        assert_eq!(current, "request:192.0.2.1:2");
+        assert_eq!(previous, "request:192.0.2.1:1");
+        assert_eq!(weight, 1.0);
+
+        // Three quarters into the bucket, a quarter of the previous one is still in the window
+        let (_, _, weight) = window_buckets("request:192.0.2.1", 60, 165_000);
+        assert_eq!(weight, 0.25);
+    }
+
+    #[test]
+    fn test_local_tracking() {
+        let mut events = LruMap::new("test_events", 10);
+        for _ in 0..5 {
+            track_events(&mut events, "192.0.2.1", 60, 2);
+        }
+        // Only enough events to exceed the threshold are kept
+        assert_eq!(track_events(&mut events, "192.0.2.1", 60, 2), 3);
+        assert_eq!(track_events(&mut events, "192.0.2.2", 60, 2), 1);
+
+        let mut traffic = LruMap::new("test_traffic", 10);
+        for _ in 0..5 {
+            track_traffic(&mut traffic, "192.0.2.1", 100, 60, 2);
+        }
+        assert_eq!(track_traffic(&mut traffic, "192.0.2.1", 100, 60, 2), 600);
+    }
+
+    #[test]
+    fn test_slow_connection() {
+        let config = SlowConnectionConfig::default();
+        let stats = |header_bytes, header_ms, body_bytes, body_ms| ConnectionStats {
+            header_bytes,
+            header_ms,
+            body_bytes,
+            body_ms,
+        };
+
+        assert!(!stats(800, 20, 5000, 100).is_slow(&config));
+        // Slowloris: headers trickled in over 20 seconds
+        assert!(stats(400, 20_000, 0, 0).is_slow(&config));
+        // Headers that never finish are slow at any rate
+        assert!(stats(100_000, 30_000, 0, 0).is_slow(&config));
+        // Slow POST: a body sent a few bytes at a time
+        assert!(stats(800, 20, 600, 60_000).is_slow(&config));
+        // Short transfers aren't judged on their rate
+        assert!(!stats(10, 1000, 10, 1000).is_slow(&config));
+    }
+
+    #[test]
+    fn test_distinct_ip_spike() {
+        let config = AggregateDetectionConfig::default();
+
+        // Few sources are never a spike, however sudden
+        assert!(!is_distinct_ip_spike(500, 0, &config));
+        assert!(is_distinct_ip_spike(5000, 200, &config));
+        assert!(!is_distinct_ip_spike(5000, 2000, &config));
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,317 @@
+//! Degraded operation while Redis is down.
+//!
+//! The first Redis error on the request path that means Redis can't be
+//! reached starts an outage. Until a probe finds Redis back, subsystems
+//! don't wait on it but decide as their `FailurePolicy` says: open, closed,
+//! or from in-memory state. That state is bounded: a local fixed-window
+//! rate limiter, and the blocks added during the outage. When Redis returns,
+//! the requests counted locally are added to the shared window counters and
+//! the blocks are written to Redis before subsystems go back to it.
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use crate::core::blocklist::{BlockEntry, Blocklist, BlocklistError};
+use crate::core::lru::LruMap;
+use crate::core::rate_limiter::{window_key, RateLimitStatus};
+use crate::core::redis_pool::RedisPool;
+use crate::models::{DegradationConfig, FailurePolicy};
+
+/// Adds requests counted in memory to a shared window counter
+///
+/// KEYS: window counter
+/// ARGV: requests counted, seconds left in the window
+const RECONCILE_SCRIPT: &str = r#"
+redis.call('INCRBY', KEYS[1], ARGV[1])
+if redis.call('TTL', KEYS[1]) < 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+return 1
+"#;
+
+/// Subsystem deciding on requests with Redis
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    RateLimit,
+    Blocklist,
+    Quota,
+    Concurrency,
+    Detection,
+}
+
+impl Subsystem {
+    fn name(self) -> &'static str {
+        match self {
+            Subsystem::RateLimit => "rate_limit",
+            Subsystem::Blocklist => "blocklist",
+            Subsystem::Quota => "quota",
+            Subsystem::Concurrency => "concurrency",
+            Subsystem::Detection => "detection",
+        }
+    }
+}
+
+/// Whether an error means Redis can't be reached, rather than a bad command
+pub fn is_outage(error: &redis::RedisError) -> bool {
+    error.is_io_error()
+        || matches!(
+            error.kind(),
+            redis::ErrorKind::IoError
+                | redis::ErrorKind::ClusterDown
+                | redis::ErrorKind::MasterDown
+                | redis::ErrorKind::BusyLoadingError
+        )
+}
+
+/// Requests of a client counted in memory
+struct Window {
+    started: Instant,
+    length: Duration,
+    count: u32,
+}
+
+/// Fixed-window rate limiter kept in memory
+struct FallbackLimiter {
+    /// Windows by client key
+    windows: LruMap<String, Window>,
+}
+
+impl FallbackLimiter {
+    fn new(capacity: usize) -> Self {
+        Self {
+            windows: LruMap::new("degraded_rate_limit", capacity),
+        }
+    }
+
+    /// Count a request, returning the remaining quota or `None` if the limit is exceeded
+    fn count(&mut self, key: &str, cost: u32, limit: u32, window_seconds: u64) -> Option<RateLimitStatus> {
+        let length = Duration::from_secs(window_seconds.max(1));
+        let window = self.windows.get_or_insert_with(key.to_string(), || Window {
+            started: Instant::now(),
+            length,
+            count: 0,
+        });
+        if window.started.elapsed() >= window.length {
+            *window = Window {
+                started: Instant::now(),
+                length,
+                count: 0,
+            };
+        }
+        window.count = window.count.saturating_add(cost);
+
+        let reset = window.length.saturating_sub(window.started.elapsed()).as_secs();
+        (window.count <= limit).then(|| RateLimitStatus {
+            limit,
+            remaining: limit - window.count,
+            reset,
+        })
+    }
+}
+
+/// Current degradation state
+#[derive(Debug, Clone, Serialize)]
+pub struct DegradationStatus {
+    /// Whether Redis is down
+    pub degraded: bool,
+    /// When the current outage started
+    pub since: Option<DateTime<Utc>>,
+    /// Clients counted by the in-memory rate limiter
+    pub tracked_clients: usize,
+    /// Blocks added during the outage, waiting to be written to Redis
+    pub pending_blocks: usize,
+}
+
+/// Redis outage state and in-memory fallbacks, shared by all subsystems
+///
+/// Cloning is cheap and all clones share the same state.
+#[derive(Clone)]
+pub struct Degradation {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Degradation configuration
+    config: DegradationConfig,
+    /// When the current outage started, if Redis is down
+    since: Arc<RwLock<Option<DateTime<Utc>>>>,
+    /// In-memory rate limiter
+    limiter: Arc<Mutex<FallbackLimiter>>,
+    /// Blocks added during the outage
+    pending_blocks: Arc<Mutex<Vec<BlockEntry>>>,
+}
+
+impl Degradation {
+    pub fn new(redis: RedisPool, config: DegradationConfig) -> Self {
+        let limiter = FallbackLimiter::new(config.max_tracked_clients);
+        Self {
+            redis,
+            config,
+            since: Arc::new(RwLock::new(None)),
+            limiter: Arc::new(Mutex::new(limiter)),
+            pending_blocks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Policy of a subsystem
+    pub fn policy(&self, subsystem: Subsystem) -> FailurePolicy {
+        match subsystem {
+            Subsystem::RateLimit => self.config.rate_limit,
+            Subsystem::Blocklist => self.config.blocklist,
+            Subsystem::Quota => self.config.quota,
+            Subsystem::Concurrency => self.config.concurrency,
+            Subsystem::Detection => self.config.detection,
+        }
+    }
+
+    /// The policy of a subsystem if an outage is under way, so that it doesn't wait on Redis
+    pub async fn active_policy(&self, subsystem: Subsystem) -> Option<FailurePolicy> {
+        if self.since.read().await.is_none() {
+            return None;
+        }
+        metrics::increment_counter!("degraded_decisions_total", "subsystem" => subsystem.name());
+        Some(self.policy(subsystem))
+    }
+
+    /// Start an outage if `error` means Redis can't be reached, returning the subsystem's policy
+    ///
+    /// Returns `None` for other errors, which the subsystem reports as before.
+    pub async fn fail(&self, subsystem: Subsystem, error: &redis::RedisError) -> Option<FailurePolicy> {
+        if !is_outage(error) {
+            return None;
+        }
+        let mut since = self.since.write().await;
+        if since.is_none() {
+            error!("Redis is unreachable ({} failed: {}), degrading until it returns", subsystem.name(), error);
+            *since = Some(Utc::now());
+            metrics::gauge!("redis_degraded", 1.0);
+        }
+        drop(since);
+        metrics::increment_counter!("degraded_decisions_total", "subsystem" => subsystem.name());
+        Some(self.policy(subsystem))
+    }
+
+    /// Count a request against the in-memory rate limiter
+    ///
+    /// Returns the remaining quota, or `None` if the limit is exceeded.
+    pub async fn count_request(&self, key: &str, cost: u32, limit: u32, window_seconds: u64) -> Option<RateLimitStatus> {
+        self.limiter.lock().await.count(key, cost, limit, window_seconds)
+    }
+
+    /// Keep a block added during the outage to write to Redis when it returns
+    ///
+    /// Returns whether it was kept; the oldest blocks are kept once the limit is reached.
+    pub async fn queue_block(&self, entry: BlockEntry) -> bool {
+        let mut pending = self.pending_blocks.lock().await;
+        if pending.len() >= self.config.max_pending_blocks {
+            metrics::increment_counter!("degraded_blocks_dropped_total");
+            return false;
+        }
+        pending.push(entry);
+        true
+    }
+
+    /// Get the current degradation state
+    pub async fn status(&self) -> DegradationStatus {
+        let since = *self.since.read().await;
+        DegradationStatus {
+            degraded: since.is_some(),
+            since,
+            tracked_clients: self.limiter.lock().await.windows.len(),
+            pending_blocks: self.pending_blocks.lock().await.len(),
+        }
+    }
+
+    /// Write the in-memory state to Redis
+    async fn reconcile(&self, blocklist: &Blocklist) -> Result<(usize, usize), BlocklistError> {
+        let windows = self.limiter.lock().await.windows.drain();
+        let script = redis::Script::new(RECONCILE_SCRIPT);
+        let mut conn = self.redis.get();
+        let mut counted = 0;
+        for (key, window) in windows {
+            let left = window.length.saturating_sub(window.started.elapsed());
+            if left.is_zero() || window.count == 0 {
+                continue;
+            }
+            let _: () = script
+                .key(window_key(&key))
+                .arg(window.count)
+                .arg(left.as_secs().max(1))
+                .invoke_async(&mut conn)
+                .await?;
+            counted += 1;
+        }
+
+        let blocks = std::mem::take(&mut *self.pending_blocks.lock().await);
+        let now = Utc::now();
+        let mut restored = 0;
+        for entry in blocks.iter().filter(|entry| !entry.is_expired(now)) {
+            blocklist.store(entry).await?;
+            restored += 1;
+        }
+        Ok((counted, restored))
+    }
+
+    /// Probe Redis during outages and end them once it answers
+    pub async fn start(&self, blocklist: Blocklist) {
+        let interval = Duration::from_secs(self.config.probe_interval_seconds.max(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(since) = *self.since.read().await else {
+                continue;
+            };
+
+            let mut conn = self.redis.get();
+            let ping: redis::RedisResult<String> = redis::cmd("PING").query_async(&mut conn).await;
+            if ping.is_err() {
+                continue;
+            }
+            match self.reconcile(&blocklist).await {
+                Ok((counted, restored)) => {
+                    *self.since.write().await = None;
+                    metrics::gauge!("redis_degraded", 0.0);
+                    info!(
+                        "Redis is back after {}s; added {} clients' requests to their limits and restored {} blocks",
+                        (Utc::now() - since).num_seconds(),
+                        counted,
+                        restored
+                    );
+                }
+                Err(e) => error!("Failed to write in-memory state back to Redis: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback_limiter() {
+        let mut limiter = FallbackLimiter::new(2);
+        let status = limiter.count("10.0.0.1", 2, 3, 60).unwrap();
+        assert_eq!(status.remaining, 1);
+        assert!(limiter.count("10.0.0.1", 1, 3, 60).is_some());
+        assert!(limiter.count("10.0.0.1", 1, 3, 60).is_none());
+        assert!(limiter.count("10.0.0.2", 1, 3, 60).is_some());
+
+        // Tracking a third client evicts the least recently used, starting it over
+        limiter.count("10.0.0.3", 1, 3, 60);
+        assert_eq!(limiter.windows.len(), 2);
+        assert_eq!(limiter.count("10.0.0.1", 1, 3, 60).unwrap().remaining, 2);
+
+        let drained = limiter.windows.drain();
+        assert_eq!(drained.len(), 2);
+        assert_eq!(limiter.windows.len(), 0);
+    }
+
+    #[test]
+    fn test_is_outage() {
+        let refused = redis::RedisError::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert!(is_outage(&refused));
+        let wrong_type = redis::RedisError::from((redis::ErrorKind::TypeError, "wrong type"));
+        assert!(!is_outage(&wrong_type));
+    }
+}
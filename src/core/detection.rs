@@ -0,0 +1,634 @@
+//! Detection pipeline for the DDoS protection service.
+//!
+//! This module splits per-client detection into detectors that each score a
+//! request, with 1 meaning the detector's own threshold is reached, and a
+//! scorer combining the scores into a verdict. Built-in detectors cover
+//! request rate, traffic volume, rate anomalies, request entropy and TLS
+//! fingerprint rate, and organization-specific detectors can be added by
+//! implementing `Detector`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use crate::core::abuseipdb::detection_confidence;
+use crate::core::ddos_detector::{
+    AttackCategory, Classification, DdosDetectionConfig, DdosDetectionError, DetectionPipelineConfig,
+    HttpFloodConfig, ScoreCombination, TriggerMetric,
+};
+use crate::core::lru::LruMap;
+use crate::core::redis_pool::RedisPool;
+
+/// What detectors see of a request
+#[derive(Debug, Clone)]
+pub struct DetectionContext<'a> {
+    /// Client the request is attributed to (an IP, or a subnet for grouped IPv6 clients)
+    pub client: &'a str,
+    /// The requested path, or an empty string if unknown
+    pub path: &'a str,
+    /// The query parameters of the request
+    pub query: &'a HashMap<String, String>,
+    /// Requests the client made in the request rate window, including this one
+    pub requests: u64,
+    /// Bytes the client sent in the traffic volume window, including this request
+    pub volume: u64,
+    /// Requests made with the client's TLS fingerprint in the fingerprint window, if known
+    pub fingerprint_requests: Option<u64>,
+}
+
+/// A detector's score for a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectorScore {
+    /// How close the client is to the detector's threshold (1 at the threshold)
+    pub score: f64,
+    /// What the client would be detected for
+    pub classification: Classification,
+}
+
+/// A detector in the pipeline
+///
+/// Detectors are run for every request that passes the allowlist and
+/// blocklist, so they should keep their own I/O to a single round trip.
+#[async_trait]
+pub trait Detector: Send + Sync {
+    /// Name used for weights and metrics
+    fn name(&self) -> &str;
+
+    /// Score a request, or return `None` if the detector can't tell yet
+    async fn score(&self, context: &DetectionContext<'_>) -> Result<Option<DetectorScore>, DdosDetectionError>;
+}
+
+/// Outcome of the pipeline for a detected client
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    /// Combined score of all detectors
+    pub score: f64,
+    /// Name of the detector contributing most to the score
+    pub detector: String,
+    /// Classification from that detector
+    pub classification: Classification,
+}
+
+/// Combines detector scores into a verdict
+#[derive(Debug, Clone)]
+pub struct Scorer {
+    /// Pipeline configuration
+    config: DetectionPipelineConfig,
+}
+
+impl Scorer {
+    /// Create a new scorer
+    pub fn new(config: DetectionPipelineConfig) -> Self {
+        Self { config }
+    }
+
+    /// Weight of a detector's score; unlisted detectors weigh 1
+    pub fn weight(&self, detector: &str) -> f64 {
+        self.config.weights.get(detector).copied().unwrap_or(1.0)
+    }
+
+    /// Combine detector scores, returning a verdict if the combined score
+    /// exceeds the threshold
+    ///
+    /// The verdict's confidence is at least how far the combined score
+    /// exceeds the threshold, so that several detectors agreeing raise it.
+    pub fn combine(&self, scores: Vec<(String, DetectorScore)>) -> Option<Verdict> {
+        let weighted: Vec<(f64, String, Classification)> = scores
+            .into_iter()
+            .map(|(name, score)| (score.score * self.weight(&name), name, score.classification))
+            .collect();
+        let combined = match self.config.combination {
+            ScoreCombination::Max => weighted.iter().map(|(score, _, _)| *score).fold(0.0, f64::max),
+            ScoreCombination::Sum => weighted.iter().map(|(score, _, _)| score).sum(),
+        };
+        if combined <= self.config.threshold {
+            return None;
+        }
+
+        let (_, detector, mut classification) = weighted
+            .into_iter()
+            .max_by(|(a, _, _), (b, _, _)| a.total_cmp(b))?;
+        let confidence = (100.0 * (1.0 - self.config.threshold / combined)).round() as u8;
+        classification.confidence = classification.confidence.max(confidence);
+        Some(Verdict {
+            score: combined,
+            detector,
+            classification,
+        })
+    }
+}
+
+/// Create the built-in detectors named in the pipeline configuration
+///
+/// Unknown names are logged and skipped.
+pub fn builtin_detectors(redis: &RedisPool, config: &DdosDetectionConfig) -> Vec<Box<dyn Detector>> {
+    let mut detectors: Vec<Box<dyn Detector>> = Vec::new();
+    for name in &config.pipeline.detectors {
+        match name.as_str() {
+            "rate" => detectors.push(Box::new(RateDetector::new(config.request_rate_threshold as u64))),
+            "volume" => detectors.push(Box::new(VolumeDetector::new(config.traffic_volume_threshold))),
+            "anomaly" => detectors.push(Box::new(AnomalyDetector::new(config))),
+            "entropy" if config.http_flood.enabled => {
+                detectors.push(Box::new(EntropyDetector::new(redis.clone(), config.http_flood.clone())))
+            }
+            "entropy" => (),
+            "fingerprint" => {
+                detectors.push(Box::new(FingerprintDetector::new(config.tls_fingerprint.request_rate_threshold)))
+            }
+            other => log::warn!("Unknown detector in pipeline: {}", other),
+        }
+    }
+    detectors
+}
+
+/// Scores the client's request rate against its threshold
+pub struct RateDetector {
+    /// Requests allowed per request rate window
+    threshold: u64,
+}
+
+impl RateDetector {
+    /// Create a new request rate detector
+    pub fn new(threshold: u64) -> Self {
+        Self { threshold }
+    }
+}
+
+#[async_trait]
+impl Detector for RateDetector {
+    fn name(&self) -> &str {
+        "rate"
+    }
+
+    async fn score(&self, context: &DetectionContext<'_>) -> Result<Option<DetectorScore>, DdosDetectionError> {
+        Ok(Some(DetectorScore {
+            score: context.requests as f64 / self.threshold.max(1) as f64,
+            classification: Classification::threshold_exceeded(
+                AttackCategory::Volumetric,
+                "request_rate",
+                context.requests,
+                self.threshold,
+            ),
+        }))
+    }
+}
+
+/// Scores the client's traffic volume against its threshold
+pub struct VolumeDetector {
+    /// Bytes allowed per traffic volume window
+    threshold: u64,
+}
+
+impl VolumeDetector {
+    /// Create a new traffic volume detector
+    pub fn new(threshold: u64) -> Self {
+        Self { threshold }
+    }
+}
+
+#[async_trait]
+impl Detector for VolumeDetector {
+    fn name(&self) -> &str {
+        "volume"
+    }
+
+    async fn score(&self, context: &DetectionContext<'_>) -> Result<Option<DetectorScore>, DdosDetectionError> {
+        Ok(Some(DetectorScore {
+            score: context.volume as f64 / self.threshold.max(1) as f64,
+            classification: Classification::threshold_exceeded(
+                AttackCategory::Volumetric,
+                "traffic_volume",
+                context.volume,
+                self.threshold,
+            ),
+        }))
+    }
+}
+
+/// Scores the request rate of the client's TLS fingerprint against its threshold
+///
+/// Every client sharing the fingerprint is challenged once it is reached,
+/// since popular browsers share fingerprints with legitimate users too.
+pub struct FingerprintDetector {
+    /// Requests allowed per fingerprint window
+    threshold: u64,
+}
+
+impl FingerprintDetector {
+    /// Create a new TLS fingerprint detector
+    pub fn new(threshold: u64) -> Self {
+        Self { threshold }
+    }
+}
+
+#[async_trait]
+impl Detector for FingerprintDetector {
+    fn name(&self) -> &str {
+        "fingerprint"
+    }
+
+    async fn score(&self, context: &DetectionContext<'_>) -> Result<Option<DetectorScore>, DdosDetectionError> {
+        Ok(context.fingerprint_requests.map(|requests| DetectorScore {
+            score: requests as f64 / self.threshold.max(1) as f64,
+            classification: Classification::threshold_exceeded(
+                AttackCategory::ApplicationLayer,
+                "tls_fingerprint_rate",
+                requests,
+                self.threshold,
+            ),
+        }))
+    }
+}
+
+/// A client's learned request rate
+#[derive(Debug, Clone, Copy, Default)]
+struct RateHistory {
+    /// Moving average of the client's requests per window
+    mean: f64,
+    /// Moving variance of the client's requests per window
+    variance: f64,
+    /// Requests observed
+    samples: u64,
+}
+
+/// Scores the client's request rate against its own history
+///
+/// Each client's requests per window are tracked as an exponentially
+/// weighted moving average and variance, like the aggregate baselines, and
+/// a client jumping well above its usual rate scores high even when it is
+/// still below the fixed threshold. Histories are kept in memory, so each
+/// instance learns from the requests it sees.
+pub struct AnomalyDetector {
+    /// Learned rates, least recently seen clients evicted first
+    histories: Mutex<LruMap<String, RateHistory>>,
+    /// Standard deviations above its average a client's rate must be
+    sensitivity: f64,
+    /// Weight of each new sample in the average
+    smoothing: f64,
+    /// Fraction above its average a client's rate must also be
+    min_increase: f64,
+    /// Samples needed before a client is scored
+    min_samples: u64,
+    /// Fewest requests per window that score 1
+    min_requests: u64,
+}
+
+impl AnomalyDetector {
+    /// Create a new anomaly detector
+    ///
+    /// Sensitivity is `anomaly_threshold`; smoothing, the minimum sample
+    /// count and the minimum increase are shared with baseline detection.
+    pub fn new(config: &DdosDetectionConfig) -> Self {
+        Self {
+            histories: Mutex::new(LruMap::new("anomaly_detector", config.max_tracked_clients)),
+            sensitivity: config.anomaly_threshold,
+            smoothing: config.baseline.smoothing,
+            min_increase: config.baseline.min_increase,
+            min_samples: config.baseline.min_samples,
+            min_requests: config.pipeline.anomaly_min_requests,
+        }
+    }
+
+    /// Score a client's requests in the window, learning from them unless they are anomalous
+    fn observe(&self, client: &str, requests: u64) -> Option<DetectorScore> {
+        let mut histories = self.histories.lock().unwrap_or_else(|e| e.into_inner());
+        let history = histories.get_or_insert_with(client.to_string(), RateHistory::default);
+        let threshold = (history.mean + self.sensitivity * history.variance.sqrt())
+            .max(history.mean * (1.0 + self.min_increase))
+            .max(self.min_requests as f64);
+        let score = requests as f64 / threshold;
+        let learned = history.samples >= self.min_samples;
+
+        // Anomalous windows would drag the average up towards the attack rate
+        if !learned || score <= 1.0 {
+            if history.samples == 0 {
+                history.mean = requests as f64;
+            } else {
+                let diff = requests as f64 - history.mean;
+                let increment = self.smoothing * diff;
+                history.mean += increment;
+                history.variance = (1.0 - self.smoothing) * (history.variance + diff * increment);
+            }
+            history.samples += 1;
+        }
+        if !learned {
+            return None;
+        }
+
+        Some(DetectorScore {
+            score,
+            classification: Classification {
+                category: AttackCategory::Anomaly,
+                vector: "request_rate_anomaly".to_string(),
+                confidence: detection_confidence(requests, threshold as u64),
+                metrics: vec![TriggerMetric {
+                    name: "request_rate".to_string(),
+                    observed: requests as f64,
+                    threshold,
+                }],
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl Detector for AnomalyDetector {
+    fn name(&self) -> &str {
+        "anomaly"
+    }
+
+    async fn score(&self, context: &DetectionContext<'_>) -> Result<Option<DetectorScore>, DdosDetectionError> {
+        Ok(self.observe(context.client, context.requests))
+    }
+}
+
+/// Scores how much the client's requests look like a layer-7 flood
+///
+/// Layer-7 floods stay under volumetric thresholds by making requests that
+/// are expensive to serve: hammering one path, randomizing query strings,
+/// or adding random parameters to bypass caches. Each client's requests in
+/// the window are profiled in Redis so that every instance shares them.
+pub struct EntropyDetector {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// HTTP flood detection configuration
+    config: HttpFloodConfig,
+}
+
+impl EntropyDetector {
+    /// Create a new entropy detector
+    pub fn new(redis: RedisPool, config: HttpFloodConfig) -> Self {
+        Self { redis, config }
+    }
+}
+
+#[async_trait]
+impl Detector for EntropyDetector {
+    fn name(&self) -> &str {
+        "entropy"
+    }
+
+    async fn score(&self, context: &DetectionContext<'_>) -> Result<Option<DetectorScore>, DdosDetectionError> {
+        let window = self.config.window.max(1) as u64;
+        let bucket = chrono::Utc::now().timestamp() as u64 / window;
+        let client = context.client;
+        let requests_key = format!("flood:requests:{}:{}", client, bucket);
+        let paths_key = format!("flood:paths:{}:{}", client, bucket);
+        let queries_key = format!("flood:queries:{}:{}", client, bucket);
+        let random_key = format!("flood:random:{}:{}", client, bucket);
+        let path = context.path.split('?').next().unwrap_or_default();
+        let random = context.query.values().any(|value| looks_random(value));
+
+        let mut conn = self.redis.get();
+        let (requests, path_requests, distinct_queries, random_params): (u64, u64, u64, u64) = redis::pipe()
+            .cmd("INCR").arg(&requests_key)
+            .cmd("EXPIRE").arg(&requests_key).arg(window).ignore()
+            .cmd("HINCRBY").arg(&paths_key).arg(path).arg(1)
+            .cmd("EXPIRE").arg(&paths_key).arg(window).ignore()
+            .cmd("PFADD").arg(&queries_key).arg(canonical_query(context.query)).ignore()
+            .cmd("PFCOUNT").arg(&queries_key)
+            .cmd("EXPIRE").arg(&queries_key).arg(window).ignore()
+            .cmd("INCRBY").arg(&random_key).arg(random as u64)
+            .cmd("EXPIRE").arg(&random_key).arg(window).ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        let profile = RequestProfile {
+            requests,
+            path_requests,
+            distinct_queries,
+            random_params,
+        };
+        Ok(profile.score(&self.config))
+    }
+}
+
+/// Flood pattern found in a client's requests
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HttpFloodPattern {
+    /// Requests hammer a single path
+    PathConcentration,
+    /// Nearly every request has a different query string
+    RandomizedQuery,
+    /// Requests carry random parameter values to bypass caches
+    CacheBusting,
+}
+
+impl HttpFloodPattern {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::PathConcentration => "path_concentration",
+            Self::RandomizedQuery => "randomized_query",
+            Self::CacheBusting => "cache_busting",
+        }
+    }
+
+    /// Name of the share that reveals the pattern
+    fn metric(&self) -> &'static str {
+        match self {
+            Self::PathConcentration => "path_concentration",
+            Self::RandomizedQuery => "query_cardinality",
+            Self::CacheBusting => "cache_busting_ratio",
+        }
+    }
+}
+
+/// Confidence (0-100) that a share reaching its threshold is an attack,
+/// from 50 at the threshold to 100 when every request matches
+pub fn share_confidence(share: f64, threshold: f64) -> u8 {
+    if share < threshold {
+        return 0;
+    }
+    if threshold >= 1.0 {
+        return 100;
+    }
+    (50.0 + 50.0 * ((share - threshold) / (1.0 - threshold)).clamp(0.0, 1.0)).round() as u8
+}
+
+/// A client's requests in the current window
+#[derive(Debug, Clone, Copy, Default)]
+struct RequestProfile {
+    /// Requests made
+    requests: u64,
+    /// Requests to the path of the current request
+    path_requests: u64,
+    /// Distinct query strings
+    distinct_queries: u64,
+    /// Requests carrying a random-looking parameter value
+    random_params: u64,
+}
+
+impl RequestProfile {
+    /// Score the requests against the closest flood pattern
+    ///
+    /// When several patterns are matched, cache busting is the most
+    /// specific and is reported first. Clients that haven't made enough
+    /// requests yet aren't scored.
+    fn score(&self, config: &HttpFloodConfig) -> Option<DetectorScore> {
+        if self.requests < config.min_requests.max(1) {
+            return None;
+        }
+        let share = |count: u64| count as f64 / self.requests as f64;
+        let patterns = [
+            (HttpFloodPattern::CacheBusting, share(self.random_params), config.cache_busting_ratio),
+            (HttpFloodPattern::RandomizedQuery, share(self.distinct_queries), config.query_cardinality),
+            (HttpFloodPattern::PathConcentration, share(self.path_requests), config.path_concentration),
+        ];
+        let ratio = |(_, share, threshold): &(HttpFloodPattern, f64, f64)| share / threshold.max(f64::EPSILON);
+        let (pattern, share, threshold) = patterns
+            .iter()
+            .find(|(_, share, threshold)| share >= threshold)
+            .or_else(|| patterns.iter().max_by(|a, b| ratio(a).total_cmp(&ratio(b))))
+            .copied()?;
+
+        Some(DetectorScore {
+            score: ratio(&(pattern, share, threshold)),
+            classification: Classification {
+                category: AttackCategory::ApplicationLayer,
+                vector: pattern.as_str().to_string(),
+                confidence: share_confidence(share, threshold),
+                metrics: vec![
+                    TriggerMetric {
+                        name: pattern.metric().to_string(),
+                        observed: share,
+                        threshold,
+                    },
+                    TriggerMetric {
+                        name: "requests".to_string(),
+                        observed: self.requests as f64,
+                        threshold: config.min_requests as f64,
+                    },
+                ],
+            },
+        })
+    }
+}
+
+/// Shortest parameter value considered random
+const MIN_RANDOM_LENGTH: usize = 8;
+
+/// Lowest entropy of a random parameter value (bits per character)
+///
+/// Timestamps and hex or base64 tokens score above this, while words,
+/// dates and small IDs score below.
+const MIN_RANDOM_ENTROPY: f64 = 2.3;
+
+/// Shannon entropy of a string in bits per character
+fn shannon_entropy(value: &str) -> f64 {
+    let mut counts = HashMap::new();
+    for c in value.chars() {
+        *counts.entry(c).or_insert(0u32) += 1;
+    }
+    let length = value.chars().count() as f64;
+    counts
+        .values()
+        .map(|count| {
+            let p = *count as f64 / length;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Whether a parameter value looks generated to bypass caches, like
+/// `_=1697312399123` or `cb=9f86d081884c`
+fn looks_random(value: &str) -> bool {
+    value.len() >= MIN_RANDOM_LENGTH
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && value.chars().any(|c| c.is_ascii_digit())
+        && shannon_entropy(value) >= MIN_RANDOM_ENTROPY
+}
+
+/// Query parameters in a canonical order, so that reordering them doesn't
+/// make a query string distinct
+fn canonical_query(query: &HashMap<String, String>) -> String {
+    let mut params: Vec<String> = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+    params.sort();
+    params.join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entropy_scores() {
+        let config = HttpFloodConfig::default();
+        let profile = |path_requests, distinct_queries, random_params| RequestProfile {
+            requests: 500,
+            path_requests,
+            distinct_queries,
+            random_params,
+        };
+        let vector = |profile: RequestProfile| {
+            let score = profile.score(&config).unwrap();
+            (score.score >= 1.0).then_some(score.classification.vector)
+        };
+
+        assert_eq!(vector(profile(100, 50, 0)), None);
+        assert_eq!(vector(profile(480, 1, 0)).as_deref(), Some("path_concentration"));
+        assert_eq!(vector(profile(100, 490, 0)).as_deref(), Some("randomized_query"));
+        assert_eq!(vector(profile(480, 490, 450)).as_deref(), Some("cache_busting"));
+        // Too few requests to tell
+        let quiet = RequestProfile { requests: 10, path_requests: 10, ..RequestProfile::default() };
+        assert!(quiet.score(&config).is_none());
+
+        assert!(looks_random("1697312399123"));
+        assert!(looks_random("9f86d081884c7d65"));
+        assert!(!looks_random("20240101"));
+        assert!(!looks_random("document"));
+        assert!(!looks_random("42"));
+
+        let score = profile(100, 50, 500).score(&config).unwrap();
+        assert_eq!(score.classification.category, AttackCategory::ApplicationLayer);
+        assert_eq!(score.classification.confidence, 100);
+        assert_eq!(score.classification.metrics[0].name, "cache_busting_ratio");
+        assert_eq!(share_confidence(0.9, 0.9), 50);
+    }
+
+    #[test]
+    fn test_scorer() {
+        let score = |score: f64, vector: &str| DetectorScore {
+            score,
+            classification: Classification::threshold_exceeded(AttackCategory::Volumetric, vector, 0, 0),
+        };
+        let scores = || {
+            vec![
+                ("rate".to_string(), score(0.6, "request_rate")),
+                ("volume".to_string(), score(0.7, "traffic_volume")),
+            ]
+        };
+
+        let max = Scorer::new(DetectionPipelineConfig::default());
+        assert_eq!(max.combine(scores()), None);
+
+        let sum = Scorer::new(DetectionPipelineConfig {
+            combination: ScoreCombination::Sum,
+            ..DetectionPipelineConfig::default()
+        });
+        let verdict = sum.combine(scores()).unwrap();
+        assert_eq!(verdict.detector, "volume");
+        assert_eq!(verdict.classification.vector, "traffic_volume");
+        assert_eq!(verdict.classification.confidence, 23);
+
+        let weighted = Scorer::new(DetectionPipelineConfig {
+            weights: HashMap::from([("rate".to_string(), 2.0)]),
+            ..DetectionPipelineConfig::default()
+        });
+        assert_eq!(weighted.combine(scores()).unwrap().detector, "rate");
+    }
+
+    #[test]
+    fn test_anomaly_detector() {
+        let mut config = DdosDetectionConfig::default();
+        config.baseline.min_samples = 3;
+        config.pipeline.anomaly_min_requests = 10;
+        let detector = AnomalyDetector::new(&config);
+
+        for requests in [20, 40, 30] {
+            assert!(detector.observe("192.0.2.1", requests).is_none());
+        }
+        assert!(detector.observe("192.0.2.1", 30).unwrap().score <= 1.0);
+        let score = detector.observe("192.0.2.1", 200).unwrap();
+        assert!(score.score > 1.0);
+        assert_eq!(score.classification.category, AttackCategory::Anomaly);
+    }
+}
@@ -0,0 +1,136 @@
+//! DNS blocklist lookups for the DDoS protection service.
+//!
+//! This module checks client IPs against DNS blocklists such as
+//! `zen.spamhaus.org`: an IP is listed when the reversed address under the
+//! zone resolves to a `127.0.0.0/8` address. Lookups run on the system
+//! resolver with a timeout, and answers are cached in memory.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures::future::join_all;
+use tokio::sync::RwLock;
+use crate::models::DnsblConfig;
+use crate::utils::normalize_ip;
+
+/// Name queried to check an address against a zone
+///
+/// IPv4 octets and IPv6 nibbles are reversed, as in reverse DNS.
+pub fn query_name(addr: IpAddr, zone: &str) -> String {
+    let labels: Vec<String> = match addr {
+        IpAddr::V4(addr) => addr.octets().iter().rev().map(u8::to_string).collect(),
+        IpAddr::V6(addr) => addr
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| [byte & 0xf, byte >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect(),
+    };
+    format!("{}.{}", labels.join("."), zone.trim_end_matches('.'))
+}
+
+/// Whether an answer means the address is listed
+///
+/// Listings are `127.0.0.0/8` answers; `127.255.255.0/24` answers are
+/// errors (such as queries through refused public resolvers), not listings.
+fn is_listing(answer: IpAddr) -> bool {
+    match answer {
+        IpAddr::V4(answer) => {
+            let octets = answer.octets();
+            octets[0] == 127 && !(octets[1] == 255 && octets[2] == 255)
+        }
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// DNS blocklist client with a shared answer cache
+///
+/// Cloning is cheap and all clones share the cache.
+#[derive(Clone)]
+pub struct Dnsbl {
+    /// DNSBL configuration
+    config: DnsblConfig,
+    /// Cached answers keyed by query name, with when they expire
+    cache: Arc<RwLock<HashMap<String, (bool, Instant)>>>,
+}
+
+impl Dnsbl {
+    /// Create a new DNSBL client
+    pub fn new(config: DnsblConfig) -> Self {
+        Self {
+            config,
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Whether the IP is listed in any of the zones
+    ///
+    /// Zones are queried concurrently. Lookups that fail or time out count
+    /// as not listed and aren't cached, so a slow DNS server delays
+    /// evaluation by at most the timeout.
+    pub async fn is_listed(&self, ip: &str, zones: &[String]) -> bool {
+        let addr = match normalize_ip(ip) {
+            Some(addr) => addr,
+            None => return false,
+        };
+        join_all(zones.iter().map(|zone| self.lookup(addr, zone)))
+            .await
+            .into_iter()
+            .any(|listed| listed)
+    }
+
+    async fn lookup(&self, addr: IpAddr, zone: &str) -> bool {
+        let name = query_name(addr, zone);
+        if let Some((listed, expires_at)) = self.cache.read().await.get(&name) {
+            if *expires_at > Instant::now() {
+                return *listed;
+            }
+        }
+
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let listed = match tokio::time::timeout(timeout, tokio::net::lookup_host((name.as_str(), 0))).await {
+            Ok(Ok(mut answers)) => answers.any(|answer| is_listing(answer.ip())),
+            // Unlisted addresses don't resolve (NXDOMAIN)
+            Ok(Err(_)) => false,
+            Err(_) => {
+                metrics::increment_counter!("dnsbl_timeouts_total", "zone" => zone.to_string());
+                return false;
+            }
+        };
+
+        let mut cache = self.cache.write().await;
+        if cache.len() >= self.config.max_cache_entries {
+            let now = Instant::now();
+            cache.retain(|_, (_, expires_at)| *expires_at > now);
+            if cache.len() >= self.config.max_cache_entries {
+                cache.clear();
+            }
+        }
+        let ttl = Duration::from_secs(self.config.cache_ttl_seconds);
+        cache.insert(name, (listed, Instant::now() + ttl));
+        listed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_name() {
+        assert_eq!(
+            query_name("192.0.2.99".parse().unwrap(), "zen.spamhaus.org."),
+            "99.2.0.192.zen.spamhaus.org"
+        );
+        assert_eq!(
+            query_name("2001:db8::1".parse().unwrap(), "zen.spamhaus.org"),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.zen.spamhaus.org"
+        );
+
+        assert!(is_listing("127.0.0.2".parse().unwrap()));
+        assert!(!is_listing("127.255.255.254".parse().unwrap()));
+        assert!(!is_listing("10.0.0.1".parse().unwrap()));
+    }
+}
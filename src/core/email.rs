@@ -0,0 +1,264 @@
+//! Email delivery for the DDoS protection service.
+//!
+//! A small SMTP client (RFC 5321) used to send critical alerts and the
+//! scheduled summary reports. It connects in plain text, upgrades with
+//! STARTTLS, or uses TLS from the start, and authenticates with AUTH PLAIN
+//! when credentials are configured. Each message gets its own connection.
+
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+use crate::core::alert_channels::AlertChannel;
+use crate::core::monitoring::{Alert, AlertLevel, AlertStatus};
+use crate::models::{EmailConfig, SmtpSecurity};
+
+/// How long the whole SMTP exchange for one message may take
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Errors that can occur while sending email
+#[derive(Error, Debug)]
+pub enum EmailError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("SMTP error: {0}")]
+    Smtp(String),
+}
+
+/// SMTP client for the configured relay
+#[derive(Clone)]
+pub struct Mailer {
+    config: EmailConfig,
+}
+
+impl Mailer {
+    pub fn new(config: EmailConfig) -> Self {
+        Self { config }
+    }
+
+    /// Send a plain-text message
+    pub async fn send(&self, to: &[String], subject: &str, body: &str) -> Result<(), EmailError> {
+        if to.is_empty() {
+            return Ok(());
+        }
+        let message = format_message(&self.config.from, to, subject, body, Utc::now());
+        match tokio::time::timeout(SEND_TIMEOUT, self.deliver(to, &message)).await {
+            Ok(result) => result,
+            Err(_) => Err(EmailError::Smtp("timed out".to_string())),
+        }
+    }
+
+    async fn deliver(&self, to: &[String], message: &str) -> Result<(), EmailError> {
+        let stream = TcpStream::connect((self.config.smtp_host.as_str(), self.config.smtp_port)).await?;
+        match self.config.security {
+            SmtpSecurity::None => self.session(SmtpConnection::new(stream), true, to, message).await,
+            SmtpSecurity::Tls => {
+                let stream = self.tls_connect(stream).await?;
+                self.session(SmtpConnection::new(stream), true, to, message).await
+            }
+            SmtpSecurity::StartTls => {
+                let mut connection = SmtpConnection::new(stream);
+                connection.expect(220).await?;
+                connection.command(&format!("EHLO {}", self.helo_name()), 250).await?;
+                connection.command("STARTTLS", 220).await?;
+                let stream = self.tls_connect(connection.stream.into_inner()).await?;
+                // The server doesn't greet again after the upgrade
+                self.session(SmtpConnection::new(stream), false, to, message).await
+            }
+        }
+    }
+
+    async fn tls_connect(&self, stream: TcpStream) -> Result<tokio_native_tls::TlsStream<TcpStream>, EmailError> {
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(!self.config.tls_verify)
+            .build()
+            .map_err(|e| EmailError::Tls(e.to_string()))?;
+        tokio_native_tls::TlsConnector::from(connector)
+            .connect(&self.config.smtp_host, stream)
+            .await
+            .map_err(|e| EmailError::Tls(e.to_string()))
+    }
+
+    /// Name the client introduces itself with, the sender's domain
+    fn helo_name(&self) -> &str {
+        domain(&self.config.from)
+    }
+
+    async fn session<S>(
+        &self,
+        mut connection: SmtpConnection<S>,
+        greeting: bool,
+        to: &[String],
+        message: &str,
+    ) -> Result<(), EmailError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if greeting {
+            connection.expect(220).await?;
+        }
+        connection.command(&format!("EHLO {}", self.helo_name()), 250).await?;
+        if let (Some(username), Some(password)) = (&self.config.username, &self.config.password) {
+            let credentials = base64::engine::general_purpose::STANDARD.encode(format!("\0{}\0{}", username, password));
+            connection.command(&format!("AUTH PLAIN {}", credentials), 235).await?;
+        }
+        connection.command(&format!("MAIL FROM:<{}>", self.config.from), 250).await?;
+        for recipient in to {
+            connection.command(&format!("RCPT TO:<{}>", recipient), 250).await?;
+        }
+        connection.command("DATA", 354).await?;
+        connection.stream.write_all(message.as_bytes()).await?;
+        connection.command(".", 250).await?;
+        // The message is accepted; a failed goodbye doesn't matter
+        let _ = connection.command("QUIT", 221).await;
+        Ok(())
+    }
+}
+
+struct SmtpConnection<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SmtpConnection<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// Send a command and check the reply code
+    async fn command(&mut self, command: &str, code: u16) -> Result<String, EmailError> {
+        self.stream.write_all(format!("{}\r\n", command).as_bytes()).await?;
+        self.stream.flush().await?;
+        self.expect(code).await
+    }
+
+    /// Read a reply, which may span several lines, and check its code
+    async fn expect(&mut self, code: u16) -> Result<String, EmailError> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(EmailError::Smtp("connection closed".to_string()));
+            }
+            let received: u16 = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| EmailError::Smtp(format!("invalid reply: {}", line.trim_end())))?;
+            reply.push_str(line.get(4..).unwrap_or_default().trim_end());
+            if line.as_bytes().get(3) == Some(&b'-') {
+                reply.push('\n');
+                continue;
+            }
+            if received != code {
+                return Err(EmailError::Smtp(format!("{} {}", received, reply)));
+            }
+            return Ok(reply);
+        }
+    }
+}
+
+/// Domain part of an address
+fn domain(address: &str) -> &str {
+    address.rsplit_once('@').map_or("localhost", |(_, domain)| domain)
+}
+
+/// Header value, encoded (RFC 2047) when not plain ASCII
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", base64::engine::general_purpose::STANDARD.encode(value))
+    }
+}
+
+/// Message as sent after DATA, dot-stuffed, without the terminating dot
+fn format_message(from: &str, to: &[String], subject: &str, body: &str, date: DateTime<Utc>) -> String {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        from,
+        to.join(", "),
+        encode_header(subject),
+        date.to_rfc2822(),
+        Uuid::new_v4(),
+        domain(from)
+    );
+    for line in body.lines() {
+        // Lines starting with a dot would otherwise end the message early
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+/// Alert channel emailing alerts when they are raised
+pub struct EmailChannel {
+    mailer: Mailer,
+    recipients: Vec<String>,
+    levels: Vec<AlertLevel>,
+}
+
+impl EmailChannel {
+    pub fn new(mailer: Mailer, recipients: Vec<String>, levels: Vec<AlertLevel>) -> Self {
+        Self { mailer, recipients, levels }
+    }
+}
+
+#[async_trait]
+impl AlertChannel for EmailChannel {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    fn routes(&self, level: &AlertLevel) -> bool {
+        self.levels.contains(level)
+    }
+
+    async fn send(&self, alert: &Alert) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Only new alerts are mailed; acknowledgements would just add noise
+        if alert.status != AlertStatus::Active {
+            return Ok(());
+        }
+        let subject = format!("[{:?}] {}", alert.level, alert.source);
+        let body = format!(
+            "{}\n\nLevel: {:?}\nSource: {}\nRaised: {}\nAlert ID: {}\n",
+            alert.message,
+            alert.level,
+            alert.source,
+            alert.created_at.to_rfc3339(),
+            alert.id
+        );
+        self.mailer.send(&self.recipients, &subject, &body).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_message() {
+        let date = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let to = vec!["ops@example.com".to_string(), "sec@example.com".to_string()];
+        let message = format_message("ddos@example.com", &to, "Daily report", "Hello\n.hidden\nBye", date);
+
+        assert!(message.starts_with("From: ddos@example.com\r\nTo: ops@example.com, sec@example.com\r\n"));
+        assert!(message.contains("Subject: Daily report\r\nDate: Tue, 14 Nov 2023 22:13:20 +0000\r\n"));
+        assert!(message.contains("@example.com>\r\n"));
+        assert!(message.ends_with("\r\n\r\nHello\r\n..hidden\r\nBye\r\n"));
+
+        assert_eq!(encode_header("Größe"), "=?UTF-8?B?R3LDtsOfZQ==?=");
+        assert_eq!(domain("nobody"), "localhost");
+    }
+}
@@ -0,0 +1,172 @@
+//! Event enrichment for the DDoS protection service.
+//!
+//! Events about a client are given its country, ASN and AS organization,
+//! and optionally its reverse DNS name, before they are stored, so that
+//! analytics queries and reports can group by them without resolving them
+//! again. Each step is timed in the `event_enrichment_duration_seconds`
+//! metric, and reverse DNS lookups are counted by result in
+//! `event_enrichment_lookups_total`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::json;
+use crate::core::analytics::Event;
+use crate::core::bot_detection::reverse_lookup;
+use crate::core::geoip::GeoIp;
+use crate::models::EnrichmentConfig;
+use crate::utils::normalize_ip;
+
+/// Reverse DNS names by address, or `None` for addresses without one, with
+/// when they expire
+type NameCache = HashMap<IpAddr, (Option<String>, Instant)>;
+
+/// Adds client details to events
+///
+/// Cheap to clone; all clones share the reverse DNS cache and lookup rate.
+#[derive(Clone)]
+pub struct Enricher {
+    geoip: GeoIp,
+    config: EnrichmentConfig,
+    names: Arc<Mutex<NameCache>>,
+    /// Start of the current second, and the reverse DNS lookups started in it
+    lookups: Arc<Mutex<(Instant, u32)>>,
+}
+
+impl Enricher {
+    pub fn new(geoip: GeoIp, config: EnrichmentConfig) -> Self {
+        Self {
+            geoip,
+            config,
+            names: Arc::new(Mutex::new(HashMap::new())),
+            lookups: Arc::new(Mutex::new((Instant::now(), 0))),
+        }
+    }
+
+    /// Add the details of the event's client, keeping fields already set
+    pub async fn enrich(&self, event: &mut Event) {
+        let Some(addr) = event.client().and_then(normalize_ip) else {
+            return;
+        };
+
+        if self.config.geoip {
+            let started = Instant::now();
+            let info = self.geoip.lookup(&addr.to_string()).await;
+            let fields = [
+                ("country", info.country.map(|country| json!(country))),
+                ("asn", info.asn.map(|asn| json!(asn))),
+                ("as_org", info.as_org.map(|as_org| json!(as_org))),
+            ];
+            for (field, value) in fields {
+                if let Some(value) = value {
+                    event.data.entry(field.to_string()).or_insert(value);
+                }
+            }
+            metrics::histogram!("event_enrichment_duration_seconds", started.elapsed().as_secs_f64(), "step" => "geoip");
+        }
+
+        if self.config.reverse_dns {
+            let started = Instant::now();
+            if let Some(name) = self.reverse_dns(addr) {
+                event.data.entry("rdns".to_string()).or_insert(json!(name));
+            }
+            metrics::histogram!("event_enrichment_duration_seconds", started.elapsed().as_secs_f64(), "step" => "reverse_dns");
+        }
+    }
+
+    /// Cached reverse DNS name of an address
+    ///
+    /// Addresses that aren't cached are looked up in the background, unless
+    /// too many lookups were started this second.
+    fn reverse_dns(&self, addr: IpAddr) -> Option<String> {
+        let now = Instant::now();
+        if let Some((name, expires_at)) = self.names.lock().unwrap().get(&addr) {
+            if *expires_at > now {
+                return name.clone();
+            }
+        }
+        if !self.start_lookup(now) {
+            metrics::increment_counter!("event_enrichment_lookups_total", "result" => "rate_limited");
+            return None;
+        }
+
+        // Events arriving while the lookup runs don't start another
+        let timeout = Duration::from_millis(self.config.reverse_dns_timeout_ms);
+        self.cache(addr, None, now + timeout);
+        let enricher = self.clone();
+        tokio::spawn(async move {
+            let lookup = tokio::task::spawn_blocking(move || reverse_lookup(addr));
+            match tokio::time::timeout(timeout, lookup).await {
+                Ok(Ok(name)) => {
+                    metrics::increment_counter!("event_enrichment_lookups_total", "result" => "resolved");
+                    let ttl = Duration::from_secs(enricher.config.cache_ttl_seconds);
+                    enricher.cache(addr, name, Instant::now() + ttl);
+                }
+                Ok(Err(e)) => log::error!("Reverse DNS lookup of {} failed: {}", addr, e),
+                Err(_) => metrics::increment_counter!("event_enrichment_lookups_total", "result" => "timeout"),
+            }
+        });
+        None
+    }
+
+    /// Whether another reverse DNS lookup may start this second
+    fn start_lookup(&self, now: Instant) -> bool {
+        let mut lookups = self.lookups.lock().unwrap();
+        let (second, started) = &mut *lookups;
+        if now.duration_since(*second) >= Duration::from_secs(1) {
+            *second = now;
+            *started = 0;
+        }
+        if *started >= self.config.reverse_dns_rate {
+            return false;
+        }
+        *started += 1;
+        true
+    }
+
+    fn cache(&self, addr: IpAddr, name: Option<String>, expires_at: Instant) {
+        let mut names = self.names.lock().unwrap();
+        if names.len() >= self.config.max_cache_entries && !names.contains_key(&addr) {
+            let now = Instant::now();
+            names.retain(|_, (_, expires_at)| *expires_at > now);
+            if names.len() >= self.config.max_cache_entries {
+                names.clear();
+            }
+        }
+        names.insert(addr, (name, expires_at));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::analytics::EventType;
+    use crate::models::GeoIpConfig;
+
+    #[tokio::test]
+    async fn test_enrich() {
+        let config = EnrichmentConfig {
+            reverse_dns: true,
+            reverse_dns_rate: 1,
+            ..EnrichmentConfig::default()
+        };
+        let enricher = Enricher::new(GeoIp::new(GeoIpConfig::default()), config);
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        enricher.cache(addr, Some("host.example.com".to_string()), Instant::now() + Duration::from_secs(60));
+
+        let mut data = HashMap::new();
+        data.insert("ip".to_string(), json!("192.0.2.1"));
+        let mut event = Event::new(EventType::RateLimitExceeded, "test", data);
+        enricher.enrich(&mut event).await;
+        assert_eq!(event.data["rdns"], json!("host.example.com"));
+
+        // Without GeoIP databases, nothing else is added
+        assert!(!event.data.contains_key("country"));
+
+        let now = Instant::now();
+        assert!(enricher.start_lookup(now));
+        assert!(!enricher.start_lookup(now));
+        assert!(enricher.start_lookup(now + Duration::from_secs(1)));
+    }
+}
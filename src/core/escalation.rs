@@ -0,0 +1,407 @@
+//! Mitigation escalation for the DDoS protection service.
+//!
+//! This module moves mitigations up a ladder as an attack intensifies
+//! (monitor, rate limit, challenge, block subnet, upstream provider) and
+//! back down once the request rate recovers. The current step is kept in
+//! Redis so that every instance applies the same mitigations, and every
+//! transition is recorded to analytics.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use thiserror::Error;
+use crate::core::analytics::{Analytics, Event, EventType};
+use crate::core::attack_mode::scaled_limit;
+use crate::core::blocklist::Blocklist;
+use crate::core::redis_pool::RedisPool;
+use crate::models::{EscalationConfig, EscalationLevel, SubnetConfig};
+use crate::utils::{get_current_timestamp, normalize_ip};
+
+/// Redis hash holding the escalation state
+const STATE_KEY: &str = "escalation:state";
+
+/// Redis key held by the instance evaluating the ladder in the current interval
+const LOCK_KEY: &str = "escalation:lock";
+
+/// How long instances use the cached level before checking Redis again
+const CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// Errors that can occur while escalating mitigations
+#[derive(Error, Debug)]
+pub enum EscalationError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+}
+
+/// Upstream provider mitigation is handed to at the top of the ladder
+///
+/// Providers are engaged when the ladder reaches the `upstream` step and
+/// released when it drops below it. Subnets blocked by the `block_subnet`
+/// step are also blocked at the provider.
+#[async_trait]
+pub trait UpstreamMitigation: Send + Sync {
+    /// Name used in logs and analytics
+    fn name(&self) -> &str;
+
+    /// Turn on the provider's attack mitigation
+    async fn engage(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Turn off the provider's attack mitigation
+    async fn release(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Block an address or network at the provider
+    async fn block(&self, target: &str, reason: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Current step of the ladder
+#[derive(Debug, Clone, Serialize)]
+pub struct EscalationStatus {
+    /// Whether mitigations escalate automatically
+    pub enabled: bool,
+    /// Current step
+    pub level: EscalationLevel,
+    /// When the ladder moved to the current step
+    pub since: Option<DateTime<Utc>>,
+    /// Total requests per second at the latest check
+    pub rate: u64,
+    /// Checks in a row the rate has been low enough to step down
+    pub calm_checks: u32,
+}
+
+/// Mitigation escalation ladder shared by every instance
+///
+/// Cloning is cheap and all clones share the cache.
+#[derive(Clone)]
+pub struct Escalation {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Escalation configuration
+    config: EscalationConfig,
+    /// Blocklist subnets are added to
+    blocklist: Blocklist,
+    /// How clients are grouped into subnets
+    subnets: SubnetConfig,
+    /// Window of the aggregate request counters, in seconds
+    request_window: u32,
+    /// Analytics instance transitions are recorded to
+    analytics: Option<Arc<Analytics>>,
+    /// Providers engaged at the `upstream` step
+    upstream: Vec<Arc<dyn UpstreamMitigation>>,
+    /// Last level read from Redis, with when it was read
+    cache: Arc<Mutex<Option<(Instant, EscalationLevel)>>>,
+}
+
+impl Escalation {
+    /// Create a new escalation ladder
+    ///
+    /// # Arguments
+    ///
+    /// * `redis` - Redis connection pool
+    /// * `config` - Escalation configuration
+    /// * `blocklist` - Blocklist subnets are added to
+    /// * `subnets` - How clients are grouped into subnets
+    /// * `request_window` - Window of the aggregate request counters the rate is read from
+    pub fn new(
+        redis: RedisPool,
+        config: EscalationConfig,
+        blocklist: Blocklist,
+        subnets: SubnetConfig,
+        request_window: u32,
+    ) -> Self {
+        Self {
+            redis,
+            config,
+            blocklist,
+            subnets,
+            request_window,
+            analytics: None,
+            upstream: Vec::new(),
+            cache: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Record transitions to the given analytics instance
+    pub fn with_analytics(mut self, analytics: Arc<Analytics>) -> Self {
+        self.analytics = Some(analytics);
+        self
+    }
+
+    /// Hand mitigation to the given provider at the top of the ladder
+    pub fn with_upstream(mut self, provider: Arc<dyn UpstreamMitigation>) -> Self {
+        self.upstream.push(provider);
+        self
+    }
+
+    /// Current step, from a state at most a second old
+    ///
+    /// The ladder is considered at `monitor` when it is disabled or Redis
+    /// can't be reached.
+    pub async fn level(&self) -> EscalationLevel {
+        if !self.config.enabled {
+            return EscalationLevel::Monitor;
+        }
+        let cached = self
+            .cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .filter(|(read_at, _)| read_at.elapsed() < CACHE_TTL)
+            .map(|(_, level)| level);
+        if let Some(level) = cached {
+            return level;
+        }
+        match self.status().await {
+            Ok(status) => status.level,
+            Err(e) => {
+                log::error!("Failed to read escalation level: {}", e);
+                EscalationLevel::Monitor
+            }
+        }
+    }
+
+    /// Current state of the ladder, read from Redis
+    pub async fn status(&self) -> Result<EscalationStatus, EscalationError> {
+        let mut conn = self.redis.get();
+        let fields: HashMap<String, String> = redis::cmd("HGETALL").arg(STATE_KEY).query_async(&mut conn).await?;
+        let status = status_from_fields(self.config.enabled, &fields);
+        self.cache_level(status.level);
+        Ok(status)
+    }
+
+    /// Check the request rate and move the ladder, returning the current step
+    ///
+    /// Only one instance evaluates per interval, so that recovery checks
+    /// aren't counted once per instance.
+    #[tracing::instrument(name = "escalation.evaluate", skip_all)]
+    pub async fn evaluate(&self) -> Result<EscalationLevel, EscalationError> {
+        let mut conn = self.redis.get();
+        let interval = self.config.interval_seconds.max(1);
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(LOCK_KEY)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(interval)
+            .query_async(&mut conn)
+            .await?;
+        if acquired.is_none() {
+            return Ok(self.status().await?.level);
+        }
+
+        // The current window is still filling up, so the previous one is used
+        let window = self.request_window.max(1) as u64;
+        let requests_key = format!("aggregate:requests:{}", (get_current_timestamp() / window).saturating_sub(1));
+        let (requests, fields): (Option<u64>, HashMap<String, String>) = redis::pipe()
+            .cmd("GET").arg(&requests_key)
+            .cmd("HGETALL").arg(STATE_KEY)
+            .query_async(&mut conn)
+            .await?;
+        let rate = requests.unwrap_or(0) / window;
+        let current = status_from_fields(self.config.enabled, &fields);
+        let (level, calm_checks) = next_level(&self.config, current.level, rate, current.calm_checks);
+
+        let mut pipe = redis::pipe();
+        pipe.cmd("HSET")
+            .arg(STATE_KEY)
+            .arg("level").arg(level.as_str())
+            .arg("rate").arg(rate)
+            .arg("calm_checks").arg(calm_checks)
+            .ignore();
+        if level != current.level {
+            pipe.cmd("HSET").arg(STATE_KEY).arg("since").arg(Utc::now().timestamp()).ignore();
+        }
+        let _: () = pipe.query_async(&mut conn).await?;
+        self.cache_level(level);
+
+        if level != current.level {
+            self.transition(current.level, level, rate).await;
+        }
+        Ok(level)
+    }
+
+    /// Periodically move the ladder to the current request rate
+    pub async fn start(&self) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_seconds.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.evaluate().await {
+                log::error!("Failed to evaluate mitigation escalation: {}", e);
+            }
+        }
+    }
+
+    /// Scale a rate limit down to the `rate_limit` step's fraction
+    pub fn scale_limit(&self, limit: u32) -> u32 {
+        scaled_limit(limit, self.config.rate_limit_factor)
+    }
+
+    /// Block the subnet of a detected client, returning whether it was blocked
+    pub async fn block_subnet(&self, ip: &str) -> bool {
+        let Some(addr) = normalize_ip(ip) else {
+            return false;
+        };
+        let subnet = self.subnets.subnet_of(addr).to_string();
+        let reason = format!("Escalated mitigation after detecting {}", ip);
+        let duration = Some(Duration::from_secs(self.config.subnet_block_seconds));
+        match self.blocklist.block(&subnet, &reason, "escalation", duration).await {
+            Ok(_) => {
+                metrics::increment_counter!("escalation_subnet_blocks_total");
+                // Edge blocks don't hold up the response to the request that triggered them
+                for provider in &self.upstream {
+                    let provider = provider.clone();
+                    let (subnet, reason) = (subnet.clone(), reason.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = provider.block(&subnet, &reason).await {
+                            log::error!("Failed to block subnet {} at {}: {}", subnet, provider.name(), e);
+                        }
+                    });
+                }
+                true
+            }
+            Err(e) => {
+                log::error!("Failed to block subnet {}: {}", subnet, e);
+                false
+            }
+        }
+    }
+
+    /// Act on a move of the ladder and record it
+    async fn transition(&self, from: EscalationLevel, to: EscalationLevel, rate: u64) {
+        let direction = if to > from { "escalate" } else { "de-escalate" };
+        metrics::increment_counter!("escalation_transitions_total", "direction" => direction, "level" => to.as_str());
+        log::warn!("Mitigations moved from {} to {} at {} requests/s", from.as_str(), to.as_str(), rate);
+
+        let mut upstream = Vec::new();
+        if from < EscalationLevel::Upstream && to >= EscalationLevel::Upstream {
+            for provider in &self.upstream {
+                match provider.engage().await {
+                    Ok(()) => upstream.push(provider.name().to_string()),
+                    Err(e) => log::error!("Failed to engage upstream mitigation {}: {}", provider.name(), e),
+                }
+            }
+        } else if from >= EscalationLevel::Upstream && to < EscalationLevel::Upstream {
+            for provider in &self.upstream {
+                match provider.release().await {
+                    Ok(()) => upstream.push(provider.name().to_string()),
+                    Err(e) => log::error!("Failed to release upstream mitigation {}: {}", provider.name(), e),
+                }
+            }
+        }
+
+        let Some(analytics) = &self.analytics else {
+            return;
+        };
+        let mut data = HashMap::new();
+        data.insert("from".to_string(), serde_json::json!(from));
+        data.insert("to".to_string(), serde_json::json!(to));
+        data.insert("direction".to_string(), serde_json::json!(direction));
+        data.insert("rate".to_string(), serde_json::json!(rate));
+        if !upstream.is_empty() {
+            data.insert("upstream".to_string(), serde_json::json!(upstream));
+        }
+        let event = Event::new(EventType::Escalation, "escalation", data);
+        if let Err(e) = analytics.record_event(event).await {
+            log::error!("Failed to record escalation event: {}", e);
+        }
+    }
+
+    fn cache_level(&self, level: EscalationLevel) {
+        *self.cache.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), level));
+    }
+}
+
+/// Escalation state from the fields of its Redis hash
+fn status_from_fields(enabled: bool, fields: &HashMap<String, String>) -> EscalationStatus {
+    EscalationStatus {
+        enabled,
+        level: fields
+            .get("level")
+            .and_then(|level| EscalationLevel::from_name(level))
+            .unwrap_or(EscalationLevel::Monitor),
+        since: fields
+            .get("since")
+            .and_then(|since| since.parse().ok())
+            .and_then(|since| DateTime::from_timestamp(since, 0)),
+        rate: fields.get("rate").and_then(|rate| rate.parse().ok()).unwrap_or(0),
+        calm_checks: fields.get("calm_checks").and_then(|calm| calm.parse().ok()).unwrap_or(0),
+    }
+}
+
+/// Step the ladder moves to from `current` at the observed rate, with the updated count of calm checks
+///
+/// Escalation jumps straight to the strictest step reached, while
+/// de-escalation goes down one step at a time.
+fn next_level(
+    config: &EscalationConfig,
+    current: EscalationLevel,
+    rate: u64,
+    calm_checks: u32,
+) -> (EscalationLevel, u32) {
+    let reached = config
+        .steps
+        .iter()
+        .filter(|step| rate >= step.threshold)
+        .map(|step| step.level)
+        .max()
+        .unwrap_or(EscalationLevel::Monitor);
+    if reached > current {
+        return (reached, 0);
+    }
+    if current == EscalationLevel::Monitor {
+        return (current, 0);
+    }
+
+    // A step removed from the ladder is left right away
+    let step = config.steps.iter().find(|step| step.level == current);
+    if let Some(step) = step {
+        if rate as f64 >= step.threshold as f64 * config.recovery_ratio {
+            return (current, 0);
+        }
+        if calm_checks + 1 < config.recovery_checks {
+            return (current, calm_checks + 1);
+        }
+    }
+    let lower = config
+        .steps
+        .iter()
+        .map(|step| step.level)
+        .filter(|level| *level < current)
+        .max()
+        .unwrap_or(EscalationLevel::Monitor);
+    (lower, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::EscalationStep;
+
+    #[test]
+    fn test_next_level() {
+        let config = EscalationConfig::default();
+
+        assert_eq!(next_level(&config, EscalationLevel::Monitor, 100, 0), (EscalationLevel::Monitor, 0));
+        assert_eq!(next_level(&config, EscalationLevel::Monitor, 5_000, 0), (EscalationLevel::RateLimit, 0));
+        // Escalation skips steps the rate is already past
+        assert_eq!(next_level(&config, EscalationLevel::RateLimit, 60_000, 2), (EscalationLevel::BlockSubnet, 0));
+        // Rates just below the threshold hold the step and reset recovery
+        assert_eq!(next_level(&config, EscalationLevel::Challenge, 17_000, 2), (EscalationLevel::Challenge, 0));
+        // De-escalation waits for enough calm checks, then goes down one step
+        assert_eq!(next_level(&config, EscalationLevel::Challenge, 100, 0), (EscalationLevel::Challenge, 1));
+        assert_eq!(next_level(&config, EscalationLevel::Challenge, 100, 1), (EscalationLevel::Challenge, 2));
+        assert_eq!(next_level(&config, EscalationLevel::Challenge, 100, 2), (EscalationLevel::RateLimit, 0));
+
+        let config = EscalationConfig {
+            steps: vec![EscalationStep { level: EscalationLevel::BlockSubnet, threshold: 50_000 }],
+            ..Default::default()
+        };
+        assert_eq!(next_level(&config, EscalationLevel::Monitor, 50_000, 0), (EscalationLevel::BlockSubnet, 0));
+        assert_eq!(next_level(&config, EscalationLevel::BlockSubnet, 10, 2), (EscalationLevel::Monitor, 0));
+        assert_eq!(next_level(&config, EscalationLevel::Challenge, 10, 0), (EscalationLevel::Monitor, 0));
+    }
+}
@@ -0,0 +1,256 @@
+//! Event archival for the DDoS protection service.
+//!
+//! Every hour, the events of the hour before are written as a gzipped
+//! NDJSON file to an S3-compatible bucket, long before the analytics
+//! retention period trims them. Uploads are signed with AWS Signature
+//! Version 4, and only one instance archives each hour.
+
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use thiserror::Error;
+use crate::core::analytics::{Analytics, AnalyticsError, Event};
+use crate::core::redis_pool::RedisPool;
+use crate::models::EventArchiveConfig;
+use crate::utils::{hex, hmac_sha256, sha256};
+
+/// How often finished hours are looked for
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Start of the last hour archived, as a Unix timestamp
+const LAST_HOUR_KEY: &str = "analytics:archive:last_hour";
+
+/// How long an instance has to archive an hour it claimed (seconds)
+const CLAIM_TTL: u64 = 600;
+
+/// Most hours archived in one pass when catching up
+const MAX_HOURS_PER_PASS: usize = 24;
+
+/// Headers covered by request signatures
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Errors that can occur while archiving events
+#[derive(Error, Debug)]
+pub enum EventArchiveError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("S3 error {status}: {message}")]
+    S3 { status: u16, message: String },
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("Analytics error: {0}")]
+    Analytics(#[from] AnalyticsError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Events as gzipped NDJSON, one event per line
+pub fn gzip_ndjson(events: &[Event]) -> Result<Vec<u8>, EventArchiveError> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for event in events {
+        serde_json::to_writer(&mut encoder, event)?;
+        encoder.write_all(b"\n")?;
+    }
+    Ok(encoder.finish()?)
+}
+
+/// Key of the file holding the events of the hour starting at `hour`
+fn object_key(prefix: &str, hour: DateTime<Utc>) -> String {
+    let path = hour.format("%Y/%m/%d/%H.ndjson.gz").to_string();
+    match prefix.trim_matches('/') {
+        "" => path,
+        prefix => format!("{}/{}", prefix, path),
+    }
+}
+
+/// Percent-encode an object key for a request path, keeping slashes
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Key requests to a service are signed with on a day
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> [u8; 32] {
+    let key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// Writes hourly event files to the configured bucket
+#[derive(Clone)]
+pub struct EventArchiver {
+    client: reqwest::Client,
+    redis: RedisPool,
+    analytics: Arc<Analytics>,
+    config: EventArchiveConfig,
+}
+
+impl EventArchiver {
+    pub fn new(redis: RedisPool, analytics: Arc<Analytics>, config: EventArchiveConfig) -> Result<Self, EventArchiveError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()?;
+        Ok(Self {
+            client,
+            redis,
+            analytics,
+            config,
+        })
+    }
+
+    /// Host and path of an object
+    fn location(&self, key: &str) -> (String, String) {
+        let endpoint = self.config.endpoint.trim_end_matches('/');
+        let host = endpoint.split_once("://").map_or(endpoint, |(_, host)| host);
+        if self.config.path_style {
+            (host.to_string(), format!("/{}/{}", self.config.bucket, uri_encode(key)))
+        } else {
+            (format!("{}.{}", self.config.bucket, host), format!("/{}", uri_encode(key)))
+        }
+    }
+
+    /// Headers signing a request without a query string
+    fn sign(&self, method: &str, host: &str, path: &str, body: &[u8], now: DateTime<Utc>) -> Vec<(&'static str, String)> {
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &timestamp[..8];
+        let payload_hash = hex(&sha256(body));
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, payload_hash, timestamp, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&sha256(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.config.secret_access_key, date, &self.config.region, "s3");
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+        vec![
+            ("x-amz-date", timestamp),
+            ("x-amz-content-sha256", payload_hash),
+            (
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.config.access_key_id, scope, SIGNED_HEADERS, signature
+                ),
+            ),
+        ]
+    }
+
+    /// Write an object to the bucket
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), EventArchiveError> {
+        let (host, path) = self.location(key);
+        let scheme = self.config.endpoint.split_once("://").map_or("https", |(scheme, _)| scheme);
+        let mut request = self
+            .client
+            .put(format!("{}://{}{}", scheme, host, path))
+            .header("content-type", "application/gzip");
+        for (name, value) in self.sign("PUT", &host, &path, &body, Utc::now()) {
+            request = request.header(name, value);
+        }
+
+        let response = request.body(body).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(EventArchiveError::S3 {
+                status: status.as_u16(),
+                message: response.text().await.unwrap_or_default(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Write the events of the hour starting at `hour`, returning how many there were
+    pub async fn archive_hour(&self, hour: DateTime<Utc>) -> Result<usize, EventArchiveError> {
+        let start = hour.timestamp() as u64;
+        let events = self.analytics.get_events(start, start + 3599, None).await?;
+        self.put(&object_key(&self.config.prefix, hour), gzip_ndjson(&events)?).await?;
+        Ok(events.len())
+    }
+
+    /// Archive the hours that ended since the last one archived
+    ///
+    /// Starts with the previous hour when nothing was archived yet. Every
+    /// instance looks; the first to claim an hour archives it.
+    pub async fn archive_finished(&self) -> Result<(), EventArchiveError> {
+        let now = Utc::now().timestamp();
+        let current = now - now.rem_euclid(3600);
+        let mut conn = self.redis.get();
+        let last: Option<i64> = redis::cmd("GET").arg(LAST_HOUR_KEY).query_async(&mut conn).await?;
+        let first = last.map_or(current - 3600, |last| last + 3600);
+
+        for hour in (first..current).step_by(3600).take(MAX_HOURS_PER_PASS) {
+            let claimed: Option<String> = redis::cmd("SET")
+                .arg(format!("analytics:archive:claim:{}", hour))
+                .arg(1)
+                .arg("NX")
+                .arg("EX")
+                .arg(CLAIM_TTL)
+                .query_async(&mut conn)
+                .await?;
+            if claimed.is_none() {
+                break;
+            }
+            let start = DateTime::from_timestamp(hour, 0).unwrap_or_default();
+            let archived = self.archive_hour(start).await?;
+            let _: () = redis::cmd("SET").arg(LAST_HOUR_KEY).arg(hour).query_async(&mut conn).await?;
+            log::info!("Archived {} events of the hour starting {}", archived, start);
+        }
+        Ok(())
+    }
+
+    /// Archive finished hours periodically until the task is cancelled
+    pub async fn start(&self) {
+        loop {
+            if let Err(e) = self.archive_finished().await {
+                log::error!("Failed to archive events: {}", e);
+            }
+            tokio::time::sleep(ARCHIVE_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::analytics::EventType;
+    use chrono::TimeZone;
+    use flate2::read::GzDecoder;
+    use std::collections::HashMap;
+    use std::io::Read;
+
+    #[test]
+    fn test_event_archive() {
+        // Example from the AWS Signature Version 4 documentation
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex(&key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+
+        let hour = Utc.with_ymd_and_hms(2024, 5, 15, 6, 0, 0).unwrap();
+        assert_eq!(object_key("/events/", hour), "events/2024/05/15/06.ndjson.gz");
+        assert_eq!(object_key("", hour), "2024/05/15/06.ndjson.gz");
+        assert_eq!(uri_encode("a b/c+d.gz"), "a%20b/c%2Bd.gz");
+
+        let events = vec![
+            Event::new(EventType::BlockedRequest, "test", HashMap::new()),
+            Event::new(EventType::Challenge, "test", HashMap::new()),
+        ];
+        let mut ndjson = String::new();
+        GzDecoder::new(&gzip_ndjson(&events).unwrap()[..]).read_to_string(&mut ndjson).unwrap();
+        let lines: Vec<Event> = ndjson.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].id, events[1].id);
+    }
+}
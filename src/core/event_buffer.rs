@@ -0,0 +1,138 @@
+//! Event buffering for the DDoS protection service.
+//!
+//! Recorded events are queued in memory and stored in batches, so that a
+//! flood of requests doesn't turn into a flood of Redis writes. Past a
+//! threshold each second, request events are sampled: only one in N is
+//! kept, marked with its `sample_rate` so counts can be scaled back up.
+//! Other events, such as blocks and detections, are never sampled, and when
+//! the queue is full request events make room for them. Sampled and dropped
+//! events are counted in the `analytics_events_sampled_total` and
+//! `analytics_events_dropped_total` metrics.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::json;
+use crate::core::analytics::{Event, EventType};
+use crate::models::EventBufferConfig;
+
+struct State {
+    events: VecDeque<Event>,
+    /// Start of the current second, and the request events pushed in it
+    second: Instant,
+    requests: u64,
+}
+
+/// Queue of events waiting to be stored
+///
+/// Cheap to clone; all clones share the queue.
+#[derive(Clone)]
+pub struct EventBuffer {
+    config: EventBufferConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl EventBuffer {
+    pub fn new(config: EventBufferConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(Mutex::new(State {
+                events: VecDeque::new(),
+                second: Instant::now(),
+                requests: 0,
+            })),
+        }
+    }
+
+    /// Queue an event, unless it is sampled out or there is no room for it
+    ///
+    /// Returns whether a full batch is waiting to be stored.
+    pub fn push(&self, mut event: Event) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if event.event_type == EventType::Request {
+            let now = Instant::now();
+            if now.duration_since(state.second) >= Duration::from_secs(1) {
+                state.second = now;
+                state.requests = 0;
+            }
+            state.requests += 1;
+
+            let rate = self.config.sample_rate.max(1);
+            if state.requests > self.config.sample_threshold && rate > 1 {
+                if !(state.requests - self.config.sample_threshold).is_multiple_of(rate) {
+                    metrics::increment_counter!("analytics_events_sampled_total");
+                    return false;
+                }
+                event.data.insert("sample_rate".to_string(), json!(rate));
+            }
+        }
+
+        if state.events.len() >= self.config.capacity {
+            // Request events make room for others, the oldest first
+            let evicted = match event.event_type {
+                EventType::Request => None,
+                _ => state.events.iter().position(|queued| queued.event_type == EventType::Request),
+            };
+            let Some(index) = evicted else {
+                metrics::increment_counter!("analytics_events_dropped_total", "event_type" => event.event_type.name());
+                return false;
+            };
+            state.events.remove(index);
+            metrics::increment_counter!("analytics_events_dropped_total", "event_type" => EventType::Request.name());
+        }
+
+        state.events.push_back(event);
+        state.events.len() >= self.config.batch_size
+    }
+
+    /// Take the oldest events queued, a batch at most
+    pub fn take(&self) -> Vec<Event> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.events.len().min(self.config.batch_size.max(1));
+        state.events.drain(..count).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_event_buffer() {
+        let buffer = EventBuffer::new(EventBufferConfig {
+            sample_threshold: 2,
+            sample_rate: 3,
+            capacity: 4,
+            batch_size: 3,
+            flush_interval_ms: 1000,
+        });
+        let event = |event_type| Event::new(event_type, "test", HashMap::new());
+
+        // Past two request events, one in three is kept
+        assert!(!buffer.push(event(EventType::Request)));
+        assert!(!buffer.push(event(EventType::Request)));
+        for _ in 0..3 {
+            buffer.push(event(EventType::Request));
+        }
+        let batch = buffer.take();
+        assert_eq!(batch.len(), 3);
+        assert!(!batch[1].data.contains_key("sample_rate"));
+        assert_eq!(batch[2].data["sample_rate"], json!(3));
+        assert!(buffer.take().is_empty());
+
+        // Blocks are never sampled, and push request events out of a full
+        // queue; without request events left, they are dropped
+        for _ in 0..3 {
+            buffer.push(event(EventType::BlockedRequest));
+        }
+        buffer.push(event(EventType::Request));
+        assert!(buffer.push(event(EventType::DdosDetection)));
+        assert!(!buffer.push(event(EventType::BlockedRequest)));
+        let types: Vec<EventType> = [buffer.take(), buffer.take()].concat().into_iter().map(|event| event.event_type).collect();
+        assert_eq!(
+            types,
+            [EventType::BlockedRequest, EventType::BlockedRequest, EventType::BlockedRequest, EventType::DdosDetection]
+        );
+    }
+}
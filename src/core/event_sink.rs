@@ -0,0 +1,114 @@
+//! Event sinks for the DDoS protection service.
+//!
+//! Recorded analytics events are forwarded to external systems such as
+//! SIEMs through sinks. Each sink has its own bounded queue drained by a
+//! background task, so a slow or unreachable destination never holds up
+//! request handling: when a queue is full, further events are dropped and
+//! counted in the `event_sink_dropped_total` metric.
+
+use async_trait::async_trait;
+use log::error;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::{mpsc, Mutex};
+use crate::core::analytics::Event;
+
+/// Most events handed to a sink at once
+const BATCH_SIZE: usize = 100;
+
+/// Errors that can occur while forwarding events
+#[derive(Error, Debug)]
+pub enum SinkError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error("Configuration error: {0}")]
+    Config(String),
+}
+
+/// A destination events are forwarded to
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    /// Name used in logs and metrics
+    fn name(&self) -> &str;
+
+    /// Whether the sink takes this event
+    fn accepts(&self, event: &Event) -> bool {
+        let _ = event;
+        true
+    }
+
+    /// Deliver a batch of events
+    async fn send(&self, events: &[Event]) -> Result<(), SinkError>;
+}
+
+struct SinkQueue {
+    name: String,
+    sink: Arc<dyn EventSink>,
+    sender: mpsc::Sender<Event>,
+    receiver: Mutex<mpsc::Receiver<Event>>,
+}
+
+/// Queues events for every configured sink
+#[derive(Clone, Default)]
+pub struct EventSinks {
+    queues: Vec<Arc<SinkQueue>>,
+}
+
+impl EventSinks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forward events to a sink, buffering up to `queue_size` of them
+    pub fn with_sink(mut self, sink: Arc<dyn EventSink>, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(queue_size.max(1));
+        self.queues.push(Arc::new(SinkQueue {
+            name: sink.name().to_string(),
+            sink,
+            sender,
+            receiver: Mutex::new(receiver),
+        }));
+        self
+    }
+
+    /// Queue an event for the sinks that take it
+    pub fn publish(&self, event: &Event) {
+        for queue in self.queues.iter() {
+            if !queue.sink.accepts(event) {
+                continue;
+            }
+            if queue.sender.try_send(event.clone()).is_err() {
+                metrics::increment_counter!("event_sink_dropped_total", "sink" => queue.name.clone());
+            }
+        }
+    }
+
+    /// Deliver queued events to the sinks until the service shuts down
+    pub async fn start(&self) {
+        let tasks = self.queues.iter().map(|queue| queue.drain());
+        futures::future::join_all(tasks).await;
+    }
+}
+
+impl SinkQueue {
+    async fn drain(&self) {
+        let mut receiver = self.receiver.lock().await;
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        while receiver.recv_many(&mut batch, BATCH_SIZE).await > 0 {
+            match self.sink.send(&batch).await {
+                Ok(()) => {
+                    metrics::counter!("event_sink_sent_total", batch.len() as u64, "sink" => self.name.clone());
+                }
+                Err(e) => {
+                    error!("Failed to forward {} events to {}: {}", batch.len(), self.name, e);
+                    metrics::counter!("event_sink_failed_total", batch.len() as u64, "sink" => self.name.clone());
+                }
+            }
+            batch.clear();
+        }
+    }
+}
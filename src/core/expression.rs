@@ -0,0 +1,757 @@
+//! Expression rules for the DDoS protection service.
+//!
+//! This module compiles rule conditions written as expressions, such as
+//! `ip.in_cidr("10.0.0.0/8") && req.rate(60s) > 500 && ua.matches("curl.*")`,
+//! into a tree that is evaluated against a request. Expressions are compiled
+//! once, when the rule is deserialized, and serialize back to their source.
+//!
+//! Available values:
+//! - `ip`: client IP
+//! - `ua`: `User-Agent` header
+//! - `req.method`, `req.path`, `req.size`
+//! - `req.header("name")`, `req.query("name")`
+//! - `req.rate(60s)`, `req.volume(5m)`: requests and bytes in the window
+//! - `geo.country`, `geo.asn`, `geo.org`
+//!
+//! Values support `matches`, `contains`, `starts_with`, `ends_with` and
+//! `in_cidr` methods, comparisons, `in [..]` lists, `!`, `&&` and `||`.
+//! Absent values (a missing header, an unknown country) are `null` and
+//! never match string methods.
+//!
+//! Expressions are limited to 4 KiB of source and 32 levels of nesting.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use crate::core::geoip::GeoInfo;
+use crate::core::rule_engine::RequestContext;
+use crate::utils::{normalize_ip, parse_network};
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+/// Longest expression source accepted, in bytes
+const MAX_SOURCE_LENGTH: usize = 4096;
+
+/// Deepest nesting of parentheses, lists and `!` accepted
+const MAX_NESTING: usize = 32;
+
+/// Errors in compiling or evaluating expressions
+#[derive(Error, Debug)]
+pub enum ExpressionError {
+    #[error("Syntax error at {position}: {message}")]
+    Syntax { position: usize, message: String },
+    #[error("Type error: {0}")]
+    Type(String),
+}
+
+/// Compiled rule expression
+#[derive(Clone)]
+pub struct Expression {
+    source: String,
+    root: Arc<Node>,
+}
+
+/// Values an expression is evaluated against
+#[derive(Debug, Default)]
+pub struct Bindings<'a> {
+    /// Client IP
+    pub ip: &'a str,
+    /// Request being handled, if any
+    pub request: Option<&'a RequestContext>,
+    /// Request counts by window in seconds
+    pub rates: HashMap<u32, i64>,
+    /// Traffic volumes by window in seconds
+    pub volumes: HashMap<u32, i64>,
+    /// GeoIP data of the client
+    pub geo: GeoInfo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<Value>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Variable {
+    Ip,
+    UserAgent,
+    Method,
+    Path,
+    Size,
+    Country,
+    Asn,
+    AsOrg,
+    Rate(u32),
+    Volume(u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum StringMethod {
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+#[derive(Debug)]
+enum Node {
+    Literal(Value),
+    List(Vec<Node>),
+    Variable(Variable),
+    Header(String),
+    Query(String),
+    Matches(Box<Node>, Regex),
+    StringMethod(Box<Node>, StringMethod, String),
+    InCidr(Box<Node>, Vec<IpNet>),
+    In(Box<Node>, Box<Node>),
+    Compare(Box<Node>, Comparison, Box<Node>),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+}
+
+impl Expression {
+    /// Compile an expression
+    pub fn parse(source: &str) -> Result<Self, ExpressionError> {
+        if source.len() > MAX_SOURCE_LENGTH {
+            return Err(syntax(MAX_SOURCE_LENGTH, format!("expression longer than {} bytes", MAX_SOURCE_LENGTH)));
+        }
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, position: 0, depth: 0 };
+        let root = parser.parse_or()?;
+        if let Some((position, token)) = parser.tokens.get(parser.position) {
+            return Err(syntax(*position, format!("unexpected {}", token)));
+        }
+        Ok(Self { source: source.to_string(), root: Arc::new(root) })
+    }
+
+    /// Expression source
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the expression uses attributes only known while handling a request
+    pub fn needs_request(&self) -> bool {
+        self.root.any(&|node| {
+            matches!(
+                node,
+                Node::Header(_)
+                    | Node::Query(_)
+                    | Node::Variable(Variable::UserAgent | Variable::Method | Variable::Path | Variable::Size)
+            )
+        })
+    }
+
+    /// Whether the expression uses GeoIP data
+    pub fn needs_geo(&self) -> bool {
+        self.root
+            .any(&|node| matches!(node, Node::Variable(Variable::Country | Variable::Asn | Variable::AsOrg)))
+    }
+
+    /// Request rate windows used by the expression, in seconds
+    pub fn rate_windows(&self) -> Vec<u32> {
+        let mut windows = Vec::new();
+        self.root.visit(&mut |node| {
+            if let Node::Variable(Variable::Rate(window)) = node {
+                windows.push(*window);
+            }
+        });
+        windows
+    }
+
+    /// Traffic volume windows used by the expression, in seconds
+    pub fn volume_windows(&self) -> Vec<u32> {
+        let mut windows = Vec::new();
+        self.root.visit(&mut |node| {
+            if let Node::Variable(Variable::Volume(window)) = node {
+                windows.push(*window);
+            }
+        });
+        windows
+    }
+
+    /// Evaluate the expression; it must yield a boolean
+    pub fn evaluate(&self, bindings: &Bindings) -> Result<bool, ExpressionError> {
+        match self.root.evaluate(bindings)? {
+            Value::Bool(value) => Ok(value),
+            value => Err(ExpressionError::Type(format!("expression yields {:?}, not a boolean", value))),
+        }
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Expression").field(&self.source).finish()
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Expression::parse(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Node {
+    fn children(&self) -> Vec<&Node> {
+        match self {
+            Node::List(items) => items.iter().collect(),
+            Node::Matches(node, _)
+            | Node::StringMethod(node, _, _)
+            | Node::InCidr(node, _)
+            | Node::Not(node) => vec![node],
+            Node::In(left, right)
+            | Node::Compare(left, _, right)
+            | Node::And(left, right)
+            | Node::Or(left, right) => vec![left, right],
+            Node::Literal(_) | Node::Variable(_) | Node::Header(_) | Node::Query(_) => Vec::new(),
+        }
+    }
+
+    fn visit(&self, f: &mut dyn FnMut(&Node)) {
+        f(self);
+        for child in self.children() {
+            child.visit(f);
+        }
+    }
+
+    fn any(&self, f: &dyn Fn(&Node) -> bool) -> bool {
+        f(self) || self.children().into_iter().any(|child| child.any(f))
+    }
+
+    fn evaluate(&self, bindings: &Bindings) -> Result<Value, ExpressionError> {
+        let request = bindings.request;
+        let text = |value: Option<&str>| value.map_or(Value::Null, |value| Value::String(value.to_string()));
+        Ok(match self {
+            Node::Literal(value) => value.clone(),
+            Node::List(items) => Value::List(
+                items
+                    .iter()
+                    .map(|item| item.evaluate(bindings))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Node::Variable(variable) => match variable {
+                Variable::Ip => Value::String(bindings.ip.to_string()),
+                Variable::UserAgent => text(request.and_then(|request| request.header("User-Agent"))),
+                Variable::Method => text(request.map(|request| request.method.as_str())),
+                Variable::Path => text(request.map(|request| request.path.as_str())),
+                Variable::Size => request.map_or(Value::Null, |request| Value::Number(request.size as f64)),
+                Variable::Country => text(bindings.geo.country.as_deref()),
+                Variable::Asn => bindings.geo.asn.map_or(Value::Null, |asn| Value::Number(asn as f64)),
+                Variable::AsOrg => text(bindings.geo.as_org.as_deref()),
+                Variable::Rate(window) => {
+                    Value::Number(bindings.rates.get(window).copied().unwrap_or(0) as f64)
+                }
+                Variable::Volume(window) => {
+                    Value::Number(bindings.volumes.get(window).copied().unwrap_or(0) as f64)
+                }
+            },
+            Node::Header(name) => text(request.and_then(|request| request.header(name))),
+            Node::Query(name) => text(request.and_then(|request| request.query.get(name)).map(String::as_str)),
+            Node::Matches(node, regex) => {
+                Value::Bool(node.evaluate_string(bindings)?.is_some_and(|value| regex.is_match(&value)))
+            }
+            Node::StringMethod(node, method, argument) => {
+                Value::Bool(node.evaluate_string(bindings)?.is_some_and(|value| match method {
+                    StringMethod::Contains => value.contains(argument.as_str()),
+                    StringMethod::StartsWith => value.starts_with(argument.as_str()),
+                    StringMethod::EndsWith => value.ends_with(argument.as_str()),
+                }))
+            }
+            Node::InCidr(node, networks) => Value::Bool(
+                node.evaluate_string(bindings)?
+                    .and_then(|value| normalize_ip(&value))
+                    .is_some_and(|addr| networks.iter().any(|network| network.contains(&addr))),
+            ),
+            Node::In(left, right) => {
+                let value = left.evaluate(bindings)?;
+                match right.evaluate(bindings)? {
+                    Value::List(items) => Value::Bool(items.contains(&value)),
+                    other => return Err(ExpressionError::Type(format!("`in` needs a list, got {:?}", other))),
+                }
+            }
+            Node::Compare(left, comparison, right) => {
+                compare(&left.evaluate(bindings)?, *comparison, &right.evaluate(bindings)?)?
+            }
+            Node::Not(node) => Value::Bool(!node.evaluate_bool(bindings)?),
+            Node::And(left, right) => {
+                Value::Bool(left.evaluate_bool(bindings)? && right.evaluate_bool(bindings)?)
+            }
+            Node::Or(left, right) => {
+                Value::Bool(left.evaluate_bool(bindings)? || right.evaluate_bool(bindings)?)
+            }
+        })
+    }
+
+    fn evaluate_bool(&self, bindings: &Bindings) -> Result<bool, ExpressionError> {
+        match self.evaluate(bindings)? {
+            Value::Bool(value) => Ok(value),
+            value => Err(ExpressionError::Type(format!("expected a boolean, got {:?}", value))),
+        }
+    }
+
+    /// Evaluate to a string, or `None` for null
+    fn evaluate_string(&self, bindings: &Bindings) -> Result<Option<String>, ExpressionError> {
+        match self.evaluate(bindings)? {
+            Value::String(value) => Ok(Some(value)),
+            Value::Null => Ok(None),
+            value => Err(ExpressionError::Type(format!("expected a string, got {:?}", value))),
+        }
+    }
+}
+
+fn compare(left: &Value, comparison: Comparison, right: &Value) -> Result<Value, ExpressionError> {
+    let ordering = match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.partial_cmp(right),
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        // Nothing is ordered against null, so `geo.asn > 100` is false for unknown ASNs
+        _ => match comparison {
+            Comparison::Eq => return Ok(Value::Bool(left == right)),
+            Comparison::Ne => return Ok(Value::Bool(left != right)),
+            _ if matches!(left, Value::Null) || matches!(right, Value::Null) => return Ok(Value::Bool(false)),
+            _ => {
+                return Err(ExpressionError::Type(format!("cannot compare {:?} with {:?}", left, right)));
+            }
+        },
+    };
+    let ordering = match ordering {
+        Some(ordering) => ordering,
+        None => return Ok(Value::Bool(false)),
+    };
+    Ok(Value::Bool(match comparison {
+        Comparison::Eq => ordering.is_eq(),
+        Comparison::Ne => ordering.is_ne(),
+        Comparison::Lt => ordering.is_lt(),
+        Comparison::Le => ordering.is_le(),
+        Comparison::Gt => ordering.is_gt(),
+        Comparison::Ge => ordering.is_ge(),
+    }))
+}
+
+fn syntax(position: usize, message: impl Into<String>) -> ExpressionError {
+    ExpressionError::Syntax { position, message: message.into() }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(f64),
+    /// Duration literal (`60s`, `5m`, `1h`, `1d`) in seconds
+    Duration(u32),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "`{}`", name),
+            Token::String(value) => write!(f, "{:?}", value),
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Duration(seconds) => write!(f, "{}s", seconds),
+            Token::Symbol(symbol) => write!(f, "`{}`", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 14] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "[", "]", ","];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (position, c) = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    Some((_, '\\')) => {
+                        let escaped = chars.get(i + 1).ok_or_else(|| syntax(position, "unterminated string"))?.1;
+                        value.push(match escaped {
+                            'n' => '\n',
+                            't' => '\t',
+                            other => other,
+                        });
+                        i += 2;
+                    }
+                    Some((_, end)) if *end == c => break,
+                    Some((_, other)) => {
+                        value.push(*other);
+                        i += 1;
+                    }
+                    None => return Err(syntax(position, "unterminated string")),
+                }
+            }
+            i += 1;
+            tokens.push((position, Token::String(value)));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while chars.get(i).is_some_and(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().map(|(_, c)| c).collect();
+            let unit = match chars.get(i).map(|(_, c)| *c) {
+                Some('s') => Some(1),
+                Some('m') => Some(60),
+                Some('h') => Some(3600),
+                Some('d') => Some(86400),
+                _ => None,
+            };
+            let token = match unit {
+                Some(unit) => {
+                    i += 1;
+                    let value: u32 = number.parse().map_err(|_| syntax(position, "invalid duration"))?;
+                    Token::Duration(value.checked_mul(unit).ok_or_else(|| syntax(position, "duration too long"))?)
+                }
+                None => Token::Number(number.parse().map_err(|_| syntax(position, "invalid number"))?),
+            };
+            tokens.push((position, token));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while chars.get(i).is_some_and(|(_, c)| c.is_alphanumeric() || *c == '_') {
+                i += 1;
+            }
+            tokens.push((position, Token::Identifier(chars[start..i].iter().map(|(_, c)| c).collect())));
+        } else if c == '.' {
+            tokens.push((position, Token::Symbol(".")));
+            i += 1;
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| source[position..].starts_with(**symbol))
+                .ok_or_else(|| syntax(position, format!("unexpected character `{}`", c)))?;
+            tokens.push((position, Token::Symbol(symbol)));
+            i += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser; `||` binds loosest, then `&&`, `!`, comparisons and methods
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    /// Current nesting, bounded so deep input can't overflow the stack
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    /// Source offset of the next token, for error messages
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .or(self.tokens.last())
+            .map_or(0, |(position, _)| *position)
+    }
+
+    fn next(&mut self) -> Result<Token, ExpressionError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|(_, token)| token.clone())
+            .ok_or_else(|| syntax(self.offset(), "unexpected end of expression"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), ExpressionError> {
+        if self.eat(symbol) {
+            return Ok(());
+        }
+        Err(syntax(self.offset(), format!("expected `{}`", symbol)))
+    }
+
+    /// Parse a nested part of the expression, up to `MAX_NESTING` deep
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Result<Node, ExpressionError>) -> Result<Node, ExpressionError> {
+        if self.depth >= MAX_NESTING {
+            return Err(syntax(self.offset(), format!("nested deeper than {} levels", MAX_NESTING)));
+        }
+        self.depth += 1;
+        let node = parse(self);
+        self.depth -= 1;
+        node
+    }
+
+    fn identifier(&mut self) -> Result<String, ExpressionError> {
+        let offset = self.offset();
+        match self.next()? {
+            Token::Identifier(name) => Ok(name),
+            token => Err(syntax(offset, format!("expected a name, got {}", token))),
+        }
+    }
+
+    fn string_argument(&mut self) -> Result<String, ExpressionError> {
+        let offset = self.offset();
+        match self.next()? {
+            Token::String(value) => Ok(value),
+            token => Err(syntax(offset, format!("expected a string literal, got {}", token))),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.parse_and()?;
+        while self.eat("||") {
+            node = Node::Or(Box::new(node), Box::new(self.parse_and()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.parse_unary()?;
+        while self.eat("&&") {
+            node = Node::And(Box::new(node), Box::new(self.parse_unary()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_unary(&mut self) -> Result<Node, ExpressionError> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.nested(Self::parse_unary)?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Node, ExpressionError> {
+        let left = self.parse_postfix()?;
+        if self.peek() == Some(&Token::Identifier("in".to_string())) {
+            self.position += 1;
+            return Ok(Node::In(Box::new(left), Box::new(self.parse_postfix()?)));
+        }
+
+        let comparison = match self.peek() {
+            Some(Token::Symbol("==")) => Comparison::Eq,
+            Some(Token::Symbol("!=")) => Comparison::Ne,
+            Some(Token::Symbol("<")) => Comparison::Lt,
+            Some(Token::Symbol("<=")) => Comparison::Le,
+            Some(Token::Symbol(">")) => Comparison::Gt,
+            Some(Token::Symbol(">=")) => Comparison::Ge,
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        Ok(Node::Compare(Box::new(left), comparison, Box::new(self.parse_postfix()?)))
+    }
+
+    /// A primary value followed by method calls
+    fn parse_postfix(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.parse_primary()?;
+        while self.eat(".") {
+            let offset = self.offset();
+            let method = self.identifier()?;
+            self.expect("(")?;
+            node = match method.as_str() {
+                "matches" => {
+                    let pattern = self.string_argument()?;
+                    let regex = Regex::new(&pattern).map_err(|e| syntax(offset, e.to_string()))?;
+                    Node::Matches(Box::new(node), regex)
+                }
+                "contains" => Node::StringMethod(Box::new(node), StringMethod::Contains, self.string_argument()?),
+                "starts_with" => {
+                    Node::StringMethod(Box::new(node), StringMethod::StartsWith, self.string_argument()?)
+                }
+                "ends_with" => Node::StringMethod(Box::new(node), StringMethod::EndsWith, self.string_argument()?),
+                "in_cidr" => {
+                    let mut networks = Vec::new();
+                    loop {
+                        let offset = self.offset();
+                        let network = self.string_argument()?;
+                        networks.push(
+                            parse_network(&network)
+                                .ok_or_else(|| syntax(offset, format!("invalid network `{}`", network)))?,
+                        );
+                        if !self.eat(",") {
+                            break;
+                        }
+                    }
+                    Node::InCidr(Box::new(node), networks)
+                }
+                _ => return Err(syntax(offset, format!("unknown method `{}`", method))),
+            };
+            self.expect(")")?;
+        }
+        Ok(node)
+    }
+
+    fn parse_primary(&mut self) -> Result<Node, ExpressionError> {
+        let offset = self.offset();
+        match self.next()? {
+            Token::String(value) => Ok(Node::Literal(Value::String(value))),
+            Token::Number(value) => Ok(Node::Literal(Value::Number(value))),
+            Token::Duration(seconds) => Ok(Node::Literal(Value::Number(seconds as f64))),
+            Token::Symbol("(") => self.nested(|parser| {
+                let node = parser.parse_or()?;
+                parser.expect(")")?;
+                Ok(node)
+            }),
+            Token::Symbol("[") => self.nested(|parser| {
+                let mut items = Vec::new();
+                if !parser.eat("]") {
+                    loop {
+                        items.push(parser.parse_postfix()?);
+                        if !parser.eat(",") {
+                            break;
+                        }
+                    }
+                    parser.expect("]")?;
+                }
+                Ok(Node::List(items))
+            }),
+            Token::Identifier(name) => match name.as_str() {
+                "true" => Ok(Node::Literal(Value::Bool(true))),
+                "false" => Ok(Node::Literal(Value::Bool(false))),
+                "null" => Ok(Node::Literal(Value::Null)),
+                "ip" => Ok(Node::Variable(Variable::Ip)),
+                "ua" => Ok(Node::Variable(Variable::UserAgent)),
+                "req" => self.parse_request_field(),
+                "geo" => {
+                    self.expect(".")?;
+                    let offset = self.offset();
+                    match self.identifier()?.as_str() {
+                        "country" => Ok(Node::Variable(Variable::Country)),
+                        "asn" => Ok(Node::Variable(Variable::Asn)),
+                        "org" => Ok(Node::Variable(Variable::AsOrg)),
+                        field => Err(syntax(offset, format!("unknown field `geo.{}`", field))),
+                    }
+                }
+                _ => Err(syntax(offset, format!("unknown name `{}`", name))),
+            },
+            token => Err(syntax(offset, format!("unexpected {}", token))),
+        }
+    }
+
+    fn parse_request_field(&mut self) -> Result<Node, ExpressionError> {
+        self.expect(".")?;
+        let offset = self.offset();
+        let field = self.identifier()?;
+        let node = match field.as_str() {
+            "method" => return Ok(Node::Variable(Variable::Method)),
+            "path" => return Ok(Node::Variable(Variable::Path)),
+            "size" => return Ok(Node::Variable(Variable::Size)),
+            "header" | "query" => {
+                self.expect("(")?;
+                let name = self.string_argument()?;
+                if field == "header" {
+                    Node::Header(name)
+                } else {
+                    Node::Query(name)
+                }
+            }
+            "rate" | "volume" => {
+                self.expect("(")?;
+                let offset = self.offset();
+                let window = match self.next()? {
+                    Token::Duration(seconds) if seconds > 0 => seconds,
+                    Token::Number(seconds) if seconds >= 1.0 && seconds.fract() == 0.0 => seconds as u32,
+                    token => return Err(syntax(offset, format!("expected a window such as `60s`, got {}", token))),
+                };
+                if field == "rate" {
+                    Node::Variable(Variable::Rate(window))
+                } else {
+                    Node::Variable(Variable::Volume(window))
+                }
+            }
+            _ => return Err(syntax(offset, format!("unknown field `req.{}`", field))),
+        };
+        self.expect(")")?;
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expression() {
+        let expression = Expression::parse(
+            r#"ip.in_cidr("10.0.0.0/8") && req.rate(60s) > 500 && ua.matches("curl.*")"#,
+        )
+        .unwrap();
+        assert!(expression.needs_request());
+        assert!(!expression.needs_geo());
+        assert_eq!(expression.rate_windows(), vec![60]);
+
+        let request = RequestContext {
+            ip: "10.1.2.3".to_string(),
+            headers: HashMap::from([("user-agent".to_string(), "curl/8.0".to_string())]),
+            ..Default::default()
+        };
+        let mut bindings = Bindings {
+            ip: "10.1.2.3",
+            request: Some(&request),
+            rates: HashMap::from([(60, 501)]),
+            ..Default::default()
+        };
+        assert!(expression.evaluate(&bindings).unwrap());
+        bindings.rates.insert(60, 500);
+        assert!(!expression.evaluate(&bindings).unwrap());
+
+        // Absent values don't match, and ordering against them is false
+        let expression = Expression::parse(
+            r#"!(req.header("X-Api-Key").starts_with("k_") || geo.asn >= 1) && !(geo.country in ["CN", "RU"])"#,
+        )
+        .unwrap();
+        assert!(expression.needs_geo());
+        assert!(expression.evaluate(&bindings).unwrap());
+
+        // Expressions serialize to their source and are compiled when deserialized
+        let json = serde_json::to_string(&expression).unwrap();
+        assert_eq!(serde_json::from_str::<Expression>(&json).unwrap().source(), expression.source());
+        assert!(serde_json::from_str::<Expression>(r#""req.rate(0s) > 1""#).is_err());
+
+        assert!(Expression::parse("ip.matches(\"[\")").is_err());
+        assert!(Expression::parse("ip.in_cidr(\"not a network\")").is_err());
+        assert!(Expression::parse("foo == 1").is_err());
+        assert!(Expression::parse("req.rate(60s) >").is_err());
+        assert!(Expression::parse("req.size").unwrap().evaluate(&bindings).is_err());
+    }
+
+    #[test]
+    fn test_expression_limits() {
+        let nested = |depth: usize| format!("{}ip == \"10.0.0.1\"{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Expression::parse(&nested(MAX_NESTING)).is_ok());
+        assert!(Expression::parse(&nested(MAX_NESTING + 1)).is_err());
+        assert!(Expression::parse(&"!".repeat(100_000)).is_err());
+        assert!(Expression::parse(&"[".repeat(100_000)).is_err());
+
+        let long = vec!["ip == \"10.0.0.1\""; 300].join(" || ");
+        assert!(long.len() > MAX_SOURCE_LENGTH);
+        assert!(Expression::parse(&long).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! Host firewall enforcement for the DDoS protection service.
+//!
+//! This module mirrors the Redis blocklist into nftables or ipset sets on
+//! the host, so that blocked clients are dropped by the kernel before they
+//! reach the HTTP stack. Every instance keeps its own host's sets in sync;
+//! entries are added with their remaining block duration as a timeout, so
+//! the kernel expires them on its own, and blocks lifted early are removed.
+//! Blocked networks overlapping the allowlist are left out of the sets.
+
+use std::collections::{HashMap, HashSet};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use crate::core::allowlist::Allowlist;
+use crate::core::blocklist::{BlockEntry, Blocklist, BlocklistError};
+use crate::models::{FirewallBackend, FirewallConfig};
+use crate::utils::parse_network;
+
+/// Errors that can occur while updating the host firewall
+#[derive(Error, Debug)]
+pub enum FirewallError {
+    #[error("Blocklist error: {0}")]
+    BlocklistError(#[from] BlocklistError),
+    #[error("Failed to run {0}: {1}")]
+    CommandError(&'static str, std::io::Error),
+    #[error("{0} failed: {1}")]
+    CommandFailed(&'static str, String),
+}
+
+/// Changes bringing the firewall sets in line with the blocklist
+#[derive(Debug, Default, PartialEq)]
+struct SyncPlan {
+    /// Networks to add, with their timeout in seconds (`None` = permanent)
+    add: Vec<(IpNet, Option<u64>)>,
+    /// Networks to remove
+    remove: Vec<IpNet>,
+}
+
+impl SyncPlan {
+    fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// Networks of the active blocklist entries that belong in the firewall sets, with their expiry
+///
+/// The kernel drops traffic before the allowlist is consulted, so networks
+/// overlapping allowlisted ones are left out.
+fn desired_entries(
+    entries: &[BlockEntry],
+    allowlisted: &HashSet<IpNet>,
+    now: DateTime<Utc>,
+) -> HashMap<IpNet, Option<DateTime<Utc>>> {
+    entries
+        .iter()
+        .filter(|entry| !entry.is_expired(now))
+        .filter_map(|entry| Some((parse_network(&entry.target)?, entry.expires_at)))
+        .filter(|(network, _)| !allowlisted.contains(network))
+        .collect()
+}
+
+/// Plan the changes from the applied entries to the active blocklist entries
+///
+/// Entries whose expiry changed are removed and added again with the new
+/// timeout. Entries that expired on their own are already gone from the
+/// kernel, so only blocks lifted early, or networks since allowlisted, are
+/// removed.
+fn plan_sync(
+    applied: &HashMap<IpNet, Option<DateTime<Utc>>>,
+    entries: &[BlockEntry],
+    allowlisted: &HashSet<IpNet>,
+    now: DateTime<Utc>,
+) -> SyncPlan {
+    let desired = desired_entries(entries, allowlisted, now);
+
+    let mut plan = SyncPlan::default();
+    for (network, expires_at) in applied {
+        let lifted_early = expires_at.is_none_or(|expires_at| expires_at > now);
+        if desired.get(network) != Some(expires_at) && lifted_early {
+            plan.remove.push(*network);
+        }
+    }
+    for (network, expires_at) in &desired {
+        if applied.get(network) != Some(expires_at) {
+            let timeout = expires_at.map(|expires_at| (expires_at - now).num_seconds().max(1) as u64);
+            plan.add.push((*network, timeout));
+        }
+    }
+    plan.remove.sort();
+    plan.add.sort();
+    plan
+}
+
+/// Name of the set holding a network's address family
+fn set_for(config: &FirewallConfig, network: &IpNet) -> String {
+    match network {
+        IpNet::V4(_) => format!("{}_v4", config.set_name),
+        IpNet::V6(_) => format!("{}_v6", config.set_name),
+    }
+}
+
+/// Script creating empty sets, and for nftables the chain dropping their traffic
+fn setup_script(config: &FirewallConfig) -> String {
+    let (v4, v6) = (format!("{}_v4", config.set_name), format!("{}_v6", config.set_name));
+    match config.backend {
+        // The table is recreated so that restarts don't duplicate the rules
+        FirewallBackend::Nftables => format!(
+            "table inet {table}\n\
+             delete table inet {table}\n\
+             table inet {table} {{\n\
+             \x20   set {v4} {{ type ipv4_addr; flags interval, timeout; }}\n\
+             \x20   set {v6} {{ type ipv6_addr; flags interval, timeout; }}\n\
+             \x20   chain input {{\n\
+             \x20       type filter hook input priority -10; policy accept;\n\
+             \x20       ip saddr @{v4} drop\n\
+             \x20       ip6 saddr @{v6} drop\n\
+             \x20   }}\n\
+             }}\n",
+            table = config.table,
+        ),
+        FirewallBackend::Ipset => format!(
+            "create {v4} hash:net family inet timeout 0\n\
+             create {v6} hash:net family inet6 timeout 0\n\
+             flush {v4}\n\
+             flush {v6}\n",
+        ),
+    }
+}
+
+/// Script applying a sync plan
+fn sync_script(config: &FirewallConfig, plan: &SyncPlan) -> String {
+    let mut script = String::new();
+    for network in &plan.remove {
+        let set = set_for(config, network);
+        match config.backend {
+            FirewallBackend::Nftables => {
+                script.push_str(&format!("delete element inet {} {} {{ {} }}\n", config.table, set, network))
+            }
+            FirewallBackend::Ipset => script.push_str(&format!("del {} {}\n", set, network)),
+        }
+    }
+    for (network, timeout) in &plan.add {
+        let set = set_for(config, network);
+        match (config.backend, timeout) {
+            (FirewallBackend::Nftables, Some(timeout)) => script.push_str(&format!(
+                "add element inet {} {} {{ {} timeout {}s }}\n",
+                config.table, set, network, timeout
+            )),
+            (FirewallBackend::Nftables, None) => {
+                script.push_str(&format!("add element inet {} {} {{ {} }}\n", config.table, set, network))
+            }
+            // A timeout of 0 keeps the entry until it is removed
+            (FirewallBackend::Ipset, timeout) => {
+                script.push_str(&format!("add {} {} timeout {}\n", set, network, timeout.unwrap_or(0)))
+            }
+        }
+    }
+    script
+}
+
+/// Mirrors the blocklist into the host firewall
+#[derive(Clone)]
+pub struct FirewallExecutor {
+    /// Host firewall configuration
+    config: FirewallConfig,
+    /// Blocklist mirrored into the firewall
+    blocklist: Blocklist,
+    /// Allowlist whose networks are never put in the firewall sets
+    allowlist: Allowlist,
+    /// Entries in the firewall sets, with their expiry
+    applied: Arc<Mutex<HashMap<IpNet, Option<DateTime<Utc>>>>>,
+}
+
+impl FirewallExecutor {
+    /// Create a new firewall executor
+    pub fn new(config: FirewallConfig, blocklist: Blocklist, allowlist: Allowlist) -> Self {
+        Self {
+            config,
+            blocklist,
+            allowlist,
+            applied: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Create the firewall sets, emptying them if they already exist
+    pub async fn setup(&self) -> Result<(), FirewallError> {
+        let mut applied = self.applied.lock().await;
+        self.run(&setup_script(&self.config)).await?;
+        applied.clear();
+        Ok(())
+    }
+
+    /// Bring the firewall sets in line with the blocklist
+    #[tracing::instrument(name = "firewall.sync", skip_all)]
+    pub async fn sync(&self) -> Result<(), FirewallError> {
+        let entries = self.blocklist.get_entries().await?;
+        let mut allowlisted = HashSet::new();
+        for network in entries.iter().filter_map(|entry| parse_network(&entry.target)) {
+            if self.allowlist.overlaps(&network).await {
+                allowlisted.insert(network);
+            }
+        }
+        let mut applied = self.applied.lock().await;
+        let now = Utc::now();
+        let plan = plan_sync(&applied, &entries, &allowlisted, now);
+        if plan.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = self.run(&sync_script(&self.config, &plan)).await {
+            // nftables applies a script all or nothing, so one bad element would hold up the rest
+            log::warn!("Failed to update firewall sets, retrying entry by entry: {}", e);
+            for network in &plan.remove {
+                let single = SyncPlan { add: Vec::new(), remove: vec![*network] };
+                if let Err(e) = self.run(&sync_script(&self.config, &single)).await {
+                    log::error!("Failed to remove {} from the firewall: {}", network, e);
+                }
+            }
+            for add in &plan.add {
+                let single = SyncPlan { add: vec![*add], remove: Vec::new() };
+                if let Err(e) = self.run(&sync_script(&self.config, &single)).await {
+                    log::error!("Failed to add {} to the firewall: {}", add.0, e);
+                }
+            }
+        }
+
+        metrics::counter!("firewall_entries_added_total", plan.add.len() as u64);
+        metrics::counter!("firewall_entries_removed_total", plan.remove.len() as u64);
+        *applied = desired_entries(&entries, &allowlisted, now);
+        Ok(())
+    }
+
+    /// Create the firewall sets, then keep them in sync with the blocklist
+    pub async fn start(&self) {
+        if !self.config.enabled {
+            return;
+        }
+        if let Err(e) = self.setup().await {
+            log::error!("Failed to set up the host firewall, blocks won't be enforced there: {}", e);
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.sync_interval_seconds.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sync().await {
+                log::error!("Failed to sync the host firewall: {}", e);
+            }
+        }
+    }
+
+    /// Run a script through the firewall's batch interface
+    async fn run(&self, script: &str) -> Result<(), FirewallError> {
+        let (program, args): (&'static str, &[&str]) = match self.config.backend {
+            FirewallBackend::Nftables => ("nft", &["-f", "-"]),
+            FirewallBackend::Ipset => ("ipset", &["restore", "-exist"]),
+        };
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| FirewallError::CommandError(program, e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(script.as_bytes()).await.map_err(|e| FirewallError::CommandError(program, e))?;
+        }
+        let output = child.wait_with_output().await.map_err(|e| FirewallError::CommandError(program, e))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(FirewallError::CommandFailed(program, stderr));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(target: &str, expires_at: Option<DateTime<Utc>>) -> BlockEntry {
+        BlockEntry {
+            target: target.to_string(),
+            reason: "test".to_string(),
+            source: "api".to_string(),
+            created_at: Utc::now(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_plan_sync() {
+        let now = Utc::now();
+        let later = now + chrono::Duration::seconds(600);
+        let net = |value: &str| parse_network(value).unwrap();
+
+        let none = HashSet::new();
+
+        let plan = plan_sync(&HashMap::new(), &[entry("192.0.2.1", Some(later)), entry("2001:db8::/64", None)], &none, now);
+        assert_eq!(plan.add, vec![(net("192.0.2.1"), Some(600)), (net("2001:db8::/64"), None)]);
+        assert!(plan.remove.is_empty());
+
+        let applied = HashMap::from([
+            (net("192.0.2.1"), Some(later)),
+            (net("198.51.100.0/24"), None),
+            (net("203.0.113.7"), Some(now - chrono::Duration::seconds(1))),
+        ]);
+        // Unchanged entries are left alone, lifted blocks removed and expired ones left to the kernel
+        let plan = plan_sync(&applied, &[entry("192.0.2.1", Some(later))], &none, now);
+        assert_eq!(plan, SyncPlan { add: Vec::new(), remove: vec![net("198.51.100.0/24")] });
+
+        // Extended blocks are re-added with the new timeout
+        let extended = now + chrono::Duration::seconds(3600);
+        let plan = plan_sync(&applied, &[entry("192.0.2.1", Some(extended)), entry("198.51.100.0/24", None)], &none, now);
+        assert_eq!(plan, SyncPlan { add: vec![(net("192.0.2.1"), Some(3600))], remove: vec![net("192.0.2.1")] });
+
+        // Networks overlapping the allowlist are never added, and removed once allowlisted
+        let allowlisted = HashSet::from([net("198.51.100.0/24"), net("2001:db8::/64")]);
+        let entries = [entry("192.0.2.1", Some(later)), entry("198.51.100.0/24", None), entry("2001:db8::/64", None)];
+        let plan = plan_sync(&HashMap::new(), &entries, &allowlisted, now);
+        assert_eq!(plan, SyncPlan { add: vec![(net("192.0.2.1"), Some(600))], remove: Vec::new() });
+        let plan = plan_sync(&applied, &entries, &allowlisted, now);
+        assert_eq!(plan, SyncPlan { add: Vec::new(), remove: vec![net("198.51.100.0/24")] });
+    }
+
+    #[test]
+    fn test_sync_script() {
+        let plan = SyncPlan {
+            add: vec![(parse_network("192.0.2.0/24").unwrap(), Some(60)), (parse_network("2001:db8::1").unwrap(), None)],
+            remove: vec![parse_network("198.51.100.7").unwrap()],
+        };
+
+        let nftables = FirewallConfig::default();
+        assert_eq!(
+            sync_script(&nftables, &plan),
+            "delete element inet ddos_protection ddos_blocklist_v4 { 198.51.100.7/32 }\n\
+             add element inet ddos_protection ddos_blocklist_v4 { 192.0.2.0/24 timeout 60s }\n\
+             add element inet ddos_protection ddos_blocklist_v6 { 2001:db8::1/128 }\n"
+        );
+
+        let ipset = FirewallConfig { backend: FirewallBackend::Ipset, ..Default::default() };
+        assert_eq!(
+            sync_script(&ipset, &plan),
+            "del ddos_blocklist_v4 198.51.100.7/32\n\
+             add ddos_blocklist_v4 192.0.2.0/24 timeout 60\n\
+             add ddos_blocklist_v6 2001:db8::1/128 timeout 0\n"
+        );
+        assert!(setup_script(&ipset).starts_with("create ddos_blocklist_v4 hash:net family inet timeout 0\n"));
+    }
+}
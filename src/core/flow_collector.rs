@@ -0,0 +1,847 @@
+//! Network flow collection for the DDoS protection service.
+//!
+//! This module listens for NetFlow v5/v9, IPFIX and sFlow v5 datagrams
+//! exported by routers, so that volumetric attacks that never reach the
+//! HTTP layer can be detected. Flows are added up per source address,
+//! scaled by the exporter's sampling rate, and fed to the DDoS detector
+//! every flush interval. Flows feed blocking decisions, so only datagrams
+//! from the configured exporters are accepted, reported sampling rates are
+//! capped, and each exporter may only define so many templates.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use crate::core::ddos_detector::DdosDetector;
+use crate::models::FlowCollectorConfig;
+use crate::utils::parse_network;
+
+/// Largest datagram accepted
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// Most templates remembered per exporter; further ones are ignored
+const MAX_TEMPLATES_PER_EXPORTER: usize = 256;
+
+/// Most source ids or observation domains whose sampling rate is remembered per exporter
+const MAX_DOMAINS_PER_EXPORTER: usize = 64;
+
+/// NetFlow v9 and IPFIX field types used by the collector
+const FIELD_BYTES: u16 = 1;
+const FIELD_PACKETS: u16 = 2;
+const FIELD_IPV4_SOURCE: u16 = 8;
+const FIELD_IPV6_SOURCE: u16 = 27;
+const FIELD_SAMPLING_INTERVAL: u16 = 34;
+const FIELD_SAMPLING_PACKET_INTERVAL: u16 = 305;
+
+/// Errors that can occur while parsing a flow datagram
+#[derive(Error, Debug, PartialEq)]
+pub enum FlowParseError {
+    #[error("Datagram is truncated")]
+    Truncated,
+    #[error("Unsupported version: {0}")]
+    UnsupportedVersion(u32),
+}
+
+/// Traffic from one source, as reported in a flow or packet sample
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowRecord {
+    /// Source address of the traffic
+    pub source: IpAddr,
+    /// Packets seen by the exporter
+    pub packets: u64,
+    /// Bytes seen by the exporter
+    pub bytes: u64,
+    /// Sampling rate reported by the exporter, if any
+    pub sampling_rate: Option<u32>,
+}
+
+/// Big-endian reader over a datagram
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], FlowParseError> {
+        if self.data.len() < len {
+            return Err(FlowParseError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, FlowParseError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FlowParseError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, FlowParseError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), FlowParseError> {
+        self.bytes(len).map(|_| ())
+    }
+
+    /// Split off the next `len` bytes as their own reader
+    fn sub(&mut self, len: usize) -> Result<Reader<'a>, FlowParseError> {
+        Ok(Reader::new(self.bytes(len)?))
+    }
+}
+
+/// Unsigned big-endian integer of up to eight bytes
+fn read_uint(bytes: &[u8]) -> u64 {
+    bytes[bytes.len().saturating_sub(8)..]
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+/// Field of a NetFlow v9 or IPFIX template
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TemplateField {
+    /// Field type, or `None` for enterprise-specific fields
+    field_type: Option<u16>,
+    /// Field length in bytes (65535 = variable length, IPFIX only)
+    length: u16,
+}
+
+/// Template exported by a NetFlow v9 or IPFIX exporter
+type Template = Vec<TemplateField>;
+
+/// Templates are scoped to the exporter, its source id or observation domain, and the template id
+type TemplateKey = (IpAddr, u32, u16);
+
+/// Parser of flow datagrams
+///
+/// NetFlow v9 and IPFIX data records can only be decoded with templates
+/// sent earlier by the same exporter, so the parser remembers them, along
+/// with sampling rates reported in options data.
+#[derive(Default)]
+pub struct FlowParser {
+    /// Templates by exporter, domain and template id
+    templates: HashMap<TemplateKey, Template>,
+    /// Sampling rates reported in options data, by exporter and domain
+    sampling_rates: HashMap<(IpAddr, u32), u32>,
+}
+
+impl FlowParser {
+    /// Create a new parser
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a datagram from an exporter, recognizing its protocol from the version
+    pub fn parse(&mut self, exporter: IpAddr, data: &[u8]) -> Result<Vec<FlowRecord>, FlowParseError> {
+        let mut reader = Reader::new(data);
+        match reader.u16()? {
+            5 => parse_netflow_v5(&mut reader),
+            9 => self.parse_netflow_v9(exporter, &mut reader),
+            10 => self.parse_ipfix(exporter, &mut reader),
+            // sFlow versions are 32 bits wide
+            0 => match reader.u16()? {
+                5 => parse_sflow(&mut reader),
+                version => Err(FlowParseError::UnsupportedVersion(version as u32)),
+            },
+            version => Err(FlowParseError::UnsupportedVersion(version as u32)),
+        }
+    }
+
+    fn parse_netflow_v9(&mut self, exporter: IpAddr, reader: &mut Reader) -> Result<Vec<FlowRecord>, FlowParseError> {
+        // count, sys_uptime, unix_secs, sequence
+        reader.skip(14)?;
+        let source_id = reader.u32()?;
+
+        let mut records = Vec::new();
+        while reader.remaining() >= 4 {
+            let flowset_id = reader.u16()?;
+            let length = reader.u16()? as usize;
+            if length < 4 {
+                return Err(FlowParseError::Truncated);
+            }
+            let mut flowset = reader.sub(length - 4)?;
+            match flowset_id {
+                0 => {
+                    while flowset.remaining() >= 4 {
+                        let template_id = flowset.u16()?;
+                        let count = flowset.u16()?;
+                        let template = read_fields(&mut flowset, count, false)?;
+                        self.insert_template((exporter, source_id, template_id), template);
+                    }
+                }
+                1 => {
+                    while flowset.remaining() >= 6 {
+                        let template_id = flowset.u16()?;
+                        let scope_length = flowset.u16()?;
+                        let option_length = flowset.u16()?;
+                        // Scope fields have their own type numbers, which the collector doesn't use
+                        let mut template: Template = read_fields(&mut flowset, scope_length / 4, false)?
+                            .into_iter()
+                            .map(|field| TemplateField { field_type: None, ..field })
+                            .collect();
+                        template.extend(read_fields(&mut flowset, option_length / 4, false)?);
+                        self.insert_template((exporter, source_id, template_id), template);
+                    }
+                }
+                2..=255 => (),
+                template_id => self.decode_data(exporter, source_id, template_id, &mut flowset, &mut records)?,
+            }
+        }
+        Ok(records)
+    }
+
+    fn parse_ipfix(&mut self, exporter: IpAddr, reader: &mut Reader) -> Result<Vec<FlowRecord>, FlowParseError> {
+        let length = reader.u16()? as usize;
+        // export_time, sequence
+        reader.skip(8)?;
+        let domain = reader.u32()?;
+        let mut message = reader.sub(length.saturating_sub(16).min(reader.remaining()))?;
+
+        let mut records = Vec::new();
+        while message.remaining() >= 4 {
+            let set_id = message.u16()?;
+            let length = message.u16()? as usize;
+            if length < 4 {
+                return Err(FlowParseError::Truncated);
+            }
+            let mut set = message.sub(length - 4)?;
+            match set_id {
+                2 | 3 => {
+                    while set.remaining() >= 4 {
+                        let template_id = set.u16()?;
+                        let count = set.u16()?;
+                        if count == 0 {
+                            // Template withdrawal
+                            self.templates.remove(&(exporter, domain, template_id));
+                            continue;
+                        }
+                        if set_id == 3 {
+                            // scope field count; scope fields come first in the list
+                            set.skip(2)?;
+                        }
+                        let template = read_fields(&mut set, count, true)?;
+                        self.insert_template((exporter, domain, template_id), template);
+                    }
+                }
+                0..=255 => (),
+                template_id => self.decode_data(exporter, domain, template_id, &mut set, &mut records)?,
+            }
+        }
+        Ok(records)
+    }
+
+    /// Remember a template, unless its exporter already has too many
+    fn insert_template(&mut self, key: TemplateKey, template: Template) {
+        let exporter = key.0;
+        if !self.templates.contains_key(&key)
+            && self.templates.keys().filter(|(other, _, _)| *other == exporter).count() >= MAX_TEMPLATES_PER_EXPORTER
+        {
+            metrics::increment_counter!("flow_collector_dropped_templates_total");
+            return;
+        }
+        self.templates.insert(key, template);
+    }
+
+    /// Decode the records of a data flowset or set with its template
+    ///
+    /// Data arriving before its template is skipped. Records without a
+    /// source address but with a sampling interval are options data, whose
+    /// rate applies to the exporter's later records.
+    fn decode_data(
+        &mut self,
+        exporter: IpAddr,
+        domain: u32,
+        template_id: u16,
+        set: &mut Reader,
+        records: &mut Vec<FlowRecord>,
+    ) -> Result<(), FlowParseError> {
+        let Some(template) = self.templates.get(&(exporter, domain, template_id)) else {
+            metrics::increment_counter!("flow_collector_missing_templates_total");
+            return Ok(());
+        };
+        let min_length: usize = template
+            .iter()
+            .map(|field| if field.length == u16::MAX { 1 } else { field.length as usize })
+            .sum();
+        if min_length == 0 {
+            return Ok(());
+        }
+
+        let mut decoded = Vec::new();
+        // Anything shorter than a record is padding
+        while set.remaining() >= min_length {
+            let mut source = None;
+            let (mut packets, mut bytes, mut sampling_rate) = (0, 0, None);
+            for field in template {
+                let length = match field.length {
+                    u16::MAX => match set.u8()? {
+                        255 => set.u16()? as usize,
+                        length => length as usize,
+                    },
+                    length => length as usize,
+                };
+                let value = set.bytes(length)?;
+                match (field.field_type, length) {
+                    (Some(FIELD_BYTES), _) => bytes = read_uint(value),
+                    (Some(FIELD_PACKETS), _) => packets = read_uint(value),
+                    (Some(FIELD_IPV4_SOURCE), 4) => {
+                        source = Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(value).unwrap())))
+                    }
+                    (Some(FIELD_IPV6_SOURCE), 16) => {
+                        source = Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(value).unwrap())))
+                    }
+                    (Some(FIELD_SAMPLING_INTERVAL | FIELD_SAMPLING_PACKET_INTERVAL), _) => {
+                        sampling_rate = Some(read_uint(value) as u32).filter(|rate| *rate > 0)
+                    }
+                    _ => (),
+                }
+            }
+            decoded.push((source, packets, bytes, sampling_rate));
+        }
+
+        for (source, packets, bytes, sampling_rate) in decoded {
+            match source {
+                Some(source) => records.push(FlowRecord {
+                    source,
+                    packets,
+                    bytes,
+                    sampling_rate: sampling_rate.or_else(|| self.sampling_rates.get(&(exporter, domain)).copied()),
+                }),
+                None => {
+                    let known = self.sampling_rates.contains_key(&(exporter, domain))
+                        || self.sampling_rates.keys().filter(|(other, _)| *other == exporter).count()
+                            < MAX_DOMAINS_PER_EXPORTER;
+                    if let Some(rate) = sampling_rate.filter(|_| known) {
+                        self.sampling_rates.insert((exporter, domain), rate);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read a template's field specifiers
+///
+/// IPFIX fields with the enterprise bit set are followed by an enterprise
+/// number and aren't understood by the collector.
+fn read_fields(reader: &mut Reader, count: u16, ipfix: bool) -> Result<Template, FlowParseError> {
+    let mut fields = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let field_type = reader.u16()?;
+        let length = reader.u16()?;
+        if ipfix && field_type & 0x8000 != 0 {
+            reader.skip(4)?;
+            fields.push(TemplateField { field_type: None, length });
+        } else {
+            fields.push(TemplateField { field_type: Some(field_type), length });
+        }
+    }
+    Ok(fields)
+}
+
+fn parse_netflow_v5(reader: &mut Reader) -> Result<Vec<FlowRecord>, FlowParseError> {
+    let count = reader.u16()?;
+    // sys_uptime, unix_secs, unix_nsecs, flow_sequence, engine_type, engine_id
+    reader.skip(18)?;
+    // The top two bits are the sampling mode
+    let sampling_rate = Some((reader.u16()? & 0x3fff) as u32).filter(|rate| *rate > 0);
+
+    let mut records = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut record = reader.sub(48)?;
+        let source = IpAddr::V4(Ipv4Addr::from(record.u32()?));
+        // dstaddr, nexthop, input, output
+        record.skip(12)?;
+        let packets = record.u32()? as u64;
+        let bytes = record.u32()? as u64;
+        records.push(FlowRecord { source, packets, bytes, sampling_rate });
+    }
+    Ok(records)
+}
+
+fn parse_sflow(reader: &mut Reader) -> Result<Vec<FlowRecord>, FlowParseError> {
+    match reader.u32()? {
+        1 => reader.skip(4)?,
+        2 => reader.skip(16)?,
+        _ => return Err(FlowParseError::Truncated),
+    }
+    // sub_agent_id, sequence, uptime
+    reader.skip(12)?;
+    let samples = reader.u32()?;
+
+    let mut records = Vec::new();
+    for _ in 0..samples {
+        let format = reader.u32()?;
+        let length = reader.u32()? as usize;
+        let mut sample = reader.sub(length)?;
+        match format {
+            // Flow sample: sequence, source id
+            1 => sample.skip(8)?,
+            // Expanded flow sample: sequence, source id type and index
+            3 => sample.skip(12)?,
+            // Counter samples and enterprise formats
+            _ => continue,
+        }
+        let sampling_rate = Some(sample.u32()?).filter(|rate| *rate > 0);
+        // sample pool, drops, then the input and output interfaces
+        sample.skip(if format == 1 { 16 } else { 24 })?;
+        let flow_records = sample.u32()?;
+        for _ in 0..flow_records {
+            let format = sample.u32()?;
+            let length = sample.u32()? as usize;
+            let mut record = sample.sub(length)?;
+            if let Some((source, bytes)) = sampled_packet(format, &mut record)? {
+                records.push(FlowRecord { source, packets: 1, bytes, sampling_rate });
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Source address and length of a sampled packet, from an sFlow flow record
+fn sampled_packet(format: u32, record: &mut Reader) -> Result<Option<(IpAddr, u64)>, FlowParseError> {
+    match format {
+        // Raw packet header
+        1 => {
+            let protocol = record.u32()?;
+            let frame_length = record.u32()? as u64;
+            // stripped
+            record.skip(4)?;
+            let header_length = record.u32()? as usize;
+            let mut header = record.sub(header_length)?;
+            let source = match protocol {
+                // Ethernet
+                1 => ethernet_source(&mut header),
+                // IPv4
+                11 => ip_source(0x0800, &mut header),
+                // IPv6
+                12 => ip_source(0x86dd, &mut header),
+                _ => None,
+            };
+            Ok(source.map(|source| (source, frame_length)))
+        }
+        // Sampled IPv4: length, protocol, source
+        3 => {
+            let length = record.u32()? as u64;
+            record.skip(4)?;
+            Ok(Some((IpAddr::V4(Ipv4Addr::from(record.u32()?)), length)))
+        }
+        // Sampled IPv6: length, protocol, source
+        4 => {
+            let length = record.u32()? as u64;
+            record.skip(4)?;
+            let source: [u8; 16] = record.bytes(16)?.try_into().unwrap();
+            Ok(Some((IpAddr::V6(Ipv6Addr::from(source)), length)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Source address of an Ethernet frame carrying IP
+fn ethernet_source(header: &mut Reader) -> Option<IpAddr> {
+    // destination and source MAC addresses
+    header.skip(12).ok()?;
+    let mut ethertype = header.u16().ok()?;
+    // 802.1Q and 802.1ad tags
+    while ethertype == 0x8100 || ethertype == 0x88a8 {
+        header.skip(2).ok()?;
+        ethertype = header.u16().ok()?;
+    }
+    ip_source(ethertype, header)
+}
+
+/// Source address of an IPv4 or IPv6 packet
+fn ip_source(ethertype: u16, header: &mut Reader) -> Option<IpAddr> {
+    match ethertype {
+        0x0800 => {
+            header.skip(12).ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(header.u32().ok()?)))
+        }
+        0x86dd => {
+            header.skip(8).ok()?;
+            let source: [u8; 16] = header.bytes(16).ok()?.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(source)))
+        }
+        _ => None,
+    }
+}
+
+/// Sampling rate to scale an exporter's records by
+///
+/// A configured rate for the exporter wins over the reported one, which is
+/// capped at `max_sampling_rate`, and the default applies when neither is
+/// known.
+fn sampling_rate(config: &FlowCollectorConfig, exporter: IpAddr, reported: Option<u32>) -> u64 {
+    config
+        .sampling_rates
+        .get(&exporter.to_string())
+        .copied()
+        .or(reported.map(|rate| rate.min(config.max_sampling_rate)))
+        .unwrap_or(config.default_sampling_rate)
+        .max(1) as u64
+}
+
+/// Packets and bytes per source since the last flush
+type FlowTotals = HashMap<String, (u64, u64)>;
+
+/// Collector of network flows
+pub struct FlowCollector {
+    /// Flow collector configuration
+    config: FlowCollectorConfig,
+    /// Detector the aggregated flows are fed to
+    detector: Arc<DdosDetector>,
+    /// Networks of the exporters datagrams are accepted from
+    exporters: Vec<ipnet::IpNet>,
+    /// Traffic per source since the last flush
+    totals: Arc<Mutex<FlowTotals>>,
+}
+
+impl FlowCollector {
+    /// Create a new flow collector
+    pub fn new(config: FlowCollectorConfig, detector: Arc<DdosDetector>) -> Self {
+        let exporters = config.exporters.iter().filter_map(|exporter| parse_network(exporter)).collect();
+        Self {
+            config,
+            detector,
+            exporters,
+            totals: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Listen for flows and feed them to the detector until the task is aborted
+    pub async fn start(&self) {
+        if !self.config.enabled {
+            return;
+        }
+        if self.exporters.is_empty() {
+            log::error!("Flow collector not started: no exporters are configured");
+            return;
+        }
+
+        let mut receivers = Vec::new();
+        for address in &self.config.listen_addresses {
+            match UdpSocket::bind(address).await {
+                Ok(socket) => {
+                    log::info!("Flow collector listening on {}", address);
+                    receivers.push(self.receive(socket));
+                }
+                Err(e) => log::error!("Failed to bind flow collector to {}: {}", address, e),
+            }
+        }
+        if receivers.is_empty() {
+            return;
+        }
+        tokio::join!(futures::future::join_all(receivers), self.flush_loop());
+    }
+
+    /// Receive datagrams from a socket and add their flows to the totals
+    async fn receive(&self, socket: UdpSocket) {
+        let mut parser = FlowParser::new();
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    log::error!("Failed to receive flow datagram: {}", e);
+                    continue;
+                }
+            };
+            let exporter = exporter_address(from);
+            if !self.accepts(exporter) {
+                metrics::increment_counter!("flow_collector_rejected_datagrams_total");
+                log::debug!("Dropped flow datagram from unknown exporter {}", exporter);
+                continue;
+            }
+            match parser.parse(exporter, &buffer[..len]) {
+                Ok(records) => {
+                    metrics::increment_counter!("flow_collector_datagrams_total");
+                    self.add(exporter, &records);
+                }
+                Err(e) => {
+                    metrics::increment_counter!("flow_collector_parse_errors_total");
+                    log::debug!("Invalid flow datagram from {}: {}", exporter, e);
+                }
+            }
+        }
+    }
+
+    /// Whether datagrams from an address are accepted
+    fn accepts(&self, exporter: IpAddr) -> bool {
+        self.exporters.iter().any(|network| network.contains(&exporter))
+    }
+
+    fn add(&self, exporter: IpAddr, records: &[FlowRecord]) {
+        let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        for record in records {
+            let rate = sampling_rate(&self.config, exporter, record.sampling_rate);
+            let source = record.source.to_string();
+            if !totals.contains_key(&source) && totals.len() >= self.config.max_sources {
+                metrics::increment_counter!("flow_collector_dropped_records_total");
+                continue;
+            }
+            let (packets, bytes) = totals.entry(source).or_default();
+            *packets = packets.saturating_add(record.packets.saturating_mul(rate));
+            *bytes = bytes.saturating_add(record.bytes.saturating_mul(rate));
+        }
+    }
+
+    async fn flush_loop(&self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.flush_interval_seconds.max(1)));
+        loop {
+            interval.tick().await;
+            self.flush().await;
+        }
+    }
+
+    /// Feed the traffic per source since the last flush to the detector
+    #[tracing::instrument(name = "flow_collector.flush", skip_all)]
+    async fn flush(&self) {
+        let totals = std::mem::take(&mut *self.totals.lock().unwrap_or_else(|e| e.into_inner()));
+        metrics::gauge!("flow_collector_sources", totals.len() as f64);
+        for (source, (packets, bytes)) in totals {
+            metrics::counter!("flow_collector_packets_total", packets);
+            metrics::counter!("flow_collector_bytes_total", bytes);
+            let result = self.detector.check_flow(&source, packets, bytes).await;
+            match result {
+                Ok(Some(classification)) => {
+                    log::warn!("Volumetric attack from {} in flows: {}", source, classification.vector)
+                }
+                Ok(None) => (),
+                Err(e) => log::error!("Failed to check flows from {}: {}", source, e),
+            }
+        }
+    }
+}
+
+/// Exporter address, with IPv4-mapped IPv6 addresses unmapped
+fn exporter_address(from: SocketAddr) -> IpAddr {
+    match from.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORTER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    fn netflow_v5(sampling_interval: u16, flows: &[([u8; 4], u32, u32)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(5u16.to_be_bytes());
+        data.extend((flows.len() as u16).to_be_bytes());
+        data.extend([0; 18]);
+        data.extend(sampling_interval.to_be_bytes());
+        for (source, packets, bytes) in flows {
+            let mut record = [0u8; 48];
+            record[..4].copy_from_slice(source);
+            record[16..20].copy_from_slice(&packets.to_be_bytes());
+            record[20..24].copy_from_slice(&bytes.to_be_bytes());
+            data.extend(record);
+        }
+        data
+    }
+
+    fn set(id: u16, body: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(id.to_be_bytes());
+        data.extend((body.len() as u16 + 4).to_be_bytes());
+        data.extend(body);
+        data
+    }
+
+    fn fields(fields: &[(u16, u16)]) -> Vec<u8> {
+        fields.iter().flat_map(|(id, len)| [id.to_be_bytes(), len.to_be_bytes()].concat()).collect()
+    }
+
+    #[test]
+    fn test_parse_netflow_v5() {
+        let data = netflow_v5(0x4000 | 100, &[([192, 0, 2, 1], 10, 1500), ([192, 0, 2, 2], 1, 40)]);
+        let records = FlowParser::new().parse(EXPORTER, &data).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                FlowRecord { source: "192.0.2.1".parse().unwrap(), packets: 10, bytes: 1500, sampling_rate: Some(100) },
+                FlowRecord { source: "192.0.2.2".parse().unwrap(), packets: 1, bytes: 40, sampling_rate: Some(100) },
+            ]
+        );
+
+        assert_eq!(FlowParser::new().parse(EXPORTER, &data[..50]), Err(FlowParseError::Truncated));
+    }
+
+    #[test]
+    fn test_parse_netflow_v9() {
+        let mut template = 256u16.to_be_bytes().to_vec();
+        template.extend(3u16.to_be_bytes());
+        template.extend(fields(&[(FIELD_IPV4_SOURCE, 4), (FIELD_PACKETS, 4), (FIELD_BYTES, 8)]));
+        let mut options = 257u16.to_be_bytes().to_vec();
+        options.extend(4u16.to_be_bytes());
+        options.extend(4u16.to_be_bytes());
+        options.extend(fields(&[(1, 2), (FIELD_SAMPLING_INTERVAL, 2)]));
+        let mut record = vec![198, 51, 100, 7];
+        record.extend(5u32.to_be_bytes());
+        record.extend(700u64.to_be_bytes());
+        // Padding to four bytes
+        record.extend([0; 4]);
+
+        let mut parser = FlowParser::new();
+        let header = |source_id: u32| {
+            let mut data = vec![0, 9];
+            data.extend([0; 14]);
+            data.extend(source_id.to_be_bytes());
+            data
+        };
+
+        // Data before its template is skipped
+        let mut data = header(1);
+        data.extend(set(256, &record));
+        assert!(parser.parse(EXPORTER, &data).unwrap().is_empty());
+
+        let mut data = header(1);
+        data.extend(set(0, &template));
+        data.extend(set(1, &options));
+        data.extend(set(257, &[0, 0, 0, 64]));
+        data.extend(set(256, &record));
+        let records = parser.parse(EXPORTER, &data).unwrap();
+        assert_eq!(
+            records,
+            vec![FlowRecord { source: "198.51.100.7".parse().unwrap(), packets: 5, bytes: 700, sampling_rate: Some(64) }]
+        );
+
+        // Templates are scoped to the source id
+        let mut data = header(2);
+        data.extend(set(256, &record));
+        assert!(parser.parse(EXPORTER, &data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_ipfix() {
+        let mut template = 300u16.to_be_bytes().to_vec();
+        template.extend(4u16.to_be_bytes());
+        template.extend(fields(&[(FIELD_IPV6_SOURCE, 16), (FIELD_PACKETS, 2)]));
+        // Enterprise-specific variable-length field
+        template.extend(fields(&[(0x8000 | 1, u16::MAX)]));
+        template.extend(9u32.to_be_bytes());
+        template.extend(fields(&[(FIELD_BYTES, 4)]));
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut record = source.octets().to_vec();
+        record.extend(3u16.to_be_bytes());
+        record.extend([2, 0xaa, 0xbb]);
+        record.extend(180u32.to_be_bytes());
+
+        let mut sets = set(2, &template);
+        sets.extend(set(300, &record));
+        let mut data = vec![0, 10];
+        data.extend((sets.len() as u16 + 16).to_be_bytes());
+        data.extend([0; 8]);
+        data.extend(7u32.to_be_bytes());
+        data.extend(sets);
+
+        let records = FlowParser::new().parse(EXPORTER, &data).unwrap();
+        assert_eq!(
+            records,
+            vec![FlowRecord { source: IpAddr::V6(source), packets: 3, bytes: 180, sampling_rate: None }]
+        );
+    }
+
+    #[test]
+    fn test_parse_sflow() {
+        // Ethernet frame with a VLAN tag carrying IPv4 from 203.0.113.9
+        let mut frame = vec![0; 12];
+        frame.extend([0x81, 0x00, 0, 1, 0x08, 0x00]);
+        let mut ip_header = vec![0x45; 1];
+        ip_header.extend([0; 11]);
+        ip_header.extend([203, 0, 113, 9]);
+        ip_header.extend([192, 0, 2, 1]);
+        frame.extend(ip_header);
+
+        let mut raw = Vec::new();
+        raw.extend(1u32.to_be_bytes());
+        raw.extend(1514u32.to_be_bytes());
+        raw.extend(0u32.to_be_bytes());
+        raw.extend((frame.len() as u32).to_be_bytes());
+        raw.extend(&frame);
+        let mut sampled_ipv4 = 60u32.to_be_bytes().to_vec();
+        sampled_ipv4.extend(6u32.to_be_bytes());
+        sampled_ipv4.extend([203, 0, 113, 10]);
+        sampled_ipv4.extend([192, 0, 2, 1]);
+
+        let mut sample = [0u32, 0, 512, 0, 0, 0, 0, 2].iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<u8>>();
+        for (format, record) in [(1u32, &raw), (3, &sampled_ipv4)] {
+            sample.extend(format.to_be_bytes());
+            sample.extend((record.len() as u32).to_be_bytes());
+            sample.extend(record);
+        }
+
+        let mut data = [5u32, 1].iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<u8>>();
+        data.extend([10, 0, 0, 1]);
+        data.extend([0u32, 0, 0, 2].iter().flat_map(|v| v.to_be_bytes()));
+        // A counter sample, which is skipped
+        data.extend(2u32.to_be_bytes());
+        data.extend(4u32.to_be_bytes());
+        data.extend([0; 4]);
+        data.extend(1u32.to_be_bytes());
+        data.extend((sample.len() as u32).to_be_bytes());
+        data.extend(sample);
+
+        let records = FlowParser::new().parse(EXPORTER, &data).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                FlowRecord { source: "203.0.113.9".parse().unwrap(), packets: 1, bytes: 1514, sampling_rate: Some(512) },
+                FlowRecord { source: "203.0.113.10".parse().unwrap(), packets: 1, bytes: 60, sampling_rate: Some(512) },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_unsupported_version() {
+        assert_eq!(FlowParser::new().parse(EXPORTER, &[0, 7, 0, 0]), Err(FlowParseError::UnsupportedVersion(7)));
+    }
+
+    #[test]
+    fn test_sampling_rate() {
+        let mut config = FlowCollectorConfig { default_sampling_rate: 10, ..Default::default() };
+        assert_eq!(sampling_rate(&config, EXPORTER, None), 10);
+        assert_eq!(sampling_rate(&config, EXPORTER, Some(100)), 100);
+
+        config.sampling_rates.insert("10.0.0.1".to_string(), 1000);
+        assert_eq!(sampling_rate(&config, EXPORTER, Some(100)), 1000);
+
+        config.default_sampling_rate = 0;
+        assert_eq!(sampling_rate(&config, "10.0.0.2".parse().unwrap(), None), 1);
+
+        // Reported rates are capped, configured ones aren't
+        config.max_sampling_rate = 4096;
+        assert_eq!(sampling_rate(&config, "10.0.0.2".parse().unwrap(), Some(u32::MAX)), 4096);
+        config.sampling_rates.insert("10.0.0.1".to_string(), 8192);
+        assert_eq!(sampling_rate(&config, EXPORTER, Some(100)), 8192);
+    }
+
+    #[test]
+    fn test_template_limit() {
+        let mut parser = FlowParser::new();
+        for template_id in 0..MAX_TEMPLATES_PER_EXPORTER as u16 + 10 {
+            parser.insert_template((EXPORTER, 0, 256 + template_id), Vec::new());
+        }
+        assert_eq!(parser.templates.len(), MAX_TEMPLATES_PER_EXPORTER);
+        // Known templates can still be replaced, and other exporters have their own allowance
+        parser.insert_template((EXPORTER, 0, 256), Vec::new());
+        parser.insert_template(("10.0.0.2".parse().unwrap(), 0, 256), Vec::new());
+        assert_eq!(parser.templates.len(), MAX_TEMPLATES_PER_EXPORTER + 1);
+    }
+}
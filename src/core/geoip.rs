@@ -0,0 +1,527 @@
+//! GeoIP lookups for the DDoS protection service.
+//!
+//! This module resolves client IPs to countries and autonomous systems using
+//! MaxMind DB files such as GeoLite2-Country and GeoLite2-ASN. The reader
+//! implements the parts of the MaxMind DB format those databases use, and
+//! databases are reloaded whenever the files change on disk.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::models::GeoIpConfig;
+use crate::utils::{normalize_ip, parse_network};
+
+/// Marker preceding the metadata section at the end of a database
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// The metadata section lies within this many bytes of the end of a database
+const METADATA_MAX_SIZE: usize = 128 * 1024;
+
+/// Size of the zeroed separator between the search tree and the data section
+const DATA_SECTION_SEPARATOR: usize = 16;
+
+/// Deepest nesting of maps and arrays decoded, so a corrupt database can't
+/// exhaust the stack
+const MAX_DATA_DEPTH: usize = 64;
+
+/// Errors that can occur during GeoIP operations
+#[derive(Error, Debug)]
+pub enum GeoIpError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid database: {0}")]
+    InvalidDatabase(String),
+}
+
+fn invalid(message: &str) -> GeoIpError {
+    GeoIpError::InvalidDatabase(message.to_string())
+}
+
+/// Value stored in a database's data section
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Uint(u128),
+    Int(i32),
+    Map(HashMap<String, Value>),
+    Array(Vec<Value>),
+    Bool(bool),
+    Float(f32),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// Big-endian unsigned integer from up to 16 bytes
+fn be_uint(bytes: &[u8]) -> u128 {
+    bytes.iter().fold(0, |acc, byte| (acc << 8) | *byte as u128)
+}
+
+/// Decoder for the data and metadata sections
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], GeoIpError> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.buf.get(offset..end))
+            .ok_or_else(|| invalid("data section truncated"))
+    }
+
+    /// Decode the value at `offset`, returning it with the offset following it
+    fn decode(&self, offset: usize) -> Result<(Value, usize), GeoIpError> {
+        self.decode_nested(offset, 0)
+    }
+
+    /// Decode a value inside `depth` maps and arrays
+    ///
+    /// Pointers must point within the data section and not to another
+    /// pointer, as the format requires, so they can't loop.
+    fn decode_nested(&self, offset: usize, depth: usize) -> Result<(Value, usize), GeoIpError> {
+        if depth > MAX_DATA_DEPTH {
+            return Err(invalid("data nested too deeply"));
+        }
+        let ctrl = self.bytes(offset, 1)?[0];
+        let mut offset = offset + 1;
+        let mut kind = ctrl >> 5;
+
+        if kind == 1 {
+            let len = ((ctrl >> 3) & 0x3) as usize + 1;
+            let value = be_uint(self.bytes(offset, len)?) as usize;
+            let high = (ctrl & 0x7) as usize;
+            let pointer = match len {
+                1 => (high << 8) | value,
+                2 => ((high << 16) | value) + 2048,
+                3 => ((high << 24) | value) + 526_336,
+                _ => value,
+            };
+            let target = self
+                .bytes(pointer, 1)
+                .map_err(|_| invalid("pointer outside the data section"))?;
+            if target[0] >> 5 == 1 {
+                return Err(invalid("pointer to a pointer"));
+            }
+            let (value, _) = self.decode_nested(pointer, depth)?;
+            return Ok((value, offset + len));
+        }
+
+        if kind == 0 {
+            kind = 7 + self.bytes(offset, 1)?[0];
+            offset += 1;
+        }
+
+        let mut size = (ctrl & 0x1f) as usize;
+        if size >= 29 {
+            let len = size - 28;
+            let extra = be_uint(self.bytes(offset, len)?) as usize;
+            offset += len;
+            size = match len {
+                1 => 29 + extra,
+                2 => 285 + extra,
+                _ => 65_821 + extra,
+            };
+        }
+
+        match kind {
+            2 => {
+                let value = String::from_utf8(self.bytes(offset, size)?.to_vec())
+                    .map_err(|_| invalid("string is not UTF-8"))?;
+                Ok((Value::String(value), offset + size))
+            }
+            3 => {
+                let bytes = self.bytes(offset, 8)?;
+                Ok((Value::Double(f64::from_bits(be_uint(bytes) as u64)), offset + 8))
+            }
+            4 => Ok((Value::Bytes(self.bytes(offset, size)?.to_vec()), offset + size)),
+            5 | 6 | 9 | 10 => Ok((Value::Uint(be_uint(self.bytes(offset, size.min(16))?)), offset + size)),
+            7 => {
+                // Each entry takes at least two bytes, so a bogus size can't reserve much
+                let mut map = HashMap::with_capacity(size.min(self.buf.len() / 2));
+                for _ in 0..size {
+                    let (key, next) = self.decode_nested(offset, depth + 1)?;
+                    let key = match key {
+                        Value::String(key) => key,
+                        _ => return Err(invalid("map key is not a string")),
+                    };
+                    let (value, next) = self.decode_nested(next, depth + 1)?;
+                    map.insert(key, value);
+                    offset = next;
+                }
+                Ok((Value::Map(map), offset))
+            }
+            8 => Ok((Value::Int(be_uint(self.bytes(offset, size.min(4))?) as u32 as i32), offset + size)),
+            11 => {
+                let mut array = Vec::with_capacity(size.min(self.buf.len()));
+                for _ in 0..size {
+                    let (value, next) = self.decode_nested(offset, depth + 1)?;
+                    array.push(value);
+                    offset = next;
+                }
+                Ok((Value::Array(array), offset))
+            }
+            14 => Ok((Value::Bool(size != 0), offset)),
+            15 => {
+                let bytes = self.bytes(offset, 4)?;
+                Ok((Value::Float(f32::from_bits(be_uint(bytes) as u32)), offset + 4))
+            }
+            _ => Err(GeoIpError::InvalidDatabase(format!("unsupported data type {}", kind))),
+        }
+    }
+}
+
+/// Parsed MaxMind database
+struct Database {
+    data: Vec<u8>,
+    node_count: u32,
+    record_size: u16,
+    ip_version: u16,
+    /// End of the search tree, in bytes
+    tree_size: usize,
+    /// Start of the metadata marker, which ends the data section
+    metadata_start: usize,
+    /// Node at which IPv4 lookups start in an IPv6 tree
+    ipv4_start: u32,
+}
+
+impl Database {
+    fn from_bytes(data: Vec<u8>) -> Result<Self, GeoIpError> {
+        let search_start = data.len().saturating_sub(METADATA_MAX_SIZE);
+        let metadata_start = data[search_start..]
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .map(|position| search_start + position)
+            .ok_or_else(|| invalid("metadata not found"))?;
+
+        let decoder = Decoder { buf: &data[metadata_start + METADATA_MARKER.len()..] };
+        let (metadata, _) = decoder.decode(0)?;
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_uint)
+                .ok_or_else(|| GeoIpError::InvalidDatabase(format!("metadata field {} missing", name)))
+        };
+        let node_count = field("node_count")? as u32;
+        let record_size = field("record_size")? as u16;
+        let ip_version = field("ip_version")? as u16;
+
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(GeoIpError::InvalidDatabase(format!("unsupported record size {}", record_size)));
+        }
+        let tree_size = node_count as usize * record_size as usize / 4;
+        if tree_size + DATA_SECTION_SEPARATOR > metadata_start {
+            return Err(invalid("search tree overlaps metadata"));
+        }
+
+        let mut database = Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            tree_size,
+            metadata_start,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            // IPv4 addresses live under ::/96
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.read_record(node, 0)?;
+            }
+            database.ipv4_start = node;
+        }
+
+        Ok(database)
+    }
+
+    fn read_record(&self, node: u32, bit: u8) -> Result<u32, GeoIpError> {
+        let node_size = self.record_size as usize / 4;
+        let start = node as usize * node_size;
+        let bytes = self
+            .data
+            .get(start..start + node_size)
+            .ok_or_else(|| invalid("search tree truncated"))?;
+
+        let record = match (self.record_size, bit) {
+            (24, 0) => be_uint(&bytes[0..3]),
+            (24, _) => be_uint(&bytes[3..6]),
+            (28, 0) => ((bytes[3] as u128 & 0xf0) << 20) | be_uint(&bytes[0..3]),
+            (28, _) => ((bytes[3] as u128 & 0x0f) << 24) | be_uint(&bytes[4..7]),
+            (_, 0) => be_uint(&bytes[0..4]),
+            (_, _) => be_uint(&bytes[4..8]),
+        };
+        Ok(record as u32)
+    }
+
+    /// Look up the record for an address, if the database has one
+    fn lookup(&self, addr: IpAddr) -> Result<Option<Value>, GeoIpError> {
+        let (bits, width, mut node) = match addr {
+            IpAddr::V4(addr) if self.ip_version == 6 => (u32::from(addr) as u128, 32, self.ipv4_start),
+            IpAddr::V4(addr) => (u32::from(addr) as u128, 32, 0),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(addr) => (u128::from(addr), 128, 0),
+        };
+
+        for index in 0..width {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.read_record(node, ((bits >> (width - 1 - index)) & 1) as u8)?;
+        }
+
+        if node == self.node_count {
+            return Ok(None);
+        }
+        let offset = (node as usize)
+            .checked_sub(self.node_count as usize + DATA_SECTION_SEPARATOR)
+            .ok_or_else(|| invalid("invalid data pointer"))?;
+
+        let data_start = self.tree_size + DATA_SECTION_SEPARATOR;
+        let decoder = Decoder { buf: &self.data[data_start..self.metadata_start] };
+        Ok(Some(decoder.decode(offset)?.0))
+    }
+}
+
+/// Location of a client IP
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 country code
+    pub country: Option<String>,
+    /// Autonomous system number
+    pub asn: Option<u32>,
+    /// Autonomous system organization
+    pub as_org: Option<String>,
+}
+
+/// Database loaded from disk, with the modification time it was loaded at
+struct LoadedDatabase {
+    modified: Option<SystemTime>,
+    database: Database,
+}
+
+/// Shared GeoIP resolver
+///
+/// Cloning is cheap and all clones see the same databases.
+#[derive(Clone)]
+pub struct GeoIp {
+    /// GeoIP configuration
+    config: GeoIpConfig,
+    /// Country database
+    country: Arc<RwLock<Option<LoadedDatabase>>>,
+    /// ASN database
+    asn: Arc<RwLock<Option<LoadedDatabase>>>,
+}
+
+impl GeoIp {
+    /// Create a new resolver; databases are only available after the first `reload`
+    pub fn new(config: GeoIpConfig) -> Self {
+        Self {
+            config,
+            country: Arc::new(RwLock::new(None)),
+            asn: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Load the configured databases that changed since they were last loaded
+    #[tracing::instrument(name = "geoip.reload", skip_all)]
+    pub async fn reload(&self) -> Result<(), GeoIpError> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        for (path, slot) in [
+            (&self.config.country_database, &self.country),
+            (&self.config.asn_database, &self.asn),
+        ] {
+            let path = match path {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let modified = tokio::fs::metadata(path).await?.modified().ok();
+            if let Some(loaded) = &*slot.read().await {
+                if modified.is_some() && loaded.modified == modified {
+                    continue;
+                }
+            }
+
+            let data = tokio::fs::read(path).await?;
+            let database = tokio::task::spawn_blocking(move || Database::from_bytes(data))
+                .await
+                .map_err(|e| GeoIpError::InvalidDatabase(e.to_string()))??;
+            *slot.write().await = Some(LoadedDatabase { modified, database });
+            log::info!("Loaded GeoIP database {}", path);
+        }
+
+        Ok(())
+    }
+
+    /// Look up the location of an IP or of the network of a client key
+    pub async fn lookup(&self, ip: &str) -> GeoInfo {
+        let mut info = GeoInfo::default();
+        let addr = match normalize_ip(ip).or_else(|| parse_network(ip).map(|net| net.network())) {
+            Some(addr) => addr,
+            None => return info,
+        };
+
+        if let Some(record) = lookup_record(&self.country, addr).await {
+            info.country = record
+                .get("country")
+                .or_else(|| record.get("registered_country"))
+                .and_then(|country| country.get("iso_code"))
+                .and_then(Value::as_str)
+                .map(String::from);
+        }
+        if let Some(record) = lookup_record(&self.asn, addr).await {
+            info.asn = record
+                .get("autonomous_system_number")
+                .and_then(Value::as_uint)
+                .map(|asn| asn as u32);
+            info.as_org = record
+                .get("autonomous_system_organization")
+                .and_then(Value::as_str)
+                .map(String::from);
+        }
+
+        info
+    }
+
+    /// Whether lookups are enabled
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Paths of the configured databases that haven't been loaded
+    pub async fn missing_databases(&self) -> Vec<String> {
+        let mut missing = Vec::new();
+        for (path, slot) in [
+            (&self.config.country_database, &self.country),
+            (&self.config.asn_database, &self.asn),
+        ] {
+            if let (Some(path), None) = (path, &*slot.read().await) {
+                missing.push(path.clone());
+            }
+        }
+        missing
+    }
+
+    /// Periodically reload databases that changed on disk
+    pub async fn start_refresh(&self) -> Result<(), GeoIpError> {
+        let interval = Duration::from_secs(self.config.reload_interval_seconds.max(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.reload().await {
+                log::error!("Failed to reload GeoIP databases: {}", e);
+            }
+        }
+    }
+}
+
+async fn lookup_record(slot: &RwLock<Option<LoadedDatabase>>, addr: IpAddr) -> Option<Value> {
+    let loaded = slot.read().await;
+    match loaded.as_ref()?.database.lookup(addr) {
+        Ok(record) => record,
+        Err(e) => {
+            log::warn!("GeoIP lookup for {} failed: {}", addr, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a string in the data section format
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = vec![0x40 | value.len() as u8];
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_database_lookup() {
+        // One node: addresses starting with a 0 bit map to the record at offset 0
+        let mut data = vec![0, 0, 17, 0, 0, 1];
+        data.extend_from_slice(&[0; DATA_SECTION_SEPARATOR]);
+
+        // {"country": {"iso_code": "NL"}}
+        data.push(0xe1);
+        data.extend(string("country"));
+        data.push(0xe1);
+        data.extend(string("iso_code"));
+        data.extend(string("NL"));
+
+        // {"node_count": 1, "record_size": 24, "ip_version": 4}
+        data.extend_from_slice(METADATA_MARKER);
+        data.push(0xe3);
+        data.extend(string("node_count"));
+        data.extend_from_slice(&[0xc1, 1]);
+        data.extend(string("record_size"));
+        data.extend_from_slice(&[0xa1, 24]);
+        data.extend(string("ip_version"));
+        data.extend_from_slice(&[0xa1, 4]);
+
+        let database = Database::from_bytes(data).unwrap();
+        let record = database.lookup("10.0.0.1".parse().unwrap()).unwrap().unwrap();
+        let country = record.get("country").and_then(|c| c.get("iso_code"));
+        assert_eq!(country.and_then(Value::as_str), Some("NL"));
+
+        assert_eq!(database.lookup("192.0.2.1".parse().unwrap()).unwrap(), None);
+        assert_eq!(database.lookup("2001:db8::1".parse().unwrap()).unwrap(), None);
+        assert!(Database::from_bytes(vec![0; 64]).is_err());
+    }
+
+    #[test]
+    fn test_decoder_rejects_malformed_data() {
+        let decode = |buf: &[u8]| Decoder { buf }.decode(0).map(|(value, _)| value);
+
+        // A pointer to "NL" is followed, a pointer to a pointer is not
+        let mut buf = string("NL");
+        buf.extend_from_slice(&[0x20, 0, 0x20, 3]);
+        assert_eq!(Decoder { buf: &buf }.decode(3).unwrap().0.as_str(), Some("NL"));
+        assert!(Decoder { buf: &buf }.decode(5).is_err());
+        assert!(decode(&[0x20, 0]).is_err());
+
+        // A pointer past the end of the data section
+        assert!(decode(&[0x20, 0xff]).is_err());
+
+        // Arrays of one element nested past the depth limit
+        let mut nested = [0x01, 0x04].repeat(MAX_DATA_DEPTH + 2);
+        nested.extend(string("NL"));
+        assert!(decode(&nested).is_err());
+        let mut nested = [0x01, 0x04].repeat(MAX_DATA_DEPTH);
+        nested.extend(string("NL"));
+        assert!(decode(&nested).is_ok());
+    }
+}
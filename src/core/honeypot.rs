@@ -0,0 +1,83 @@
+//! Honeypot paths for the DDoS protection service.
+//!
+//! This module recognises requests for honeypot paths, which legitimate
+//! clients never request, and blocks or penalizes the clients making them.
+
+use std::time::Duration;
+use crate::core::{Blocklist, Reputation};
+use crate::core::reputation::ReputationEvent;
+use crate::models::HoneypotConfig;
+
+/// Honeypot path matcher acting on the clients that request them
+#[derive(Clone)]
+pub struct Honeypot {
+    /// Honeypot configuration, with paths lowercased
+    config: HoneypotConfig,
+    /// Blocklist trapped clients are added to
+    blocklist: Blocklist,
+    /// Reputation trapped clients are penalized in
+    reputation: Reputation,
+}
+
+impl Honeypot {
+    /// Create a new honeypot
+    pub fn new(mut config: HoneypotConfig, blocklist: Blocklist, reputation: Reputation) -> Self {
+        for path in &mut config.paths {
+            *path = path.to_ascii_lowercase();
+        }
+        Self { config, blocklist, reputation }
+    }
+
+    /// Honeypot path entry matching the requested path, if any
+    pub fn trap(&self, path: &str) -> Option<&str> {
+        if !self.config.enabled {
+            return None;
+        }
+        find_trap(&self.config.paths, path)
+    }
+
+    /// Act on a client that requested a honeypot path, returning whether it was blocked
+    pub async fn spring(&self, ip: &str, trap: &str) -> bool {
+        metrics::increment_counter!("honeypot_hits_total", "trap" => trap.to_string());
+        self.reputation.record(ip, ReputationEvent::HoneypotHit).await;
+        if !self.config.block {
+            return false;
+        }
+        let reason = format!("Requested honeypot path {}", trap);
+        let duration = Some(Duration::from_secs(self.config.block_seconds));
+        match self.blocklist.block(ip, &reason, "honeypot", duration).await {
+            Ok(_) => true,
+            Err(e) => {
+                log::error!("Failed to block {} after honeypot hit: {}", ip, e);
+                false
+            }
+        }
+    }
+}
+
+/// Lowercased honeypot path entry matching `path`, ignoring its query string
+fn find_trap<'a>(traps: &'a [String], path: &str) -> Option<&'a str> {
+    let path = path.split(['?', '#']).next().unwrap_or_default().to_ascii_lowercase();
+    traps
+        .iter()
+        .find(|trap| match trap.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == **trap,
+        })
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_trap() {
+        let traps = vec!["/wp-login.php".to_string(), "/.git/*".to_string()];
+        assert_eq!(find_trap(&traps, "/wp-login.php"), Some("/wp-login.php"));
+        assert_eq!(find_trap(&traps, "/WP-Login.php?redirect_to=/"), Some("/wp-login.php"));
+        assert_eq!(find_trap(&traps, "/.git/config"), Some("/.git/*"));
+        assert_eq!(find_trap(&traps, "/wp-login.php.bak"), None);
+        assert_eq!(find_trap(&traps, "/"), None);
+    }
+}
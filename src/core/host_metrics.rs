@@ -0,0 +1,217 @@
+//! Host and process metrics for the DDoS protection service.
+//!
+//! Counters are read straight from procfs: CPU time from `/proc/stat`,
+//! memory from `/proc/meminfo`, network traffic from `/proc/net/dev`, and the
+//! service's own CPU time and resident memory from `/proc/self`. Disk usage
+//! comes from `statvfs`. CPU usage is the share of time spent busy between
+//! two samples, so the first sample reports none. On systems without procfs
+//! the readings are left at zero.
+
+use std::ffi::CString;
+use std::sync::Mutex;
+
+/// Filesystem disk usage is reported for
+const DISK_PATH: &str = "/";
+
+/// Cumulative CPU time, in clock ticks
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CpuTimes {
+    /// Time spent on anything but idling and waiting for IO
+    pub busy: u64,
+    /// All time accounted for
+    pub total: u64,
+}
+
+/// One reading of the host and process counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostStats {
+    /// Host CPU usage since the previous sample (percentage)
+    pub cpu_usage: f64,
+    /// Host memory in use (percentage)
+    pub memory_usage: f64,
+    /// Disk usage of the root filesystem (percentage)
+    pub disk_usage: f64,
+    /// Bytes received on all interfaces but loopback
+    pub network_in: u64,
+    /// Bytes sent on all interfaces but loopback
+    pub network_out: u64,
+    /// Share of host CPU time used by this process since the previous sample (percentage)
+    pub process_cpu_usage: f64,
+    /// Resident memory of this process (bytes)
+    pub process_memory_bytes: u64,
+}
+
+/// Aggregate CPU times from the first line of `/proc/stat`
+pub fn parse_cpu_times(stat: &str) -> Option<CpuTimes> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    // user nice system idle iowait irq softirq steal; guest time is already in user
+    let fields: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .take(8)
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    if fields.len() < 4 {
+        return None;
+    }
+    let total: u64 = fields.iter().sum();
+    let idle = fields[3] + fields.get(4).copied().unwrap_or(0);
+    Some(CpuTimes {
+        busy: total - idle,
+        total,
+    })
+}
+
+/// Memory in use as a percentage, from `/proc/meminfo`
+pub fn parse_memory_usage(meminfo: &str) -> Option<f64> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.trim_start_matches(':').split_whitespace().next())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    let total = field("MemTotal")?;
+    let available = field("MemAvailable")?;
+    if total == 0 {
+        return None;
+    }
+    Some(total.saturating_sub(available) as f64 / total as f64 * 100.0)
+}
+
+/// Bytes received and sent on all interfaces but loopback, from `/proc/net/dev`
+pub fn parse_network_bytes(net_dev: &str) -> (u64, u64) {
+    net_dev
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(interface, _)| interface.trim() != "lo")
+        .fold((0, 0), |(received, sent), (_, counters)| {
+            let counters: Vec<u64> = counters.split_whitespace().filter_map(|value| value.parse().ok()).collect();
+            match (counters.first(), counters.get(8)) {
+                (Some(rx), Some(tx)) => (received + rx, sent + tx),
+                _ => (received, sent),
+            }
+        })
+}
+
+/// User and system CPU time of a process, from its `/proc/<pid>/stat`
+pub fn parse_process_cpu(stat: &str) -> Option<u64> {
+    // The command name may contain spaces, so fields are counted after it
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // utime and stime are the 14th and 15th fields; state is the 3rd
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Resident memory of a process in bytes, from its `/proc/<pid>/status`
+pub fn parse_process_memory(status: &str) -> Option<u64> {
+    let kilobytes: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Percentage of a filesystem in use, as `df` reports it
+fn disk_usage(path: &str) -> Option<f64> {
+    let path = CString::new(path).ok()?;
+    // SAFETY: statvfs is plain data, and the call only writes to it and
+    // reads the NUL-terminated path
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    let used = (stats.f_blocks - stats.f_bfree) as f64;
+    // Blocks reserved for root don't count as available
+    let usable = used + stats.f_bavail as f64;
+    if usable == 0.0 {
+        return None;
+    }
+    Some(used / usable * 100.0)
+}
+
+fn read(path: &str) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+/// Percentage of `total` ticks that `used` ticks make up
+fn share(used: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        used as f64 / total as f64 * 100.0
+    }
+}
+
+/// Samples host and process counters, keeping the previous CPU times for usage
+#[derive(Default)]
+pub struct HostMetrics {
+    previous: Mutex<Option<(CpuTimes, u64)>>,
+}
+
+impl HostMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the current counters
+    pub fn sample(&self) -> HostStats {
+        let cpu = read("/proc/stat").and_then(|stat| parse_cpu_times(&stat)).unwrap_or_default();
+        let process_cpu = read("/proc/self/stat").and_then(|stat| parse_process_cpu(&stat)).unwrap_or(0);
+        let (network_in, network_out) = read("/proc/net/dev").map(|net_dev| parse_network_bytes(&net_dev)).unwrap_or_default();
+
+        let mut stats = HostStats {
+            memory_usage: read("/proc/meminfo").and_then(|meminfo| parse_memory_usage(&meminfo)).unwrap_or(0.0),
+            disk_usage: disk_usage(DISK_PATH).unwrap_or(0.0),
+            network_in,
+            network_out,
+            process_memory_bytes: read("/proc/self/status").and_then(|status| parse_process_memory(&status)).unwrap_or(0),
+            ..HostStats::default()
+        };
+
+        let mut previous = self.previous.lock().unwrap();
+        if let Some((previous_cpu, previous_process_cpu)) = *previous {
+            let elapsed = cpu.total.saturating_sub(previous_cpu.total);
+            stats.cpu_usage = share(cpu.busy.saturating_sub(previous_cpu.busy), elapsed);
+            stats.process_cpu_usage = share(process_cpu.saturating_sub(previous_process_cpu), elapsed);
+        }
+        *previous = Some((cpu, process_cpu));
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host_counters() {
+        let stat = "cpu  100 20 30 800 50 0 0 0 0 0\ncpu0 50 10 15 400 25 0 0 0 0 0\nintr 12345\n";
+        assert_eq!(parse_cpu_times(stat), Some(CpuTimes { busy: 150, total: 1000 }));
+
+        let meminfo = "MemTotal:       16000000 kB\nMemFree:         2000000 kB\nMemAvailable:    4000000 kB\n";
+        assert_eq!(parse_memory_usage(meminfo), Some(75.0));
+
+        let net_dev = "Inter-|   Receive                            |  Transmit\n \
+            face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n    \
+            lo:  5000      50    0    0    0     0          0         0     5000      50    0    0    0     0       0          0\n  \
+            eth0: 12000     100    0    0    0     0          0         0     3000      40    0    0    0     0       0          0\n  \
+            eth1:  1000      10    0    0    0     0          0         0      500       5    0    0    0     0       0          0\n";
+        assert_eq!(parse_network_bytes(net_dev), (13000, 3500));
+    }
+
+    #[test]
+    fn test_parse_process_counters() {
+        let stat = "4242 (ddos (worker) 1) S 1 4242 4242 0 -1 4194560 2000 0 0 0 350 150 0 0 20 0 8 0 100 1000000 2500";
+        assert_eq!(parse_process_cpu(stat), Some(500));
+
+        let status = "Name:\tddos-protection\nVmPeak:\t  200000 kB\nVmRSS:\t   51200 kB\nThreads:\t8\n";
+        assert_eq!(parse_process_memory(status), Some(51200 * 1024));
+        assert_eq!(parse_process_memory("Name:\tkthreadd\n"), None);
+    }
+}
@@ -0,0 +1,520 @@
+//! Kafka output for the DDoS protection service.
+//!
+//! Publishes every analytics event as JSON to a Kafka topic, keyed by the
+//! client IP so that each client's events land on one partition, in order.
+//! The producer speaks the Kafka protocol directly (Metadata v1, Produce v3
+//! with uncompressed v2 record batches) and picks partitions with the same
+//! murmur2 hash as the Java client, so keys map to the partitions consumers
+//! expect.
+//!
+//! An unreachable cluster never affects request handling: failed batches
+//! are logged and counted, metadata and connections are rebuilt on the next
+//! batch, and events queue up to the sink's queue size in the meantime.
+
+use async_trait::async_trait;
+use bytes::BufMut;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use crate::core::analytics::Event;
+use crate::core::event_sink::{EventSink, SinkError};
+use crate::models::KafkaSinkConfig;
+
+const PRODUCE: i16 = 0;
+const METADATA: i16 = 3;
+
+/// CRC-32C (Castagnoli) lookup table, used to checksum record batches
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Brokers and partition leaders of the topic
+struct TopicMetadata {
+    brokers: HashMap<i32, (String, u16)>,
+    /// Partition index and leader broker, by partition index
+    partitions: Vec<(i32, i32)>,
+}
+
+#[derive(Default)]
+struct Producer {
+    correlation_id: i32,
+    metadata: Option<TopicMetadata>,
+    /// Connections to partition leaders, by broker ID
+    connections: HashMap<i32, TcpStream>,
+    /// Partition counter for events without a client IP
+    round_robin: usize,
+}
+
+/// Sink publishing events to a Kafka topic
+pub struct KafkaSink {
+    config: KafkaSinkConfig,
+    producer: Mutex<Producer>,
+}
+
+impl KafkaSink {
+    pub fn new(config: KafkaSinkConfig) -> Self {
+        Self {
+            config,
+            producer: Mutex::new(Producer::default()),
+        }
+    }
+
+    async fn produce(&self, producer: &mut Producer, events: &[Event]) -> Result<(), SinkError> {
+        let metadata = match producer.metadata.take() {
+            Some(metadata) => metadata,
+            None => self.fetch_metadata(producer).await?,
+        };
+
+        let mut by_partition: BTreeMap<usize, Vec<&Event>> = BTreeMap::new();
+        for event in events {
+            let index = match key(event) {
+                Some(key) => partition(key.as_bytes(), metadata.partitions.len()),
+                None => {
+                    producer.round_robin = producer.round_robin.wrapping_add(1);
+                    producer.round_robin % metadata.partitions.len()
+                }
+            };
+            by_partition.entry(index).or_default().push(event);
+        }
+
+        let mut by_leader: BTreeMap<i32, Vec<(i32, Vec<u8>)>> = BTreeMap::new();
+        for (index, events) in by_partition {
+            let (partition, leader) = metadata.partitions[index];
+            by_leader.entry(leader).or_default().push((partition, record_batch(&events)));
+        }
+
+        for (leader, batches) in by_leader {
+            let (host, port) = metadata
+                .brokers
+                .get(&leader)
+                .ok_or_else(|| SinkError::Protocol(format!("unknown broker {}", leader)))?;
+            producer.correlation_id = producer.correlation_id.wrapping_add(1);
+            let stream = match producer.connections.entry(leader) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(TcpStream::connect((host.as_str(), *port)).await?),
+            };
+            let body = self.produce_request(&batches);
+            let response = request(
+                stream,
+                PRODUCE,
+                3,
+                producer.correlation_id,
+                &self.config.client_id,
+                &body,
+                self.config.acks != 0,
+            )
+            .await?;
+            if let Some(response) = response {
+                check_produce_response(&response)?;
+            }
+        }
+
+        producer.metadata = Some(metadata);
+        Ok(())
+    }
+
+    /// Look up the topic's partitions on the first bootstrap broker that answers
+    async fn fetch_metadata(&self, producer: &mut Producer) -> Result<TopicMetadata, SinkError> {
+        let mut body = Vec::new();
+        body.put_i32(1);
+        put_string(&mut body, &self.config.topic);
+
+        let mut last_error = SinkError::Config("no Kafka brokers configured".to_string());
+        for broker in &self.config.brokers {
+            producer.correlation_id = producer.correlation_id.wrapping_add(1);
+            let response = match TcpStream::connect(broker.as_str()).await {
+                Ok(mut stream) => {
+                    request(&mut stream, METADATA, 1, producer.correlation_id, &self.config.client_id, &body, true).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            match response.and_then(|response| parse_metadata(&response.unwrap_or_default(), &self.config.topic)) {
+                Ok(metadata) => return Ok(metadata),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn produce_request(&self, batches: &[(i32, Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        // No transactional ID
+        body.put_i16(-1);
+        body.put_i16(self.config.acks);
+        body.put_i32(i32::try_from(self.config.timeout_ms).unwrap_or(i32::MAX));
+        body.put_i32(1);
+        put_string(&mut body, &self.config.topic);
+        body.put_i32(batches.len() as i32);
+        for (partition, batch) in batches {
+            body.put_i32(*partition);
+            body.put_i32(batch.len() as i32);
+            body.put_slice(batch);
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl EventSink for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    async fn send(&self, events: &[Event]) -> Result<(), SinkError> {
+        let mut producer = self.producer.lock().await;
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let result = match tokio::time::timeout(timeout, self.produce(&mut producer, events)).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Kafka request timed out").into()),
+        };
+        if result.is_err() {
+            // Leaders may have moved; start over on the next batch
+            producer.metadata = None;
+            producer.connections.clear();
+        }
+        result
+    }
+}
+
+/// Send a request and read its response, if one is expected
+async fn request(
+    stream: &mut TcpStream,
+    api_key: i16,
+    api_version: i16,
+    correlation_id: i32,
+    client_id: &str,
+    body: &[u8],
+    expect_response: bool,
+) -> Result<Option<Vec<u8>>, SinkError> {
+    let mut header = Vec::new();
+    header.put_i16(api_key);
+    header.put_i16(api_version);
+    header.put_i32(correlation_id);
+    put_string(&mut header, client_id);
+
+    let mut frame = Vec::with_capacity(4 + header.len() + body.len());
+    frame.put_i32((header.len() + body.len()) as i32);
+    frame.put_slice(&header);
+    frame.put_slice(body);
+    stream.write_all(&frame).await?;
+    if !expect_response {
+        return Ok(None);
+    }
+
+    let size = stream.read_i32().await?;
+    let mut response = vec![0; size.max(0) as usize];
+    stream.read_exact(&mut response).await?;
+    let mut reader = Reader::new(&response);
+    if reader.i32()? != correlation_id {
+        return Err(SinkError::Protocol("response to another request".to_string()));
+    }
+    Ok(Some(reader.data.to_vec()))
+}
+
+fn parse_metadata(data: &[u8], topic: &str) -> Result<TopicMetadata, SinkError> {
+    let mut reader = Reader::new(data);
+    let mut brokers = HashMap::new();
+    for _ in 0..reader.i32()? {
+        let id = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        // Rack
+        reader.string()?;
+        brokers.insert(id, (host, port as u16));
+    }
+    // Controller ID
+    reader.i32()?;
+
+    for _ in 0..reader.i32()? {
+        let error_code = reader.i16()?;
+        let name = reader.string()?;
+        // Whether the topic is internal
+        reader.take(1)?;
+        let mut partitions = Vec::new();
+        for _ in 0..reader.i32()? {
+            let partition_error = reader.i16()?;
+            let index = reader.i32()?;
+            let leader = reader.i32()?;
+            // Replicas and in-sync replicas
+            reader.skip_array(4)?;
+            reader.skip_array(4)?;
+            if partition_error != 0 || leader < 0 {
+                return Err(SinkError::Protocol(format!("partition {} of {} has no leader", index, name)));
+            }
+            partitions.push((index, leader));
+        }
+        if name != topic {
+            continue;
+        }
+        if error_code != 0 {
+            return Err(SinkError::Protocol(format!("topic {}: error code {}", topic, error_code)));
+        }
+        if partitions.is_empty() {
+            return Err(SinkError::Protocol(format!("topic {} has no partitions", topic)));
+        }
+        partitions.sort();
+        return Ok(TopicMetadata { brokers, partitions });
+    }
+    Err(SinkError::Protocol(format!("topic {} not found", topic)))
+}
+
+fn check_produce_response(data: &[u8]) -> Result<(), SinkError> {
+    let mut reader = Reader::new(data);
+    for _ in 0..reader.i32()? {
+        let topic = reader.string()?;
+        for _ in 0..reader.i32()? {
+            let partition = reader.i32()?;
+            let error_code = reader.i16()?;
+            // Base offset and log append time
+            reader.take(16)?;
+            if error_code != 0 {
+                return Err(SinkError::Protocol(format!(
+                    "partition {} of {}: error code {}",
+                    partition, topic, error_code
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Partitioning key of an event, the client IP
+fn key(event: &Event) -> Option<&str> {
+    event.data.get("ip")?.as_str()
+}
+
+/// Partition for a key, as chosen by the Java client's default partitioner
+fn partition(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+/// murmur2 hash as implemented by Kafka
+fn murmur2(data: &[u8]) -> i32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    if tail.len() >= 3 {
+        h ^= u32::from(tail[2]) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= u32::from(tail[1]) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= u32::from(tail[0]);
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Encode events as a v2 record batch
+fn record_batch(events: &[&Event]) -> Vec<u8> {
+    let timestamps = events.iter().map(|event| event.timestamp.timestamp_millis());
+    let first_timestamp = timestamps.clone().min().unwrap_or_default();
+    let max_timestamp = timestamps.max().unwrap_or_default();
+
+    let mut records = Vec::new();
+    for (offset, event) in events.iter().enumerate() {
+        let mut record = Vec::new();
+        // Attributes
+        record.put_i8(0);
+        put_varint(&mut record, event.timestamp.timestamp_millis() - first_timestamp);
+        put_varint(&mut record, offset as i64);
+        match key(event) {
+            Some(key) => put_bytes(&mut record, key.as_bytes()),
+            None => put_varint(&mut record, -1),
+        }
+        put_bytes(&mut record, &serde_json::to_vec(event).unwrap_or_default());
+        // Headers
+        put_varint(&mut record, 0);
+
+        put_varint(&mut records, record.len() as i64);
+        records.put_slice(&record);
+    }
+
+    let mut body = Vec::new();
+    // Attributes: no compression, create time
+    body.put_i16(0);
+    body.put_i32(events.len() as i32 - 1);
+    body.put_i64(first_timestamp);
+    body.put_i64(max_timestamp);
+    // Producer ID, epoch and base sequence, unused without idempotence
+    body.put_i64(-1);
+    body.put_i16(-1);
+    body.put_i32(-1);
+    body.put_i32(events.len() as i32);
+    body.put_slice(&records);
+
+    let mut batch = Vec::with_capacity(21 + body.len());
+    // Base offset, assigned by the broker
+    batch.put_i64(0);
+    // Length of the batch from the partition leader epoch on
+    batch.put_i32((9 + body.len()) as i32);
+    batch.put_i32(-1);
+    // Magic
+    batch.put_i8(2);
+    batch.put_u32(crc32c(&body));
+    batch.put_slice(&body);
+    batch
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    buf.put_i16(value.len() as i16);
+    buf.put_slice(value.as_bytes());
+}
+
+/// Zigzag-encoded variable-length integer
+fn put_varint(buf: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, value: &[u8]) {
+    put_varint(buf, value.len() as i64);
+    buf.put_slice(value);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SinkError> {
+        if self.data.len() < len {
+            return Err(SinkError::Protocol("truncated response".to_string()));
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    fn i16(&mut self) -> Result<i16, SinkError> {
+        let bytes = self.take(2)?;
+        Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, SinkError> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A string, empty when null
+    fn string(&mut self) -> Result<String, SinkError> {
+        let len = self.i16()?;
+        let bytes = self.take(len.max(0) as usize)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// Skip an array of fixed-size items
+    fn skip_array(&mut self, item_size: usize) -> Result<(), SinkError> {
+        let len = self.i32()?;
+        self.take(len.max(0) as usize * item_size)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::analytics::EventType;
+
+    #[test]
+    fn test_hashes() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+
+        assert_eq!(murmur2(b"21"), -973932308);
+        assert_eq!(murmur2(b"foobar"), -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string"), -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string"), -1486304829);
+        assert_eq!(murmur2(b"abc"), 479470107);
+    }
+
+    #[test]
+    fn test_record_batch() {
+        let data = HashMap::from([("ip".to_string(), serde_json::json!("203.0.113.7"))]);
+        let event = Event::new(EventType::BlockedRequest, "api", data);
+        let batch = record_batch(&[&event, &event]);
+
+        let mut reader = Reader::new(&batch);
+        reader.take(8).unwrap();
+        assert_eq!(reader.i32().unwrap() as usize, batch.len() - 12);
+        reader.take(4).unwrap();
+        assert_eq!(reader.take(1).unwrap(), [2]);
+        let crc = reader.take(4).unwrap();
+        assert_eq!(crc, crc32c(&batch[21..]).to_be_bytes());
+        assert_eq!(i32::from_be_bytes(batch[57..61].try_into().unwrap()), 2);
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let mut data = Vec::new();
+        data.put_i32(1);
+        data.put_i32(7);
+        put_string(&mut data, "kafka-1");
+        data.put_i32(9092);
+        data.put_i16(-1);
+        data.put_i32(7);
+        data.put_i32(1);
+        data.put_i16(0);
+        put_string(&mut data, "events");
+        data.put_u8(0);
+        data.put_i32(2);
+        for index in [1, 0] {
+            data.put_i16(0);
+            data.put_i32(index);
+            data.put_i32(7);
+            data.put_i32(1);
+            data.put_i32(7);
+            data.put_i32(0);
+        }
+
+        let metadata = parse_metadata(&data, "events").unwrap();
+        assert_eq!(metadata.brokers[&7], ("kafka-1".to_string(), 9092));
+        assert_eq!(metadata.partitions, vec![(0, 7), (1, 7)]);
+        assert!(parse_metadata(&data, "other").is_err());
+        assert!(parse_metadata(&data[..20], "events").is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Live event stream for the DDoS protection service.
+//!
+//! Analytics events and monitoring alerts are published on Redis channels
+//! as they are recorded, by every instance of the service. This module
+//! holds one subscription to those channels and fans the messages out to
+//! the connected dashboards, each with its own filter.
+
+use futures::StreamExt;
+use ipnet::IpNet;
+use log::error;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use crate::core::analytics::{Event, EventType, EVENTS_CHANNEL};
+use crate::core::monitoring::{Alert, AlertLevel, ALERTS_CHANNEL};
+use crate::core::redis_pool::RedisPool;
+
+/// Messages buffered per subscriber before a slow one starts missing them
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// An event or alert, as pushed to subscribers
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LiveEvent {
+    Event(Event),
+    Alert(Alert),
+}
+
+impl LiveEvent {
+    /// Name of the kind of message, used as the SSE event name
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LiveEvent::Event(_) => "event",
+            LiveEvent::Alert(_) => "alert",
+        }
+    }
+
+    /// Severity of the message; events are rated by their type
+    pub fn severity(&self) -> AlertLevel {
+        match self {
+            LiveEvent::Alert(alert) => alert.level.clone(),
+            LiveEvent::Event(event) => match event.event_type {
+                EventType::DdosAttack | EventType::DdosDetection => AlertLevel::Critical,
+                EventType::BlockedRequest
+                | EventType::RateLimitExceeded
+                | EventType::QuotaExceeded
+                | EventType::SlowConnection
+                | EventType::HoneypotHit
+                | EventType::LoginProtection
+                | EventType::Escalation => AlertLevel::Warning,
+                _ => AlertLevel::Info,
+            },
+        }
+    }
+
+    /// Client address the message is about, if any
+    fn ip(&self) -> Option<IpAddr> {
+        match self {
+            LiveEvent::Event(event) => event.data.get("ip")?.as_str()?.parse().ok(),
+            LiveEvent::Alert(_) => None,
+        }
+    }
+
+    /// Name of the event type, or `Alert` for alerts
+    fn type_name(&self) -> String {
+        match self {
+            LiveEvent::Event(event) => match serde_json::to_value(&event.event_type) {
+                Ok(serde_json::Value::String(name)) => name,
+                _ => String::new(),
+            },
+            LiveEvent::Alert(_) => "Alert".to_string(),
+        }
+    }
+}
+
+/// Which messages a subscriber receives
+#[derive(Debug, Clone, Default)]
+pub struct LiveEventFilter {
+    /// Event type names (`Alert` for alerts); empty for all
+    pub types: Vec<String>,
+    /// Network the client of an event must be in; alerts never match
+    pub network: Option<IpNet>,
+    /// Lowest severity to receive
+    pub min_severity: Option<AlertLevel>,
+}
+
+impl LiveEventFilter {
+    /// Build a filter from comma-separated types, an IP or CIDR network and a severity name
+    pub fn parse(types: Option<&str>, ip: Option<&str>, severity: Option<&str>) -> Result<Self, String> {
+        let types = types
+            .map(|types| {
+                types
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let network = match ip {
+            Some(ip) => Some(
+                ip.parse::<IpNet>()
+                    .or_else(|_| ip.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("Invalid IP or network: {}", ip))?,
+            ),
+            None => None,
+        };
+        let min_severity = match severity.map(str::to_ascii_lowercase).as_deref() {
+            None => None,
+            Some("info") => Some(AlertLevel::Info),
+            Some("warning") => Some(AlertLevel::Warning),
+            Some("error") => Some(AlertLevel::Error),
+            Some("critical") => Some(AlertLevel::Critical),
+            Some(other) => return Err(format!("Invalid severity: {}", other)),
+        };
+        Ok(Self { types, network, min_severity })
+    }
+
+    /// Whether a message passes the filter
+    pub fn matches(&self, event: &LiveEvent) -> bool {
+        if !self.types.is_empty() && !self.types.contains(&event.type_name()) {
+            return false;
+        }
+        if let Some(network) = &self.network {
+            if !event.ip().is_some_and(|ip| network.contains(&ip)) {
+                return false;
+            }
+        }
+        match &self.min_severity {
+            Some(min) => event.severity() >= *min,
+            None => true,
+        }
+    }
+}
+
+/// Fan-out of live events and alerts to subscribers
+#[derive(Clone)]
+pub struct LiveEvents {
+    sender: broadcast::Sender<LiveEvent>,
+}
+
+impl Default for LiveEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LiveEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Receive messages published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Forward published events and alerts to subscribers, resubscribing on failure
+    pub async fn start(&self, redis: RedisPool) {
+        loop {
+            if let Err(e) = self.forward(&redis).await {
+                error!("Live event subscription failed: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn forward(&self, redis: &RedisPool) -> redis::RedisResult<()> {
+        let mut pubsub = redis.pubsub().await?;
+        pubsub.subscribe(&[EVENTS_CHANNEL, ALERTS_CHANNEL]).await?;
+
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            let event = if message.get_channel_name() == ALERTS_CHANNEL {
+                serde_json::from_str(&payload).map(LiveEvent::Alert)
+            } else {
+                serde_json::from_str(&payload).map(LiveEvent::Event)
+            };
+            match event {
+                // Sending only fails when nobody is subscribed
+                Ok(event) => {
+                    let _ = self.sender.send(event);
+                }
+                Err(e) => error!("Failed to parse live event: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn event(event_type: EventType, ip: &str) -> LiveEvent {
+        let data = HashMap::from([("ip".to_string(), serde_json::json!(ip))]);
+        LiveEvent::Event(Event::new(event_type, "test", data))
+    }
+
+    #[test]
+    fn test_filter() {
+        let attack = event(EventType::DdosAttack, "203.0.113.7");
+        let challenge = event(EventType::Challenge, "198.51.100.1");
+
+        assert!(LiveEventFilter::default().matches(&attack));
+
+        let filter = LiveEventFilter::parse(Some("DdosAttack, Alert"), None, None).unwrap();
+        assert!(filter.matches(&attack));
+        assert!(!filter.matches(&challenge));
+
+        let filter = LiveEventFilter::parse(None, Some("203.0.113.0/24"), None).unwrap();
+        assert!(filter.matches(&attack));
+        assert!(!filter.matches(&challenge));
+        let filter = LiveEventFilter::parse(None, Some("198.51.100.1"), None).unwrap();
+        assert!(filter.matches(&challenge));
+
+        let filter = LiveEventFilter::parse(None, None, Some("warning")).unwrap();
+        assert!(filter.matches(&attack));
+        assert!(!filter.matches(&challenge));
+
+        assert!(LiveEventFilter::parse(None, Some("not-an-ip"), None).is_err());
+        assert!(LiveEventFilter::parse(None, None, Some("loud")).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! Access log ingestion for the DDoS protection service.
+//!
+//! This module reads nginx or HAProxy access logs, from files, a syslog
+//! socket or journald, and runs every logged request through the DDoS
+//! detector and the rule engine. It lets the service protect a proxy layer
+//! that can't call the API: requests are only seen after they're served, so
+//! detected clients are stopped through the blocklist, like fail2ban does.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::web;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+use tokio::net::UdpSocket;
+use tokio::process::Command;
+use crate::core::allowlist::Allowlist;
+use crate::core::blocklist::Blocklist;
+use crate::core::ddos_detector::DdosDetector;
+use crate::core::rule_engine::{RequestContext, RuleEngine};
+use crate::models::{LogFormat, LogIngestConfig};
+
+/// How long to wait before reopening a log file that can't be opened
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+
+/// Input reading log lines until the task is aborted
+type Input<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Split off the next whitespace-separated field
+fn next_field(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    if line.is_empty() {
+        return None;
+    }
+    Some(line.split_once(' ').unwrap_or((line, "")))
+}
+
+/// Split off the next double-quoted field, unescaping `\"`
+fn next_quoted(line: &str) -> Option<(String, &str)> {
+    let line = line.trim_start().strip_prefix('"')?;
+    let mut value = String::new();
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            '"' => return Some((value, &line[i + 1..])),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Strip a syslog header (`<134>Oct 15 12:00:00 host nginx: `) from a line
+fn strip_syslog_header(line: &str) -> &str {
+    match line.strip_prefix('<') {
+        Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit()) => {
+            rest.split_once(": ").map(|(_, message)| message).unwrap_or(line)
+        }
+        _ => line,
+    }
+}
+
+/// Request context from a request line (`GET /search?q=a HTTP/1.1`)
+fn request_from(ip: &str, request_line: &str, size: u64, headers: HashMap<String, String>) -> Option<RequestContext> {
+    let mut parts = request_line.split(' ');
+    let method = parts.next().filter(|method| !method.is_empty())?;
+    let uri = parts.next()?;
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let query = web::Query::<HashMap<String, String>>::from_query(query)
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    Some(RequestContext {
+        ip: ip.to_string(),
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        query,
+        size,
+        header_order: Vec::new(),
+        bot_score: None,
+        ja3: None,
+        ja4: None,
+        tenant: None,
+    })
+}
+
+/// Parse an nginx `combined` log line
+///
+/// `$remote_addr - $remote_user [$time_local] "$request" $status
+/// $body_bytes_sent "$http_referer" "$http_user_agent"`
+fn parse_nginx(line: &str) -> Option<RequestContext> {
+    let (ip, rest) = next_field(line)?;
+    let (_, rest) = rest.split_once(']')?;
+    let (request_line, rest) = next_quoted(rest)?;
+    let (_status, rest) = next_field(rest)?;
+    let (size, rest) = next_field(rest)?;
+
+    let mut headers = HashMap::new();
+    let quoted = next_quoted(rest);
+    if let Some((referer, rest)) = quoted {
+        if referer != "-" {
+            headers.insert("Referer".to_string(), referer);
+        }
+        if let Some((user_agent, _)) = next_quoted(rest).filter(|(user_agent, _)| user_agent != "-") {
+            headers.insert("User-Agent".to_string(), user_agent);
+        }
+    }
+    request_from(ip, &request_line, size.parse().unwrap_or(0), headers)
+}
+
+/// Parse an HAProxy `option httplog` log line
+///
+/// `client_ip:port [accept_date] frontend backend/server timers status
+/// bytes_read ... {captured request headers} {captured response headers}
+/// "request"`. A captured `User-Agent` is used when it's the only request
+/// header captured.
+fn parse_haproxy(line: &str) -> Option<RequestContext> {
+    let (client, rest) = next_field(line)?;
+    let (ip, _port) = client.rsplit_once(':')?;
+    let ip = ip.trim_start_matches('[').trim_end_matches(']');
+    let (_, rest) = rest.split_once(']')?;
+    // frontend, backend/server, timers, status
+    let (_, rest) = next_field(rest)?;
+    let (_, rest) = next_field(rest)?;
+    let (_, rest) = next_field(rest)?;
+    let (_, rest) = next_field(rest)?;
+    let (size, rest) = next_field(rest)?;
+
+    let quote = rest.find('"')?;
+    let (fields, request) = rest.split_at(quote);
+    let mut headers = HashMap::new();
+    if let Some(captured) = fields.split_once('{').and_then(|(_, rest)| rest.split_once('}')).map(|(c, _)| c) {
+        if !captured.is_empty() && !captured.contains('|') {
+            headers.insert("User-Agent".to_string(), captured.to_string());
+        }
+    }
+    let (request_line, _) = next_quoted(request)?;
+    request_from(ip, &request_line, size.parse().unwrap_or(0), headers)
+}
+
+/// Parse a log line in the given format
+pub fn parse_line(format: LogFormat, line: &str) -> Option<RequestContext> {
+    let line = strip_syslog_header(line.trim_end());
+    match format {
+        LogFormat::Nginx => parse_nginx(line),
+        LogFormat::Haproxy => parse_haproxy(line),
+    }
+}
+
+/// Ingester of proxy access logs
+pub struct LogIngester {
+    /// Log ingestion configuration
+    config: LogIngestConfig,
+    /// Detector logged requests are run through
+    detector: Arc<DdosDetector>,
+    /// Rule engine logged requests are run through
+    rule_engine: Option<Arc<RuleEngine>>,
+    /// Allowlisted clients are skipped
+    allowlist: Allowlist,
+    /// Clients already blocked are skipped
+    blocklist: Blocklist,
+}
+
+impl LogIngester {
+    /// Create a new log ingester
+    pub fn new(
+        config: LogIngestConfig,
+        detector: Arc<DdosDetector>,
+        allowlist: Allowlist,
+        blocklist: Blocklist,
+    ) -> Self {
+        Self {
+            config,
+            detector,
+            rule_engine: None,
+            allowlist,
+            blocklist,
+        }
+    }
+
+    /// Run logged requests through a rule engine
+    pub fn with_rule_engine(mut self, rule_engine: Arc<RuleEngine>) -> Self {
+        self.rule_engine = Some(rule_engine);
+        self
+    }
+
+    /// Read every configured input until the task is aborted
+    pub async fn start(&self) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let mut inputs: Vec<Input> = Vec::new();
+        for path in &self.config.files {
+            inputs.push(Box::pin(self.follow_file(path)));
+        }
+        if let Some(address) = &self.config.syslog_address {
+            inputs.push(Box::pin(self.receive_syslog(address)));
+        }
+        for unit in &self.config.journald_units {
+            inputs.push(Box::pin(self.follow_journald(unit)));
+        }
+        futures::future::join_all(inputs).await;
+    }
+
+    /// Follow a log file from its end, across rotation and truncation
+    async fn follow_file(&self, path: &str) {
+        let poll_interval = Duration::from_millis(self.config.poll_interval_ms.max(10));
+        let mut from_start = false;
+        loop {
+            let mut file = match File::open(path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    log::error!("Failed to open log file {}: {}", path, e);
+                    tokio::time::sleep(REOPEN_DELAY).await;
+                    continue;
+                }
+            };
+            let inode = file.metadata().await.map(|metadata| metadata.ino()).unwrap_or_default();
+            // Only lines written after startup are read; a rotated file is read from its start
+            let mut position = if from_start { 0 } else { file.seek(SeekFrom::End(0)).await.unwrap_or(0) };
+            log::info!("Following log file {}", path);
+
+            let mut reader = BufReader::new(file);
+            let mut line = Vec::new();
+            loop {
+                match reader.read_until(b'\n', &mut line).await {
+                    Ok(0) => {
+                        tokio::time::sleep(poll_interval).await;
+                        match tokio::fs::metadata(path).await {
+                            Ok(metadata) if metadata.ino() != inode => break,
+                            Ok(metadata) if metadata.len() < position => {
+                                log::info!("Log file {} was truncated", path);
+                                position = reader.seek(SeekFrom::Start(0)).await.unwrap_or(0);
+                                line.clear();
+                            }
+                            // The file may be briefly missing while it's rotated
+                            _ => (),
+                        }
+                    }
+                    Ok(read) => {
+                        position += read as u64;
+                        // A line being written is completed by the next read
+                        if line.ends_with(b"\n") {
+                            self.process(&String::from_utf8_lossy(&line)).await;
+                            line.clear();
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Failed to read log file {}: {}", path, e);
+                        break;
+                    }
+                }
+            }
+            log::info!("Log file {} was rotated, reopening", path);
+            from_start = true;
+        }
+    }
+
+    /// Receive log lines as syslog datagrams
+    async fn receive_syslog(&self, address: &str) {
+        let socket = match UdpSocket::bind(address).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::error!("Failed to bind syslog input to {}: {}", address, e);
+                return;
+            }
+        };
+        log::info!("Receiving access logs over syslog on {}", address);
+        let mut buffer = vec![0; 65_535];
+        loop {
+            match socket.recv_from(&mut buffer).await {
+                Ok((len, _)) => self.process(&String::from_utf8_lossy(&buffer[..len])).await,
+                Err(e) => log::error!("Failed to receive syslog message: {}", e),
+            }
+        }
+    }
+
+    /// Follow a journald unit's messages
+    async fn follow_journald(&self, unit: &str) {
+        loop {
+            let child = Command::new("journalctl")
+                .args(["--follow", "--lines=0", "--output=cat", "--unit", unit])
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(e) => {
+                    log::error!("Failed to follow journald unit {}: {}", unit, e);
+                    return;
+                }
+            };
+            log::info!("Following journald unit {}", unit);
+            if let Some(stdout) = child.stdout.take() {
+                let mut lines = BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    self.process(&line).await;
+                }
+            }
+            let _ = child.wait().await;
+            log::warn!("journalctl exited while following {}, restarting", unit);
+            tokio::time::sleep(REOPEN_DELAY).await;
+        }
+    }
+
+    /// Run a logged request through the detector and the rule engine
+    #[tracing::instrument(name = "log_ingest.process", skip_all)]
+    async fn process(&self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        let Some(request) = parse_line(self.config.format, line) else {
+            metrics::increment_counter!("log_ingest_parse_errors_total");
+            log::debug!("Unparsable access log line: {}", line.trim_end());
+            return;
+        };
+        metrics::increment_counter!("log_ingest_requests_total");
+
+        // All traffic counts towards the totals, including allowlisted and blocked clients
+        if let Err(e) = self.detector.check_aggregate(&request.ip, &request.path, request.size).await {
+            log::error!("Failed to run aggregate detection: {}", e);
+        }
+
+        if self.allowlist.is_allowed(Some(&request.ip), None).await {
+            return;
+        }
+        match self.blocklist.check(&request.ip).await {
+            Ok(Some(_)) => return,
+            Ok(None) => (),
+            Err(e) => log::error!("Failed to check blocklist for {}: {}", request.ip, e),
+        }
+
+        // Rule actions such as blocks are carried out by the rule engine itself
+        if let Some(rule_engine) = &self.rule_engine {
+            if let Err(e) = rule_engine.evaluate_request(&request).await {
+                log::error!("Failed to evaluate rules for {}: {}", request.ip, e);
+            }
+        }
+
+        let result = self.detector.check_request(&request).await;
+        match result {
+            Ok(Some(classification)) => {
+                metrics::increment_counter!("log_ingest_detections_total");
+                log::warn!("Detected {} from {} in access logs", classification.vector, request.ip);
+            }
+            Ok(None) => (),
+            Err(e) => log::error!("Failed to check logged request from {}: {}", request.ip, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nginx() {
+        let line = r#"203.0.113.7 - - [15/Oct/2026:12:00:00 +0000] "GET /search?q=a%20b&page=2 HTTP/1.1" 200 512 "https://example.com/" "Mozilla/5.0 (X11; Linux x86_64)""#;
+        let request = parse_line(LogFormat::Nginx, line).unwrap();
+        assert_eq!(request.ip, "203.0.113.7");
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/search");
+        assert_eq!(request.query.get("q").map(String::as_str), Some("a b"));
+        assert_eq!(request.query.get("page").map(String::as_str), Some("2"));
+        assert_eq!(request.size, 512);
+        assert_eq!(request.header("user-agent"), Some("Mozilla/5.0 (X11; Linux x86_64)"));
+        assert_eq!(request.header("referer"), Some("https://example.com/"));
+
+        // Escaped quotes, no referer, and a syslog header
+        let line = r#"<190>Oct 15 12:00:00 web1 nginx: 2001:db8::1 - bob [15/Oct/2026:12:00:00 +0000] "POST /login HTTP/2.0" 401 - "-" "curl \"7\"""#;
+        let request = parse_line(LogFormat::Nginx, line).unwrap();
+        assert_eq!(request.ip, "2001:db8::1");
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/login");
+        assert_eq!(request.size, 0);
+        assert_eq!(request.header("referer"), None);
+        assert_eq!(request.header("user-agent"), Some(r#"curl "7""#));
+
+        assert!(parse_line(LogFormat::Nginx, "not an access log line").is_none());
+    }
+
+    #[test]
+    fn test_parse_haproxy() {
+        let line = r#"<134>Oct 15 12:00:00 lb1 haproxy[14389]: 198.51.100.4:33317 [15/Oct/2026:12:00:00.655] http-in static/srv1 10/0/30/69/109 200 2750 - - ---- 1/1/1/1/0 0/0 {curl/8.0} {} "GET /index.html?x=1 HTTP/1.1""#;
+        let request = parse_line(LogFormat::Haproxy, line).unwrap();
+        assert_eq!(request.ip, "198.51.100.4");
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/index.html");
+        assert_eq!(request.query.get("x").map(String::as_str), Some("1"));
+        assert_eq!(request.size, 2750);
+        assert_eq!(request.header("user-agent"), Some("curl/8.0"));
+
+        let line = r#"[2001:db8::5]:443 [15/Oct/2026:12:00:00.655] https-in app/web2 0/0/1/2/3 503 120 - - ---- 1/1/1/1/0 0/0 "HEAD / HTTP/1.1""#;
+        let request = parse_line(LogFormat::Haproxy, line).unwrap();
+        assert_eq!(request.ip, "2001:db8::5");
+        assert_eq!(request.method, "HEAD");
+        assert_eq!(request.path, "/");
+        assert!(request.headers.is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+//! Login protection for the DDoS protection service.
+//!
+//! This module protects login endpoints against brute force and credential
+//! stuffing. The proxy checks each login attempt before passing it on and
+//! reports its outcome afterwards; attempts, failures, usernames per IP and
+//! IPs per username are counted in Redis so that every instance shares
+//! them. Usernames are only stored hashed.
+
+use chrono::Utc;
+use serde::Serialize;
+use thiserror::Error;
+use crate::core::redis_pool::RedisPool;
+use crate::models::{LoginAction, LoginProtectionConfig};
+use crate::utils::{hex, sha256};
+
+/// Errors that can occur during login protection
+#[derive(Error, Debug)]
+pub enum LoginProtectionError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+}
+
+/// Why a login attempt was acted on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginSignal {
+    /// The IP or username is locked out
+    LockedOut,
+    /// The IP made too many attempts
+    IpAttempts,
+    /// The IP tried too many usernames
+    UsernamesPerIp,
+    /// Too many of the IP's attempts failed
+    IpFailureRatio,
+    /// The username received too many attempts
+    UsernameAttempts,
+    /// The username was tried from too many IPs
+    IpsPerUsername,
+    /// Too many attempts on the username failed
+    UsernameFailureRatio,
+}
+
+impl LoginSignal {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::LockedOut => "locked_out",
+            Self::IpAttempts => "ip_attempts",
+            Self::UsernamesPerIp => "usernames_per_ip",
+            Self::IpFailureRatio => "ip_failure_ratio",
+            Self::UsernameAttempts => "username_attempts",
+            Self::IpsPerUsername => "ips_per_username",
+            Self::UsernameFailureRatio => "username_failure_ratio",
+        }
+    }
+}
+
+/// Outcome of a login attempt check
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoginDecision {
+    /// Whether the attempt may proceed without a challenge
+    pub allowed: bool,
+    /// How the attempt should be answered, if not allowed
+    pub action: Option<LoginAction>,
+    /// Why the attempt was acted on
+    pub signal: Option<LoginSignal>,
+    /// Seconds until a lockout expires
+    pub retry_after: Option<u64>,
+}
+
+impl LoginDecision {
+    /// Decision letting an attempt proceed
+    pub fn allowed() -> Self {
+        Self {
+            allowed: true,
+            action: None,
+            signal: None,
+            retry_after: None,
+        }
+    }
+}
+
+/// Attempts and failures counted for an IP or a username in the current window
+#[derive(Debug, Clone, Copy, Default)]
+struct LoginCounters {
+    /// Attempts, including the current one
+    attempts: u64,
+    /// Failed attempts
+    failures: u64,
+    /// Distinct usernames tried by the IP, or IPs trying the username
+    spread: u64,
+}
+
+impl LoginCounters {
+    /// Whether the failed share of the attempts reaches the threshold
+    fn failure_ratio_reached(&self, config: &LoginProtectionConfig) -> bool {
+        self.attempts >= config.min_attempts.max(1)
+            && self.failures as f64 / self.attempts as f64 >= config.failure_ratio
+    }
+
+    /// The first limit an IP exceeds, if any
+    fn ip_signal(&self, config: &LoginProtectionConfig) -> Option<LoginSignal> {
+        if self.attempts > config.max_attempts_per_ip {
+            Some(LoginSignal::IpAttempts)
+        } else if self.spread > config.max_usernames_per_ip {
+            Some(LoginSignal::UsernamesPerIp)
+        } else if self.failure_ratio_reached(config) {
+            Some(LoginSignal::IpFailureRatio)
+        } else {
+            None
+        }
+    }
+
+    /// The first limit a username exceeds, if any
+    fn username_signal(&self, config: &LoginProtectionConfig) -> Option<LoginSignal> {
+        if self.attempts > config.max_attempts_per_username {
+            Some(LoginSignal::UsernameAttempts)
+        } else if self.spread > config.max_ips_per_username {
+            Some(LoginSignal::IpsPerUsername)
+        } else if self.failure_ratio_reached(config) {
+            Some(LoginSignal::UsernameFailureRatio)
+        } else {
+            None
+        }
+    }
+}
+
+/// Login protection shared by every instance
+#[derive(Clone)]
+pub struct LoginProtection {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Login protection configuration
+    config: LoginProtectionConfig,
+}
+
+impl LoginProtection {
+    /// Create a new login protection instance
+    pub fn new(redis: RedisPool, config: LoginProtectionConfig) -> Self {
+        Self { redis, config }
+    }
+
+    /// Count a login attempt and decide whether it may proceed
+    pub async fn check_attempt(&self, ip: &str, username: &str) -> Result<LoginDecision, LoginProtectionError> {
+        if !self.config.enabled {
+            return Ok(LoginDecision::allowed());
+        }
+        let user = username_key(username);
+        let ip_lockout_key = format!("login:lockout:ip:{}", ip);
+        let user_lockout_key = format!("login:lockout:user:{}", user);
+
+        let mut conn = self.redis.get();
+        let (ip_ttl, user_ttl): (i64, i64) = redis::pipe()
+            .cmd("TTL").arg(&ip_lockout_key)
+            .cmd("TTL").arg(&user_lockout_key)
+            .query_async(&mut conn)
+            .await?;
+        let locked_for = ip_ttl.max(user_ttl);
+        if locked_for > 0 {
+            return Ok(self.lockout(LoginSignal::LockedOut, locked_for as u64));
+        }
+
+        let window = self.config.window.max(1) as u64;
+        let bucket = Utc::now().timestamp() as u64 / window;
+        let ip_attempts_key = format!("login:attempts:ip:{}:{}", ip, bucket);
+        let ip_failures_key = format!("login:failures:ip:{}:{}", ip, bucket);
+        let ip_users_key = format!("login:users:{}:{}", ip, bucket);
+        let user_attempts_key = format!("login:attempts:user:{}:{}", user, bucket);
+        let user_failures_key = format!("login:failures:user:{}:{}", user, bucket);
+        let user_ips_key = format!("login:ips:{}:{}", user, bucket);
+
+        let (ip_attempts, ip_failures, usernames, user_attempts, user_failures, ips): (
+            u64,
+            Option<u64>,
+            u64,
+            u64,
+            Option<u64>,
+            u64,
+        ) = redis::pipe()
+            .cmd("INCR").arg(&ip_attempts_key)
+            .cmd("EXPIRE").arg(&ip_attempts_key).arg(window).ignore()
+            .cmd("GET").arg(&ip_failures_key)
+            .cmd("PFADD").arg(&ip_users_key).arg(&user).ignore()
+            .cmd("PFCOUNT").arg(&ip_users_key)
+            .cmd("EXPIRE").arg(&ip_users_key).arg(window).ignore()
+            .cmd("INCR").arg(&user_attempts_key)
+            .cmd("EXPIRE").arg(&user_attempts_key).arg(window).ignore()
+            .cmd("GET").arg(&user_failures_key)
+            .cmd("PFADD").arg(&user_ips_key).arg(ip).ignore()
+            .cmd("PFCOUNT").arg(&user_ips_key)
+            .cmd("EXPIRE").arg(&user_ips_key).arg(window).ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        let ip_counters = LoginCounters {
+            attempts: ip_attempts,
+            failures: ip_failures.unwrap_or(0),
+            spread: usernames,
+        };
+        let user_counters = LoginCounters {
+            attempts: user_attempts,
+            failures: user_failures.unwrap_or(0),
+            spread: ips,
+        };
+        let ip_signal = ip_counters.ip_signal(&self.config).map(|signal| (signal, self.config.ip_action, ip_lockout_key));
+        let user_signal = user_counters
+            .username_signal(&self.config)
+            .map(|signal| (signal, self.config.username_action, user_lockout_key));
+
+        // Lockouts take precedence over challenges
+        let triggered = match (ip_signal, user_signal) {
+            (Some(ip), Some(user)) if ip.1 != LoginAction::Lockout && user.1 == LoginAction::Lockout => Some(user),
+            (Some(ip), _) => Some(ip),
+            (None, user) => user,
+        };
+        let Some((signal, action, lockout_key)) = triggered else {
+            return Ok(LoginDecision::allowed());
+        };
+        metrics::increment_counter!("login_protection_triggered_total", "signal" => signal.as_str());
+
+        match action {
+            LoginAction::Lockout => {
+                let _: () = redis::cmd("SET")
+                    .arg(&lockout_key)
+                    .arg(1)
+                    .arg("EX")
+                    .arg(self.config.lockout_seconds.max(1))
+                    .query_async(&mut conn)
+                    .await?;
+                Ok(self.lockout(signal, self.config.lockout_seconds.max(1)))
+            }
+            LoginAction::Challenge => Ok(LoginDecision {
+                allowed: false,
+                action: Some(LoginAction::Challenge),
+                signal: Some(signal),
+                retry_after: None,
+            }),
+        }
+    }
+
+    /// Count the outcome of a login attempt
+    pub async fn record_result(&self, ip: &str, username: &str, success: bool) -> Result<(), LoginProtectionError> {
+        if !self.config.enabled || success {
+            return Ok(());
+        }
+        let window = self.config.window.max(1) as u64;
+        let bucket = Utc::now().timestamp() as u64 / window;
+        let ip_failures_key = format!("login:failures:ip:{}:{}", ip, bucket);
+        let user_failures_key = format!("login:failures:user:{}:{}", username_key(username), bucket);
+
+        let mut conn = self.redis.get();
+        let _: () = redis::pipe()
+            .cmd("INCR").arg(&ip_failures_key).ignore()
+            .cmd("EXPIRE").arg(&ip_failures_key).arg(window).ignore()
+            .cmd("INCR").arg(&user_failures_key).ignore()
+            .cmd("EXPIRE").arg(&user_failures_key).arg(window).ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Lift the lockouts of an IP and a username
+    pub async fn unlock(&self, ip: Option<&str>, username: Option<&str>) -> Result<bool, LoginProtectionError> {
+        let mut keys = Vec::new();
+        if let Some(ip) = ip {
+            keys.push(format!("login:lockout:ip:{}", ip));
+        }
+        if let Some(username) = username {
+            keys.push(format!("login:lockout:user:{}", username_key(username)));
+        }
+        if keys.is_empty() {
+            return Ok(false);
+        }
+        let mut conn = self.redis.get();
+        let removed: u64 = redis::cmd("DEL").arg(&keys).query_async(&mut conn).await?;
+        Ok(removed > 0)
+    }
+
+    fn lockout(&self, signal: LoginSignal, retry_after: u64) -> LoginDecision {
+        LoginDecision {
+            allowed: false,
+            action: Some(LoginAction::Lockout),
+            signal: Some(signal),
+            retry_after: Some(retry_after),
+        }
+    }
+}
+
+/// Hashed, case-insensitive username, so that usernames aren't stored in Redis
+fn username_key(username: &str) -> String {
+    hex(&sha256(username.trim().to_lowercase().as_bytes())[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_signals() {
+        let config = LoginProtectionConfig::default();
+        let counters = |attempts, failures, spread| LoginCounters { attempts, failures, spread };
+
+        assert_eq!(counters(3, 0, 1).ip_signal(&config), None);
+        assert_eq!(counters(31, 0, 1).ip_signal(&config), Some(LoginSignal::IpAttempts));
+        assert_eq!(counters(8, 0, 6).ip_signal(&config), Some(LoginSignal::UsernamesPerIp));
+        // The failure ratio is only judged once there are enough attempts
+        assert_eq!(counters(4, 4, 1).ip_signal(&config), None);
+        assert_eq!(counters(5, 4, 1).ip_signal(&config), Some(LoginSignal::IpFailureRatio));
+
+        assert_eq!(counters(11, 0, 1).username_signal(&config), Some(LoginSignal::UsernameAttempts));
+        assert_eq!(counters(10, 0, 11).username_signal(&config), Some(LoginSignal::IpsPerUsername));
+        assert_eq!(counters(10, 7, 2).username_signal(&config), None);
+        assert_eq!(counters(10, 8, 2).username_signal(&config), Some(LoginSignal::UsernameFailureRatio));
+
+        assert_eq!(username_key(" Alice "), username_key("alice"));
+        assert_eq!(username_key("alice").len(), 32);
+    }
+}
@@ -0,0 +1,80 @@
+//! Bounded least-recently-used map for the DDoS protection service.
+//!
+//! In-memory state keyed by client must never grow with the number of
+//! clients seen, or a spoofed-source attack exhausts memory. This map holds
+//! at most a fixed number of entries and evicts the least recently used
+//! one to make room, counting evictions in metrics.
+
+use std::hash::Hash;
+use linked_hash_map::LinkedHashMap;
+
+/// Map holding at most `capacity` entries, evicting the least recently used
+pub struct LruMap<K: Hash + Eq, V> {
+    /// Entries, least recently used first
+    entries: LinkedHashMap<K, V>,
+    /// Most entries held
+    capacity: usize,
+    /// Name used to label metrics
+    name: &'static str,
+}
+
+impl<K: Hash + Eq + Clone, V> LruMap<K, V> {
+    /// Create a map holding at most `capacity` entries, labelled `name` in metrics
+    pub fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            entries: LinkedHashMap::new(),
+            capacity: capacity.max(1),
+            name,
+        }
+    }
+
+    /// Get an entry, inserting `default()` if missing, and mark it as recently used
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, default: F) -> &mut V {
+        if !self.entries.contains_key(&key) {
+            while self.entries.len() >= self.capacity {
+                self.entries.pop_front();
+                metrics::increment_counter!("lru_evictions_total", "map" => self.name);
+            }
+            self.entries.insert(key.clone(), default());
+            metrics::gauge!("lru_entries", self.entries.len() as f64, "map" => self.name);
+        }
+        self.entries.get_refresh(&key).expect("entry was just inserted")
+    }
+
+    /// Remove an entry
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key)
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Remove and return all entries, least recently used first
+    pub fn drain(&mut self) -> Vec<(K, V)> {
+        let entries = std::mem::take(&mut self.entries).into_iter().collect();
+        metrics::gauge!("lru_entries", 0.0, "map" => self.name);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut map = LruMap::new("test", 2);
+        *map.get_or_insert_with("a", || 0) += 1;
+        *map.get_or_insert_with("b", || 0) += 1;
+
+        // Using "a" makes "b" the least recently used
+        *map.get_or_insert_with("a", || 0) += 1;
+        map.get_or_insert_with("c", || 0);
+
+        assert_eq!(map.remove(&"a"), Some(2));
+        assert_eq!(map.remove(&"b"), None);
+        assert_eq!(map.remove(&"c"), Some(0));
+    }
+}
@@ -1,45 +1,86 @@
 //! Core functionality for the DDoS protection service.
 //! 
 //! This module contains the core components of the service,
-//! including rate limiting, DDoS detection, rule engine, analytics, and monitoring.
+//! including trusted proxies, the allowlist, blocklist, host firewall enforcement, network flow collection, access log ingestion, GeoIP lookups, DNS blocklists, bot detection, honeypots, scanner detection, login protection, attack mode, mitigation escalation, challenges, CAPTCHA verification, IP reputation, threat intelligence feeds, AbuseIPDB reporting, CrowdSec, Cloudflare, BGP mitigation, rate limiting, concurrency limiting, quotas, DDoS detection, the detection pipeline, TLS fingerprinting, traffic baselines, attack tracking and post-attack reports, rule engine, analytics with event enrichment, sampling and batching, its ClickHouse backend and S3 event archival, traffic aggregates, event sinks such as syslog and Kafka output, live event streaming, monitoring, host metrics, alert rules, alert silences, alert channels, the background task watchdog, degraded operation while Redis is down, email alerts and reports, webhook notifications, tenants, and telemetry export.
 
+pub mod redis_pool;
+pub mod degradation;
+pub mod prefix_trie;
+pub mod lru;
+pub mod geoip;
+pub mod trusted_proxies;
+pub mod dnsbl;
+pub mod bot_detection;
+pub mod honeypot;
+pub mod scanner;
+pub mod login_protection;
+pub mod attack_mode;
+pub mod escalation;
+pub mod challenge;
+pub mod captcha;
+pub mod allowlist;
+pub mod blocklist;
+pub mod firewall;
+pub mod flow_collector;
+pub mod log_ingest;
+pub mod reputation;
+pub mod threat_intel;
+pub mod abuseipdb;
+pub mod crowdsec;
+pub mod cloudflare;
+pub mod bgp;
 pub mod rate_limiter;
+pub mod concurrency_limiter;
+pub mod quota;
 pub mod ddos_detector;
+pub mod detection;
+pub mod tls_fingerprint;
+pub mod baseline;
+pub mod attacks;
+pub mod attack_reports;
 pub mod rule_engine;
+pub mod expression;
+pub mod schedule;
 pub mod analytics;
+pub mod enrichment;
+pub mod event_buffer;
+pub mod traffic;
+pub mod clickhouse;
+pub mod event_archive;
+pub mod event_sink;
+pub mod syslog;
+pub mod kafka;
+pub mod live_events;
 pub mod monitoring;
+pub mod host_metrics;
+pub mod alert_rules;
+pub mod silences;
+pub mod alert_channels;
+pub mod watchdog;
+pub mod email;
+pub mod reports;
+pub mod webhooks;
+pub mod tenants;
+pub mod telemetry;
 
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DdosDetectionConfig {
-    pub connection_rate_threshold: u32,
-    pub connection_rate_window: u32,
-    pub request_rate_threshold: u32,
-    pub request_rate_window: u32,
-    pub traffic_volume_threshold: u64,
-    pub traffic_volume_window: u32,
-    pub anomaly_threshold: f64,
-    pub anomaly_window: u32,
-}
-
-impl Default for DdosDetectionConfig {
-    fn default() -> Self {
-        Self {
-            connection_rate_threshold: 100,
-            connection_rate_window: 60,
-            request_rate_threshold: 1000,
-            request_rate_window: 60,
-            traffic_volume_threshold: 10_000_000,
-            traffic_volume_window: 60,
-            anomaly_threshold: 3.0,
-            anomaly_window: 300,
-        }
-    }
-}
-
+pub use redis_pool::RedisPool;
+pub use degradation::Degradation;
+pub use allowlist::Allowlist;
+pub use blocklist::Blocklist;
+pub use geoip::GeoIp;
+pub use dnsbl::Dnsbl;
+pub use reputation::Reputation;
+pub use threat_intel::ThreatIntel;
+pub use abuseipdb::AbuseIpdb;
+pub use crowdsec::CrowdSec;
 pub use rate_limiter::RateLimiter;
-pub use ddos_detector::DdosDetector;
-pub use rule_engine::{RuleEngine, Rule, RuleCondition, RuleAction};
+pub use concurrency_limiter::ConcurrencyLimiter;
+pub use quota::QuotaManager;
+pub use ddos_detector::{DdosDetector, DdosDetectionConfig};
+pub use baseline::BaselineLearner;
+pub use attacks::AttackTracker;
+pub use rule_engine::{RuleEngine, Rule, RuleCondition, RuleAction, RequestContext, Mitigation};
 pub use analytics::Analytics;
+pub use event_sink::EventSinks;
+pub use live_events::LiveEvents;
 pub use monitoring::Monitoring; 
\ No newline at end of file
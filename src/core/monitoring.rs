@@ -3,19 +3,35 @@
 //! This module provides monitoring capabilities for tracking
 //! system performance and detecting issues.
 
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::time;
-use crate::models::MonitoringConfig;
-use redis::Client as RedisClient;
+use crate::models::{AlertEscalationConfig, MonitoringConfig, PagerDutyConfig};
+use crate::core::redis_pool::{RedisConnection, RedisPool};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 use log::{info, warn, error};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::broadcast::Receiver;
 use redis::AsyncCommands;
+use crate::core::alert_channels::{AlertChannel, PagerDutyChannel, SlackChannel};
+use crate::core::alert_rules::{self, AlertRule, AlertRules, RuleEvaluator, RuleState};
+use crate::core::silences::Silences;
+use crate::core::analytics::{RequestCounters, RequestTotals};
+use crate::core::host_metrics::HostMetrics;
+use crate::core::watchdog::Heartbeat;
+use crate::core::webhooks::{Notification, Webhooks};
+use crate::storage::{Archive, RedisStorage, Storage};
+
+/// Latest system metrics
+const METRICS_KEY: &str = "system_metrics";
+/// Sorted set of system metrics samples, scored by timestamp
+pub const METRICS_HISTORY_KEY: &str = "system_metrics:history";
+/// Averaged series, by bucket size in seconds, each built from the one before
+const ROLLUPS: [(i64, &str); 3] = [(60, "1m"), (300, "5m"), (3600, "1h")];
 
 /// Errors that can occur during monitoring operations
 #[derive(Error, Debug)]
@@ -27,7 +43,7 @@ pub enum MonitoringError {
 }
 
 /// System metrics
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct SystemMetrics {
     /// CPU usage (percentage)
     pub cpu_usage: f64,
@@ -35,9 +51,9 @@ pub struct SystemMetrics {
     pub memory_usage: f64,
     /// Disk usage (percentage)
     pub disk_usage: f64,
-    /// Network traffic in (bytes)
+    /// Network traffic in (bytes received since boot)
     pub network_in: u64,
-    /// Network traffic out (bytes)
+    /// Network traffic out (bytes sent since boot)
     pub network_out: u64,
     /// Request rate (requests per second)
     pub request_rate: f64,
@@ -45,10 +61,35 @@ pub struct SystemMetrics {
     pub error_rate: f64,
     /// Response time (ms)
     pub response_time_ms: f64,
+    /// CPU usage of the service process (percentage of host CPU)
+    #[serde(default)]
+    pub process_cpu_usage: f64,
+    /// Resident memory of the service process (bytes)
+    #[serde(default)]
+    pub process_memory_bytes: u64,
     /// Timestamp
     pub timestamp: i64,
 }
 
+impl SystemMetrics {
+    /// Value of a metric by field name, for alert rules
+    pub fn value(&self, metric: &str) -> Option<f64> {
+        Some(match metric {
+            "cpu_usage" => self.cpu_usage,
+            "memory_usage" => self.memory_usage,
+            "disk_usage" => self.disk_usage,
+            "network_in" => self.network_in as f64,
+            "network_out" => self.network_out as f64,
+            "request_rate" => self.request_rate,
+            "error_rate" => self.error_rate,
+            "response_time_ms" => self.response_time_ms,
+            "process_cpu_usage" => self.process_cpu_usage,
+            "process_memory_bytes" => self.process_memory_bytes as f64,
+            _ => return None,
+        })
+    }
+}
+
 impl redis::FromRedisValue for SystemMetrics {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         let str_value: String = redis::FromRedisValue::from_redis_value(v)?;
@@ -61,8 +102,25 @@ impl redis::FromRedisValue for SystemMetrics {
     }
 }
 
-/// Alert level
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+/// Channel every created alert is published on, as JSON
+pub const ALERTS_CHANNEL: &str = "monitoring:alerts:live";
+/// Redis hash mapping fingerprints to the ID of their unresolved alert
+const OPEN_ALERTS_KEY: &str = "alerts:open";
+/// Redis sorted set of alert IDs, scored by creation time
+const ALERTS_KEY: &str = "alerts:index";
+/// Redis hash holding alerts keyed by ID
+const ALERT_DATA_KEY: &str = "alerts:data";
+/// Redis set of the IDs of active alerts
+const ACTIVE_ALERTS_KEY: &str = "alerts:active";
+/// Sorted set alerts were stored in as JSON before they were indexed by ID
+const LEGACY_ALERTS_KEY: &str = "alerts";
+/// Days alerts are kept
+const ALERT_RETENTION_DAYS: i64 = 30;
+/// Most alerts a query may return
+pub const MAX_ALERT_QUERY_LIMIT: usize = 500;
+
+/// Alert level, ordered by severity
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AlertLevel {
     /// Info alert
     Info,
@@ -74,6 +132,17 @@ pub enum AlertLevel {
     Critical,
 }
 
+impl AlertLevel {
+    /// The next level up, if any
+    pub fn raised(&self) -> AlertLevel {
+        match self {
+            AlertLevel::Info => AlertLevel::Warning,
+            AlertLevel::Warning => AlertLevel::Error,
+            AlertLevel::Error | AlertLevel::Critical => AlertLevel::Critical,
+        }
+    }
+}
+
 /// Alert status
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum AlertStatus {
@@ -82,6 +151,123 @@ pub enum AlertStatus {
     Resolved,
 }
 
+/// Field alerts are sorted by
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertSort {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    Level,
+}
+
+/// Sort direction
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Filters, sorting and page of an alert listing
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertQuery {
+    #[serde(default)]
+    pub level: Option<AlertLevel>,
+    #[serde(default)]
+    pub status: Option<AlertStatus>,
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Only alerts created at or after this time
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub sort: AlertSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    #[serde(default)]
+    pub offset: usize,
+    /// Page to return, from 1, instead of skipping `offset` alerts
+    #[serde(default)]
+    pub page: Option<usize>,
+    #[serde(default = "default_alert_query_limit")]
+    pub limit: usize,
+}
+
+fn default_alert_query_limit() -> usize {
+    50
+}
+
+impl Default for AlertQuery {
+    fn default() -> Self {
+        Self {
+            level: None,
+            status: None,
+            source: None,
+            since: None,
+            sort: AlertSort::default(),
+            order: SortOrder::default(),
+            offset: 0,
+            page: None,
+            limit: default_alert_query_limit(),
+        }
+    }
+}
+
+impl AlertQuery {
+    /// Whether an alert passes the filters
+    pub fn matches(&self, alert: &Alert) -> bool {
+        self.level.as_ref().is_none_or(|level| *level == alert.level)
+            && self.status.as_ref().is_none_or(|status| *status == alert.status)
+            && self.source.as_ref().is_none_or(|source| *source == alert.source)
+            && self.since.is_none_or(|since| alert.created_at >= since)
+    }
+
+    /// Alerts skipped before the page
+    pub fn skip(&self) -> usize {
+        match self.page {
+            Some(page) => page.saturating_sub(1).saturating_mul(self.limit),
+            None => self.offset,
+        }
+    }
+
+    /// Whether the query can be paged through the index of alerts by creation time
+    fn paged_by_index(&self) -> bool {
+        self.sort == AlertSort::CreatedAt && self.level.is_none() && self.status.is_none() && self.source.is_none()
+    }
+
+    /// Filter, sort and page alerts
+    pub fn apply(&self, alerts: Vec<Alert>) -> AlertPage {
+        let mut alerts: Vec<Alert> = alerts.into_iter().filter(|alert| self.matches(alert)).collect();
+        self.sort(&mut alerts);
+        AlertPage {
+            total: alerts.len() as u64,
+            alerts: alerts.into_iter().skip(self.skip()).take(self.limit.min(MAX_ALERT_QUERY_LIMIT)).collect(),
+        }
+    }
+
+    fn sort(&self, alerts: &mut [Alert]) {
+        match self.sort {
+            AlertSort::CreatedAt => alerts.sort_by_key(|alert| alert.created_at),
+            AlertSort::UpdatedAt => alerts.sort_by_key(|alert| alert.updated_at),
+            // Ties keep the newest first once reversed
+            AlertSort::Level => alerts.sort_by(|a, b| a.level.cmp(&b.level).then(a.created_at.cmp(&b.created_at))),
+        }
+        if self.order == SortOrder::Desc {
+            alerts.reverse();
+        }
+    }
+}
+
+/// A page of the alerts matching a query
+#[derive(Debug, Clone)]
+pub struct AlertPage {
+    pub alerts: Vec<Alert>,
+    /// Alerts matching the query, on any page
+    pub total: u64,
+}
+
 /// Alert
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Alert {
@@ -103,43 +289,307 @@ pub struct Alert {
     pub acknowledged_at: Option<DateTime<Utc>>,
     /// Alert resolution timestamp
     pub resolved_at: Option<DateTime<Utc>>,
+    /// Labels of the alert rule that raised the alert
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Identifies the condition the alert is about; while the alert is
+    /// unresolved, raising it again updates it instead of adding another
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// Times the alert was raised
+    #[serde(default)]
+    pub occurrences: u64,
+    /// Silence that muted the alert when it was raised
+    #[serde(default)]
+    pub silenced_by: Option<String>,
+    /// Times the alert was escalated for going unacknowledged
+    #[serde(default)]
+    pub escalation_level: u32,
+    /// When the alert was last escalated
+    #[serde(default)]
+    pub escalated_at: Option<DateTime<Utc>>,
+}
+
+impl Alert {
+    /// Create an active alert
+    pub fn new(level: AlertLevel, source: &str, message: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            level,
+            message: message.to_string(),
+            source: source.to_string(),
+            status: AlertStatus::Active,
+            created_at: now,
+            updated_at: now,
+            acknowledged_at: None,
+            resolved_at: None,
+            labels: HashMap::new(),
+            fingerprint: None,
+            occurrences: 1,
+            silenced_by: None,
+            escalation_level: 0,
+            escalated_at: None,
+        }
+    }
+
+    /// Whether the escalation policy calls for escalating the alert at `now`
+    pub fn escalation_due(&self, policy: &AlertEscalationConfig, now: DateTime<Utc>) -> bool {
+        let waiting_since = self.escalated_at.unwrap_or(self.created_at);
+        policy.enabled
+            && self.status == AlertStatus::Active
+            && self.silenced_by.is_none()
+            && self.escalation_level < policy.max_steps
+            && (policy.levels.contains(&self.level) || self.escalation_level > 0)
+            && now - waiting_since >= chrono::Duration::minutes(policy.ack_timeout_minutes as i64)
+    }
+}
+
+/// Sorted set of the averaged series with the given label
+fn rollup_key(label: &str) -> String {
+    format!("{}:{}", METRICS_HISTORY_KEY, label)
+}
+
+/// Start of the `step` second bucket a timestamp falls in
+fn bucket(timestamp: i64, step: i64) -> i64 {
+    timestamp - timestamp.rem_euclid(step)
+}
+
+/// Average of a set of samples, timestamped at the start of their bucket
+///
+/// Network counters only ever grow, so the latest value is kept.
+fn average(samples: &[SystemMetrics], timestamp: i64) -> Option<SystemMetrics> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mean = |value: fn(&SystemMetrics) -> f64| samples.iter().map(value).sum::<f64>() / samples.len() as f64;
+    Some(SystemMetrics {
+        cpu_usage: mean(|m| m.cpu_usage),
+        memory_usage: mean(|m| m.memory_usage),
+        disk_usage: mean(|m| m.disk_usage),
+        network_in: samples.iter().map(|m| m.network_in).max().unwrap_or(0),
+        network_out: samples.iter().map(|m| m.network_out).max().unwrap_or(0),
+        request_rate: mean(|m| m.request_rate),
+        error_rate: mean(|m| m.error_rate),
+        response_time_ms: mean(|m| m.response_time_ms),
+        process_cpu_usage: mean(|m| m.process_cpu_usage),
+        process_memory_bytes: mean(|m| m.process_memory_bytes as f64) as u64,
+        timestamp,
+    })
+}
+
+/// Average samples, ordered by timestamp, over buckets of `step` seconds
+fn downsample(samples: &[SystemMetrics], step: i64) -> Vec<SystemMetrics> {
+    samples
+        .chunk_by(|a, b| bucket(a.timestamp, step) == bucket(b.timestamp, step))
+        .filter_map(|chunk| average(chunk, bucket(chunk[0].timestamp, step)))
+        .collect()
 }
 
 /// Monitoring service
 pub struct Monitoring {
-    /// Redis client
-    redis_client: RedisClient,
+    /// Redis connection pool
+    redis_client: RedisPool,
+    /// Where the metrics history is kept
+    storage: Arc<dyn Storage>,
     /// Monitoring configuration
     config: MonitoringConfig,
+    /// Webhooks alerts are sent to
+    webhooks: Option<Webhooks>,
+    /// Channels alerts are routed to by level
+    channels: Vec<Arc<dyn AlertChannel>>,
+    /// Channels escalated alerts are also sent to
+    escalation_channels: Vec<Arc<dyn AlertChannel>>,
+    /// Host and process counters
+    host: HostMetrics,
+    /// Requests served, which request and error rates are computed from
+    requests: RequestCounters,
+    /// Request totals at the previous collection
+    previous_requests: std::sync::Mutex<Option<(Instant, RequestTotals)>>,
+    /// Rules alerts are raised by
+    alert_rules: AlertRules,
+    /// How long each alert rule has matched
+    evaluator: std::sync::Mutex<RuleEvaluator>,
+    /// Silences muting alerts
+    silences: Silences,
+    /// Durable copy of alerts
+    archive: Option<Archive>,
+}
+
+/// Request rate, error rate and average response time between two readings
+fn request_rates(previous: RequestTotals, current: RequestTotals, elapsed: Duration) -> (f64, f64, f64) {
+    let seconds = elapsed.as_secs_f64();
+    if seconds <= 0.0 {
+        return (0.0, 0.0, 0.0);
+    }
+    let requests = current.requests.saturating_sub(previous.requests);
+    let errors = current.errors.saturating_sub(previous.errors);
+    let response_time_ms = if requests == 0 {
+        0.0
+    } else {
+        current.response_time_us.saturating_sub(previous.response_time_us) as f64 / requests as f64 / 1000.0
+    };
+    (requests as f64 / seconds, errors as f64 / seconds, response_time_ms)
 }
 
 impl Monitoring {
     /// Create a new monitoring service
-    pub fn new(redis_client: RedisClient, config: MonitoringConfig) -> Self {
+    pub fn new(redis_client: RedisPool, config: MonitoringConfig) -> Self {
+        let mut channels: Vec<Arc<dyn AlertChannel>> = Vec::new();
+        if let Some(slack) = SlackChannel::from_config(&config.slack) {
+            channels.push(Arc::new(slack));
+        }
+        if let Some(pagerduty) = PagerDutyChannel::from_config(&config.pagerduty) {
+            channels.push(Arc::new(pagerduty));
+        }
+
+        let mut escalation_channels: Vec<Arc<dyn AlertChannel>> = Vec::new();
+        let escalation_pagerduty = PagerDutyConfig {
+            routing_key: config.escalation.pagerduty_routing_key.clone(),
+            levels: vec![AlertLevel::Info, AlertLevel::Warning, AlertLevel::Error, AlertLevel::Critical],
+            events_url: config.pagerduty.events_url.clone(),
+        };
+        if let Some(pagerduty) = PagerDutyChannel::from_config(&escalation_pagerduty) {
+            escalation_channels.push(Arc::new(pagerduty));
+        }
+
+        let evaluator = RuleEvaluator::new(config.alert_consecutive_breaches);
         Self {
-            redis_client,
             config,
+            webhooks: None,
+            channels,
+            escalation_channels,
+            host: HostMetrics::new(),
+            requests: RequestCounters::new(),
+            previous_requests: std::sync::Mutex::new(None),
+            alert_rules: AlertRules::new(redis_client.clone()),
+            silences: Silences::new(redis_client.clone()),
+            evaluator: std::sync::Mutex::new(evaluator),
+            storage: Arc::new(RedisStorage::new(redis_client.clone())),
+            archive: None,
+            redis_client,
         }
     }
 
+    /// Keep the metrics history in another storage backend than Redis
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Keep alerts in the given archive too, restoring them from it when Redis has none
+    pub fn with_archive(mut self, archive: Archive) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Compute request rates from the given counters
+    pub fn with_request_counters(mut self, requests: RequestCounters) -> Self {
+        self.requests = requests;
+        self
+    }
+
+    /// Send alerts to the given webhooks
+    pub fn with_webhooks(mut self, webhooks: Webhooks) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Route alerts to another channel, besides the configured Slack and PagerDuty ones
+    pub fn with_channel(mut self, channel: Arc<dyn AlertChannel>) -> Self {
+        self.channels.push(channel);
+        self
+    }
+
     /// Start monitoring
-    pub async fn start_monitoring(&self) -> Result<()> {
+    pub async fn start_monitoring(&self, heartbeat: Heartbeat) -> Result<()> {
         info!("Starting monitoring service...");
+        let mut rules = alert_rules::threshold_rules(&self.config.alert_thresholds);
+        rules.extend(self.config.alert_rules.iter().cloned());
+        if let Err(e) = self.alert_rules.seed(&rules).await {
+            error!("Failed to add configured alert rules: {}", e);
+        }
+
+        if let Err(e) = self.migrate_legacy_alerts().await {
+            error!("Failed to migrate stored alerts: {}", e);
+        }
+        if let Err(e) = self.restore_alerts().await {
+            error!("Failed to restore alerts from PostgreSQL: {}", e);
+        }
+
         let mut interval = time::interval(Duration::from_secs(self.config.interval_seconds as u64));
 
         loop {
             interval.tick().await;
+            heartbeat.beat().await;
             match self.check_system_health().await {
                 Ok(_) => info!("System health check completed successfully"),
                 Err(e) => error!("System health check failed: {}", e),
             }
+            match self.collect_metrics().await {
+                Ok(metrics) => {
+                    if let Err(e) = self.evaluate_alert_rules(&metrics).await {
+                        error!("Failed to evaluate alert rules: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to collect system metrics: {}", e),
+            }
+            if let Err(e) = self.escalate_alerts().await {
+                error!("Failed to escalate alerts: {}", e);
+            }
         }
     }
 
+    /// Escalate the alerts that went unacknowledged for too long
+    async fn escalate_alerts(&self) -> Result<()> {
+        let policy = &self.config.escalation;
+        let now = Utc::now();
+        let due: Vec<Alert> = self
+            .get_active_alerts()
+            .await
+            .into_iter()
+            .filter(|alert| alert.escalation_due(policy, now))
+            .collect();
+
+        for alert in due {
+            let escalated = self
+                .update_alert(&alert.id, |alert| {
+                    alert.escalation_level += 1;
+                    alert.escalated_at = Some(Utc::now());
+                    alert.level = alert.level.raised();
+                })
+                .await?;
+            let Some(alert) = escalated else {
+                continue;
+            };
+            warn!(
+                "Alert {} from {} unacknowledged for {} minutes, escalated to {:?} (step {})",
+                alert.id, alert.source, policy.ack_timeout_minutes, alert.level, alert.escalation_level
+            );
+
+            self.dispatch(&alert);
+            for channel in &self.escalation_channels {
+                let channel = channel.clone();
+                let alert = alert.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = channel.send(&alert).await {
+                        error!("Failed to send escalated alert {} to {}: {}", alert.id, channel.name(), e);
+                    }
+                });
+            }
+            if let Some(webhooks) = &self.webhooks {
+                let message = format!("Unacknowledged, escalated (step {}): {}", alert.escalation_level, alert.message);
+                webhooks
+                    .notify(Notification::new(&policy.webhook_channel, alert.level.clone(), &alert.source, &message))
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
     async fn check_system_health(&self) -> Result<()> {
         // Check Redis connection
-        let mut conn = self.redis_client.get_async_connection().await
-            .map_err(|e| anyhow::anyhow!("Failed to connect to Redis: {}", e))?;
+        let mut conn = self.redis_client.get();
 
         // Check memory usage
         self.check_memory_usage(&mut conn).await?;
@@ -150,7 +600,7 @@ impl Monitoring {
         Ok(())
     }
 
-    async fn check_memory_usage(&self, conn: &mut redis::aio::Connection) -> Result<()> {
+    async fn check_memory_usage(&self, conn: &mut RedisConnection) -> Result<()> {
         let info: String = redis::cmd("INFO")
             .query_async(conn)
             .await
@@ -175,7 +625,7 @@ impl Monitoring {
         Ok(())
     }
 
-    async fn check_request_rate(&self, conn: &mut redis::aio::Connection) -> Result<()> {
+    async fn check_request_rate(&self, conn: &mut RedisConnection) -> Result<()> {
         let request_count: Option<u64> = conn
             .get("request_count")
             .await
@@ -193,89 +643,139 @@ impl Monitoring {
         Ok(())
     }
 
-    /// Collect system metrics
-    async fn collect_metrics(&self) -> Result<SystemMetrics, Box<dyn std::error::Error>> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
-        // In a real implementation, we would collect actual system metrics
-        // For now, we'll use placeholder values
+    /// Collect system metrics, storing them as the latest sample and in the history
+    async fn collect_metrics(&self) -> Result<SystemMetrics> {
+        let host = self.host.sample();
+        let totals = self.requests.totals();
+        let now = Instant::now();
+        let (request_rate, error_rate, response_time_ms) = {
+            let mut previous = self.previous_requests.lock().unwrap();
+            let rates = match *previous {
+                Some((at, before)) => request_rates(before, totals, now - at),
+                None => (0.0, 0.0, 0.0),
+            };
+            *previous = Some((now, totals));
+            rates
+        };
+
         let metrics = SystemMetrics {
-            cpu_usage: 45.5,
-            memory_usage: 60.2,
-            disk_usage: 60.0,
-            network_in: 1000000,
-            network_out: 500000,
-            request_rate: 100.0,
-            error_rate: 0.02,
-            response_time_ms: 50.0,
-            timestamp: now as i64,
+            cpu_usage: host.cpu_usage,
+            memory_usage: host.memory_usage,
+            disk_usage: host.disk_usage,
+            network_in: host.network_in,
+            network_out: host.network_out,
+            request_rate,
+            error_rate,
+            response_time_ms,
+            process_cpu_usage: host.process_cpu_usage,
+            process_memory_bytes: host.process_memory_bytes,
+            timestamp: Utc::now().timestamp(),
         };
-        
-        let mut conn = self.redis_client.get_async_connection().await?;
-        
+
         let metrics_json = serde_json::to_string(&metrics)?;
-        
-        let _: () = redis::cmd("SET")
-            .arg("system_metrics")
-            .arg(metrics_json)
-            .query_async(&mut conn)
-            .await?;
-        
+        let cutoff = metrics.timestamp - self.config.history.raw_retention_hours as i64 * 3600;
+        let mut conn = self.redis_client.get();
+        let _: () = redis::cmd("SET").arg(METRICS_KEY).arg(&metrics_json).query_async(&mut conn).await?;
+        self.storage.add(METRICS_HISTORY_KEY, &metrics_json, metrics.timestamp as f64).await?;
+        self.storage.remove_by_score(METRICS_HISTORY_KEY, f64::NEG_INFINITY, (cutoff - 1) as f64).await?;
+        self.update_rollups(metrics.timestamp).await?;
+
         Ok(metrics)
     }
 
-    /// Check for alerts
-    async fn check_thresholds(&self, metrics: &SystemMetrics) -> Result<(), Box<dyn std::error::Error>> {
-        let mut conn = self.redis_client.get_async_connection().await?;
+    /// Recompute the averages of the buckets a new sample falls in
+    ///
+    /// Each series is averaged from the finer one before it, so a bucket
+    /// never takes more than a few reads to rebuild.
+    async fn update_rollups(&self, timestamp: i64) -> Result<()> {
+        let mut source = METRICS_HISTORY_KEY.to_string();
+        for (step, label) in ROLLUPS {
+            let start = bucket(timestamp, step);
+            // Timestamps are whole seconds, so this is the bucket without its end
+            let samples = self.storage.range_by_score(&source, start as f64, (start + step - 1) as f64).await?;
+            let samples: Vec<SystemMetrics> = samples.iter().filter_map(|(json, _)| serde_json::from_str(json).ok()).collect();
 
-        // Check CPU usage
-        if metrics.cpu_usage > self.config.alert_thresholds.cpu_usage as f64 {
-            self.create_alert(
-                "High CPU Usage",
-                &format!("CPU usage is at {}%", metrics.cpu_usage),
-                AlertLevel::Warning,
-            ).await?;
+            let key = rollup_key(label);
+            if let Some(average) = average(&samples, start) {
+                let cutoff = timestamp - self.rollup_retention_days(step) as i64 * 86400;
+                self.storage.remove_by_score(&key, start as f64, start as f64).await?;
+                self.storage.add(&key, &serde_json::to_string(&average)?, start as f64).await?;
+                self.storage.remove_by_score(&key, f64::NEG_INFINITY, (cutoff - 1) as f64).await?;
+            }
+            source = key;
         }
+        Ok(())
+    }
 
-        // Check memory usage
-        if metrics.memory_usage > self.config.alert_thresholds.memory_usage as f64 {
-            self.create_alert(
-                "High Memory Usage",
-                &format!("Memory usage is at {}%", metrics.memory_usage),
-                AlertLevel::Warning,
-            ).await?;
+    fn rollup_retention_days(&self, step: i64) -> u64 {
+        let history = &self.config.history;
+        match step {
+            60 => history.minute_retention_days,
+            300 => history.five_minute_retention_days,
+            _ => history.hour_retention_days,
         }
+    }
 
-        // Check request rate
-        if metrics.request_rate > self.config.alert_thresholds.request_rate as f64 {
-            self.create_alert(
-                "High Request Rate",
-                &format!("Request rate is at {}/s", metrics.request_rate),
-                AlertLevel::Warning,
-            ).await?;
-        }
+    /// System metrics between two timestamps, averaged over `step` seconds
+    ///
+    /// Points are read from the coarsest series that is still at least as
+    /// fine as the step asked for.
+    pub async fn get_metrics_history(&self, from: i64, to: i64, step: i64) -> Result<Vec<SystemMetrics>> {
+        let key = ROLLUPS
+            .iter()
+            .rev()
+            .find(|(resolution, _)| *resolution <= step)
+            .map_or_else(|| METRICS_HISTORY_KEY.to_string(), |(_, label)| rollup_key(label));
 
-        // Check error rate
-        if metrics.error_rate > self.config.alert_thresholds.error_rate as f64 {
-            self.create_alert(
-                "High Error Rate",
-                &format!("Error rate is at {}%", metrics.error_rate),
-                AlertLevel::Error,
-            ).await?;
-        }
+        let samples = self.storage.range_by_score(&key, from as f64, to as f64).await?;
+        let samples: Vec<SystemMetrics> = samples.iter().filter_map(|(json, _)| serde_json::from_str(json).ok()).collect();
+
+        Ok(downsample(&samples, step))
+    }
 
+    /// Raise or update the alerts of matching rules and resolve those of recovered ones
+    async fn evaluate_alert_rules(&self, metrics: &SystemMetrics) -> Result<()> {
+        let rules = self.alert_rules.get_rules().await?;
+        let states: Vec<(AlertRule, RuleState)> = self
+            .evaluator
+            .lock()
+            .unwrap()
+            .evaluate(&rules, metrics)
+            .into_iter()
+            .map(|(rule, state)| (rule.clone(), state))
+            .collect();
+
+        let mut conn = self.redis_client.get();
+        let open: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(OPEN_ALERTS_KEY)
+            .query_async(&mut conn)
+            .await?;
+        for (rule, state) in states {
+            match state {
+                RuleState::Firing(value) => {
+                    let mut alert = Alert::new(rule.severity.clone(), &rule.name, &rule.message(value));
+                    alert.labels = rule.labels.clone();
+                    alert.fingerprint = Some(rule.fingerprint());
+                    self.raise("alerts", alert).await?;
+                }
+                RuleState::Recovered => {
+                    if let Some(alert_id) = open.get(&rule.fingerprint()) {
+                        info!("Alert rule {} recovered, resolving alert {}", rule.id, alert_id);
+                        self.resolve_alert(alert_id).await?;
+                    }
+                }
+                RuleState::Pending => {}
+            }
+        }
         Ok(())
     }
 
     /// Get current system metrics
     pub async fn get_current_metrics(&self) -> Result<SystemMetrics> {
-        let mut conn = self.redis_client.get_async_connection().await?;
+        let mut conn = self.redis_client.get();
         
         let metrics_json: Option<String> = redis::cmd("GET")
-            .arg("system_metrics")
+            .arg(METRICS_KEY)
             .query_async(&mut conn)
             .await?;
 
@@ -283,150 +783,414 @@ impl Monitoring {
             Ok(serde_json::from_str(&json)?)
         } else {
             Ok(SystemMetrics {
-                cpu_usage: 0.0,
-                memory_usage: 0.0,
-                disk_usage: 0.0,
-                network_in: 0,
-                network_out: 0,
-                request_rate: 0.0,
-                error_rate: 0.0,
-                response_time_ms: 0.0,
                 timestamp: Utc::now().timestamp(),
+                ..SystemMetrics::default()
             })
         }
     }
 
     /// Get active alerts
     pub async fn get_active_alerts(&self) -> Vec<Alert> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(_) => return Vec::new(),
-        };
+        let mut conn = self.redis_client.get();
 
-        let alerts_json: Vec<String> = match redis::cmd("ZRANGE")
-            .arg("alerts")
-            .arg(0)
-            .arg(-1)
+        let ids: Vec<String> = match redis::cmd("SMEMBERS")
+            .arg(ACTIVE_ALERTS_KEY)
             .query_async(&mut conn)
             .await {
-                Ok(alerts) => alerts,
+                Ok(ids) => ids,
                 Err(_) => return Vec::new(),
             };
 
-        alerts_json
+        self.load_alerts(&ids).await.unwrap_or_default()
+    }
+
+    /// A page of the alerts matching a query
+    ///
+    /// Listing alerts by creation time, filtered by `since` alone, pages
+    /// through the index, so only the alerts on the page are read. Otherwise
+    /// only active alerts are read when the query asks for them, or the
+    /// index narrows the alerts read down to those created since the query's
+    /// start.
+    pub async fn query_alerts(&self, query: &AlertQuery) -> Result<AlertPage> {
+        let mut conn = self.redis_client.get();
+        let min = query.since.map_or_else(|| "-inf".to_string(), |since| since.timestamp().to_string());
+
+        if query.paged_by_index() {
+            let (total, ids): (u64, Vec<String>) = if query.order == SortOrder::Desc {
+                redis::pipe()
+                    .cmd("ZCOUNT").arg(ALERTS_KEY).arg(&min).arg("+inf")
+                    .cmd("ZREVRANGEBYSCORE").arg(ALERTS_KEY).arg("+inf").arg(&min)
+                    .arg("LIMIT").arg(query.skip()).arg(query.limit.min(MAX_ALERT_QUERY_LIMIT))
+                    .query_async(&mut conn)
+                    .await?
+            } else {
+                redis::pipe()
+                    .cmd("ZCOUNT").arg(ALERTS_KEY).arg(&min).arg("+inf")
+                    .cmd("ZRANGEBYSCORE").arg(ALERTS_KEY).arg(&min).arg("+inf")
+                    .arg("LIMIT").arg(query.skip()).arg(query.limit.min(MAX_ALERT_QUERY_LIMIT))
+                    .query_async(&mut conn)
+                    .await?
+            };
+            // The index orders alerts created in the same second by ID
+            let mut alerts = self.load_alerts(&ids).await?;
+            query.sort(&mut alerts);
+            return Ok(AlertPage { alerts, total });
+        }
+
+        let ids: Vec<String> = if query.status == Some(AlertStatus::Active) {
+            redis::cmd("SMEMBERS")
+                .arg(ACTIVE_ALERTS_KEY)
+                .query_async(&mut conn)
+                .await?
+        } else {
+            redis::cmd("ZRANGEBYSCORE")
+                .arg(ALERTS_KEY)
+                .arg(min)
+                .arg("+inf")
+                .query_async(&mut conn)
+                .await?
+        };
+
+        Ok(query.apply(self.load_alerts(&ids).await?))
+    }
+
+    /// Stored alerts by ID, skipping those that are gone
+    async fn load_alerts(&self, ids: &[String]) -> Result<Vec<Alert>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.redis_client.get();
+        let alerts_json: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(ALERT_DATA_KEY)
+            .arg(ids)
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(alerts_json
             .into_iter()
+            .flatten()
             .filter_map(|json| serde_json::from_str(&json).ok())
-            .filter(|alert: &Alert| alert.status == AlertStatus::Active)
-            .collect()
+            .collect())
     }
 
-    /// Acknowledge an alert
-    pub async fn acknowledge_alert(&self, alert_id: &str) -> Result<()> {
-        let mut conn = self.redis_client.get_async_connection().await?;
-        
+    /// Queue the commands that store an alert and keep the active set current
+    fn save_alert(pipe: &mut redis::Pipeline, alert: &Alert, alert_json: &str) {
+        pipe.cmd("HSET").arg(ALERT_DATA_KEY).arg(&alert.id).arg(alert_json).ignore();
+        let active_cmd = if alert.status == AlertStatus::Active { "SADD" } else { "SREM" };
+        pipe.cmd(active_cmd).arg(ACTIVE_ALERTS_KEY).arg(&alert.id).ignore();
+    }
+
+    /// Move alerts stored as JSON in the legacy sorted set to the indexed keys
+    async fn migrate_legacy_alerts(&self) -> Result<()> {
+        let mut conn = self.redis_client.get();
         let alerts_json: Vec<String> = redis::cmd("ZRANGE")
-            .arg("alerts")
+            .arg(LEGACY_ALERTS_KEY)
             .arg(0)
             .arg(-1)
             .query_async(&mut conn)
             .await?;
+        if alerts_json.is_empty() {
+            return Ok(());
+        }
 
-        for alert_json in alerts_json {
-            if let Ok(mut alert) = serde_json::from_str::<Alert>(&alert_json) {
-                if alert.id == alert_id {
-                    alert.status = AlertStatus::Acknowledged;
-                    alert.acknowledged_at = Some(Utc::now());
-                    alert.updated_at = Utc::now();
-
-                    let updated_json = serde_json::to_string(&alert)?;
-                    let _: () = redis::pipe()
-                        .atomic()
-                        .cmd("ZREM")
-                        .arg("alerts")
-                        .arg(alert_json)
-                        .cmd("ZADD")
-                        .arg("alerts")
-                        .arg(alert.updated_at.timestamp())
-                        .arg(updated_json)
-                        .query_async(&mut conn)
-                        .await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let mut migrated = 0;
+        for alert_json in &alerts_json {
+            if let Ok(alert) = serde_json::from_str::<Alert>(alert_json) {
+                pipe.cmd("ZADD").arg(ALERTS_KEY).arg(alert.created_at.timestamp()).arg(&alert.id).ignore();
+                Self::save_alert(&mut pipe, &alert, alert_json);
+                migrated += 1;
+            }
+        }
+        pipe.cmd("DEL").arg(LEGACY_ALERTS_KEY).ignore();
+        let _: () = pipe.query_async(&mut conn).await?;
+        info!("Migrated {} stored alerts", migrated);
+        Ok(())
+    }
 
-                    break;
-                }
+    /// Write the archived alerts of the retention period to Redis if it has none
+    async fn restore_alerts(&self) -> Result<()> {
+        let Some(archive) = &self.archive else {
+            return Ok(());
+        };
+        let mut conn = self.redis_client.get();
+        let stored: u64 = redis::cmd("ZCARD").arg(ALERTS_KEY).query_async(&mut conn).await?;
+        if stored > 0 {
+            return Ok(());
+        }
+        let alerts = archive
+            .alerts_since(Utc::now() - chrono::Duration::days(ALERT_RETENTION_DAYS))
+            .await?;
+        if alerts.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for alert in &alerts {
+            pipe.cmd("ZADD").arg(ALERTS_KEY).arg(alert.created_at.timestamp()).arg(&alert.id).ignore();
+            Self::save_alert(&mut pipe, alert, &serde_json::to_string(alert)?);
+            // Raising an unresolved alert again updates it
+            let open = alert.fingerprint.as_ref().filter(|_| alert.status != AlertStatus::Resolved);
+            if let Some(fingerprint) = open {
+                pipe.cmd("HSET").arg(OPEN_ALERTS_KEY).arg(fingerprint).arg(&alert.id).ignore();
+            }
+        }
+        let _: () = pipe.query_async(&mut conn).await?;
+        info!("Restored {} alerts from PostgreSQL", alerts.len());
+        Ok(())
+    }
+
+    /// Copy an alert to the archive, if there is one
+    async fn archive_alert(&self, alert: &Alert) {
+        if let Some(archive) = &self.archive {
+            if let Err(e) = archive.save_alert(alert).await {
+                error!("Failed to archive alert {}: {}", alert.id, e);
+            }
+        }
+    }
+
+    /// Acknowledge an alert
+    pub async fn acknowledge_alert(&self, alert_id: &str) -> Result<()> {
+        self.set_alert_status(alert_id, AlertStatus::Acknowledged).await
+    }
+
+    /// Resolve an alert
+    pub async fn resolve_alert(&self, alert_id: &str) -> Result<()> {
+        self.set_alert_status(alert_id, AlertStatus::Resolved).await
+    }
+
+    /// Resolve the unresolved alert with a fingerprint, returning whether there was one
+    pub async fn resolve_fingerprint(&self, fingerprint: &str) -> Result<bool> {
+        let mut conn = self.redis_client.get();
+        let alert_id: Option<String> = redis::cmd("HGET")
+            .arg(OPEN_ALERTS_KEY)
+            .arg(fingerprint)
+            .query_async(&mut conn)
+            .await?;
+        match alert_id {
+            Some(alert_id) => {
+                self.resolve_alert(&alert_id).await?;
+                Ok(true)
             }
+            None => Ok(false),
         }
+    }
+
+    /// Move an alert to a new status and tell its channels
+    async fn set_alert_status(&self, alert_id: &str, status: AlertStatus) -> Result<()> {
+        let updated = self
+            .update_alert(alert_id, |alert| {
+                match status {
+                    AlertStatus::Acknowledged => alert.acknowledged_at = Some(Utc::now()),
+                    AlertStatus::Resolved => alert.resolved_at = Some(Utc::now()),
+                    AlertStatus::Active => {}
+                }
+                alert.status = status;
+            })
+            .await?;
 
+        if let Some(alert) = updated {
+            if let (AlertStatus::Resolved, Some(fingerprint)) = (&alert.status, &alert.fingerprint) {
+                // Raising the alert again opens a new one
+                let mut conn = self.redis_client.get();
+                let _: () = redis::cmd("HDEL")
+                    .arg(OPEN_ALERTS_KEY)
+                    .arg(fingerprint)
+                    .query_async(&mut conn)
+                    .await?;
+            }
+            if !self.is_muted(&alert).await {
+                self.dispatch(&alert);
+            }
+        }
         Ok(())
     }
 
+    /// Whether a silence mutes an alert right now
+    async fn is_muted(&self, alert: &Alert) -> bool {
+        match self.silences.muting(alert).await {
+            Ok(silence) => silence.is_some(),
+            Err(e) => {
+                // Better a notification too many than a missed page
+                error!("Failed to check silences for alert {}: {}", alert.id, e);
+                false
+            }
+        }
+    }
+
+    /// Change a stored alert, returning it as updated if it exists
+    async fn update_alert<F>(&self, alert_id: &str, update: F) -> Result<Option<Alert>>
+    where
+        F: FnOnce(&mut Alert),
+    {
+        let mut conn = self.redis_client.get();
+
+        let alert_json: Option<String> = redis::cmd("HGET")
+            .arg(ALERT_DATA_KEY)
+            .arg(alert_id)
+            .query_async(&mut conn)
+            .await?;
+        let Some(alert_json) = alert_json else {
+            return Ok(None);
+        };
+
+        let mut alert: Alert = serde_json::from_str(&alert_json)?;
+        update(&mut alert);
+        alert.updated_at = Utc::now();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        Self::save_alert(&mut pipe, &alert, &serde_json::to_string(&alert)?);
+        let _: () = pipe.query_async(&mut conn).await?;
+        self.archive_alert(&alert).await;
+
+        Ok(Some(alert))
+    }
+
     /// Clean up old alerts
     async fn cleanup_old_alerts(&self) -> Result<()> {
-        let mut conn = self.redis_client.get_async_connection().await?;
-        let retention_days = 30; // Keep alerts for 30 days
-        let cutoff = Utc::now().timestamp() - (retention_days * 24 * 60 * 60);
+        let mut conn = self.redis_client.get();
+        let cutoff = Utc::now().timestamp() - (ALERT_RETENTION_DAYS * 24 * 60 * 60);
 
-        let _: () = redis::cmd("ZREMRANGEBYSCORE")
-            .arg("alerts")
+        let ids: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(ALERTS_KEY)
             .arg("-inf")
             .arg(cutoff)
             .query_async(&mut conn)
             .await?;
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let _: () = redis::pipe()
+            .atomic()
+            .cmd("ZREM").arg(ALERTS_KEY).arg(&ids).ignore()
+            .cmd("HDEL").arg(ALERT_DATA_KEY).arg(&ids).ignore()
+            .cmd("SREM").arg(ACTIVE_ALERTS_KEY).arg(&ids).ignore()
+            .query_async(&mut conn)
+            .await?;
 
         Ok(())
     }
 
-    async fn create_alert(&self, title: &str, message: &str, level: AlertLevel) -> Result<()> {
-        let mut conn = self.redis_client.get_async_connection().await?;
-        
-        let alert = Alert {
-            id: Uuid::new_v4().to_string(),
-            level,
-            message: message.to_string(),
-            source: title.to_string(),
-            status: AlertStatus::Active,
-            created_at: Utc::now(),
-            updated_at: Utc::now(),
-            acknowledged_at: None,
-            resolved_at: None,
-        };
+    /// Create and store an active alert, sent to the webhooks on the `alerts` channel
+    pub async fn create_alert(&self, title: &str, message: &str, level: AlertLevel) -> Result<()> {
+        self.notify("alerts", title, message, level).await
+    }
+
+    /// Create an alert and send it to the webhooks subscribed to `channel`
+    pub async fn notify(&self, channel: &str, title: &str, message: &str, level: AlertLevel) -> Result<()> {
+        self.raise(channel, Alert::new(level, title, message)).await
+    }
+
+    /// Store an alert, publish it, and send it to its channels and webhooks
+    ///
+    /// An alert with a fingerprint that already has an unresolved alert
+    /// updates that one instead, without notifying anyone again.
+    ///
+    /// Alerts muted by a silence are stored, but nobody is notified.
+    pub async fn raise(&self, channel: &str, mut alert: Alert) -> Result<()> {
+        let mut conn = self.redis_client.get();
+
+        if let Some(fingerprint) = &alert.fingerprint {
+            let claimed: bool = redis::cmd("HSETNX")
+                .arg(OPEN_ALERTS_KEY)
+                .arg(fingerprint)
+                .arg(&alert.id)
+                .query_async(&mut conn)
+                .await?;
+            if !claimed {
+                let open_id: Option<String> = redis::cmd("HGET")
+                    .arg(OPEN_ALERTS_KEY)
+                    .arg(fingerprint)
+                    .query_async(&mut conn)
+                    .await?;
+                if let Some(open_id) = open_id {
+                    let updated = self
+                        .update_alert(&open_id, |open| {
+                            open.occurrences += 1;
+                            open.message = alert.message.clone();
+                        })
+                        .await?;
+                    if updated.is_some() {
+                        return Ok(());
+                    }
+                }
+                // The open alert is gone, cleaned up with old alerts; this one takes its place
+                let _: () = redis::cmd("HSET")
+                    .arg(OPEN_ALERTS_KEY)
+                    .arg(fingerprint)
+                    .arg(&alert.id)
+                    .query_async(&mut conn)
+                    .await?;
+            }
+        }
+
+        match self.silences.muting(&alert).await {
+            Ok(silence) => alert.silenced_by = silence.map(|silence| silence.id),
+            Err(e) => error!("Failed to check silences for alert {}: {}", alert.id, e),
+        }
 
         let alert_json = serde_json::to_string(&alert)?;
-        let _: Result<(), redis::RedisError> = redis::pipe()
-            .atomic()
+        let mut pipe = redis::pipe();
+        pipe.atomic()
             .cmd("ZADD")
-            .arg("alerts")
+            .arg(ALERTS_KEY)
             .arg(alert.created_at.timestamp())
-            .arg(alert_json)
-            .query_async(&mut conn)
-            .await;
+            .arg(&alert.id)
+            .ignore();
+        Self::save_alert(&mut pipe, &alert, &alert_json);
+        pipe.cmd("PUBLISH").arg(ALERTS_CHANNEL).arg(&alert_json).ignore();
+        let _: Result<(), redis::RedisError> = pipe.query_async(&mut conn).await;
+        self.archive_alert(&alert).await;
+
+        if let Some(silence_id) = &alert.silenced_by {
+            info!("Alert {} from {} muted by silence {}", alert.id, alert.source, silence_id);
+            return Ok(());
+        }
+        self.dispatch(&alert);
+        if let Some(webhooks) = &self.webhooks {
+            webhooks
+                .notify(Notification::new(channel, alert.level.clone(), &alert.source, &alert.message))
+                .await;
+        }
 
         Ok(())
     }
 
+    /// Send an alert to the channels routing its level, in the background
+    fn dispatch(&self, alert: &Alert) {
+        for channel in self.channels.iter().filter(|channel| channel.routes(&alert.level)) {
+            let channel = channel.clone();
+            let alert = alert.clone();
+            tokio::spawn(async move {
+                if let Err(e) = channel.send(&alert).await {
+                    error!("Failed to send alert {} to {}: {}", alert.id, channel.name(), e);
+                }
+            });
+        }
+    }
+
     pub async fn get_alerts(&self) -> Result<Vec<Alert>, MonitoringError> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(_) => return Ok(Vec::new()),
-        };
+        let mut conn = self.redis_client.get();
 
-        let alerts_json: Vec<String> = match redis::cmd("ZRANGE")
-            .arg("alerts")
+        let ids: Vec<String> = match redis::cmd("ZRANGE")
+            .arg(ALERTS_KEY)
             .arg(0)
             .arg(-1)
             .query_async(&mut conn)
             .await {
-                Ok(alerts) => alerts,
+                Ok(ids) => ids,
                 Err(_) => return Ok(Vec::new()),
             };
 
-        Ok(alerts_json
-            .into_iter()
-            .filter_map(|json| serde_json::from_str(&json).ok())
-            .collect())
+        Ok(self.load_alerts(&ids).await.unwrap_or_default())
     }
 
     pub async fn get_metrics(&self) -> Result<SystemMetrics, MonitoringError> {
-        let _conn = self.redis_client.get_async_connection().await?;
+        let _conn = self.redis_client.get();
         // TODO: Implement metrics retrieval from Redis
         Ok(SystemMetrics::default())
     }
@@ -441,4 +1205,112 @@ mod tests {
         // This is a placeholder test
         // In a real implementation, we would use a test Redis instance
     }
+
+    fn sample(timestamp: i64, cpu_usage: f64, network_in: u64) -> SystemMetrics {
+        SystemMetrics {
+            cpu_usage,
+            network_in,
+            timestamp,
+            ..SystemMetrics::default()
+        }
+    }
+
+    #[test]
+    fn test_downsample() {
+        let samples = vec![
+            sample(1_700_000_000, 10.0, 100),
+            sample(1_700_000_030, 30.0, 300),
+            sample(1_700_000_050, 20.0, 200),
+            sample(1_700_000_070, 50.0, 400),
+        ];
+        // 1_699_999_980 is the start of the first minute
+        let averages = downsample(&samples, 60);
+        assert_eq!(averages, vec![sample(1_699_999_980, 20.0, 300), sample(1_700_000_040, 35.0, 400)]);
+
+        let averages = downsample(&samples, 3600);
+        assert_eq!(averages.len(), 1);
+        assert_eq!(averages[0].cpu_usage, 27.5);
+        assert_eq!(averages[0].network_in, 400);
+        assert!(downsample(&[], 60).is_empty());
+    }
+
+    #[test]
+    fn test_alert_query() {
+        let now = Utc::now();
+        let alert = |level: AlertLevel, source: &str, minutes_ago: i64| {
+            let mut alert = Alert::new(level, source, "message");
+            alert.created_at = now - chrono::Duration::minutes(minutes_ago);
+            alert
+        };
+        let mut resolved = alert(AlertLevel::Critical, "High Error Rate", 30);
+        resolved.status = AlertStatus::Resolved;
+        let alerts = vec![
+            alert(AlertLevel::Warning, "High CPU Usage", 10),
+            resolved,
+            alert(AlertLevel::Critical, "High Error Rate", 5),
+            alert(AlertLevel::Info, "Attack Detected", 120),
+        ];
+
+        let newest = AlertQuery::default().apply(alerts.clone());
+        assert_eq!(newest.total, 4);
+        let ages: Vec<i64> = newest.alerts.iter().map(|alert| (now - alert.created_at).num_minutes()).collect();
+        assert_eq!(ages, vec![5, 10, 30, 120]);
+
+        let query = AlertQuery {
+            status: Some(AlertStatus::Active),
+            since: Some(now - chrono::Duration::hours(1)),
+            sort: AlertSort::Level,
+            ..AlertQuery::default()
+        };
+        let levels: Vec<AlertLevel> = query.apply(alerts.clone()).alerts.into_iter().map(|alert| alert.level).collect();
+        assert_eq!(levels, vec![AlertLevel::Critical, AlertLevel::Warning]);
+
+        let query = AlertQuery {
+            source: Some("High Error Rate".to_string()),
+            order: SortOrder::Asc,
+            page: Some(2),
+            limit: 1,
+            ..AlertQuery::default()
+        };
+        let page = query.apply(alerts);
+        assert_eq!(page.total, 2);
+        assert_eq!(page.alerts.len(), 1);
+        assert_eq!(page.alerts[0].status, AlertStatus::Active);
+    }
+
+    #[test]
+    fn test_escalation_due() {
+        let policy = AlertEscalationConfig {
+            max_steps: 2,
+            ..AlertEscalationConfig::default()
+        };
+        let mut alert = Alert::new(AlertLevel::Critical, "High Error Rate", "error_rate is 25.00 (> 10)");
+        let created = alert.created_at;
+        assert!(!alert.escalation_due(&policy, created + chrono::Duration::minutes(10)));
+        assert!(alert.escalation_due(&policy, created + chrono::Duration::minutes(15)));
+
+        // The next step waits for another timeout
+        alert.escalation_level = 1;
+        alert.escalated_at = Some(created + chrono::Duration::minutes(15));
+        assert!(!alert.escalation_due(&policy, created + chrono::Duration::minutes(20)));
+        assert!(alert.escalation_due(&policy, created + chrono::Duration::minutes(30)));
+        alert.escalation_level = 2;
+        assert!(!alert.escalation_due(&policy, created + chrono::Duration::hours(1)));
+
+        let mut alert = Alert::new(AlertLevel::Critical, "High Error Rate", "error_rate is 25.00 (> 10)");
+        alert.status = AlertStatus::Acknowledged;
+        assert!(!alert.escalation_due(&policy, created + chrono::Duration::hours(1)));
+        let alert = Alert::new(AlertLevel::Warning, "High CPU Usage", "cpu_usage is 95.00 (> 80)");
+        assert!(!alert.escalation_due(&policy, created + chrono::Duration::hours(1)));
+        assert_eq!(AlertLevel::Warning.raised(), AlertLevel::Error);
+    }
+
+    #[test]
+    fn test_request_rates() {
+        let previous = RequestTotals { requests: 1000, errors: 10, response_time_us: 5_000_000 };
+        let current = RequestTotals { requests: 1600, errors: 40, response_time_us: 8_000_000 };
+        assert_eq!(request_rates(previous, current, Duration::from_secs(60)), (10.0, 0.5, 5.0));
+        assert_eq!(request_rates(current, current, Duration::from_secs(60)), (0.0, 0.0, 0.0));
+        assert_eq!(request_rates(previous, current, Duration::ZERO), (0.0, 0.0, 0.0));
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,200 @@
+//! Binary prefix trie for IP network lookups.
+//!
+//! This module maps CIDR ranges to values and finds every range containing
+//! an address in time proportional to the prefix length rather than the
+//! number of ranges stored.
+
+use std::net::IpAddr;
+use ipnet::IpNet;
+
+/// Trie node; children are indexed by the next address bit
+struct Node<V> {
+    children: [Option<Box<Node<V>>>; 2],
+    value: Option<V>,
+}
+
+impl<V> Node<V> {
+    /// Whether this node or any below it holds a value
+    fn holds_value(&self) -> bool {
+        self.value.is_some() || self.children.iter().flatten().any(|child| child.holds_value())
+    }
+}
+
+impl<V> Default for Node<V> {
+    fn default() -> Self {
+        Self {
+            children: [None, None],
+            value: None,
+        }
+    }
+}
+
+/// Map from IP networks to values with containment lookups
+pub struct PrefixTrie<V> {
+    v4: Node<V>,
+    v6: Node<V>,
+}
+
+impl<V> Default for PrefixTrie<V> {
+    fn default() -> Self {
+        Self {
+            v4: Node::default(),
+            v6: Node::default(),
+        }
+    }
+}
+
+/// Address bits left-aligned in a `u128`, with the address width
+fn address_bits(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(addr) => ((u32::from(addr) as u128) << 96, 32),
+        IpAddr::V6(addr) => (u128::from(addr), 128),
+    }
+}
+
+fn bit(bits: u128, index: u8) -> usize {
+    ((bits >> (127 - index)) & 1) as usize
+}
+
+/// Rebuild the network of the given prefix length containing `addr`
+fn network(addr: IpAddr, prefix_len: u8) -> IpNet {
+    IpNet::new(addr, prefix_len)
+        .map(|net| net.trunc())
+        .unwrap_or_else(|_| IpNet::from(addr))
+}
+
+impl<V> PrefixTrie<V> {
+    /// Create an empty trie
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn root(&self, addr: IpAddr) -> &Node<V> {
+        match addr {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        }
+    }
+
+    fn node(&self, net: &IpNet) -> Option<&Node<V>> {
+        let (bits, _) = address_bits(net.network());
+        let mut node = self.root(net.network());
+        for index in 0..net.prefix_len() {
+            node = node.children[bit(bits, index)].as_deref()?;
+        }
+        Some(node)
+    }
+
+    fn node_mut(&mut self, net: &IpNet) -> &mut Node<V> {
+        let (bits, _) = address_bits(net.network());
+        let mut node = match net {
+            IpNet::V4(_) => &mut self.v4,
+            IpNet::V6(_) => &mut self.v6,
+        };
+        for index in 0..net.prefix_len() {
+            node = node.children[bit(bits, index)].get_or_insert_with(Box::default);
+        }
+        node
+    }
+
+    /// Insert a network, returning the value it previously held
+    pub fn insert(&mut self, net: IpNet, value: V) -> Option<V> {
+        self.node_mut(&net.trunc()).value.replace(value)
+    }
+
+    /// Remove a network, returning its value
+    ///
+    /// Empty branches are left in place; they are reclaimed when the trie is rebuilt.
+    pub fn remove(&mut self, net: &IpNet) -> Option<V> {
+        let net = net.trunc();
+        self.node(&net)?;
+        self.node_mut(&net).value.take()
+    }
+
+    /// All networks containing `addr`, from the broadest to the most specific
+    pub fn matches(&self, addr: IpAddr) -> Vec<(IpNet, &V)> {
+        let (bits, width) = address_bits(addr);
+        let mut matches = Vec::new();
+        let mut node = self.root(addr);
+
+        for prefix_len in 0..=width {
+            if let Some(value) = &node.value {
+                matches.push((network(addr, prefix_len), value));
+            }
+            if prefix_len == width {
+                break;
+            }
+            node = match node.children[bit(bits, prefix_len)].as_deref() {
+                Some(child) => child,
+                None => break,
+            };
+        }
+
+        matches
+    }
+
+    /// Whether any network contains `addr`
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        !self.matches(addr).is_empty()
+    }
+
+    /// Whether any network overlaps `net`, either containing it or lying inside it
+    pub fn overlaps(&self, net: &IpNet) -> bool {
+        let (bits, _) = address_bits(net.network());
+        let mut node = self.root(net.network());
+        for index in 0..net.prefix_len() {
+            if node.value.is_some() {
+                return true;
+            }
+            node = match node.children[bit(bits, index)].as_deref() {
+                Some(child) => child,
+                None => return false,
+            };
+        }
+        node.holds_value()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_trie() {
+        let mut trie = PrefixTrie::new();
+        trie.insert("10.0.0.0/8".parse().unwrap(), "wide");
+        trie.insert("10.1.2.0/24".parse().unwrap(), "narrow");
+        trie.insert("2001:db8::/32".parse().unwrap(), "v6");
+
+        let addr: IpAddr = "10.1.2.3".parse().unwrap();
+        let matches = trie.matches(addr);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0], ("10.0.0.0/8".parse().unwrap(), &"wide"));
+        assert_eq!(matches[1], ("10.1.2.0/24".parse().unwrap(), &"narrow"));
+
+        assert!(trie.contains("10.200.0.1".parse().unwrap()));
+        assert!(!trie.contains("11.0.0.1".parse().unwrap()));
+        assert!(trie.contains("2001:db8::1".parse().unwrap()));
+        // IPv4 and IPv6 networks don't overlap
+        assert!(!trie.contains("::a01:203".parse().unwrap()));
+
+        // Host bits are ignored on insert and removal
+        assert_eq!(trie.remove(&"10.1.2.99/24".parse().unwrap()), Some("narrow"));
+        assert_eq!(trie.matches(addr), vec![("10.0.0.0/8".parse().unwrap(), &"wide")]);
+        assert_eq!(trie.remove(&"10.1.2.0/24".parse().unwrap()), None);
+
+        // Single addresses are full-length prefixes
+        trie.insert(IpNet::from(addr), "host");
+        assert_eq!(trie.matches(addr).pop(), Some(("10.1.2.3/32".parse().unwrap(), &"host")));
+
+        // Networks overlap those containing them and those inside them
+        assert!(trie.overlaps(&"10.9.0.0/16".parse().unwrap()));
+        assert!(trie.overlaps(&"10.1.0.0/16".parse().unwrap()));
+        assert!(trie.overlaps(&"0.0.0.0/0".parse().unwrap()));
+        assert!(!trie.overlaps(&"11.0.0.0/8".parse().unwrap()));
+        // Branches left by removals hold nothing
+        trie.remove(&"10.0.0.0/8".parse().unwrap());
+        trie.remove(&IpNet::from(addr));
+        assert!(!trie.overlaps(&"10.0.0.0/8".parse().unwrap()));
+    }
+}
@@ -0,0 +1,322 @@
+//! Long-horizon quotas for the DDoS protection service.
+//!
+//! This module provides daily and monthly request quotas per API key.
+//! Quota definitions and usage counters both live in Redis, so they
+//! survive restarts and are shared by all instances.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::core::redis_pool::RedisPool;
+use crate::utils::{format_rate_limit_key, redact_key};
+
+/// Redis hash holding quota definitions keyed by API key
+const QUOTAS_KEY: &str = "quotas";
+
+/// Extra time usage counters are kept after their period ends
+const USAGE_GRACE_SECONDS: i64 = 86400;
+
+/// Errors that can occur during quota operations
+#[derive(Error, Debug)]
+pub enum QuotaError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Quota exceeded")]
+    ExceededQuota(QuotaStatus),
+}
+
+/// Quota period
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    /// Identifier of the period containing `now` (e.g. `20240131` or `202401`)
+    fn period_id(&self, now: DateTime<Utc>) -> String {
+        match self {
+            QuotaPeriod::Daily => now.format("%Y%m%d").to_string(),
+            QuotaPeriod::Monthly => now.format("%Y%m").to_string(),
+        }
+    }
+
+    /// Start of the period following the one containing `now`
+    pub fn next_reset(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let date = match self {
+            QuotaPeriod::Daily => now.date_naive() + Duration::days(1),
+            QuotaPeriod::Monthly => {
+                let (year, month) = if now.month() == 12 {
+                    (now.year() + 1, 1)
+                } else {
+                    (now.year(), now.month() + 1)
+                };
+                NaiveDate::from_ymd_opt(year, month, 1).unwrap_or_else(|| now.date_naive())
+            }
+        };
+        Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap_or_default())
+    }
+
+    fn usage_key(&self, api_key: &str, now: DateTime<Utc>) -> String {
+        let period = match self {
+            QuotaPeriod::Daily => "daily",
+            QuotaPeriod::Monthly => "monthly",
+        };
+        format_rate_limit_key(
+            "quota:usage",
+            &format!("{}:{}:{}", api_key, period, self.period_id(now)),
+        )
+    }
+}
+
+/// Quota definition for an API key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    /// API key the quota applies to
+    pub api_key: String,
+    /// Maximum usage per UTC day
+    pub daily_limit: Option<u64>,
+    /// Maximum usage per UTC calendar month
+    pub monthly_limit: Option<u64>,
+    /// Creation timestamp
+    pub created_at: DateTime<Utc>,
+    /// Update timestamp
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Quota {
+    /// The quota with its API key hidden, for listing
+    pub fn redacted(mut self) -> Self {
+        self.api_key = redact_key(&self.api_key);
+        self
+    }
+}
+
+/// Usage of a quota within its current period
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaUsage {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub resets_at: DateTime<Utc>,
+}
+
+/// Current state of all quotas for an API key
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaStatus {
+    pub api_key: String,
+    pub daily: Option<QuotaUsage>,
+    pub monthly: Option<QuotaUsage>,
+}
+
+/// Atomically checks that `cost` fits in both quotas and, if so, consumes it.
+///
+/// KEYS: daily usage, monthly usage
+/// ARGV: cost, daily limit (-1 = none), monthly limit (-1 = none),
+///       daily expiry timestamp, monthly expiry timestamp
+/// Returns: {allowed (0/1), daily used, monthly used}
+const CONSUME_SCRIPT: &str = r#"
+local cost = tonumber(ARGV[1])
+local daily_limit = tonumber(ARGV[2])
+local monthly_limit = tonumber(ARGV[3])
+local daily = tonumber(redis.call('GET', KEYS[1]) or '0')
+local monthly = tonumber(redis.call('GET', KEYS[2]) or '0')
+
+if (daily_limit >= 0 and daily + cost > daily_limit)
+    or (monthly_limit >= 0 and monthly + cost > monthly_limit) then
+    return {0, daily, monthly}
+end
+
+daily = redis.call('INCRBY', KEYS[1], cost)
+redis.call('EXPIREAT', KEYS[1], ARGV[4])
+monthly = redis.call('INCRBY', KEYS[2], cost)
+redis.call('EXPIREAT', KEYS[2], ARGV[5])
+return {1, daily, monthly}
+"#;
+
+/// Quota manager
+pub struct QuotaManager {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Script consuming quota usage
+    consume_script: redis::Script,
+}
+
+impl QuotaManager {
+    /// Create a new quota manager instance
+    pub fn new(redis: RedisPool) -> Self {
+        Self {
+            redis,
+            consume_script: redis::Script::new(CONSUME_SCRIPT),
+        }
+    }
+
+    /// Create or replace the quota for an API key
+    pub async fn set_quota(&self, quota: &Quota) -> Result<(), QuotaError> {
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("HSET")
+            .arg(QUOTAS_KEY)
+            .arg(&quota.api_key)
+            .arg(serde_json::to_string(quota)?)
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Get the quota for an API key
+    pub async fn get_quota(&self, api_key: &str) -> Result<Option<Quota>, QuotaError> {
+        let mut conn = self.redis.get();
+        let json: Option<String> = redis::cmd("HGET")
+            .arg(QUOTAS_KEY)
+            .arg(api_key)
+            .query_async(&mut conn)
+            .await?;
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Get all quotas
+    pub async fn get_quotas(&self) -> Result<Vec<Quota>, QuotaError> {
+        let mut conn = self.redis.get();
+        let quotas_json: Vec<String> = redis::cmd("HVALS")
+            .arg(QUOTAS_KEY)
+            .query_async(&mut conn)
+            .await?;
+        Ok(quotas_json
+            .into_iter()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect())
+    }
+
+    /// Remove the quota for an API key, returning whether it existed
+    pub async fn remove_quota(&self, api_key: &str) -> Result<bool, QuotaError> {
+        let mut conn = self.redis.get();
+        let removed: u32 = redis::cmd("HDEL")
+            .arg(QUOTAS_KEY)
+            .arg(api_key)
+            .query_async(&mut conn)
+            .await?;
+        if removed > 0 {
+            self.reset_usage(api_key).await?;
+        }
+        Ok(removed > 0)
+    }
+
+    /// Reset the current period's usage for an API key
+    pub async fn reset_usage(&self, api_key: &str) -> Result<(), QuotaError> {
+        let now = Utc::now();
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("DEL")
+            .arg(QuotaPeriod::Daily.usage_key(api_key, now))
+            .arg(QuotaPeriod::Monthly.usage_key(api_key, now))
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Get the current usage of an API key's quota
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(None)` if the API key has no quota
+    /// * `Ok(Some(QuotaStatus))` with the current usage
+    pub async fn get_status(&self, api_key: &str) -> Result<Option<QuotaStatus>, QuotaError> {
+        let quota = match self.get_quota(api_key).await? {
+            Some(quota) => quota,
+            None => return Ok(None),
+        };
+
+        let now = Utc::now();
+        let mut conn = self.redis.get();
+        let (daily, monthly): (Option<u64>, Option<u64>) = redis::pipe()
+            .cmd("GET")
+            .arg(QuotaPeriod::Daily.usage_key(api_key, now))
+            .cmd("GET")
+            .arg(QuotaPeriod::Monthly.usage_key(api_key, now))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(Some(build_status(&quota, now, daily.unwrap_or(0), monthly.unwrap_or(0))))
+    }
+
+    /// Consume quota for a request
+    ///
+    /// # Arguments
+    ///
+    /// * `api_key` - The API key making the request
+    /// * `cost` - How much of the quota the request consumes
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(None)` if the API key has no quota
+    /// * `Ok(Some(QuotaStatus))` with the usage after this request if it was allowed
+    /// * `Err(QuotaError::ExceededQuota)` if the request doesn't fit in the quota; nothing is consumed
+    pub async fn consume(&self, api_key: &str, cost: u64) -> Result<Option<QuotaStatus>, QuotaError> {
+        let quota = match self.get_quota(api_key).await? {
+            Some(quota) => quota,
+            None => return Ok(None),
+        };
+
+        let now = Utc::now();
+        let expire_at = |period: QuotaPeriod| period.next_reset(now).timestamp() + USAGE_GRACE_SECONDS;
+        let mut conn = self.redis.get();
+        let (allowed, daily, monthly): (u8, u64, u64) = self.consume_script
+            .key(QuotaPeriod::Daily.usage_key(api_key, now))
+            .key(QuotaPeriod::Monthly.usage_key(api_key, now))
+            .arg(cost)
+            .arg(quota.daily_limit.map(|l| l as i64).unwrap_or(-1))
+            .arg(quota.monthly_limit.map(|l| l as i64).unwrap_or(-1))
+            .arg(expire_at(QuotaPeriod::Daily))
+            .arg(expire_at(QuotaPeriod::Monthly))
+            .invoke_async(&mut conn)
+            .await?;
+
+        let status = build_status(&quota, now, daily, monthly);
+        if allowed == 0 {
+            return Err(QuotaError::ExceededQuota(status));
+        }
+
+        Ok(Some(status))
+    }
+}
+
+fn build_status(quota: &Quota, now: DateTime<Utc>, daily_used: u64, monthly_used: u64) -> QuotaStatus {
+    let usage = |period: QuotaPeriod, limit: Option<u64>, used: u64| {
+        limit.map(|limit| QuotaUsage {
+            period,
+            limit,
+            used,
+            remaining: limit.saturating_sub(used),
+            resets_at: period.next_reset(now),
+        })
+    };
+
+    QuotaStatus {
+        api_key: quota.api_key.clone(),
+        daily: usage(QuotaPeriod::Daily, quota.daily_limit, daily_used),
+        monthly: usage(QuotaPeriod::Monthly, quota.monthly_limit, monthly_used),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_periods() {
+        let now = Utc.with_ymd_and_hms(2024, 12, 31, 15, 30, 0).unwrap();
+
+        assert_eq!(QuotaPeriod::Daily.period_id(now), "20241231");
+        assert_eq!(QuotaPeriod::Monthly.period_id(now), "202412");
+        assert_eq!(
+            QuotaPeriod::Daily.next_reset(now),
+            Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            QuotaPeriod::Monthly.next_reset(now),
+            Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()
+        );
+    }
+}
@@ -4,9 +4,14 @@
 //! storage for tracking request counts and implementing the token bucket algorithm.
 
 use redis::AsyncCommands;
-use crate::models::RateLimitConfig;
-use crate::utils::format_rate_limit_key;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Serialize;
+use crate::core::degradation::{Degradation, Subsystem};
+use crate::core::monitoring::{Monitoring, SystemMetrics};
+use crate::models::{AlertThresholds, FailurePolicy, RateLimitConfig};
 use thiserror::Error;
+use crate::core::redis_pool::{RedisConnection, RedisPool};
 
 /// Errors that can occur during rate limiting operations
 #[derive(Error, Debug)]
@@ -15,20 +20,202 @@ pub enum RateLimitError {
     RedisError(#[from] redis::RedisError),
     #[error("Rate limit exceeded")]
     ExceededLimit,
+    #[error("Client is banned: {0:?}")]
+    Banned(PenaltyState),
+    #[error("Rate limiting is unavailable while Redis is down")]
+    Unavailable,
+}
+
+/// Current penalty state of a repeat offender
+#[derive(Debug, Clone, Serialize)]
+pub struct PenaltyState {
+    /// Number of offenses within the offense window
+    pub offense_count: u32,
+    /// Seconds until the current ban expires
+    pub banned_for_seconds: u64,
 }
 
 /// Rate limiter implementation using Redis
 pub struct RateLimiter {
-    /// Redis connection manager
-    redis: redis::Client,
+    /// Redis connection pool
+    redis: RedisPool,
     /// Rate limit configuration
     config: RateLimitConfig,
+    /// Script counting a request against the current window
+    window_script: redis::Script,
+    /// Script reading the current window
+    status_script: redis::Script,
+    /// Redis outage handling
+    degradation: Option<Degradation>,
+}
+
+/// Atomically checks for a penalty ban, then increments the window counter,
+/// (re)applies its TTL and reports the remaining quota.
+///
+/// Setting the TTL whenever it is missing (rather than only on the first
+/// increment) also repairs counters left without an expiry. The limit is
+/// scaled by the shared adaptive load factor. A per-client override set by
+/// the rule engine caps the limit before scaling.
+///
+/// KEYS: see `window_script_keys`
+/// ARGV: cost, window seconds, base limit, adaptive load factor
+/// Returns: {count, remaining, reset seconds, offense count, effective limit},
+/// with count = -1 when banned
+const WINDOW_SCRIPT: &str = r#"
+local limit = tonumber(ARGV[3])
+local override = redis.call('GET', KEYS[4])
+if override then
+    limit = math.min(limit, tonumber(override) * tonumber(ARGV[2]))
+end
+local factor = tonumber(ARGV[4])
+if factor < 1 then
+    limit = math.max(1, math.floor(limit * factor))
+end
+
+local ban_ttl = redis.call('TTL', KEYS[2])
+if ban_ttl > 0 then
+    return {-1, 0, ban_ttl, tonumber(redis.call('GET', KEYS[3]) or '0'), limit}
+end
+
+local count = redis.call('INCRBY', KEYS[1], ARGV[1])
+local ttl = redis.call('TTL', KEYS[1])
+if ttl < 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+    ttl = tonumber(ARGV[2])
+end
+
+local remaining = limit - count
+if remaining < 0 then
+    remaining = 0
+end
+return {count, remaining, ttl, 0, limit}
+"#;
+
+/// Reads a key's window without counting a request
+///
+/// The limit is scaled as in `WINDOW_SCRIPT`.
+///
+/// KEYS: see `status_script_keys`
+/// ARGV: base limit, window seconds, adaptive load factor
+/// Returns: {count, remaining, reset seconds, ban seconds, effective limit}
+const STATUS_SCRIPT: &str = r#"
+local limit = tonumber(ARGV[1])
+local override = redis.call('GET', KEYS[3])
+if override then
+    limit = math.min(limit, tonumber(override) * tonumber(ARGV[2]))
+end
+local factor = tonumber(ARGV[3])
+if factor < 1 then
+    limit = math.max(1, math.floor(limit * factor))
+end
+
+local count = tonumber(redis.call('GET', KEYS[1]) or '0')
+local remaining = limit - count
+if remaining < 0 then
+    remaining = 0
+end
+return {count, remaining, math.max(redis.call('TTL', KEYS[1]), 0), math.max(redis.call('TTL', KEYS[2]), 0), limit}
+"#;
+
+/// Redis key holding the shared adaptive load factor
+///
+/// It lives in a different Redis Cluster slot than the client keys, so it is
+/// read on its own and passed to the scripts as an argument.
+const ADAPTIVE_FACTOR_KEY: &str = "rate_limit:adaptive_factor";
+
+/// Redis key holding one part of a client's rate limiting state
+///
+/// The client key is the hash tag, so all of a client's keys share a Redis
+/// Cluster slot and the scripts can touch them together.
+fn client_state_key(key: &str, part: &str) -> String {
+    format!("rate_limit:{{{}}}:{}", key, part)
+}
+
+/// Redis key counting a client's current window
+pub fn window_key(key: &str) -> String {
+    client_state_key(key, "window")
+}
+
+/// Redis key of a client's penalty ban
+fn ban_key(key: &str) -> String {
+    client_state_key(key, "ban")
+}
+
+/// Redis key counting a client's offenses within the offense window
+fn offenses_key(key: &str) -> String {
+    client_state_key(key, "offenses")
+}
+
+/// Redis key holding a client's requests-per-second cap set by a `RateLimit` rule action
+pub fn limit_override_key(key: &str) -> String {
+    client_state_key(key, "override")
+}
+
+/// KEYS of `WINDOW_SCRIPT`: window counter, ban key, offense counter, limit override
+fn window_script_keys(key: &str) -> [String; 4] {
+    [window_key(key), ban_key(key), offenses_key(key), limit_override_key(key)]
+}
+
+/// KEYS of `STATUS_SCRIPT`: window counter, ban key, limit override
+fn status_script_keys(key: &str) -> [String; 3] {
+    [window_key(key), ban_key(key), limit_override_key(key)]
+}
+
+/// Effective limit state when adaptive limits are in use
+#[derive(Debug, Clone, Serialize)]
+pub struct EffectiveLimit {
+    /// Whether adaptive limits are enabled
+    pub adaptive: bool,
+    /// Configured limit
+    pub base_limit: u32,
+    /// Current load factor (1.0 = no reduction)
+    pub factor: f64,
+    /// Limit currently enforced
+    pub effective_limit: u32,
+}
+
+/// Usage of a key's current window, read without counting a request
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitUsage {
+    /// Limit enforced for this window (scaled down under load)
+    pub limit: u32,
+    /// Cost counted in the current window
+    pub count: u32,
+    /// Quota remaining in the current window, in cost units
+    pub remaining: u32,
+    /// Seconds until the current window resets
+    pub reset: u64,
+    /// Seconds left of a penalty ban, 0 if not banned
+    pub banned_for_seconds: u64,
+}
+
+/// Quota left after an allowed request
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitStatus {
+    /// Limit enforced for this window (scaled down under load)
+    pub limit: u32,
+    /// Quota remaining in the current window, in cost units
+    pub remaining: u32,
+    /// Seconds until the current window resets
+    pub reset: u64,
 }
 
 impl RateLimiter {
     /// Create a new rate limiter instance
-    pub fn new(redis: redis::Client, config: RateLimitConfig) -> Self {
-        Self { redis, config }
+    pub fn new(redis: RedisPool, config: RateLimitConfig) -> Self {
+        Self {
+            redis,
+            config,
+            window_script: redis::Script::new(WINDOW_SCRIPT),
+            status_script: redis::Script::new(STATUS_SCRIPT),
+            degradation: None,
+        }
+    }
+
+    /// Keep limiting while Redis is down, as the degradation policy says
+    pub fn with_degradation(mut self, degradation: Degradation) -> Self {
+        self.degradation = Some(degradation);
+        self
     }
 
     /// Check if a request should be rate limited
@@ -36,51 +223,253 @@ impl RateLimiter {
     /// # Arguments
     /// 
     /// * `key` - The key to rate limit (e.g., IP address or user ID)
+    /// * `cost` - How much of the quota this request consumes (see `RateLimitConfig::cost_for_path`)
+    /// * `limit` - Requests allowed per window before scaling (see `RateLimitConfig::limit_for`)
     /// 
     /// # Returns
     /// 
-    /// * `Ok(())` if the request should be allowed
+    /// * `Ok(RateLimitStatus)` with the remaining quota if the request should be allowed
     /// * `Err(RateLimitError::ExceededLimit)` if the rate limit has been exceeded
+    /// * `Err(RateLimitError::Banned)` if the client is serving a penalty ban
+    /// * `Err(RateLimitError::Unavailable)` if Redis is down and the policy is to fail closed
     /// * `Err(RateLimitError::RedisError)` if there was an error communicating with Redis
-    pub async fn check_rate_limit(&mut self, key: &str) -> Result<(), RateLimitError> {
-        let window_key = format_rate_limit_key("rate_limit", key);
-        let mut conn = match self.redis.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(RateLimitError::RedisError(e)),
-        };
-        
-        let count: u32 = match conn.incr(&window_key, 1).await {
-            Ok(count) => count,
-            Err(e) => return Err(RateLimitError::RedisError(e)),
+    pub async fn check_rate_limit(&self, key: &str, cost: u32, limit: u32) -> Result<RateLimitStatus, RateLimitError> {
+        if let Some(degradation) = &self.degradation {
+            if let Some(policy) = degradation.active_policy(Subsystem::RateLimit).await {
+                return self.check_without_redis(degradation, policy, key, cost, limit).await;
+            }
+        }
+        let mut conn = self.redis.get();
+
+        let result = match self.adaptive_factor(&mut conn).await {
+            Ok(factor) => self.window_script
+                .key(&window_script_keys(key)[..])
+                .arg(cost)
+                .arg(self.config.window_seconds)
+                .arg(limit)
+                .arg(factor)
+                .invoke_async(&mut conn)
+                .await,
+            Err(e) => Err(e),
         };
-        
-        if count == 1 {
-            let _: () = match conn.expire::<_, ()>(&window_key, self.config.window_seconds as usize).await {
-                Ok(_) => (),
-                Err(e) => return Err(RateLimitError::RedisError(e)),
+        let (count, remaining, reset, offense_count, limit): (i64, i64, i64, u32, u32) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    if let Some(degradation) = &self.degradation {
+                        if let Some(policy) = degradation.fail(Subsystem::RateLimit, &e).await {
+                            return self.check_without_redis(degradation, policy, key, cost, limit).await;
+                        }
+                    }
+                    return Err(RateLimitError::RedisError(e));
+                }
             };
+
+        // A negative count means the script found an active ban and didn't count the request
+        if count < 0 {
+            return Err(RateLimitError::Banned(PenaltyState {
+                offense_count,
+                banned_for_seconds: reset.max(0) as u64,
+            }));
         }
 
-        if count > self.config.default_limit {
+        if count > limit as i64 {
+            // Shadow mode must not leave bans behind that would be enforced once it is turned off
+            if self.config.penalty.enabled && !self.config.shadow {
+                let penalty = self.apply_penalty(&mut conn, key).await?;
+                return Err(RateLimitError::Banned(penalty));
+            }
             return Err(RateLimitError::ExceededLimit);
         }
 
-        Ok(())
+        Ok(RateLimitStatus {
+            limit,
+            remaining: remaining.max(0) as u32,
+            reset: reset.max(0) as u64,
+        })
+    }
+
+    /// Check a request while Redis is down
+    ///
+    /// With the fallback policy, the request is counted against a fixed
+    /// window kept in memory, without adaptive scaling or penalties.
+    async fn check_without_redis(
+        &self,
+        degradation: &Degradation,
+        policy: FailurePolicy,
+        key: &str,
+        cost: u32,
+        limit: u32,
+    ) -> Result<RateLimitStatus, RateLimitError> {
+        match policy {
+            FailurePolicy::Fallback => degradation
+                .count_request(key, cost, limit, self.config.window_seconds as u64)
+                .await
+                .ok_or(RateLimitError::ExceededLimit),
+            FailurePolicy::Open => Ok(RateLimitStatus {
+                limit,
+                remaining: limit,
+                reset: 0,
+            }),
+            FailurePolicy::Closed => Err(RateLimitError::Unavailable),
+        }
+    }
+
+    /// Whether rejections are only logged rather than enforced
+    pub fn shadow_enabled(&self) -> bool {
+        self.config.shadow
+    }
+
+    /// Record an offense and ban the client for the escalated duration
+    ///
+    /// Banned clients are rejected by the window script before their counter
+    /// is touched, so an offense is only recorded once per ban.
+    async fn apply_penalty(
+        &self,
+        conn: &mut RedisConnection,
+        key: &str,
+    ) -> Result<PenaltyState, RateLimitError> {
+        let offense_key = offenses_key(key);
+        let ban_key = ban_key(key);
+
+        let (offense_count,): (u32,) = redis::pipe()
+            .atomic()
+            .cmd("INCR")
+            .arg(&offense_key)
+            .cmd("EXPIRE")
+            .arg(&offense_key)
+            .arg(self.config.penalty.offense_window_seconds)
+            .ignore()
+            .query_async(conn)
+            .await?;
+
+        let duration = self.config.penalty.ban_duration(offense_count).unwrap_or(0);
+        if duration > 0 {
+            let _: () = redis::cmd("SET")
+                .arg(&ban_key)
+                .arg(offense_count)
+                .arg("EX")
+                .arg(duration)
+                .query_async(conn)
+                .await?;
+            log::warn!(
+                "Banning {} for {}s after {} rate limit offenses",
+                key, duration, offense_count
+            );
+        }
+
+        Ok(PenaltyState {
+            offense_count,
+            banned_for_seconds: duration,
+        })
+    }
+
+    /// Whether limits scale with system load
+    pub fn adaptive_enabled(&self) -> bool {
+        self.config.adaptive.enabled
+    }
+
+    /// Current adaptive load factor, 1.0 when adaptive limits are disabled
+    async fn adaptive_factor(&self, conn: &mut RedisConnection) -> Result<f64, redis::RedisError> {
+        if !self.config.adaptive.enabled {
+            return Ok(1.0);
+        }
+        let factor: Option<f64> = conn.get(ADAPTIVE_FACTOR_KEY).await?;
+        Ok(factor.unwrap_or(1.0))
+    }
+
+    /// Get the currently enforced default limit
+    pub async fn get_effective_limit(&self) -> Result<EffectiveLimit, RateLimitError> {
+        let factor = self.adaptive_factor(&mut self.redis.get()).await?;
+
+        Ok(EffectiveLimit {
+            adaptive: self.config.adaptive.enabled,
+            base_limit: self.config.default_limit,
+            factor,
+            effective_limit: ((self.config.default_limit as f64 * factor).floor() as u32).max(1),
+        })
+    }
+
+    /// Adjust the shared load factor from the latest system metrics
+    ///
+    /// The factor drops multiplicatively while CPU, memory or error rate are
+    /// above their alert thresholds and recovers additively afterwards.
+    pub async fn update_adaptive_factor(
+        &self,
+        metrics: &SystemMetrics,
+        thresholds: &AlertThresholds,
+    ) -> Result<f64, RateLimitError> {
+        let mut conn = self.redis.get();
+        let current: Option<f64> = conn.get(ADAPTIVE_FACTOR_KEY).await?;
+
+        let overloaded = metrics.cpu_usage > thresholds.cpu_usage
+            || metrics.memory_usage > thresholds.memory_usage
+            || metrics.error_rate > thresholds.error_rate as f64;
+        let factor = self.config.adaptive.next_factor(current.unwrap_or(1.0), overloaded);
+
+        let _: () = conn.set(ADAPTIVE_FACTOR_KEY, factor).await?;
+        if overloaded {
+            log::warn!("System under load, scaling rate limits to {:.0}%", factor * 100.0);
+        }
+
+        Ok(factor)
+    }
+
+    /// Periodically adjust rate limits to the load reported by monitoring
+    pub async fn start_adaptive_limits(
+        &self,
+        monitoring: Arc<Monitoring>,
+        thresholds: AlertThresholds,
+    ) -> Result<(), RateLimitError> {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.adaptive.interval_seconds));
+
+        loop {
+            interval.tick().await;
+            match monitoring.get_current_metrics().await {
+                Ok(metrics) => {
+                    if let Err(e) = self.update_adaptive_factor(&metrics, &thresholds).await {
+                        log::error!("Failed to update adaptive rate limit: {}", e);
+                    }
+                }
+                Err(e) => log::error!("Failed to get system metrics for adaptive rate limit: {}", e),
+            }
+        }
+    }
+
+    /// Get the penalty state for a given key
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(None)` if the client has no recorded offenses
+    /// * `Ok(Some(PenaltyState))` with the offense count and remaining ban time (0 if not banned)
+    pub async fn get_penalty(&self, key: &str) -> Result<Option<PenaltyState>, RateLimitError> {
+        let mut conn = self.redis.get();
+
+        let (offense_count, ban_ttl): (Option<u32>, i64) = redis::pipe()
+            .cmd("GET")
+            .arg(offenses_key(key))
+            .cmd("TTL")
+            .arg(ban_key(key))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(offense_count.map(|offense_count| PenaltyState {
+            offense_count,
+            banned_for_seconds: ban_ttl.max(0) as u64,
+        }))
     }
 
     /// Reset the rate limit for a given key
     /// 
+    /// This also lifts any penalty ban and forgets previous offenses.
+    /// 
     /// # Arguments
     /// 
     /// * `key` - The key to reset the rate limit for
-    pub async fn reset_rate_limit(&mut self, key: &str) -> Result<(), RateLimitError> {
-        let window_key = format_rate_limit_key("rate_limit", key);
-        let mut conn = match self.redis.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(RateLimitError::RedisError(e)),
-        };
+    pub async fn reset_rate_limit(&self, key: &str) -> Result<(), RateLimitError> {
+        let mut conn = self.redis.get();
         
-        let _: () = match conn.del::<_, ()>(&window_key).await {
+        let keys = [window_key(key), offenses_key(key), ban_key(key)];
+        let _: () = match conn.del::<_, ()>(&keys[..]).await {
             Ok(_) => (),
             Err(e) => return Err(RateLimitError::RedisError(e)),
         };
@@ -88,32 +477,48 @@ impl RateLimiter {
         Ok(())
     }
 
-    pub async fn get_remaining(&self, key: &str) -> i64 {
-        let mut conn = match self.redis.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(_) => return 0,
-        };
+    /// Get the usage of a key's current window without counting a request
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to report on
+    /// * `limit` - Requests allowed per window before scaling, as for `check_rate_limit`
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(RateLimitUsage)` with the count, remaining quota and reset of the window
+    /// * `Err(RateLimitError::Unavailable)` if Redis is down and limiting runs without it
+    /// * `Err(RateLimitError::RedisError)` if there was an error communicating with Redis
+    pub async fn get_usage(&self, key: &str, limit: u32) -> Result<RateLimitUsage, RateLimitError> {
+        if let Some(degradation) = &self.degradation {
+            if degradation.active_policy(Subsystem::RateLimit).await.is_some() {
+                return Err(RateLimitError::Unavailable);
+            }
+        }
+        let mut conn = self.redis.get();
 
-        let current: i64 = match redis::cmd("GET")
-            .arg(format!("rate_limit:{}", key))
-            .query_async(&mut conn)
-            .await {
-                Ok(count) => count,
-                Err(_) => return self.config.default_limit as i64,
-            };
+        let factor = self.adaptive_factor(&mut conn).await?;
+        let (count, remaining, reset, banned_for_seconds, limit): (i64, i64, i64, i64, u32) = self.status_script
+            .key(&status_script_keys(key)[..])
+            .arg(limit)
+            .arg(self.config.window_seconds)
+            .arg(factor)
+            .invoke_async(&mut conn)
+            .await?;
 
-        (self.config.default_limit as i64) - current
+        Ok(RateLimitUsage {
+            limit,
+            count: count.max(0) as u32,
+            remaining: remaining.max(0) as u32,
+            reset: reset.max(0) as u64,
+            banned_for_seconds: banned_for_seconds.max(0) as u64,
+        })
     }
 
     pub async fn get_reset_time(&self, key: &str) -> Result<u64, RateLimitError> {
-        let mut conn = match self.redis.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(RateLimitError::RedisError(e)),
-        };
-        let window_key = format!("rate_limit:{}", key);
-        
+        let mut conn = self.redis.get();
         let ttl: i64 = match redis::cmd("TTL")
-            .arg(&window_key)
+            .arg(window_key(key))
             .query_async(&mut conn)
             .await {
                 Ok(ttl) => ttl,
@@ -127,6 +532,9 @@ impl RateLimiter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::geoip::GeoInfo;
+    use crate::core::redis_pool::key_slot;
+    use crate::models::{AdaptiveLimitConfig, PenaltyPolicy};
     use redis::Client;
 
     #[tokio::test]
@@ -138,24 +546,158 @@ mod tests {
             default_limit: 2,
             burst_size: 3,
             window_seconds: 60,
+            path_costs: Default::default(),
+            geo_limits: Default::default(),
+            penalty: PenaltyPolicy {
+                enabled: false,
+                ..Default::default()
+            },
+            headers: Default::default(),
+            adaptive: Default::default(),
+            shadow: false,
         };
         
-        let limiter = RateLimiter::new(redis, config);
+        let limiter = RateLimiter::new(RedisPool::from(redis), config);
         
         // First request should succeed
-        assert!(limiter.check_rate_limit("test_key").await.is_ok());
+        assert!(limiter.check_rate_limit("test_key", 1, 2).await.is_ok());
         
+        // Reading the usage doesn't count a request
+        assert_eq!(limiter.get_usage("test_key", 2).await.unwrap().remaining, 1);
+        assert_eq!(limiter.get_usage("test_key", 2).await.unwrap().count, 1);
+
         // Second request should succeed
-        assert!(limiter.check_rate_limit("test_key").await.is_ok());
+        assert!(limiter.check_rate_limit("test_key", 1, 2).await.is_ok());
         
         // Third request should fail
         assert!(matches!(
-            limiter.check_rate_limit("test_key").await,
+            limiter.check_rate_limit("test_key", 1, 2).await,
             Err(RateLimitError::ExceededLimit)
         ));
         
         // Reset should allow new requests
         limiter.reset_rate_limit("test_key").await.unwrap();
-        assert!(limiter.check_rate_limit("test_key").await.is_ok());
+        assert!(limiter.check_rate_limit("test_key", 1, 2).await.is_ok());
+    }
+
+    #[test]
+    fn test_cost_for_path() {
+        let mut config = RateLimitConfig {
+            default_limit: 100,
+            burst_size: 200,
+            window_seconds: 60,
+            path_costs: Default::default(),
+            geo_limits: Default::default(),
+            penalty: Default::default(),
+            headers: Default::default(),
+            adaptive: Default::default(),
+            shadow: false,
+        };
+        config.path_costs.insert("/api/search".to_string(), 5);
+        config.path_costs.insert("/api/search/export".to_string(), 20);
+
+        assert_eq!(config.cost_for_path("/api/items"), 1);
+        assert_eq!(config.cost_for_path("/api/search?q=x"), 5);
+        assert_eq!(config.cost_for_path("/api/search/export"), 20);
+        // Prefixes match whole segments only
+        assert_eq!(config.cost_for_path("/api/searches"), 1);
+        assert_eq!(config.cost_for_path("/api/search/exports"), 5);
+    }
+
+    #[test]
+    fn test_limit_for() {
+        let mut config = RateLimitConfig {
+            default_limit: 100,
+            burst_size: 200,
+            window_seconds: 60,
+            path_costs: Default::default(),
+            geo_limits: Default::default(),
+            penalty: Default::default(),
+            headers: Default::default(),
+            adaptive: Default::default(),
+            shadow: false,
+        };
+        config.geo_limits.countries.insert("DE".to_string(), 200);
+        config.geo_limits.asns.insert("AS16509".to_string(), 10);
+        let geo = |country: Option<&str>, asn: Option<u32>| GeoInfo {
+            country: country.map(String::from),
+            asn,
+            as_org: None,
+        };
+
+        assert_eq!(config.limit_for(&geo(None, None)), 100);
+        assert_eq!(config.limit_for(&geo(Some("FR"), Some(3215))), 100);
+        assert_eq!(config.limit_for(&geo(Some("de"), Some(3320))), 200);
+        // ASN limits take precedence over country limits
+        assert_eq!(config.limit_for(&geo(Some("DE"), Some(16509))), 10);
+    }
+
+    #[test]
+    fn test_penalty_escalation() {
+        let policy = PenaltyPolicy {
+            enabled: true,
+            ban_durations_seconds: vec![60, 600, 3600],
+            offense_window_seconds: 86400,
+        };
+
+        assert_eq!(policy.ban_duration(1), Some(60));
+        assert_eq!(policy.ban_duration(2), Some(600));
+        assert_eq!(policy.ban_duration(3), Some(3600));
+        // Further offenses keep the longest ban
+        assert_eq!(policy.ban_duration(10), Some(3600));
+
+        let no_bans = PenaltyPolicy {
+            ban_durations_seconds: vec![],
+            ..policy
+        };
+        assert_eq!(no_bans.ban_duration(1), None);
+    }
+
+    #[test]
+    fn test_cluster_slots() {
+        // Highest KEYS index a script uses
+        let keys_used = |script: &str| {
+            script
+                .split("KEYS[")
+                .skip(1)
+                .filter_map(|rest| rest.split(']').next()?.parse::<usize>().ok())
+                .max()
+                .unwrap_or(0)
+        };
+
+        for key in ["192.0.2.1", "2001:db8::/64", "tenant:shop-eu:192.0.2.1", "api{key}"] {
+            let window_keys = window_script_keys(key);
+            let status_keys = status_script_keys(key);
+            assert_eq!(window_keys.len(), keys_used(WINDOW_SCRIPT));
+            assert_eq!(status_keys.len(), keys_used(STATUS_SCRIPT));
+
+            // Every key a script touches must be in the slot it is sent to
+            let slot = key_slot(window_keys[0].as_bytes());
+            for script_key in window_keys.iter().chain(&status_keys) {
+                assert_eq!(key_slot(script_key.as_bytes()), slot, "{}", script_key);
+            }
+            assert!(!window_keys.contains(&ADAPTIVE_FACTOR_KEY.to_string()));
+        }
+        assert_ne!(key_slot(window_key("192.0.2.1").as_bytes()), key_slot(window_key("192.0.2.2").as_bytes()));
+    }
+
+    #[test]
+    fn test_adaptive_factor() {
+        let config = AdaptiveLimitConfig {
+            enabled: true,
+            min_factor: 0.25,
+            decrease_factor: 0.5,
+            recovery_step: 0.1,
+            interval_seconds: 10,
+        };
+
+        // Scales down quickly under load, but never below the floor
+        assert_eq!(config.next_factor(1.0, true), 0.5);
+        assert_eq!(config.next_factor(0.5, true), 0.25);
+        assert_eq!(config.next_factor(0.25, true), 0.25);
+
+        // Recovers gradually and caps at the configured limit
+        assert!((config.next_factor(0.25, false) - 0.35).abs() < f64::EPSILON);
+        assert_eq!(config.next_factor(0.95, false), 1.0);
     }
 } 
\ No newline at end of file
@@ -0,0 +1,891 @@
+//! Shared Redis connection pool for the DDoS protection service.
+//!
+//! This module provides a small pool of multiplexed, auto-reconnecting
+//! Redis connections that is shared by all core components, so that
+//! individual operations no longer open a new TCP connection each time.
+//!
+//! The pool hides the Redis topology (`redis.topology`) from the cores:
+//!
+//! - `standalone`: connections to `redis.url`.
+//! - `sentinel`: connections to the master the sentinels name. When a
+//!   command fails in a way that suggests a failover (the connection drops,
+//!   or the server has become a read-only replica), the master is looked up
+//!   again, the connections replaced, and the command sent once more.
+//! - `cluster`: a connection to each master, with every command sent to the
+//!   node owning its key's slot. `MOVED` redirections refresh the slot
+//!   layout and `ASK` redirections are followed. Pipelines are split by
+//!   node, and the part sent to a node that redirects it, or can't be
+//!   reached, is resent once the layout is refreshed. Multi-key commands and
+//!   transactions need their keys in one slot, through a `{hash tag}`.
+//!   `KEYS`, `SCAN` and `SCRIPT` go to every master.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use log::{info, warn};
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::{Arg, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, Pipeline, RedisConnectionInfo, RedisError, RedisFuture, RedisResult, Value};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use crate::models::{RedisConfig, RedisTopology};
+
+/// Number of hash slots in a Redis Cluster
+const CLUSTER_SLOTS: u16 = 16384;
+/// Attempts at a cluster command, across redirections and unavailability
+const CLUSTER_ATTEMPTS: u32 = 5;
+/// Delay before retrying a cluster command the cluster couldn't serve
+const CLUSTER_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Low bits of a cluster `SCAN` cursor holding the index of the node being scanned
+const SCAN_NODE_BITS: u32 = 10;
+/// How long a sentinel, or the master it names, gets to answer
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Redis connection pool statistics
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+    /// Number of connections in the pool
+    pub size: usize,
+    /// Total number of connection checkouts since startup
+    pub checkouts: u64,
+    /// How the Redis deployment is laid out
+    pub topology: RedisTopology,
+    /// Servers connected to: the master with Sentinel, every master with Cluster
+    pub nodes: Vec<String>,
+}
+
+/// Pool of Redis connections handed out round-robin
+///
+/// Each `ConnectionManager` multiplexes concurrent commands over a single
+/// connection and reconnects on failure, so checking one out never blocks
+/// and cloning the pool is cheap.
+#[derive(Clone)]
+pub struct RedisPool {
+    /// Pooled connections
+    backend: Arc<Backend>,
+    /// Index of the next connection to hand out
+    next: Arc<AtomicUsize>,
+    /// Total number of checkouts
+    checkouts: Arc<AtomicU64>,
+}
+
+enum Backend {
+    Standalone {
+        client: Option<redis::Client>,
+        connections: Vec<ConnectionManager>,
+    },
+    Sentinel(Arc<SentinelMaster>),
+    Cluster(Arc<Cluster>),
+}
+
+impl RedisPool {
+    /// Create a new pool with `pool_size` connections (at least one) to a single server
+    pub async fn new(client: redis::Client, pool_size: u32) -> redis::RedisResult<Self> {
+        let size = pool_size.max(1) as usize;
+        let mut connections = Vec::with_capacity(size);
+        for _ in 0..size {
+            connections.push(client.get_connection_manager().await?);
+        }
+
+        metrics::gauge!("redis_pool_size", size as f64);
+
+        Ok(Self::from_backend(Backend::Standalone {
+            client: Some(client),
+            connections,
+        }))
+    }
+
+    /// Connect to Redis as laid out in the configuration
+    pub async fn connect(config: &RedisConfig) -> RedisResult<Self> {
+        let backend = match config.topology {
+            RedisTopology::Standalone => {
+                return Self::new(redis::Client::open(config.url.as_str())?, config.pool_size).await;
+            }
+            RedisTopology::Sentinel => Backend::Sentinel(Arc::new(SentinelMaster::connect(config).await?)),
+            RedisTopology::Cluster => Backend::Cluster(Arc::new(Cluster::connect(config).await?)),
+        };
+        let pool = Self::from_backend(backend);
+        metrics::gauge!("redis_pool_size", pool.stats().size as f64);
+        Ok(pool)
+    }
+
+    fn from_backend(backend: Backend) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            next: Arc::new(AtomicUsize::new(0)),
+            checkouts: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Check out a connection from the pool
+    pub fn get(&self) -> RedisConnection {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        metrics::increment_counter!("redis_pool_checkouts_total");
+
+        let inner = match &*self.backend {
+            Backend::Standalone { connections, .. } => {
+                Connection::Standalone(connections[index % connections.len()].clone())
+            }
+            Backend::Sentinel(master) => {
+                let (connection, generation) = master.connection(index);
+                Connection::Sentinel(SentinelConnection {
+                    master: master.clone(),
+                    connection,
+                    generation,
+                    index,
+                })
+            }
+            Backend::Cluster(cluster) => Connection::Cluster(cluster.clone()),
+        };
+        RedisConnection { inner }
+    }
+
+    /// Open a pub/sub connection
+    ///
+    /// With Sentinel, this is to the current master; with Cluster, to any
+    /// node, as messages are published cluster-wide.
+    pub async fn pubsub(&self) -> RedisResult<redis::aio::PubSub> {
+        let client = match &*self.backend {
+            Backend::Standalone { client: Some(client), .. } => client.clone(),
+            Backend::Standalone { client: None, .. } => {
+                return Err((ErrorKind::InvalidClientConfig, "pool was built from a connection").into());
+            }
+            Backend::Sentinel(master) => redis::Client::open(master.discover().await?)?,
+            Backend::Cluster(cluster) => redis::Client::open(cluster.node_info(&cluster.any_node()?.0)?)?,
+        };
+        Ok(client.get_async_connection().await?.into_pubsub())
+    }
+
+    /// Get pool statistics
+    pub fn stats(&self) -> PoolStats {
+        let (size, topology, nodes) = match &*self.backend {
+            Backend::Standalone { client, connections } => (
+                connections.len(),
+                RedisTopology::Standalone,
+                client.iter().map(|client| client.get_connection_info().addr.to_string()).collect(),
+            ),
+            Backend::Sentinel(master) => {
+                let current = master.current();
+                (current.connections.len(), RedisTopology::Sentinel, vec![current.address.clone()])
+            }
+            Backend::Cluster(cluster) => {
+                let nodes: Vec<String> = cluster.masters().into_iter().map(|(address, _)| address).collect();
+                (nodes.len(), RedisTopology::Cluster, nodes)
+            }
+        };
+        PoolStats {
+            size,
+            checkouts: self.checkouts.load(Ordering::Relaxed),
+            topology,
+            nodes,
+        }
+    }
+}
+
+impl From<ConnectionManager> for RedisPool {
+    fn from(connection: ConnectionManager) -> Self {
+        Self::from_backend(Backend::Standalone {
+            client: None,
+            connections: vec![connection],
+        })
+    }
+}
+
+/// Connection checked out of a [`RedisPool`]
+#[derive(Clone)]
+pub struct RedisConnection {
+    inner: Connection,
+}
+
+#[derive(Clone)]
+enum Connection {
+    Standalone(ConnectionManager),
+    Sentinel(SentinelConnection),
+    Cluster(Arc<Cluster>),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match &mut self.inner {
+            Connection::Standalone(connection) => connection.req_packed_command(cmd),
+            Connection::Sentinel(connection) => Box::pin(connection.query(cmd)),
+            Connection::Cluster(cluster) => Box::pin(cluster.query(cmd)),
+        }
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, pipeline: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>> {
+        match &mut self.inner {
+            Connection::Standalone(connection) => connection.req_packed_commands(pipeline, offset, count),
+            Connection::Sentinel(connection) => Box::pin(connection.query_pipeline(pipeline, offset, count)),
+            Connection::Cluster(cluster) => Box::pin(cluster.query_pipeline(pipeline, offset, count)),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match &self.inner {
+            Connection::Standalone(connection) => connection.get_db(),
+            Connection::Sentinel(connection) => connection.connection.get_db(),
+            // Clusters only have database 0
+            Connection::Cluster(_) => 0,
+        }
+    }
+}
+
+/// Whether an error suggests the master has changed
+fn is_failover(error: &RedisError) -> bool {
+    error.is_io_error() || matches!(error.kind(), ErrorKind::ReadOnly | ErrorKind::MasterDown)
+}
+
+/// Master found through Sentinel, and the pool's connections to it
+struct SentinelMaster {
+    sentinels: Vec<ConnectionInfo>,
+    master_name: String,
+    /// Credentials and database used with the master
+    redis: RedisConnectionInfo,
+    pool_size: usize,
+    current: RwLock<Arc<Master>>,
+    /// Held while the master is looked up again, so that concurrent failures look it up once
+    lookup: Mutex<()>,
+}
+
+struct Master {
+    address: String,
+    connections: Vec<ConnectionManager>,
+    /// Incremented every time the connections are replaced
+    generation: u64,
+}
+
+impl SentinelMaster {
+    async fn connect(config: &RedisConfig) -> RedisResult<Self> {
+        if config.sentinels.is_empty() {
+            return Err((ErrorKind::InvalidClientConfig, "redis.sentinels is empty").into());
+        }
+        let sentinels = config
+            .sentinels
+            .iter()
+            .map(|url| url.as_str().into_connection_info())
+            .collect::<RedisResult<Vec<_>>>()?;
+        let mut master = Self {
+            sentinels,
+            master_name: config.master_name.clone(),
+            redis: config.url.as_str().into_connection_info()?.redis,
+            pool_size: config.pool_size.max(1) as usize,
+            current: RwLock::new(Arc::new(Master {
+                address: String::new(),
+                connections: Vec::new(),
+                generation: 0,
+            })),
+            lookup: Mutex::new(()),
+        };
+        let current = master.open(0).await?;
+        info!("Connected to Redis master {} through Sentinel", current.address);
+        master.current = RwLock::new(Arc::new(current));
+        Ok(master)
+    }
+
+    fn current(&self) -> Arc<Master> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// The `index`th connection to the current master, with its generation
+    fn connection(&self, index: usize) -> (ConnectionManager, u64) {
+        let current = self.current();
+        (current.connections[index % current.connections.len()].clone(), current.generation)
+    }
+
+    /// Ask the sentinels in turn where the master is
+    async fn discover(&self) -> RedisResult<ConnectionInfo> {
+        let mut last_error = None;
+        for sentinel in &self.sentinels {
+            let error = match tokio::time::timeout(DISCOVERY_TIMEOUT, self.ask(sentinel)).await {
+                Ok(Ok(master)) => return Ok(master),
+                Ok(Err(e)) => e,
+                Err(_) => (ErrorKind::IoError, "sentinel timed out").into(),
+            };
+            warn!("Sentinel {} couldn't name the Redis master: {}", sentinel.addr, error);
+            last_error = Some(error);
+        }
+        Err(last_error.unwrap_or_else(|| (ErrorKind::InvalidClientConfig, "no sentinels").into()))
+    }
+
+    async fn ask(&self, sentinel: &ConnectionInfo) -> RedisResult<ConnectionInfo> {
+        let mut conn = redis::Client::open(sentinel.clone())?.get_async_connection().await?;
+        let address: Option<(String, u16)> = redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(&self.master_name)
+            .query_async(&mut conn)
+            .await?;
+        let (host, port) = address
+            .ok_or_else(|| RedisError::from((ErrorKind::ResponseError, "unknown master", self.master_name.clone())))?;
+        let master = ConnectionInfo {
+            addr: ConnectionAddr::Tcp(host, port),
+            redis: self.redis.clone(),
+        };
+
+        // A sentinel that hasn't noticed a failover yet can name a replica
+        let mut conn = redis::Client::open(master.clone())?.get_async_connection().await?;
+        let role: Vec<Value> = redis::cmd("ROLE").query_async(&mut conn).await?;
+        match role.first() {
+            Some(Value::Data(role)) if role == b"master" => Ok(master),
+            _ => Err((ErrorKind::ResponseError, "named server isn't a master", master.addr.to_string()).into()),
+        }
+    }
+
+    async fn open(&self, generation: u64) -> RedisResult<Master> {
+        let master = self.discover().await?;
+        let client = redis::Client::open(master.clone())?;
+        let mut connections = Vec::with_capacity(self.pool_size);
+        for _ in 0..self.pool_size {
+            connections.push(client.get_connection_manager().await?);
+        }
+        Ok(Master {
+            address: master.addr.to_string(),
+            connections,
+            generation,
+        })
+    }
+
+    /// Replace the connections of `generation` with ones to the current
+    /// master, and return the `index`th connection
+    async fn failover(&self, generation: u64, index: usize) -> RedisResult<(ConnectionManager, u64)> {
+        let _lookup = self.lookup.lock().await;
+        let previous = self.current();
+        // Connections already replaced after another failure
+        if previous.generation != generation {
+            return Ok(self.connection(index));
+        }
+
+        let current = self.open(generation + 1).await?;
+        if current.address != previous.address {
+            warn!("Redis master moved from {} to {}", previous.address, current.address);
+        }
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(current);
+        Ok(self.connection(index))
+    }
+}
+
+/// Connection to a master found through Sentinel
+#[derive(Clone)]
+struct SentinelConnection {
+    master: Arc<SentinelMaster>,
+    connection: ConnectionManager,
+    generation: u64,
+    index: usize,
+}
+
+impl SentinelConnection {
+    async fn query(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        match self.connection.req_packed_command(cmd).await {
+            Err(e) if is_failover(&e) => {
+                self.failover(&e).await?;
+                self.connection.req_packed_command(cmd).await
+            }
+            result => result,
+        }
+    }
+
+    async fn query_pipeline(&mut self, pipeline: &Pipeline, offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        match self.connection.req_packed_commands(pipeline, offset, count).await {
+            Err(e) if is_failover(&e) => {
+                self.failover(&e).await?;
+                self.connection.req_packed_commands(pipeline, offset, count).await
+            }
+            result => result,
+        }
+    }
+
+    async fn failover(&mut self, error: &RedisError) -> RedisResult<()> {
+        warn!("Redis command failed ({}), looking the master up again", error);
+        (self.connection, self.generation) = self.master.failover(self.generation, self.index).await?;
+        Ok(())
+    }
+}
+
+/// Slot of a key in a Redis Cluster
+///
+/// Only the part between the first `{` and the following `}` is hashed, if
+/// not empty, so keys sharing that tag share a slot.
+pub(crate) fn key_slot(key: &[u8]) -> u16 {
+    let tagged = key
+        .iter()
+        .position(|&byte| byte == b'{')
+        .and_then(|open| {
+            let tag = &key[open + 1..];
+            tag.iter().position(|&byte| byte == b'}').map(|close| &tag[..close])
+        })
+        .filter(|tag| !tag.is_empty());
+    crc16(tagged.unwrap_or(key)) % CLUSTER_SLOTS
+}
+
+/// CRC-16/XMODEM, as Redis Cluster hashes keys with
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Where a cluster command is sent
+#[derive(Debug, PartialEq)]
+enum Route {
+    /// The node owning a slot
+    Slot(u16),
+    /// Any node
+    Any,
+    /// Every master, with the first reply returned
+    AllFirst,
+    /// Every master, with the replies concatenated
+    AllConcat,
+    /// Every master in turn, tracked through the cursor
+    Scan,
+}
+
+fn route(cmd: &Cmd) -> Route {
+    let mut args = cmd.args_iter().filter_map(|arg| match arg {
+        Arg::Simple(arg) => Some(arg),
+        Arg::Cursor => None,
+    });
+    let Some(name) = args.next() else {
+        return Route::Any;
+    };
+    match name.to_ascii_uppercase().as_slice() {
+        b"SCRIPT" | b"FLUSHDB" | b"FLUSHALL" => Route::AllFirst,
+        b"KEYS" => Route::AllConcat,
+        b"SCAN" => Route::Scan,
+        b"PING" | b"ECHO" | b"INFO" | b"TIME" | b"DBSIZE" | b"PUBLISH" | b"CLIENT" | b"CONFIG" | b"CLUSTER" => Route::Any,
+        b"EVAL" | b"EVALSHA" => match (args.nth(1), args.next()) {
+            (Some(keys), Some(key)) if keys != b"0" => Route::Slot(key_slot(key)),
+            _ => Route::Any,
+        },
+        _ => args.next().map_or(Route::Any, |key| Route::Slot(key_slot(key))),
+    }
+}
+
+/// Whether a cluster command may succeed once sent again
+fn is_retryable(error: &RedisError) -> bool {
+    error.is_io_error() || error.is_cluster_error()
+}
+
+/// Redis Cluster nodes and the slots each serves
+struct Cluster {
+    /// Nodes the slot layout is first read from
+    seeds: Vec<ConnectionInfo>,
+    /// Credentials used with every node
+    redis: RedisConnectionInfo,
+    layout: RwLock<Arc<Layout>>,
+    /// Held while the layout is refreshed, so that concurrent redirections refresh it once
+    refresh: Mutex<()>,
+}
+
+#[derive(Default)]
+struct Layout {
+    /// Node serving each range of slots, by the range's last slot, with its first slot
+    slots: BTreeMap<u16, (u16, String)>,
+    /// Connection to each node, by address
+    nodes: HashMap<String, ConnectionManager>,
+    /// Incremented every time the layout is refreshed
+    generation: u64,
+}
+
+impl Cluster {
+    async fn connect(config: &RedisConfig) -> RedisResult<Self> {
+        let seeds = if config.cluster_nodes.is_empty() {
+            vec![config.url.as_str().into_connection_info()?]
+        } else {
+            config
+                .cluster_nodes
+                .iter()
+                .map(|url| url.as_str().into_connection_info())
+                .collect::<RedisResult<Vec<_>>>()?
+        };
+        let cluster = Self {
+            seeds,
+            redis: config.url.as_str().into_connection_info()?.redis,
+            layout: RwLock::new(Arc::new(Layout::default())),
+            refresh: Mutex::new(()),
+        };
+        cluster.refresh(0).await?;
+        info!("Connected to Redis Cluster with {} masters", cluster.layout().nodes.len());
+        Ok(cluster)
+    }
+
+    fn layout(&self) -> Arc<Layout> {
+        self.layout.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Masters and connections to them, by address
+    fn masters(&self) -> Vec<(String, ConnectionManager)> {
+        let layout = self.layout();
+        let mut masters: Vec<_> = layout.nodes.iter().map(|(address, conn)| (address.clone(), conn.clone())).collect();
+        masters.sort_by(|a, b| a.0.cmp(&b.0));
+        masters
+    }
+
+    fn any_node(&self) -> RedisResult<(String, ConnectionManager)> {
+        self.masters()
+            .into_iter()
+            .next()
+            .ok_or_else(|| (ErrorKind::ClusterDown, "no known nodes").into())
+    }
+
+    fn node_info(&self, address: &str) -> RedisResult<ConnectionInfo> {
+        let (host, port) = address
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+            .ok_or_else(|| RedisError::from((ErrorKind::InvalidClientConfig, "invalid node address", address.to_string())))?;
+        Ok(ConnectionInfo {
+            addr: ConnectionAddr::Tcp(host, port),
+            redis: self.redis.clone(),
+        })
+    }
+
+    /// Connection to a node, opened if it isn't known yet
+    async fn node(&self, address: &str) -> RedisResult<ConnectionManager> {
+        let known = self.layout().nodes.get(address).cloned();
+        match known {
+            Some(conn) => Ok(conn),
+            None => redis::Client::open(self.node_info(address)?)?.get_connection_manager().await,
+        }
+    }
+
+    /// Connection to the node owning `slot`, or any node
+    async fn node_for(&self, slot: Option<u16>) -> RedisResult<ConnectionManager> {
+        let Some(slot) = slot else {
+            return Ok(self.any_node()?.1);
+        };
+        let layout = self.layout();
+        let owner = layout
+            .slots
+            .range(slot..)
+            .next()
+            .filter(|(_, (first, _))| *first <= slot)
+            .map(|(_, (_, address))| address.clone());
+        match owner {
+            Some(address) => self.node(&address).await,
+            None => {
+                self.refresh(layout.generation).await?;
+                Err((ErrorKind::ClusterDown, "no node serves slot", slot.to_string()).into())
+            }
+        }
+    }
+
+    /// Read the slot layout again, unless it changed since `generation`
+    async fn refresh(&self, generation: u64) -> RedisResult<()> {
+        let _refresh = self.refresh.lock().await;
+        let previous = self.layout();
+        if previous.generation != generation {
+            return Ok(());
+        }
+
+        // Ask the known nodes first, then the seeds
+        let mut reply = Err((ErrorKind::ClusterDown, "no node answered").into());
+        for (_, mut conn) in self.masters() {
+            reply = redis::cmd("CLUSTER").arg("SLOTS").query_async(&mut conn).await;
+            if reply.is_ok() {
+                break;
+            }
+        }
+        if reply.is_err() {
+            for seed in &self.seeds {
+                let ask = async {
+                    let mut conn = redis::Client::open(seed.clone())?.get_async_connection().await?;
+                    redis::cmd("CLUSTER").arg("SLOTS").query_async(&mut conn).await
+                };
+                reply = tokio::time::timeout(DISCOVERY_TIMEOUT, ask)
+                    .await
+                    .unwrap_or_else(|_| Err((ErrorKind::IoError, "node timed out").into()));
+                if reply.is_ok() {
+                    break;
+                }
+            }
+        }
+        let slots = parse_slots(reply?)?;
+
+        let mut nodes = HashMap::new();
+        for (_, address) in slots.values() {
+            if !nodes.contains_key(address) {
+                nodes.insert(address.clone(), self.node(address).await?);
+            }
+        }
+        let mut addresses: Vec<&String> = nodes.keys().collect();
+        addresses.sort();
+        info!("Redis Cluster layout refreshed: {} masters ({})", nodes.len(), addresses.iter().map(|a| a.as_str()).collect::<Vec<_>>().join(", "));
+
+        *self.layout.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(Layout {
+            slots,
+            nodes,
+            generation: generation + 1,
+        });
+        Ok(())
+    }
+
+    async fn query(&self, cmd: &Cmd) -> RedisResult<Value> {
+        match route(cmd) {
+            Route::Slot(slot) => self.query_slot(Some(slot), cmd).await,
+            Route::Any => self.query_slot(None, cmd).await,
+            all @ (Route::AllFirst | Route::AllConcat) => {
+                let mut replies = Vec::new();
+                for (_, mut conn) in self.masters() {
+                    replies.push(conn.req_packed_command(cmd).await?);
+                }
+                if all == Route::AllFirst {
+                    return replies
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| (ErrorKind::ClusterDown, "no known nodes").into());
+                }
+                Ok(Value::Bulk(
+                    replies
+                        .into_iter()
+                        .flat_map(|reply| match reply {
+                            Value::Bulk(items) => items,
+                            other => vec![other],
+                        })
+                        .collect(),
+                ))
+            }
+            Route::Scan => self.scan(cmd).await,
+        }
+    }
+
+    /// Send a command to the node owning `slot`, following redirections
+    async fn query_slot(&self, slot: Option<u16>, cmd: &Cmd) -> RedisResult<Value> {
+        let mut asking: Option<String> = None;
+        let mut attempt = 1;
+        loop {
+            let generation = self.layout().generation;
+            let result = match asking.take() {
+                Some(address) => {
+                    let mut conn = self.node(&address).await?;
+                    let mut pipeline = redis::pipe();
+                    pipeline.cmd("ASKING").add_command(cmd.clone());
+                    conn.req_packed_commands(&pipeline, 1, 1).await.map(|mut replies| replies.remove(0))
+                }
+                None => match self.node_for(slot).await {
+                    Ok(mut conn) => conn.req_packed_command(cmd).await,
+                    Err(e) => Err(e),
+                },
+            };
+            let error = match result {
+                Err(e) if is_retryable(&e) && attempt < CLUSTER_ATTEMPTS => e,
+                result => return result,
+            };
+            attempt += 1;
+            match error.kind() {
+                ErrorKind::Moved => self.refresh(generation).await?,
+                ErrorKind::Ask => asking = error.redirect_node().map(|(address, _)| address.to_string()),
+                // Unreachable nodes may have failed over to a replica
+                _ => {
+                    tokio::time::sleep(CLUSTER_RETRY_DELAY).await;
+                    self.refresh(generation).await?;
+                }
+            }
+        }
+    }
+
+    /// Send a pipeline, split by node unless it's a transaction
+    ///
+    /// A transaction runs on the node owning its first key. `ASK`
+    /// redirections aren't followed here; the part redirected is resent
+    /// once the slot has moved.
+    async fn query_pipeline(&self, pipeline: &Pipeline, offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        let commands: Vec<&Cmd> = pipeline.cmd_iter().collect();
+        // Transactions are read past MULTI and the queued replies, to EXEC's
+        let transaction = offset > 0;
+        let mut replies = vec![Value::Nil; if transaction { count } else { commands.len() }];
+        let mut pending: Vec<usize> = (0..commands.len()).collect();
+        let mut attempt = 1;
+
+        while !pending.is_empty() {
+            let generation = self.layout().generation;
+            let mut groups: Vec<(ConnectionManager, Vec<usize>)> = Vec::new();
+            if transaction {
+                let slot = commands.iter().find_map(|cmd| match route(cmd) {
+                    Route::Slot(slot) => Some(slot),
+                    _ => None,
+                });
+                groups.push((self.node_for(slot).await?, pending.clone()));
+            } else {
+                let layout = self.layout();
+                let mut by_node: BTreeMap<Option<String>, Vec<usize>> = BTreeMap::new();
+                for &index in &pending {
+                    let owner = match route(commands[index]) {
+                        Route::Slot(slot) => layout
+                            .slots
+                            .range(slot..)
+                            .next()
+                            .filter(|(_, (first, _))| *first <= slot)
+                            .map(|(_, (_, address))| address.clone()),
+                        _ => None,
+                    };
+                    by_node.entry(owner).or_default().push(index);
+                }
+                for (address, indices) in by_node {
+                    let conn = match address {
+                        Some(address) => self.node(&address).await?,
+                        None => self.any_node()?.1,
+                    };
+                    groups.push((conn, indices));
+                }
+            }
+
+            let mut failed = Vec::new();
+            let mut last_error = None;
+            for (mut conn, indices) in groups {
+                let result = if transaction {
+                    conn.req_packed_commands(pipeline, offset, count).await
+                } else {
+                    let mut part = redis::pipe();
+                    for &index in &indices {
+                        part.add_command(commands[index].clone());
+                    }
+                    conn.req_packed_commands(&part, 0, indices.len()).await
+                };
+                match result {
+                    Ok(values) if transaction => replies = values,
+                    Ok(values) => {
+                        for (index, value) in indices.into_iter().zip(values) {
+                            replies[index] = value;
+                        }
+                    }
+                    Err(e) if is_retryable(&e) && attempt < CLUSTER_ATTEMPTS => {
+                        failed.extend(indices);
+                        last_error = Some(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            pending = failed;
+            if let Some(error) = last_error {
+                attempt += 1;
+                if error.kind() != ErrorKind::Moved {
+                    tokio::time::sleep(CLUSTER_RETRY_DELAY).await;
+                }
+                self.refresh(generation).await?;
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Scan every master in turn
+    ///
+    /// The cursor returned combines the node's own cursor with the index of
+    /// the node, in its low bits.
+    async fn scan(&self, cmd: &Cmd) -> RedisResult<Value> {
+        let args: Vec<&[u8]> = cmd
+            .args_iter()
+            .filter_map(|arg| match arg {
+                Arg::Simple(arg) => Some(arg),
+                Arg::Cursor => None,
+            })
+            .collect();
+        let cursor: u64 = args
+            .get(1)
+            .and_then(|cursor| std::str::from_utf8(cursor).ok()?.parse().ok())
+            .ok_or_else(|| RedisError::from((ErrorKind::ClientError, "invalid SCAN cursor")))?;
+        let node = (cursor & ((1 << SCAN_NODE_BITS) - 1)) as usize;
+        let masters = self.masters();
+        let Some((_, conn)) = masters.get(node) else {
+            return Ok(Value::Bulk(vec![Value::Data(b"0".to_vec()), Value::Bulk(Vec::new())]));
+        };
+
+        let mut scan = redis::cmd("SCAN");
+        scan.arg(cursor >> SCAN_NODE_BITS);
+        for arg in &args[2..] {
+            scan.arg(*arg);
+        }
+        let (next, keys): (u64, Value) = scan.query_async(&mut conn.clone()).await?;
+        let next = if next != 0 {
+            (next << SCAN_NODE_BITS) | node as u64
+        } else if node + 1 < masters.len() {
+            node as u64 + 1
+        } else {
+            0
+        };
+        Ok(Value::Bulk(vec![Value::Data(next.to_string().into_bytes()), keys]))
+    }
+}
+
+/// Parse a `CLUSTER SLOTS` reply into the master of each slot range
+fn parse_slots(reply: Value) -> RedisResult<BTreeMap<u16, (u16, String)>> {
+    let invalid = || RedisError::from((ErrorKind::TypeError, "invalid CLUSTER SLOTS reply"));
+    let Value::Bulk(ranges) = reply else {
+        return Err(invalid());
+    };
+    let mut slots = BTreeMap::new();
+    for range in ranges {
+        // [first slot, last slot, [host, port, id], replicas...]
+        let Value::Bulk(range) = range else {
+            return Err(invalid());
+        };
+        let [Value::Int(first), Value::Int(last), Value::Bulk(master), ..] = range.as_slice() else {
+            return Err(invalid());
+        };
+        let [Value::Data(host), Value::Int(port), ..] = master.as_slice() else {
+            return Err(invalid());
+        };
+        let address = format!("{}:{}", String::from_utf8_lossy(host), port);
+        slots.insert(*last as u16, (*first as u16, address));
+    }
+    if slots.is_empty() {
+        return Err((ErrorKind::ClusterDown, "no slots are served").into());
+    }
+    Ok(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Client;
+
+    #[tokio::test]
+    async fn test_redis_pool() {
+        let client = Client::open("redis://127.0.0.1:6379").unwrap();
+        let pool = RedisPool::new(client, 3).await.unwrap();
+
+        let mut conn = pool.get();
+        let pong: String = redis::cmd("PING").query_async(&mut conn).await.unwrap();
+        assert_eq!(pong, "PONG");
+
+        pool.get();
+        let stats = pool.stats();
+        assert_eq!(stats.size, 3);
+        assert_eq!(stats.checkouts, 2);
+    }
+
+    #[test]
+    fn test_cluster_routing() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"{user1000}.followers"));
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % CLUSTER_SLOTS);
+
+        assert_eq!(route(redis::cmd("GET").arg("foo")), Route::Slot(12182));
+        assert_eq!(route(redis::cmd("EVALSHA").arg("sha").arg(1).arg("foo")), Route::Slot(12182));
+        assert_eq!(route(redis::cmd("EVAL").arg("return 1").arg(0)), Route::Any);
+        assert_eq!(route(redis::cmd("SCAN").arg(0)), Route::Scan);
+        assert_eq!(route(redis::cmd("SCRIPT").arg("LOAD").arg("return 1")), Route::AllFirst);
+
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(8191),
+                Value::Bulk(vec![Value::Data(b"10.0.0.1".to_vec()), Value::Int(6379), Value::Data(b"id1".to_vec())]),
+            ]),
+            Value::Bulk(vec![
+                Value::Int(8192),
+                Value::Int(16383),
+                Value::Bulk(vec![Value::Data(b"10.0.0.2".to_vec()), Value::Int(6379), Value::Data(b"id2".to_vec())]),
+            ]),
+        ]);
+        let slots = parse_slots(reply).unwrap();
+        assert_eq!(slots[&16383], (8192, "10.0.0.2:6379".to_string()));
+        assert!(parse_slots(Value::Bulk(Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Scheduled summary reports for the DDoS protection service.
+//!
+//! Every day or week, a plain-text summary of the period is emailed to the
+//! configured recipients: event volumes by type, the clients behind the most
+//! security events, rule matches and a timeline of attacks. Reports are
+//! rendered from analytics event counts and tracked attacks, and only one
+//! instance sends each report.
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use crate::core::analytics::{Analytics, EventSummary};
+use crate::core::attacks::{Attack, AttackTracker};
+use crate::core::email::Mailer;
+use crate::core::redis_pool::RedisPool;
+use crate::models::{EmailConfig, ReportSchedule};
+
+/// Attackers listed in a report
+const TOP_ATTACKERS: usize = 10;
+/// Most attacks looked at when building the timeline
+const MAX_ATTACKS: usize = 500;
+
+/// Summary of a reporting period
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Events recorded, by type name
+    pub events_by_type: BTreeMap<String, u64>,
+    /// Clients behind the most security events, with their event counts
+    pub top_attackers: Vec<(String, u64)>,
+    /// Matches by rule name, most matched first
+    pub rule_matches: Vec<(String, u64)>,
+    /// Attacks started in the period, oldest first
+    pub attacks: Vec<Attack>,
+}
+
+impl Report {
+    /// Summarize the event counts and attacks of a period
+    pub fn build(start: DateTime<Utc>, end: DateTime<Utc>, summary: EventSummary, attacks: Vec<Attack>) -> Self {
+        let mut attacks: Vec<_> = attacks
+            .into_iter()
+            .filter(|attack| attack.started_at >= start && attack.started_at < end)
+            .collect();
+        attacks.sort_by_key(|attack| attack.started_at);
+
+        Self {
+            start,
+            end,
+            events_by_type: summary.events_by_type,
+            top_attackers: summary.top_clients,
+            rule_matches: summary.rule_matches,
+            attacks,
+        }
+    }
+
+    /// Plain-text rendering of the report
+    pub fn render(&self) -> String {
+        let mut text = format!(
+            "DDoS protection report\n{} to {}\n",
+            self.start.format("%Y-%m-%d %H:%M UTC"),
+            self.end.format("%Y-%m-%d %H:%M UTC")
+        );
+
+        text.push_str("\nTraffic\n");
+        if self.events_by_type.is_empty() {
+            text.push_str("  No events recorded\n");
+        }
+        for (event_type, count) in &self.events_by_type {
+            text.push_str(&format!("  {:<24}{}\n", event_type, count));
+        }
+
+        text.push_str("\nTop attackers\n");
+        if self.top_attackers.is_empty() {
+            text.push_str("  None\n");
+        }
+        for (client, count) in &self.top_attackers {
+            text.push_str(&format!("  {:<40}{} events\n", client, count));
+        }
+
+        text.push_str("\nRule matches\n");
+        if self.rule_matches.is_empty() {
+            text.push_str("  None\n");
+        }
+        for (rule, count) in &self.rule_matches {
+            text.push_str(&format!("  {:<40}{}\n", rule, count));
+        }
+
+        text.push_str("\nAttacks\n");
+        if self.attacks.is_empty() {
+            text.push_str("  None\n");
+        }
+        for attack in &self.attacks {
+            let ended = match attack.ended_at {
+                Some(ended_at) => ended_at.format("%Y-%m-%d %H:%M").to_string(),
+                None => "ongoing".to_string(),
+            };
+            text.push_str(&format!(
+                "  {} to {}  {}  peak {} rps, {} detections, {} targets\n",
+                attack.started_at.format("%Y-%m-%d %H:%M"),
+                ended,
+                attack.vector,
+                attack.peak_rps,
+                attack.detections,
+                attack.targets.len()
+            ));
+        }
+        text
+    }
+}
+
+/// Time of the first report due after `now`, if reports are scheduled
+///
+/// Daily reports go out at `hour` UTC, weekly ones at `hour` on Mondays.
+pub fn next_run(schedule: ReportSchedule, hour: u32, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let today = Utc.from_utc_datetime(&now.date_naive().and_hms_opt(hour.min(23), 0, 0)?);
+    match schedule {
+        ReportSchedule::Off => None,
+        ReportSchedule::Daily => Some(if today > now { today } else { today + Duration::days(1) }),
+        ReportSchedule::Weekly => {
+            let monday = today - Duration::days(now.weekday().num_days_from_monday() as i64);
+            Some(if monday > now { monday } else { monday + Duration::weeks(1) })
+        }
+    }
+}
+
+/// Sends the scheduled reports
+pub struct ReportScheduler {
+    redis: RedisPool,
+    analytics: Arc<Analytics>,
+    attacks: AttackTracker,
+    mailer: Mailer,
+    config: EmailConfig,
+}
+
+impl ReportScheduler {
+    pub fn new(redis: RedisPool, analytics: Arc<Analytics>, attacks: AttackTracker, mailer: Mailer, config: EmailConfig) -> Self {
+        Self {
+            redis,
+            analytics,
+            attacks,
+            mailer,
+            config,
+        }
+    }
+
+    /// Send reports as they fall due
+    pub async fn start(&self) {
+        if self.config.report_recipients.is_empty() {
+            return;
+        }
+        while let Some(run_at) = next_run(self.config.report_schedule, self.config.report_hour, Utc::now()) {
+            let wait = (run_at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            if let Err(e) = self.send_report(run_at).await {
+                error!("Failed to send report: {}", e);
+            }
+        }
+    }
+
+    /// Build and send the report of the period ending at `end`
+    pub async fn send_report(&self, end: DateTime<Utc>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (start, label) = match self.config.report_schedule {
+            ReportSchedule::Weekly => (end - Duration::weeks(1), "Weekly"),
+            _ => (end - Duration::days(1), "Daily"),
+        };
+
+        // Every instance wakes up for the report; the first one sends it
+        let mut conn = self.redis.get();
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(format!("reports:sent:{}", end.timestamp()))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(7 * 86400)
+            .query_async(&mut conn)
+            .await?;
+        if claimed.is_none() {
+            return Ok(());
+        }
+
+        let summary = self.analytics.summarize(start, end, TOP_ATTACKERS).await?;
+        let attacks = self.attacks.list(false, MAX_ATTACKS).await?;
+        let report = Report::build(start, end, summary, attacks);
+
+        let subject = format!("{} DDoS protection report for {}", label, start.format("%Y-%m-%d"));
+        self.mailer.send(&self.config.report_recipients, &subject, &report.render()).await?;
+        info!("Sent {} report to {} recipients", label.to_lowercase(), self.config.report_recipients.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::analytics::{Event, EventType};
+    use crate::core::attacks::AttackStatus;
+    use std::collections::HashMap;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        // A Wednesday
+        Utc.with_ymd_and_hms(2024, 5, 15, hour, 30, 0).unwrap()
+    }
+
+    fn event(event_type: EventType, key: &str, value: &str, timestamp: DateTime<Utc>) -> Event {
+        let data = HashMap::from([(key.to_string(), serde_json::json!(value))]);
+        let mut event = Event::new(event_type, "test", data);
+        event.timestamp = timestamp;
+        event
+    }
+
+    #[test]
+    fn test_next_run() {
+        assert_eq!(next_run(ReportSchedule::Off, 8, at(7)), None);
+        assert_eq!(next_run(ReportSchedule::Daily, 8, at(7)), Some(Utc.with_ymd_and_hms(2024, 5, 15, 8, 0, 0).unwrap()));
+        assert_eq!(next_run(ReportSchedule::Daily, 8, at(9)), Some(Utc.with_ymd_and_hms(2024, 5, 16, 8, 0, 0).unwrap()));
+        assert_eq!(next_run(ReportSchedule::Weekly, 8, at(9)), Some(Utc.with_ymd_and_hms(2024, 5, 20, 8, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn test_report() {
+        let start = at(0);
+        let end = start + Duration::days(1);
+        let events = vec![
+            event(EventType::BlockedRequest, "ip", "203.0.113.7", at(1)),
+            event(EventType::BlockedRequest, "ip", "203.0.113.7", at(2)),
+            event(EventType::RuleTriggered, "rule_name", "block-scrapers", at(3)),
+            event(EventType::HoneypotHit, "ip", "198.51.100.1", at(4)),
+            event(EventType::Challenge, "ip", "192.0.2.1", at(5)),
+            event(EventType::BlockedRequest, "ip", "192.0.2.99", start - Duration::hours(1)),
+        ];
+        let attack = Attack {
+            id: "a1".to_string(),
+            vector: "request_rate".to_string(),
+            status: AttackStatus::Ended,
+            started_at: at(6),
+            updated_at: at(7),
+            ended_at: Some(at(7)),
+            targets: vec!["203.0.113.7".to_string()],
+            peak_rps: 5000,
+            detections: 12,
+            mitigations: vec!["block".to_string()],
+        };
+
+        let summary = EventSummary::from_events(start, end, &events, TOP_ATTACKERS);
+        let report = Report::build(start, end, summary, vec![attack]);
+        assert_eq!(report.events_by_type["BlockedRequest"], 2);
+        assert_eq!(report.events_by_type.values().sum::<u64>(), 5);
+        assert_eq!(
+            report.top_attackers,
+            vec![("203.0.113.7".to_string(), 2), ("198.51.100.1".to_string(), 1)]
+        );
+        assert_eq!(report.rule_matches, vec![("block-scrapers".to_string(), 1)]);
+
+        let text = report.render();
+        assert!(text.contains("2024-05-15 06:30 to 2024-05-15 07:30  request_rate  peak 5000 rps"));
+        assert!(text.contains("block-scrapers"));
+    }
+}
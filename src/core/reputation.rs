@@ -0,0 +1,263 @@
+//! IP reputation for the DDoS protection service.
+//!
+//! This module keeps a score per client in Redis. Rate limit violations,
+//! DDoS detections and rule matches lower the score, clean requests raise
+//! it, and over time every score decays back towards neutral so that past
+//! behaviour is gradually forgiven. Scores are consumed by the rule engine's
+//! `IpReputation` condition.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::core::redis_pool::RedisPool;
+use crate::models::{ReputationConfig, SubnetConfig};
+use crate::utils::format_rate_limit_key;
+
+/// Lowest possible score
+pub const MIN_SCORE: f64 = 0.0;
+
+/// Highest possible score
+pub const MAX_SCORE: f64 = 10.0;
+
+/// Decays the stored score to now, applies the adjustment and clamps the result
+///
+/// KEYS[1] score hash
+/// ARGV: now, adjustment, neutral score, half-life, min, max, TTL (0 = none)
+const ADJUST_SCRIPT: &str = r#"
+local data = redis.call('HMGET', KEYS[1], 'score', 'updated_at')
+local now = tonumber(ARGV[1])
+local neutral = tonumber(ARGV[3])
+local half_life = tonumber(ARGV[4])
+local score = tonumber(data[1]) or neutral
+local updated_at = tonumber(data[2]) or now
+
+if half_life > 0 and now > updated_at then
+    score = neutral + (score - neutral) * math.pow(0.5, (now - updated_at) / half_life)
+end
+score = math.max(tonumber(ARGV[5]), math.min(tonumber(ARGV[6]), score + tonumber(ARGV[2])))
+
+redis.call('HSET', KEYS[1], 'score', tostring(score), 'updated_at', now)
+if tonumber(ARGV[7]) > 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[7])
+end
+return tostring(score)
+"#;
+
+/// Errors that can occur during reputation operations
+#[derive(Error, Debug)]
+pub enum ReputationError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+}
+
+/// Behaviour that changes a client's reputation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReputationEvent {
+    /// The client exceeded a rate limit
+    RateLimitViolation,
+    /// The DDoS detector flagged the client
+    DdosDetection,
+    /// A rule matched the client
+    RuleMatch,
+    /// The client requested a honeypot path
+    HoneypotHit,
+    /// A request from the client passed the checks
+    CleanRequest,
+}
+
+/// Reputation of a client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReputationScore {
+    /// Client key the score is tracked under
+    pub client: String,
+    /// Current score, decayed to now
+    pub score: f64,
+    /// When the score last changed, or `None` for clients without history
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// Score after decaying towards `neutral` for `elapsed` seconds
+fn decayed(score: f64, elapsed: i64, neutral: f64, half_life: u64) -> f64 {
+    if half_life == 0 || elapsed <= 0 {
+        return score;
+    }
+    neutral + (score - neutral) * 0.5f64.powf(elapsed as f64 / half_life as f64)
+}
+
+fn score_key(client: &str) -> String {
+    format_rate_limit_key("reputation", client)
+}
+
+/// Shared IP reputation store
+///
+/// Cloning is cheap; all clones use the same Redis.
+#[derive(Clone)]
+pub struct Reputation {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Reputation configuration
+    config: ReputationConfig,
+    /// Script adjusting scores
+    adjust_script: redis::Script,
+    /// Subnet sizes used to key clients
+    subnets: SubnetConfig,
+}
+
+impl Reputation {
+    /// Create a new reputation store
+    pub fn new(redis: RedisPool, config: ReputationConfig) -> Self {
+        Self {
+            redis,
+            config,
+            adjust_script: redis::Script::new(ADJUST_SCRIPT),
+            subnets: SubnetConfig::default(),
+        }
+    }
+
+    /// Track IPv6 clients by subnets of the given sizes
+    pub fn with_subnets(mut self, subnets: SubnetConfig) -> Self {
+        self.subnets = subnets;
+        self
+    }
+
+    /// Get a client's current score
+    pub async fn get(&self, ip: &str) -> Result<ReputationScore, ReputationError> {
+        let client = self.subnets.client_key(ip);
+        let mut conn = self.redis.get();
+        let (score, updated_at): (Option<f64>, Option<i64>) = redis::cmd("HMGET")
+            .arg(score_key(&client))
+            .arg("score")
+            .arg("updated_at")
+            .query_async(&mut conn)
+            .await?;
+
+        let now = Utc::now().timestamp();
+        let score = match (score, updated_at) {
+            (Some(score), Some(updated_at)) => decayed(
+                score,
+                now - updated_at,
+                self.config.neutral_score,
+                self.config.half_life_seconds,
+            ),
+            _ => self.config.neutral_score,
+        };
+        Ok(ReputationScore {
+            client,
+            score,
+            updated_at: updated_at.and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)),
+        })
+    }
+
+    /// Add `delta` to a client's score, returning the new score
+    pub async fn adjust(&self, ip: &str, delta: f64) -> Result<ReputationScore, ReputationError> {
+        self.apply(ip, delta, MAX_SCORE).await
+    }
+
+    /// Lower a client's score to at most `ceiling`, returning the new score
+    ///
+    /// Unlike `adjust`, repeating this has no further effect, which suits
+    /// sources that report the same clients periodically.
+    pub async fn cap(&self, ip: &str, ceiling: f64) -> Result<ReputationScore, ReputationError> {
+        self.apply(ip, 0.0, ceiling.clamp(MIN_SCORE, MAX_SCORE)).await
+    }
+
+    async fn apply(&self, ip: &str, delta: f64, max: f64) -> Result<ReputationScore, ReputationError> {
+        let client = self.subnets.client_key(ip);
+        // Ten half-lives bring any score within 0.1% of neutral, so the key can go
+        let ttl = self.config.half_life_seconds.saturating_mul(10);
+        let now = Utc::now();
+        let mut conn = self.redis.get();
+        let score: String = self.adjust_script
+            .key(score_key(&client))
+            .arg(now.timestamp())
+            .arg(delta)
+            .arg(self.config.neutral_score)
+            .arg(self.config.half_life_seconds)
+            .arg(MIN_SCORE)
+            .arg(max)
+            .arg(ttl)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(ReputationScore {
+            client,
+            score: score.parse().unwrap_or(self.config.neutral_score),
+            updated_at: Some(now),
+        })
+    }
+
+    /// Reset a client to the neutral score
+    pub async fn reset(&self, ip: &str) -> Result<(), ReputationError> {
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("DEL")
+            .arg(score_key(&self.subnets.client_key(ip)))
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Apply the configured score change for an event
+    ///
+    /// Failures are logged rather than returned so that reputation tracking
+    /// never fails the request being handled.
+    pub async fn record(&self, ip: &str, event: ReputationEvent) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let delta = match event {
+            ReputationEvent::RateLimitViolation => -self.config.rate_limit_penalty,
+            ReputationEvent::DdosDetection => -self.config.ddos_penalty,
+            ReputationEvent::RuleMatch => -self.config.rule_match_penalty,
+            ReputationEvent::HoneypotHit => -self.config.honeypot_penalty,
+            ReputationEvent::CleanRequest => self.config.clean_request_reward,
+        };
+        if delta == 0.0 {
+            return;
+        }
+        if let Err(e) = self.adjust(ip, delta).await {
+            log::error!("Failed to update reputation of {}: {}", ip, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Client;
+
+    #[test]
+    fn test_decay() {
+        assert_eq!(decayed(1.0, 3600, 5.0, 3600), 3.0);
+        assert_eq!(decayed(9.0, 7200, 5.0, 3600), 6.0);
+        assert_eq!(decayed(1.0, 3600, 5.0, 0), 1.0);
+        assert_eq!(decayed(1.0, -5, 5.0, 3600), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_reputation() {
+        let client = Client::open("redis://127.0.0.1:6379").unwrap();
+        let pool = RedisPool::new(client, 1).await.unwrap();
+        let reputation = Reputation::new(pool, ReputationConfig::default());
+        let ip = "192.0.2.77";
+        reputation.reset(ip).await.unwrap();
+
+        let score = reputation.get(ip).await.unwrap();
+        assert_eq!(score.score, 5.0);
+        assert!(score.updated_at.is_none());
+
+        reputation.record(ip, ReputationEvent::DdosDetection).await;
+        let score = reputation.get(ip).await.unwrap();
+        assert!((score.score - 2.0).abs() < 0.01);
+
+        // Scores are clamped to the scale
+        let score = reputation.cap(ip, 1.5).await.unwrap();
+        assert_eq!(score.score, 1.5);
+        let score = reputation.cap(ip, 4.0).await.unwrap();
+        assert_eq!(score.score, 1.5);
+        let score = reputation.adjust(ip, -100.0).await.unwrap();
+        assert_eq!(score.score, MIN_SCORE);
+
+        reputation.reset(ip).await.unwrap();
+    }
+}
@@ -4,14 +4,34 @@
 //! custom detection and mitigation rules based on various conditions.
 
 use std::collections::HashMap;
-use redis::Client as RedisClient;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use crate::core::allowlist::Allowlist;
+use crate::core::analytics::{Analytics, Event, EventType};
+use crate::core::blocklist::Blocklist;
+use crate::core::cloudflare::CloudflareClient;
+use crate::core::dnsbl::Dnsbl;
+use crate::core::expression::{Bindings, Expression};
+use crate::core::geoip::GeoIp;
+use crate::core::redis_pool::RedisPool;
+use crate::core::reputation::{Reputation, ReputationEvent};
+use crate::core::schedule::RuleSchedule;
+use crate::core::tenants::{self, Tenants};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use anyhow::Result;
 use thiserror::Error;
 use crate::models::RuleConfig;
-use crate::core::monitoring::{Alert, MonitoringError};
+use crate::models::SubnetConfig;
+use crate::utils::{normalize_ip, parse_network, yaml_to_json};
+use crate::core::monitoring::{Alert, AlertLevel, Monitoring, MonitoringError, SortOrder};
+use crate::core::rate_limiter::limit_override_key;
+use crate::core::watchdog::Heartbeat;
+use crate::storage::Archive;
+use chrono::{DateTime, Utc};
 use std::time::Duration;
+use futures::future::{BoxFuture, FutureExt};
+use futures::StreamExt;
 use log::{info, error};
 
 /// Errors that can occur during rule evaluation
@@ -62,13 +82,158 @@ pub enum RuleCondition {
     UserAgent {
         pattern: String,
     },
+    /// Client's reputation score is below the minimum (scores range from 0 to 10)
     IpReputation {
         min_score: f32,
     },
+    /// Client IP falls in one of the networks (CIDR notation or single IPs)
+    SourceNetwork {
+        networks: Vec<String>,
+    },
+    /// Request path contains the pattern
+    Path {
+        pattern: String,
+    },
+    /// Request method is one of the methods (case-insensitive)
+    Method {
+        methods: Vec<String>,
+    },
+    /// Header is present and its value contains the pattern (names are case-insensitive)
+    Header {
+        name: String,
+        pattern: String,
+    },
+    /// Query parameter is present and its value contains the pattern
+    QueryParam {
+        name: String,
+        pattern: String,
+    },
+    /// Referer header contains the pattern
+    Referer {
+        pattern: String,
+    },
+    /// Client IP is located in one of the countries (ISO 3166-1 alpha-2 codes)
+    Country {
+        codes: Vec<String>,
+    },
+    /// Request's bot score (0-100, see `core::bot_detection`) is at least the minimum
+    BotScore {
+        min: u8,
+    },
+    /// Client's TLS stack has one of the JA3 or JA4 fingerprints (case-insensitive)
+    TlsFingerprint {
+        fingerprints: Vec<String>,
+    },
+    /// Client IP belongs to one of the autonomous systems
+    Asn {
+        numbers: Vec<u32>,
+    },
+    /// Client IP is listed in one of the DNS blocklists (e.g. `zen.spamhaus.org`)
+    Dnsbl {
+        zones: Vec<String>,
+    },
+    /// Boolean combination of nested conditions
+    ConditionGroup(ConditionGroup),
+    /// Expression that must evaluate to true (see `core::expression`)
+    Expression {
+        expression: Expression,
+    },
 }
 
-/// Rule action type
+/// Boolean combination of conditions
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConditionGroup {
+    /// Every condition holds
+    AllOf(Vec<RuleCondition>),
+    /// At least one condition holds
+    AnyOf(Vec<RuleCondition>),
+    /// The condition doesn't hold
+    Not(Box<RuleCondition>),
+}
+
+impl RuleCondition {
+    /// This condition and every condition nested in it
+    fn flatten(&self) -> Vec<&RuleCondition> {
+        let mut conditions = vec![self];
+        if let RuleCondition::ConditionGroup(group) = self {
+            match group {
+                ConditionGroup::AllOf(nested) | ConditionGroup::AnyOf(nested) => {
+                    conditions.extend(nested.iter().flat_map(RuleCondition::flatten));
+                }
+                ConditionGroup::Not(nested) => conditions.extend(nested.flatten()),
+            }
+        }
+        conditions
+    }
+
+    /// Whether the condition depends on attributes only known while handling a request
+    fn needs_request(&self) -> bool {
+        self.flatten().into_iter().any(|condition| match condition {
+            RuleCondition::Expression { expression } => expression.needs_request(),
+            condition => matches!(
+                condition,
+                RuleCondition::UserAgent { .. }
+                    | RuleCondition::Path { .. }
+                    | RuleCondition::Method { .. }
+                    | RuleCondition::Header { .. }
+                    | RuleCondition::QueryParam { .. }
+                    | RuleCondition::Referer { .. }
+                    | RuleCondition::TlsFingerprint { .. }
+                    | RuleCondition::BotScore { .. }
+            ),
+        })
+    }
+}
+
+/// Request attributes rule conditions are evaluated against
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestContext {
+    /// Client IP
+    pub ip: String,
+    /// HTTP method
+    #[serde(default)]
+    pub method: String,
+    /// Request path, without the query string
+    #[serde(default)]
+    pub path: String,
+    /// Request headers; names are matched case-insensitively
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Query parameters
+    #[serde(default)]
+    pub query: HashMap<String, String>,
+    /// Request size in bytes
+    #[serde(default)]
+    pub size: u64,
+    /// Header names in the order the client sent them, if known
+    #[serde(default)]
+    pub header_order: Vec<String>,
+    /// Bot score (0-100) of the request, if scored
+    #[serde(default)]
+    pub bot_score: Option<u8>,
+    /// JA3 fingerprint of the client's TLS stack, if known
+    #[serde(default)]
+    pub ja3: Option<String>,
+    /// JA4 fingerprint of the client's TLS stack, if known
+    #[serde(default)]
+    pub ja4: Option<String>,
+    /// Tenant the request was attributed to, if any
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+impl RequestContext {
+    /// Get a header value by case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Rule action type
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum RuleAction {
     Block {
         duration_seconds: u32,
@@ -84,6 +249,341 @@ pub enum RuleAction {
         channel: String,
         message: String,
     },
+    /// Require the client to pass a challenge before proceeding
+    Challenge,
+    /// Delay the response to slow the client down
+    Tarpit {
+        delay_ms: u64,
+    },
+    /// Redirect the client elsewhere
+    Redirect {
+        url: String,
+        status: u16,
+    },
+    /// Block the client at the Cloudflare edge with an IP Access Rule, as well as here
+    ///
+    /// The rule is created in the background and lasts
+    /// `cloudflare.block_duration_seconds`.
+    CloudflareBlock,
+}
+
+/// Rules file and bulk export format
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Parse a JSON or YAML rules document
+    pub fn parse(contents: &str, yaml: bool) -> Result<Self> {
+        if !yaml {
+            return Ok(serde_json::from_str(contents)?);
+        }
+
+        let document = yaml_rust::YamlLoader::load_from_str(contents)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("empty rules file"))?;
+        Ok(serde_json::from_value(yaml_to_json(document))?)
+    }
+}
+
+/// Field rules are sorted by
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleSort {
+    #[default]
+    Priority,
+    Name,
+    Id,
+}
+
+/// Most rules a query may return
+pub const MAX_RULE_QUERY_LIMIT: usize = 1000;
+
+/// Filters, sorting and page of a rule listing
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleQuery {
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    #[serde(default)]
+    pub shadow: Option<bool>,
+    /// Tenant the rules belong to
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Text in the rule's ID, name or description, ignoring case
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub sort: RuleSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    #[serde(default)]
+    pub offset: usize,
+    /// Page to return, from 1, instead of skipping `offset` rules
+    #[serde(default)]
+    pub page: Option<usize>,
+    #[serde(default = "default_rule_query_limit")]
+    pub limit: usize,
+}
+
+fn default_rule_query_limit() -> usize {
+    100
+}
+
+impl Default for RuleQuery {
+    fn default() -> Self {
+        Self {
+            enabled: None,
+            shadow: None,
+            tenant: None,
+            q: None,
+            sort: RuleSort::default(),
+            order: SortOrder::default(),
+            offset: 0,
+            page: None,
+            limit: default_rule_query_limit(),
+        }
+    }
+}
+
+impl RuleQuery {
+    /// Whether a rule passes the filters
+    pub fn matches(&self, rule: &Rule) -> bool {
+        let text = self.q.as_ref().map(|q| q.to_lowercase());
+        self.enabled.is_none_or(|enabled| rule.enabled == enabled)
+            && self.shadow.is_none_or(|shadow| rule.shadow == shadow)
+            && self.tenant.as_ref().is_none_or(|tenant| rule.tenant.as_ref() == Some(tenant))
+            && text.is_none_or(|text| {
+                [Some(&rule.id), Some(&rule.name), rule.description.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .any(|field| field.to_lowercase().contains(&text))
+            })
+    }
+
+    /// Rules skipped before the page
+    pub fn skip(&self) -> usize {
+        match self.page {
+            Some(page) => page.saturating_sub(1).saturating_mul(self.limit),
+            None => self.offset,
+        }
+    }
+
+    /// Filter, sort and page rules, cloning only those on the page
+    pub fn apply<'a>(&self, rules: impl IntoIterator<Item = &'a Rule>) -> RulePage {
+        let mut rules: Vec<&Rule> = rules.into_iter().filter(|rule| self.matches(rule)).collect();
+        // Ties are broken by ID, so pages don't shift between requests
+        match self.sort {
+            RuleSort::Priority => rules.sort_by(|a, b| a.priority.cmp(&b.priority).then_with(|| b.id.cmp(&a.id))),
+            RuleSort::Name => rules.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id))),
+            RuleSort::Id => rules.sort_by(|a, b| a.id.cmp(&b.id)),
+        }
+        if self.order == SortOrder::Desc {
+            rules.reverse();
+        }
+        RulePage {
+            total: rules.len() as u64,
+            rules: rules.into_iter().skip(self.skip()).take(self.limit.min(MAX_RULE_QUERY_LIMIT)).cloned().collect(),
+        }
+    }
+}
+
+/// A page of the rules matching a query
+#[derive(Debug, Clone)]
+pub struct RulePage {
+    pub rules: Vec<Rule>,
+    /// Rules matching the query, on any page
+    pub total: u64,
+}
+
+/// What to do when an imported rule has the ID of an existing rule
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Keep the existing rule
+    #[default]
+    Skip,
+    /// Replace the existing rule
+    Overwrite,
+}
+
+/// Outcome of a rule import
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportSummary {
+    /// IDs of rules that didn't exist yet
+    pub created: Vec<String>,
+    /// IDs of existing rules that were replaced
+    pub overwritten: Vec<String>,
+    /// IDs of existing rules that were kept
+    pub skipped: Vec<String>,
+}
+
+/// Kind of change recorded in a rule's history
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleChange {
+    Created,
+    Updated,
+    Deleted,
+    /// Restored to the rule as it was after `version`
+    RolledBack { version: u64 },
+}
+
+/// Field value before and after a change
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FieldChange {
+    pub before: serde_json::Value,
+    pub after: serde_json::Value,
+}
+
+/// Versioned record of a rule change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleHistoryEntry {
+    /// Version number, increasing with each change to the rule
+    pub version: u64,
+    /// Rule ID
+    pub rule_id: String,
+    /// Kind of change
+    pub change: RuleChange,
+    /// Who made the change
+    pub actor: String,
+    /// When the change was made
+    pub timestamp: DateTime<Utc>,
+    /// Changed fields
+    pub diff: HashMap<String, FieldChange>,
+    /// Rule after the change, or `None` if it was deleted
+    pub rule: Option<Rule>,
+}
+
+/// Fields that differ between two versions of a rule
+fn rule_diff(before: Option<&Rule>, after: Option<&Rule>) -> HashMap<String, FieldChange> {
+    let fields = |rule: Option<&Rule>| match rule.map(serde_json::to_value) {
+        Some(Ok(serde_json::Value::Object(fields))) => fields,
+        _ => serde_json::Map::new(),
+    };
+    let before = fields(before);
+    let after = fields(after);
+
+    before
+        .keys()
+        .chain(after.keys())
+        .filter_map(|key| {
+            let old = before.get(key).cloned().unwrap_or(serde_json::Value::Null);
+            let new = after.get(key).cloned().unwrap_or(serde_json::Value::Null);
+            (old != new).then(|| (key.clone(), FieldChange { before: old, after: new }))
+        })
+        .collect()
+}
+
+/// Outcome of evaluating one of a rule's conditions in a dry run
+#[derive(Debug, Clone, Serialize)]
+pub struct ConditionResult {
+    /// The condition
+    pub condition: RuleCondition,
+    /// Whether it held
+    pub matched: bool,
+}
+
+/// Outcome of a rule dry run
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleTestResult {
+    /// Whether all conditions held
+    pub matched: bool,
+    /// Per-condition outcomes
+    pub conditions: Vec<ConditionResult>,
+    /// Actions that would fire
+    pub actions: Vec<RuleAction>,
+    /// Whether the actions would only be logged because of shadow mode
+    pub shadowed: bool,
+}
+
+/// Evaluation statistics of a rule
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuleStats {
+    /// Rule ID
+    pub rule_id: String,
+    /// Requests the rule was evaluated against
+    pub evaluations: u64,
+    /// Times the rule's conditions held
+    pub matches: u64,
+    /// Share of evaluations that matched
+    pub match_rate: f64,
+    /// When the rule last matched
+    pub last_matched_at: Option<DateTime<Utc>>,
+}
+
+impl RuleStats {
+    fn from_fields(rule_id: &str, fields: HashMap<String, String>) -> Self {
+        let count = |field: &str| fields.get(field).and_then(|value| value.parse().ok()).unwrap_or(0);
+        let evaluations = count("evaluations");
+        let matches = count("matches");
+        Self {
+            rule_id: rule_id.to_string(),
+            evaluations,
+            matches,
+            match_rate: if evaluations > 0 { matches as f64 / evaluations as f64 } else { 0.0 },
+            last_matched_at: fields
+                .get("last_matched_at")
+                .and_then(|value| value.parse::<i64>().ok())
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)),
+        }
+    }
+}
+
+/// Longest delay a `Tarpit` action can impose
+pub const MAX_TARPIT_DELAY: Duration = Duration::from_secs(30);
+
+/// How a request matching rules should be answered
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Mitigation {
+    /// Reject the request
+    Block,
+    /// Redirect the client
+    Redirect {
+        url: String,
+        status: u16,
+    },
+    /// Serve a challenge instead of the response
+    Challenge,
+}
+
+impl Mitigation {
+    /// The strongest mitigation among matched rule actions
+    ///
+    /// Blocks take precedence over redirects, which take precedence over
+    /// challenges; among redirects the first one wins.
+    pub fn from_actions(actions: &[RuleAction]) -> Option<Self> {
+        let mut mitigation = None;
+        for action in actions {
+            let candidate = match action {
+                RuleAction::Block { .. } | RuleAction::CloudflareBlock => return Some(Mitigation::Block),
+                RuleAction::Redirect { url, status } => Mitigation::Redirect {
+                    url: url.clone(),
+                    status: *status,
+                },
+                RuleAction::Challenge => Mitigation::Challenge,
+                _ => continue,
+            };
+            if mitigation.is_none() || mitigation == Some(Mitigation::Challenge) {
+                mitigation = Some(candidate);
+            }
+        }
+        mitigation
+    }
+}
+
+/// Delay imposed by matched `Tarpit` actions (the longest one, capped)
+pub fn tarpit_delay(actions: &[RuleAction]) -> Option<Duration> {
+    actions
+        .iter()
+        .filter_map(|action| match action {
+            RuleAction::Tarpit { delay_ms } => Some(Duration::from_millis(*delay_ms)),
+            _ => None,
+        })
+        .max()
+        .map(|delay| delay.min(MAX_TARPIT_DELAY))
 }
 
 /// Rule definition
@@ -103,269 +603,727 @@ pub struct Rule {
     pub priority: i32,
     /// Whether the rule is enabled
     pub enabled: bool,
+    /// Whether matches are only logged to analytics instead of acted upon
+    #[serde(default)]
+    pub shadow: bool,
+    /// When the rule is active; the schedule overrides `enabled`
+    #[serde(default)]
+    pub schedule: Option<RuleSchedule>,
+    /// When the rule is disabled for good, for temporary mitigation rules
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Tenant whose requests the rule applies to, or `None` for every request
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+impl Rule {
+    /// Whether the rule has expired at `now`
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 /// Rule engine state
 pub struct RuleEngine {
-    redis_client: RedisClient,
+    redis_client: RedisPool,
     config: RuleConfig,
+    /// Write-through cache of the rules stored in Redis
     rules: RwLock<HashMap<String, Rule>>,
+    /// Whether the cache has been loaded from storage at least once
+    loaded: AtomicBool,
+    /// Analytics sink for rule matches
+    analytics: Option<Arc<Analytics>>,
+    /// Monitoring service receiving `Notify` actions as alerts and webhook notifications
+    monitoring: Option<Arc<Monitoring>>,
+    /// Clients exempt from rule evaluation
+    allowlist: Option<Allowlist>,
+    /// Blocklist used by `Block` actions
+    blocklist: Option<Blocklist>,
+    /// Tenant blocklists used by the `Block` actions of tenant rules
+    tenants: Option<Tenants>,
+    /// Cloudflare client used by `CloudflareBlock` actions
+    cloudflare: Option<Arc<CloudflareClient>>,
+    /// GeoIP resolver used by `Country` and `Asn` conditions
+    geoip: Option<GeoIp>,
+    /// Reputation store read by `IpReputation` conditions and lowered by matches
+    reputation: Option<Reputation>,
+    /// DNS blocklist client used by `Dnsbl` conditions
+    dnsbl: Option<Dnsbl>,
+    /// Subnet sizes used to key clients
+    subnets: SubnetConfig,
+    /// Durable copy of rules and their history, and the audit log of rule changes
+    archive: Option<Archive>,
+}
+
+/// Redis hash holding rules as JSON keyed by rule ID
+const RULES_KEY: &str = "rules:definitions";
+
+/// Key rules were stored under before `RULES_KEY`
+const LEGACY_RULES_KEY: &str = "rules";
+
+/// Pub/sub channel announcing the IDs of changed rules
+const RULES_CHANNEL: &str = "rules:changed";
+
+/// Redis hash of the latest history version of each rule
+const RULE_VERSIONS_KEY: &str = "rules:versions";
+
+/// Number of history entries kept per rule
+const RULE_HISTORY_LIMIT: isize = 100;
+
+/// Redis list of a rule's history entries, newest first
+fn history_key(id: &str) -> String {
+    format!("rules:history:{}", id)
+}
+
+/// Redis sorted set of recently seen clients scored by last request time
+const ACTIVE_CLIENTS_KEY: &str = "rules:active_clients";
+
+/// Redis hash of a rule's evaluation statistics
+fn stats_key(id: &str) -> String {
+    format!("rules:stats:{}", id)
+}
+
+/// Key of a client as counted for the tenant of its requests, if any
+fn tenant_client_key(client: &str, tenant: Option<&str>) -> String {
+    match tenant {
+        Some(tenant) => tenants::client_key(tenant, client),
+        None => client.to_string(),
+    }
+}
+
+/// Redis counter of a client's requests or bytes over a rule window
+///
+/// Counters are kept per tenant, so a tenant's traffic only counts towards
+/// rules evaluated for that tenant.
+fn counter_key(kind: &str, client: &str, tenant: Option<&str>, window_seconds: u32) -> String {
+    format!("{}:{}:{}", kind, tenant_client_key(client, tenant), window_seconds)
 }
 
 impl RuleEngine {
     /// Create a new rule engine instance
-    pub fn new(redis_client: RedisClient, config: RuleConfig) -> Self {
+    pub fn new(redis_client: RedisPool, config: RuleConfig) -> Self {
         Self {
             redis_client,
             config,
             rules: RwLock::new(HashMap::new()),
+            loaded: AtomicBool::new(false),
+            analytics: None,
+            monitoring: None,
+            allowlist: None,
+            blocklist: None,
+            tenants: None,
+            cloudflare: None,
+            geoip: None,
+            reputation: None,
+            dnsbl: None,
+            subnets: SubnetConfig::default(),
+            archive: None,
         }
     }
 
-    /// Load rules from storage
+    /// Record rule matches to the given analytics instance
+    pub fn with_analytics(mut self, analytics: Arc<Analytics>) -> Self {
+        self.analytics = Some(analytics);
+        self
+    }
+
+    /// Raise `Notify` actions as alerts on the given monitoring service, notifying
+    /// the webhooks subscribed to the action's channel
+    pub fn with_monitoring(mut self, monitoring: Arc<Monitoring>) -> Self {
+        self.monitoring = Some(monitoring);
+        self
+    }
+
+    /// Skip rule evaluation for clients on the given allowlist
+    pub fn with_allowlist(mut self, allowlist: Allowlist) -> Self {
+        self.allowlist = Some(allowlist);
+        self
+    }
+
+    /// Add clients matching `Block` actions to the given blocklist
+    pub fn with_blocklist(mut self, blocklist: Blocklist) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    /// Add clients matching the `Block` actions of tenant rules to the tenant's blocklist
+    pub fn with_tenants(mut self, tenants: Tenants) -> Self {
+        self.tenants = Some(tenants);
+        self
+    }
+
+    /// Block clients matching `CloudflareBlock` actions at the Cloudflare edge
+    pub fn with_cloudflare(mut self, cloudflare: Arc<CloudflareClient>) -> Self {
+        self.cloudflare = Some(cloudflare);
+        self
+    }
+
+    /// Resolve client locations for `Country` and `Asn` conditions
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// Score clients for `IpReputation` conditions and penalize matched clients
+    pub fn with_reputation(mut self, reputation: Reputation) -> Self {
+        self.reputation = Some(reputation);
+        self
+    }
+
+    /// Check clients against DNS blocklists for `Dnsbl` conditions
+    pub fn with_dnsbl(mut self, dnsbl: Dnsbl) -> Self {
+        self.dnsbl = Some(dnsbl);
+        self
+    }
+
+    /// Key clients by subnets of the given sizes
+    pub fn with_subnets(mut self, subnets: SubnetConfig) -> Self {
+        self.subnets = subnets;
+        self
+    }
+
+    /// Keep rules and their history in the given archive too, recording
+    /// rule changes in its audit log
+    ///
+    /// Changes are written to the archive first, so a change the archive
+    /// refuses isn't made at all.
+    pub fn with_archive(mut self, archive: Archive) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Load rules from storage, replacing the cache
+    ///
+    /// Rules written by older versions under the legacy `rules` key are
+    /// migrated first. If Redis has no rules, those in the archive are
+    /// restored.
     pub async fn load_rules(&self) -> Result<()> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(anyhow::anyhow!("Redis connection error: {}", e)),
-        };
-        let rules_json: Option<String> = match redis::cmd("GET")
-            .arg("rules")
+        self.migrate_legacy_rules().await?;
+
+        let mut conn = self.redis_client.get();
+        let mut rules_json: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(RULES_KEY)
             .query_async(&mut conn)
-            .await {
-                Ok(rules) => rules,
-                Err(e) => return Err(anyhow::anyhow!("Redis query error: {}", e)),
-            };
+            .await?;
+        if let (true, Some(archive)) = (rules_json.is_empty(), &self.archive) {
+            rules_json = self.restore_rules(archive).await?;
+        }
 
-        if let Some(json) = rules_json {
-            let rules: HashMap<String, Rule> = match serde_json::from_str(&json) {
-                Ok(rules) => rules,
-                Err(e) => return Err(anyhow::anyhow!("Rule parsing error: {}", e)),
-            };
-            let mut rules_lock = self.rules.write().await;
-            *rules_lock = rules;
+        let mut rules = HashMap::with_capacity(rules_json.len());
+        for (id, json) in rules_json {
+            match serde_json::from_str::<Rule>(&json) {
+                Ok(rule) => {
+                    rules.insert(id, rule);
+                }
+                Err(e) => error!("Skipping invalid rule {}: {}", id, e),
+            }
+        }
+
+        *self.rules.write().await = rules;
+        self.loaded.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether the rules have been loaded from storage
+    pub fn rules_loaded(&self) -> bool {
+        self.loaded.load(Ordering::Relaxed)
+    }
+
+    /// Write the archived rules and history versions to Redis, returning the rules as JSON by ID
+    async fn restore_rules(&self, archive: &Archive) -> Result<HashMap<String, String>> {
+        let rules = archive.rules().await?;
+        let versions = archive.rule_versions().await?;
+        if rules.is_empty() && versions.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut rules_json = HashMap::with_capacity(rules.len());
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for rule in &rules {
+            let json = serde_json::to_string(rule)?;
+            pipe.cmd("HSETNX").arg(RULES_KEY).arg(&rule.id).arg(&json).ignore();
+            rules_json.insert(rule.id.clone(), json);
         }
+        // New history entries continue from the archived versions
+        for (id, version) in &versions {
+            pipe.cmd("HSET").arg(RULE_VERSIONS_KEY).arg(id).arg(version).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut self.redis_client.get()).await?;
+
+        info!("Restored {} rules from PostgreSQL", rules.len());
+        Ok(rules_json)
+    }
 
+    /// Record a rule change in the archive's history and audit log
+    async fn archive_change(archive: &Archive, entry: &RuleHistoryEntry) -> Result<()> {
+        archive.record_rule_change(entry).await?;
+        let action = match entry.change {
+            RuleChange::Created => "rule.created",
+            RuleChange::Updated => "rule.updated",
+            RuleChange::Deleted => "rule.deleted",
+            RuleChange::RolledBack { .. } => "rule.rolled_back",
+        };
+        let details = serde_json::json!({
+            "version": entry.version,
+            "change": entry.change,
+            "diff": entry.diff,
+        });
+        archive.record_audit(&entry.actor, action, &entry.rule_id, details).await?;
         Ok(())
     }
 
-    /// Save rules to storage
-    pub async fn save_rules(&self) -> Result<()> {
-        let rules_lock = self.rules.read().await;
-        let rules_json = match serde_json::to_string(&*rules_lock) {
-            Ok(json) => json,
-            Err(e) => return Err(anyhow::anyhow!("Rule serialization error: {}", e)),
+    /// Move rules from the legacy `rules` key (a JSON map or a sorted set of
+    /// rules) into the rules hash, keeping rules already in the hash
+    async fn migrate_legacy_rules(&self) -> Result<()> {
+        let mut conn = self.redis_client.get();
+        let key_type: String = redis::cmd("TYPE")
+            .arg(LEGACY_RULES_KEY)
+            .query_async(&mut conn)
+            .await?;
+
+        let rules: Vec<Rule> = match key_type.as_str() {
+            "string" => {
+                let json: String = redis::cmd("GET")
+                    .arg(LEGACY_RULES_KEY)
+                    .query_async(&mut conn)
+                    .await?;
+                serde_json::from_str::<HashMap<String, Rule>>(&json)?
+                    .into_values()
+                    .collect()
+            }
+            "zset" => {
+                let rules_json: Vec<String> = redis::cmd("ZRANGE")
+                    .arg(LEGACY_RULES_KEY)
+                    .arg(0)
+                    .arg(-1)
+                    .query_async(&mut conn)
+                    .await?;
+                rules_json
+                    .iter()
+                    .filter_map(|json| serde_json::from_str(json).ok())
+                    .collect()
+            }
+            _ => return Ok(()),
         };
 
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(anyhow::anyhow!("Redis connection error: {}", e)),
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for rule in &rules {
+            pipe.cmd("HSETNX")
+                .arg(RULES_KEY)
+                .arg(&rule.id)
+                .arg(serde_json::to_string(rule)?)
+                .ignore();
+        }
+        pipe.cmd("DEL").arg(LEGACY_RULES_KEY).ignore();
+        pipe.query_async::<_, ()>(&mut conn).await?;
+
+        info!("Migrated {} rules from the legacy rules key", rules.len());
+        Ok(())
+    }
+
+    /// Write a rule to storage and the cache, record it in the rule's
+    /// history and notify other instances
+    async fn save_rule(&self, rule: Rule, actor: &str, rolled_back_to: Option<u64>) -> Result<()> {
+        let mut conn = self.redis_client.get();
+        let previous: Option<String> = redis::cmd("HGET")
+            .arg(RULES_KEY)
+            .arg(&rule.id)
+            .query_async(&mut conn)
+            .await?;
+        let previous: Option<Rule> = previous.and_then(|json| serde_json::from_str(&json).ok());
+
+        let change = match (&previous, rolled_back_to) {
+            (_, Some(version)) => RuleChange::RolledBack { version },
+            (None, None) => RuleChange::Created,
+            (Some(_), None) => RuleChange::Updated,
         };
-        let _: () = match redis::cmd("SET")
-            .arg("rules")
-            .arg(rules_json)
+        let entry = self
+            .history_entry(&rule.id, change, actor, previous.as_ref(), Some(&rule))
+            .await?;
+        if let Some(archive) = &self.archive {
+            archive.save_rule(&rule).await?;
+            Self::archive_change(archive, &entry).await?;
+        }
+
+        redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(RULES_KEY)
+            .arg(&rule.id)
+            .arg(serde_json::to_string(&rule)?)
+            .ignore()
+            .cmd("LPUSH")
+            .arg(history_key(&rule.id))
+            .arg(serde_json::to_string(&entry)?)
+            .ignore()
+            .cmd("LTRIM")
+            .arg(history_key(&rule.id))
+            .arg(0)
+            .arg(RULE_HISTORY_LIMIT - 1)
+            .ignore()
+            .cmd("PUBLISH")
+            .arg(RULES_CHANNEL)
+            .arg(&rule.id)
+            .ignore()
             .query_async::<_, ()>(&mut conn)
-            .await {
-                Ok(_) => (),
-                Err(e) => return Err(anyhow::anyhow!("Redis query error: {}", e)),
-            };
+            .await?;
+
+        self.rules.write().await.insert(rule.id.clone(), rule);
+        Ok(())
+    }
+
+    /// Write a rule enabled or disabled by the engine itself, on expiry or by
+    /// its schedule, to storage and the cache and notify other instances
+    ///
+    /// These automatic toggles aren't recorded in the rule's history, so a
+    /// frequent schedule can't push the edits made by people out of it.
+    async fn save_toggle(&self, rule: &Rule, enabled: bool) -> Result<()> {
+        let rule = Rule { enabled, ..rule.clone() };
+        if let Some(archive) = &self.archive {
+            archive.save_rule(&rule).await?;
+        }
+
+        let mut conn = self.redis_client.get();
+        redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(RULES_KEY)
+            .arg(&rule.id)
+            .arg(serde_json::to_string(&rule)?)
+            .ignore()
+            .cmd("PUBLISH")
+            .arg(RULES_CHANNEL)
+            .arg(&rule.id)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
 
+        self.rules.write().await.insert(rule.id.clone(), rule);
         Ok(())
     }
 
+    /// Build the next history entry of a rule
+    async fn history_entry(
+        &self,
+        id: &str,
+        change: RuleChange,
+        actor: &str,
+        before: Option<&Rule>,
+        after: Option<&Rule>,
+    ) -> Result<RuleHistoryEntry> {
+        let mut conn = self.redis_client.get();
+        let version: u64 = redis::cmd("HINCRBY")
+            .arg(RULE_VERSIONS_KEY)
+            .arg(id)
+            .arg(1)
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(RuleHistoryEntry {
+            version,
+            rule_id: id.to_string(),
+            change,
+            actor: actor.to_string(),
+            timestamp: Utc::now(),
+            diff: rule_diff(before, after),
+            rule: after.cloned(),
+        })
+    }
+
     /// Add a new rule
-    pub async fn add_rule(&mut self, rule: Rule) {
-        let mut rules_lock = self.rules.write().await;
-        rules_lock.insert(rule.id.clone(), rule);
-        drop(rules_lock);
-        let _ = self.save_rules().await;
+    pub async fn add_rule(&self, rule: Rule, actor: &str) -> Result<()> {
+        self.save_rule(rule, actor, None).await
     }
 
     /// Get a rule by ID
     pub async fn get_rule(&self, id: &str) -> Option<Rule> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(_) => return None,
+        self.rules.read().await.get(id).cloned()
+    }
+
+    /// Get all rules, highest priority first
+    pub async fn get_rules(&self) -> Vec<Rule> {
+        let mut rules: Vec<Rule> = self.rules.read().await.values().cloned().collect();
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+        rules
+    }
+
+    /// A page of the rules matching a query, read from the cache
+    pub async fn query_rules(&self, query: &RuleQuery) -> RulePage {
+        query.apply(self.rules.read().await.values())
+    }
+
+    /// Update an existing rule, returning whether it existed
+    pub async fn update_rule(&self, id: &str, updated_rule: Rule, actor: &str) -> Result<bool> {
+        if !self.rules.read().await.contains_key(id) {
+            return Ok(false);
+        }
+
+        self.save_rule(Rule { id: id.to_string(), ..updated_rule }, actor, None).await?;
+        Ok(true)
+    }
+
+    /// Enable or disable a rule, returning it, or `None` if it doesn't exist
+    ///
+    /// A rule already in that state is left alone, without a new version.
+    pub async fn set_rule_enabled(&self, id: &str, enabled: bool, actor: &str) -> Result<Option<Rule>> {
+        let Some(rule) = self.get_rule(id).await else {
+            return Ok(None);
         };
-        
-        let rules_json: Vec<String> = match redis::cmd("ZRANGE")
-            .arg("rules")
-            .arg(0)
-            .arg(-1)
-            .query_async(&mut conn)
-            .await {
-                Ok(rules) => rules,
-                Err(_) => return None,
-            };
+        if rule.enabled == enabled {
+            return Ok(Some(rule));
+        }
 
-        rules_json
-            .into_iter()
-            .filter_map(|json| serde_json::from_str(&json).ok())
-            .find(|rule: &Rule| rule.id == id)
+        let rule = Rule { enabled, ..rule };
+        self.save_rule(rule.clone(), actor, None).await?;
+        Ok(Some(rule))
     }
 
-    /// Get all rules
-    pub async fn get_rules(&self) -> Vec<Rule> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(_) => return vec![],
+    /// Remove a rule, returning whether it existed
+    pub async fn remove_rule(&self, id: &str, actor: &str) -> Result<bool> {
+        let mut conn = self.redis_client.get();
+        let previous: Option<String> = redis::cmd("HGET")
+            .arg(RULES_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        let previous: Rule = match previous.and_then(|json| serde_json::from_str(&json).ok()) {
+            Some(rule) => rule,
+            None => {
+                self.rules.write().await.remove(id);
+                return Ok(false);
+            }
         };
 
-        let rules_json: Vec<String> = match redis::cmd("ZRANGE")
-            .arg("rules")
+        let entry = self
+            .history_entry(id, RuleChange::Deleted, actor, Some(&previous), None)
+            .await?;
+        if let Some(archive) = &self.archive {
+            archive.delete_rule(id).await?;
+            Self::archive_change(archive, &entry).await?;
+        }
+        let (removed,): (u32,) = redis::pipe()
+            .atomic()
+            .cmd("HDEL")
+            .arg(RULES_KEY)
+            .arg(id)
+            .cmd("LPUSH")
+            .arg(history_key(id))
+            .arg(serde_json::to_string(&entry)?)
+            .ignore()
+            .cmd("LTRIM")
+            .arg(history_key(id))
             .arg(0)
-            .arg(-1)
+            .arg(RULE_HISTORY_LIMIT - 1)
+            .ignore()
+            .cmd("DEL")
+            .arg(stats_key(id))
+            .ignore()
+            .cmd("PUBLISH")
+            .arg(RULES_CHANNEL)
+            .arg(id)
+            .ignore()
             .query_async(&mut conn)
-            .await {
-                Ok(rules) => rules,
-                Err(_) => return vec![],
-            };
+            .await?;
 
-        rules_json
-            .into_iter()
-            .filter_map(|json| serde_json::from_str(&json).ok())
-            .collect()
+        self.rules.write().await.remove(id);
+        Ok(removed > 0)
     }
 
-    /// Update an existing rule
-    pub async fn update_rule(&mut self, id: &str, updated_rule: Rule) -> bool {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(_) => return false,
-        };
-        
-        let rules_json: Vec<String> = match redis::cmd("ZRANGE")
-            .arg("rules")
-            .arg(0)
-            .arg(-1)
+    /// Get a rule's evaluation statistics
+    pub async fn get_rule_stats(&self, id: &str) -> Result<RuleStats> {
+        let mut conn = self.redis_client.get();
+        let fields: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(stats_key(id))
             .query_async(&mut conn)
-            .await {
-                Ok(rules) => rules,
-                Err(_) => return false,
-            };
+            .await?;
+        Ok(RuleStats::from_fields(id, fields))
+    }
 
-        let mut found = false;
-        for json in rules_json {
-            if let Ok(rule) = serde_json::from_str::<Rule>(&json) {
-                if rule.id == id {
-                    found = true;
-                    let updated_json = match serde_json::to_string(&updated_rule) {
-                        Ok(json) => json,
-                        Err(_) => continue,
-                    };
-                    let _: Result<(), redis::RedisError> = redis::pipe()
-                        .atomic()
-                        .cmd("ZREM")
-                        .arg("rules")
-                        .arg(json)
-                        .cmd("ZADD")
-                        .arg("rules")
-                        .arg(updated_rule.priority)
-                        .arg(updated_json)
-                        .query_async(&mut conn)
-                        .await;
-                    break;
-                }
-            }
+    /// Get the statistics of all rules, most matched first
+    pub async fn get_all_rule_stats(&self) -> Result<Vec<RuleStats>> {
+        let ids: Vec<String> = self.rules.read().await.keys().cloned().collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
         }
 
-        found
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.cmd("HGETALL").arg(stats_key(id));
+        }
+
+        let mut conn = self.redis_client.get();
+        let fields: Vec<HashMap<String, String>> = pipe.query_async(&mut conn).await?;
+        let mut stats: Vec<RuleStats> = ids
+            .iter()
+            .zip(fields)
+            .map(|(id, fields)| RuleStats::from_fields(id, fields))
+            .collect();
+        stats.sort_by_key(|stats| std::cmp::Reverse(stats.matches));
+        Ok(stats)
     }
 
-    /// Remove a rule
-    pub async fn remove_rule(&mut self, id: &str) -> bool {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(_) => return false,
-        };
-        
-        let rules_json: Vec<String> = match redis::cmd("ZRANGE")
-            .arg("rules")
+    /// Get a rule's history, newest first
+    ///
+    /// With an archive, the whole history is read from it; Redis only keeps
+    /// the latest entries.
+    pub async fn get_history(&self, id: &str) -> Result<Vec<RuleHistoryEntry>> {
+        if let Some(archive) = &self.archive {
+            return Ok(archive.rule_history(id).await?);
+        }
+        let mut conn = self.redis_client.get();
+        let entries_json: Vec<String> = redis::cmd("LRANGE")
+            .arg(history_key(id))
             .arg(0)
             .arg(-1)
             .query_async(&mut conn)
-            .await {
-                Ok(rules) => rules,
-                Err(_) => return false,
-            };
+            .await?;
+        Ok(entries_json
+            .iter()
+            .filter_map(|json| serde_json::from_str(json).ok())
+            .collect())
+    }
 
-        let mut found = false;
-        for json in rules_json {
-            if let Ok(rule) = serde_json::from_str::<Rule>(&json) {
-                if rule.id == id {
-                    found = true;
-                    let _: Result<(), redis::RedisError> = redis::cmd("ZREM")
-                        .arg("rules")
-                        .arg(json)
-                        .query_async(&mut conn)
-                        .await;
-                    break;
+    /// Restore a rule as it was after the given version
+    ///
+    /// Returns `None` if the version isn't in the rule's history or the rule
+    /// was deleted by that change.
+    pub async fn rollback_rule(&self, id: &str, version: u64, actor: &str) -> Result<Option<Rule>> {
+        let rule = self
+            .get_history(id)
+            .await?
+            .into_iter()
+            .find(|entry| entry.version == version)
+            .and_then(|entry| entry.rule);
+        let rule = match rule {
+            Some(rule) => rule,
+            None => return Ok(None),
+        };
+
+        self.save_rule(rule.clone(), actor, Some(version)).await?;
+        Ok(Some(rule))
+    }
+
+    /// Import rules in bulk
+    pub async fn import_rules(
+        &self,
+        rules: Vec<Rule>,
+        on_conflict: ConflictPolicy,
+        actor: &str,
+    ) -> Result<ImportSummary> {
+        let mut summary = ImportSummary::default();
+        for rule in rules {
+            let exists = self.rules.read().await.contains_key(&rule.id);
+            let id = rule.id.clone();
+            match (exists, on_conflict) {
+                (true, ConflictPolicy::Skip) => {
+                    summary.skipped.push(id);
+                    continue;
                 }
+                (true, ConflictPolicy::Overwrite) => summary.overwritten.push(id),
+                (false, _) => summary.created.push(id),
+            }
+            self.save_rule(rule, actor, None).await?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Import the rules of a JSON or YAML file (by extension), keeping existing rules
+    pub async fn load_rules_file(&self, path: &str) -> Result<ImportSummary> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        let yaml = path.ends_with(".yaml") || path.ends_with(".yml");
+        let rule_set = RuleSet::parse(&contents, yaml)?;
+        self.import_rules(rule_set.rules, ConflictPolicy::Skip, "rules_file").await
+    }
+
+    /// Keep the cache in sync with rule changes made by other instances
+    ///
+    /// Subscribes to rule change notifications and refreshes changed rules.
+    /// The cache is fully reloaded whenever the subscription is
+    /// (re)established, so changes missed while disconnected are picked up.
+    pub async fn start_sync(&self) -> Result<()> {
+        loop {
+            if let Err(e) = self.sync_rules().await {
+                error!("Rule sync subscription failed: {}", e);
             }
+            tokio::time::sleep(Duration::from_secs(1)).await;
         }
+    }
 
-        found
+    async fn sync_rules(&self) -> Result<()> {
+        let mut pubsub = self.redis_client.pubsub().await?;
+        pubsub.subscribe(RULES_CHANNEL).await?;
+        self.load_rules().await?;
+
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let id: String = message.get_payload()?;
+            self.refresh_rule(&id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reload a single rule from storage into the cache
+    async fn refresh_rule(&self, id: &str) -> Result<()> {
+        let mut conn = self.redis_client.get();
+        let json: Option<String> = redis::cmd("HGET")
+            .arg(RULES_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+
+        let mut rules = self.rules.write().await;
+        match json {
+            Some(json) => {
+                rules.insert(id.to_string(), serde_json::from_str(&json)?);
+            }
+            None => {
+                rules.remove(id);
+            }
+        }
+        Ok(())
     }
 
     /// Evaluate rules for a request
-    pub async fn evaluate_request(
-        &self,
-        ip: &str,
-        _request_size: u64,
-        user_agent: &str,
-    ) -> Result<Vec<RuleAction>> {
+    ///
+    /// Counts the request towards the client's counters, then fires every
+    /// enabled rule whose conditions hold. Actions of shadowed rules are
+    /// logged but not returned.
+    #[tracing::instrument(name = "rules", skip_all)]
+    pub async fn evaluate_request(&self, request: &RequestContext) -> Result<Vec<RuleAction>> {
         let mut actions = Vec::new();
-        let rules_lock = self.rules.read().await;
-
-        for rule in rules_lock.values() {
-            if !rule.enabled {
-                continue;
+        if let Some(allowlist) = &self.allowlist {
+            if allowlist.is_allowed(Some(&request.ip), None).await {
+                return Ok(actions);
             }
+        }
 
-            let mut conditions_met = true;
-            for condition in &rule.conditions {
-                match condition {
-                    RuleCondition::RequestRate { threshold, window_seconds } => {
-                        let key = format!("request_rate:{}:{}", ip, window_seconds);
-                        let count = match self.get_counter(&key).await {
-                            Ok(count) => count,
-                            Err(_) => continue,
-                        };
-                        if count <= *threshold as i64 {
-                            conditions_met = false;
-                            break;
-                        }
-                    },
-                    RuleCondition::TrafficVolume { threshold_bytes, window_seconds } => {
-                        let key = format!("traffic_volume:{}:{}", ip, window_seconds);
-                        let volume = match self.get_counter(&key).await {
-                            Ok(volume) => volume,
-                            Err(_) => continue,
-                        };
-                        if volume <= *threshold_bytes as i64 {
-                            conditions_met = false;
-                            break;
-                        }
-                    },
-                    RuleCondition::UserAgent { pattern } => {
-                        if !user_agent.contains(pattern) {
-                            conditions_met = false;
-                            break;
-                        }
-                    },
-                    RuleCondition::IpReputation { min_score } => {
-                        let score = match self.get_ip_reputation(ip).await {
-                            Ok(score) => score,
-                            Err(_) => continue,
-                        };
-                        if score < *min_score {
-                            conditions_met = false;
-                            break;
-                        }
-                    },
-                }
+        // Counters and blocks apply to the client key (the /64 for IPv6 clients)
+        let client = self.subnets.client_key(&request.ip);
+        // Expired rules stop matching before the background loop disables them
+        let now = Utc::now();
+        let rules: Vec<Rule> = self
+            .get_rules()
+            .await
+            .into_iter()
+            .filter(|rule| rule.enabled && !rule.is_expired(now))
+            .filter(|rule| rule.tenant.is_none() || rule.tenant == request.tenant)
+            .collect();
+        if let Err(e) = self.record_request(&client, request.tenant.as_deref(), request.size, &rules).await {
+            error!("Failed to record request for {}: {}", client, e);
+        }
+
+        for rule in &rules {
+            if !self.check_rule_conditions(rule, &client, Some(request)).await {
+                continue;
             }
 
-            if conditions_met {
+            self.fire_rule(rule, &client, request.tenant.as_deref()).await;
+            if !self.is_shadowed(rule) {
                 actions.extend(rule.actions.clone());
             }
         }
@@ -373,12 +1331,80 @@ impl RuleEngine {
         Ok(actions)
     }
 
+    /// Count a request towards every request rate and traffic volume window used by the rules
+    async fn record_request(&self, client: &str, tenant: Option<&str>, request_size: u64, rules: &[Rule]) -> Result<()> {
+        // Rules sharing a window share its counter, so count each key once
+        let mut counters = HashMap::new();
+        for condition in rules.iter().flat_map(|rule| &rule.conditions).flat_map(RuleCondition::flatten) {
+            match condition {
+                RuleCondition::RequestRate { window_seconds, .. } => {
+                    counters.insert(counter_key("request_rate", client, tenant, *window_seconds), (1, *window_seconds));
+                }
+                RuleCondition::TrafficVolume { window_seconds, .. } => {
+                    counters.insert(
+                        counter_key("traffic_volume", client, tenant, *window_seconds),
+                        (request_size, *window_seconds),
+                    );
+                }
+                RuleCondition::Expression { expression } => {
+                    for window in expression.rate_windows() {
+                        counters.insert(counter_key("request_rate", client, tenant, window), (1, window));
+                    }
+                    for window in expression.volume_windows() {
+                        counters.insert(counter_key("traffic_volume", client, tenant, window), (request_size, window));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let mut pipe = redis::pipe();
+        for (key, (amount, window)) in counters {
+            // Creating the counter with its TTL first makes each window fixed
+            pipe.cmd("SET").arg(&key).arg(0).arg("EX").arg(window.max(1)).arg("NX").ignore();
+            pipe.cmd("INCRBY").arg(&key).arg(amount).ignore();
+        }
+        for rule in rules {
+            pipe.cmd("HINCRBY").arg(stats_key(&rule.id)).arg("evaluations").arg(1).ignore();
+        }
+        pipe.cmd("ZADD")
+            .arg(ACTIVE_CLIENTS_KEY)
+            .arg(Utc::now().timestamp())
+            .arg(client)
+            .ignore();
+
+        let mut conn = self.redis_client.get();
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Evaluate a rule against a sample request without side effects
+    ///
+    /// Counter conditions are checked against the client's current counters;
+    /// the sample request itself isn't counted and no action is executed.
+    /// Disabled, expired and scheduled-off rules are evaluated all the same.
+    pub async fn test_rule(&self, rule: &Rule, request: &RequestContext) -> RuleTestResult {
+        let client = self.subnets.client_key(&request.ip);
+        let mut conditions = Vec::with_capacity(rule.conditions.len());
+        for condition in &rule.conditions {
+            conditions.push(ConditionResult {
+                condition: condition.clone(),
+                matched: self.condition_met(condition, &client, Some(request)).await,
+            });
+        }
+
+        let matched = conditions.iter().all(|result| result.matched);
+        RuleTestResult {
+            matched,
+            conditions,
+            actions: if matched { rule.actions.clone() } else { Vec::new() },
+            shadowed: self.is_shadowed(rule),
+        }
+    }
+
     /// Get a counter value from Redis
     async fn get_counter(&self, key: &str) -> Result<i64> {
-        let mut conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => return Err(anyhow::anyhow!("Redis connection error: {}", e)),
-        };
+        let mut conn = self.redis_client.get();
         let count: Option<i64> = match redis::cmd("GET")
             .arg(key)
             .query_async(&mut conn)
@@ -389,36 +1415,53 @@ impl RuleEngine {
         Ok(count.unwrap_or(0))
     }
 
-    /// Get IP reputation score (placeholder implementation)
-    async fn get_ip_reputation(&self, _ip: &str) -> Result<f32> {
-        // TODO: Implement actual IP reputation lookup
-        Ok(5.0)
+    /// Get a client's reputation score
+    async fn get_ip_reputation(&self, ip: &str) -> Result<f32> {
+        let reputation = self
+            .reputation
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no reputation store configured"))?;
+        Ok(reputation.get(ip).await?.score as f32)
     }
 
     pub async fn get_alerts(&self) -> Result<Vec<Alert>, MonitoringError> {
-        let _conn = match self.redis_client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(_) => return Ok(Vec::new()),
-        };
+        let _conn = self.redis_client.get();
 
         Ok(Vec::new())
     }
 
-    pub async fn process_rules(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Periodically evaluate rules against recently active clients
+    ///
+    /// This catches clients whose counters cross a threshold between their
+    /// requests. Conditions that need the request itself (such as the user
+    /// agent) can't be evaluated here, so rules using them only fire inline,
+    /// as do the rules of a tenant and rules on the traffic of a tenant.
+    pub async fn process_rules(&self, heartbeat: Heartbeat) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
         loop {
-            // Get all rules
-            let rules = self.get_rules().await;
-            
-            // Process each rule
-            for rule in &rules {
-                if !rule.enabled {
-                    continue;
-                }
+            heartbeat.beat().await;
+            self.expire_rules().await;
+            self.apply_schedules().await;
+
+            let rules: Vec<Rule> = self
+                .get_rules()
+                .await
+                .into_iter()
+                .filter(|rule| rule.enabled && rule.tenant.is_none())
+                .filter(|rule| !rule.conditions.iter().any(RuleCondition::needs_request))
+                .collect();
 
-                // Check rule conditions
-                if self.check_rule_conditions(rule).await? {
-                    // Execute rule actions
-                    self.execute_rule_actions(rule).await?;
+            let horizon = rules.iter().map(rule_window).max().unwrap_or(0);
+            if horizon > 0 {
+                for client in self.get_active_clients(horizon).await? {
+                    for rule in &rules {
+                        if self.check_rule_conditions(rule, &client, None).await {
+                            self.fire_rule(rule, &client, None).await;
+                        }
+                    }
                 }
             }
 
@@ -427,28 +1470,383 @@ impl RuleEngine {
         }
     }
 
-    async fn check_rule_conditions(&self, rule: &Rule) -> Result<bool, Box<dyn std::error::Error>> {
-        let _conn = self.redis_client.get_async_connection().await?;
-        
-        // TODO: Implement rule condition checking logic
-        // For now, just return true if the rule is enabled
-        Ok(rule.enabled)
+    /// Disable rules that reached their expiry time
+    #[tracing::instrument(name = "rule_engine.expire_rules", skip_all)]
+    async fn expire_rules(&self) {
+        let now = Utc::now();
+        for rule in self.get_rules().await {
+            if !rule.enabled || !rule.is_expired(now) {
+                continue;
+            }
+
+            info!("Rule {} ({}) expired", rule.name, rule.id);
+            match self.save_toggle(&rule, false).await {
+                Ok(()) => self.record_rule_event(EventType::RuleExpired, &rule, None, rule.tenant.as_deref()).await,
+                Err(e) => error!("Failed to disable expired rule {}: {}", rule.id, e),
+            }
+        }
     }
 
-    async fn execute_rule_actions(&self, rule: &Rule) -> Result<(), Box<dyn std::error::Error>> {
-        let _conn = self.redis_client.get_async_connection().await?;
-        
-        // TODO: Implement rule action execution logic
-        info!("Executing rule: {}", rule.name);
+    /// Enable or disable scheduled rules according to their schedules
+    async fn apply_schedules(&self) {
+        let now = Utc::now();
+        for rule in self.get_rules().await {
+            if rule.is_expired(now) {
+                continue;
+            }
+            let schedule = match &rule.schedule {
+                Some(schedule) => schedule,
+                None => continue,
+            };
+            let active = match schedule.is_active(now) {
+                Ok(active) => active,
+                Err(e) => {
+                    error!("Invalid schedule for rule {}: {}", rule.id, e);
+                    continue;
+                }
+            };
+            if active == rule.enabled {
+                continue;
+            }
+
+            info!("Schedule {} rule {} ({})", if active { "enables" } else { "disables" }, rule.name, rule.id);
+            if let Err(e) = self.save_toggle(&rule, active).await {
+                error!("Failed to apply schedule to rule {}: {}", rule.id, e);
+            }
+        }
+    }
+
+    /// Get clients seen within the last `horizon` seconds, forgetting older ones
+    async fn get_active_clients(&self, horizon: u64) -> Result<Vec<String>> {
+        let cutoff = Utc::now().timestamp() - horizon as i64;
+        let mut conn = self.redis_client.get();
+        let (clients,): (Vec<String>,) = redis::pipe()
+            .cmd("ZREMRANGEBYSCORE")
+            .arg(ACTIVE_CLIENTS_KEY)
+            .arg("-inf")
+            .arg(cutoff)
+            .ignore()
+            .cmd("ZRANGE")
+            .arg(ACTIVE_CLIENTS_KEY)
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut conn)
+            .await?;
+        Ok(clients)
+    }
+
+    /// Check whether all of a rule's conditions hold for a client
+    ///
+    /// Request attributes are only known when evaluating a request;
+    /// conditions on them don't hold without one. Counter lookup failures
+    /// count as not holding so that Redis errors never trigger actions.
+    async fn check_rule_conditions(
+        &self,
+        rule: &Rule,
+        client: &str,
+        request: Option<&RequestContext>,
+    ) -> bool {
+        for condition in &rule.conditions {
+            if !self.condition_met(condition, client, request).await {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Check whether a single, possibly nested, condition holds for a client
+    fn condition_met<'a>(
+        &'a self,
+        condition: &'a RuleCondition,
+        client: &'a str,
+        request: Option<&'a RequestContext>,
+    ) -> BoxFuture<'a, bool> {
+        async move {
+            let ip = request.map(|request| request.ip.as_str());
+            let tenant = request.and_then(|request| request.tenant.as_deref());
+            match condition {
+                RuleCondition::RequestRate { threshold, window_seconds } => {
+                    let key = counter_key("request_rate", client, tenant, *window_seconds);
+                    self.get_counter(&key).await.is_ok_and(|count| count > *threshold as i64)
+                }
+                RuleCondition::TrafficVolume { threshold_bytes, window_seconds } => {
+                    let key = counter_key("traffic_volume", client, tenant, *window_seconds);
+                    self.get_counter(&key).await.is_ok_and(|volume| volume > *threshold_bytes as i64)
+                }
+                RuleCondition::UserAgent { pattern } => {
+                    header_contains(request, "User-Agent", pattern)
+                }
+                RuleCondition::Referer { pattern } => header_contains(request, "Referer", pattern),
+                RuleCondition::Header { name, pattern } => header_contains(request, name, pattern),
+                RuleCondition::Path { pattern } => {
+                    request.is_some_and(|request| request.path.contains(pattern.as_str()))
+                }
+                RuleCondition::Method { methods } => request.is_some_and(|request| {
+                    methods.iter().any(|method| method.eq_ignore_ascii_case(&request.method))
+                }),
+                RuleCondition::QueryParam { name, pattern } => request
+                    .and_then(|request| request.query.get(name))
+                    .is_some_and(|value| value.contains(pattern.as_str())),
+                RuleCondition::BotScore { min } => {
+                    request.and_then(|request| request.bot_score).is_some_and(|score| score >= *min)
+                }
+                RuleCondition::TlsFingerprint { fingerprints } => request.is_some_and(|request| {
+                    [&request.ja3, &request.ja4].into_iter().flatten().any(|fingerprint| {
+                        fingerprints.iter().any(|candidate| candidate.eq_ignore_ascii_case(fingerprint))
+                    })
+                }),
+                RuleCondition::SourceNetwork { networks } => {
+                    network_contains(networks, ip.unwrap_or(client))
+                }
+                RuleCondition::IpReputation { min_score } => {
+                    self.get_ip_reputation(ip.unwrap_or(client))
+                        .await
+                        .is_ok_and(|score| score < *min_score)
+                }
+                RuleCondition::Country { codes } => match &self.geoip {
+                    Some(geoip) => geoip
+                        .lookup(ip.unwrap_or(client))
+                        .await
+                        .country
+                        .is_some_and(|country| codes.iter().any(|code| code.eq_ignore_ascii_case(&country))),
+                    None => false,
+                },
+                RuleCondition::Asn { numbers } => match &self.geoip {
+                    Some(geoip) => geoip
+                        .lookup(ip.unwrap_or(client))
+                        .await
+                        .asn
+                        .is_some_and(|asn| numbers.contains(&asn)),
+                    None => false,
+                },
+                RuleCondition::Dnsbl { zones } => match &self.dnsbl {
+                    Some(dnsbl) => dnsbl.is_listed(ip.unwrap_or(client), zones).await,
+                    None => false,
+                },
+                RuleCondition::ConditionGroup(ConditionGroup::AllOf(conditions)) => {
+                    for condition in conditions {
+                        if !self.condition_met(condition, client, request).await {
+                            return false;
+                        }
+                    }
+                    true
+                }
+                RuleCondition::ConditionGroup(ConditionGroup::AnyOf(conditions)) => {
+                    for condition in conditions {
+                        if self.condition_met(condition, client, request).await {
+                            return true;
+                        }
+                    }
+                    false
+                }
+                RuleCondition::ConditionGroup(ConditionGroup::Not(condition)) => {
+                    !self.condition_met(condition, client, request).await
+                }
+                RuleCondition::Expression { expression } => {
+                    let mut bindings = Bindings { ip: ip.unwrap_or(client), request, ..Default::default() };
+                    for window in expression.rate_windows() {
+                        let key = counter_key("request_rate", client, tenant, window);
+                        bindings.rates.insert(window, self.get_counter(&key).await.unwrap_or(0));
+                    }
+                    for window in expression.volume_windows() {
+                        let key = counter_key("traffic_volume", client, tenant, window);
+                        bindings.volumes.insert(window, self.get_counter(&key).await.unwrap_or(0));
+                    }
+                    if let (true, Some(geoip)) = (expression.needs_geo(), &self.geoip) {
+                        bindings.geo = geoip.lookup(bindings.ip).await;
+                    }
+
+                    expression.evaluate(&bindings).unwrap_or_else(|e| {
+                        error!("Failed to evaluate expression `{}`: {}", expression.source(), e);
+                        false
+                    })
+                }
+            }
+        }
+        .boxed()
+    }
+
+    /// Act on a rule match for a client
+    ///
+    /// Hits are always counted; side effects run at most once per rule
+    /// window for each client so that a client staying over a threshold
+    /// doesn't re-trigger them on every request.
+    async fn fire_rule(&self, rule: &Rule, client: &str, tenant: Option<&str>) {
+        metrics::increment_counter!("rule_hits_total", "rule" => rule.id.clone());
+
+        let mut conn = self.redis_client.get();
+        let cooldown_key = format!("rules:fired:{}:{}", rule.id, tenant_client_key(client, tenant));
+        let first: redis::RedisResult<(Option<String>,)> = redis::pipe()
+            .cmd("HINCRBY")
+            .arg(stats_key(&rule.id))
+            .arg("matches")
+            .arg(1)
+            .ignore()
+            .cmd("HSET")
+            .arg(stats_key(&rule.id))
+            .arg("last_matched_at")
+            .arg(Utc::now().timestamp())
+            .ignore()
+            .cmd("INCR")
+            .arg("analytics:rules_triggered")
+            .ignore()
+            .cmd("SET")
+            .arg(&cooldown_key)
+            .arg(1)
+            .arg("EX")
+            .arg(rule_window(rule).max(1))
+            .arg("NX")
+            .query_async(&mut conn)
+            .await;
+
+        match first {
+            Ok((Some(_),)) => (),
+            Ok((None,)) => return,
+            Err(e) => {
+                error!("Failed to record hit for rule {}: {}", rule.id, e);
+                return;
+            }
+        }
+
+        if self.is_shadowed(rule) {
+            self.record_rule_event(EventType::ShadowDecision, rule, Some(client), tenant).await;
+            info!("Shadow rule matched: {} ({}) for {}", rule.name, rule.id, client);
+            return;
+        }
+
+        self.record_rule_event(EventType::RuleTriggered, rule, Some(client), tenant).await;
+        if let Some(reputation) = &self.reputation {
+            reputation.record(client, ReputationEvent::RuleMatch).await;
+        }
+        if let Err(e) = self.execute_rule_actions(rule, client, tenant).await {
+            error!("Failed to execute actions of rule {}: {}", rule.id, e);
+        }
+    }
+
+    /// Execute a rule's actions against a client
+    ///
+    /// Actions apply within the tenant of the request, whose traffic the
+    /// rule counted: blocks go to the tenant's blocklist and limits apply to
+    /// the client as counted for the tenant.
+    async fn execute_rule_actions(&self, rule: &Rule, client: &str, tenant: Option<&str>) -> Result<()> {
+        for action in &rule.actions {
+            match action {
+                RuleAction::Block { duration_seconds } => {
+                    let blocklist = match (tenant, &self.tenants) {
+                        (Some(tenant), Some(tenants)) => Some(tenants.blocklist(tenant).await),
+                        (Some(_), None) => None,
+                        (None, _) => self.blocklist.clone(),
+                    };
+                    if let Some(blocklist) = blocklist {
+                        let reason = format!("matched rule {}", rule.name);
+                        let duration = Duration::from_secs(*duration_seconds as u64);
+                        blocklist.block(client, &reason, "rule_engine", Some(duration)).await?;
+                    }
+                }
+                RuleAction::RateLimit { requests_per_second } => {
+                    // Enforced by the rate limiter for as long as the rule window lasts
+                    let key = tenant_client_key(client, tenant);
+                    let mut conn = self.redis_client.get();
+                    let _: () = redis::cmd("SET")
+                        .arg(limit_override_key(&key))
+                        .arg(requests_per_second)
+                        .arg("EX")
+                        .arg(rule_window(rule).max(1))
+                        .query_async(&mut conn)
+                        .await?;
+                }
+                RuleAction::Log { level, message } => {
+                    let level = level.parse().unwrap_or(log::Level::Info);
+                    log::log!(level, "Rule {} matched {}: {}", rule.name, client, message);
+                }
+                RuleAction::Notify { channel, message } => {
+                    if let Some(monitoring) = &self.monitoring {
+                        let message = format!("Rule {} matched {}: {}", rule.name, client, message);
+                        monitoring
+                            .notify(channel, &format!("rule_engine:{}", channel), &message, AlertLevel::Warning)
+                            .await?;
+                    }
+                }
+                RuleAction::CloudflareBlock => {
+                    if let Some(cloudflare) = &self.cloudflare {
+                        let notes = format!("matched rule {}", rule.name);
+                        cloudflare.block_in_background(client, &notes);
+                    }
+                }
+                // Applied to the request by the caller from the returned actions
+                RuleAction::Challenge | RuleAction::Tarpit { .. } | RuleAction::Redirect { .. } => (),
+            }
+        }
+
         Ok(())
     }
+
+    /// Record a rule match to analytics
+    async fn record_rule_event(&self, event_type: EventType, rule: &Rule, client: Option<&str>, tenant: Option<&str>) {
+        let analytics = match &self.analytics {
+            Some(analytics) => analytics,
+            None => return,
+        };
+
+        let mut data = HashMap::new();
+        data.insert("rule_id".to_string(), serde_json::json!(rule.id));
+        data.insert("rule_name".to_string(), serde_json::json!(rule.name));
+        data.insert("actions".to_string(), serde_json::json!(rule.actions));
+        if let Some(client) = client {
+            data.insert("client".to_string(), serde_json::json!(client));
+        }
+        if let Some(tenant) = tenant {
+            data.insert("tenant".to_string(), serde_json::json!(tenant));
+        }
+
+        let event = Event::new(event_type, "rule_engine", data);
+        if let Err(e) = analytics.record_event(event).await {
+            error!("Failed to record rule event: {}", e);
+        }
+    }
+
+    /// Whether a rule's matches should only be logged
+    fn is_shadowed(&self, rule: &Rule) -> bool {
+        self.config.shadow || rule.shadow
+    }
+}
+
+/// Longest counter window used by a rule's conditions, in seconds
+fn rule_window(rule: &Rule) -> u64 {
+    rule.conditions
+        .iter()
+        .flat_map(RuleCondition::flatten)
+        .flat_map(|condition| match condition {
+            RuleCondition::RequestRate { window_seconds, .. }
+            | RuleCondition::TrafficVolume { window_seconds, .. } => vec![*window_seconds],
+            RuleCondition::Expression { expression } => {
+                expression.rate_windows().into_iter().chain(expression.volume_windows()).collect()
+            }
+            _ => Vec::new(),
+        })
+        .map(u64::from)
+        .max()
+        .unwrap_or(60)
+}
+
+/// Whether the request has the header and its value contains the pattern
+fn header_contains(request: Option<&RequestContext>, name: &str, pattern: &str) -> bool {
+    request
+        .and_then(|request| request.header(name))
+        .is_some_and(|value| value.contains(pattern))
 }
 
-/// Load rules from configuration
-pub fn load_rules(_config: &RuleConfig) -> Result<Vec<Rule>, RuleEngineError> {
-    // In a real implementation, this would load rules from a file or database
-    // For now, we'll just return an empty vector
-    Ok(Vec::new())
+/// Whether any of the networks contains the IP; unparsable entries never match
+fn network_contains(networks: &[String], ip: &str) -> bool {
+    let addr = match normalize_ip(ip) {
+        Some(addr) => addr,
+        None => return false,
+    };
+    networks
+        .iter()
+        .filter_map(|network| parse_network(network))
+        .any(|network| network.contains(&addr))
 }
 
 impl redis::FromRedisValue for Rule {
@@ -466,11 +1864,19 @@ impl redis::FromRedisValue for Rule {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use redis::Client;
 
-    #[test]
-    fn test_rule_engine() {
-        let mut engine = RuleEngine::new();
+    #[tokio::test]
+    async fn test_rule_engine() {
+        let client = Client::open("redis://127.0.0.1:6379").unwrap();
+        let redis = RedisPool::from(client.get_connection_manager().await.unwrap());
+        let config = RuleConfig {
+            rules_file: None,
+            default_priority: 0,
+            enabled: true,
+            shadow: false,
+        };
+        let engine = RuleEngine::new(redis.clone(), config);
         
         // Create a rule
         let rule = Rule {
@@ -490,20 +1896,251 @@ mod tests {
             ],
             priority: 1,
             enabled: true,
+            shadow: false,
+            schedule: None,
+            expires_at: None,
+            tenant: None,
         };
         
         // Add the rule
-        engine.add_rule(rule);
+        engine.add_rule(rule, "test").await.unwrap();
         
-        // Create a context
-        let mut context = HashMap::new();
-        context.insert("request_count".to_string(), serde_json::json!(150));
+        // Put the client over the threshold
+        let mut conn = redis.get();
+        let _: () = redis::pipe()
+            .cmd("SET").arg("request_rate:127.0.0.1:60").arg(150).arg("EX").arg(60).ignore()
+            .cmd("DEL").arg("rules:fired:rule1:127.0.0.1").ignore()
+            .query_async(&mut conn)
+            .await
+            .unwrap();
         
         // Evaluate rules
-        let actions = engine.evaluate_request("127.0.0.1", 150, "Mozilla/5.0").await.unwrap();
+        let mut request = RequestContext {
+            ip: "127.0.0.1".to_string(),
+            size: 150,
+            ..RequestContext::default()
+        };
+        request.headers.insert("User-Agent".to_string(), "Mozilla/5.0".to_string());
+        let actions = engine.evaluate_request(&request).await.unwrap();
         
         // Check that one action was triggered
         assert_eq!(actions.len(), 1);
         assert_eq!(actions[0], RuleAction::Block { duration_seconds: 300 });
+
+        // Clients under the threshold match nothing
+        request.ip = "127.0.0.2".to_string();
+        let actions = engine.evaluate_request(&request).await.unwrap();
+        assert!(actions.is_empty());
+
+        // Nor does the same client's traffic for a tenant, which is counted apart
+        request.ip = "127.0.0.1".to_string();
+        request.tenant = Some("shop".to_string());
+        let actions = engine.evaluate_request(&request).await.unwrap();
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn test_counter_key() {
+        assert_eq!(counter_key("request_rate", "192.0.2.1", None, 60), "request_rate:192.0.2.1:60");
+        assert_eq!(
+            counter_key("traffic_volume", "192.0.2.1", Some("shop"), 60),
+            "traffic_volume:tenant:shop:192.0.2.1:60"
+        );
+    }
+
+    #[test]
+    fn test_request_headers() {
+        let mut request = RequestContext::default();
+        request.headers.insert("user-agent".to_string(), "sqlmap/1.7".to_string());
+
+        assert_eq!(request.header("User-Agent"), Some("sqlmap/1.7"));
+        assert!(header_contains(Some(&request), "USER-AGENT", "sqlmap"));
+        assert!(!header_contains(Some(&request), "User-Agent", "curl"));
+        assert!(!header_contains(Some(&request), "Referer", ""));
+        assert!(!header_contains(None, "User-Agent", "sqlmap"));
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_condition_group() {
+        let json = r#"{"ConditionGroup": {"AllOf": [
+            {"ConditionGroup": {"AnyOf": [
+                {"UserAgent": {"pattern": "sqlmap"}},
+                {"SourceNetwork": {"networks": ["203.0.113.0/24"]}}
+            ]}},
+            {"ConditionGroup": {"Not": {"RequestRate": {"threshold": 10, "window_seconds": 30}}}}
+        ]}}"#;
+        let condition: RuleCondition = serde_json::from_str(json).unwrap();
+
+        assert_eq!(condition.flatten().len(), 6);
+        assert!(condition.needs_request());
+
+        let rule = Rule {
+            id: "rule1".to_string(),
+            name: "Nested".to_string(),
+            description: None,
+            conditions: vec![condition],
+            actions: Vec::new(),
+            priority: 0,
+            enabled: true,
+            shadow: false,
+            schedule: None,
+            expires_at: None,
+            tenant: None,
+        };
+        assert_eq!(rule_window(&rule), 30);
+
+        let now = Utc::now();
+        assert!(!rule.is_expired(now));
+        let rule = Rule { expires_at: Some(now), ..rule };
+        assert!(rule.is_expired(now));
+        assert!(!rule.is_expired(now - chrono::Duration::seconds(1)));
+    }
+
+    #[test]
+    fn test_mitigation() {
+        let redirect = RuleAction::Redirect {
+            url: "https://example.com/busy".to_string(),
+            status: 302,
+        };
+        let tarpit = RuleAction::Tarpit { delay_ms: 60_000 };
+
+        assert_eq!(Mitigation::from_actions(&[]), None);
+        assert_eq!(Mitigation::from_actions(std::slice::from_ref(&tarpit)), None);
+        assert_eq!(
+            Mitigation::from_actions(&[RuleAction::Challenge, redirect.clone()]),
+            Some(Mitigation::Redirect {
+                url: "https://example.com/busy".to_string(),
+                status: 302,
+            })
+        );
+        assert_eq!(
+            Mitigation::from_actions(&[redirect, RuleAction::Block { duration_seconds: 60 }]),
+            Some(Mitigation::Block)
+        );
+
+        assert_eq!(tarpit_delay(&[RuleAction::Tarpit { delay_ms: 500 }]), Some(Duration::from_millis(500)));
+        assert_eq!(tarpit_delay(&[tarpit]), Some(MAX_TARPIT_DELAY));
+        assert_eq!(tarpit_delay(&[RuleAction::Challenge]), None);
+    }
+
+    #[test]
+    fn test_rule_set_parse() {
+        let yaml = "
+rules:
+  - id: rule_1
+    name: Scrapers
+    description: null
+    conditions:
+      - UserAgent:
+          pattern: scrapy
+      - RequestRate:
+          threshold: 100
+          window_seconds: 60
+    actions:
+      - Tarpit:
+          delay_ms: 2000
+    priority: 5
+    enabled: true
+";
+        let rule_set = RuleSet::parse(yaml, true).unwrap();
+        assert_eq!(rule_set.rules.len(), 1);
+        let rule = &rule_set.rules[0];
+        assert_eq!(rule.id, "rule_1");
+        assert_eq!(rule.conditions.len(), 2);
+        assert_eq!(rule.actions, vec![RuleAction::Tarpit { delay_ms: 2000 }]);
+
+        let json = serde_json::to_string(&rule_set).unwrap();
+        assert_eq!(RuleSet::parse(&json, false).unwrap().rules[0].priority, 5);
+        assert!(RuleSet::parse("rules: 3", true).is_err());
+    }
+
+    #[test]
+    fn test_rule_diff() {
+        let rule = Rule {
+            id: "rule1".to_string(),
+            name: "Scrapers".to_string(),
+            description: None,
+            conditions: Vec::new(),
+            actions: Vec::new(),
+            priority: 0,
+            enabled: true,
+            shadow: false,
+            schedule: None,
+            expires_at: None,
+            tenant: None,
+        };
+        let updated = Rule { enabled: false, priority: 5, ..rule.clone() };
+
+        let diff = rule_diff(Some(&rule), Some(&updated));
+        assert_eq!(diff.len(), 2);
+        assert_eq!(
+            diff["enabled"],
+            FieldChange { before: serde_json::json!(true), after: serde_json::json!(false) }
+        );
+        assert_eq!(diff["priority"].after, serde_json::json!(5));
+
+        assert!(rule_diff(Some(&rule), Some(&rule)).is_empty());
+        let created = rule_diff(None, Some(&rule));
+        assert_eq!(created["name"].before, serde_json::Value::Null);
+        // Fields that are null in both versions are unchanged
+        assert_eq!(created.len(), 7);
+    }
+
+    #[test]
+    fn test_rule_query() {
+        let rule = |id: &str, name: &str, priority: i32, enabled: bool| Rule {
+            id: id.to_string(),
+            name: name.to_string(),
+            description: None,
+            conditions: Vec::new(),
+            actions: Vec::new(),
+            priority,
+            enabled,
+            shadow: false,
+            schedule: None,
+            expires_at: None,
+            tenant: None,
+        };
+        let rules = [
+            rule("r1", "Scrapers", 10, true),
+            rule("r2", "Login flood", 50, true),
+            rule("r3", "Old scrapers", 10, false),
+            Rule { tenant: Some("shop".to_string()), ..rule("r4", "Bad bots", 0, true) },
+        ];
+        let ids = |page: RulePage| page.rules.into_iter().map(|rule| rule.id).collect::<Vec<_>>();
+
+        let page = RuleQuery::default().apply(&rules);
+        assert_eq!(page.total, 4);
+        assert_eq!(ids(page), vec!["r2", "r1", "r3", "r4"]);
+
+        let query = RuleQuery { q: Some("SCRAPERS".to_string()), sort: RuleSort::Name, order: SortOrder::Asc, ..RuleQuery::default() };
+        assert_eq!(ids(query.apply(&rules)), vec!["r3", "r1"]);
+
+        let query = RuleQuery { enabled: Some(true), page: Some(2), limit: 2, ..RuleQuery::default() };
+        let page = query.apply(&rules);
+        assert_eq!(page.total, 3);
+        assert_eq!(ids(page), vec!["r4"]);
+
+        let query = RuleQuery { tenant: Some("shop".to_string()), ..RuleQuery::default() };
+        assert_eq!(ids(query.apply(&rules)), vec!["r4"]);
+    }
+
+    #[test]
+    fn test_rule_stats() {
+        let fields = HashMap::from([
+            ("evaluations".to_string(), "40".to_string()),
+            ("matches".to_string(), "10".to_string()),
+            ("last_matched_at".to_string(), "1700000000".to_string()),
+        ]);
+        let stats = RuleStats::from_fields("rule1", fields);
+        assert_eq!(stats.evaluations, 40);
+        assert_eq!(stats.matches, 10);
+        assert_eq!(stats.match_rate, 0.25);
+        assert_eq!(stats.last_matched_at.unwrap().timestamp(), 1700000000);
+
+        let empty = RuleStats::from_fields("rule2", HashMap::new());
+        assert_eq!(empty.matches, 0);
+        assert_eq!(empty.match_rate, 0.0);
+        assert!(empty.last_matched_at.is_none());
+    }
+}
@@ -0,0 +1,376 @@
+//! Scanner detection for the DDoS protection service.
+//!
+//! This module detects vulnerability scanners and path enumeration: requests
+//! carrying well-known attack payloads, clients walking numbered paths in
+//! sequence, and clients whose requests are mostly answered with 404. The
+//! signature list can be extended at runtime; added signatures are stored in
+//! Redis and picked up by every instance.
+
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::Utc;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::core::ddos_detector::{AttackCategory, Classification, TriggerMetric};
+use crate::core::detection::share_confidence;
+use crate::core::redis_pool::RedisPool;
+use crate::core::rule_engine::{Mitigation, RequestContext};
+use crate::models::{ScannerDetectionConfig, ScannerMitigation};
+
+/// Redis set of signatures added at runtime
+const SIGNATURES_KEY: &str = "scanner:signatures";
+
+/// Errors that can occur during scanner detection
+#[derive(Error, Debug)]
+pub enum ScannerError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+}
+
+impl ScannerMitigation {
+    /// Mitigation answering the client, if any
+    pub fn mitigation(&self) -> Option<Mitigation> {
+        match self {
+            Self::Block => Some(Mitigation::Block),
+            Self::Challenge => Some(Mitigation::Challenge),
+            Self::Log => None,
+        }
+    }
+}
+
+/// A client detected as a scanner
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanDetection {
+    /// What gave the scanner away
+    pub classification: Classification,
+    /// Signature the request matched, if any
+    pub signature: Option<String>,
+    /// How the request should be answered
+    pub mitigation: Option<Mitigation>,
+}
+
+/// Signature list entry
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Signature {
+    /// Signature, lowercased
+    pub signature: String,
+    /// Whether the signature was added at runtime rather than configured
+    pub runtime: bool,
+}
+
+/// Scanner detector
+///
+/// Cloning is cheap and all clones share the same signatures.
+#[derive(Clone)]
+pub struct ScannerDetector {
+    /// Redis connection pool
+    redis: RedisPool,
+    /// Scanner detection configuration
+    config: ScannerDetectionConfig,
+    /// Configured and runtime signatures
+    signatures: Arc<RwLock<Vec<Signature>>>,
+}
+
+impl ScannerDetector {
+    /// Create a new scanner detector with the configured signatures
+    pub fn new(redis: RedisPool, config: ScannerDetectionConfig) -> Self {
+        let signatures = configured_signatures(&config);
+        Self {
+            redis,
+            config,
+            signatures: Arc::new(RwLock::new(signatures)),
+        }
+    }
+
+    /// Current signatures, configured ones first
+    pub async fn signatures(&self) -> Vec<Signature> {
+        self.signatures.read().await.clone()
+    }
+
+    /// Add a runtime signature, returning whether it was new
+    pub async fn add_signature(&self, signature: &str) -> Result<bool, ScannerError> {
+        let signature = signature.trim().to_lowercase();
+        if signature.len() < 3 {
+            return Err(ScannerError::InvalidSignature(signature));
+        }
+        let mut conn = self.redis.get();
+        let added: bool = redis::cmd("SADD").arg(SIGNATURES_KEY).arg(&signature).query_async(&mut conn).await?;
+        self.reload().await?;
+        Ok(added)
+    }
+
+    /// Remove a runtime signature, returning whether it existed
+    ///
+    /// Configured signatures can only be removed from the configuration.
+    pub async fn remove_signature(&self, signature: &str) -> Result<bool, ScannerError> {
+        let mut conn = self.redis.get();
+        let removed: bool = redis::cmd("SREM")
+            .arg(SIGNATURES_KEY)
+            .arg(signature.trim().to_lowercase())
+            .query_async(&mut conn)
+            .await?;
+        self.reload().await?;
+        Ok(removed)
+    }
+
+    /// Reload runtime signatures from Redis
+    #[tracing::instrument(name = "scanner.reload", skip_all)]
+    pub async fn reload(&self) -> Result<(), ScannerError> {
+        let mut conn = self.redis.get();
+        let mut runtime: Vec<String> = redis::cmd("SMEMBERS").arg(SIGNATURES_KEY).query_async(&mut conn).await?;
+        runtime.sort();
+
+        let mut signatures = configured_signatures(&self.config);
+        for signature in runtime {
+            if !signatures.iter().any(|s| s.signature == signature) {
+                signatures.push(Signature { signature, runtime: true });
+            }
+        }
+        *self.signatures.write().await = signatures;
+        Ok(())
+    }
+
+    /// Periodically reload signatures so ones added on other instances are picked up
+    pub async fn start_refresh(&self) -> Result<(), ScannerError> {
+        let interval = Duration::from_secs(self.config.refresh_interval_seconds.max(1));
+        loop {
+            if let Err(e) = self.reload().await {
+                log::error!("Failed to reload scanner signatures: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Check a request for scanner behavior
+    pub async fn check(&self, request: &RequestContext) -> Result<Option<ScanDetection>, ScannerError> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+
+        let haystack = request_haystack(request);
+        let matched = self
+            .signatures
+            .read()
+            .await
+            .iter()
+            .find(|s| haystack.contains(&s.signature))
+            .map(|s| s.signature.clone());
+        if let Some(signature) = matched {
+            return Ok(Some(self.detection(
+                Classification {
+                    category: AttackCategory::Reconnaissance,
+                    vector: "scanner_signature".to_string(),
+                    confidence: 100,
+                    metrics: Vec::new(),
+                },
+                Some(signature),
+                self.config.signature_mitigation,
+            )));
+        }
+
+        let window = self.config.window.max(1) as u64;
+        let bucket = Utc::now().timestamp() as u64 / window;
+        let last_key = format!("scanner:last:{}", request.ip);
+        let responses_key = format!("scanner:responses:{}:{}", request.ip, bucket);
+        let not_found_key = format!("scanner:not_found:{}:{}", request.ip, bucket);
+        let sequential_key = format!("scanner:sequential:{}:{}", request.ip, bucket);
+
+        let mut conn = self.redis.get();
+        let (previous, responses, not_found): (Option<String>, Option<u64>, Option<u64>) = redis::pipe()
+            .cmd("GETSET").arg(&last_key).arg(&request.path)
+            .cmd("EXPIRE").arg(&last_key).arg(window).ignore()
+            .cmd("GET").arg(&responses_key)
+            .cmd("GET").arg(&not_found_key)
+            .query_async(&mut conn)
+            .await?;
+
+        if previous.is_some_and(|previous| is_next_path(&previous, &request.path)) {
+            let (sequential,): (u64,) = redis::pipe()
+                .cmd("INCR").arg(&sequential_key)
+                .cmd("EXPIRE").arg(&sequential_key).arg(window).ignore()
+                .query_async(&mut conn)
+                .await?;
+            if sequential >= self.config.sequential_threshold.max(1) {
+                return Ok(Some(self.detection(
+                    Classification::threshold_exceeded(
+                        AttackCategory::Reconnaissance,
+                        "sequential_probing",
+                        sequential,
+                        self.config.sequential_threshold,
+                    ),
+                    None,
+                    self.config.enumeration_mitigation,
+                )));
+            }
+        }
+
+        let (responses, not_found) = (responses.unwrap_or(0), not_found.unwrap_or(0));
+        if responses < self.config.min_responses.max(1) {
+            return Ok(None);
+        }
+        let share = not_found as f64 / responses as f64;
+        if share < self.config.not_found_ratio {
+            return Ok(None);
+        }
+        Ok(Some(self.detection(
+            Classification {
+                category: AttackCategory::Reconnaissance,
+                vector: "not_found_ratio".to_string(),
+                confidence: share_confidence(share, self.config.not_found_ratio),
+                metrics: vec![
+                    TriggerMetric {
+                        name: "not_found_ratio".to_string(),
+                        observed: share,
+                        threshold: self.config.not_found_ratio,
+                    },
+                    TriggerMetric {
+                        name: "responses".to_string(),
+                        observed: responses as f64,
+                        threshold: self.config.min_responses as f64,
+                    },
+                ],
+            },
+            None,
+            self.config.enumeration_mitigation,
+        )))
+    }
+
+    /// Count a response served to a client towards its 404 ratio
+    pub async fn record_response(&self, ip: &str, status: u16) -> Result<(), ScannerError> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let window = self.config.window.max(1) as u64;
+        let bucket = Utc::now().timestamp() as u64 / window;
+        let responses_key = format!("scanner:responses:{}:{}", ip, bucket);
+        let not_found_key = format!("scanner:not_found:{}:{}", ip, bucket);
+
+        let mut pipe = redis::pipe();
+        pipe.cmd("INCR").arg(&responses_key).ignore()
+            .cmd("EXPIRE").arg(&responses_key).arg(window).ignore();
+        if status == 404 || status == 410 {
+            pipe.cmd("INCR").arg(&not_found_key).ignore()
+                .cmd("EXPIRE").arg(&not_found_key).arg(window).ignore();
+        }
+        let mut conn = self.redis.get();
+        let _: () = pipe.query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    fn detection(
+        &self,
+        classification: Classification,
+        signature: Option<String>,
+        mitigation: ScannerMitigation,
+    ) -> ScanDetection {
+        metrics::increment_counter!("scanner_detections_total", "vector" => classification.vector.clone());
+        ScanDetection {
+            classification,
+            signature,
+            mitigation: mitigation.mitigation(),
+        }
+    }
+}
+
+/// Configured signatures, lowercased
+fn configured_signatures(config: &ScannerDetectionConfig) -> Vec<Signature> {
+    config
+        .signatures
+        .iter()
+        .map(|signature| Signature {
+            signature: signature.to_lowercase(),
+            runtime: false,
+        })
+        .collect()
+}
+
+/// Lowercased, percent-decoded path and query matched against signatures
+fn request_haystack(request: &RequestContext) -> String {
+    let mut haystack = percent_decode(&request.path);
+    for (name, value) in &request.query {
+        haystack.push(' ');
+        haystack.push_str(&percent_decode(name));
+        haystack.push('=');
+        haystack.push_str(&percent_decode(value));
+    }
+    haystack.to_lowercase()
+}
+
+/// Decode `%XX` escapes and `+`, leaving malformed escapes as they are
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 3;
+                        continue;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Split a path around its last number (`/users/42/posts` into `/users/`, 42, `/posts`)
+fn split_number(path: &str) -> Option<(&str, u64, &str)> {
+    let end = path.rfind(|c: char| c.is_ascii_digit())? + 1;
+    let start = path[..end].rfind(|c: char| !c.is_ascii_digit()).map_or(0, |i| i + 1);
+    Some((&path[..start], path[start..end].parse().ok()?, &path[end..]))
+}
+
+/// Whether `path` is `previous` with its last number incremented
+fn is_next_path(previous: &str, path: &str) -> bool {
+    match (split_number(previous), split_number(path)) {
+        (Some((prefix, number, suffix)), Some((next_prefix, next, next_suffix))) => {
+            prefix == next_prefix && suffix == next_suffix && number.checked_add(1) == Some(next)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_request_haystack() {
+        let mut query = HashMap::new();
+        query.insert("id".to_string(), "1%20UNION+SELECT%20password".to_string());
+        let request = RequestContext {
+            ip: "192.0.2.1".to_string(),
+            path: "/static/..%2F..%2Fetc/passwd".to_string(),
+            query,
+            ..RequestContext::default()
+        };
+        let haystack = request_haystack(&request);
+        assert!(haystack.starts_with("/static/../../etc/passwd"));
+        assert!(haystack.contains("id=1 union select password"));
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+    }
+
+    #[test]
+    fn test_is_next_path() {
+        assert!(is_next_path("/users/41", "/users/42"));
+        assert!(is_next_path("/users/9/posts", "/users/10/posts"));
+        assert!(is_next_path("/backup1.zip", "/backup2.zip"));
+        assert!(!is_next_path("/users/42", "/users/42"));
+        assert!(!is_next_path("/users/41", "/orders/42"));
+        assert!(!is_next_path("/users", "/users/1"));
+    }
+}
@@ -0,0 +1,272 @@
+//! Rule schedules for the DDoS protection service.
+//!
+//! This module decides when scheduled rules are active, from weekly time
+//! windows and/or cron expressions evaluated in a fixed time zone. Named
+//! zones such as `Europe/Paris` aren't supported, so schedules don't follow
+//! daylight saving time changes.
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveTime, Timelike, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Errors in schedule definitions
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+    #[error("Invalid time zone: {0}")]
+    InvalidTimezone(String),
+    #[error("Invalid time: {0}")]
+    InvalidTime(String),
+    #[error("Invalid cron expression: {0}")]
+    InvalidCron(String),
+    #[error("Invalid duration: {0} minutes")]
+    InvalidDuration(u32),
+    #[error("Cron expression without a duration")]
+    MissingDuration,
+    #[error("Schedule has neither windows nor a cron expression")]
+    Empty,
+}
+
+/// Weekly time window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeWindow {
+    /// Days the window starts on
+    pub days: Vec<Weekday>,
+    /// Start time (`HH:MM`)
+    pub start: String,
+    /// End time (`HH:MM`); windows ending at or before their start run past midnight
+    pub end: String,
+}
+
+/// When a rule is active
+///
+/// A rule is active while any window contains the current time, or for
+/// `duration_minutes` from each minute the cron expression matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleSchedule {
+    /// Time zone the schedule is expressed in: `UTC` or a fixed offset such as `+05:30`
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    /// Weekly windows
+    #[serde(default)]
+    pub windows: Vec<TimeWindow>,
+    /// Cron expression (minute, hour, day of month, month, day of week)
+    /// matching the start of each active period
+    #[serde(default)]
+    pub cron: Option<String>,
+    /// How long the rule stays active from each cron match, required with `cron`
+    #[serde(default)]
+    pub duration_minutes: Option<u32>,
+}
+
+/// Longest active period of a cron schedule, a week
+const MAX_DURATION_MINUTES: u32 = 7 * 24 * 60;
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn parse_timezone(value: &str) -> Result<FixedOffset, ScheduleError> {
+    let invalid = || ScheduleError::InvalidTimezone(value.to_string());
+    if value.eq_ignore_ascii_case("UTC") || value == "Z" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+
+    let (sign, offset) = match value.split_at_checked(1) {
+        Some(("+", offset)) => (1, offset),
+        Some(("-", offset)) => (-1, offset),
+        _ => return Err(invalid()),
+    };
+    let (hours, minutes) = offset.split_once(':').ok_or_else(invalid)?;
+    let hours: i32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+    if minutes >= 60 {
+        return Err(invalid());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, ScheduleError> {
+    NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| ScheduleError::InvalidTime(value.to_string()))
+}
+
+impl TimeWindow {
+    fn contains(&self, local: DateTime<FixedOffset>) -> Result<bool, ScheduleError> {
+        let start = parse_time(&self.start)?;
+        let end = parse_time(&self.end)?;
+        let time = local.time();
+        let today = self.days.contains(&local.weekday());
+
+        if start < end {
+            return Ok(today && time >= start && time < end);
+        }
+        // Past midnight: the part after the start today, or before the end after starting yesterday
+        let yesterday = self.days.contains(&(local - Duration::days(1)).weekday());
+        Ok((today && time >= start) || (yesterday && time < end))
+    }
+}
+
+impl RuleSchedule {
+    /// Check that the schedule can be evaluated
+    pub fn validate(&self) -> Result<(), ScheduleError> {
+        if self.windows.is_empty() && self.cron.is_none() {
+            return Err(ScheduleError::Empty);
+        }
+        self.is_active(Utc::now()).map(|_| ())
+    }
+
+    /// Whether the schedule is active at `now`
+    pub fn is_active(&self, now: DateTime<Utc>) -> Result<bool, ScheduleError> {
+        let local = now.with_timezone(&parse_timezone(&self.timezone)?);
+
+        let mut active = false;
+        for window in &self.windows {
+            active |= window.contains(local)?;
+        }
+        if let Some(cron) = &self.cron {
+            let duration = match self.duration_minutes {
+                Some(duration) if (1..=MAX_DURATION_MINUTES).contains(&duration) => duration,
+                Some(duration) => return Err(ScheduleError::InvalidDuration(duration)),
+                None => return Err(ScheduleError::MissingDuration),
+            };
+            let cron = CronExpression::parse(cron)?;
+            // Active if a period started within the last `duration` minutes
+            active |= (0..duration as i64).any(|minutes| cron.matches(local - Duration::minutes(minutes)));
+        }
+        Ok(active)
+    }
+}
+
+/// Parsed cron expression; each field is a bit set of allowed values
+struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Whether the day fields were restricted rather than `*`
+    restricted_days: (bool, bool),
+}
+
+/// Parse one cron field (`*`, values, ranges and steps, comma-separated)
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut set = 0u64;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|step| *step > 0)?),
+            None => (item, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                None if item.contains('/') => (range.parse().ok()?, max),
+                None => (range.parse().ok()?, range.parse().ok()?),
+            },
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step) {
+            set |= 1 << value;
+        }
+    }
+    Some(set)
+}
+
+impl CronExpression {
+    fn parse(expression: &str) -> Result<Self, ScheduleError> {
+        let invalid = || ScheduleError::InvalidCron(expression.to_string());
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7).ok_or_else(invalid)?;
+        // Both 0 and 7 mean Sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59).ok_or_else(invalid)?,
+            hours: parse_field(fields[1], 0, 23).ok_or_else(invalid)?,
+            days_of_month: parse_field(fields[2], 1, 31).ok_or_else(invalid)?,
+            months: parse_field(fields[3], 1, 12).ok_or_else(invalid)?,
+            days_of_week,
+            restricted_days: (fields[2] != "*", fields[4] != "*"),
+        })
+    }
+
+    fn matches(&self, local: DateTime<FixedOffset>) -> bool {
+        let has = |set: u64, value: u32| set & (1 << value) != 0;
+        let day_of_month = has(self.days_of_month, local.day());
+        let day_of_week = has(self.days_of_week, local.weekday().num_days_from_sunday());
+
+        // As in cron, a day matches either day field when both are restricted
+        let day = match self.restricted_days {
+            (true, true) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+
+        day && has(self.minutes, local.minute())
+            && has(self.hours, local.hour())
+            && has(self.months, local.month())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_rule_schedule() {
+        // Monday 2024-01-01
+        let schedule = RuleSchedule {
+            timezone: "+02:00".to_string(),
+            windows: vec![TimeWindow {
+                days: vec![Weekday::Mon],
+                start: "22:00".to_string(),
+                end: "02:00".to_string(),
+            }],
+            cron: Some("*/15 9-17 * * 1-5".to_string()),
+            duration_minutes: Some(5),
+        };
+        schedule.validate().unwrap();
+
+        // 23:30 and 01:30 local time, across midnight
+        assert!(schedule.is_active(at("2024-01-01T21:30:00Z")).unwrap());
+        assert!(schedule.is_active(at("2024-01-01T23:30:00Z")).unwrap());
+        assert!(!schedule.is_active(at("2024-01-02T00:30:00Z")).unwrap());
+
+        // 09:15 to 09:19 local time on a Tuesday, for the 5 minutes from the cron match
+        assert!(!schedule.is_active(at("2024-01-02T07:14:00Z")).unwrap());
+        assert!(schedule.is_active(at("2024-01-02T07:15:00Z")).unwrap());
+        assert!(schedule.is_active(at("2024-01-02T07:19:59Z")).unwrap());
+        assert!(!schedule.is_active(at("2024-01-02T07:20:00Z")).unwrap());
+        // Periods started at 17:45 run past the last matching hour
+        assert!(schedule.is_active(at("2024-01-02T15:48:00Z")).unwrap());
+        // 09:15 on a Sunday
+        assert!(!schedule.is_active(at("2024-01-07T07:15:00Z")).unwrap());
+
+        assert!(parse_timezone("Europe/Paris").is_err());
+        assert!(CronExpression::parse("60 * * * *").is_err());
+        assert!(CronExpression::parse("* * *").is_err());
+        let cron = |duration_minutes| RuleSchedule {
+            timezone: default_timezone(),
+            windows: Vec::new(),
+            cron: Some("0 9 * * *".to_string()),
+            duration_minutes,
+        };
+        assert!(matches!(cron(None).validate(), Err(ScheduleError::MissingDuration)));
+        assert!(matches!(cron(Some(0)).validate(), Err(ScheduleError::InvalidDuration(0))));
+        assert!(cron(Some(MAX_DURATION_MINUTES)).validate().is_ok());
+        assert!(matches!(
+            RuleSchedule { timezone: default_timezone(), windows: Vec::new(), cron: None, duration_minutes: None }.validate(),
+            Err(ScheduleError::Empty)
+        ));
+    }
+}
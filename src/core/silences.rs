@@ -0,0 +1,204 @@
+//! Alert silences for the DDoS protection service.
+//!
+//! A silence mutes the alerts it matches, by source, level and labels, for a
+//! time window, so planned load tests and deploys don't page anyone.
+//! Silenced alerts are still stored and can be listed, but aren't sent to
+//! alert channels or webhooks. Silences live in Redis, shared by all
+//! instances.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
+use uuid::Uuid;
+use crate::core::monitoring::{Alert, AlertLevel};
+use crate::core::redis_pool::RedisPool;
+
+/// Redis hash holding silences keyed by ID
+const SILENCES_KEY: &str = "monitoring:silences";
+/// How long ended silences are kept for reference
+const ENDED_RETENTION_DAYS: i64 = 7;
+
+/// Errors that can occur during silence operations
+#[derive(Error, Debug)]
+pub enum SilenceError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Invalid silence: {0}")]
+    InvalidSilence(String),
+}
+
+/// Alerts muted by a silence; every matcher given must match
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SilenceMatchers {
+    /// Alert source, such as an alert rule's name
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Alert level
+    #[serde(default)]
+    pub level: Option<AlertLevel>,
+    /// Labels the alert must carry with these values
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+/// A time window in which matching alerts aren't notified
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Silence {
+    pub id: String,
+    #[serde(flatten)]
+    pub matchers: SilenceMatchers,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    /// Why the alerts are silenced
+    #[serde(default)]
+    pub comment: String,
+    #[serde(default)]
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Silence {
+    /// Create a silence, checking that it matches something and ends after it starts
+    pub fn new(matchers: SilenceMatchers, starts_at: DateTime<Utc>, ends_at: DateTime<Utc>) -> Result<Self, SilenceError> {
+        if matchers == SilenceMatchers::default() {
+            return Err(SilenceError::InvalidSilence(
+                "at least one of source, level or labels is required".to_string(),
+            ));
+        }
+        if ends_at <= starts_at {
+            return Err(SilenceError::InvalidSilence("ends_at must be after starts_at".to_string()));
+        }
+        Ok(Self {
+            id: Uuid::new_v4().to_string(),
+            matchers,
+            starts_at,
+            ends_at,
+            comment: String::new(),
+            created_by: None,
+            created_at: Utc::now(),
+        })
+    }
+
+    /// Whether the silence is in effect at `now`
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.starts_at <= now && now < self.ends_at
+    }
+
+    /// Whether the silence mutes an alert at `now`
+    pub fn mutes(&self, alert: &Alert, now: DateTime<Utc>) -> bool {
+        let matchers = &self.matchers;
+        self.is_active(now)
+            && matchers.source.as_ref().is_none_or(|source| *source == alert.source)
+            && matchers.level.as_ref().is_none_or(|level| *level == alert.level)
+            && matchers.labels.iter().all(|(name, value)| alert.labels.get(name) == Some(value))
+    }
+}
+
+/// Silence storage
+#[derive(Clone)]
+pub struct Silences {
+    redis: RedisPool,
+}
+
+impl Silences {
+    pub fn new(redis: RedisPool) -> Self {
+        Self { redis }
+    }
+
+    /// Create or replace a silence
+    pub async fn set_silence(&self, silence: &Silence) -> Result<(), SilenceError> {
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("HSET")
+            .arg(SILENCES_KEY)
+            .arg(&silence.id)
+            .arg(serde_json::to_string(silence)?)
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// All silences, soonest ending first, dropping those that ended long ago
+    pub async fn get_silences(&self) -> Result<Vec<Silence>, SilenceError> {
+        let mut conn = self.redis.get();
+        let silences_json: Vec<String> = redis::cmd("HVALS")
+            .arg(SILENCES_KEY)
+            .query_async(&mut conn)
+            .await?;
+        let (mut silences, expired): (Vec<Silence>, Vec<Silence>) = silences_json
+            .into_iter()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .partition(|silence: &Silence| silence.ends_at + Duration::days(ENDED_RETENTION_DAYS) > Utc::now());
+
+        if !expired.is_empty() {
+            let mut pipe = redis::pipe();
+            for silence in &expired {
+                pipe.cmd("HDEL").arg(SILENCES_KEY).arg(&silence.id).ignore();
+            }
+            let _: () = pipe.query_async(&mut conn).await?;
+        }
+        silences.sort_by_key(|silence| silence.ends_at);
+        Ok(silences)
+    }
+
+    /// The silence muting an alert right now, if any
+    pub async fn muting(&self, alert: &Alert) -> Result<Option<Silence>, SilenceError> {
+        let now = Utc::now();
+        Ok(self.get_silences().await?.into_iter().find(|silence| silence.mutes(alert, now)))
+    }
+
+    /// Get a silence by ID
+    pub async fn get_silence(&self, id: &str) -> Result<Option<Silence>, SilenceError> {
+        let mut conn = self.redis.get();
+        let json: Option<String> = redis::cmd("HGET")
+            .arg(SILENCES_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Remove a silence, returning whether it existed
+    pub async fn remove_silence(&self, id: &str) -> Result<bool, SilenceError> {
+        let mut conn = self.redis.get();
+        let removed: u32 = redis::cmd("HDEL")
+            .arg(SILENCES_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mutes() {
+        let now = Utc::now();
+        let matchers = SilenceMatchers {
+            level: Some(AlertLevel::Warning),
+            labels: HashMap::from([("team".to_string(), "edge".to_string())]),
+            ..SilenceMatchers::default()
+        };
+        let silence = Silence::new(matchers, now - Duration::minutes(5), now + Duration::hours(1)).unwrap();
+
+        let mut alert = Alert::new(AlertLevel::Warning, "High Request Rate", "request_rate is 2000.00 (> 1000)");
+        assert!(!silence.mutes(&alert, now));
+        alert.labels.insert("team".to_string(), "edge".to_string());
+        assert!(silence.mutes(&alert, now));
+        assert!(!silence.mutes(&alert, now + Duration::hours(2)));
+        alert.level = AlertLevel::Critical;
+        assert!(!silence.mutes(&alert, now));
+
+        assert!(Silence::new(SilenceMatchers::default(), now, now + Duration::hours(1)).is_err());
+        let source = SilenceMatchers {
+            source: Some("High Request Rate".to_string()),
+            ..SilenceMatchers::default()
+        };
+        assert!(Silence::new(source, now, now).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//! Syslog output for the DDoS protection service.
+//!
+//! Forwards analytics events to a syslog server as CEF or LEEF messages so
+//! that blocks, attacks and rule matches show up in SIEMs such as Splunk,
+//! ArcSight and QRadar. Messages use the RFC 5424 header and are sent over
+//! UDP, or over TCP or TLS with octet-counted framing.
+
+use async_trait::async_trait;
+use chrono::SecondsFormat;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio_native_tls::TlsStream;
+use crate::core::analytics::{Event, EventType};
+use crate::core::event_sink::{EventSink, SinkError};
+use crate::models::{SyslogFormat, SyslogProtocol, SyslogSinkConfig};
+
+const VENDOR: &str = "dejetem";
+const PRODUCT: &str = "ddos-protection-service";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Event data fields with a standard CEF and LEEF key
+const KNOWN_FIELDS: [(&str, &str, &str); 4] = [
+    ("ip", "src", "src"),
+    ("path", "request", "url"),
+    ("method", "requestMethod", "method"),
+    ("reason", "reason", "reason"),
+];
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// Sink forwarding events to a syslog server
+pub struct SyslogSink {
+    config: SyslogSinkConfig,
+    hostname: String,
+    /// Open connection, re-established after a failure
+    connection: Mutex<Option<Connection>>,
+}
+
+impl SyslogSink {
+    pub fn new(config: SyslogSinkConfig) -> Result<Self, SinkError> {
+        if config.facility > 23 {
+            return Err(SinkError::Config(format!("invalid syslog facility: {}", config.facility)));
+        }
+        let hostname = std::env::var("HOSTNAME")
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "-".to_string());
+        Ok(Self {
+            config,
+            hostname,
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn connect(&self) -> Result<Connection, SinkError> {
+        let address = (self.config.host.as_str(), self.config.port);
+        Ok(match self.config.protocol {
+            SyslogProtocol::Udp => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+                socket.connect(address).await?;
+                Connection::Udp(socket)
+            }
+            SyslogProtocol::Tcp => Connection::Tcp(TcpStream::connect(address).await?),
+            SyslogProtocol::Tls => {
+                let connector = native_tls::TlsConnector::builder()
+                    .danger_accept_invalid_certs(!self.config.tls_verify)
+                    .build()
+                    .map_err(|e| SinkError::Tls(e.to_string()))?;
+                let stream = TcpStream::connect(address).await?;
+                let stream = tokio_native_tls::TlsConnector::from(connector)
+                    .connect(&self.config.host, stream)
+                    .await
+                    .map_err(|e| SinkError::Tls(e.to_string()))?;
+                Connection::Tls(Box::new(stream))
+            }
+        })
+    }
+
+    /// Syslog message for an event
+    fn message(&self, event: &Event) -> String {
+        let body = match self.config.format {
+            SyslogFormat::Cef => cef(event),
+            SyslogFormat::Leef => leef(event),
+        };
+        let priority = u16::from(self.config.facility) * 8 + u16::from(syslog_severity(severity(&event.event_type)));
+        format!(
+            "<{}>1 {} {} {} - {} - {}",
+            priority,
+            event.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.hostname,
+            self.config.app_name,
+            type_name(&event.event_type),
+            body
+        )
+    }
+}
+
+#[async_trait]
+impl EventSink for SyslogSink {
+    fn name(&self) -> &str {
+        "syslog"
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        self.config.event_types.contains(&event.event_type)
+    }
+
+    async fn send(&self, events: &[Event]) -> Result<(), SinkError> {
+        let mut connection = self.connection.lock().await;
+        let stream = match connection.as_mut() {
+            Some(stream) => stream,
+            None => connection.insert(self.connect().await?),
+        };
+
+        let mut result = Ok(());
+        for event in events {
+            let message = self.message(event);
+            result = match stream {
+                Connection::Udp(socket) => socket.send(message.as_bytes()).await.map(|_| ()),
+                Connection::Tcp(stream) => write_framed(stream, &message).await,
+                Connection::Tls(stream) => write_framed(stream.as_mut(), &message).await,
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_err() {
+            *connection = None;
+        }
+        result.map_err(SinkError::from)
+    }
+}
+
+/// Write an octet-counted message to a stream
+async fn write_framed<S: AsyncWriteExt + Unpin>(stream: &mut S, message: &str) -> std::io::Result<()> {
+    stream.write_all(format!("{} {}", message.len(), message).as_bytes()).await
+}
+
+/// Name of an event type, used as the event class ID
+fn type_name(event_type: &EventType) -> String {
+    format!("{:?}", event_type)
+}
+
+/// Human-readable name of an event type
+fn display_name(event_type: &EventType) -> &'static str {
+    match event_type {
+        EventType::Request => "Request",
+        EventType::BlockedRequest => "Blocked request",
+        EventType::DdosAttack => "DDoS attack",
+        EventType::RuleTriggered => "Rule triggered",
+        EventType::RateLimitExceeded => "Rate limit exceeded",
+        EventType::QuotaExceeded => "Quota exceeded",
+        EventType::ShadowDecision => "Shadow decision",
+        EventType::RuleExpired => "Rule expired",
+        EventType::RateLimit => "Rate limit",
+        EventType::DdosDetection => "DDoS detection",
+        EventType::SlowConnection => "Slow connection",
+        EventType::Challenge => "Challenge",
+        EventType::HoneypotHit => "Honeypot hit",
+        EventType::LoginProtection => "Login protection",
+        EventType::Escalation => "Escalation",
+        EventType::RuleEngine => "Rule engine",
+        EventType::System => "System",
+    }
+}
+
+/// Severity of an event type on the CEF scale of 0 to 10
+fn severity(event_type: &EventType) -> u8 {
+    match event_type {
+        EventType::DdosAttack | EventType::DdosDetection => 9,
+        EventType::BlockedRequest | EventType::HoneypotHit | EventType::SlowConnection => 7,
+        EventType::RuleTriggered
+        | EventType::RateLimitExceeded
+        | EventType::QuotaExceeded
+        | EventType::LoginProtection
+        | EventType::Escalation => 5,
+        _ => 3,
+    }
+}
+
+/// Syslog severity for a CEF severity
+fn syslog_severity(severity: u8) -> u8 {
+    match severity {
+        9.. => 2,
+        7..=8 => 4,
+        4..=6 => 5,
+        _ => 6,
+    }
+}
+
+fn value_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Event data as key-value pairs, standard fields first under their format's key
+fn fields(event: &Event, leef: bool) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    for (name, cef_key, leef_key) in KNOWN_FIELDS {
+        if let Some(value) = event.data.get(name) {
+            let key = if leef { leef_key } else { cef_key };
+            fields.push((key.to_string(), value_string(value)));
+        }
+    }
+
+    let mut rest: Vec<_> = event
+        .data
+        .iter()
+        .filter(|(name, _)| !KNOWN_FIELDS.iter().any(|(known, _, _)| known == name))
+        .map(|(name, value)| {
+            let key: String = name.chars().filter(char::is_ascii_alphanumeric).collect();
+            (key, value_string(value))
+        })
+        .filter(|(key, _)| !key.is_empty())
+        .collect();
+    rest.sort();
+    fields.extend(rest);
+    fields
+}
+
+fn escape_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+/// Format an event as CEF
+fn cef(event: &Event) -> String {
+    let mut extension = vec![
+        format!("rt={}", event.timestamp.timestamp_millis()),
+        format!("cat={}", type_name(&event.event_type)),
+        format!("externalId={}", event.id),
+        format!("deviceFacility={}", escape_cef_value(&event.source)),
+    ];
+    for (key, value) in fields(event, false) {
+        extension.push(format!("{}={}", key, escape_cef_value(&value)));
+    }
+    format!(
+        "CEF:0|{}|{}|{}|{}|{}|{}|{}",
+        escape_header(VENDOR),
+        escape_header(PRODUCT),
+        escape_header(VERSION),
+        type_name(&event.event_type),
+        display_name(&event.event_type),
+        severity(&event.event_type),
+        extension.join(" ")
+    )
+}
+
+fn escape_cef_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+/// Format an event as LEEF 2.0, tab-delimited
+fn leef(event: &Event) -> String {
+    let mut attributes = vec![
+        format!("devTime={}", event.timestamp.timestamp_millis()),
+        "devTimeFormat=epoch".to_string(),
+        format!("cat={}", type_name(&event.event_type)),
+        format!("sev={}", severity(&event.event_type).max(1)),
+        format!("externalId={}", event.id),
+        format!("source={}", escape_leef_value(&event.source)),
+    ];
+    for (key, value) in fields(event, true) {
+        attributes.push(format!("{}={}", key, escape_leef_value(&value)));
+    }
+    format!(
+        "LEEF:2.0|{}|{}|{}|{}|x09|{}",
+        escape_header(VENDOR),
+        escape_header(PRODUCT),
+        escape_header(VERSION),
+        type_name(&event.event_type),
+        attributes.join("\t")
+    )
+}
+
+fn escape_leef_value(value: &str) -> String {
+    value.replace(['\t', '\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn event() -> Event {
+        let data = HashMap::from([
+            ("ip".to_string(), serde_json::json!("203.0.113.7")),
+            ("path".to_string(), serde_json::json!("/login")),
+            ("rule".to_string(), serde_json::json!("a=b|c")),
+            ("score".to_string(), serde_json::json!(0.9)),
+        ]);
+        Event::new(EventType::BlockedRequest, "api", data)
+    }
+
+    #[test]
+    fn test_cef() {
+        let event = event();
+        let message = cef(&event);
+        assert!(message.starts_with(&format!(
+            "CEF:0|dejetem|ddos-protection-service|{}|BlockedRequest|Blocked request|7|rt=",
+            VERSION
+        )));
+        assert!(message.contains(" cat=BlockedRequest "));
+        assert!(message.contains(" src=203.0.113.7 request=/login rule=a\\=b|c score=0.9"));
+    }
+
+    #[test]
+    fn test_leef_and_syslog_header() {
+        let event = event();
+        let message = leef(&event);
+        assert!(message.starts_with("LEEF:2.0|dejetem|ddos-protection-service|"));
+        assert!(message.contains("|BlockedRequest|x09|devTime="));
+        assert!(message.contains("\tsev=7\t"));
+        assert!(message.contains("\tsrc=203.0.113.7\turl=/login\trule=a=b|c\tscore=0.9"));
+
+        let sink = SyslogSink::new(SyslogSinkConfig {
+            format: SyslogFormat::Leef,
+            ..SyslogSinkConfig::default()
+        })
+        .unwrap();
+        // local0 (16) * 8 + warning (4)
+        assert!(sink.message(&event).starts_with("<132>1 "));
+        assert!(sink.message(&event).contains(" ddos-protection - BlockedRequest - LEEF:2.0|"));
+
+        assert!(SyslogSink::new(SyslogSinkConfig { facility: 24, ..SyslogSinkConfig::default() }).is_err());
+    }
+}
@@ -0,0 +1,564 @@
+//! OpenTelemetry export for the DDoS protection service.
+//!
+//! The request decision path and background tasks are instrumented with
+//! `tracing` spans. When telemetry is enabled, this module is installed as
+//! the global `tracing` subscriber: it assigns trace and span IDs, continues
+//! traces started by the proxy through the W3C `traceparent` header, and
+//! exports sampled spans to an OTLP/HTTP collector together with histograms
+//! of span durations, so decision latency can be broken down per stage.
+//!
+//! Only spans of this crate are recorded; `tracing` events are left to the
+//! `log` based logging.
+
+use log::{error, warn};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use uuid::Uuid;
+use crate::models::TelemetryConfig;
+
+/// Upper bounds of the span duration histogram buckets, in seconds
+const DURATION_BOUNDS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// OTLP span kinds
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+/// OTLP cumulative aggregation temporality
+const TEMPORALITY_CUMULATIVE: u8 = 2;
+
+thread_local! {
+    /// Spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Trace context of a W3C `traceparent` header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+/// Parse a `traceparent` header (`00-<trace id>-<parent id>-<flags>`)
+pub fn parse_traceparent(value: &str) -> Option<TraceContext> {
+    let mut parts = value.trim().split('-');
+    let version = decode_hex::<1>(parts.next()?)?;
+    let trace_id = decode_hex::<16>(parts.next()?)?;
+    let span_id = decode_hex::<8>(parts.next()?)?;
+    let flags = decode_hex::<1>(parts.next()?)?;
+    // Later versions may append fields, version 00 may not
+    if version[0] == 0xff || (version[0] == 0 && parts.next().is_some()) {
+        return None;
+    }
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+    Some(TraceContext {
+        trace_id,
+        span_id,
+        sampled: flags[0] & 0x01 != 0,
+    })
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || !value.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+impl AttributeValue {
+    fn to_otlp(&self) -> Value {
+        match self {
+            AttributeValue::String(value) => json!({ "stringValue": value }),
+            AttributeValue::Int(value) => json!({ "intValue": value.to_string() }),
+            AttributeValue::Double(value) => json!({ "doubleValue": value }),
+            AttributeValue::Bool(value) => json!({ "boolValue": value }),
+        }
+    }
+}
+
+/// A span that has not closed yet
+struct SpanData {
+    name: &'static str,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    /// Whether the parent is local, so a `traceparent` must not override it
+    local_parent: bool,
+    sampled: bool,
+    server: bool,
+    start: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    /// Handles to the span still alive
+    refs: usize,
+}
+
+/// A closed span waiting for export
+#[derive(Debug)]
+struct FinishedSpan {
+    name: &'static str,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    server: bool,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+}
+
+impl FinishedSpan {
+    fn to_otlp(&self) -> Value {
+        let mut span = json!({
+            "traceId": encode_hex(&self.trace_id),
+            "spanId": encode_hex(&self.span_id),
+            "name": self.name,
+            "kind": if self.server { SPAN_KIND_SERVER } else { SPAN_KIND_INTERNAL },
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": attributes_to_otlp(&self.attributes),
+        });
+        if let Some(parent_span_id) = self.parent_span_id {
+            span["parentSpanId"] = json!(encode_hex(&parent_span_id));
+        }
+        span
+    }
+}
+
+fn attributes_to_otlp(attributes: &[(&str, AttributeValue)]) -> Vec<Value> {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": value.to_otlp() }))
+        .collect()
+}
+
+/// Cumulative histogram of the durations of one kind of span
+#[derive(Debug, Clone)]
+struct DurationHistogram {
+    bucket_counts: [u64; DURATION_BOUNDS.len() + 1],
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl DurationHistogram {
+    fn new() -> Self {
+        Self {
+            bucket_counts: [0; DURATION_BOUNDS.len() + 1],
+            count: 0,
+            sum: 0.0,
+            min: f64::MAX,
+            max: 0.0,
+        }
+    }
+
+    fn observe(&mut self, seconds: f64) {
+        let bucket = DURATION_BOUNDS.iter().position(|bound| seconds <= *bound).unwrap_or(DURATION_BOUNDS.len());
+        self.bucket_counts[bucket] += 1;
+        self.count += 1;
+        self.sum += seconds;
+        self.min = self.min.min(seconds);
+        self.max = self.max.max(seconds);
+    }
+}
+
+/// Records field values onto a span
+struct SpanVisitor<'a> {
+    span: &'a mut SpanData,
+}
+
+impl SpanVisitor<'_> {
+    fn push(&mut self, field: &Field, value: AttributeValue) {
+        self.span.attributes.retain(|(key, _)| *key != field.name());
+        self.span.attributes.push((field.name(), value));
+    }
+}
+
+impl Visit for SpanVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            // Continue the trace of the proxy, unless the span already has a local parent
+            "traceparent" => {
+                if let Some(context) = parse_traceparent(value).filter(|_| !self.span.local_parent) {
+                    self.span.trace_id = context.trace_id;
+                    self.span.parent_span_id = Some(context.span_id);
+                    self.span.sampled = context.sampled;
+                }
+            }
+            "otel.kind" => self.span.server = value == "server",
+            _ => self.push(field, AttributeValue::String(value.to_string())),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, AttributeValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, AttributeValue::Int(value as i64));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, AttributeValue::Double(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, AttributeValue::Bool(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, AttributeValue::String(format!("{:?}", value)));
+    }
+}
+
+struct Inner {
+    config: TelemetryConfig,
+    /// Target prefix of the spans to record
+    crate_name: &'static str,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    finished: Mutex<Vec<FinishedSpan>>,
+    /// Spans dropped because the export queue was full
+    dropped: AtomicU64,
+    durations: Mutex<HashMap<&'static str, DurationHistogram>>,
+    started_at: SystemTime,
+    client: reqwest::Client,
+}
+
+/// `tracing` subscriber exporting spans and their durations over OTLP
+#[derive(Clone)]
+pub struct Telemetry {
+    inner: Arc<Inner>,
+}
+
+impl Telemetry {
+    /// Create a telemetry exporter
+    pub fn new(config: TelemetryConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                crate_name: module_path!().split("::").next().unwrap_or_default(),
+                next_id: AtomicU64::new(1),
+                spans: Mutex::new(HashMap::new()),
+                finished: Mutex::new(Vec::new()),
+                dropped: AtomicU64::new(0),
+                durations: Mutex::new(HashMap::new()),
+                started_at: SystemTime::now(),
+                client: reqwest::Client::new(),
+            }),
+        }
+    }
+
+    /// Install as the global `tracing` subscriber, if telemetry is enabled
+    pub fn install(&self) -> Result<(), tracing::subscriber::SetGlobalDefaultError> {
+        if !self.inner.config.enabled {
+            return Ok(());
+        }
+        tracing::subscriber::set_global_default(self.clone())
+    }
+
+    /// Export finished spans and duration histograms every export interval
+    pub async fn start(&self) {
+        if !self.inner.config.enabled {
+            return;
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(self.inner.config.export_interval_seconds.max(1)));
+        loop {
+            interval.tick().await;
+            self.flush().await;
+        }
+    }
+
+    /// Export the spans finished since the last export and the duration histograms
+    pub async fn flush(&self) {
+        if !self.inner.config.enabled {
+            return;
+        }
+
+        let spans = std::mem::take(&mut *self.inner.finished.lock().unwrap());
+        let dropped = self.inner.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Dropped {} spans because the telemetry export queue was full", dropped);
+        }
+        if !spans.is_empty() {
+            self.post("/v1/traces", &self.traces_payload(&spans)).await;
+        }
+        self.post("/v1/metrics", &self.metrics_payload(SystemTime::now())).await;
+    }
+
+    async fn post(&self, path: &str, payload: &Value) {
+        let url = format!("{}{}", self.inner.config.otlp_endpoint.trim_end_matches('/'), path);
+        match self.inner.client.post(&url).json(payload).send().await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => error!("OTLP export to {} failed with status {}", url, response.status()),
+            Err(e) => error!("OTLP export to {} failed: {}", url, e),
+        }
+    }
+
+    fn resource(&self) -> Value {
+        json!({
+            "attributes": [
+                { "key": "service.name", "value": { "stringValue": self.inner.config.service_name } },
+                { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+            ]
+        })
+    }
+
+    fn traces_payload(&self, spans: &[FinishedSpan]) -> Value {
+        json!({
+            "resourceSpans": [{
+                "resource": self.resource(),
+                "scopeSpans": [{
+                    "scope": { "name": self.inner.crate_name },
+                    "spans": spans.iter().map(FinishedSpan::to_otlp).collect::<Vec<_>>(),
+                }],
+            }]
+        })
+    }
+
+    fn metrics_payload(&self, now: SystemTime) -> Value {
+        let durations = self.inner.durations.lock().unwrap();
+        let mut names: Vec<_> = durations.keys().copied().collect();
+        names.sort();
+        let data_points: Vec<Value> = names
+            .into_iter()
+            .map(|name| {
+                let histogram = &durations[name];
+                json!({
+                    "attributes": [{ "key": "span.name", "value": { "stringValue": name } }],
+                    "startTimeUnixNano": unix_nanos(self.inner.started_at),
+                    "timeUnixNano": unix_nanos(now),
+                    "count": histogram.count.to_string(),
+                    "sum": histogram.sum,
+                    "min": histogram.min,
+                    "max": histogram.max,
+                    "bucketCounts": histogram.bucket_counts.iter().map(u64::to_string).collect::<Vec<_>>(),
+                    "explicitBounds": DURATION_BOUNDS,
+                })
+            })
+            .collect();
+        json!({
+            "resourceMetrics": [{
+                "resource": self.resource(),
+                "scopeMetrics": [{
+                    "scope": { "name": self.inner.crate_name },
+                    "metrics": [{
+                        "name": "span.duration",
+                        "description": "Duration of the spans of the decision path and background tasks",
+                        "unit": "s",
+                        "histogram": {
+                            "aggregationTemporality": TEMPORALITY_CUMULATIVE,
+                            "dataPoints": data_points,
+                        },
+                    }],
+                }],
+            }]
+        })
+    }
+
+    fn finish(&self, span: SpanData) {
+        let end = SystemTime::now();
+        let seconds = end.duration_since(span.start).unwrap_or_default().as_secs_f64();
+        self.inner
+            .durations
+            .lock()
+            .unwrap()
+            .entry(span.name)
+            .or_insert_with(DurationHistogram::new)
+            .observe(seconds);
+
+        if !span.sampled {
+            return;
+        }
+        let mut finished = self.inner.finished.lock().unwrap();
+        if finished.len() >= self.inner.config.max_queue_size {
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        finished.push(FinishedSpan {
+            name: span.name,
+            trace_id: span.trace_id,
+            span_id: span.span_id,
+            parent_span_id: span.parent_span_id,
+            server: span.server,
+            start: span.start,
+            end,
+            attributes: span.attributes,
+        });
+    }
+
+    fn sample(&self) -> bool {
+        let random = u32::from_le_bytes(Uuid::new_v4().as_bytes()[..4].try_into().unwrap());
+        f64::from(random) / f64::from(u32::MAX) < self.inner.config.sample_ratio
+    }
+}
+
+fn random_span_id() -> [u8; 8] {
+    Uuid::new_v4().as_bytes()[..8].try_into().unwrap()
+}
+
+impl Subscriber for Telemetry {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() && metadata.target().starts_with(self.inner.crate_name)
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let parent_id = if let Some(parent) = attributes.parent() {
+            Some(parent.into_u64())
+        } else if attributes.is_contextual() {
+            ENTERED.with(|entered| entered.borrow().last().copied())
+        } else {
+            None
+        };
+
+        let mut spans = self.inner.spans.lock().unwrap();
+        let parent = parent_id.and_then(|id| spans.get(&id));
+        let mut span = SpanData {
+            name: attributes.metadata().name(),
+            trace_id: parent.map(|parent| parent.trace_id).unwrap_or_else(|| *Uuid::new_v4().as_bytes()),
+            span_id: random_span_id(),
+            parent_span_id: parent.map(|parent| parent.span_id),
+            local_parent: parent.is_some(),
+            sampled: parent.map(|parent| parent.sampled).unwrap_or_else(|| self.sample()),
+            server: false,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            refs: 1,
+        };
+        attributes.record(&mut SpanVisitor { span: &mut span });
+
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        spans.insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.inner.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut SpanVisitor { span });
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let Some(span) = self.inner.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            span.refs += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let mut spans = self.inner.spans.lock().unwrap();
+        let Some(span) = spans.get_mut(&id.into_u64()) else {
+            return false;
+        };
+        span.refs -= 1;
+        if span.refs > 0 {
+            return false;
+        }
+        let span = spans.remove(&id.into_u64()).unwrap();
+        drop(spans);
+        self.finish(span);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let context = parse_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01").unwrap();
+        assert_eq!(encode_hex(&context.trace_id), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(encode_hex(&context.span_id), "b7ad6b7169203331");
+        assert!(context.sampled);
+
+        assert!(!parse_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00").unwrap().sampled);
+        // Invalid version, all-zero IDs, uppercase hex and wrong lengths
+        assert_eq!(parse_traceparent("ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"), None);
+        assert_eq!(parse_traceparent("00-00000000000000000000000000000000-b7ad6b7169203331-01"), None);
+        assert_eq!(parse_traceparent("00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01"), None);
+        assert_eq!(parse_traceparent("00-0af7651916cd43dd-b7ad6b7169203331-01"), None);
+        assert_eq!(parse_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra"), None);
+    }
+
+    #[test]
+    fn test_spans_continue_proxy_trace() {
+        let config = TelemetryConfig { enabled: true, sample_ratio: 0.0, ..Default::default() };
+        let telemetry = Telemetry::new(config);
+        tracing::subscriber::with_default(telemetry.clone(), || {
+            let root = tracing::info_span!(
+                "authorize",
+                otel.kind = "server",
+                traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                ip = %"203.0.113.7",
+            );
+            let _entered = root.enter();
+            tracing::info_span!("rules").in_scope(|| {});
+            // Not sampled, but still counted in the duration histograms
+            tracing::info_span!(parent: None, "flush").in_scope(|| {});
+        });
+
+        let finished = telemetry.inner.finished.lock().unwrap();
+        assert_eq!(finished.len(), 2);
+        let (rules, authorize) = (&finished[0], &finished[1]);
+        assert_eq!(authorize.name, "authorize");
+        assert!(authorize.server);
+        assert_eq!(encode_hex(&authorize.trace_id), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(authorize.parent_span_id.map(|id| encode_hex(&id)), Some("b7ad6b7169203331".to_string()));
+        assert_eq!(authorize.attributes, vec![("ip", AttributeValue::String("203.0.113.7".to_string()))]);
+        assert_eq!(rules.trace_id, authorize.trace_id);
+        assert_eq!(rules.parent_span_id, Some(authorize.span_id));
+        assert!(!rules.server);
+
+        let durations = telemetry.inner.durations.lock().unwrap();
+        assert_eq!(durations.len(), 3);
+        assert_eq!(durations["flush"].count, 1);
+    }
+}
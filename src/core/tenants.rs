@@ -0,0 +1,307 @@
+//! Tenants for the DDoS protection service.
+//!
+//! One deployment can protect many customer properties, each a tenant with
+//! its own rate limit, blocklist and rules. Decision requests are attributed
+//! to a tenant by the tenant header or by the API key they carry; those
+//! without one are handled globally, as before. A tenant's clients are
+//! counted under `tenant:{id}:{client}` and its blocklist lives under
+//! `tenant:{id}:`, so its state is kept apart from other tenants' and is
+//! removed along with it. Tenants live in Redis, shared by all instances.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::core::blocklist::Blocklist;
+use crate::core::redis_pool::RedisPool;
+use crate::utils::redact_key;
+
+/// Redis hash holding tenants keyed by ID
+const TENANTS_KEY: &str = "tenants";
+/// Redis hash mapping API keys to the tenant owning them
+const TENANT_API_KEYS_KEY: &str = "tenants:api_keys";
+/// Longest tenant ID
+const MAX_TENANT_ID_LENGTH: usize = 64;
+/// Keys deleted per round trip when a tenant's state is removed
+const PURGE_BATCH: usize = 500;
+
+/// Errors that can occur during tenant operations
+#[derive(Error, Debug)]
+pub enum TenantError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Invalid tenant: {0}")]
+    InvalidTenant(String),
+    #[error("API key already belongs to tenant {0}")]
+    KeyInUse(String),
+}
+
+/// A customer property protected by the service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tenant {
+    /// Tenant ID, as sent in the tenant header
+    pub id: String,
+    pub name: String,
+    /// API keys whose requests are attributed to the tenant
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// Requests per window allowed to each client, instead of `rate_limit.default_limit`
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Tenant {
+    /// Create a tenant, checking that its ID can be sent in a header and used in keys
+    pub fn new(id: &str, name: &str) -> Result<Self, TenantError> {
+        if id.is_empty() || id.len() > MAX_TENANT_ID_LENGTH {
+            return Err(TenantError::InvalidTenant(format!(
+                "id must be 1 to {} characters",
+                MAX_TENANT_ID_LENGTH
+            )));
+        }
+        if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(TenantError::InvalidTenant(
+                "id may only contain letters, digits, '-' and '_'".to_string(),
+            ));
+        }
+        let now = Utc::now();
+        Ok(Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            api_keys: Vec::new(),
+            rate_limit: None,
+            created_at: now,
+            updated_at: now,
+        })
+    }
+
+    /// Key a client of the tenant is counted under
+    pub fn client_key(&self, key: &str) -> String {
+        client_key(&self.id, key)
+    }
+
+    /// Copy of the tenant with its API keys hidden
+    pub fn redacted(mut self) -> Self {
+        self.api_keys = self.api_keys.iter().map(|key| redact_key(key)).collect();
+        self
+    }
+}
+
+/// Prefix of a tenant's Redis keys
+pub fn namespace(tenant: &str) -> String {
+    format!("tenant:{}:", tenant)
+}
+
+/// Key a client of a tenant is counted under
+pub fn client_key(tenant: &str, key: &str) -> String {
+    format!("{}{}", namespace(tenant), key)
+}
+
+/// Tenant storage, with the blocklist of each tenant
+///
+/// Cloning is cheap and all clones share the tenant blocklists.
+#[derive(Clone)]
+pub struct Tenants {
+    redis: RedisPool,
+    /// Global blocklist the tenant blocklists are made from
+    blocklist: Blocklist,
+    /// Blocklists of the tenants that were used, by tenant ID
+    blocklists: Arc<RwLock<HashMap<String, Blocklist>>>,
+}
+
+impl Tenants {
+    pub fn new(redis: RedisPool, blocklist: Blocklist) -> Self {
+        Self {
+            redis,
+            blocklist,
+            blocklists: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// All tenants, by ID
+    pub async fn get_tenants(&self) -> Result<Vec<Tenant>, TenantError> {
+        let mut conn = self.redis.get();
+        let tenants_json: Vec<String> = redis::cmd("HVALS")
+            .arg(TENANTS_KEY)
+            .query_async(&mut conn)
+            .await?;
+        let mut tenants: Vec<Tenant> = tenants_json
+            .into_iter()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect();
+        tenants.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(tenants)
+    }
+
+    /// Get a tenant by ID
+    pub async fn get_tenant(&self, id: &str) -> Result<Option<Tenant>, TenantError> {
+        let mut conn = self.redis.get();
+        let json: Option<String> = redis::cmd("HGET")
+            .arg(TENANTS_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Tenant owning an API key, if any
+    pub async fn tenant_for_key(&self, api_key: &str) -> Result<Option<Tenant>, TenantError> {
+        let mut conn = self.redis.get();
+        let id: Option<String> = redis::cmd("HGET")
+            .arg(TENANT_API_KEYS_KEY)
+            .arg(api_key)
+            .query_async(&mut conn)
+            .await?;
+        match id {
+            Some(id) => self.get_tenant(&id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Create or replace a tenant, moving its API keys over
+    ///
+    /// Fails without changing anything if another tenant owns one of the keys.
+    pub async fn set_tenant(&self, tenant: &Tenant) -> Result<(), TenantError> {
+        let previous = self.get_tenant(&tenant.id).await?;
+        let mut conn = self.redis.get();
+        if !tenant.api_keys.is_empty() {
+            let owners: Vec<Option<String>> = redis::cmd("HMGET")
+                .arg(TENANT_API_KEYS_KEY)
+                .arg(&tenant.api_keys)
+                .query_async(&mut conn)
+                .await?;
+            if let Some(owner) = owners.into_iter().flatten().find(|owner| *owner != tenant.id) {
+                return Err(TenantError::KeyInUse(owner));
+            }
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let removed: Vec<&String> = previous
+            .iter()
+            .flat_map(|previous| &previous.api_keys)
+            .filter(|key| !tenant.api_keys.contains(key))
+            .collect();
+        if !removed.is_empty() {
+            pipe.cmd("HDEL").arg(TENANT_API_KEYS_KEY).arg(removed).ignore();
+        }
+        for key in &tenant.api_keys {
+            pipe.cmd("HSET").arg(TENANT_API_KEYS_KEY).arg(key).arg(&tenant.id).ignore();
+        }
+        pipe.cmd("HSET")
+            .arg(TENANTS_KEY)
+            .arg(&tenant.id)
+            .arg(serde_json::to_string(tenant)?)
+            .ignore();
+        let _: () = pipe.query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Remove a tenant along with its counters and blocklist, returning whether it existed
+    pub async fn remove_tenant(&self, id: &str) -> Result<bool, TenantError> {
+        let Some(tenant) = self.get_tenant(id).await? else {
+            return Ok(false);
+        };
+        let mut conn = self.redis.get();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if !tenant.api_keys.is_empty() {
+            pipe.cmd("HDEL").arg(TENANT_API_KEYS_KEY).arg(&tenant.api_keys).ignore();
+        }
+        pipe.cmd("HDEL").arg(TENANTS_KEY).arg(id).ignore();
+        let _: () = pipe.query_async(&mut conn).await?;
+
+        self.blocklists.write().await.remove(id);
+        self.purge(id).await?;
+        Ok(true)
+    }
+
+    /// Delete every key in a tenant's namespace
+    async fn purge(&self, id: &str) -> Result<(), TenantError> {
+        // Counters keep their own prefix, such as `rate_limit:tenant:{id}:{client}`
+        let pattern = format!("*{}*", namespace(id));
+        let mut conn = self.redis.get();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(PURGE_BATCH)
+                .query_async(&mut conn)
+                .await?;
+            if !keys.is_empty() {
+                let mut pipe = redis::pipe();
+                for key in &keys {
+                    pipe.cmd("DEL").arg(key).ignore();
+                }
+                let _: () = pipe.query_async(&mut conn).await?;
+            }
+            if next == 0 {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+
+    /// Blocklist of a tenant, loaded the first time it is used
+    pub async fn blocklist(&self, id: &str) -> Blocklist {
+        if let Some(blocklist) = self.blocklists.read().await.get(id) {
+            return blocklist.clone();
+        }
+        let blocklist = self.blocklist.for_tenant(id);
+        if let Err(e) = blocklist.reload().await {
+            log::error!("Failed to load blocklist of tenant {}: {}", id, e);
+        }
+        self.blocklists.write().await.entry(id.to_string()).or_insert(blocklist).clone()
+    }
+
+    /// Periodically reload the tenant blocklists in use, so ranges blocked
+    /// on other instances are picked up
+    pub async fn start_refresh(&self) {
+        let interval = self.blocklist.refresh_interval().max(Duration::from_secs(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            let blocklists: Vec<(String, Blocklist)> = self
+                .blocklists
+                .read()
+                .await
+                .iter()
+                .map(|(id, blocklist)| (id.clone(), blocklist.clone()))
+                .collect();
+            for (id, blocklist) in blocklists {
+                if let Err(e) = blocklist.reload().await {
+                    log::error!("Failed to reload blocklist of tenant {}: {}", id, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant() {
+        let tenant = Tenant::new("shop-eu", "Shop EU").unwrap();
+        assert_eq!(tenant.client_key("203.0.113.7"), "tenant:shop-eu:203.0.113.7");
+        assert!(Tenant::new("", "Empty").is_err());
+        assert!(Tenant::new("shop:eu", "Colon").is_err());
+        assert!(Tenant::new(&"a".repeat(65), "Long").is_err());
+
+        let tenant = Tenant {
+            api_keys: vec!["sk_live_0123456789".to_string(), "short".to_string()],
+            ..tenant
+        };
+        assert_eq!(tenant.redacted().api_keys, ["sk_l...6789", "*****"]);
+    }
+}
@@ -0,0 +1,183 @@
+//! Threat intelligence feeds for the DDoS protection service.
+//!
+//! This module periodically downloads lists of known-bad addresses (such as
+//! Spamhaus DROP, FireHOL or the AbuseIPDB blacklist) and loads them into
+//! the blocklist or the reputation store. Entries expire after the feed's
+//! TTL, so addresses dropped from a feed lapse on their own.
+
+use std::time::Duration;
+use chrono::Utc;
+use futures::future::join_all;
+use ipnet::IpNet;
+use thiserror::Error;
+use crate::core::blocklist::{Blocklist, BlocklistError};
+use crate::core::reputation::{Reputation, ReputationError};
+use crate::models::{ThreatFeed, ThreatFeedAction, ThreatIntelConfig};
+use crate::utils::parse_network;
+
+/// Errors that can occur while loading feeds
+#[derive(Error, Debug)]
+pub enum ThreatIntelError {
+    #[error("Download failed: {0}")]
+    Download(#[from] reqwest::Error),
+    #[error("Blocklist error: {0}")]
+    Blocklist(#[from] BlocklistError),
+    #[error("Reputation error: {0}")]
+    Reputation(#[from] ReputationError),
+}
+
+/// Parse a feed into networks
+///
+/// Comments, unparsable lines and reserved ranges (private, loopback,
+/// multicast and the like, which some bogon lists include) are skipped so
+/// that a feed can never block internal traffic.
+pub fn parse_feed(contents: &str) -> Vec<IpNet> {
+    contents
+        .lines()
+        .filter_map(|line| line.split(['#', ';']).next()?.split_whitespace().next())
+        .filter_map(parse_network)
+        .filter(|network| !is_reserved(network))
+        .collect()
+}
+
+/// Whether a network is a reserved range or too broad to block wholesale
+fn is_reserved(network: &IpNet) -> bool {
+    match network {
+        IpNet::V4(network) => {
+            let addr = network.network();
+            network.prefix_len() < 8
+                || addr.is_private()
+                || addr.is_loopback()
+                || addr.is_link_local()
+                || addr.is_unspecified()
+                || addr.is_multicast()
+                || addr.is_broadcast()
+        }
+        IpNet::V6(network) => {
+            let addr = network.network();
+            network.prefix_len() < 16 || addr.is_loopback() || addr.is_unspecified() || addr.is_multicast()
+        }
+    }
+}
+
+/// Threat intelligence feed loader
+pub struct ThreatIntel {
+    /// HTTP client
+    client: reqwest::Client,
+    /// Threat intelligence configuration
+    config: ThreatIntelConfig,
+    /// Blocklist used by the `block` action
+    blocklist: Blocklist,
+    /// Reputation store used by the `reputation` action
+    reputation: Option<Reputation>,
+}
+
+impl ThreatIntel {
+    /// Create a new feed loader
+    pub fn new(config: ThreatIntelConfig, blocklist: Blocklist) -> Result<Self, ThreatIntelError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds.max(1)))
+            .build()?;
+        Ok(Self {
+            client,
+            config,
+            blocklist,
+            reputation: None,
+        })
+    }
+
+    /// Load feeds with the `reputation` action into a reputation store
+    pub fn with_reputation(mut self, reputation: Reputation) -> Self {
+        self.reputation = Some(reputation);
+        self
+    }
+
+    /// Download a feed and load its entries, returning how many were loaded
+    #[tracing::instrument(name = "threat_intel.refresh_feed", skip_all, fields(feed = %feed.name))]
+    pub async fn refresh_feed(&self, feed: &ThreatFeed) -> Result<usize, ThreatIntelError> {
+        let mut request = self.client.get(&feed.url);
+        for (name, value) in &feed.headers {
+            request = request.header(name, value);
+        }
+        let contents = request.send().await?.error_for_status()?.text().await?;
+        let networks = parse_feed(&contents);
+
+        let ttl = Duration::from_secs(feed.ttl_seconds.max(1));
+        let loaded = match feed.action {
+            ThreatFeedAction::Block => {
+                let reason = format!("listed by threat intel feed {}", feed.name);
+                self.blocklist.block_many(&networks, &reason, "threat_intel", Some(ttl)).await?;
+                networks.len()
+            }
+            ThreatFeedAction::Reputation => {
+                let reputation = match &self.reputation {
+                    Some(reputation) => reputation,
+                    None => return Ok(0),
+                };
+                // Scores are kept per client, so only single addresses can be scored
+                let mut loaded = 0;
+                for network in networks.iter().filter(|network| network.prefix_len() == network.max_prefix_len()) {
+                    reputation.cap(&network.addr().to_string(), feed.reputation_score).await?;
+                    loaded += 1;
+                }
+                loaded
+            }
+        };
+
+        metrics::gauge!("threat_feed_entries", loaded as f64, "feed" => feed.name.clone());
+        metrics::gauge!(
+            "threat_feed_last_success_timestamp",
+            Utc::now().timestamp() as f64,
+            "feed" => feed.name.clone()
+        );
+        Ok(loaded)
+    }
+
+    /// Refresh one feed on its interval
+    async fn run_feed(&self, feed: &ThreatFeed) {
+        let interval = Duration::from_secs(feed.refresh_interval_seconds.max(60));
+        loop {
+            match self.refresh_feed(feed).await {
+                Ok(loaded) => log::info!("Loaded {} entries from threat intel feed {}", loaded, feed.name),
+                Err(e) => {
+                    metrics::increment_counter!("threat_feed_failures_total", "feed" => feed.name.clone());
+                    log::error!("Failed to refresh threat intel feed {}: {}", feed.name, e);
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Refresh every configured feed until the task is cancelled
+    pub async fn start(&self) {
+        if !self.config.enabled {
+            return;
+        }
+        join_all(self.config.feeds.iter().map(|feed| self.run_feed(feed))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_feed() {
+        let contents = "\
+; Spamhaus DROP List
+1.10.16.0/20 ; SBL256894
+# FireHOL
+2.56.192.0/22
+192.168.0.0/16
+0.0.0.0/8
+203.0.113.7 reported 12 times
+2001:db8:1::/48
+not an address
+";
+        let networks: Vec<String> = parse_feed(contents).iter().map(IpNet::to_string).collect();
+        assert_eq!(
+            networks,
+            vec!["1.10.16.0/20", "2.56.192.0/22", "203.0.113.7/32", "2001:db8:1::/48"]
+        );
+    }
+}
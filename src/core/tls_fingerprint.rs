@@ -0,0 +1,376 @@
+//! TLS fingerprinting for the DDoS protection service.
+//!
+//! This module computes JA3 and JA4 fingerprints from the TLS ClientHello
+//! that the proxy forwards with each request. Botnets usually run one TLS
+//! stack, so thousands of addresses share a fingerprint that rules can
+//! match and detectors can count. Requests per fingerprint are counted in
+//! shared storage so that every instance contributes to the same view.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::core::ddos_detector::TlsFingerprintConfig;
+use crate::storage::{Storage, StorageError};
+use crate::utils::{hex, md5, sha256};
+
+/// ClientHello handshake message type
+const CLIENT_HELLO: u8 = 1;
+
+/// TLS handshake record content type
+const HANDSHAKE_RECORD: u8 = 22;
+
+/// Extensions read for fingerprinting
+const SERVER_NAME: u16 = 0x0000;
+const SUPPORTED_GROUPS: u16 = 0x000a;
+const EC_POINT_FORMATS: u16 = 0x000b;
+const SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const ALPN: u16 = 0x0010;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// Whether a value is a GREASE value (RFC 8701), which clients add at
+/// random and fingerprints ignore
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Reads big-endian, length-prefixed TLS structures
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.data.len() < length {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3).map(|bytes| (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+    }
+
+    /// A structure prefixed with a one-byte length
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let length = self.u8()? as usize;
+        self.bytes(length).map(|data| Reader { data })
+    }
+
+    /// A structure prefixed with a two-byte length
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let length = self.u16()? as usize;
+        self.bytes(length).map(|data| Reader { data })
+    }
+
+    fn u16s(mut self) -> Vec<u16> {
+        let mut values = Vec::new();
+        while let Some(value) = self.u16() {
+            values.push(value);
+        }
+        values
+    }
+}
+
+/// Fields of a ClientHello that fingerprints are computed from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientHello {
+    /// Legacy protocol version (`0x0303` for TLS 1.2 and 1.3)
+    pub version: u16,
+    /// Offered cipher suites, in order
+    pub ciphers: Vec<u16>,
+    /// Extension types, in order
+    pub extensions: Vec<u16>,
+    /// Supported groups (elliptic curves)
+    pub groups: Vec<u16>,
+    /// Elliptic curve point formats
+    pub point_formats: Vec<u8>,
+    /// Supported protocol versions, for TLS 1.3 clients
+    pub supported_versions: Vec<u16>,
+    /// Signature algorithms, in order
+    pub signature_algorithms: Vec<u16>,
+    /// First ALPN protocol offered (e.g. `h2`)
+    pub alpn: Option<Vec<u8>>,
+}
+
+impl ClientHello {
+    /// Parse a ClientHello, with or without its TLS record header
+    ///
+    /// Returns `None` for truncated or malformed messages.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader { data };
+        if data.first() == Some(&HANDSHAKE_RECORD) {
+            reader.bytes(5)?;
+        }
+        if reader.u8()? != CLIENT_HELLO {
+            return None;
+        }
+        let length = reader.u24()?;
+        let mut hello = Reader { data: reader.bytes(length)? };
+
+        let mut client_hello = ClientHello {
+            version: hello.u16()?,
+            ..ClientHello::default()
+        };
+        hello.bytes(32)?; // random
+        hello.vec8()?; // session ID
+        client_hello.ciphers = hello.vec16()?.u16s();
+        hello.vec8()?; // compression methods
+
+        // Extensions are optional in TLS 1.2 and earlier
+        let mut extensions = match hello.vec16() {
+            Some(extensions) => extensions,
+            None => return Some(client_hello),
+        };
+        while let Some(extension_type) = extensions.u16() {
+            let mut data = extensions.vec16()?;
+            client_hello.extensions.push(extension_type);
+            match extension_type {
+                SUPPORTED_GROUPS => client_hello.groups = data.vec16()?.u16s(),
+                EC_POINT_FORMATS => client_hello.point_formats = data.vec8()?.data.to_vec(),
+                SIGNATURE_ALGORITHMS => client_hello.signature_algorithms = data.vec16()?.u16s(),
+                SUPPORTED_VERSIONS => client_hello.supported_versions = data.vec8()?.u16s(),
+                ALPN => client_hello.alpn = data.vec16()?.vec8().map(|protocol| protocol.data.to_vec()),
+                _ => (),
+            }
+        }
+        Some(client_hello)
+    }
+
+    /// The JA3 string: version, ciphers, extensions, groups and point
+    /// formats in decimal, GREASE values removed
+    pub fn ja3_string(&self) -> String {
+        let join = |values: &mut dyn Iterator<Item = u16>| {
+            values.filter(|value| !is_grease(*value)).map(|value| value.to_string()).collect::<Vec<_>>().join("-")
+        };
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(&mut self.ciphers.iter().copied()),
+            join(&mut self.extensions.iter().copied()),
+            join(&mut self.groups.iter().copied()),
+            join(&mut self.point_formats.iter().map(|format| *format as u16)),
+        )
+    }
+
+    /// The JA3 fingerprint: MD5 of the JA3 string
+    pub fn ja3(&self) -> String {
+        hex(&md5(self.ja3_string().as_bytes()))
+    }
+
+    /// The JA4 fingerprint (TCP), e.g. `t13d1516h2_8daaf6152771_b186095e22b6`
+    ///
+    /// Unlike JA3, ciphers and extensions are sorted, so clients that
+    /// randomize extension order keep one fingerprint.
+    pub fn ja4(&self) -> String {
+        let version = self
+            .supported_versions
+            .iter()
+            .copied()
+            .filter(|version| !is_grease(*version))
+            .max()
+            .unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+        let sni = if self.extensions.contains(&SERVER_NAME) { 'd' } else { 'i' };
+        let ciphers: Vec<u16> = self.ciphers.iter().copied().filter(|cipher| !is_grease(*cipher)).collect();
+        let extensions: Vec<u16> = self.extensions.iter().copied().filter(|extension| !is_grease(*extension)).collect();
+        let protocol = self.alpn.as_deref().unwrap_or_default();
+        let alpn = match (protocol.first(), protocol.last()) {
+            (Some(first), Some(last)) => {
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", *first as char, *last as char)
+                } else {
+                    let (first, last) = (format!("{:02x}", first), format!("{:02x}", last));
+                    format!("{}{}", &first[..1], &last[1..])
+                }
+            }
+            _ => "00".to_string(),
+        };
+
+        let mut sorted_ciphers = ciphers.clone();
+        sorted_ciphers.sort_unstable();
+        let mut sorted_extensions: Vec<u16> = extensions
+            .iter()
+            .copied()
+            .filter(|extension| *extension != SERVER_NAME && *extension != ALPN)
+            .collect();
+        sorted_extensions.sort_unstable();
+        let mut extension_input = hex_list(&sorted_extensions);
+        if !self.signature_algorithms.is_empty() {
+            extension_input = format!("{}_{}", extension_input, hex_list(&self.signature_algorithms));
+        }
+
+        format!(
+            "t{}{}{:02}{:02}{}_{}_{}",
+            version,
+            sni,
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn,
+            truncated_hash(&sorted_ciphers, &hex_list(&sorted_ciphers)),
+            truncated_hash(&sorted_extensions, &extension_input),
+        )
+    }
+}
+
+/// Values as comma-separated 4-digit hex, as JA4 hashes them
+fn hex_list(values: &[u16]) -> String {
+    values.iter().map(|value| format!("{:04x}", value)).collect::<Vec<_>>().join(",")
+}
+
+/// First 12 hex digits of the SHA-256 of `input`, or zeros if there are no values
+fn truncated_hash(values: &[u16], input: &str) -> String {
+    if values.is_empty() {
+        return "000000000000".to_string();
+    }
+    hex(&sha256(input.as_bytes()))[..12].to_string()
+}
+
+/// Kind of TLS fingerprint
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FingerprintKind {
+    Ja3,
+    Ja4,
+}
+
+impl FingerprintKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ja3 => "ja3",
+            Self::Ja4 => "ja4",
+        }
+    }
+}
+
+/// Requests made with a fingerprint in the current window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FingerprintCount {
+    /// JA3 or JA4 fingerprint
+    pub fingerprint: String,
+    /// Requests in the window
+    pub requests: u64,
+}
+
+/// Shared counter of requests per TLS fingerprint
+///
+/// Cloning is cheap; all clones use the same storage.
+#[derive(Clone)]
+pub struct FingerprintTracker {
+    /// Where request counts are kept
+    storage: Arc<dyn Storage>,
+    /// TLS fingerprint configuration
+    config: TlsFingerprintConfig,
+}
+
+impl FingerprintTracker {
+    /// Create a new fingerprint tracker
+    pub fn new(storage: Arc<dyn Storage>, config: TlsFingerprintConfig) -> Self {
+        Self { storage, config }
+    }
+
+    fn key(&self, kind: FingerprintKind, bucket: u64) -> String {
+        format!("tls:{}:{}", kind.as_str(), bucket)
+    }
+
+    fn bucket(&self) -> u64 {
+        Utc::now().timestamp() as u64 / self.config.window.max(1) as u64
+    }
+
+    /// Count a request, returning the requests made with its JA4 fingerprint
+    /// in the current window
+    pub async fn record(&self, ja3: Option<&str>, ja4: Option<&str>) -> Result<Option<u64>, StorageError> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+        let bucket = self.bucket();
+        let ttl = Some(Duration::from_secs(2 * self.config.window.max(1) as u64));
+        if let Some(ja3) = ja3 {
+            self.storage.increment_score(&self.key(FingerprintKind::Ja3, bucket), ja3, 1.0, ttl).await?;
+        }
+        match ja4 {
+            Some(ja4) => {
+                let requests = self.storage.increment_score(&self.key(FingerprintKind::Ja4, bucket), ja4, 1.0, ttl).await?;
+                Ok(Some(requests as u64))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Fingerprints with the most requests in the current window, most first
+    pub async fn top(&self, kind: FingerprintKind, limit: usize) -> Result<Vec<FingerprintCount>, StorageError> {
+        let counts = self.storage.top(&self.key(kind, self.bucket()), limit).await?;
+        Ok(counts
+            .into_iter()
+            .map(|(fingerprint, requests)| FingerprintCount {
+                fingerprint,
+                requests: requests as u64,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprints() {
+        // TLS 1.3 ClientHello with GREASE, SNI and ALPN
+        let mut extensions = Vec::new();
+        let mut extension = |extension_type: u16, data: &[u8]| {
+            extensions.extend_from_slice(&extension_type.to_be_bytes());
+            extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(data);
+        };
+        extension(0x1a1a, &[]);
+        extension(SERVER_NAME, &[0, 6, 0, 0, 3, b'a', b'.', b'b']);
+        extension(SUPPORTED_GROUPS, &[0, 6, 0x2a, 0x2a, 0x00, 0x1d, 0x00, 0x17]);
+        extension(EC_POINT_FORMATS, &[1, 0]);
+        extension(SIGNATURE_ALGORITHMS, &[0, 4, 0x04, 0x03, 0x08, 0x04]);
+        extension(ALPN, &[0, 3, 2, b'h', b'2']);
+        extension(SUPPORTED_VERSIONS, &[4, 0x03, 0x04, 0x03, 0x03]);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0, 6, 0x0a, 0x0a, 0x13, 0x01, 0xc0, 0x2f]);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut message = vec![CLIENT_HELLO, 0, (body.len() >> 8) as u8, body.len() as u8];
+        message.extend_from_slice(&body);
+
+        let hello = ClientHello::parse(&message).unwrap();
+        assert_eq!(hello.ja3_string(), "771,4865-49199,0-10-11-13-16-43,29-23,0");
+        assert_eq!(hello.ja3(), hex(&md5(b"771,4865-49199,0-10-11-13-16-43,29-23,0")));
+
+        let ja4 = hello.ja4();
+        assert!(ja4.starts_with("t13d0206h2_"), "{}", ja4);
+        assert_eq!(&ja4[11..23], &hex(&sha256(b"1301,c02f"))[..12]);
+        assert_eq!(&ja4[24..], &hex(&sha256(b"000a,000b,000d,002b_0403,0804"))[..12]);
+
+        // With a record header, and truncated
+        let mut record = vec![HANDSHAKE_RECORD, 0x03, 0x01, 0, message.len() as u8];
+        record.extend_from_slice(&message);
+        assert_eq!(ClientHello::parse(&record), Some(hello));
+        assert_eq!(ClientHello::parse(&message[..40]), None);
+    }
+}
@@ -0,0 +1,418 @@
+//! Traffic aggregates for the DDoS protection service.
+//!
+//! Requests, blocks, rate limits, bytes and unique client IPs are counted in
+//! memory as traffic is checked, and a background task adds them to
+//! per-minute and per-hour buckets in Redis every few seconds, so graphs
+//! read a few buckets instead of scanning events. Unique IPs are counted
+//! with HyperLogLog, which merges across instances and buckets.
+//!
+//! The same buckets hold leaderboards of offending IPs, paths, user agents,
+//! countries and ASNs as sorted sets, each capped to its top members.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Result;
+use log::error;
+use uuid::Uuid;
+use crate::core::geoip::GeoIp;
+use crate::core::redis_pool::RedisPool;
+use crate::core::watchdog::Heartbeat;
+
+/// How often counts are added to the buckets in Redis
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long minute buckets are kept
+const MINUTE_RETENTION: Duration = Duration::from_secs(2 * 24 * 3600);
+
+/// Bucket sizes in seconds, with the label of their keys
+const MINUTE: (i64, &str) = (60, "1m");
+const HOUR: (i64, &str) = (3600, "1h");
+
+/// Most buckets a series may be read from
+pub const MAX_BUCKETS: i64 = 20_000;
+
+/// Members kept in each leaderboard bucket
+const MAX_TOP_MEMBERS: isize = 1000;
+
+/// Most members of each leaderboard counted in memory between flushes;
+/// requests from further members only count towards the totals
+const MAX_PENDING_MEMBERS: usize = 10_000;
+
+/// Longest range a leaderboard is read from minute buckets for
+const MAX_TOP_MINUTES_RANGE: i64 = 6 * 3600;
+
+/// A traffic metric that can be graphed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficMetric {
+    Requests,
+    /// Requests answered with a block
+    Blocks,
+    /// Requests rejected by rate limiting
+    RateLimits,
+    /// Request bytes
+    Bytes,
+    /// Distinct client IPs (estimated)
+    UniqueIps,
+}
+
+impl TrafficMetric {
+    /// Field of the bucket hash holding the metric; unique IPs are kept apart
+    fn field(self) -> Option<&'static str> {
+        match self {
+            TrafficMetric::Requests => Some("requests"),
+            TrafficMetric::Blocks => Some("blocks"),
+            TrafficMetric::RateLimits => Some("rate_limits"),
+            TrafficMetric::Bytes => Some("bytes"),
+            TrafficMetric::UniqueIps => None,
+        }
+    }
+}
+
+/// What a leaderboard ranks
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TopDimension {
+    /// Client IPs by blocked and rate-limited requests
+    Ips,
+    Paths,
+    UserAgents,
+    Countries,
+    Asns,
+}
+
+impl TopDimension {
+    fn as_str(self) -> &'static str {
+        match self {
+            TopDimension::Ips => "ips",
+            TopDimension::Paths => "paths",
+            TopDimension::UserAgents => "user_agents",
+            TopDimension::Countries => "countries",
+            TopDimension::Asns => "asns",
+        }
+    }
+}
+
+/// A leaderboard member and its requests
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopEntry {
+    pub value: String,
+    pub requests: u64,
+}
+
+/// A point of a series
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Point {
+    /// Start of the point's interval, as a Unix timestamp
+    pub timestamp: i64,
+    pub value: u64,
+}
+
+/// Counts of a minute not yet added to Redis
+#[derive(Debug, Default)]
+struct Counts {
+    requests: u64,
+    blocks: u64,
+    rate_limits: u64,
+    bytes: u64,
+    /// Requests by client IP
+    ips: HashMap<String, u64>,
+    /// Blocked and rate-limited requests by client IP
+    offenders: HashMap<String, u64>,
+    paths: HashMap<String, u64>,
+    user_agents: HashMap<String, u64>,
+}
+
+/// Count a request from a member, unless too many members are counted already
+fn count(members: &mut HashMap<String, u64>, member: &str) {
+    if let Some(requests) = members.get_mut(member) {
+        *requests += 1;
+    } else if members.len() < MAX_PENDING_MEMBERS {
+        members.insert(member.to_string(), 1);
+    }
+}
+
+fn bucket_key(label: &str, start: i64) -> String {
+    format!("analytics:traffic:{}:{}", label, start)
+}
+
+fn ips_key(label: &str, start: i64) -> String {
+    format!("analytics:traffic:{}:{}:ips", label, start)
+}
+
+fn top_key(dimension: TopDimension, label: &str, start: i64) -> String {
+    format!("analytics:top:{}:{}:{}", dimension.as_str(), label, start)
+}
+
+/// Size of the buckets a series with the given step is read from
+pub fn resolution(step: i64) -> i64 {
+    if step % HOUR.0 == 0 {
+        HOUR.0
+    } else {
+        MINUTE.0
+    }
+}
+
+/// Start of each point from `from` to `to`, with the buckets it covers
+fn points(from: i64, to: i64, step: i64) -> Vec<(i64, Vec<i64>)> {
+    let resolution = resolution(step);
+    let first = from - from.rem_euclid(step);
+    (first..=to)
+        .step_by(step as usize)
+        .map(|start| (start, (start..start + step).step_by(resolution as usize).collect()))
+        .collect()
+}
+
+/// Traffic counts, kept in memory until the next flush
+///
+/// Cheap to clone; all clones share the same counts.
+#[derive(Clone)]
+pub struct TrafficStats {
+    redis: RedisPool,
+    /// Resolves the countries and ASNs of client IPs as counts are flushed
+    geoip: GeoIp,
+    /// How long hour buckets are kept
+    retention_period: Duration,
+    /// Counts by minute
+    pending: Arc<Mutex<HashMap<i64, Counts>>>,
+}
+
+impl TrafficStats {
+    pub fn new(redis: RedisPool, geoip: GeoIp, retention_period: Duration) -> Self {
+        Self {
+            redis,
+            geoip,
+            retention_period,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn record(&self, update: impl FnOnce(&mut Counts)) {
+        let now = Utc::now().timestamp();
+        let minute = now - now.rem_euclid(MINUTE.0);
+        update(self.pending.lock().unwrap().entry(minute).or_default());
+    }
+
+    /// Count a request from a client
+    pub fn record_request(&self, ip: &str, path: &str, user_agent: &str, bytes: u64) {
+        self.record(|counts| {
+            counts.requests += 1;
+            counts.bytes += bytes;
+            count(&mut counts.ips, ip);
+            if !path.is_empty() {
+                count(&mut counts.paths, path);
+            }
+            if !user_agent.is_empty() {
+                count(&mut counts.user_agents, user_agent);
+            }
+        });
+    }
+
+    /// Count a request from a client answered with a block
+    pub fn record_block(&self, ip: &str) {
+        self.record(|counts| {
+            counts.blocks += 1;
+            count(&mut counts.offenders, ip);
+        });
+    }
+
+    /// Count a request from a client rejected by rate limiting
+    pub fn record_rate_limit(&self, ip: &str) {
+        self.record(|counts| {
+            counts.rate_limits += 1;
+            count(&mut counts.offenders, ip);
+        });
+    }
+
+    /// Requests by country and by ASN of the clients counted
+    async fn locate(&self, ips: &HashMap<String, u64>) -> (HashMap<String, u64>, HashMap<String, u64>) {
+        let mut countries = HashMap::new();
+        let mut asns = HashMap::new();
+        for (ip, requests) in ips {
+            let info = self.geoip.lookup(ip).await;
+            if let Some(country) = info.country {
+                *countries.entry(country).or_default() += requests;
+            }
+            if let Some(asn) = info.asn {
+                *asns.entry(asn.to_string()).or_default() += requests;
+            }
+        }
+        (countries, asns)
+    }
+
+    /// Add the counts so far to the buckets in Redis
+    ///
+    /// Counts that fail to be added are dropped.
+    pub async fn flush(&self) -> redis::RedisResult<()> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for (minute, counts) in &pending {
+            let (countries, asns) = self.locate(&counts.ips).await;
+            let leaderboards = [
+                (TopDimension::Ips, &counts.offenders),
+                (TopDimension::Paths, &counts.paths),
+                (TopDimension::UserAgents, &counts.user_agents),
+                (TopDimension::Countries, &countries),
+                (TopDimension::Asns, &asns),
+            ];
+            for ((size, label), retention) in [(MINUTE, MINUTE_RETENTION), (HOUR, self.retention_period)] {
+                let start = minute - minute.rem_euclid(size);
+                let key = bucket_key(label, start);
+                for (field, value) in [
+                    ("requests", counts.requests),
+                    ("blocks", counts.blocks),
+                    ("rate_limits", counts.rate_limits),
+                    ("bytes", counts.bytes),
+                ] {
+                    if value > 0 {
+                        pipe.cmd("HINCRBY").arg(&key).arg(field).arg(value).ignore();
+                    }
+                }
+                let expiry = retention.as_secs().max(size as u64) as i64 + size;
+                pipe.cmd("EXPIRE").arg(&key).arg(expiry).ignore();
+                if !counts.ips.is_empty() {
+                    let ips = ips_key(label, start);
+                    pipe.cmd("PFADD").arg(&ips).arg(counts.ips.keys().collect::<Vec<_>>()).ignore();
+                    pipe.cmd("EXPIRE").arg(&ips).arg(expiry).ignore();
+                }
+                for (dimension, members) in leaderboards {
+                    if members.is_empty() {
+                        continue;
+                    }
+                    let key = top_key(dimension, label, start);
+                    for (member, requests) in members {
+                        pipe.cmd("ZINCRBY").arg(&key).arg(*requests).arg(member).ignore();
+                    }
+                    pipe.cmd("ZREMRANGEBYRANK").arg(&key).arg(0).arg(-MAX_TOP_MEMBERS - 1).ignore();
+                    pipe.cmd("EXPIRE").arg(&key).arg(expiry).ignore();
+                }
+            }
+        }
+        pipe.query_async(&mut self.redis.get()).await
+    }
+
+    /// Add counts to the buckets in Redis every few seconds
+    pub async fn start(&self, heartbeat: Heartbeat) -> Result<()> {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            heartbeat.beat().await;
+            if let Err(e) = self.flush().await {
+                error!("Failed to add traffic counts: {}", e);
+            }
+        }
+    }
+
+    /// A metric between two timestamps, summed over `step` seconds
+    ///
+    /// The step must be a whole number of minutes; hour buckets are read
+    /// when it is a whole number of hours. Counts not flushed yet aren't
+    /// included.
+    pub async fn series(&self, metric: TrafficMetric, from: i64, to: i64, step: i64) -> redis::RedisResult<Vec<Point>> {
+        let label = if resolution(step) == HOUR.0 { HOUR.1 } else { MINUTE.1 };
+        let points = points(from, to, step);
+        if points.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for (_, buckets) in &points {
+            match metric.field() {
+                Some(field) => {
+                    for start in buckets {
+                        pipe.cmd("HGET").arg(bucket_key(label, *start)).arg(field);
+                    }
+                }
+                None => {
+                    let keys: Vec<String> = buckets.iter().map(|start| ips_key(label, *start)).collect();
+                    pipe.cmd("PFCOUNT").arg(keys);
+                }
+            }
+        }
+        let values: Vec<Option<u64>> = pipe.query_async(&mut self.redis.get()).await?;
+
+        let mut values = values.into_iter();
+        Ok(points
+            .into_iter()
+            .map(|(timestamp, buckets)| {
+                let replies = if metric.field().is_some() { buckets.len() } else { 1 };
+                let value = values.by_ref().take(replies).flatten().sum();
+                Point { timestamp, value }
+            })
+            .collect())
+    }
+
+    /// Members of a leaderboard with the most requests between two timestamps, most first
+    ///
+    /// Ranges up to six hours are read from minute buckets and longer ones
+    /// from hour buckets, which start on the hour. Members are only kept
+    /// while they are among the top of a bucket, so counts of members that
+    /// rarely are may be low.
+    pub async fn top(&self, dimension: TopDimension, from: i64, to: i64, limit: usize) -> redis::RedisResult<Vec<TopEntry>> {
+        if limit == 0 || from > to {
+            return Ok(Vec::new());
+        }
+        let (size, label) = top_resolution(from, to);
+        let keys: Vec<String> = (from - from.rem_euclid(size)..=to)
+            .step_by(size as usize)
+            .map(|start| top_key(dimension, label, start))
+            .collect();
+
+        // Buckets are merged into a temporary key, which is dropped with the reply
+        let union = format!("analytics:top:union:{}", Uuid::new_v4());
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("ZUNIONSTORE").arg(&union).arg(keys.len()).arg(&keys).ignore()
+            .cmd("ZREVRANGE").arg(&union).arg(0).arg(limit - 1).arg("WITHSCORES")
+            .cmd("DEL").arg(&union).ignore();
+        let (members,): (Vec<(String, u64)>,) = pipe.query_async(&mut self.redis.get()).await?;
+        Ok(members
+            .into_iter()
+            .map(|(value, requests)| TopEntry { value, requests })
+            .collect())
+    }
+}
+
+/// Size and label of the buckets a leaderboard is read from
+fn top_resolution(from: i64, to: i64) -> (i64, &'static str) {
+    if to - from <= MAX_TOP_MINUTES_RANGE {
+        MINUTE
+    } else {
+        HOUR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_points() {
+        assert_eq!(resolution(300), 60);
+        assert_eq!(resolution(7200), 3600);
+
+        let minutes = points(130, 700, 300);
+        assert_eq!(minutes.iter().map(|(start, _)| *start).collect::<Vec<_>>(), vec![0, 300, 600]);
+        assert_eq!(minutes[1].1, vec![300, 360, 420, 480, 540]);
+
+        let hours = points(3600, 10_799, 7200);
+        assert_eq!(hours, vec![(0, vec![0, 3600]), (7200, vec![7200, 10_800])]);
+
+        assert_eq!(top_resolution(0, 6 * 3600), MINUTE);
+        assert_eq!(top_resolution(0, 6 * 3600 + 1), HOUR);
+
+        let mut members = HashMap::new();
+        for ip in ["10.0.0.1", "10.0.0.1", "10.0.0.2"] {
+            count(&mut members, ip);
+        }
+        assert_eq!(members["10.0.0.1"], 2);
+        assert_eq!(members.len(), 2);
+    }
+}
@@ -0,0 +1,168 @@
+//! Client IP resolution behind trusted proxies for the DDoS protection service.
+//!
+//! This module finds the client IP of a request that went through load
+//! balancers or CDNs. Client IP headers are only read when the connecting
+//! address is a trusted proxy, and forwarding chains are walked from the
+//! nearest hop so that entries a client prepends itself are ignored.
+
+use std::net::{IpAddr, SocketAddr};
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use crate::models::TrustedProxyConfig;
+use crate::utils::{normalize_ip, parse_network};
+
+/// Resolves client IPs from the connecting address and forwarding headers
+#[derive(Debug, Clone)]
+pub struct TrustedProxies {
+    /// Networks of trusted proxies
+    networks: Vec<IpNet>,
+    /// Client IP headers, lowercased, in order of preference
+    headers: Vec<String>,
+}
+
+impl TrustedProxies {
+    /// Create a resolver; invalid networks are logged and skipped
+    pub fn new(config: &TrustedProxyConfig) -> Self {
+        let networks = config
+            .networks
+            .iter()
+            .filter_map(|network| {
+                let parsed = parse_network(network);
+                if parsed.is_none() {
+                    log::warn!("Ignoring invalid trusted proxy network: {}", network);
+                }
+                parsed
+            })
+            .collect();
+        Self {
+            networks,
+            headers: config.headers.iter().map(|header| header.trim().to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// Whether an address belongs to a trusted proxy
+    pub fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&addr))
+    }
+
+    /// Client IP of a request made to the API
+    pub fn client_ip(&self, req: &HttpRequest) -> String {
+        let peer = req.peer_addr().map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string());
+        self.resolve(&peer, |name| {
+            let values: Vec<&str> = req.headers().get_all(name).filter_map(|value| value.to_str().ok()).collect();
+            (!values.is_empty()).then(|| values.join(","))
+        })
+    }
+
+    /// Client IP of a request the proxy reported, given the address it came from and its headers
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - Address the request came from
+    /// * `header` - Looks up a header by lowercase name, with repeated headers joined by commas
+    pub fn resolve<F>(&self, peer: &str, header: F) -> String
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let peer_addr = match normalize_ip(peer) {
+            Some(addr) if self.is_trusted(addr) => addr,
+            _ => return peer.to_string(),
+        };
+
+        for name in &self.headers {
+            let Some(value) = header(name) else {
+                continue;
+            };
+            let hops: Vec<Option<IpAddr>> = match name.as_str() {
+                "forwarded" => forwarded_for(&value).map(|hop| parse_hop(&hop)).collect(),
+                "x-forwarded-for" => value.split(',').map(parse_hop).collect(),
+                // Single-address headers (CF-Connecting-IP, X-Real-IP, True-Client-IP) are set by the proxy itself
+                _ => vec![parse_hop(&value)],
+            };
+            if let Some(client) = self.client_from_hops(&hops) {
+                return client.to_string();
+            }
+        }
+        peer_addr.to_string()
+    }
+
+    /// Walk hops from the nearest one, returning the first address that isn't a trusted proxy
+    ///
+    /// Anything further away than that address was written by the client,
+    /// so it can't be trusted. An unparseable hop ends the walk at the last
+    /// trusted hop before it.
+    fn client_from_hops(&self, hops: &[Option<IpAddr>]) -> Option<IpAddr> {
+        let mut nearest = None;
+        for hop in hops.iter().rev() {
+            match hop {
+                Some(addr) if self.is_trusted(*addr) => nearest = Some(*addr),
+                Some(addr) => return Some(*addr),
+                None => return nearest,
+            }
+        }
+        nearest
+    }
+}
+
+/// `for` parameters of a `Forwarded` header, in order
+fn forwarded_for(value: &str) -> impl Iterator<Item = String> + '_ {
+    value.split(',').filter_map(|element| {
+        element.split(';').find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("for").then(|| value.trim().trim_matches('"').to_string())
+        })
+    })
+}
+
+/// Parse a forwarding hop, which may carry a port or IPv6 brackets
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    normalize_ip(hop)
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| normalize_ip(hop.trim_start_matches('[').trim_end_matches(']')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn trusted(headers: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(&TrustedProxyConfig {
+            networks: vec!["10.0.0.0/8".to_string(), "2001:db8:ffff::/48".to_string()],
+            headers: headers.iter().map(|header| header.to_string()).collect(),
+        })
+    }
+
+    fn resolve(proxies: &TrustedProxies, peer: &str, headers: &[(&str, &str)]) -> String {
+        let headers: HashMap<String, String> =
+            headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        proxies.resolve(peer, |name| headers.get(name).cloned())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let proxies = trusted(&["x-forwarded-for", "forwarded"]);
+
+        // Headers from untrusted peers are ignored
+        assert_eq!(resolve(&proxies, "198.51.100.7", &[("x-forwarded-for", "192.0.2.1")]), "198.51.100.7");
+        // The nearest untrusted hop is the client, not one the client prepended
+        assert_eq!(
+            resolve(&proxies, "10.0.0.2", &[("x-forwarded-for", "6.6.6.6, 192.0.2.1, 10.0.0.1")]),
+            "192.0.2.1"
+        );
+        assert_eq!(resolve(&proxies, "10.0.0.2", &[("x-forwarded-for", "192.0.2.1:4711")]), "192.0.2.1");
+        assert_eq!(
+            resolve(&proxies, "10.0.0.2", &[("forwarded", r#"for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711""#)]),
+            "2001:db8:cafe::17"
+        );
+        // Garbage ends the walk at the last trusted hop
+        assert_eq!(resolve(&proxies, "10.0.0.2", &[("x-forwarded-for", "unknown, 10.0.0.1")]), "10.0.0.1");
+        assert_eq!(resolve(&proxies, "10.0.0.2", &[("x-forwarded-for", "unknown")]), "10.0.0.2");
+        assert_eq!(resolve(&proxies, "10.0.0.2", &[]), "10.0.0.2");
+
+        let cloudflare = trusted(&["cf-connecting-ip"]);
+        assert_eq!(resolve(&cloudflare, "10.0.0.2", &[("cf-connecting-ip", "192.0.2.1")]), "192.0.2.1");
+        assert_eq!(resolve(&cloudflare, "10.0.0.2", &[("x-forwarded-for", "192.0.2.1")]), "10.0.0.2");
+    }
+}
@@ -0,0 +1,293 @@
+//! Background task watchdog for the DDoS protection service.
+//!
+//! Long-running loops such as monitoring and the rule engine run under a
+//! supervisor. Each run gets a [`Heartbeat`] to beat as it makes progress;
+//! beats are recorded in Redis, per instance, so operators can see which
+//! tasks are alive. A task that returns an error, panics, or stops beating
+//! is restarted after a backoff, and a Critical alert is raised for it until
+//! it is beating again. A task that returns successfully is done and isn't
+//! restarted. The last beat of each running task is also kept in
+//! [`Heartbeats`], for readiness checks.
+
+use anyhow::Result;
+use chrono::Utc;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::{JoinError, JoinHandle};
+use uuid::Uuid;
+use crate::core::monitoring::{Alert, AlertLevel, Monitoring};
+use crate::core::redis_pool::RedisPool;
+use crate::models::WatchdogConfig;
+
+/// Redis hash holding the last heartbeat of each task, as `task@instance`
+const HEARTBEATS_KEY: &str = "watchdog:heartbeats";
+
+/// Progress marker handed to a supervised task
+#[derive(Clone)]
+pub struct Heartbeat {
+    redis: RedisPool,
+    field: String,
+    /// Unix timestamp of the last beat
+    last: Arc<AtomicI64>,
+}
+
+impl Heartbeat {
+    fn new(redis: RedisPool, task: &str, instance: &str) -> Self {
+        Self {
+            redis,
+            field: format!("{}@{}", task, instance),
+            last: Arc::new(AtomicI64::new(Utc::now().timestamp())),
+        }
+    }
+
+    /// Record that the task is making progress
+    pub async fn beat(&self) {
+        let now = Utc::now().timestamp();
+        self.last.store(now, Ordering::Relaxed);
+
+        let mut conn = self.redis.get();
+        let result: Result<(), redis::RedisError> = redis::cmd("HSET")
+            .arg(HEARTBEATS_KEY)
+            .arg(&self.field)
+            .arg(now)
+            .query_async(&mut conn)
+            .await;
+        if let Err(e) = result {
+            warn!("Failed to record heartbeat of {}: {}", self.field, e);
+        }
+    }
+
+    /// Seconds since the last beat
+    fn age(&self) -> i64 {
+        Utc::now().timestamp() - self.last.load(Ordering::Relaxed)
+    }
+}
+
+/// Last beat of each running task of this instance
+///
+/// Cheap to clone; all clones share the tasks. Tasks that finished are
+/// left out.
+#[derive(Clone, Default)]
+pub struct Heartbeats {
+    tasks: Arc<Mutex<BTreeMap<String, Arc<AtomicI64>>>>,
+}
+
+impl Heartbeats {
+    fn track(&self, task: &str, heartbeat: &Heartbeat) {
+        self.tasks.lock().unwrap().insert(task.to_string(), heartbeat.last.clone());
+    }
+
+    fn forget(&self, task: &str) {
+        self.tasks.lock().unwrap().remove(task);
+    }
+
+    /// Seconds since the last beat of each task, by name
+    pub fn ages(&self) -> Vec<(String, i64)> {
+        let now = Utc::now().timestamp();
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(task, last)| (task.clone(), now - last.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Delay before restarting a task after its `failures`th consecutive failure
+pub fn backoff(failures: u32, config: &WatchdogConfig) -> Duration {
+    let factor = 2u64.saturating_pow(failures.saturating_sub(1));
+    Duration::from_secs(config.initial_backoff_seconds.saturating_mul(factor).min(config.max_backoff_seconds))
+}
+
+/// Why a run of a task ended
+enum Exit {
+    Finished,
+    Failed(String),
+    Stalled,
+}
+
+impl Exit {
+    fn from_join(result: Result<Result<()>, JoinError>) -> Self {
+        match result {
+            Ok(Ok(())) => Exit::Finished,
+            Ok(Err(e)) => Exit::Failed(e.to_string()),
+            Err(e) if e.is_panic() => {
+                let panic = e.into_panic();
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".to_string());
+                Exit::Failed(format!("panicked: {}", message))
+            }
+            Err(e) => Exit::Failed(e.to_string()),
+        }
+    }
+}
+
+/// Aborts the task it holds when dropped, so aborting a supervisor stops its task too
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Restarts failed background tasks and raises alerts for them
+#[derive(Clone)]
+pub struct Watchdog {
+    redis: RedisPool,
+    config: WatchdogConfig,
+    monitoring: Arc<Monitoring>,
+    /// Identifies this instance's heartbeats and alerts
+    instance: String,
+    heartbeats: Heartbeats,
+}
+
+impl Watchdog {
+    pub fn new(redis: RedisPool, config: WatchdogConfig, monitoring: Arc<Monitoring>) -> Self {
+        let instance = std::env::var("HOSTNAME")
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        Self {
+            redis,
+            config,
+            monitoring,
+            instance,
+            heartbeats: Heartbeats::default(),
+        }
+    }
+
+    /// Last beats of the tasks running under supervision
+    pub fn heartbeats(&self) -> Heartbeats {
+        self.heartbeats.clone()
+    }
+
+    /// Run a task under supervision, starting it again with `start` whenever it fails
+    pub fn supervise<F, Fut>(&self, name: &str, start: F) -> JoinHandle<()>
+    where
+        F: Fn(Heartbeat) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let watchdog = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            if !watchdog.config.enabled {
+                let heartbeat = Heartbeat::new(watchdog.redis.clone(), &name, &watchdog.instance);
+                watchdog.heartbeats.track(&name, &heartbeat);
+                if let Err(e) = start(heartbeat).await {
+                    error!("{} error: {}", name, e);
+                }
+                watchdog.heartbeats.forget(&name);
+                return;
+            }
+            watchdog.run(&name, start).await;
+        })
+    }
+
+    async fn run<F, Fut>(&self, name: &str, start: F)
+    where
+        F: Fn(Heartbeat) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let check_interval = Duration::from_secs(self.config.check_interval_seconds.max(1));
+        let timeout = self.config.heartbeat_timeout_seconds as i64;
+        let mut failures = 0;
+        let mut down = false;
+
+        loop {
+            let heartbeat = Heartbeat::new(self.redis.clone(), name, &self.instance);
+            self.heartbeats.track(name, &heartbeat);
+            heartbeat.beat().await;
+            let started = Instant::now();
+            let mut task = AbortOnDrop(tokio::spawn(start(heartbeat.clone())));
+
+            let exit = loop {
+                tokio::select! {
+                    result = &mut task.0 => break Exit::from_join(result),
+                    _ = tokio::time::sleep(check_interval) => {
+                        if heartbeat.age() > timeout {
+                            task.0.abort();
+                            break Exit::Stalled;
+                        }
+                        if down {
+                            info!("{} is running again", name);
+                            self.report_up(name).await;
+                            down = false;
+                        }
+                    }
+                }
+            };
+
+            let reason = match exit {
+                Exit::Finished => {
+                    self.heartbeats.forget(name);
+                    if down {
+                        self.report_up(name).await;
+                    }
+                    return;
+                }
+                Exit::Failed(reason) => reason,
+                Exit::Stalled => format!("no heartbeat for {} seconds", heartbeat.age()),
+            };
+
+            // A run that lasted longer than the longest backoff starts the count over
+            if started.elapsed() > Duration::from_secs(self.config.max_backoff_seconds) {
+                failures = 0;
+            }
+            failures += 1;
+            let delay = backoff(failures, &self.config);
+            error!("{} stopped ({}), restarting in {:?}", name, reason, delay);
+            self.report_down(name, &reason, failures).await;
+            down = true;
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn fingerprint(&self, name: &str) -> String {
+        format!("watchdog:{}:{}", self.instance, name)
+    }
+
+    async fn report_down(&self, name: &str, reason: &str, failures: u32) {
+        let mut alert = Alert::new(
+            AlertLevel::Critical,
+            "Subsystem Down",
+            &format!("{} on {} stopped ({}); restart attempt {}", name, self.instance, reason, failures),
+        );
+        alert.fingerprint = Some(self.fingerprint(name));
+        alert.labels.insert("task".to_string(), name.to_string());
+        alert.labels.insert("instance".to_string(), self.instance.clone());
+        if let Err(e) = self.monitoring.raise("alerts", alert).await {
+            error!("Failed to raise alert for {}: {}", name, e);
+        }
+    }
+
+    async fn report_up(&self, name: &str) {
+        if let Err(e) = self.monitoring.resolve_fingerprint(&self.fingerprint(name)).await {
+            error!("Failed to resolve alert for {}: {}", name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let config = WatchdogConfig {
+            initial_backoff_seconds: 2,
+            max_backoff_seconds: 30,
+            ..WatchdogConfig::default()
+        };
+        let delays: Vec<u64> = (1..=6).map(|failures| backoff(failures, &config).as_secs()).collect();
+        assert_eq!(delays, vec![2, 4, 8, 16, 30, 30]);
+        assert_eq!(backoff(100, &config).as_secs(), 30);
+    }
+}
@@ -0,0 +1,361 @@
+//! Webhook notifications for the DDoS protection service.
+//!
+//! Alerts and rule `Notify` actions are POSTed as JSON to the webhook
+//! endpoints subscribed to their channel. Each request is signed with the
+//! endpoint's secret: the `X-Webhook-Signature` header holds
+//! `sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">`, with the timestamp
+//! sent in `X-Webhook-Timestamp`. Failed deliveries are retried with
+//! exponential backoff and recorded as dead letters once the attempts run
+//! out. Endpoints and dead letters live in Redis, shared by all instances.
+
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+use uuid::Uuid;
+use crate::core::monitoring::AlertLevel;
+use crate::core::redis_pool::RedisPool;
+use crate::models::WebhookConfig;
+use crate::utils::{hex, hmac_sha256};
+
+/// Redis hash holding webhook endpoints keyed by ID
+const ENDPOINTS_KEY: &str = "webhooks:endpoints";
+/// Redis list holding notifications that could not be delivered, newest first
+const DEAD_LETTERS_KEY: &str = "webhooks:dead_letters";
+
+/// Errors that can occur during webhook operations
+#[derive(Error, Debug)]
+pub enum WebhookError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+}
+
+/// An endpoint notifications are delivered to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEndpoint {
+    pub id: String,
+    /// URL notifications are POSTed to
+    pub url: String,
+    /// Key payloads are signed with
+    pub secret: String,
+    /// Channels delivered to the endpoint; empty for all
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Lowest level delivered to the endpoint
+    #[serde(default)]
+    pub min_level: Option<AlertLevel>,
+    /// Whether notifications are delivered
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl WebhookEndpoint {
+    /// Create an enabled endpoint, generating a secret if none is given
+    pub fn new(url: &str, secret: Option<String>) -> Result<Self, WebhookError> {
+        validate_url(url)?;
+        let now = Utc::now();
+        Ok(Self {
+            id: Uuid::new_v4().to_string(),
+            url: url.to_string(),
+            secret: secret.unwrap_or_else(|| format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())),
+            channels: Vec::new(),
+            min_level: None,
+            enabled: true,
+            created_at: now,
+            updated_at: now,
+        })
+    }
+
+    /// Whether a notification should be delivered to the endpoint
+    pub fn accepts(&self, notification: &Notification) -> bool {
+        self.enabled
+            && (self.channels.is_empty() || self.channels.contains(&notification.channel))
+            && match &self.min_level {
+                Some(min) => notification.level >= *min,
+                None => true,
+            }
+    }
+
+    /// The endpoint with its secret hidden, for listing
+    pub fn redacted(mut self) -> Self {
+        self.secret = "********".to_string();
+        self
+    }
+}
+
+/// Check that a URL can receive webhooks
+pub fn validate_url(url: &str) -> Result<(), WebhookError> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+        _ => Err(WebhookError::InvalidUrl(url.to_string())),
+    }
+}
+
+/// Payload POSTed to webhook endpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub id: String,
+    /// Channel the notification was sent on (`alerts`, or a rule's channel)
+    pub channel: String,
+    pub level: AlertLevel,
+    /// What raised the notification
+    pub source: String,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Notification {
+    pub fn new(channel: &str, level: AlertLevel, source: &str, message: &str) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            channel: channel.to_string(),
+            level,
+            source: source.to_string(),
+            message: message.to_string(),
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// A notification that could not be delivered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub endpoint_id: String,
+    pub url: String,
+    pub notification: Notification,
+    pub attempts: u32,
+    /// Error of the last attempt
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Signature header value for a payload sent at `timestamp`
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut message = format!("{}.", timestamp).into_bytes();
+    message.extend_from_slice(body);
+    format!("sha256={}", hex(&hmac_sha256(secret.as_bytes(), &message)))
+}
+
+/// Delay before retry number `retry` (starting at 1)
+fn backoff(config: &WebhookConfig, retry: u32) -> Duration {
+    let delay = config
+        .initial_backoff_ms
+        .saturating_mul(1u64 << (retry - 1).min(32))
+        .min(config.max_backoff_ms);
+    Duration::from_millis(delay)
+}
+
+/// Webhook endpoint management and delivery
+#[derive(Clone)]
+pub struct Webhooks {
+    redis: RedisPool,
+    config: WebhookConfig,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(redis: RedisPool, config: WebhookConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .build()
+            .unwrap_or_default();
+        Self { redis, config, client }
+    }
+
+    /// Create or replace an endpoint
+    pub async fn set_endpoint(&self, endpoint: &WebhookEndpoint) -> Result<(), WebhookError> {
+        let mut conn = self.redis.get();
+        let _: () = redis::cmd("HSET")
+            .arg(ENDPOINTS_KEY)
+            .arg(&endpoint.id)
+            .arg(serde_json::to_string(endpoint)?)
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Get an endpoint by ID
+    pub async fn get_endpoint(&self, id: &str) -> Result<Option<WebhookEndpoint>, WebhookError> {
+        let mut conn = self.redis.get();
+        let json: Option<String> = redis::cmd("HGET")
+            .arg(ENDPOINTS_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Get all endpoints
+    pub async fn get_endpoints(&self) -> Result<Vec<WebhookEndpoint>, WebhookError> {
+        let mut conn = self.redis.get();
+        let endpoints_json: Vec<String> = redis::cmd("HVALS")
+            .arg(ENDPOINTS_KEY)
+            .query_async(&mut conn)
+            .await?;
+        Ok(endpoints_json
+            .into_iter()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect())
+    }
+
+    /// Remove an endpoint, returning whether it existed
+    pub async fn remove_endpoint(&self, id: &str) -> Result<bool, WebhookError> {
+        let mut conn = self.redis.get();
+        let removed: u32 = redis::cmd("HDEL")
+            .arg(ENDPOINTS_KEY)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    /// Most recent notifications that could not be delivered
+    pub async fn get_dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, WebhookError> {
+        let mut conn = self.redis.get();
+        let letters_json: Vec<String> = redis::cmd("LRANGE")
+            .arg(DEAD_LETTERS_KEY)
+            .arg(0)
+            .arg(limit.saturating_sub(1))
+            .query_async(&mut conn)
+            .await?;
+        Ok(letters_json
+            .into_iter()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect())
+    }
+
+    /// Deliver a notification to every endpoint that accepts it, in the background
+    pub async fn notify(&self, notification: Notification) {
+        let endpoints = match self.get_endpoints().await {
+            Ok(endpoints) => endpoints,
+            Err(e) => {
+                error!("Failed to load webhook endpoints: {}", e);
+                return;
+            }
+        };
+        for endpoint in endpoints.into_iter().filter(|endpoint| endpoint.accepts(&notification)) {
+            let webhooks = self.clone();
+            let notification = notification.clone();
+            tokio::spawn(async move {
+                webhooks.deliver(&endpoint, &notification).await;
+            });
+        }
+    }
+
+    /// POST a notification, retrying until it is accepted or the attempts run out
+    async fn deliver(&self, endpoint: &WebhookEndpoint, notification: &Notification) {
+        let body = serde_json::to_vec(notification).unwrap_or_default();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let error = match self.post(endpoint, &body).await {
+                Ok(()) => {
+                    metrics::increment_counter!("webhook_deliveries_total", "result" => "delivered");
+                    return;
+                }
+                Err(e) => e,
+            };
+            if attempts >= self.config.max_attempts {
+                error!("Giving up on webhook {} after {} attempts: {}", endpoint.url, attempts, error);
+                metrics::increment_counter!("webhook_deliveries_total", "result" => "dead_letter");
+                self.record_dead_letter(endpoint, notification, attempts, error).await;
+                return;
+            }
+            warn!("Webhook {} failed (attempt {}): {}", endpoint.url, attempts, error);
+            tokio::time::sleep(backoff(&self.config, attempts)).await;
+        }
+    }
+
+    async fn post(&self, endpoint: &WebhookEndpoint, body: &[u8]) -> Result<(), String> {
+        let timestamp = Utc::now().timestamp();
+        let response = self
+            .client
+            .post(&endpoint.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Id", &endpoint.id)
+            .header("X-Webhook-Timestamp", timestamp.to_string())
+            .header("X-Webhook-Signature", signature(&endpoint.secret, timestamp, body))
+            .body(body.to_vec())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("HTTP {}", response.status()))
+        }
+    }
+
+    async fn record_dead_letter(&self, endpoint: &WebhookEndpoint, notification: &Notification, attempts: u32, error: String) {
+        let letter = DeadLetter {
+            endpoint_id: endpoint.id.clone(),
+            url: endpoint.url.clone(),
+            notification: notification.clone(),
+            attempts,
+            error,
+            failed_at: Utc::now(),
+        };
+        let Ok(json) = serde_json::to_string(&letter) else {
+            return;
+        };
+        let mut conn = self.redis.get();
+        let result: redis::RedisResult<()> = redis::pipe()
+            .cmd("LPUSH").arg(DEAD_LETTERS_KEY).arg(json).ignore()
+            .cmd("LTRIM").arg(DEAD_LETTERS_KEY).arg(0).arg(self.config.max_dead_letters.saturating_sub(1)).ignore()
+            .query_async(&mut conn)
+            .await;
+        if let Err(e) = result {
+            error!("Failed to record webhook dead letter: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_and_backoff() {
+        let body = br#"{"message":"test"}"#;
+        let expected = format!("sha256={}", hex(&hmac_sha256(b"secret", b"1700000000.{\"message\":\"test\"}")));
+        assert_eq!(signature("secret", 1_700_000_000, body), expected);
+        assert_ne!(signature("other", 1_700_000_000, body), expected);
+
+        let config = WebhookConfig {
+            initial_backoff_ms: 500,
+            max_backoff_ms: 3000,
+            ..WebhookConfig::default()
+        };
+        assert_eq!(backoff(&config, 1), Duration::from_millis(500));
+        assert_eq!(backoff(&config, 3), Duration::from_millis(2000));
+        assert_eq!(backoff(&config, 10), Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn test_endpoint_accepts() {
+        let mut endpoint = WebhookEndpoint::new("https://hooks.example.com/ddos", None).unwrap();
+        let alert = Notification::new("alerts", AlertLevel::Warning, "monitoring", "High request rate");
+        let rule = Notification::new("security", AlertLevel::Info, "rule_engine", "Rule matched");
+        assert!(endpoint.accepts(&alert) && endpoint.accepts(&rule));
+
+        endpoint.channels = vec!["alerts".to_string()];
+        assert!(endpoint.accepts(&alert) && !endpoint.accepts(&rule));
+
+        endpoint.channels.clear();
+        endpoint.min_level = Some(AlertLevel::Error);
+        assert!(!endpoint.accepts(&alert));
+
+        endpoint.min_level = None;
+        endpoint.enabled = false;
+        assert!(!endpoint.accepts(&alert));
+
+        assert!(WebhookEndpoint::new("ftp://example.com", None).is_err());
+        assert!(WebhookEndpoint::new("not a url", None).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! Protocol Buffers wire format for the gRPC API.
+//!
+//! Only the parts of the encoding the messages in
+//! `proto/ddos_protection.proto` need: varints, length-delimited fields and
+//! skipping fixed-width fields of newer clients. Default values are left out
+//! when encoding, as proto3 does.
+
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Wire types of the Protocol Buffers encoding
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LENGTH_DELIMITED: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+/// Errors that can occur while decoding a message
+#[derive(Debug, Error, PartialEq)]
+pub enum DecodeError {
+    #[error("Message truncated")]
+    Truncated,
+    #[error("Varint too long")]
+    VarintOverflow,
+    #[error("Unsupported wire type {0}")]
+    UnsupportedWireType(u8),
+    #[error("Field {0} has the wrong wire type")]
+    WrongWireType(u32),
+    #[error("Field {0} is not valid UTF-8")]
+    InvalidUtf8(u32),
+}
+
+/// Value of a decoded field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Varint(u64),
+    /// 32 or 64-bit value, which no message of the API uses
+    Fixed,
+    LengthDelimited(&'a [u8]),
+}
+
+/// A decoded field of a message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Field<'a> {
+    pub number: u32,
+    pub value: Value<'a>,
+}
+
+impl<'a> Field<'a> {
+    pub fn as_u64(&self) -> Result<u64, DecodeError> {
+        match self.value {
+            Value::Varint(value) => Ok(value),
+            _ => Err(DecodeError::WrongWireType(self.number)),
+        }
+    }
+
+    pub fn as_u32(&self) -> Result<u32, DecodeError> {
+        self.as_u64().map(|value| value as u32)
+    }
+
+    /// Signed values are sign-extended to 64 bits on the wire
+    pub fn as_i64(&self) -> Result<i64, DecodeError> {
+        self.as_u64().map(|value| value as i64)
+    }
+
+    pub fn as_i32(&self) -> Result<i32, DecodeError> {
+        self.as_u64().map(|value| value as i32)
+    }
+
+    pub fn as_bool(&self) -> Result<bool, DecodeError> {
+        self.as_u64().map(|value| value != 0)
+    }
+
+    pub fn as_bytes(&self) -> Result<&'a [u8], DecodeError> {
+        match self.value {
+            Value::LengthDelimited(bytes) => Ok(bytes),
+            _ => Err(DecodeError::WrongWireType(self.number)),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<String, DecodeError> {
+        std::str::from_utf8(self.as_bytes()?)
+            .map(str::to_string)
+            .map_err(|_| DecodeError::InvalidUtf8(self.number))
+    }
+
+    pub fn as_message<M: Message>(&self) -> Result<M, DecodeError> {
+        M::decode(self.as_bytes()?)
+    }
+
+    /// Decode a `map<string, string>` entry
+    pub fn as_map_entry(&self) -> Result<(String, String), DecodeError> {
+        let mut entry = (String::new(), String::new());
+        for field in Fields::new(self.as_bytes()?) {
+            let field = field?;
+            match field.number {
+                1 => entry.0 = field.as_string()?,
+                2 => entry.1 = field.as_string()?,
+                _ => {}
+            }
+        }
+        Ok(entry)
+    }
+}
+
+/// Iterator over the fields of an encoded message
+pub struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.bytes(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::VarintOverflow)
+    }
+
+    fn field(&mut self) -> Result<Field<'a>, DecodeError> {
+        let key = self.varint()?;
+        let number = (key >> 3) as u32;
+        let value = match (key & 0x7) as u8 {
+            WIRE_VARINT => Value::Varint(self.varint()?),
+            WIRE_FIXED64 => {
+                self.bytes(8)?;
+                Value::Fixed
+            }
+            WIRE_LENGTH_DELIMITED => {
+                let len = self.varint()? as usize;
+                Value::LengthDelimited(self.bytes(len)?)
+            }
+            WIRE_FIXED32 => {
+                self.bytes(4)?;
+                Value::Fixed
+            }
+            wire_type => return Err(DecodeError::UnsupportedWireType(wire_type)),
+        };
+        Ok(Field { number, value })
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<Field<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            // Nothing after a malformed field can be trusted
+            self.data = &[];
+        }
+        Some(field)
+    }
+}
+
+/// A message of the gRPC API
+///
+/// Unknown fields are skipped when decoding, so that older servers accept
+/// messages of newer clients.
+pub trait Message: Default {
+    /// Append the encoded message to `buf`
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Set the message field a decoded field belongs to
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError>;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut message = Self::default();
+        for field in Fields::new(data) {
+            message.merge_field(field?)?;
+        }
+        Ok(message)
+    }
+
+    fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, number: u32, wire_type: u8) {
+    put_varint(buf, (u64::from(number) << 3) | u64::from(wire_type));
+}
+
+fn put_length_delimited(buf: &mut Vec<u8>, number: u32, bytes: &[u8]) {
+    put_key(buf, number, WIRE_LENGTH_DELIMITED);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, number: u32, value: u64) {
+    if value != 0 {
+        put_key(buf, number, WIRE_VARINT);
+        put_varint(buf, value);
+    }
+}
+
+/// Encode a proto3 `optional` field, which is sent even when it is zero
+pub fn put_optional_u64(buf: &mut Vec<u8>, number: u32, value: Option<u64>) {
+    if let Some(value) = value {
+        put_key(buf, number, WIRE_VARINT);
+        put_varint(buf, value);
+    }
+}
+
+pub fn put_i64(buf: &mut Vec<u8>, number: u32, value: i64) {
+    put_u64(buf, number, value as u64);
+}
+
+pub fn put_bool(buf: &mut Vec<u8>, number: u32, value: bool) {
+    put_u64(buf, number, u64::from(value));
+}
+
+pub fn put_string(buf: &mut Vec<u8>, number: u32, value: &str) {
+    if !value.is_empty() {
+        put_length_delimited(buf, number, value.as_bytes());
+    }
+}
+
+pub fn put_strings(buf: &mut Vec<u8>, number: u32, values: &[String]) {
+    for value in values {
+        put_length_delimited(buf, number, value.as_bytes());
+    }
+}
+
+pub fn put_message<M: Message>(buf: &mut Vec<u8>, number: u32, message: &M) {
+    put_length_delimited(buf, number, &message.encode_to_vec());
+}
+
+pub fn put_messages<M: Message>(buf: &mut Vec<u8>, number: u32, messages: &[M]) {
+    for message in messages {
+        put_message(buf, number, message);
+    }
+}
+
+/// Encode a `map<string, string>` field, in key order so encoding is stable
+pub fn put_map(buf: &mut Vec<u8>, number: u32, map: &HashMap<String, String>) {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort();
+    for (key, value) in entries {
+        let mut entry = Vec::new();
+        put_string(&mut entry, 1, key);
+        put_string(&mut entry, 2, value);
+        put_length_delimited(buf, number, &entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_and_string_fields() {
+        let mut buf = Vec::new();
+        put_u64(&mut buf, 1, 300);
+        put_string(&mut buf, 2, "testing");
+        put_i64(&mut buf, 3, -1);
+        put_u64(&mut buf, 4, 0);
+        // Reference encodings from the Protocol Buffers documentation
+        assert_eq!(&buf[..3], &[0x08, 0xac, 0x02]);
+        assert_eq!(&buf[3..12], b"\x12\x07testing");
+        assert_eq!(buf.len(), 12 + 11);
+
+        let fields: Vec<Field> = Fields::new(&buf).collect::<Result<_, _>>().unwrap();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0].as_u64(), Ok(300));
+        assert_eq!(fields[1].as_string(), Ok("testing".to_string()));
+        assert_eq!(fields[2].as_i32(), Ok(-1));
+        assert_eq!(fields[1].as_u64(), Err(DecodeError::WrongWireType(2)));
+    }
+
+    #[test]
+    fn test_map_entries_and_truncation() {
+        let map = HashMap::from([("b".to_string(), "2".to_string()), ("a".to_string(), "1".to_string())]);
+        let mut buf = Vec::new();
+        put_map(&mut buf, 6, &map);
+        let entries: Vec<(String, String)> = Fields::new(&buf)
+            .map(|field| field.and_then(|field| field.as_map_entry()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(entries, vec![("a".to_string(), "1".to_string()), ("b".to_string(), "2".to_string())]);
+
+        let mut fields = Fields::new(&buf[..buf.len() - 1]);
+        assert!(fields.next().unwrap().is_ok());
+        assert_eq!(fields.next(), Some(Err(DecodeError::Truncated)));
+        assert_eq!(fields.next(), None);
+    }
+}
@@ -0,0 +1,648 @@
+//! Messages of the gRPC API and their conversions to the service's models.
+//!
+//! Field numbers match `proto/ddos_protection.proto`.
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use crate::api;
+use crate::core::analytics;
+use crate::core::blocklist;
+use crate::core::rule_engine;
+use crate::core::Mitigation;
+use super::codec::{self, DecodeError, Field, Message};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Empty {}
+
+impl Message for Empty {
+    fn encode(&self, _buf: &mut Vec<u8>) {}
+
+    fn merge_field(&mut self, _field: Field<'_>) -> Result<(), DecodeError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitRequest {
+    pub ip: String,
+    pub path: String,
+    /// 0 for the configured cost of the path
+    pub cost: u32,
+    pub api_key: String,
+}
+
+impl Message for RateLimitRequest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.ip);
+        codec::put_string(buf, 2, &self.path);
+        codec::put_u64(buf, 3, self.cost.into());
+        codec::put_string(buf, 4, &self.api_key);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.ip = field.as_string()?,
+            2 => self.path = field.as_string()?,
+            3 => self.cost = field.as_u32()?,
+            4 => self.api_key = field.as_string()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitResponse {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: u64,
+    pub shadowed: bool,
+    pub allowlisted: bool,
+    pub blocked: Option<BlockEntry>,
+}
+
+impl Message for RateLimitResponse {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_bool(buf, 1, self.allowed);
+        codec::put_u64(buf, 2, self.limit.into());
+        codec::put_u64(buf, 3, self.remaining.into());
+        codec::put_u64(buf, 4, self.reset);
+        codec::put_bool(buf, 5, self.shadowed);
+        codec::put_bool(buf, 6, self.allowlisted);
+        if let Some(blocked) = &self.blocked {
+            codec::put_message(buf, 7, blocked);
+        }
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.allowed = field.as_bool()?,
+            2 => self.limit = field.as_u32()?,
+            3 => self.remaining = field.as_u32()?,
+            4 => self.reset = field.as_u64()?,
+            5 => self.shadowed = field.as_bool()?,
+            6 => self.allowlisted = field.as_bool()?,
+            7 => self.blocked = Some(field.as_message()?),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl From<api::RateLimitResponse> for RateLimitResponse {
+    fn from(response: api::RateLimitResponse) -> Self {
+        Self {
+            allowed: response.allowed,
+            limit: response.limit,
+            remaining: response.remaining,
+            reset: response.reset,
+            shadowed: response.shadowed,
+            allowlisted: response.allowlisted,
+            blocked: response.blocked.map(BlockEntry::from),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DdosCheckRequest {
+    pub ip: String,
+    pub request_size: u64,
+    pub user_agent: String,
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub header_order: Vec<String>,
+    pub query: HashMap<String, String>,
+    pub ja3: String,
+    pub ja4: String,
+    pub tls_client_hello: String,
+}
+
+impl Message for DdosCheckRequest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.ip);
+        codec::put_u64(buf, 2, self.request_size);
+        codec::put_string(buf, 3, &self.user_agent);
+        codec::put_string(buf, 4, &self.method);
+        codec::put_string(buf, 5, &self.path);
+        codec::put_map(buf, 6, &self.headers);
+        codec::put_strings(buf, 7, &self.header_order);
+        codec::put_map(buf, 8, &self.query);
+        codec::put_string(buf, 9, &self.ja3);
+        codec::put_string(buf, 10, &self.ja4);
+        codec::put_string(buf, 11, &self.tls_client_hello);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.ip = field.as_string()?,
+            2 => self.request_size = field.as_u64()?,
+            3 => self.user_agent = field.as_string()?,
+            4 => self.method = field.as_string()?,
+            5 => self.path = field.as_string()?,
+            6 => {
+                let (key, value) = field.as_map_entry()?;
+                self.headers.insert(key, value);
+            }
+            7 => self.header_order.push(field.as_string()?),
+            8 => {
+                let (key, value) = field.as_map_entry()?;
+                self.query.insert(key, value);
+            }
+            9 => self.ja3 = field.as_string()?,
+            10 => self.ja4 = field.as_string()?,
+            11 => self.tls_client_hello = field.as_string()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Empty strings are unset fields in proto3
+fn non_empty(value: String) -> Option<String> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+impl From<DdosCheckRequest> for api::DdosCheckRequest {
+    fn from(request: DdosCheckRequest) -> Self {
+        Self {
+            ip: request.ip,
+            request_size: request.request_size,
+            user_agent: request.user_agent,
+            method: request.method,
+            path: request.path,
+            headers: request.headers,
+            header_order: request.header_order,
+            query: request.query,
+            ja3: non_empty(request.ja3),
+            ja4: non_empty(request.ja4),
+            tls_client_hello: non_empty(request.tls_client_hello),
+            tenant: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DdosCheckResponse {
+    pub is_under_attack: bool,
+    pub detection_type: String,
+    pub mitigation: String,
+    pub redirect_url: String,
+    pub redirect_status: u32,
+    pub attack_mode: bool,
+    pub details_json: String,
+}
+
+impl Message for DdosCheckResponse {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_bool(buf, 1, self.is_under_attack);
+        codec::put_string(buf, 2, &self.detection_type);
+        codec::put_string(buf, 3, &self.mitigation);
+        codec::put_string(buf, 4, &self.redirect_url);
+        codec::put_u64(buf, 5, self.redirect_status.into());
+        codec::put_bool(buf, 6, self.attack_mode);
+        codec::put_string(buf, 7, &self.details_json);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.is_under_attack = field.as_bool()?,
+            2 => self.detection_type = field.as_string()?,
+            3 => self.mitigation = field.as_string()?,
+            4 => self.redirect_url = field.as_string()?,
+            5 => self.redirect_status = field.as_u32()?,
+            6 => self.attack_mode = field.as_bool()?,
+            7 => self.details_json = field.as_string()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Name a unit enum variant serializes to (e.g. `rate_limit`)
+fn variant_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+impl From<api::DdosCheckResponse> for DdosCheckResponse {
+    fn from(response: api::DdosCheckResponse) -> Self {
+        let (mitigation, redirect_url, redirect_status) = match &response.mitigation {
+            Some(Mitigation::Block) => ("block", String::new(), 0),
+            Some(Mitigation::Challenge) => ("challenge", String::new(), 0),
+            Some(Mitigation::Redirect { url, status }) => ("redirect", url.clone(), u32::from(*status)),
+            None => ("", String::new(), 0),
+        };
+        Self {
+            is_under_attack: response.is_under_attack,
+            detection_type: response.detection_type.as_ref().map(variant_name).unwrap_or_default(),
+            mitigation: mitigation.to_string(),
+            redirect_url,
+            redirect_status,
+            attack_mode: response.attack_mode,
+            details_json: serde_json::to_string(&response).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rule {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub priority: i32,
+    pub enabled: bool,
+    pub shadow: bool,
+    pub conditions_json: String,
+    pub actions_json: String,
+    pub schedule_json: String,
+    /// Unix timestamp, 0 for never
+    pub expires_at: i64,
+    pub tenant: String,
+}
+
+impl Message for Rule {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.id);
+        codec::put_string(buf, 2, &self.name);
+        codec::put_string(buf, 3, &self.description);
+        codec::put_i64(buf, 4, self.priority.into());
+        codec::put_bool(buf, 5, self.enabled);
+        codec::put_bool(buf, 6, self.shadow);
+        codec::put_string(buf, 7, &self.conditions_json);
+        codec::put_string(buf, 8, &self.actions_json);
+        codec::put_string(buf, 9, &self.schedule_json);
+        codec::put_i64(buf, 10, self.expires_at);
+        codec::put_string(buf, 11, &self.tenant);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.id = field.as_string()?,
+            2 => self.name = field.as_string()?,
+            3 => self.description = field.as_string()?,
+            4 => self.priority = field.as_i32()?,
+            5 => self.enabled = field.as_bool()?,
+            6 => self.shadow = field.as_bool()?,
+            7 => self.conditions_json = field.as_string()?,
+            8 => self.actions_json = field.as_string()?,
+            9 => self.schedule_json = field.as_string()?,
+            10 => self.expires_at = field.as_i64()?,
+            11 => self.tenant = field.as_string()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn timestamp(time: Option<DateTime<Utc>>) -> i64 {
+    time.map(|time| time.timestamp()).unwrap_or(0)
+}
+
+impl From<rule_engine::Rule> for Rule {
+    fn from(rule: rule_engine::Rule) -> Self {
+        Self {
+            id: rule.id,
+            name: rule.name,
+            description: rule.description.unwrap_or_default(),
+            priority: rule.priority,
+            enabled: rule.enabled,
+            shadow: rule.shadow,
+            conditions_json: serde_json::to_string(&rule.conditions).unwrap_or_default(),
+            actions_json: serde_json::to_string(&rule.actions).unwrap_or_default(),
+            schedule_json: rule
+                .schedule
+                .map(|schedule| serde_json::to_string(&schedule).unwrap_or_default())
+                .unwrap_or_default(),
+            expires_at: timestamp(rule.expires_at),
+            tenant: rule.tenant.unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<Rule> for rule_engine::Rule {
+    type Error = String;
+
+    fn try_from(rule: Rule) -> Result<Self, Self::Error> {
+        let schedule = match rule.schedule_json.as_str() {
+            "" => None,
+            json => Some(serde_json::from_str(json).map_err(|e| format!("Invalid schedule: {}", e))?),
+        };
+        let expires_at = match rule.expires_at {
+            0 => None,
+            seconds => Some(
+                Utc.timestamp_opt(seconds, 0)
+                    .single()
+                    .ok_or_else(|| format!("Invalid expiry: {}", seconds))?,
+            ),
+        };
+        Ok(Self {
+            id: rule.id,
+            name: rule.name,
+            description: non_empty(rule.description),
+            conditions: serde_json::from_str(&rule.conditions_json)
+                .map_err(|e| format!("Invalid conditions: {}", e))?,
+            actions: serde_json::from_str(&rule.actions_json)
+                .map_err(|e| format!("Invalid actions: {}", e))?,
+            priority: rule.priority,
+            enabled: rule.enabled,
+            shadow: rule.shadow,
+            schedule,
+            expires_at,
+            tenant: non_empty(rule.tenant),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleId {
+    pub id: String,
+}
+
+impl Message for RuleId {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.id);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        if field.number == 1 {
+            self.id = field.as_string()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleList {
+    pub rules: Vec<Rule>,
+}
+
+impl Message for RuleList {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_messages(buf, 1, &self.rules);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        if field.number == 1 {
+            self.rules.push(field.as_message()?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockEntry {
+    pub target: String,
+    pub reason: String,
+    pub source: String,
+    pub created_at: i64,
+    /// 0 for a permanent block
+    pub expires_at: i64,
+}
+
+impl Message for BlockEntry {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.target);
+        codec::put_string(buf, 2, &self.reason);
+        codec::put_string(buf, 3, &self.source);
+        codec::put_i64(buf, 4, self.created_at);
+        codec::put_i64(buf, 5, self.expires_at);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.target = field.as_string()?,
+            2 => self.reason = field.as_string()?,
+            3 => self.source = field.as_string()?,
+            4 => self.created_at = field.as_i64()?,
+            5 => self.expires_at = field.as_i64()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl From<blocklist::BlockEntry> for BlockEntry {
+    fn from(entry: blocklist::BlockEntry) -> Self {
+        Self {
+            target: entry.target,
+            reason: entry.reason,
+            source: entry.source,
+            created_at: entry.created_at.timestamp(),
+            expires_at: timestamp(entry.expires_at),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockEntryList {
+    pub entries: Vec<BlockEntry>,
+}
+
+impl Message for BlockEntryList {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_messages(buf, 1, &self.entries);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        if field.number == 1 {
+            self.entries.push(field.as_message()?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlocklistRequest {
+    pub target: String,
+    pub reason: String,
+    /// 0 = permanent, `None` = configured default
+    pub duration_seconds: Option<u64>,
+}
+
+impl Message for BlocklistRequest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.target);
+        codec::put_string(buf, 2, &self.reason);
+        codec::put_optional_u64(buf, 3, self.duration_seconds);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.target = field.as_string()?,
+            2 => self.reason = field.as_string()?,
+            3 => self.duration_seconds = Some(field.as_u64()?),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlocklistTarget {
+    pub target: String,
+}
+
+impl Message for BlocklistTarget {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.target);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        if field.number == 1 {
+            self.target = field.as_string()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlocklistCheckResponse {
+    pub ip: String,
+    pub blocked: bool,
+    pub entry: Option<BlockEntry>,
+}
+
+impl Message for BlocklistCheckResponse {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.ip);
+        codec::put_bool(buf, 2, self.blocked);
+        if let Some(entry) = &self.entry {
+            codec::put_message(buf, 3, entry);
+        }
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.ip = field.as_string()?,
+            2 => self.blocked = field.as_bool()?,
+            3 => self.entry = Some(field.as_message()?),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamEventsRequest {
+    /// Empty for all event types
+    pub event_types: Vec<String>,
+}
+
+impl Message for StreamEventsRequest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_strings(buf, 1, &self.event_types);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        if field.number == 1 {
+            self.event_types.push(field.as_string()?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    pub id: String,
+    pub timestamp: String,
+    pub event_type: String,
+    pub source: String,
+    pub data_json: String,
+}
+
+impl Message for Event {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_string(buf, 1, &self.id);
+        codec::put_string(buf, 2, &self.timestamp);
+        codec::put_string(buf, 3, &self.event_type);
+        codec::put_string(buf, 4, &self.source);
+        codec::put_string(buf, 5, &self.data_json);
+    }
+
+    fn merge_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+        match field.number {
+            1 => self.id = field.as_string()?,
+            2 => self.timestamp = field.as_string()?,
+            3 => self.event_type = field.as_string()?,
+            4 => self.source = field.as_string()?,
+            5 => self.data_json = field.as_string()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl From<analytics::Event> for Event {
+    fn from(event: analytics::Event) -> Self {
+        Self {
+            id: event.id,
+            timestamp: event.timestamp.to_rfc3339(),
+            event_type: variant_name(&event.event_type),
+            source: event.source,
+            data_json: serde_json::to_string(&event.data).unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let request = DdosCheckRequest {
+            ip: "203.0.113.7".to_string(),
+            request_size: 512,
+            method: "POST".to_string(),
+            path: "/login".to_string(),
+            headers: HashMap::from([("Host".to_string(), "example.com".to_string())]),
+            header_order: vec!["Host".to_string()],
+            ja3: "771,4865".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(DdosCheckRequest::decode(&request.encode_to_vec()), Ok(request));
+
+        let response = RateLimitResponse {
+            allowed: false,
+            limit: 100,
+            blocked: Some(BlockEntry { target: "203.0.113.0/24".to_string(), expires_at: -1, ..Default::default() }),
+            ..Default::default()
+        };
+        assert_eq!(RateLimitResponse::decode(&response.encode_to_vec()), Ok(response));
+
+        // An explicit zero duration is a permanent block, not the default
+        let block = BlocklistRequest { target: "203.0.113.7".to_string(), duration_seconds: Some(0), ..Default::default() };
+        assert_eq!(BlocklistRequest::decode(&block.encode_to_vec()), Ok(block));
+        assert_eq!(BlocklistRequest::decode(&[]).unwrap().duration_seconds, None);
+    }
+
+    #[test]
+    fn test_rule_conversion() {
+        let rule = Rule {
+            id: "rule_1".to_string(),
+            name: "Block scanners".to_string(),
+            priority: -5,
+            enabled: true,
+            conditions_json: "[]".to_string(),
+            actions_json: "[]".to_string(),
+            expires_at: 1_700_000_000,
+            ..Default::default()
+        };
+        let decoded = Rule::decode(&rule.encode_to_vec()).unwrap();
+        assert_eq!(decoded, rule);
+
+        let converted = rule_engine::Rule::try_from(decoded).unwrap();
+        assert_eq!(converted.priority, -5);
+        assert_eq!(converted.description, None);
+        assert_eq!(converted.expires_at.map(|time| time.timestamp()), Some(1_700_000_000));
+        assert_eq!(Rule::from(converted), rule);
+
+        let invalid = Rule { conditions_json: "{".to_string(), ..rule };
+        assert!(rule_engine::Rule::try_from(invalid).is_err());
+    }
+}
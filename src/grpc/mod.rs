@@ -0,0 +1,383 @@
+//! gRPC API for the DDoS protection service.
+//!
+//! Internal services call the decision and admin operations of the REST API
+//! over gRPC, as defined in `proto/ddos_protection.proto`, and stream
+//! analytics events as they are recorded. The server speaks HTTP/2 with
+//! gRPC's length-prefixed message framing; messages are encoded by `codec`.
+
+mod codec;
+mod messages;
+
+use actix_web::web;
+use bytes::{BufMut, Bytes, BytesMut};
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::{HeaderMap, HeaderValue, Request, Response};
+use log::{error, info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+use crate::api::{self, ApiState};
+use crate::core::blocklist::BlocklistError;
+use crate::core::live_events::LiveEvent;
+use crate::core::schedule::RuleSchedule;
+use crate::models::GrpcConfig;
+use codec::Message;
+
+/// Path prefix of the service's methods
+const SERVICE_PATH: &str = "/ddos_protection.v1.DdosProtection/";
+
+/// Largest request message accepted, gRPC's default
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// gRPC status codes used by the service
+const OK: u32 = 0;
+const INVALID_ARGUMENT: u32 = 3;
+const NOT_FOUND: u32 = 5;
+const RESOURCE_EXHAUSTED: u32 = 8;
+const UNIMPLEMENTED: u32 = 12;
+const INTERNAL: u32 = 13;
+const UNAVAILABLE: u32 = 14;
+
+/// Outcome of a failed call, sent in the `grpc-status` trailer
+#[derive(Debug)]
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    fn internal(context: &str, e: impl std::fmt::Display) -> Self {
+        error!("gRPC {} failed: {}", context, e);
+        Self::new(INTERNAL, "internal error")
+    }
+}
+
+impl From<codec::DecodeError> for Status {
+    fn from(e: codec::DecodeError) -> Self {
+        Self::new(INVALID_ARGUMENT, e.to_string())
+    }
+}
+
+/// What a call is answered with
+enum Reply {
+    Unary(Vec<u8>),
+    /// Stream analytics events of these types (all if empty) until the client goes away
+    Events(Vec<String>),
+}
+
+fn reply<M: Message>(message: M) -> Result<Reply, Status> {
+    Ok(Reply::Unary(message.encode_to_vec()))
+}
+
+/// gRPC server
+pub struct GrpcServer {
+    config: GrpcConfig,
+    state: web::Data<ApiState>,
+}
+
+impl GrpcServer {
+    /// Create a gRPC server calling into the same state as the REST API
+    pub fn new(config: GrpcConfig, state: web::Data<ApiState>) -> Self {
+        Self { config, state }
+    }
+
+    /// Accept connections until the task is aborted
+    pub async fn start(&self) -> std::io::Result<()> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let listener = TcpListener::bind((self.config.host.as_str(), self.config.port)).await?;
+        info!("gRPC server listening on {}:{}", self.config.host, self.config.port);
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let state = self.state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(socket, state).await {
+                    warn!("gRPC connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn serve_connection(socket: TcpStream, state: web::Data<ApiState>) -> Result<(), h2::Error> {
+    let mut connection = h2::server::handshake(socket).await?;
+    // Accepting also drives the connection, so calls are handled on their own tasks
+    while let Some(call) = connection.accept().await {
+        let (request, respond) = call?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_call(request, respond, &state).await {
+                warn!("gRPC call failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_call(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    state: &ApiState,
+) -> Result<(), h2::Error> {
+    let method = request
+        .uri()
+        .path()
+        .strip_prefix(SERVICE_PATH)
+        .unwrap_or_default()
+        .to_string();
+    let result = match read_message(request.into_body()).await {
+        Ok(message) => dispatch(state, &method, &message).await,
+        Err(status) => Err(status),
+    };
+
+    match result {
+        Ok(Reply::Unary(message)) => {
+            let mut stream = respond.send_response(response_headers(), false)?;
+            stream.send_data(frame(&message), false)?;
+            stream.send_trailers(status_trailers(&Status::new(OK, "")))?;
+        }
+        Ok(Reply::Events(event_types)) => {
+            let mut stream = respond.send_response(response_headers(), false)?;
+            if let Some(status) = stream_events(&mut stream, state.live_events.subscribe(), &event_types).await? {
+                stream.send_trailers(status_trailers(&status))?;
+            }
+        }
+        Err(status) => {
+            // Trailers-only response
+            let mut response = response_headers();
+            response.headers_mut().extend(status_trailers(&status));
+            respond.send_response(response, true)?;
+        }
+    }
+    Ok(())
+}
+
+fn response_headers() -> Response<()> {
+    Response::builder()
+        .header("content-type", "application/grpc")
+        .body(())
+        .unwrap()
+}
+
+fn status_trailers(status: &Status) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(status.code));
+    if !status.message.is_empty() {
+        // Messages are percent-encoded, leaving printable ASCII other than `%` as is
+        let mut message = String::new();
+        for byte in status.message.bytes() {
+            if (b' '..=b'~').contains(&byte) && byte != b'%' {
+                message.push(byte as char);
+            } else {
+                message.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&message) {
+            trailers.insert("grpc-message", value);
+        }
+    }
+    trailers
+}
+
+/// Prefix a message with gRPC's uncompressed flag and length
+fn frame(message: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(5 + message.len());
+    buf.put_u8(0);
+    buf.put_u32(message.len() as u32);
+    buf.put_slice(message);
+    buf.freeze()
+}
+
+/// Read the single request message of a call
+async fn read_message(mut body: RecvStream) -> Result<Vec<u8>, Status> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Status::new(UNAVAILABLE, e.to_string()))?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        data.extend_from_slice(&chunk);
+        if data.len() > MAX_MESSAGE_SIZE + 5 {
+            return Err(Status::new(RESOURCE_EXHAUSTED, "message too large"));
+        }
+    }
+    unframe(&data)
+}
+
+fn unframe(data: &[u8]) -> Result<Vec<u8>, Status> {
+    if data.len() < 5 {
+        return Err(Status::new(INVALID_ARGUMENT, "missing request message"));
+    }
+    if data[0] != 0 {
+        return Err(Status::new(UNIMPLEMENTED, "compressed messages are not supported"));
+    }
+    let len = u32::from_be_bytes(data[1..5].try_into().unwrap()) as usize;
+    if data.len() - 5 != len {
+        return Err(Status::new(INVALID_ARGUMENT, "request must be a single message"));
+    }
+    Ok(data[5..].to_vec())
+}
+
+async fn dispatch(state: &ApiState, method: &str, message: &[u8]) -> Result<Reply, Status> {
+    match method {
+        "CheckRateLimit" => {
+            let request = messages::RateLimitRequest::decode(message)?;
+            let cost = match request.cost {
+                0 => state.config.rate_limit.cost_for_path(&request.path),
+                cost => cost,
+            };
+            let api_key = Some(request.api_key.as_str()).filter(|key| !key.is_empty());
+            let response = api::rate_limit_decision(state, &request.ip, None, &request.path, cost, api_key, None).await;
+            reply(messages::RateLimitResponse::from(response))
+        }
+        "CheckDdos" => {
+            let request = messages::DdosCheckRequest::decode(message)?;
+            let response = api::ddos_decision(state, request.into()).await;
+            reply(messages::DdosCheckResponse::from(response))
+        }
+        "ListRules" => {
+            let rules = state.rule_engine.get_rules().await;
+            reply(messages::RuleList { rules: rules.into_iter().map(messages::Rule::from).collect() })
+        }
+        "GetRule" => {
+            let request = messages::RuleId::decode(message)?;
+            match state.rule_engine.get_rule(&request.id).await {
+                Some(rule) => reply(messages::Rule::from(rule)),
+                None => Err(Status::new(NOT_FOUND, "rule not found")),
+            }
+        }
+        "CreateRule" => {
+            let mut rule = rule_from(message)?;
+            rule.id = format!("rule_{}", Uuid::new_v4());
+            if let Err(e) = state.rule_engine.add_rule(rule.clone(), "grpc").await {
+                return Err(Status::internal("add rule", e));
+            }
+            reply(messages::Rule::from(rule))
+        }
+        "UpdateRule" => {
+            let rule = rule_from(message)?;
+            let id = rule.id.clone();
+            match state.rule_engine.update_rule(&id, rule, "grpc").await {
+                Ok(true) => reply(messages::Empty {}),
+                Ok(false) => Err(Status::new(NOT_FOUND, "rule not found")),
+                Err(e) => Err(Status::internal("update rule", e)),
+            }
+        }
+        "DeleteRule" => {
+            let request = messages::RuleId::decode(message)?;
+            match state.rule_engine.remove_rule(&request.id, "grpc").await {
+                Ok(true) => reply(messages::Empty {}),
+                Ok(false) => Err(Status::new(NOT_FOUND, "rule not found")),
+                Err(e) => Err(Status::internal("remove rule", e)),
+            }
+        }
+        "ListBlocklist" => match state.blocklist.get_entries().await {
+            Ok(entries) => reply(messages::BlockEntryList {
+                entries: entries.into_iter().map(messages::BlockEntry::from).collect(),
+            }),
+            Err(e) => Err(Status::internal("get blocklist", e)),
+        },
+        "AddBlocklistEntry" => {
+            let request = messages::BlocklistRequest::decode(message)?;
+            let duration = match request.duration_seconds {
+                Some(0) => None,
+                Some(seconds) => Some(std::time::Duration::from_secs(seconds)),
+                None => state.blocklist.default_duration(),
+            };
+            match state.blocklist.block(&request.target, &request.reason, "grpc", duration).await {
+                Ok(entry) => reply(messages::BlockEntry::from(entry)),
+                Err(e) => Err(blocklist_status("add blocklist entry", e)),
+            }
+        }
+        "RemoveBlocklistEntry" => {
+            let request = messages::BlocklistTarget::decode(message)?;
+            match state.blocklist.unblock(&request.target).await {
+                Ok(true) => reply(messages::Empty {}),
+                Ok(false) => Err(Status::new(NOT_FOUND, "entry not found")),
+                Err(e) => Err(blocklist_status("remove blocklist entry", e)),
+            }
+        }
+        "CheckBlocklist" => {
+            let request = messages::BlocklistTarget::decode(message)?;
+            match state.blocklist.check(&request.target).await {
+                Ok(entry) => reply(messages::BlocklistCheckResponse {
+                    ip: request.target,
+                    blocked: entry.is_some(),
+                    entry: entry.map(messages::BlockEntry::from),
+                }),
+                Err(e) => Err(Status::internal("check blocklist", e)),
+            }
+        }
+        "StreamEvents" => {
+            let request = messages::StreamEventsRequest::decode(message)?;
+            Ok(Reply::Events(request.event_types))
+        }
+        _ => Err(Status::new(UNIMPLEMENTED, format!("unknown method {}", method))),
+    }
+}
+
+/// Decode and validate a rule
+fn rule_from(message: &[u8]) -> Result<crate::core::Rule, Status> {
+    let rule = crate::core::Rule::try_from(messages::Rule::decode(message)?)
+        .map_err(|e| Status::new(INVALID_ARGUMENT, e))?;
+    if let Some(Err(e)) = rule.schedule.as_ref().map(RuleSchedule::validate) {
+        return Err(Status::new(INVALID_ARGUMENT, e.to_string()));
+    }
+    Ok(rule)
+}
+
+fn blocklist_status(context: &str, e: BlocklistError) -> Status {
+    match e {
+        BlocklistError::InvalidTarget(target) => Status::new(INVALID_ARGUMENT, format!("Invalid target: {}", target)),
+        e => Status::internal(context, e),
+    }
+}
+
+/// Forward recorded events to the client until it goes away
+///
+/// Returns the status to end the stream with when the service shuts down,
+/// or `None` if the client cancelled the stream.
+async fn stream_events(
+    stream: &mut h2::SendStream<Bytes>,
+    mut events: broadcast::Receiver<LiveEvent>,
+    event_types: &[String],
+) -> Result<Option<Status>, h2::Error> {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(LiveEvent::Event(event)) => {
+                    let event = messages::Event::from(event);
+                    if event_types.is_empty() || event_types.contains(&event.event_type) {
+                        stream.send_data(frame(&event.encode_to_vec()), false)?;
+                    }
+                }
+                Ok(LiveEvent::Alert(_)) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(Some(Status::new(UNAVAILABLE, "service shutting down"))),
+            },
+            reset = futures::future::poll_fn(|cx| stream.poll_reset(cx)) => {
+                return reset.map(|_| None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framing() {
+        let framed = frame(b"\x08\x01");
+        assert_eq!(&framed[..], b"\x00\x00\x00\x00\x02\x08\x01");
+        assert_eq!(unframe(&framed).unwrap(), b"\x08\x01");
+
+        assert_eq!(unframe(b"\x00\x00\x00").unwrap_err().code, INVALID_ARGUMENT);
+        assert_eq!(unframe(b"\x01\x00\x00\x00\x00").unwrap_err().code, UNIMPLEMENTED);
+        assert_eq!(unframe(b"\x00\x00\x00\x00\x05\x08").unwrap_err().code, INVALID_ARGUMENT);
+    }
+}
@@ -6,20 +6,56 @@
 mod api;
 mod config;
 mod core;
+mod grpc;
 mod models;
+mod storage;
 mod utils;
 
 use actix_web::{web, App, HttpServer};
+use actix_web::dev::Service;
 use actix_web::middleware::Logger;
-use dotenv::dotenv;
-use log::{info, error};
+use log::{info, error, warn};
 use std::sync::Arc;
 use tokio::sync::broadcast;
-use redis::Client as RedisClient;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::models::Config;
-use crate::core::{Analytics, Monitoring, RuleEngine};
+use crate::api::ApiState;
+use crate::grpc::GrpcServer;
+use crate::core::{AbuseIpdb, Allowlist, Analytics, AttackTracker, BaselineLearner, Blocklist, CrowdSec, Degradation, Dnsbl, GeoIp, LiveEvents, Reputation, ThreatIntel, ConcurrencyLimiter, DdosDetector, EventSinks, Monitoring, QuotaManager, RateLimiter, RedisPool, RuleEngine};
+use crate::core::bot_detection::BotDetector;
+use crate::core::captcha::Captcha;
+use crate::core::challenge::ChallengeManager;
+use crate::core::honeypot::Honeypot;
+use crate::core::scanner::ScannerDetector;
+use crate::core::trusted_proxies::TrustedProxies;
+use crate::core::login_protection::LoginProtection;
+use crate::core::attack_mode::AttackMode;
+use crate::core::escalation::Escalation;
+use crate::core::cloudflare::CloudflareClient;
+use crate::core::bgp::BgpAnnouncer;
+use crate::core::firewall::FirewallExecutor;
+use crate::core::flow_collector::FlowCollector;
+use crate::core::kafka::KafkaSink;
+use crate::core::clickhouse::{ClickHouse, ClickHouseSink};
+use crate::core::log_ingest::LogIngester;
+use crate::core::alert_channels::AlertChannel;
+use crate::core::attack_reports::AttackReporter;
+use crate::core::alert_rules::AlertRules;
+use crate::core::silences::Silences;
+use crate::core::analytics::RequestCounters;
+use crate::core::email::{EmailChannel, Mailer};
+use crate::core::event_archive::EventArchiver;
+use crate::core::enrichment::Enricher;
+use crate::core::event_buffer::EventBuffer;
+use crate::core::reports::ReportScheduler;
+use crate::core::syslog::SyslogSink;
+use crate::core::telemetry::Telemetry;
+use crate::core::tls_fingerprint::FingerprintTracker;
+use crate::core::traffic::TrafficStats;
+use crate::core::watchdog::Watchdog;
+use crate::core::tenants::Tenants;
+use crate::core::webhooks::Webhooks;
+use crate::storage::Archive;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -27,31 +63,332 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     info!("Starting DDoS Protection Service...");
 
-    // Load configuration
-    let config = Config::from_env()?;
+    // Load configuration: defaults, then the config file, then environment variables
+    let config = match config::load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
     info!("Configuration loaded successfully");
 
-    // Initialize Redis connection
-    let redis_client = RedisClient::open(config.redis.url.clone())?;
-    let _redis_conn = redis_client.get_async_connection().await?;
-    info!("Connected to Redis successfully");
+    // Check the configuration before starting anything; `--check-config` only checks
+    let report = config::check_config(&config).await;
+    if std::env::args().any(|arg| arg == "--check-config") {
+        println!("{}", report);
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
+    if !report.passed() {
+        error!("Configuration check failed:\n{}", report);
+        std::process::exit(1);
+    }
+    for result in report.with_status(config::CheckStatus::Warning) {
+        warn!("{}: {}", result.name, result.message);
+    }
+
+    // Record decision path and background task spans before anything creates them
+    let telemetry = Telemetry::new(config.telemetry.clone());
+    telemetry.install()?;
+
+    // Initialize the shared Redis connection pool
+    let redis_pool = RedisPool::connect(&config.redis).await?;
+    info!("Connected to Redis successfully (pool size: {})", redis_pool.stats().size);
+
+    // Counters, sorted sets and event logs go to the configured backend
+    let storage = storage::open(&config.storage, redis_pool.clone());
+
+    // Rules, alerts, attacks and the audit log are also kept in PostgreSQL when enabled
+    let archive = if config.postgres.enabled {
+        let archive = Archive::connect(&config.postgres).await?;
+        info!("Connected to PostgreSQL");
+        Some(archive)
+    } else {
+        None
+    };
+
+    // Load the allowlist before serving so allowlisted clients are never limited
+    let allowlist = Allowlist::new(redis_pool.clone(), config.allowlist.clone())?;
+    if let Err(e) = allowlist.reload().await {
+        error!("Failed to load allowlist entries: {}", e);
+    }
+
+    // Decides how subsystems behave while Redis is down
+    let degradation = Degradation::new(redis_pool.clone(), config.degradation.clone());
+
+    let blocklist = Blocklist::new(redis_pool.clone(), config.blocklist.clone())
+        .with_subnets(config.subnets.clone())
+        .with_degradation(degradation.clone());
+    if let Err(e) = blocklist.reload().await {
+        error!("Failed to load blocklist entries: {}", e);
+    }
+    let tenants = Tenants::new(redis_pool.clone(), blocklist.clone());
+
+    let geoip = GeoIp::new(config.geoip.clone());
+    if let Err(e) = geoip.reload().await {
+        error!("Failed to load GeoIP databases: {}", e);
+    }
+
+    let reputation = Reputation::new(redis_pool.clone(), config.reputation.clone())
+        .with_subnets(config.subnets.clone());
+
+    let abuseipdb = AbuseIpdb::new(redis_pool.clone(), config.abuseipdb.clone())?;
+    let crowdsec = CrowdSec::new(config.crowdsec.clone(), blocklist.clone())?;
+    let mut attacks = AttackTracker::new(redis_pool.clone(), config.attacks.clone());
+    if let Some(archive) = &archive {
+        attacks = attacks.with_archive(archive.clone());
+    }
+    let challenges = ChallengeManager::new(config.challenge.clone())
+        .with_captcha(Captcha::new(config.challenge.captcha.clone())?);
+    let scanners = ScannerDetector::new(redis_pool.clone(), config.scanner_detection.clone());
+    if let Err(e) = scanners.reload().await {
+        error!("Failed to load scanner signatures: {}", e);
+    }
+    let fingerprints = FingerprintTracker::new(storage.clone(), config.ddos_detection.tls_fingerprint.clone());
 
     // Initialize services with their configurations
-    let analytics = Arc::new(Analytics::new(
-        redis_client.clone(),
-        config.analytics.clone(),
-        Duration::from_secs(config.analytics.retention_days * 24 * 60 * 60),
-    ));
-
-    let monitoring = Arc::new(Monitoring::new(
-        redis_client.clone(),
-        config.monitoring.clone(),
-    ));
-
-    let rule_engine = Arc::new(RuleEngine::new(
-        redis_client.clone(),
+    let retention_period = Duration::from_secs(config.analytics.retention_days * 24 * 60 * 60);
+
+    // Forward recorded events to external systems
+    let mut event_sinks = EventSinks::new();
+    if config.syslog.enabled {
+        event_sinks = event_sinks.with_sink(Arc::new(SyslogSink::new(config.syslog.clone())?), config.syslog.queue_size);
+    }
+    if config.kafka.enabled {
+        event_sinks = event_sinks.with_sink(Arc::new(KafkaSink::new(config.kafka.clone())), config.kafka.queue_size);
+    }
+
+    // Events are stored and queried in ClickHouse, with only the latest buffered in Redis
+    let clickhouse = if config.analytics.storage_type == "clickhouse" {
+        let clickhouse = ClickHouse::new(config.clickhouse.clone(), config.analytics.retention_days)?;
+        if let Err(e) = clickhouse.create_table().await {
+            error!("Failed to create the ClickHouse events table: {}", e);
+        }
+        event_sinks = event_sinks.with_sink(Arc::new(ClickHouseSink::new(clickhouse.clone())), config.clickhouse.queue_size);
+        Some(clickhouse)
+    } else {
+        None
+    };
+
+    // Responses are counted for the request and error rates in the system and analytics metrics
+    let request_counters = RequestCounters::new();
+
+    // Events are given their client's location and name before they are stored
+    let enricher = Enricher::new(geoip.clone(), config.analytics.enrichment.clone());
+
+    // Events are stored in batches, and request events sampled under load
+    let event_buffer = EventBuffer::new(config.analytics.buffer.clone());
+
+    let new_analytics = || {
+        let analytics = Analytics::new(redis_pool.clone(), config.analytics.clone(), retention_period)
+            .with_storage(storage.clone())
+            .with_sinks(event_sinks.clone())
+            .with_enricher(enricher.clone())
+            .with_buffer(event_buffer.clone());
+        match &clickhouse {
+            Some(clickhouse) => analytics.with_clickhouse(clickhouse.clone()),
+            None => analytics,
+        }
+    };
+    let analytics = Arc::new(new_analytics().with_request_counters(request_counters.clone()));
+
+    // Alerts and rule notifications are delivered to the managed webhook endpoints
+    let webhooks = Webhooks::new(redis_pool.clone(), config.webhooks.clone());
+
+    // Critical alerts and scheduled reports go out by email
+    let mailer = Mailer::new(config.email.clone());
+    let email_channel: Option<Arc<dyn AlertChannel>> = (config.email.enabled && !config.email.alert_recipients.is_empty())
+        .then(|| {
+            Arc::new(EmailChannel::new(
+                mailer.clone(),
+                config.email.alert_recipients.clone(),
+                config.email.alert_levels.clone(),
+            )) as Arc<dyn AlertChannel>
+        });
+    let new_monitoring = || {
+        let monitoring = Monitoring::new(redis_pool.clone(), config.monitoring.clone())
+            .with_storage(storage.clone())
+            .with_webhooks(webhooks.clone())
+            .with_request_counters(request_counters.clone());
+        let monitoring = match &archive {
+            Some(archive) => monitoring.with_archive(archive.clone()),
+            None => monitoring,
+        };
+        match &email_channel {
+            Some(channel) => monitoring.with_channel(channel.clone()),
+            None => monitoring,
+        }
+    };
+
+    let monitoring = Arc::new(new_monitoring());
+
+    let cloudflare = config
+        .cloudflare
+        .enabled
+        .then(|| Arc::new(CloudflareClient::from_config(&config.cloudflare, redis_pool.clone())));
+
+    let mut rule_engine = RuleEngine::new(
+        redis_pool.clone(),
         config.rule_config.clone(),
-    ));
+    )
+    .with_analytics(analytics.clone())
+    .with_monitoring(monitoring.clone())
+    .with_allowlist(allowlist.clone())
+    .with_blocklist(blocklist.clone())
+    .with_tenants(tenants.clone())
+    .with_geoip(geoip.clone())
+    .with_dnsbl(Dnsbl::new(config.dnsbl.clone()))
+    .with_reputation(reputation.clone())
+    .with_subnets(config.subnets.clone());
+    if let Some(cloudflare) = &cloudflare {
+        rule_engine = rule_engine.with_cloudflare(cloudflare.clone());
+    }
+    if let Some(archive) = &archive {
+        rule_engine = rule_engine.with_archive(archive.clone());
+    }
+    let rule_engine = Arc::new(rule_engine);
+    if let Err(e) = rule_engine.load_rules().await {
+        error!("Failed to load rules: {}", e);
+    }
+    if let Some(rules_file) = &config.rule_config.rules_file {
+        match rule_engine.load_rules_file(rules_file).await {
+            Ok(summary) => info!(
+                "Loaded {} rules from {} ({} already present)",
+                summary.created.len(),
+                rules_file,
+                summary.skipped.len()
+            ),
+            Err(e) => error!("Failed to load rules file {}: {}", rules_file, e),
+        }
+    }
+
+    let mut escalation = Escalation::new(
+        redis_pool.clone(),
+        config.escalation.clone(),
+        blocklist.clone(),
+        config.subnets.clone(),
+        config.ddos_detection.request_rate_window,
+    )
+    .with_analytics(analytics.clone());
+    if let Some(cloudflare) = &cloudflare {
+        escalation = escalation.with_upstream(cloudflare.clone());
+    }
+    let bgp = config
+        .bgp
+        .enabled
+        .then(|| Arc::new(BgpAnnouncer::new(redis_pool.clone(), config.bgp.clone())));
+    if let Some(bgp) = &bgp {
+        escalation = escalation.with_upstream(bgp.clone());
+    }
+
+    let ddos_detector = Arc::new(
+        DdosDetector::new(redis_pool.clone(), config.ddos_detection.clone())
+            .with_blocklist(blocklist.clone())
+            .with_geoip(geoip.clone())
+            .with_reputation(reputation.clone())
+            .with_abuseipdb(abuseipdb.clone())
+            .with_crowdsec(crowdsec.clone())
+            .with_attacks(attacks.clone())
+            .with_fingerprints(fingerprints.clone())
+            .with_subnets(config.subnets.clone()),
+    );
+
+    // Fan out events and alerts recorded by any instance to live streams
+    let live_events = LiveEvents::new();
+
+    // Per-minute and per-hour traffic counts for graphs
+    let traffic = TrafficStats::new(redis_pool.clone(), geoip.clone(), retention_period);
+
+    // Reports are built as attacks end
+    let attack_reports = AttackReporter::new(
+        redis_pool.clone(),
+        attacks.clone(),
+        analytics.clone(),
+        traffic.clone(),
+        Duration::from_secs(config.attacks.retention_seconds),
+    );
+
+    // Supervises the background tasks; the readiness probe reads their heartbeats
+    let watchdog = Watchdog::new(redis_pool.clone(), config.monitoring.watchdog.clone(), monitoring.clone());
+
+    // Initialize API state
+    let api_state = web::Data::new(ApiState {
+        allowlist: allowlist.clone(),
+        blocklist: blocklist.clone(),
+        geoip: geoip.clone(),
+        trusted_proxies: TrustedProxies::new(&config.trusted_proxies),
+        reputation: reputation.clone(),
+        attacks: attacks.clone(),
+        attack_reports: attack_reports.clone(),
+        baseline: BaselineLearner::new(
+            redis_pool.clone(),
+            config.ddos_detection.baseline.clone(),
+            config.ddos_detection.anomaly_threshold,
+        ),
+        rate_limiter: Arc::new(
+            RateLimiter::new(redis_pool.clone(), config.rate_limit.clone()).with_degradation(degradation.clone()),
+        ),
+        concurrency_limiter: Arc::new(ConcurrencyLimiter::new(
+            storage.clone(),
+            config.concurrency.clone(),
+        )),
+        quota_manager: Arc::new(QuotaManager::new(redis_pool.clone())),
+        ddos_detector: ddos_detector.clone(),
+        fingerprints,
+        bots: BotDetector::new(redis_pool.clone(), config.bot_detection.clone()),
+        honeypot: Honeypot::new(config.honeypot.clone(), blocklist.clone(), reputation.clone()),
+        scanners: scanners.clone(),
+        logins: LoginProtection::new(redis_pool.clone(), config.login_protection.clone()),
+        attack_mode: AttackMode::new(redis_pool.clone(), config.attack_mode.clone()),
+        escalation: escalation.clone(),
+        challenges,
+        rule_engine: rule_engine.clone(),
+        analytics: Arc::new(new_analytics()),
+        monitoring: Arc::new(new_monitoring()),
+        alert_rules: AlertRules::new(redis_pool.clone()),
+        silences: Silences::new(redis_pool.clone()),
+        live_events: live_events.clone(),
+        traffic: traffic.clone(),
+        webhooks,
+        tenants: tenants.clone(),
+        redis_pool: redis_pool.clone(),
+        degradation: degradation.clone(),
+        heartbeats: watchdog.heartbeats(),
+        archive: archive.clone(),
+        config: config.clone(),
+    });
+
+    // The gRPC API calls into the same state as the REST API
+    let grpc_server = GrpcServer::new(config.grpc.clone(), api_state.clone());
+
+    // Start the API server
+    let server = HttpServer::new(move || {
+        let request_counters = request_counters.clone();
+        App::new()
+            .app_data(api_state.clone())
+            .wrap_fn(move |req, srv| {
+                let request_counters = request_counters.clone();
+                let started = Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await;
+                    let status = match &response {
+                        Ok(res) => res.status(),
+                        Err(e) => e.as_response_error().status_code(),
+                    };
+                    request_counters.record(status.as_u16(), started.elapsed());
+                    response
+                }
+            })
+            .wrap(Logger::default())
+            .configure(api::config)
+    })
+    .bind((config.server.host.clone(), config.server.port))?
+    .disable_signals()
+    .run();
+    let server_handle = server.handle();
+    let server_task = tokio::spawn(server);
+    info!("API server listening on {}:{}", config.server.host, config.server.port);
 
     // Start background tasks
     let analytics_clone = analytics.clone();
@@ -62,22 +399,186 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (shutdown_tx, _shutdown_rx) = broadcast::channel(1);
     let mut shutdown_rx_clone = shutdown_tx.subscribe();
 
-    // Spawn background tasks
-    let analytics_handle = tokio::spawn(async move {
-        if let Err(e) = analytics_clone.start_collection().await {
-            error!("Analytics processing error: {}", e);
+    // Spawn background tasks; failed ones are restarted and alerted on
+    let analytics_handle = watchdog.supervise("Analytics", move |heartbeat| {
+        let analytics = analytics_clone.clone();
+        async move { analytics.start_collection(heartbeat).await }
+    });
+
+    let analytics_clone = analytics.clone();
+    let event_buffer_handle = watchdog.supervise("Event buffer", move |heartbeat| {
+        let analytics = analytics_clone.clone();
+        async move { analytics.start_flushing(heartbeat).await }
+    });
+
+    let traffic_handle = watchdog.supervise("Traffic", move |heartbeat| {
+        let traffic = traffic.clone();
+        async move { traffic.start(heartbeat).await }
+    });
+
+    let monitoring_handle = watchdog.supervise("Monitoring", move |heartbeat| {
+        let monitoring = monitoring_clone.clone();
+        async move { monitoring.start_monitoring(heartbeat).await }
+    });
+
+    let rule_engine_handle = watchdog.supervise("Rule engine", move |heartbeat| {
+        let rule_engine = rule_engine_clone.clone();
+        async move {
+            rule_engine
+                .process_rules(heartbeat)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+    });
+
+    // Pick up rule changes made through other instances
+    let rule_sync_engine = rule_engine.clone();
+    let rule_sync_handle = tokio::spawn(async move {
+        if let Err(e) = rule_sync_engine.start_sync().await {
+            error!("Rule sync error: {}", e);
+        }
+    });
+
+    let telemetry_exporter = telemetry.clone();
+    let telemetry_handle = tokio::spawn(async move {
+        telemetry_exporter.start().await;
+    });
+
+    let live_events_redis = redis_pool.clone();
+    let live_events_handle = tokio::spawn(async move {
+        live_events.start(live_events_redis).await;
+    });
+
+    let reports = ReportScheduler::new(redis_pool.clone(), analytics.clone(), attacks.clone(), mailer, config.email.clone());
+    let email_enabled = config.email.enabled;
+    let reports_handle = tokio::spawn(async move {
+        if email_enabled {
+            reports.start().await;
+        }
+    });
+
+    let event_sinks_handle = tokio::spawn(async move {
+        event_sinks.start().await;
+    });
+
+    let grpc_handle = tokio::spawn(async move {
+        if let Err(e) = grpc_server.start().await {
+            error!("gRPC server error: {}", e);
+        }
+    });
+
+    let adaptive_limiter = RateLimiter::new(redis_pool.clone(), config.rate_limit.clone());
+    let adaptive_monitoring = monitoring.clone();
+    let alert_thresholds = config.monitoring.alert_thresholds.clone();
+    let adaptive_handle = tokio::spawn(async move {
+        if !adaptive_limiter.adaptive_enabled() {
+            return;
+        }
+        if let Err(e) = adaptive_limiter.start_adaptive_limits(adaptive_monitoring, alert_thresholds).await {
+            error!("Adaptive rate limit error: {}", e);
+        }
+    });
+
+    let mut log_ingester = LogIngester::new(
+        config.log_ingest.clone(),
+        ddos_detector.clone(),
+        allowlist.clone(),
+        blocklist.clone(),
+    );
+    if config.rule_config.enabled {
+        log_ingester = log_ingester.with_rule_engine(rule_engine.clone());
+    }
+    let log_ingest_handle = tokio::spawn(async move {
+        log_ingester.start().await;
+    });
+
+    let refreshed_allowlist = allowlist.clone();
+    let allowlist_handle = tokio::spawn(async move {
+        if let Err(e) = refreshed_allowlist.start_refresh().await {
+            error!("Allowlist refresh error: {}", e);
+        }
+    });
+
+    let threat_intel = ThreatIntel::new(config.threat_intel.clone(), blocklist.clone())?
+        .with_reputation(reputation.clone());
+    let threat_intel_handle = tokio::spawn(async move {
+        threat_intel.start().await;
+    });
+
+    let attacks_handle = tokio::spawn(async move {
+        attacks.start().await;
+    });
+
+    let attack_reports_handle = tokio::spawn(async move {
+        attack_reports.start().await;
+    });
+
+    let event_archiver = if config.event_archive.enabled {
+        Some(EventArchiver::new(redis_pool.clone(), analytics.clone(), config.event_archive.clone())?)
+    } else {
+        None
+    };
+    let event_archive_handle = tokio::spawn(async move {
+        if let Some(event_archiver) = event_archiver {
+            event_archiver.start().await;
+        }
+    });
+
+    let escalation_handle = tokio::spawn(async move {
+        escalation.start().await;
+    });
+
+    let bgp_handle = tokio::spawn(async move {
+        if let Some(bgp) = bgp {
+            bgp.start().await;
+        }
+    });
+
+    let cloudflare_handle = tokio::spawn(async move {
+        if let Some(cloudflare) = cloudflare {
+            cloudflare.start().await;
+        }
+    });
+
+    let crowdsec_handle = tokio::spawn(async move {
+        crowdsec.start_sync().await;
+    });
+
+    let firewall = FirewallExecutor::new(config.firewall.clone(), blocklist.clone(), allowlist.clone());
+    let firewall_handle = tokio::spawn(async move {
+        firewall.start().await;
+    });
+
+    let flow_collector = FlowCollector::new(config.flow_collector.clone(), ddos_detector);
+    let flow_collector_handle = tokio::spawn(async move {
+        flow_collector.start().await;
+    });
+
+    // Probe Redis during outages and write in-memory state back once it returns
+    let degradation_blocklist = blocklist.clone();
+    let degradation_handle = tokio::spawn(async move {
+        degradation.start(degradation_blocklist).await;
+    });
+
+    let blocklist_handle = tokio::spawn(async move {
+        if let Err(e) = blocklist.start_refresh().await {
+            error!("Blocklist refresh error: {}", e);
         }
     });
 
-    let monitoring_handle = tokio::spawn(async move {
-        if let Err(e) = monitoring_clone.start_monitoring().await {
-            error!("Monitoring error: {}", e);
+    let tenants_handle = tokio::spawn(async move {
+        tenants.start_refresh().await;
+    });
+
+    let scanners_handle = tokio::spawn(async move {
+        if let Err(e) = scanners.start_refresh().await {
+            error!("Scanner signature refresh error: {}", e);
         }
     });
 
-    let rule_engine_handle = tokio::spawn(async move {
-        if let Err(e) = rule_engine_clone.process_rules().await {
-            error!("Rule engine processing error: {}", e);
+    let geoip_handle = tokio::spawn(async move {
+        if let Err(e) = geoip.start_refresh().await {
+            error!("GeoIP refresh error: {}", e);
         }
     });
 
@@ -96,10 +597,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _ = shutdown_rx_clone.recv().await;
     info!("Shutting down...");
 
+    // Stop accepting requests and let in-flight ones finish
+    server_handle.stop(true).await;
+    let _ = server_task.await;
+
+    // Store the events still queued
+    if let Err(e) = analytics.flush_events().await {
+        error!("Failed to store analytics events: {}", e);
+    }
+
     // Cancel all background tasks
     analytics_handle.abort();
+    event_buffer_handle.abort();
+    traffic_handle.abort();
     monitoring_handle.abort();
     rule_engine_handle.abort();
+    rule_sync_handle.abort();
+    live_events_handle.abort();
+    event_sinks_handle.abort();
+    reports_handle.abort();
+    telemetry_handle.abort();
+    grpc_handle.abort();
+    adaptive_handle.abort();
+    allowlist_handle.abort();
+    blocklist_handle.abort();
+    tenants_handle.abort();
+    degradation_handle.abort();
+    firewall_handle.abort();
+    flow_collector_handle.abort();
+    log_ingest_handle.abort();
+    geoip_handle.abort();
+    scanners_handle.abort();
+    threat_intel_handle.abort();
+    crowdsec_handle.abort();
+    attacks_handle.abort();
+    attack_reports_handle.abort();
+    event_archive_handle.abort();
+    escalation_handle.abort();
+    bgp_handle.abort();
+    cloudflare_handle.abort();
+
+    // Export the spans of the last requests
+    telemetry.flush().await;
 
     info!("Shutdown complete");
     Ok(())
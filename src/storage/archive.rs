@@ -0,0 +1,285 @@
+//! Durable records in PostgreSQL.
+//!
+//! Redis holds everything the request path needs, but a flush takes rules
+//! and alert history with it. With PostgreSQL configured, rules and their
+//! history, alerts, ended attacks and an audit log of changes are written
+//! there as well, and rules and recent alerts are restored into Redis from
+//! it when Redis comes up empty. Hot counters stay in Redis only.
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use crate::core::attacks::Attack;
+use crate::core::monitoring::Alert;
+use crate::core::rule_engine::{Rule, RuleHistoryEntry};
+use crate::models::PostgresConfig;
+use super::postgres::{Postgres, PostgresError, Row};
+
+/// Schema migrations by version, applied in order
+const MIGRATIONS: &[(i64, &str)] = &[(1, include_str!("../../migrations/0001_durable_entities.sql"))];
+
+/// Advisory lock held while migrating, so that instances starting together don't race
+const MIGRATION_LOCK: i64 = 0x6464_6f73;
+
+/// Errors that can occur while reading or writing the archive
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("PostgreSQL error: {0}")]
+    Postgres(#[from] PostgresError),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// An entry of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// Who made the change
+    pub actor: String,
+    /// What was done (`rule.created`, ...)
+    pub action: String,
+    /// What it was done to
+    pub target: String,
+    /// Details of the change
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The first column of each row, parsed from JSON, skipping rows that don't parse
+fn json_rows<T: DeserializeOwned>(rows: Vec<Row>) -> Vec<T> {
+    rows.into_iter()
+        .filter_map(|row| row.into_iter().next().flatten())
+        .filter_map(|json| serde_json::from_str(&json).ok())
+        .collect()
+}
+
+/// Name of a unit enum variant as serialized (`Active`, ...)
+fn variant_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// Durable store for rules, alerts, attacks and the audit log
+///
+/// Cloning is cheap and all clones share the same connection.
+#[derive(Clone)]
+pub struct Archive {
+    postgres: Arc<Postgres>,
+}
+
+impl Archive {
+    /// Connect and bring the schema up to date
+    pub async fn connect(config: &PostgresConfig) -> Result<Self, ArchiveError> {
+        let postgres = Postgres::new(&config.url, Duration::from_secs(config.timeout_seconds.max(1)))?;
+        let archive = Self {
+            postgres: Arc::new(postgres),
+        };
+        archive.migrate().await?;
+        Ok(archive)
+    }
+
+    /// Apply the migrations that haven't been applied yet
+    async fn migrate(&self) -> Result<(), ArchiveError> {
+        let lock = MIGRATION_LOCK.to_string();
+        self.postgres.execute("SELECT pg_advisory_lock($1::bigint)", &[&lock]).await?;
+        let result = self.apply_migrations().await;
+        self.postgres.execute("SELECT pg_advisory_unlock($1::bigint)", &[&lock]).await?;
+        result
+    }
+
+    async fn apply_migrations(&self) -> Result<(), ArchiveError> {
+        self.postgres
+            .batch(
+                "CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version BIGINT PRIMARY KEY,
+                    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+            )
+            .await?;
+        let rows = self.postgres.query("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", &[]).await?;
+        let current: i64 = rows
+            .first()
+            .and_then(|row| row.first().cloned().flatten())
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(0);
+
+        for (version, sql) in MIGRATIONS.iter().filter(|(version, _)| *version > current) {
+            // Statements sent together run in one transaction
+            self.postgres
+                .batch(&format!("{}\nINSERT INTO schema_migrations (version) VALUES ({});", sql, version))
+                .await?;
+            log::info!("Applied PostgreSQL migration {}", version);
+        }
+        Ok(())
+    }
+
+    /// Store a rule, replacing any with the same ID
+    pub async fn save_rule(&self, rule: &Rule) -> Result<(), ArchiveError> {
+        self.postgres
+            .execute(
+                "INSERT INTO rules (id, definition, updated_at) VALUES ($1, $2::jsonb, now())
+                 ON CONFLICT (id) DO UPDATE SET definition = EXCLUDED.definition, updated_at = now()",
+                &[&rule.id, &serde_json::to_string(rule)?],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Remove a rule
+    pub async fn delete_rule(&self, id: &str) -> Result<(), ArchiveError> {
+        self.postgres.execute("DELETE FROM rules WHERE id = $1", &[id]).await?;
+        Ok(())
+    }
+
+    /// All stored rules
+    pub async fn rules(&self) -> Result<Vec<Rule>, ArchiveError> {
+        Ok(json_rows(self.postgres.query("SELECT definition::text FROM rules", &[]).await?))
+    }
+
+    /// Add an entry to a rule's history
+    pub async fn record_rule_change(&self, entry: &RuleHistoryEntry) -> Result<(), ArchiveError> {
+        self.postgres
+            .execute(
+                "INSERT INTO rule_history (rule_id, version, entry, created_at)
+                 VALUES ($1, $2::bigint, $3::jsonb, $4::timestamptz)
+                 ON CONFLICT (rule_id, version) DO NOTHING",
+                &[
+                    &entry.rule_id,
+                    &entry.version.to_string(),
+                    &serde_json::to_string(entry)?,
+                    &entry.timestamp.to_rfc3339(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// A rule's whole history, newest first
+    pub async fn rule_history(&self, id: &str) -> Result<Vec<RuleHistoryEntry>, ArchiveError> {
+        let rows = self
+            .postgres
+            .query(
+                "SELECT entry::text FROM rule_history WHERE rule_id = $1 ORDER BY version DESC",
+                &[id],
+            )
+            .await?;
+        Ok(json_rows(rows))
+    }
+
+    /// The latest history version of each rule
+    pub async fn rule_versions(&self) -> Result<Vec<(String, u64)>, ArchiveError> {
+        let rows = self
+            .postgres
+            .query("SELECT rule_id, MAX(version) FROM rule_history GROUP BY rule_id", &[])
+            .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| match row.as_slice() {
+                [Some(id), Some(version)] => Some((id.clone(), version.parse().ok()?)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Store an alert, replacing any with the same ID
+    pub async fn save_alert(&self, alert: &Alert) -> Result<(), ArchiveError> {
+        self.postgres
+            .execute(
+                "INSERT INTO alerts (id, level, status, source, alert, created_at, updated_at)
+                 VALUES ($1, $2, $3, $4, $5::jsonb, $6::timestamptz, $7::timestamptz)
+                 ON CONFLICT (id) DO UPDATE SET status = EXCLUDED.status, alert = EXCLUDED.alert,
+                     updated_at = EXCLUDED.updated_at",
+                &[
+                    &alert.id,
+                    &variant_name(&alert.level),
+                    &variant_name(&alert.status),
+                    &alert.source,
+                    &serde_json::to_string(alert)?,
+                    &alert.created_at.to_rfc3339(),
+                    &alert.updated_at.to_rfc3339(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Alerts created since a time, oldest first
+    pub async fn alerts_since(&self, since: DateTime<Utc>) -> Result<Vec<Alert>, ArchiveError> {
+        let rows = self
+            .postgres
+            .query(
+                "SELECT alert::text FROM alerts WHERE created_at >= $1::timestamptz ORDER BY created_at",
+                &[&since.to_rfc3339()],
+            )
+            .await?;
+        Ok(json_rows(rows))
+    }
+
+    /// Store an attack, replacing any with the same ID
+    pub async fn save_attack(&self, attack: &Attack) -> Result<(), ArchiveError> {
+        let ended_at = attack.ended_at.map(|ended_at| ended_at.to_rfc3339()).unwrap_or_default();
+        self.postgres
+            .execute(
+                "INSERT INTO attacks (id, vector, attack, started_at, ended_at)
+                 VALUES ($1, $2, $3::jsonb, $4::timestamptz, NULLIF($5, '')::timestamptz)
+                 ON CONFLICT (id) DO UPDATE SET attack = EXCLUDED.attack, ended_at = EXCLUDED.ended_at",
+                &[
+                    &attack.id,
+                    &attack.vector,
+                    &serde_json::to_string(attack)?,
+                    &attack.started_at.to_rfc3339(),
+                    &ended_at,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The latest attacks, newest first
+    pub async fn attacks(&self, limit: usize) -> Result<Vec<Attack>, ArchiveError> {
+        let rows = self
+            .postgres
+            .query(
+                "SELECT attack::text FROM attacks ORDER BY started_at DESC LIMIT $1::bigint",
+                &[&limit.to_string()],
+            )
+            .await?;
+        Ok(json_rows(rows))
+    }
+
+    /// Add an entry to the audit log
+    pub async fn record_audit(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+        details: serde_json::Value,
+    ) -> Result<(), ArchiveError> {
+        self.postgres
+            .execute(
+                "INSERT INTO audit_log (actor, action, target, details) VALUES ($1, $2, $3, $4::jsonb)",
+                &[actor, action, target, &details.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The latest audit log entries, newest first
+    pub async fn audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, ArchiveError> {
+        let rows = self
+            .postgres
+            .query(
+                "SELECT json_build_object('id', id, 'actor', actor, 'action', action, 'target', target,
+                     'details', details, 'created_at', created_at)::text
+                 FROM audit_log ORDER BY id DESC LIMIT $1::bigint",
+                &[&limit.to_string()],
+            )
+            .await?;
+        Ok(json_rows(rows))
+    }
+}
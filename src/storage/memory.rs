@@ -0,0 +1,276 @@
+//! In-memory storage backend.
+
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use super::{entry_order, CounterStore, EventLog, LogEntry, SortedSetStore, StorageError};
+
+/// A value and when it expires
+struct Entry<T> {
+    value: T,
+    expires_at: Option<Instant>,
+}
+
+impl<T> Entry<T> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Entries by key, dropping expired ones as they are read
+struct Table<T>(HashMap<String, Entry<T>>);
+
+impl<T: Default> Table<T> {
+    fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    fn get(&mut self, key: &str) -> Option<&mut T> {
+        let now = Instant::now();
+        if self.0.get(key).is_some_and(|entry| entry.is_expired(now)) {
+            self.0.remove(key);
+        }
+        self.0.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    /// Get an entry, creating it empty, and restart its expiry if `ttl` is given
+    fn get_or_default(&mut self, key: &str, ttl: Option<Duration>) -> &mut T {
+        self.get(key);
+        let entry = self.0.entry(key.to_string()).or_insert_with(|| Entry {
+            value: T::default(),
+            expires_at: None,
+        });
+        if let Some(ttl) = ttl {
+            entry.expires_at = Some(Instant::now() + ttl);
+        }
+        &mut entry.value
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.0.remove(key);
+    }
+}
+
+/// ID of a log entry: milliseconds and sequence number
+type EntryId = (u64, u64);
+
+fn format_id((ms, seq): EntryId) -> String {
+    format!("{}-{}", ms, seq)
+}
+
+/// An event log
+#[derive(Default)]
+struct Log {
+    entries: VecDeque<(EntryId, String)>,
+    /// Last ID given out, which trimming doesn't reset
+    last_id: EntryId,
+}
+
+/// Storage kept in this process
+///
+/// Nothing is shared with other instances or survives a restart. Cloning is
+/// cheap and all clones share the same data.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    counters: Arc<Mutex<Table<i64>>>,
+    sorted_sets: Arc<Mutex<Table<HashMap<String, f64>>>>,
+    logs: Arc<Mutex<HashMap<String, Log>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self {
+            counters: Arc::new(Mutex::new(Table::new())),
+            sorted_sets: Arc::new(Mutex::new(Table::new())),
+            logs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Members of a sorted set within a score range, by score then name
+fn sorted(members: &HashMap<String, f64>, min: f64, max: f64) -> Vec<(String, f64)> {
+    let mut sorted: Vec<(String, f64)> = members
+        .iter()
+        .filter(|(_, score)| (min..=max).contains(*score))
+        .map(|(member, score)| (member.clone(), *score))
+        .collect();
+    sorted.sort_by(|(a, a_score), (b, b_score)| a_score.total_cmp(b_score).then_with(|| a.cmp(b)));
+    sorted
+}
+
+#[async_trait]
+impl CounterStore for MemoryStorage {
+    async fn increment(&self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64, StorageError> {
+        let mut counters = self.counters.lock().await;
+        let created = counters.get(key).is_none();
+        let value = counters.get_or_default(key, ttl.filter(|_| created));
+        *value += by;
+        Ok(*value)
+    }
+
+    async fn decrement(&self, key: &str, by: i64) -> Result<i64, StorageError> {
+        let mut counters = self.counters.lock().await;
+        let value = counters.get_or_default(key, None);
+        *value -= by;
+        let value = *value;
+        if value <= 0 {
+            counters.remove(key);
+        }
+        Ok(value)
+    }
+}
+
+#[async_trait]
+impl SortedSetStore for MemoryStorage {
+    async fn add(&self, key: &str, member: &str, score: f64) -> Result<(), StorageError> {
+        self.sorted_sets.lock().await.get_or_default(key, None).insert(member.to_string(), score);
+        Ok(())
+    }
+
+    async fn increment_score(&self, key: &str, member: &str, by: f64, ttl: Option<Duration>) -> Result<f64, StorageError> {
+        let mut sorted_sets = self.sorted_sets.lock().await;
+        let score = sorted_sets.get_or_default(key, ttl).entry(member.to_string()).or_insert(0.0);
+        *score += by;
+        Ok(*score)
+    }
+
+    async fn range_by_score(&self, key: &str, min: f64, max: f64) -> Result<Vec<(String, f64)>, StorageError> {
+        let mut sorted_sets = self.sorted_sets.lock().await;
+        Ok(sorted_sets.get(key).map(|members| sorted(members, min, max)).unwrap_or_default())
+    }
+
+    async fn top(&self, key: &str, limit: usize) -> Result<Vec<(String, f64)>, StorageError> {
+        let mut sorted_sets = self.sorted_sets.lock().await;
+        let Some(members) = sorted_sets.get(key) else {
+            return Ok(Vec::new());
+        };
+        Ok(sorted(members, f64::NEG_INFINITY, f64::INFINITY).into_iter().rev().take(limit).collect())
+    }
+
+    async fn remove_by_score(&self, key: &str, min: f64, max: f64) -> Result<u64, StorageError> {
+        let mut sorted_sets = self.sorted_sets.lock().await;
+        let Some(members) = sorted_sets.get(key) else {
+            return Ok(0);
+        };
+        let before = members.len();
+        members.retain(|_, score| !(min..=max).contains(score));
+        Ok((before - members.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl EventLog for MemoryStorage {
+    async fn append(&self, log: &str, entry: &str, max_len: usize) -> Result<String, StorageError> {
+        let mut logs = self.logs.lock().await;
+        let log = logs.entry(log.to_string()).or_default();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let id = if now > log.last_id.0 { (now, 0) } else { (log.last_id.0, log.last_id.1 + 1) };
+        log.last_id = id;
+        log.entries.push_back((id, entry.to_string()));
+        let excess = log.entries.len().saturating_sub(max_len);
+        log.entries.drain(..excess);
+        Ok(format_id(id))
+    }
+
+    async fn range(
+        &self,
+        log: &str,
+        start_ms: u64,
+        end_ms: u64,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<LogEntry>, StorageError> {
+        let logs = self.logs.lock().await;
+        let Some(log) = logs.get(log) else {
+            return Ok(Vec::new());
+        };
+        let after = after.map(entry_order);
+        Ok(log
+            .entries
+            .iter()
+            .filter(|(id, _)| (start_ms..=end_ms).contains(&id.0) && after.is_none_or(|after| *id > after))
+            .take(count)
+            .map(|(id, entry)| LogEntry {
+                id: format_id(*id),
+                entry: entry.clone(),
+            })
+            .collect())
+    }
+
+    async fn trim_before(&self, log: &str, before_ms: u64) -> Result<(), StorageError> {
+        if let Some(log) = self.logs.lock().await.get_mut(log) {
+            let expired = log.entries.iter().take_while(|((ms, _), _)| *ms < before_ms).count();
+            log.entries.drain(..expired);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Current value of a counter, if it exists
+    async fn counter(storage: &MemoryStorage, key: &str) -> Option<i64> {
+        storage.counters.lock().await.get(key).copied()
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+
+        assert_eq!(storage.increment("in_flight", 2, None).await.unwrap(), 2);
+        assert_eq!(storage.increment("in_flight", -1, None).await.unwrap(), 1);
+        assert_eq!(counter(&storage, "in_flight").await, Some(1));
+
+        storage.increment("in_flight", 1, None).await.unwrap();
+        assert_eq!(storage.decrement("in_flight", 1).await.unwrap(), 1);
+        assert_eq!(counter(&storage, "in_flight").await, Some(1));
+        assert_eq!(storage.decrement("in_flight", 1).await.unwrap(), 0);
+        assert_eq!(counter(&storage, "in_flight").await, None);
+        assert_eq!(storage.decrement("in_flight", 1).await.unwrap(), -1);
+        assert_eq!(counter(&storage, "in_flight").await, None);
+
+        storage.increment("expired", 1, Some(Duration::ZERO)).await.unwrap();
+        assert_eq!(counter(&storage, "expired").await, None);
+
+        // Only creating a counter sets its expiry
+        storage.increment("expiring", 1, Some(Duration::from_secs(60))).await.unwrap();
+        storage.increment("expiring", 1, Some(Duration::ZERO)).await.unwrap();
+        assert_eq!(counter(&storage, "expiring").await, Some(2));
+
+        for (member, score) in [("b", 2.0), ("a", 2.0), ("c", 1.0)] {
+            storage.add("series", member, score).await.unwrap();
+        }
+        assert_eq!(storage.increment_score("series", "c", 2.0, None).await.unwrap(), 3.0);
+        let names = |members: Vec<(String, f64)>| members.into_iter().map(|(member, _)| member).collect::<Vec<_>>();
+        assert_eq!(names(storage.range_by_score("series", 0.0, 2.0).await.unwrap()), vec!["a", "b"]);
+        assert_eq!(names(storage.top("series", 2).await.unwrap()), vec!["c", "b"]);
+        assert_eq!(storage.remove_by_score("series", f64::NEG_INFINITY, 2.0).await.unwrap(), 2);
+        assert_eq!(names(storage.top("series", 10).await.unwrap()), vec!["c"]);
+
+        let entries = |entries: Vec<LogEntry>| entries.into_iter().map(|entry| entry.entry).collect::<Vec<_>>();
+        storage.append("events", "first", 2).await.unwrap();
+        storage.append("events", "second", 2).await.unwrap();
+        storage.append("events", "third", 2).await.unwrap();
+        let logged = storage.range("events", 0, u64::MAX, None, usize::MAX).await.unwrap();
+        assert_eq!(entries(logged.clone()), vec!["second", "third"]);
+        let page = storage.range("events", 0, u64::MAX, None, 1).await.unwrap();
+        assert_eq!(entries(page.clone()), vec!["second"]);
+        let page = storage.range("events", 0, u64::MAX, Some(&page[0].id), 5).await.unwrap();
+        assert_eq!(entries(page), vec!["third"]);
+        let appended_at: u64 = logged[0].id.split('-').next().unwrap().parse().unwrap();
+        storage.trim_before("events", appended_at).await.unwrap();
+        assert_eq!(entries(storage.range("events", 0, u64::MAX, None, usize::MAX).await.unwrap()), vec!["second", "third"]);
+        storage.trim_before("events", u64::MAX).await.unwrap();
+        assert!(storage.range("events", 0, u64::MAX, None, usize::MAX).await.unwrap().is_empty());
+    }
+}
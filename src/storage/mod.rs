@@ -0,0 +1,139 @@
+//! Storage backends for the DDoS protection service.
+//!
+//! Subsystems that only need counters, sorted sets or an append-only event
+//! log reach their data through the traits here instead of issuing Redis
+//! commands, so the backend can be swapped: Redis in production, shared by
+//! every instance, or memory for development and tests. Another backend
+//! only has to implement the three traits.
+//!
+//! Records that must outlive Redis, such as rules and alerts, can also be
+//! kept in PostgreSQL through the `Archive`.
+
+mod archive;
+mod memory;
+mod postgres;
+mod redis;
+
+pub use self::archive::{Archive, ArchiveError};
+pub use self::memory::MemoryStorage;
+pub use self::postgres::Postgres;
+pub use self::redis::RedisStorage;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use crate::core::redis_pool::RedisPool;
+use crate::models::{StorageBackend, StorageConfig};
+
+/// Errors that can occur while reading or writing storage
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] ::redis::RedisError),
+}
+
+/// Counters, such as requests in flight
+#[async_trait]
+pub trait CounterStore: Send + Sync {
+    /// Add `by` to a counter, creating it at zero, and return the new value
+    ///
+    /// With a `ttl`, a counter created by this call expires that long after
+    /// it; later increments leave the expiry alone.
+    async fn increment(&self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64, StorageError>;
+
+    /// Subtract `by` from a counter and return the new value, removing the
+    /// counter in the same step once it is zero or below
+    ///
+    /// The counter keeps its expiry while it is above zero.
+    async fn decrement(&self, key: &str, by: i64) -> Result<i64, StorageError>;
+}
+
+/// Sets of members ordered by score, such as time series or leaderboards
+///
+/// Members with the same score are ordered by name. Score ranges include
+/// both bounds.
+#[async_trait]
+pub trait SortedSetStore: Send + Sync {
+    /// Add a member, or change its score
+    async fn add(&self, key: &str, member: &str, score: f64) -> Result<(), StorageError>;
+
+    /// Add `by` to a member's score, adding it at zero, and return the new score
+    ///
+    /// With a `ttl`, the set expires that long after this call.
+    async fn increment_score(&self, key: &str, member: &str, by: f64, ttl: Option<Duration>) -> Result<f64, StorageError>;
+
+    /// Members with a score between `min` and `max`, lowest first
+    async fn range_by_score(&self, key: &str, min: f64, max: f64) -> Result<Vec<(String, f64)>, StorageError>;
+
+    /// The `limit` members with the highest scores, highest first
+    async fn top(&self, key: &str, limit: usize) -> Result<Vec<(String, f64)>, StorageError>;
+
+    /// Remove the members with a score between `min` and `max`, returning how many were removed
+    async fn remove_by_score(&self, key: &str, min: f64, max: f64) -> Result<u64, StorageError>;
+}
+
+/// An entry of an event log
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    /// `<milliseconds>-<sequence>`, where milliseconds is when the entry was
+    /// appended; IDs increase through a log
+    pub id: String,
+    pub entry: String,
+}
+
+/// Milliseconds and sequence of an entry ID, which order entries through a log
+fn entry_order(id: &str) -> (u64, u64) {
+    let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+    (ms.parse().unwrap_or(0), seq.parse().unwrap_or(0))
+}
+
+/// Append-only logs of entries, such as analytics events
+///
+/// Entries are identified by the time they were appended, so logs are read
+/// and trimmed by time.
+#[async_trait]
+pub trait EventLog: Send + Sync {
+    /// Add an entry at the end of a log, returning its ID
+    ///
+    /// The oldest entries are dropped to keep the log at about `max_len` entries.
+    async fn append(&self, log: &str, entry: &str, max_len: usize) -> Result<String, StorageError>;
+
+    /// Add entries at the end of a log, in order
+    async fn append_all(&self, log: &str, entries: &[String], max_len: usize) -> Result<(), StorageError> {
+        for entry in entries {
+            self.append(log, entry, max_len).await?;
+        }
+        Ok(())
+    }
+
+    /// The first `count` entries appended from `start_ms` to `end_ms`, oldest
+    /// first, following the entry `after` if given
+    async fn range(
+        &self,
+        log: &str,
+        start_ms: u64,
+        end_ms: u64,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<LogEntry>, StorageError>;
+
+    /// Drop the entries appended before `before_ms`
+    async fn trim_before(&self, log: &str, before_ms: u64) -> Result<(), StorageError>;
+}
+
+/// A backend providing every kind of storage
+pub trait Storage: CounterStore + SortedSetStore + EventLog {}
+
+impl<T: CounterStore + SortedSetStore + EventLog> Storage for T {}
+
+/// Open the configured storage backend
+///
+/// Only counters, sorted sets and event logs go through it; the other
+/// cores still use `redis` directly.
+pub fn open(config: &StorageConfig, redis: RedisPool) -> Arc<dyn Storage> {
+    match config.backend {
+        StorageBackend::Redis => Arc::new(RedisStorage::new(redis)),
+        StorageBackend::Memory => Arc::new(MemoryStorage::new()),
+    }
+}
@@ -0,0 +1,202 @@
+//! Redis storage backend.
+
+use async_trait::async_trait;
+use std::time::Duration;
+use crate::core::redis_pool::RedisPool;
+use super::{entry_order, CounterStore, EventLog, LogEntry, SortedSetStore, StorageError};
+
+/// Increment a counter, setting its expiry only when this creates it
+///
+/// KEYS: counter
+/// ARGV: amount, TTL in seconds (0 = none)
+/// Returns: the new value
+const INCREMENT_SCRIPT: &str = r#"
+local created = redis.call('EXISTS', KEYS[1]) == 0
+local value = redis.call('INCRBY', KEYS[1], ARGV[1])
+if created and tonumber(ARGV[2]) > 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+return value
+"#;
+
+/// Decrement a counter, deleting it once it drops to zero or below
+///
+/// KEYS: counter
+/// ARGV: amount
+/// Returns: the new value
+const DECREMENT_SCRIPT: &str = r#"
+local value = redis.call('DECRBY', KEYS[1], ARGV[1])
+if value <= 0 then
+    redis.call('DEL', KEYS[1])
+end
+return value
+"#;
+
+/// Storage in Redis, shared by every instance
+///
+/// Counters are strings, sorted sets are sorted sets and event logs are
+/// streams, under the keys given.
+#[derive(Clone)]
+pub struct RedisStorage {
+    redis: RedisPool,
+    increment_script: redis::Script,
+    decrement_script: redis::Script,
+}
+
+impl RedisStorage {
+    pub fn new(redis: RedisPool) -> Self {
+        Self {
+            redis,
+            increment_script: redis::Script::new(INCREMENT_SCRIPT),
+            decrement_script: redis::Script::new(DECREMENT_SCRIPT),
+        }
+    }
+}
+
+#[async_trait]
+impl CounterStore for RedisStorage {
+    async fn increment(&self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64, StorageError> {
+        let ttl = ttl.map_or(0, |ttl| ttl.as_secs().max(1));
+        Ok(self.increment_script.key(key).arg(by).arg(ttl).invoke_async(&mut self.redis.get()).await?)
+    }
+
+    async fn decrement(&self, key: &str, by: i64) -> Result<i64, StorageError> {
+        Ok(self.decrement_script.key(key).arg(by).invoke_async(&mut self.redis.get()).await?)
+    }
+}
+
+#[async_trait]
+impl SortedSetStore for RedisStorage {
+    async fn add(&self, key: &str, member: &str, score: f64) -> Result<(), StorageError> {
+        let _: () = redis::cmd("ZADD").arg(key).arg(score).arg(member).query_async(&mut self.redis.get()).await?;
+        Ok(())
+    }
+
+    async fn increment_score(&self, key: &str, member: &str, by: f64, ttl: Option<Duration>) -> Result<f64, StorageError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("ZINCRBY").arg(key).arg(by).arg(member);
+        if let Some(ttl) = ttl {
+            pipe.cmd("EXPIRE").arg(key).arg(ttl.as_secs().max(1)).ignore();
+        }
+        let (score,): (f64,) = pipe.query_async(&mut self.redis.get()).await?;
+        Ok(score)
+    }
+
+    async fn range_by_score(&self, key: &str, min: f64, max: f64) -> Result<Vec<(String, f64)>, StorageError> {
+        Ok(redis::cmd("ZRANGEBYSCORE")
+            .arg(key)
+            .arg(min)
+            .arg(max)
+            .arg("WITHSCORES")
+            .query_async(&mut self.redis.get())
+            .await?)
+    }
+
+    async fn top(&self, key: &str, limit: usize) -> Result<Vec<(String, f64)>, StorageError> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(redis::cmd("ZREVRANGE")
+            .arg(key)
+            .arg(0)
+            .arg(limit - 1)
+            .arg("WITHSCORES")
+            .query_async(&mut self.redis.get())
+            .await?)
+    }
+
+    async fn remove_by_score(&self, key: &str, min: f64, max: f64) -> Result<u64, StorageError> {
+        Ok(redis::cmd("ZREMRANGEBYSCORE")
+            .arg(key)
+            .arg(min)
+            .arg(max)
+            .query_async(&mut self.redis.get())
+            .await?)
+    }
+}
+
+/// Field of a stream entry holding the log entry
+const ENTRY_FIELD: &str = "entry";
+
+/// Entries of an XRANGE reply
+fn stream_entries(reply: &[redis::Value]) -> redis::RedisResult<Vec<LogEntry>> {
+    let mut entries = Vec::new();
+    for value in reply {
+        let (id, fields): (String, Vec<String>) = redis::from_redis_value(value)?;
+        if let Some(pair) = fields.chunks_exact(2).find(|pair| pair[0] == ENTRY_FIELD) {
+            entries.push(LogEntry {
+                id,
+                entry: pair[1].clone(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+#[async_trait]
+impl EventLog for RedisStorage {
+    async fn append(&self, log: &str, entry: &str, max_len: usize) -> Result<String, StorageError> {
+        Ok(redis::cmd("XADD")
+            .arg(log)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(max_len)
+            .arg("*")
+            .arg(ENTRY_FIELD)
+            .arg(entry)
+            .query_async(&mut self.redis.get())
+            .await?)
+    }
+
+    async fn append_all(&self, log: &str, entries: &[String], max_len: usize) -> Result<(), StorageError> {
+        let mut pipe = redis::pipe();
+        for entry in entries {
+            pipe.cmd("XADD")
+                .arg(log)
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(max_len)
+                .arg("*")
+                .arg(ENTRY_FIELD)
+                .arg(entry)
+                .ignore();
+        }
+        let _: () = pipe.query_async(&mut self.redis.get()).await?;
+        Ok(())
+    }
+
+    async fn range(
+        &self,
+        log: &str,
+        start_ms: u64,
+        end_ms: u64,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<LogEntry>, StorageError> {
+        // An exclusive start reads on from the entry after `after`
+        let start = match after {
+            Some(after) if entry_order(after) >= (start_ms, 0) => format!("({}", after),
+            _ => start_ms.to_string(),
+        };
+        let reply: Vec<redis::Value> = redis::cmd("XRANGE")
+            .arg(log)
+            .arg(start)
+            .arg(end_ms)
+            .arg("COUNT")
+            .arg(count)
+            .query_async(&mut self.redis.get())
+            .await?;
+        Ok(stream_entries(&reply)?)
+    }
+
+    async fn trim_before(&self, log: &str, before_ms: u64) -> Result<(), StorageError> {
+        let _: () = redis::cmd("XTRIM")
+            .arg(log)
+            .arg("MINID")
+            .arg("~")
+            .arg(before_ms)
+            .query_async(&mut self.redis.get())
+            .await?;
+        Ok(())
+    }
+}
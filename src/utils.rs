@@ -1,4 +1,11 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::time::{SystemTime, UNIX_EPOCH};
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+pub use openssl::sha::sha256;
 
 pub fn get_current_timestamp() -> u64 {
     SystemTime::now()
@@ -9,4 +16,178 @@ pub fn get_current_timestamp() -> u64 {
 
 pub fn format_rate_limit_key(prefix: &str, key: &str) -> String {
     format!("{}:{}", prefix, key)
-} 
\ No newline at end of file
+}
+
+/// Parse a client IP into its canonical form
+///
+/// Accepts addresses with ports (`1.2.3.4:80`, `[::1]:80`), brackets and
+/// IPv6 zone IDs, and unmaps IPv4-mapped IPv6 addresses (`::ffff:1.2.3.4`)
+/// so that the same client always yields the same address.
+pub fn normalize_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    let addr = value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            let value = value.trim_start_matches('[').trim_end_matches(']');
+            value.split('%').next()?.parse().ok()
+        })?;
+    Some(addr.to_canonical())
+}
+
+/// Parse a network in CIDR notation or a single IP into its canonical form
+pub fn parse_network(value: &str) -> Option<ipnet::IpNet> {
+    value
+        .parse::<ipnet::IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(ipnet::IpNet::from))
+        .map(|net| net.trunc())
+        .ok()
+}
+
+/// Convert a YAML document into the equivalent JSON value
+///
+/// Used to deserialize YAML files with serde; aliases and non-string map
+/// keys are converted to their string form.
+pub fn yaml_to_json(yaml: yaml_rust::Yaml) -> serde_json::Value {
+    use yaml_rust::Yaml;
+
+    match yaml {
+        Yaml::Real(value) => value
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map_or(serde_json::Value::Null, serde_json::Value::Number),
+        Yaml::Integer(value) => serde_json::Value::from(value),
+        Yaml::String(value) => serde_json::Value::String(value),
+        Yaml::Boolean(value) => serde_json::Value::Bool(value),
+        Yaml::Array(values) => serde_json::Value::Array(values.into_iter().map(yaml_to_json).collect()),
+        Yaml::Hash(entries) => serde_json::Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Yaml::String(key) => key,
+                        Yaml::Integer(key) => key.to_string(),
+                        Yaml::Real(key) => key,
+                        Yaml::Boolean(key) => key.to_string(),
+                        _ => String::new(),
+                    };
+                    (key, yaml_to_json(value))
+                })
+                .collect(),
+        ),
+        Yaml::Alias(_) | Yaml::Null | Yaml::BadValue => serde_json::Value::Null,
+    }
+}
+
+/// Escape text for HTML content and attribute values
+pub fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Find the value of the longest key in `map` that is a prefix of `path`
+///
+/// Prefixes match whole path segments: `/api/search` matches
+/// `/api/search/all` and `/api/search?q=x`, but not `/api/searches`.
+pub fn longest_prefix_match<'a, V>(map: &'a HashMap<String, V>, path: &str) -> Option<&'a V> {
+    map.iter()
+        .filter(|(prefix, _)| is_path_prefix(prefix, path))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, value)| value)
+}
+
+/// Whether `prefix` is `path` or a leading run of its segments
+fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?']),
+        None => false,
+    }
+}
+
+/// Bytes as lowercase hex
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// MD5 digest, for fingerprints and legacy protocols only; MD5 isn't secure
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    let digest = hash(MessageDigest::md5(), data).expect("OpenSSL provides MD5");
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&digest);
+    bytes
+}
+
+/// HMAC-SHA256 of a message under a key
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let key = PKey::hmac(key).expect("OpenSSL accepts any HMAC key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("OpenSSL provides HMAC-SHA256");
+    signer.update(message).expect("OpenSSL signs in memory");
+    let mut bytes = [0; 32];
+    signer.sign(&mut bytes).expect("HMAC-SHA256 is 32 bytes");
+    bytes
+}
+
+/// Compare secrets in time independent of where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The first and last characters of a key, enough to tell keys apart
+pub fn redact_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 8 {
+        return "*".repeat(chars.len());
+    }
+    let start: String = chars[..4].iter().collect();
+    let end: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}...{}", start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SubnetConfig;
+
+    #[test]
+    fn test_normalize_ip() {
+        let v4: IpAddr = "1.2.3.4".parse().unwrap();
+        assert_eq!(normalize_ip("1.2.3.4"), Some(v4));
+        assert_eq!(normalize_ip(" 1.2.3.4:8080 "), Some(v4));
+        assert_eq!(normalize_ip("::ffff:1.2.3.4"), Some(v4));
+        assert_eq!(normalize_ip("[2001:db8::1]:443"), "2001:db8::1".parse().ok());
+        assert_eq!(normalize_ip("2001:0db8:0000::0001"), "2001:db8::1".parse().ok());
+        assert_eq!(normalize_ip("fe80::1%eth0"), "fe80::1".parse().ok());
+        assert_eq!(normalize_ip("unknown"), None);
+
+        let subnets = SubnetConfig::default();
+        assert_eq!(subnets.client_key("::ffff:1.2.3.4"), "1.2.3.4");
+        assert_eq!(subnets.client_key("2001:db8:0:1:aaaa::1"), "2001:db8:0:1::/64");
+        assert_eq!(subnets.client_key("2001:db8:0:1:bbbb::2"), "2001:db8:0:1::/64");
+        assert_eq!(subnets.client_key("unknown"), "unknown");
+    }
+
+    #[test]
+    fn test_digests() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"The quick brown fox jumps over the lazy dog")), "9e107d9d372bb6826bd81d3542a419d6");
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(
+            hex(&hmac_sha256(b"key", b"The quick brown fox jumps over the lazy dog")),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        // RFC 4231 test case 6: keys longer than a block are hashed first
+        assert_eq!(
+            hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+    }
+}